@@ -1,13 +1,15 @@
 -- Delete duplicate games from the database
 -- Removes games with identical EventID, SiteID, Round, WhiteID, BlackID, Moves, Date, UTCTime
--- Keeps only the first occurrence (lowest ID) of each duplicate set
+-- Keeps only the first occurrence of each duplicate set: the game whose source comes first
+-- in the configured precedence, then the lowest ID
 DELETE FROM Games
 WHERE ID IN (
     SELECT ID
     FROM (
-        SELECT ID,
-            ROW_NUMBER() OVER (PARTITION BY EventID, SiteID, Round, WhiteID, BlackID, Moves, Date, UTCTime ORDER BY ID) AS RowNum
-        FROM Games
+        SELECT g.ID AS ID,
+            ROW_NUMBER() OVER (PARTITION BY EventID, SiteID, Round, WhiteID, BlackID, Moves, Date, UTCTime ORDER BY {precedence}, g.ID) AS RowNum
+        FROM Games g
+        LEFT JOIN GameSources s ON s.GameID = g.ID
     ) AS Subquery
     WHERE RowNum > 1
-);
\ No newline at end of file
+);
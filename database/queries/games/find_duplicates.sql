@@ -0,0 +1,12 @@
+-- Find duplicate games in the database
+-- Matches games the same way as delete_duplicates.sql, along with the first occurrence
+-- of each duplicate set (by source precedence, then lowest ID), which is the one kept
+SELECT ID, Original
+FROM (
+    SELECT g.ID AS ID,
+        FIRST_VALUE(g.ID) OVER (PARTITION BY EventID, SiteID, Round, WhiteID, BlackID, Moves, Date, UTCTime ORDER BY {precedence}, g.ID) AS Original
+    FROM Games g
+    LEFT JOIN GameSources s ON s.GameID = g.ID
+) AS Subquery
+WHERE ID <> Original
+ORDER BY ID;
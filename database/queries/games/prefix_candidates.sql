@@ -0,0 +1,12 @@
+-- Find games that may be the same game at different stages
+-- Groups games with identical EventID, Round, WhiteID, BlackID, Date and FEN, and returns
+-- the games of every group with more than one game, in ID order
+SELECT ID, GroupID, Moves
+FROM (
+    SELECT ID, Moves,
+        DENSE_RANK() OVER (ORDER BY EventID, Round, WhiteID, BlackID, Date, FEN) AS GroupID,
+        COUNT(*) OVER (PARTITION BY EventID, Round, WhiteID, BlackID, Date, FEN) AS GroupSize
+    FROM Games
+) AS Subquery
+WHERE GroupSize > 1
+ORDER BY GroupID, ID;
@@ -0,0 +1,14 @@
+-- Analysis attributions schema for Pawn Appétit
+-- Which engine and search settings produced the evaluations written into a game, most
+-- recent last. Only the last few analyses of each game are kept.
+
+CREATE TABLE IF NOT EXISTS AnalysisAttributions (
+    ID INTEGER PRIMARY KEY,
+    GameID INTEGER NOT NULL,
+    Engine TEXT NOT NULL,
+    GoMode TEXT NOT NULL,
+    AnalyzedAt INTEGER NOT NULL,
+    FOREIGN KEY(GameID) REFERENCES Games(ID) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS analysis_attributions_game ON AnalysisAttributions(GameID);
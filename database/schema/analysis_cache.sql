@@ -0,0 +1,24 @@
+-- Analysis cache schema for Pawn Appétit
+-- Final engine lines of every finished search, kept in the app data directory
+
+CREATE TABLE IF NOT EXISTS AnalysisCache (
+    PositionHash BIGINT NOT NULL,
+    Fen TEXT NOT NULL,
+    Engine TEXT NOT NULL,
+    Line INTEGER NOT NULL,
+    Depth INTEGER NOT NULL,
+    Score TEXT NOT NULL,
+    UciMoves TEXT NOT NULL,
+    SanMoves TEXT NOT NULL,
+    UpdatedAt BIGINT NOT NULL,
+    PRIMARY KEY (Fen, Engine, Line)
+);
+
+CREATE INDEX IF NOT EXISTS analysis_cache_position_hash ON AnalysisCache(PositionHash);
+CREATE INDEX IF NOT EXISTS analysis_cache_updated_at ON AnalysisCache(UpdatedAt);
+
+-- Name each engine reported, by path, so searches can be answered before the engine starts
+CREATE TABLE IF NOT EXISTS AnalysisCacheEngines (
+    Path TEXT PRIMARY KEY,
+    Engine TEXT NOT NULL
+);
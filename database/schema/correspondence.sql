@@ -0,0 +1,16 @@
+-- Correspondence schema for Pawn Appétit
+-- Per-game rules and conditional move sequences of correspondence games, kept in the app data directory
+
+CREATE TABLE IF NOT EXISTS CorrespondenceRules (
+    GameRef TEXT PRIMARY KEY,
+    EnginesAllowed BOOLEAN NOT NULL,
+    TablebasesAllowed BOOLEAN NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS ConditionalMoves (
+    GameRef TEXT NOT NULL,
+    PositionHash BIGINT NOT NULL,
+    Fen TEXT NOT NULL,
+    Moves TEXT NOT NULL,
+    PRIMARY KEY (GameRef, PositionHash, Moves)
+);
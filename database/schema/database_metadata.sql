@@ -0,0 +1,15 @@
+-- Database metadata schema for Pawn Appétit
+-- Notes, source, license, tags and cover of a database, beyond the title and description
+-- kept in Info. A single row, whose Version is bumped on every write so that concurrent
+-- edits can be detected.
+
+CREATE TABLE IF NOT EXISTS DatabaseMetadata (
+    ID INTEGER PRIMARY KEY CHECK (ID = 1),
+    Version INTEGER NOT NULL DEFAULT 0,
+    Notes TEXT NOT NULL DEFAULT '',
+    SourceUrl TEXT,
+    License TEXT,
+    Tags TEXT NOT NULL DEFAULT '{}',
+    CoverColor TEXT,
+    CoverIcon TEXT
+);
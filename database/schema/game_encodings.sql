@@ -0,0 +1,8 @@
+-- Move encoding schema for Pawn Appétit
+-- Encoding version of each game's move blob; games without a row are version 0
+
+CREATE TABLE IF NOT EXISTS GameEncodings (
+    GameID INTEGER PRIMARY KEY,
+    Version INTEGER NOT NULL,
+    FOREIGN KEY(GameID) REFERENCES Games ON DELETE CASCADE
+);
@@ -0,0 +1,16 @@
+-- Game links schema for Pawn Appétit
+-- Cross-references from one game to another, such as "compare with Kasparov–Karpov 1985".
+-- Links from a deleted game are removed; links to a deleted game are kept with a NULL
+-- ToGame so the annotator can see that the referenced game is gone.
+
+CREATE TABLE IF NOT EXISTS GameLinks (
+    ID INTEGER PRIMARY KEY,
+    FromGame INTEGER NOT NULL,
+    ToGame INTEGER,
+    Label TEXT NOT NULL DEFAULT '',
+    FOREIGN KEY(FromGame) REFERENCES Games(ID) ON DELETE CASCADE,
+    FOREIGN KEY(ToGame) REFERENCES Games(ID) ON DELETE SET NULL,
+    UNIQUE(FromGame, ToGame)
+);
+
+CREATE INDEX IF NOT EXISTS game_links_to_game ON GameLinks(ToGame);
@@ -0,0 +1,14 @@
+-- Game sources schema for Pawn Appétit
+-- Where each game came from: the PGN file it was imported from or the online account it
+-- was downloaded from. Games copied from another database keep their original source.
+
+CREATE TABLE IF NOT EXISTS GameSources (
+    GameID INTEGER PRIMARY KEY,
+    Kind TEXT NOT NULL,
+    Name TEXT NOT NULL DEFAULT '',
+    Account TEXT,
+    ImportedAt INTEGER,
+    FOREIGN KEY(GameID) REFERENCES Games ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS game_sources_kind_name ON GameSources(Kind, Name, Account);
@@ -0,0 +1,36 @@
+-- Opening frequency schema for Pawn Appétit
+-- Number of games of each opening (by ECO code), kept up to date by triggers on Games
+
+CREATE TABLE IF NOT EXISTS OpeningFrequencies (
+    OpeningKey TEXT PRIMARY KEY,
+    GameCount INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS opening_frequencies_game_count ON OpeningFrequencies(GameCount);
+
+CREATE TRIGGER IF NOT EXISTS opening_frequencies_insert
+AFTER INSERT ON Games
+WHEN NEW.ECO IS NOT NULL AND NEW.ECO <> ''
+BEGIN
+    INSERT INTO OpeningFrequencies (OpeningKey, GameCount) VALUES (NEW.ECO, 1)
+    ON CONFLICT(OpeningKey) DO UPDATE SET GameCount = GameCount + 1;
+END;
+
+CREATE TRIGGER IF NOT EXISTS opening_frequencies_delete
+AFTER DELETE ON Games
+WHEN OLD.ECO IS NOT NULL AND OLD.ECO <> ''
+BEGIN
+    UPDATE OpeningFrequencies SET GameCount = GameCount - 1 WHERE OpeningKey = OLD.ECO;
+    DELETE FROM OpeningFrequencies WHERE OpeningKey = OLD.ECO AND GameCount <= 0;
+END;
+
+CREATE TRIGGER IF NOT EXISTS opening_frequencies_update
+AFTER UPDATE OF ECO ON Games
+WHEN OLD.ECO IS NOT NEW.ECO
+BEGIN
+    UPDATE OpeningFrequencies SET GameCount = GameCount - 1 WHERE OpeningKey = OLD.ECO;
+    DELETE FROM OpeningFrequencies WHERE OpeningKey = OLD.ECO AND GameCount <= 0;
+    INSERT INTO OpeningFrequencies (OpeningKey, GameCount)
+    SELECT NEW.ECO, 1 WHERE NEW.ECO IS NOT NULL AND NEW.ECO <> ''
+    ON CONFLICT(OpeningKey) DO UPDATE SET GameCount = GameCount + 1;
+END;
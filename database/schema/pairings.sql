@@ -0,0 +1,31 @@
+-- Tournament pairings schema for Pawn Appétit
+-- Club events paired with the Swiss or round robin system, kept in the app data directory.
+-- Players are numbered from 0 in pairing order; a game without a black player is a bye.
+
+CREATE TABLE IF NOT EXISTS PairingEvents (
+    ID INTEGER PRIMARY KEY,
+    Name TEXT NOT NULL,
+    System TEXT NOT NULL,
+    Rounds INTEGER NOT NULL,
+    Seed BIGINT NOT NULL,
+    CreatedAt BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS PairingPlayers (
+    EventID INTEGER NOT NULL REFERENCES PairingEvents(ID) ON DELETE CASCADE,
+    Number INTEGER NOT NULL,
+    Name TEXT NOT NULL,
+    Rating INTEGER,
+    PlayerID INTEGER,
+    PRIMARY KEY (EventID, Number)
+);
+
+CREATE TABLE IF NOT EXISTS PairingGames (
+    EventID INTEGER NOT NULL REFERENCES PairingEvents(ID) ON DELETE CASCADE,
+    Round INTEGER NOT NULL,
+    Board INTEGER NOT NULL,
+    White INTEGER NOT NULL,
+    Black INTEGER,
+    Result TEXT,
+    PRIMARY KEY (EventID, Round, Board)
+);
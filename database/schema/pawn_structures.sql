@@ -0,0 +1,10 @@
+-- Pawn structure schema for Pawn Appétit
+-- Pawn structure family of each game, classified during import or by a backfill
+
+CREATE TABLE IF NOT EXISTS GameStructures (
+    GameID INTEGER PRIMARY KEY,
+    Structure TEXT NOT NULL,
+    FOREIGN KEY(GameID) REFERENCES Games ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS game_structures_structure ON GameStructures(Structure);
@@ -0,0 +1,11 @@
+-- Player metadata schema for Pawn Appétit
+-- Federation, title and photo information resolved for database players
+
+CREATE TABLE IF NOT EXISTS PlayerMetadata (
+    PlayerID INTEGER PRIMARY KEY,
+    Federation TEXT,
+    Title TEXT,
+    PhotoPath TEXT,
+    FideID INTEGER,
+    FOREIGN KEY(PlayerID) REFERENCES Players ON DELETE CASCADE
+);
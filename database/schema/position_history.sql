@@ -0,0 +1,20 @@
+-- Position history schema for Pawn Appétit
+-- Positions recently looked at in any tab, kept in the app data directory so they can be
+-- reopened later. Bounded: the oldest visits are pruned.
+
+CREATE TABLE IF NOT EXISTS PositionHistory (
+    ID INTEGER PRIMARY KEY AUTOINCREMENT,
+    VisitedAt BIGINT NOT NULL,
+    Fen TEXT NOT NULL,
+    Tab TEXT NOT NULL,
+    GameFile TEXT,
+    GameID INTEGER,
+    Opening TEXT
+);
+
+CREATE INDEX IF NOT EXISTS position_history_fen ON PositionHistory(Fen);
+
+CREATE TABLE IF NOT EXISTS PositionHistorySettings (
+    ID INTEGER PRIMARY KEY CHECK (ID = 1),
+    Enabled BOOLEAN NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Position notes schema for Pawn Appétit
+-- Notes and board shapes attached to positions rather than to games, kept in the app data
+-- directory. Positions are keyed by their FEN without move counters.
+
+CREATE TABLE IF NOT EXISTS PositionNotes (
+    Fen TEXT PRIMARY KEY,
+    PositionHash BIGINT NOT NULL,
+    Note TEXT NOT NULL,
+    Shapes TEXT NOT NULL,
+    UpdatedAt BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS position_notes_position_hash ON PositionNotes(PositionHash);
+CREATE INDEX IF NOT EXISTS position_notes_updated_at ON PositionNotes(UpdatedAt);
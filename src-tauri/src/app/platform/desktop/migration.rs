@@ -1,9 +1,26 @@
-#[cfg(desktop)]
-use fs_extra::dir::{copy, CopyOptions};
-#[cfg(desktop)]
+//! Migration of user data from legacy apps.
+//!
+//! Databases, puzzle databases and engines of previous app identifiers are detected item by
+//! item. On a first run, with an empty app data directory, everything is copied over;
+//! otherwise the frontend is told what is left so the user can pick what to migrate.
+//!
+//! Two legacy layouts are recognized: the current one, with `db`, `puzzles` and `engines`
+//! directories, and the flat one of early builds, which kept databases and `engines.json`
+//! directly in the data directory. Every successful copy is recorded in `migration.json`, so
+//! items are only offered until they are migrated.
+
+use std::collections::HashMap;
 use std::fs::create_dir_all;
-#[cfg(desktop)]
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
 use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::error::Error;
+use crate::fs::{copy_file, unique_path};
 
 #[cfg(desktop)]
 #[derive(Debug, thiserror::Error)]
@@ -32,96 +49,710 @@ pub enum MigrationError {
 /// Legacy app identifiers that we need to migrate from
 const LEGACY_IDENTIFIERS: &[&str] = &["org.encroissant.app"];
 
+/// Record of the migrated items, in the app data directory.
+const MIGRATION_LOG_FILE: &str = "migration.json";
+
+/// Files at least this large are checksummed after being copied.
+const VERIFY_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum LegacyItemKind {
+    Database,
+    Puzzles,
+    /// Engine settings, with the engines installed in the app data directory.
+    Engines,
+}
+
+/// Something that can be migrated from a legacy app.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyItem {
+    /// Identifier of the legacy app.
+    pub identifier: String,
+    pub kind: LegacyItemKind,
+    /// Path relative to the legacy data directory, with `/` separators.
+    pub path: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Whether the item was migrated before.
+    pub migrated: bool,
+}
+
+/// Item to migrate, as reported by `detect_legacy_data`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationSelection {
+    pub identifier: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum MigrationOutcome {
+    Copied {
+        target: PathBuf,
+    },
+    /// Copied under another name, as the target already existed.
+    Renamed {
+        target: PathBuf,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct MigratedItem {
+    pub identifier: String,
+    pub path: String,
+    pub outcome: MigrationOutcome,
+}
+
+/// Event payload reporting the copy of an item.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationProgress {
+    pub identifier: String,
+    pub path: String,
+    /// Position of the item in the selection.
+    pub index: usize,
+    pub total: usize,
+    /// Bytes of the item copied so far.
+    pub copied: u64,
+    pub size: u64,
+}
+
+/// Event payload sent at startup when legacy data is left to migrate.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyDataAvailable {
+    pub items: Vec<LegacyItem>,
+}
+
+fn relative(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn size_of(path: &Path) -> std::io::Result<u64> {
+    if path.is_dir() {
+        let mut size = 0;
+        for entry in std::fs::read_dir(path)? {
+            size += size_of(&entry?.path())?;
+        }
+        Ok(size)
+    } else {
+        Ok(path.metadata()?.len())
+    }
+}
+
+/// Files with the extension `ext` directly in `dir`, sorted by name.
+fn files_with_extension(dir: &Path, ext: &str) -> std::io::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == ext) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether an engine settings file lists any engine.
+fn has_engines(settings: &Path) -> bool {
+    std::fs::read_to_string(settings)
+        .ok()
+        .and_then(|s| serde_json::from_str::<Vec<Value>>(&s).ok())
+        .is_some_and(|engines| !engines.is_empty())
+}
+
+/// Items of the legacy data directory `root`, by kind then path.
+fn scan_legacy_dir(identifier: &str, root: &Path) -> std::io::Result<Vec<LegacyItem>> {
+    let mut found = Vec::new();
+    for (dir, kind) in [
+        ("db", LegacyItemKind::Database),
+        ("puzzles", LegacyItemKind::Puzzles),
+        // Flat layout
+        ("", LegacyItemKind::Database),
+    ] {
+        for file in files_with_extension(&root.join(dir), "db3")? {
+            found.push((kind, file));
+        }
+    }
+    let engines = root.join("engines");
+    if has_engines(&engines.join("engines.json")) {
+        found.push((LegacyItemKind::Engines, engines));
+    }
+    // Flat layout
+    let engines = root.join("engines.json");
+    if has_engines(&engines) {
+        found.push((LegacyItemKind::Engines, engines));
+    }
+
+    let mut items = found
+        .into_iter()
+        .map(|(kind, path)| {
+            Ok(LegacyItem {
+                identifier: identifier.to_string(),
+                kind,
+                path: relative(path.strip_prefix(root).unwrap_or(&path)),
+                size: size_of(&path)?,
+                migrated: false,
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    items.sort_by(|a, b| (a.kind, &a.path).cmp(&(b.kind, &b.path)));
+    Ok(items)
+}
+
+fn read_migration_log(target: &Path) -> Vec<MigratedItem> {
+    std::fs::read_to_string(target.join(MIGRATION_LOG_FILE))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn append_migration_log(target: &Path, items: &[MigratedItem]) -> Result<(), Error> {
+    let mut log = read_migration_log(target);
+    log.extend(
+        items
+            .iter()
+            .filter(|item| !matches!(item.outcome, MigrationOutcome::Failed { .. }))
+            .cloned(),
+    );
+    create_dir_all(target)?;
+    std::fs::write(
+        target.join(MIGRATION_LOG_FILE),
+        serde_json::to_string_pretty(&log)?,
+    )?;
+    Ok(())
+}
+
+/// Items of all legacy data directories, flagging those already migrated to `target`.
+fn detect(roots: &[(String, PathBuf)], target: &Path) -> Result<Vec<LegacyItem>, Error> {
+    let log = read_migration_log(target);
+    let mut items = Vec::new();
+    for (identifier, root) in roots {
+        if !root.is_dir() {
+            continue;
+        }
+        for mut item in scan_legacy_dir(identifier, root)? {
+            item.migrated = log
+                .iter()
+                .any(|x| x.identifier == item.identifier && x.path == item.path);
+            items.push(item);
+        }
+    }
+    Ok(items)
+}
+
+/// Replace the `from` prefix of every path in `value` by the matching `to`.
+fn rewrite_paths(value: &mut Value, moved: &[(PathBuf, PathBuf)]) {
+    match value {
+        Value::String(s) => {
+            for (from, to) in moved {
+                if let Ok(rest) = Path::new(s.as_str()).strip_prefix(from) {
+                    *s = to.join(rest).to_string_lossy().into_owned();
+                    return;
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| rewrite_paths(v, moved)),
+        Value::Object(values) => values.values_mut().for_each(|v| rewrite_paths(v, moved)),
+        _ => {}
+    }
+}
+
+/// Copy legacy engines into `target`, merging their settings into the current ones.
+///
+/// `source` is either an `engines` directory or, in the flat layout, an `engines.json` file.
+/// Engines installed in the directory are copied next to the current ones, and their paths
+/// in the settings updated. Engines already set up with the same path are skipped.
+fn migrate_engines(source: &Path, target: &Path) -> Result<(PathBuf, bool), Error> {
+    let target_dir = target.join("engines");
+    create_dir_all(&target_dir)?;
+    let (settings, installed) = if source.is_dir() {
+        (source.join("engines.json"), Some(source))
+    } else {
+        (source.to_path_buf(), None)
+    };
+
+    let mut moved = Vec::new();
+    let mut renamed = false;
+    if let Some(installed) = installed {
+        let mut entries: Vec<_> = std::fs::read_dir(installed)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for from in entries {
+            let name = from.file_name().unwrap().to_string_lossy().into_owned();
+            if name == "engines.json" {
+                continue;
+            }
+            let (to, changed) = unique_path(&target_dir, &name);
+            if from.is_dir() {
+                let mut options = fs_extra::dir::CopyOptions::new();
+                options.copy_inside = true;
+                fs_extra::dir::copy(&from, &to, &options)
+                    .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+            } else {
+                std::fs::copy(&from, &to)?;
+            }
+            renamed |= changed;
+            moved.push((from, to));
+        }
+    }
+
+    let mut legacy: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(&settings)?)?;
+    let target_settings = target_dir.join("engines.json");
+    let mut current: Vec<Value> = std::fs::read_to_string(&target_settings)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    for mut engine in legacy.drain(..) {
+        rewrite_paths(&mut engine, &moved);
+        let duplicate = current
+            .iter()
+            .any(|x| match (x.get("path"), engine.get("path")) {
+                (Some(a), Some(b)) => a == b,
+                _ => *x == engine,
+            });
+        if !duplicate {
+            current.push(engine);
+        }
+    }
+    std::fs::write(&target_settings, serde_json::to_string_pretty(&current)?)?;
+    Ok((target_settings, renamed))
+}
+
+/// Copy a single legacy item into the app data directory `target`.
+fn migrate_item(
+    root: &Path,
+    item: &LegacyItem,
+    target: &Path,
+    progress: &mut dyn FnMut(u64),
+) -> Result<MigrationOutcome, Error> {
+    let source = root.join(&item.path);
+    let (target, renamed) = match item.kind {
+        LegacyItemKind::Engines => migrate_engines(&source, target)?,
+        LegacyItemKind::Database | LegacyItemKind::Puzzles => {
+            let dir = target.join(match item.kind {
+                LegacyItemKind::Puzzles => "puzzles",
+                _ => "db",
+            });
+            create_dir_all(&dir)?;
+            let name = source.file_name().unwrap().to_string_lossy();
+            let (to, renamed) = unique_path(&dir, &name);
+            copy_file(&source, &to, item.size >= VERIFY_THRESHOLD, progress)?;
+            (to, renamed)
+        }
+    };
+    progress(item.size);
+    Ok(if renamed {
+        MigrationOutcome::Renamed { target }
+    } else {
+        MigrationOutcome::Copied { target }
+    })
+}
+
+/// Copy the selected legacy items into the app data directory `target`, recording the ones
+/// that were copied.
+///
+/// An item that fails is reported as such and doesn't stop the others. Selections that don't
+/// match a detected item are ignored.
+fn migrate(
+    roots: &[(String, PathBuf)],
+    target: &Path,
+    selection: &[MigrationSelection],
+    progress: &mut dyn FnMut(MigrationProgress),
+) -> Result<Vec<MigratedItem>, Error> {
+    let items: Vec<_> = detect(roots, target)?
+        .into_iter()
+        .filter(|item| {
+            selection
+                .iter()
+                .any(|s| s.identifier == item.identifier && s.path == item.path)
+        })
+        .collect();
+    let roots: HashMap<_, _> = roots.iter().cloned().collect();
+
+    let total = items.len();
+    let mut report = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        log::info!("Migrating {} from {}", item.path, item.identifier);
+        let mut report_progress = |copied| {
+            progress(MigrationProgress {
+                identifier: item.identifier.clone(),
+                path: item.path.clone(),
+                index,
+                total,
+                copied,
+                size: item.size,
+            })
+        };
+        let outcome = migrate_item(
+            &roots[&item.identifier],
+            &item,
+            target,
+            &mut report_progress,
+        )
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to migrate {}: {}", item.path, e);
+            MigrationOutcome::Failed {
+                error: e.to_string(),
+            }
+        });
+        report.push(MigratedItem {
+            identifier: item.identifier,
+            path: item.path,
+            outcome,
+        });
+    }
+    append_migration_log(target, &report)?;
+    Ok(report)
+}
+
+/// Data directories of the legacy apps, by identifier.
+fn legacy_roots() -> Vec<(String, PathBuf)> {
+    #[cfg(desktop)]
+    {
+        LEGACY_IDENTIFIERS
+            .iter()
+            .filter_map(
+                |&identifier| match super::get_legacy_app_data_path(identifier) {
+                    Ok(path) => Some((identifier.to_string(), path)),
+                    Err(e) => {
+                        log::warn!("No legacy data path for {}: {}", identifier, e);
+                        None
+                    }
+                },
+            )
+            .collect()
+    }
+
+    #[cfg(not(desktop))]
+    Vec::new()
+}
+
+/// Whether a directory is missing or has nothing in it.
+fn is_empty_dir(path: &Path) -> bool {
+    path.read_dir()
+        .map(|mut dir| dir.next().is_none())
+        .unwrap_or(true)
+}
+
+/// List what can be migrated from legacy apps.
+#[tauri::command]
+#[specta::specta]
+pub async fn detect_legacy_data(app: AppHandle) -> Result<Vec<LegacyItem>, Error> {
+    detect(&legacy_roots(), &app.path().app_data_dir()?)
+}
+
+/// Copy the selected legacy items, reporting the progress of each with `MigrationProgress`.
+#[tauri::command]
+#[specta::specta]
+pub async fn run_migration(
+    selection: Vec<MigrationSelection>,
+    app: AppHandle,
+) -> Result<Vec<MigratedItem>, Error> {
+    let target = app.path().app_data_dir()?;
+    tokio::task::spawn_blocking(move || {
+        migrate(&legacy_roots(), &target, &selection, &mut |progress| {
+            progress.emit(&app).ok();
+        })
+    })
+    .await
+    .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+}
+
 #[cfg(desktop)]
 /// Migrates user data from old app directories to the new one
 ///
-/// This function checks for existing data directories from previous app identifiers
-/// and copies all their contents to the new app data directory. It only runs if
-/// the new directory doesn't already exist (first run).
+/// Everything found in legacy directories is copied, but only on a first run, when the
+/// current app data directory is empty. Otherwise nothing happens and the user can migrate
+/// items from the frontend, which `announce_legacy_data` tells about.
 ///
 /// # Arguments
 /// * `app` - The Tauri app handle used to resolve paths
 ///
 /// # Returns
-/// * `Ok(())` if migration completed successfully or was skipped
-/// * `Err(MigrationError)` if there was an error during migration
+/// * `Ok(())` if migration completed or was skipped; items that failed are only logged
+/// * `Err(MigrationError)` if the legacy data couldn't be read
 pub fn migrate_from_legacy_apps(app: &AppHandle) -> Result<(), MigrationError> {
-    #[cfg(desktop)]
-    {
-        log::info!("Checking for legacy app data migration");
-
-        // Get the current app data directory
-        let current_app_data = app
-            .path()
-            .app_data_dir()
-            .map_err(|e| MigrationError::AppDataDirectoryFailed { source: e })?;
-
-        // Skip migration if current directory already exists and has content
-        if current_app_data.exists()
-            && current_app_data
-                .read_dir()
-                .map_or(false, |mut dir| dir.next().is_some())
-        {
-            log::info!(
-                "Current app data directory already exists with content, skipping migration"
-            );
-            return Ok(());
+    log::info!("Checking for legacy app data migration");
+
+    let current_app_data = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| MigrationError::AppDataDirectoryFailed { source: e })?;
+
+    if !is_empty_dir(&current_app_data) {
+        log::info!("Current app data directory already has content, skipping automatic migration");
+        return Ok(());
+    }
+
+    let roots = legacy_roots();
+    let selection: Vec<_> = detect(&roots, &current_app_data)
+        .map_err(|e| MigrationError::LegacyMigrationFailed {
+            identifier: LEGACY_IDENTIFIERS.join(", "),
+            source: Box::new(std::io::Error::other(e.to_string())),
+        })?
+        .into_iter()
+        .map(|item| MigrationSelection {
+            identifier: item.identifier,
+            path: item.path,
+        })
+        .collect();
+    if selection.is_empty() {
+        log::info!("No legacy app data found to migrate");
+        return Ok(());
+    }
+
+    if let Some(parent) = current_app_data.parent() {
+        create_dir_all(parent).map_err(|e| MigrationError::DirectoryCreationFailed {
+            path: parent.display().to_string(),
+            source: e,
+        })?;
+    }
+    let report = migrate(&roots, &current_app_data, &selection, &mut |_| {}).map_err(|e| {
+        MigrationError::LegacyDataCopyFailed {
+            from: LEGACY_IDENTIFIERS.join(", "),
+            to: current_app_data.display().to_string(),
+            source: Box::new(std::io::Error::other(e.to_string())),
         }
+    })?;
+    let failed = report
+        .iter()
+        .filter(|x| matches!(x.outcome, MigrationOutcome::Failed { .. }))
+        .count();
+    log::info!(
+        "Migrated {} legacy items, {} failed",
+        report.len() - failed,
+        failed
+    );
+    Ok(())
+}
 
-        // Look for legacy app directories to migrate from
-        for &legacy_identifier in LEGACY_IDENTIFIERS {
-            let legacy_path = super::get_legacy_app_data_path(legacy_identifier).map_err(|e| {
-                MigrationError::LegacyMigrationFailed {
-                    identifier: legacy_identifier.to_string(),
-                    source: e,
-                }
-            })?;
-
-            if legacy_path.exists() && legacy_path.is_dir() {
-                log::info!("Found legacy app data at: {}", legacy_path.display());
-                log::info!("Migrating to: {}", current_app_data.display());
-
-                // Ensure the parent directory of the current app data exists
-                if let Some(parent) = current_app_data.parent() {
-                    create_dir_all(parent).map_err(|e| {
-                        MigrationError::DirectoryCreationFailed {
-                            path: parent.display().to_string(),
-                            source: e,
-                        }
-                    })?;
-                }
+#[cfg(desktop)]
+/// Emits `LegacyDataAvailable` if legacy items are left to migrate. Events must be mounted.
+pub fn announce_legacy_data(app: &AppHandle) -> Result<(), Error> {
+    let items: Vec<_> = detect(&legacy_roots(), &app.path().app_data_dir()?)?
+        .into_iter()
+        .filter(|item| !item.migrated)
+        .collect();
+    if !items.is_empty() {
+        log::info!("{} legacy items can be migrated", items.len());
+        LegacyDataAvailable { items }.emit(app)?;
+    }
+    Ok(())
+}
 
-                // Copy options for fs_extra
-                let mut options = CopyOptions::new();
-                options.overwrite = true;
-                options.copy_inside = true;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{checksum, COPY_CHUNK};
 
-                // Copy all contents from legacy directory to new directory
-                copy(&legacy_path, &current_app_data, &options).map_err(|e| {
-                    MigrationError::LegacyDataCopyFailed {
-                        from: legacy_path.display().to_string(),
-                        to: current_app_data.display().to_string(),
-                        source: Box::new(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            e.to_string(),
-                        )),
-                    }
-                })?;
+    fn write(path: &Path, contents: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
 
-                log::info!("Successfully migrated data from {}", legacy_identifier);
-                return Ok(());
-            } else {
-                log::info!("No legacy data found for identifier: {}", legacy_identifier);
+    fn select(items: &[LegacyItem]) -> Vec<MigrationSelection> {
+        items
+            .iter()
+            .map(|item| MigrationSelection {
+                identifier: item.identifier.clone(),
+                path: item.path.clone(),
+            })
+            .collect()
+    }
+
+    /// A legacy directory in the current layout, with an installed engine.
+    fn nested_layout(root: &Path) {
+        write(&root.join("db/Masters.db3"), "masters");
+        write(&root.join("db/notes.txt"), "not a database");
+        write(&root.join("puzzles/Lichess.db3"), "puzzles");
+        let stockfish = root.join("engines/stockfish/stockfish");
+        write(&stockfish, "binary");
+        write(
+            &root.join("engines/engines.json"),
+            &serde_json::json!([{ "name": "Stockfish", "path": stockfish }]).to_string(),
+        );
+    }
+
+    #[test]
+    fn both_legacy_layouts_are_detected() {
+        let nested = tempfile::tempdir().unwrap();
+        nested_layout(nested.path());
+        let flat = tempfile::tempdir().unwrap();
+        write(&flat.path().join("Old games.db3"), "old");
+        write(
+            &flat.path().join("engines.json"),
+            r#"[{"name":"Komodo","path":"/usr/bin/komodo"}]"#,
+        );
+        let target = tempfile::tempdir().unwrap();
+
+        let roots = vec![
+            ("nested".to_string(), nested.path().to_path_buf()),
+            ("flat".to_string(), flat.path().to_path_buf()),
+            ("missing".to_string(), nested.path().join("missing")),
+        ];
+        let items = detect(&roots, target.path()).unwrap();
+        let found: Vec<_> = items
+            .iter()
+            .map(|x| (x.identifier.as_str(), x.kind, x.path.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("nested", LegacyItemKind::Database, "db/Masters.db3"),
+                ("nested", LegacyItemKind::Puzzles, "puzzles/Lichess.db3"),
+                ("nested", LegacyItemKind::Engines, "engines"),
+                ("flat", LegacyItemKind::Database, "Old games.db3"),
+                ("flat", LegacyItemKind::Engines, "engines.json"),
+            ]
+        );
+        assert_eq!(items[0].size, 7);
+        assert!(items.iter().all(|x| !x.migrated));
+    }
+
+    #[test]
+    fn only_selected_items_are_migrated_and_collisions_are_renamed() {
+        let legacy = tempfile::tempdir().unwrap();
+        nested_layout(legacy.path());
+        let target = tempfile::tempdir().unwrap();
+        write(&target.path().join("db/Masters.db3"), "current");
+        let roots = vec![("legacy".to_string(), legacy.path().to_path_buf())];
+
+        let items = detect(&roots, target.path()).unwrap();
+        let mut progress = Vec::new();
+        let report = migrate(&roots, target.path(), &select(&items[..1]), &mut |p| {
+            progress.push((p.index, p.total, p.copied))
+        })
+        .unwrap();
+        assert_eq!(
+            report[0].outcome,
+            MigrationOutcome::Renamed {
+                target: target.path().join("db/Masters (1).db3")
             }
-        }
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("db/Masters.db3")).unwrap(),
+            "current"
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("db/Masters (1).db3")).unwrap(),
+            "masters"
+        );
+        assert_eq!(progress.last(), Some(&(0, 1, 7)));
+        assert!(!target.path().join("puzzles").exists());
 
-        log::info!("No legacy app data found to migrate");
-        Ok(())
+        // Migrated items are flagged from then on.
+        let items = detect(&roots, target.path()).unwrap();
+        assert!(items[0].migrated);
+        assert!(items[1..].iter().all(|x| !x.migrated));
     }
 
-    #[cfg(not(desktop))]
-    {
-        // On non-desktop platforms, migration is not needed
-        Ok(())
+    #[test]
+    fn engines_are_merged_with_their_paths_updated() {
+        let legacy = tempfile::tempdir().unwrap();
+        nested_layout(legacy.path());
+        let target = tempfile::tempdir().unwrap();
+        write(
+            &target.path().join("engines/engines.json"),
+            r#"[{"name":"Lc0","path":"/usr/bin/lc0"}]"#,
+        );
+        let roots = vec![("legacy".to_string(), legacy.path().to_path_buf())];
+
+        let selection = vec![MigrationSelection {
+            identifier: "legacy".to_string(),
+            path: "engines".to_string(),
+        }];
+        let report = migrate(&roots, target.path(), &selection, &mut |_| {}).unwrap();
+        assert!(matches!(report[0].outcome, MigrationOutcome::Copied { .. }));
+
+        let engines: Vec<Value> = serde_json::from_str(
+            &std::fs::read_to_string(target.path().join("engines/engines.json")).unwrap(),
+        )
+        .unwrap();
+        let stockfish = target.path().join("engines/stockfish/stockfish");
+        assert_eq!(engines.len(), 2);
+        assert_eq!(engines[1]["path"], stockfish.to_string_lossy().as_ref());
+        assert_eq!(std::fs::read_to_string(stockfish).unwrap(), "binary");
+
+        // Migrating them again copies the binaries aside rather than over the ones in use.
+        migrate(&roots, target.path(), &selection, &mut |_| {}).unwrap();
+        let engines: Vec<Value> = serde_json::from_str(
+            &std::fs::read_to_string(target.path().join("engines/engines.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(engines.len(), 3);
+        assert!(target.path().join("engines/stockfish (1)").exists());
+    }
+
+    #[test]
+    fn unknown_selections_are_ignored() {
+        let legacy = tempfile::tempdir().unwrap();
+        nested_layout(legacy.path());
+        let target = tempfile::tempdir().unwrap();
+        let roots = vec![("legacy".to_string(), legacy.path().to_path_buf())];
+
+        let selection = vec![MigrationSelection {
+            identifier: "legacy".to_string(),
+            path: "db/notes.txt".to_string(),
+        }];
+        let report = migrate(&roots, target.path(), &selection, &mut |_| {}).unwrap();
+        assert!(report.is_empty());
+        assert!(!target.path().join("db").exists());
+    }
+
+    #[test]
+    fn verified_copies_match_their_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("big.db3");
+        let contents: String = (0..COPY_CHUNK + 10)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        write(&from, &contents);
+
+        let to = dir.path().join("copy.db3");
+        let mut reports = 0;
+        copy_file(&from, &to, true, &mut |_| reports += 1).unwrap();
+        assert!(reports >= 2);
+        assert_eq!(checksum(&from).unwrap(), checksum(&to).unwrap());
+
+        // A failed copy leaves nothing behind.
+        let missing = dir.path().join("missing.db3");
+        let to = dir.path().join("failed.db3");
+        assert!(copy_file(&missing, &to, true, &mut |_| {}).is_err());
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn colliding_names_get_a_number() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            unique_path(dir.path(), "a.pgn.zst"),
+            (dir.path().join("a.pgn.zst"), false)
+        );
+        write(&dir.path().join("a.pgn.zst"), "");
+        write(&dir.path().join("a (1).pgn.zst"), "");
+        assert_eq!(
+            unique_path(dir.path(), "a.pgn.zst"),
+            (dir.path().join("a (2).pgn.zst"), true)
+        );
+        write(&dir.path().join("stockfish"), "");
+        assert_eq!(
+            unique_path(dir.path(), "stockfish"),
+            (dir.path().join("stockfish (1)"), true)
+        );
     }
 }
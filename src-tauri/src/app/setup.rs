@@ -1,7 +1,11 @@
-use tauri::App;
+use tauri::{App, AppHandle, Manager, RunEvent, WindowEvent};
 
 use crate::app::platform;
+use crate::chess::{run_engine_queue, TabEngineScheduler};
+use crate::db::purge_trash_on_startup;
+use crate::dirty_tabs::intercept_exit;
 use crate::telemetry::handle_initial_run_telemetry;
+use crate::AppState;
 
 /// Shared app setup logic for both desktop and mobile
 pub fn setup_tauri_app(
@@ -14,7 +18,52 @@ pub fn setup_tauri_app(
 
     specta_builder.mount_events(app);
 
+    #[cfg(desktop)]
+    if let Err(e) = platform::desktop::migration::announce_legacy_data(app.handle()) {
+        log::warn!("Failed to look for legacy app data: {}", e);
+    }
+
+    if let Err(e) = app.state::<AppState>().path_scope.restore(app.handle()) {
+        log::warn!("Failed to restore authorized paths: {}", e);
+    }
+
+    if let Err(e) = TabEngineScheduler::new(app.state()).restore(app.handle()) {
+        log::warn!("Failed to restore tab engine policies: {}", e);
+    }
+
+    tauri::async_runtime::spawn(run_engine_queue(app.handle().clone()));
+
+    if let Err(e) = app.state::<AppState>().db_watcher.start(app.handle()) {
+        log::warn!("Failed to watch databases: {}", e);
+    }
+
+    if let Err(e) = purge_trash_on_startup(app.handle()) {
+        log::warn!("Failed to purge the database trash: {}", e);
+    }
+
     let _ = log::info!("Finished tauri application initialization");
     let _ = handle_initial_run_telemetry(&app.handle());
     Ok(())
 }
+
+/// Keep the app open while tabs have unsaved changes, whether it's quit or its main
+/// window is closed. The frontend is asked to confirm with `ConfirmExit` instead.
+pub fn handle_run_event(app: &AppHandle, event: RunEvent) {
+    match event {
+        RunEvent::ExitRequested { api, .. } => {
+            if intercept_exit(app) {
+                api.prevent_exit();
+            }
+        }
+        RunEvent::WindowEvent {
+            label,
+            event: WindowEvent::CloseRequested { api, .. },
+            ..
+        } if label == "main" => {
+            if intercept_exit(app) {
+                api.prevent_close();
+            }
+        }
+        _ => {}
+    }
+}
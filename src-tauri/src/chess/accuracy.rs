@@ -0,0 +1,696 @@
+//! Accuracy of analysed games.
+//!
+//! Move accuracy follows lichess: the drop in winning chances caused by each move, mapped
+//! onto a 0-100 scale, with mates counted as a thousand centipawns. Games are also split
+//! into phases, so reports show where the points were lost: the opening runs up to the last
+//! book position, and the endgame starts once the queens are off or little material is
+//! left.
+//!
+//! Centipawn loss weighs mistakes the same at every level, so moves are also judged by the
+//! expected points they lose at the rating of the player who made them, which gives an
+//! estimate of the rating the game was played at.
+
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+use shakmaty::{ByColor, Color, Material, Setup};
+use specta::Type;
+
+use crate::opening::find_opening_annotation;
+
+use super::cp_loss::GameCpLoss;
+use super::evaluation::{score_to_cp, EVAL_CAP};
+use super::swindle::GameSwindles;
+use super::types::{GameTermination, MoveAnalysis};
+
+/// Non-pawn material, in pawns for both sides together, below which the game is an endgame.
+const ENDGAME_MATERIAL: u32 = 26;
+
+/// Drops in winning chances, in percentage points, making a move an inaccuracy, a mistake
+/// or a blunder.
+const INACCURACY_WIN_CHANCE_DROP: f64 = 5.0;
+const MISTAKE_WIN_CHANCE_DROP: f64 = 10.0;
+const BLUNDER_WIN_CHANCE_DROP: f64 = 20.0;
+
+/// Steepness of the winning chances curve, per centipawn.
+const WIN_CHANCE_SCALE: f64 = 0.00368208;
+
+/// Rating assumed for players whose rating isn't known.
+pub const DEFAULT_RATING: u32 = 1500;
+
+/// Ratings the expected score model is defined for; others are clamped into it.
+const MODEL_RATINGS: RangeInclusive<u32> = 400..=3000;
+
+/// Rating at which the expected score matches the winning chances.
+const REFERENCE_RATING: f64 = 2000.0;
+
+/// Highest score, in points, a performance estimate is based on, as a perfect score has no
+/// finite performance.
+const MAX_PERFORMANCE_SCORE: f64 = 0.99;
+
+/// Accuracy of one player over part of a game.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct PlayerAccuracy {
+    /// Average move accuracy, from 0 to 100.
+    pub accuracy: f64,
+    /// Average centipawn loss.
+    pub acpl: f64,
+    pub blunders: u32,
+    /// Total expected points lost, at the player's rating.
+    pub expected_points_lost: f64,
+    /// Moves with evaluations on both sides; the other fields are zero without any.
+    pub moves: u32,
+}
+
+/// How bad a move was, by the winning chances it lost.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveJudgement {
+    Good,
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl MoveJudgement {
+    fn from_drop(drop: f64) -> Self {
+        if drop > BLUNDER_WIN_CHANCE_DROP {
+            MoveJudgement::Blunder
+        } else if drop > MISTAKE_WIN_CHANCE_DROP {
+            MoveJudgement::Mistake
+        } else if drop > INACCURACY_WIN_CHANCE_DROP {
+            MoveJudgement::Inaccuracy
+        } else {
+            MoveJudgement::Good
+        }
+    }
+}
+
+/// A move of a player and how it was judged.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct JudgedMove {
+    /// Moves played before it.
+    pub ply: u32,
+    pub judgement: MoveJudgement,
+    /// From 0 to 100.
+    pub accuracy: f64,
+    pub expected_points_lost: f64,
+}
+
+/// Accuracy of both players during a phase of the game.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct PhaseAccuracy {
+    /// First move of the phase, as the number of moves played before it.
+    pub start_ply: u32,
+    /// Moves played by the end of the phase.
+    pub end_ply: u32,
+    pub white: PlayerAccuracy,
+    pub black: PlayerAccuracy,
+}
+
+/// Phases are absent when the game has no moves in them, as short games skip the endgame.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GamePhases {
+    pub opening: Option<PhaseAccuracy>,
+    pub middlegame: Option<PhaseAccuracy>,
+    pub endgame: Option<PhaseAccuracy>,
+}
+
+/// Rating a player's moves were judged at, and the rating they played at.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct PlayerPerformance {
+    /// The player's rating in the game, or `DEFAULT_RATING`.
+    pub rating: u32,
+    /// Performance against the opponent's rating, scoring what's left of an even game once
+    /// both players lost their expected points.
+    pub estimate: i32,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GamePerformance {
+    pub white: PlayerPerformance,
+    pub black: PlayerPerformance,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GameAccuracy {
+    pub white: PlayerAccuracy,
+    pub black: PlayerAccuracy,
+    pub phases: GamePhases,
+    pub performance: GamePerformance,
+    /// Swindle chances of each player, when the analysis measured them.
+    pub swindles: Option<GameSwindles>,
+    /// Centipawn loss of each player's moves, in game analyses.
+    pub cp_loss: Option<GameCpLoss>,
+}
+
+/// Where the phases of a game begin, in moves played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseBoundaries {
+    /// Moves up to this ply are book moves.
+    pub opening_end: usize,
+    /// First position of the endgame, if the game got there.
+    pub endgame_start: Option<usize>,
+}
+
+impl PhaseBoundaries {
+    /// Ranges of plies of the opening, middlegame and endgame of a game of `plies` moves.
+    fn ranges(&self, plies: usize) -> [std::ops::Range<usize>; 3] {
+        let opening_end = self.opening_end.min(plies);
+        let endgame_start = self.endgame_start.unwrap_or(plies).min(plies);
+        [
+            0..opening_end,
+            opening_end..endgame_start,
+            endgame_start..plies,
+        ]
+    }
+}
+
+/// Whether a position belongs to the endgame, with the queens off or little material left.
+fn is_endgame(setup: &Setup) -> bool {
+    let material = setup.board.material();
+    let pieces = |side: &Material| {
+        3 * (side.knight + side.bishop) as u32 + 5 * side.rook as u32 + 9 * side.queen as u32
+    };
+    let queens = material.white.queen + material.black.queen;
+    queens == 0 || pieces(&material.white) + pieces(&material.black) <= ENDGAME_MATERIAL
+}
+
+/// Find the phases of a game, given the position after each of its moves with the start
+/// position first.
+///
+/// The endgame never starts before the opening ends, so book endgame lines are opening moves.
+pub fn detect_phases(positions: &[Setup]) -> PhaseBoundaries {
+    let opening_end = find_opening_annotation(positions).map_or(0, |opening| opening.ply as usize);
+    let endgame_start = positions
+        .iter()
+        .position(is_endgame)
+        .map(|start| start.max(opening_end));
+    PhaseBoundaries {
+        opening_end,
+        endgame_start,
+    }
+}
+
+/// Chances of winning, in percent, of the side a centipawn evaluation is for.
+fn win_chance(cp: i32) -> f64 {
+    50.0 + 50.0 * (2.0 / (1.0 + (-WIN_CHANCE_SCALE * cp as f64).exp()) - 1.0)
+}
+
+/// Expected score, from 0 to 1, of a player of `rating` with an evaluation of `cp` from
+/// their point of view.
+///
+/// This is the winning chances curve, steeper for stronger players and flatter for weaker
+/// ones in proportion to their rating, as an advantage is worth more to players who can
+/// convert it. It matches the winning chances at `REFERENCE_RATING`.
+pub fn expected_score(cp: i32, rating: u32) -> f64 {
+    let rating = rating.clamp(*MODEL_RATINGS.start(), *MODEL_RATINGS.end()) as f64;
+    let scale = WIN_CHANCE_SCALE * rating / REFERENCE_RATING;
+    1.0 / (1.0 + (-scale * cp as f64).exp())
+}
+
+/// Expected points a player of `rating` loses with a move changing the evaluation from
+/// `before` to `after`, both from their point of view. Moves never gain points.
+pub fn expected_points_lost(before: i32, after: i32, rating: u32) -> f64 {
+    (expected_score(before, rating) - expected_score(after, rating)).max(0.0)
+}
+
+/// Performance of a player who lost `lost` expected points against an opponent of
+/// `opponent_rating` who lost `opponent_lost`.
+///
+/// Both start from an even game, each point lost going to the other side, and the score
+/// left is turned into a rating difference with the Elo formula.
+pub fn performance_estimate(lost: f64, opponent_lost: f64, opponent_rating: u32) -> i32 {
+    let score =
+        (0.5 - lost + opponent_lost).clamp(1.0 - MAX_PERFORMANCE_SCORE, MAX_PERFORMANCE_SCORE);
+    (opponent_rating as f64 + 400.0 * (score / (1.0 - score)).log10()).round() as i32
+}
+
+/// Evaluation of a position from `color`'s point of view, capped at `EVAL_CAP`.
+///
+/// Positions without engine lines have no evaluation, unless the game is over there.
+fn evaluation(analysis: &MoveAnalysis, color: Color) -> Option<i32> {
+    let sign = if color == Color::White { 1 } else { -1 };
+    if let Some(termination) = analysis.termination {
+        // Only reached after `color`'s move, so a mate is theirs.
+        return Some(match termination {
+            GameTermination::Checkmate => EVAL_CAP,
+            _ => 0,
+        });
+    }
+    // Engine scores are from White's point of view.
+    Some(sign * score_to_cp(&analysis.best.first()?.score))
+}
+
+/// Running totals of a player's moves.
+#[derive(Default)]
+struct Totals {
+    accuracy: f64,
+    cp_loss: f64,
+    blunders: u32,
+    expected_points_lost: f64,
+    moves: u32,
+}
+
+impl Totals {
+    /// Add the move played at `ply`, returning how it was judged.
+    fn add(&mut self, ply: usize, before: i32, after: i32, rating: u32) -> JudgedMove {
+        let drop = win_chance(before) - win_chance(after);
+        let accuracy = (103.1668 * (-0.04354 * drop).exp() - 3.1669 + 1.0).clamp(0.0, 100.0);
+        let judgement = MoveJudgement::from_drop(drop);
+        let lost = expected_points_lost(before, after, rating);
+        self.accuracy += accuracy;
+        self.cp_loss += (before - after).max(0) as f64;
+        if judgement == MoveJudgement::Blunder {
+            self.blunders += 1;
+        }
+        self.expected_points_lost += lost;
+        self.moves += 1;
+        JudgedMove {
+            ply: ply as u32,
+            judgement,
+            accuracy,
+            expected_points_lost: lost,
+        }
+    }
+
+    fn finish(&self) -> PlayerAccuracy {
+        if self.moves == 0 {
+            return PlayerAccuracy::default();
+        }
+        PlayerAccuracy {
+            accuracy: self.accuracy / self.moves as f64,
+            acpl: self.cp_loss / self.moves as f64,
+            blunders: self.blunders,
+            expected_points_lost: self.expected_points_lost,
+            moves: self.moves,
+        }
+    }
+}
+
+/// Evaluations before and after the move played at `ply`, from the point of view of the
+/// player who made it.
+pub(super) fn move_evaluations(
+    positions: &[Setup],
+    analysis: &[MoveAnalysis],
+    ply: usize,
+) -> Option<(Color, i32, i32)> {
+    let color = positions[ply].turn;
+    let before = evaluation(&analysis[ply], color)?;
+    let after = evaluation(&analysis[ply + 1], color)?;
+    Some((color, before, after))
+}
+
+/// Accuracy of both players over the moves in `plies`.
+fn players_accuracy(
+    positions: &[Setup],
+    analysis: &[MoveAnalysis],
+    plies: std::ops::Range<usize>,
+    ratings: ByColor<u32>,
+) -> (PlayerAccuracy, PlayerAccuracy) {
+    let mut white = Totals::default();
+    let mut black = Totals::default();
+    for ply in plies {
+        let Some((color, before, after)) = move_evaluations(positions, analysis, ply) else {
+            continue;
+        };
+        let rating = *ratings.get(color);
+        match color {
+            Color::White => white.add(ply, before, after, rating),
+            Color::Black => black.add(ply, before, after, rating),
+        };
+    }
+    (white.finish(), black.finish())
+}
+
+/// Accuracy of `color`'s moves so far and how each of them was judged, given the positions
+/// with the start position first and the analysis of those evaluated yet.
+///
+/// Moves are added up as `game_accuracy` does, so a game followed move by move ends with
+/// the accuracy its report gives at the same depth.
+pub fn judge_moves(
+    positions: &[Setup],
+    analysis: &[MoveAnalysis],
+    color: Color,
+    rating: u32,
+) -> (PlayerAccuracy, Vec<JudgedMove>) {
+    let plies = positions.len().min(analysis.len()).saturating_sub(1);
+    let mut totals = Totals::default();
+    let mut judged = Vec::new();
+    for ply in 0..plies {
+        let Some((mover, before, after)) = move_evaluations(positions, analysis, ply) else {
+            continue;
+        };
+        if mover == color {
+            judged.push(totals.add(ply, before, after, rating));
+        }
+    }
+    (totals.finish(), judged)
+}
+
+/// Record on each position the expected points lost by the move leading to it, given the
+/// positions with the start position first and the analysis of each of them.
+pub fn annotate_expected_points(
+    positions: &[Setup],
+    analysis: &mut [MoveAnalysis],
+    ratings: ByColor<u32>,
+) {
+    let plies = positions.len().min(analysis.len()).saturating_sub(1);
+    for ply in 0..plies {
+        analysis[ply + 1].expected_points_lost = move_evaluations(positions, analysis, ply)
+            .map(|(color, before, after)| expected_points_lost(before, after, *ratings.get(color)));
+    }
+}
+
+/// Compute the accuracy of an analysed game, given its positions with the start position
+/// first, the analysis of each of them and the players' ratings.
+pub fn game_accuracy(
+    positions: &[Setup],
+    analysis: &[MoveAnalysis],
+    ratings: ByColor<u32>,
+) -> GameAccuracy {
+    let len = positions.len().min(analysis.len());
+    let plies = len.saturating_sub(1);
+    let (positions, analysis) = (&positions[..len], &analysis[..len]);
+
+    let (white, black) = players_accuracy(positions, analysis, 0..plies, ratings);
+    let [opening, middlegame, endgame] =
+        detect_phases(positions)
+            .ranges(plies)
+            .map(|range| -> Option<PhaseAccuracy> {
+                if range.is_empty() {
+                    return None;
+                }
+                let (white, black) = players_accuracy(positions, analysis, range.clone(), ratings);
+                Some(PhaseAccuracy {
+                    start_ply: range.start as u32,
+                    end_ply: range.end as u32,
+                    white,
+                    black,
+                })
+            });
+
+    let performance = GamePerformance {
+        white: PlayerPerformance {
+            rating: ratings.white,
+            estimate: performance_estimate(
+                white.expected_points_lost,
+                black.expected_points_lost,
+                ratings.black,
+            ),
+        },
+        black: PlayerPerformance {
+            rating: ratings.black,
+            estimate: performance_estimate(
+                black.expected_points_lost,
+                white.expected_points_lost,
+                ratings.white,
+            ),
+        },
+    };
+
+    GameAccuracy {
+        white,
+        black,
+        phases: GamePhases {
+            opening,
+            middlegame,
+            endgame,
+        },
+        performance,
+        swindles: None,
+        cp_loss: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, san::San, Chess, EnPassantMode, Position};
+    use vampirc_uci::uci::{Score, ScoreValue};
+
+    use crate::chess::types::BestMoves;
+
+    const UNRATED: ByColor<u32> = ByColor {
+        white: DEFAULT_RATING,
+        black: DEFAULT_RATING,
+    };
+
+    fn positions(fen: Option<&str>, san: &str) -> Vec<Setup> {
+        let mut chess: Chess = match fen {
+            Some(fen) => fen
+                .parse::<Fen>()
+                .unwrap()
+                .into_position(shakmaty::CastlingMode::Standard)
+                .unwrap(),
+            None => Chess::default(),
+        };
+        let mut positions = vec![chess.clone().into_setup(EnPassantMode::Legal)];
+        for san in san.split_whitespace() {
+            let m = san.parse::<San>().unwrap().to_move(&chess).unwrap();
+            chess.play_unchecked(&m);
+            positions.push(chess.clone().into_setup(EnPassantMode::Legal));
+        }
+        positions
+    }
+
+    fn analysis(value: ScoreValue) -> MoveAnalysis {
+        MoveAnalysis {
+            best: vec![BestMoves {
+                score: Score {
+                    value,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn cp(evals: &[i32]) -> Vec<MoveAnalysis> {
+        evals
+            .iter()
+            .map(|&cp| analysis(ScoreValue::Cp(cp)))
+            .collect()
+    }
+
+    #[test]
+    fn book_moves_are_the_opening() {
+        // Ruy Lopez, then out of book.
+        let game = positions(None, "e4 e5 Nf3 Nc6 Bb5 a6 h4 h5");
+        let phases = detect_phases(&game);
+        assert_eq!(phases.opening_end, 6);
+        assert_eq!(phases.endgame_start, None);
+        assert_eq!(phases.ranges(8), [0..6, 6..8, 8..8]);
+
+        let custom = positions(
+            Some("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3"),
+            "Bb5 a6",
+        );
+        assert_eq!(detect_phases(&custom).opening_end, 0);
+    }
+
+    #[test]
+    fn trading_queens_starts_the_endgame() {
+        let game = positions(None, "d4 d5 c4 e6 Nc3 Nf6 Bg5 Be7 e3 O-O Qb3 Qd6 Qb6 Qxb6");
+        let phases = detect_phases(&game);
+        assert_eq!(phases.endgame_start, None);
+
+        let game = positions(
+            Some("r1b1k2r/ppq2ppp/n7/8/8/8/PPQ2PPP/R1B1K2R w KQkq - 0 12"),
+            "Qxc7 Nxc7 Bd2",
+        );
+        let phases = detect_phases(&game);
+        assert_eq!(phases.opening_end, 0);
+        assert_eq!(phases.endgame_start, Some(2));
+        assert_eq!(phases.ranges(3), [0..0, 0..2, 2..3]);
+    }
+
+    #[test]
+    fn little_material_is_an_endgame_even_with_queens() {
+        let queens_and_rooks = positions(Some("3qk3/3r4/8/8/8/8/3R4/3QK3 w - - 0 40"), "");
+        assert!(!is_endgame(&queens_and_rooks[0]));
+        let queens_and_minors = positions(Some("3qk3/3n4/8/8/8/8/3N4/3QK3 w - - 0 40"), "");
+        assert!(is_endgame(&queens_and_minors[0]));
+    }
+
+    #[test]
+    fn accuracy_is_split_by_phase() {
+        // The Blackburne-Kostić Gambit, White taking the bait and losing the queen trade.
+        let game = positions(
+            None,
+            "e4 e5 Nf3 Nc6 Bc4 Nd4 Nxe5 Qg5 Nxf7 Qxg2 Rf1 Qxe4+ Qe2 Qxe2+ Bxe2 Nxe2 Kxe2",
+        );
+        let phases = detect_phases(&game);
+        assert_eq!(phases.opening_end, 6);
+        assert_eq!(phases.endgame_start, Some(15));
+
+        let evals = cp(&[
+            20, 30, 25, 30, 25, 40, 30, -300, -320, -330, -600, -620, -900, -900, -950, -950, -950,
+            -950,
+        ]);
+        let accuracy = game_accuracy(&game, &evals, UNRATED);
+        assert_eq!(accuracy.white.moves, 9);
+        assert_eq!(accuracy.black.moves, 8);
+        assert_eq!(accuracy.white.blunders, 1);
+        assert_eq!(accuracy.black.blunders, 0);
+        assert!(accuracy.white.accuracy < accuracy.black.accuracy);
+
+        let opening = accuracy.phases.opening.unwrap();
+        assert_eq!((opening.start_ply, opening.end_ply), (0, 6));
+        assert_eq!((opening.white.moves, opening.black.moves), (3, 3));
+        assert_eq!(opening.white.acpl, 0.0);
+
+        let middlegame = accuracy.phases.middlegame.unwrap();
+        assert_eq!((middlegame.start_ply, middlegame.end_ply), (6, 15));
+        assert_eq!(middlegame.white.blunders, 1);
+        assert_eq!(middlegame.white.acpl, 72.0);
+
+        let endgame = accuracy.phases.endgame.unwrap();
+        assert_eq!((endgame.start_ply, endgame.end_ply), (15, 17));
+        assert_eq!((endgame.white.moves, endgame.black.moves), (1, 1));
+        assert_eq!(endgame.white.acpl, 0.0);
+    }
+
+    #[test]
+    fn moves_judged_one_by_one_add_up_to_the_report() {
+        let game = positions(
+            None,
+            "e4 e5 Nf3 Nc6 Bc4 Nd4 Nxe5 Qg5 Nxf7 Qxg2 Rf1 Qxe4+ Qe2 Qxe2+ Bxe2 Nxe2 Kxe2",
+        );
+        let evals = cp(&[
+            20, 30, 25, 30, 25, 40, 30, -300, -320, -330, -600, -620, -900, -900, -950, -950, -950,
+            -950,
+        ]);
+        let report = game_accuracy(&game, &evals, UNRATED);
+
+        // As during the game, with the positions evaluated so far.
+        let mut judged_so_far = 0;
+        for evaluated in 1..=game.len() {
+            let (_, judged) = judge_moves(&game, &evals[..evaluated], Color::White, DEFAULT_RATING);
+            assert!(judged.len() >= judged_so_far);
+            judged_so_far = judged.len();
+        }
+        let (white, judged) = judge_moves(&game, &evals, Color::White, DEFAULT_RATING);
+        assert_eq!(white, report.white);
+        assert_eq!(judged.len(), 9);
+        assert!(judged.iter().all(|m| m.ply % 2 == 0));
+        let judgement = |ply| judged.iter().find(|m| m.ply == ply).unwrap().judgement;
+        assert_eq!(judgement(0), MoveJudgement::Good);
+        assert_eq!(judgement(6), MoveJudgement::Blunder);
+        assert_eq!(judgement(10), MoveJudgement::Mistake);
+        assert_eq!(
+            judge_moves(&game, &evals, Color::Black, DEFAULT_RATING).0,
+            report.black
+        );
+    }
+
+    #[test]
+    fn mates_and_missing_evaluations() {
+        let game = positions(None, "f3 e5 g4 Qh4#");
+        let mut evals = cp(&[0, -50, -60]);
+        evals.push(analysis(ScoreValue::Mate(-1)));
+        evals.push(MoveAnalysis {
+            termination: Some(GameTermination::Checkmate),
+            ..Default::default()
+        });
+        let accuracy = game_accuracy(&game, &evals, UNRATED);
+        assert_eq!(accuracy.white.blunders, 1);
+        assert_eq!(accuracy.black.moves, 2);
+        assert_eq!(accuracy.black.acpl, 0.0);
+
+        // Positions the engine didn't evaluate leave their moves out.
+        let evals = vec![MoveAnalysis::default(), analysis(ScoreValue::Cp(0))];
+        let accuracy = game_accuracy(&positions(None, "e4"), &evals, UNRATED);
+        assert_eq!(accuracy.white, PlayerAccuracy::default());
+        assert_eq!(accuracy.phases.opening.unwrap().white.moves, 0);
+        assert_eq!(accuracy.phases.middlegame, None);
+    }
+
+    #[test]
+    fn expected_score_depends_on_the_rating() {
+        let table = [
+            (0, 1500, 0.5),
+            (100, 1100, 0.5505),
+            (100, 2000, 0.591),
+            (100, 2700, 0.6218),
+            (-300, 1500, 0.304),
+            // Ratings outside the model are clamped.
+            (1000, 3000, 0.996),
+            (1000, 3200, 0.996),
+        ];
+        for (cp, rating, score) in table {
+            assert!(
+                (expected_score(cp, rating) - score).abs() < 1e-4,
+                "{cp} at {rating}"
+            );
+        }
+        let reference = expected_score(100, REFERENCE_RATING as u32);
+        assert!((reference - win_chance(100) / 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn expected_points_lost_and_performance() {
+        let table = [
+            // The same mistake costs a strong player more.
+            (30, 0, 1100, 0.0152),
+            (30, 0, 2700, 0.0372),
+            (0, -300, 1500, 0.196),
+            (-500, -800, 2000, 0.087),
+            // Improving on the evaluation doesn't make up for other moves.
+            (-100, 50, 1500, 0.0),
+        ];
+        for (before, after, rating, lost) in table {
+            assert!(
+                (expected_points_lost(before, after, rating) - lost).abs() < 1e-4,
+                "{before} to {after} at {rating}"
+            );
+        }
+
+        let table = [
+            (0.1, 0.1, 1800, 1800),
+            (0.3, 0.05, 2000, 1809),
+            (0.0, 2.0, 1500, 2298),
+            (2.0, 0.0, 1500, 702),
+        ];
+        for (lost, opponent_lost, opponent_rating, estimate) in table {
+            assert_eq!(
+                performance_estimate(lost, opponent_lost, opponent_rating),
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn moves_are_judged_at_the_players_rating() {
+        let game = positions(None, "e4 e5 Nf3 Nc6");
+        let mut evals = cp(&[0, 0, 0, -100, -100]);
+        let ratings = ByColor {
+            white: 2700,
+            black: 1100,
+        };
+        let accuracy = game_accuracy(&game, &evals, ratings);
+        assert!((accuracy.white.expected_points_lost - 0.1218).abs() < 1e-4);
+        assert_eq!(accuracy.black.expected_points_lost, 0.0);
+        assert_eq!(
+            accuracy.performance,
+            GamePerformance {
+                white: PlayerPerformance {
+                    rating: 2700,
+                    estimate: 1014,
+                },
+                black: PlayerPerformance {
+                    rating: 1100,
+                    estimate: 2786,
+                },
+            }
+        );
+
+        annotate_expected_points(&game, &mut evals, ratings);
+        assert_eq!(evals[0].expected_points_lost, None);
+        assert_eq!(evals[1].expected_points_lost, Some(0.0));
+        assert!((evals[3].expected_points_lost.unwrap() - 0.1218).abs() < 1e-4);
+        assert_eq!(evals[4].expected_points_lost, Some(0.0));
+    }
+}
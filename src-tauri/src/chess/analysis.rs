@@ -3,20 +3,643 @@
 //! This module provides the `GameAnalysisService` struct, which exposes methods to analyze chess games move-by-move using a UCI-compatible engine.
 //! It integrates with the database for novelty detection and annotates sacrifices, supporting progress reporting for UI updates.
 
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, EnPassantMode, Position};
+use log::{info, warn};
+use shakmaty::{
+    fen::Fen, uci::UciMove, ByColor, CastlingMode, Chess, EnPassantMode, Move, Position,
+};
 use vampirc_uci::parse_one;
 
 use crate::db::{is_position_in_db, GameQueryJs, PositionQueryJs};
 use crate::error::Error;
+use crate::opening::find_opening_annotation;
+use crate::scope::PathScope;
+use crate::tasks::{TaskHandle, TaskKind};
 use crate::AppState;
 
-use super::evaluation::naive_eval;
+use super::accuracy::{annotate_expected_points, game_accuracy, DEFAULT_RATING};
+use super::budget::{AdaptiveScheduler, DepthSample};
+use super::consensus::{merge_consensus, DEFAULT_DISAGREEMENT_CP, MAX_CONSENSUS_ENGINES};
+use super::cp_loss::{annotate_cp_loss, CpLossThresholds};
+use super::evaluation::score_to_cp;
+use super::evaluation::{game_termination, naive_eval};
+use super::only_move::{annotate_only_moves, OnlyMoveThresholds};
 use super::process::{parse_uci_attrs, EngineProcess};
-use super::types::{AnalysisOptions, EngineOption, MoveAnalysis, ReportProgress};
+use super::swindle::{
+    game_swindles, is_lost, plausible_replies, resourcefulness, stalemating_replies,
+    SwindleChances, DEFAULT_SWINDLE_LOSING_CP, SWINDLE_DEPTH, SWINDLE_REPLIES,
+};
+use super::types::{
+    AnalysisOptions, BestMoves, EngineLines, EngineOption, EngineOptions, GameAnalysis,
+    GameTermination, GoMode, MoveAnalysis, ReportProgress,
+};
+use super::uci::EngineStdout;
 use tauri_specta::Event;
 
+/// A position of the analysed game, identified by the number of game moves leading to it so
+/// that positions share the game's move list instead of each holding a copy.
+#[derive(Debug)]
+pub struct AnalysisPosition {
+    pub fen: Fen,
+    /// The position follows the first `ply` moves of the game.
+    pub ply: usize,
+    pub is_sacrifice: bool,
+    pub termination: Option<GameTermination>,
+}
+
+/// Build the positions of a game to analyse, tracking sacrifices and stopping at the first
+/// position where the game is over or after `max_ply` moves.
+///
+/// Also returns whether moves were left out because of `max_ply`.
+pub fn build_analysis_positions(
+    fen: &Fen,
+    moves: &[String],
+    max_ply: Option<usize>,
+) -> Result<(Vec<AnalysisPosition>, bool), Error> {
+    let limit = max_ply.map_or(moves.len(), |max| max.min(moves.len()));
+    let mut chess: Chess = fen.clone().into_position(CastlingMode::Chess960)?;
+    // Repetitions only reach back to the last capture or pawn move, so older positions are
+    // dropped to keep termination checks cheap in long games.
+    let mut history = vec![chess.clone()];
+    let mut positions = Vec::with_capacity(limit + 1);
+    positions.push(AnalysisPosition {
+        fen: fen.clone(),
+        ply: 0,
+        is_sacrifice: false,
+        termination: game_termination(&history),
+    });
+
+    for (i, m) in moves[..limit].iter().enumerate() {
+        if positions.last().is_some_and(|p| p.termination.is_some()) {
+            break;
+        }
+        let uci = UciMove::from_ascii(m.as_bytes())?;
+        let m = uci.to_move(&chess)?;
+        let previous_pos = chess.clone();
+        chess.play_unchecked(&m);
+        if chess.halfmoves() == 0 {
+            history.clear();
+        }
+        history.push(chess.clone());
+        let termination = game_termination(&history);
+        // Detect sacrifices by comparing naive evals before and after the move.
+        let is_sacrifice = termination.is_none() && {
+            let prev_eval = naive_eval(&previous_pos);
+            let cur_eval = -naive_eval(&chess);
+            prev_eval > cur_eval + 100 // Mark as sacrifice if eval drops by > 100.
+        };
+        positions.push(AnalysisPosition {
+            fen: Fen::from_position(chess.clone(), EnPassantMode::Legal),
+            ply: i + 1,
+            is_sacrifice,
+            termination,
+        });
+    }
+
+    let truncated =
+        limit < moves.len() && positions.last().is_some_and(|p| p.termination.is_none());
+    Ok((positions, truncated))
+}
+
+/// Share of the progress bar taken by the first pass of an adaptive analysis.
+const FIRST_PASS_PROGRESS: f64 = 50.0;
+
+/// Search a position, returning the lines of the deepest complete MultiPV set along with a
+/// sample of each depth the engine completed.
+pub(super) async fn search_position(
+    proc: &mut EngineProcess,
+    reader: &mut EngineStdout,
+    options: EngineOptions,
+    go_mode: &GoMode,
+) -> Result<(Vec<BestMoves>, Vec<DepthSample>), Error> {
+    search_position_moves(proc, reader, options, go_mode, &[]).await
+}
+
+/// Search a position like `search_position`, considering only `searchmoves` if any are given.
+pub(super) async fn search_position_moves(
+    proc: &mut EngineProcess,
+    reader: &mut EngineStdout,
+    options: EngineOptions,
+    go_mode: &GoMode,
+    searchmoves: &[String],
+) -> Result<(Vec<BestMoves>, Vec<DepthSample>), Error> {
+    let (best, samples, _) =
+        search_lines(proc, reader, options, go_mode, searchmoves, &|| false).await?;
+    Ok((best, samples))
+}
+
+/// How long an engine may stay silent in the middle of a search before it is taken for hung.
+const ENGINE_SILENCE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time between two checks for cancellation while an engine searches.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// Search a position, returning its lines and depth samples along with whether the engine
+/// ended the search with its best move, rather than going away or silent.
+///
+/// Once `is_cancelled` returns true the search is stopped, and the lines found until then
+/// are returned when the engine answers.
+async fn search_lines(
+    proc: &mut EngineProcess,
+    reader: &mut EngineStdout,
+    options: EngineOptions,
+    go_mode: &GoMode,
+    searchmoves: &[String],
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+) -> Result<(Vec<BestMoves>, Vec<DepthSample>, bool), Error> {
+    proc.set_options(options).await?;
+    proc.go_searchmoves(go_mode, searchmoves).await?;
+
+    let mut best = Vec::new();
+    let mut samples: Vec<DepthSample> = Vec::new();
+    let mut answered = false;
+    let mut stopped = false;
+    let mut last_line = Instant::now();
+    loop {
+        if !stopped && is_cancelled() {
+            proc.stop().await?;
+            stopped = true;
+        }
+        let line = match tokio::time::timeout(CANCEL_POLL, reader.next_line()).await {
+            Err(_) if last_line.elapsed() < ENGINE_SILENCE_TIMEOUT => continue,
+            Ok(Ok(Some(line))) => line,
+            _ => break,
+        };
+        last_line = Instant::now();
+        match parse_one(&line) {
+            vampirc_uci::UciMessage::Info(attrs) => {
+                if let Ok(best_moves) =
+                    parse_uci_attrs(attrs, &proc.options.fen.parse()?, &proc.options.moves)
+                {
+                    let multipv = best_moves.multipv;
+                    let cur_depth = best_moves.depth;
+                    if multipv as usize == proc.best_moves.len() + 1 {
+                        proc.best_moves.push(best_moves);
+                        if multipv == proc.real_multipv {
+                            if proc.best_moves.iter().all(|x| x.depth == cur_depth)
+                                && cur_depth >= proc.last_depth
+                            {
+                                best = proc.best_moves.clone();
+                                if samples.last().is_none_or(|sample| sample.depth < cur_depth) {
+                                    samples.push(DepthSample::from_line(&best[0]));
+                                }
+                                proc.last_depth = cur_depth;
+                            }
+                            assert_eq!(proc.best_moves.len(), proc.real_multipv as usize);
+                            proc.best_moves.clear();
+                        }
+                    }
+                }
+            }
+            vampirc_uci::UciMessage::BestMove { .. } => {
+                answered = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok((best, samples, answered))
+}
+
+/// Restarts an engine may need over a whole game analysis before it is given up on.
+const MAX_ENGINE_RESTARTS: u32 = 3;
+
+/// An engine of a game analysis, kept running from one position to the next.
+struct AnalysisEngine<'a> {
+    name: String,
+    /// Paths the engine must be in, checked again on every restart.
+    scope: &'a PathScope,
+    proc: EngineProcess,
+    reader: EngineStdout,
+    /// Times the engine was started, the first included.
+    spawns: u32,
+}
+
+impl<'a> AnalysisEngine<'a> {
+    async fn start(name: String, scope: &'a PathScope) -> Result<Self, Error> {
+        let (proc, reader) = EngineProcess::new(PathBuf::from(&name), scope).await?;
+        Ok(Self {
+            name,
+            scope,
+            proc,
+            reader,
+            spawns: 1,
+        })
+    }
+
+    /// Replace a crashed or hung engine with a new process.
+    async fn restart(&mut self) -> Result<(), Error> {
+        if self.spawns > MAX_ENGINE_RESTARTS {
+            return Err(Error::EngineTimeout(format!(
+                "{} stopped answering {} times",
+                self.name, self.spawns
+            )));
+        }
+        let _ = self.proc.kill().await;
+        let (proc, reader) = EngineProcess::new(PathBuf::from(&self.name), self.scope).await?;
+        self.proc = proc;
+        self.reader = reader;
+        self.spawns += 1;
+        Ok(())
+    }
+
+    /// Search a position, trying again once on a new process if the engine stops answering.
+    /// A position it can't search either way gets no lines, and a search cancelled with
+    /// `is_cancelled` is stopped and not tried again.
+    async fn search(
+        &mut self,
+        options: EngineOptions,
+        go_mode: &GoMode,
+        is_cancelled: &(dyn Fn() -> bool + Sync),
+    ) -> Result<(Vec<BestMoves>, Vec<DepthSample>), Error> {
+        for _ in 0..2 {
+            let search = search_lines(
+                &mut self.proc,
+                &mut self.reader,
+                options.clone(),
+                go_mode,
+                &[],
+                is_cancelled,
+            )
+            .await;
+            match search {
+                Ok((best, samples, true)) => return Ok((best, samples)),
+                _ if is_cancelled() => break,
+                Ok(_) => warn!("{} stopped answering, restarting it", self.name),
+                Err(e) => warn!("{} failed: {}, restarting it", self.name, e),
+            }
+            self.restart().await?;
+        }
+        Ok((Vec::new(), Vec::new()))
+    }
+}
+
+/// Search a position with each engine in turn, calling `on_engine` before each engine after
+/// the first with the share of the engines already done.
+///
+/// Returns the lines of every engine along with the depth samples of the first one. Once
+/// `is_cancelled` returns true the running search is stopped and the engines after it are
+/// skipped, so the lines are only of use when it doesn't.
+async fn search_engines(
+    engines: &mut [AnalysisEngine<'_>],
+    options: EngineOptions,
+    go_mode: &GoMode,
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+    mut on_engine: impl FnMut(f64) -> Result<(), Error>,
+) -> Result<(Vec<EngineLines>, Vec<DepthSample>), Error> {
+    let count = engines.len();
+    let mut lines = Vec::with_capacity(count);
+    let mut first_samples = Vec::new();
+    for (i, engine) in engines.iter_mut().enumerate() {
+        if is_cancelled() {
+            break;
+        }
+        if i > 0 {
+            on_engine(i as f64 / count as f64)?;
+        }
+        let (best, samples) = engine
+            .search(options.clone(), go_mode, is_cancelled)
+            .await?;
+        if i == 0 {
+            first_samples = samples;
+        }
+        lines.push(EngineLines {
+            engine: engine.name.clone(),
+            best,
+        });
+    }
+    Ok((lines, first_samples))
+}
+
+async fn kill_engines(engines: &mut [AnalysisEngine<'_>]) -> Result<(), Error> {
+    for engine in engines {
+        engine.proc.kill().await?;
+    }
+    Ok(())
+}
+
+/// The analysis of a position from the lines of each engine: those of a single engine as
+/// they are, or the consensus of several.
+fn engine_analysis(mut lines: Vec<EngineLines>, disagreement_cp: i32) -> MoveAnalysis {
+    if lines.len() == 1 {
+        return MoveAnalysis {
+            best: lines.pop().map(|lines| lines.best).unwrap_or_default(),
+            ..Default::default()
+        };
+    }
+    let consensus = merge_consensus(&lines, disagreement_cp);
+    MoveAnalysis {
+        best: consensus.best,
+        engines_disagree: consensus.engines_disagree,
+        engine_lines: lines,
+        ..Default::default()
+    }
+}
+
+/// Evaluations after each plausible reply to `m`, played as `uci` in `position`, from the
+/// opponent's point of view. Each reply is searched on its own with `searchmoves`.
+async fn search_replies(
+    engine: &mut AnalysisEngine<'_>,
+    position: &Chess,
+    options: &EngineOptions,
+    uci: &str,
+    m: &Move,
+) -> Result<Vec<i32>, Error> {
+    let mut after = position.clone();
+    after.play_unchecked(m);
+    let mut moves = options.moves.clone();
+    moves.push(uci.to_string());
+    let options = EngineOptions {
+        moves,
+        ..options.clone()
+    };
+    // Engine scores are from White's point of view.
+    let sign = if after.turn().is_white() { 1 } else { -1 };
+    let mut cps = Vec::new();
+    for reply in plausible_replies(&after, SWINDLE_REPLIES) {
+        let reply = reply.to_uci(CastlingMode::Standard).to_string();
+        let (lines, _) = search_position_moves(
+            &mut engine.proc,
+            &mut engine.reader,
+            options.clone(),
+            &GoMode::Depth(SWINDLE_DEPTH),
+            &[reply],
+        )
+        .await?;
+        if let Some(line) = lines.first() {
+            cps.push(sign * score_to_cp(&line.score));
+        }
+    }
+    Ok(cps)
+}
+
+/// Swindle chances of `played` in the lost `position`, compared with the first moves of the
+/// engine's `lines`. `options` set up `position` on the engine.
+async fn swindle_chances(
+    engine: &mut AnalysisEngine<'_>,
+    position: &Chess,
+    options: &EngineOptions,
+    played: &str,
+    lines: &[BestMoves],
+    losing_cp: i32,
+) -> Result<Option<SwindleChances>, Error> {
+    let parse = |uci: &str| {
+        UciMove::from_ascii(uci.as_bytes())
+            .ok()?
+            .to_move(position)
+            .ok()
+    };
+    let Some(played_move) = parse(played) else {
+        return Ok(None);
+    };
+    let cps = search_replies(engine, position, options, played, &played_move).await?;
+    if cps.is_empty() {
+        return Ok(None);
+    }
+    let (score, throwaways) = resourcefulness(&cps, losing_cp);
+
+    let mut best_try = true;
+    for uci in lines.iter().filter_map(|line| line.uci_moves.first()) {
+        let Some(m) = parse(uci.as_str()).filter(|m| *m != played_move) else {
+            continue;
+        };
+        let cps = search_replies(engine, position, options, uci, &m).await?;
+        if resourcefulness(&cps, losing_cp).0 > score {
+            best_try = false;
+            break;
+        }
+    }
+
+    let mut after = position.clone();
+    after.play_unchecked(&played_move);
+    Ok(Some(SwindleChances {
+        resourcefulness: score,
+        replies: cps.len() as u32,
+        throwaways,
+        stalemates: stalemating_replies(&after),
+        best_try,
+    }))
+}
+
+/// Search the positions of a game with `engines`: each position in turn, then for adaptive
+/// analyses the most interesting ones again, deeper, and then the swindle chances of the lost
+/// ones. `report` is called with the progress along the way.
+///
+/// Once `is_cancelled` returns true the running search is stopped and the engines are killed,
+/// leaving out the positions not searched yet.
+async fn search_game(
+    engines: &mut [AnalysisEngine<'_>],
+    positions: &[AnalysisPosition],
+    go_mode: &GoMode,
+    options: &AnalysisOptions,
+    uci_options: &[EngineOption],
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+    mut report: impl FnMut(f64) -> Result<(), Error>,
+) -> Result<GameAnalysis, Error> {
+    let disagreement_cp = options.disagreement_cp.unwrap_or(DEFAULT_DISAGREEMENT_CP);
+    let mut analysis: Vec<MoveAnalysis> = Vec::new();
+
+    // Ensure MultiPV=2 for principal variation analysis.
+    let mut extra_options = uci_options.to_vec();
+    if !extra_options.iter().any(|x| x.name == "MultiPV") {
+        extra_options.push(EngineOption {
+            name: "MultiPV".to_string(),
+            value: "2".to_string(),
+        });
+    } else {
+        extra_options.iter_mut().for_each(|x| {
+            if x.name == "MultiPV" {
+                x.value = "2".to_string();
+            }
+        });
+    }
+    let engine_options = |ply: usize| EngineOptions {
+        fen: options.fen.clone(),
+        moves: options.moves[..ply].to_vec(),
+        extra_options: extra_options.clone(),
+    };
+
+    // Adaptive analyses first search every position to the initial depth.
+    let mut scheduler = options.adaptive.clone().map(|config| {
+        let mut sacrifices = vec![false; positions.len()];
+        for position in positions {
+            sacrifices[position.ply] = position.is_sacrifice;
+        }
+        AdaptiveScheduler::new(config, positions.len(), sacrifices)
+    });
+    let first_go_mode = match &scheduler {
+        Some(scheduler) => GoMode::Depth(scheduler.initial_depth()),
+        None => go_mode.clone(),
+    };
+    let first_pass_share = if scheduler.is_some() {
+        FIRST_PASS_PROGRESS
+    } else {
+        100.0
+    };
+    let started = Instant::now();
+    let mut cancelled = false;
+
+    // Analyze each position using the engine, reporting progress.
+    for (i, position) in positions.iter().enumerate() {
+        if is_cancelled() {
+            kill_engines(engines).await?;
+            cancelled = true;
+            break;
+        }
+        let progress = (i as f64 / positions.len() as f64) * first_pass_share;
+        report(progress)?;
+
+        // Decided positions carry their result rather than a meaningless engine eval.
+        if position.termination.is_some() {
+            analysis.push(MoveAnalysis {
+                termination: position.termination,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        // Lines found beforehand stand in for the shallow search of an adaptive analysis.
+        let known = options
+            .first_pass
+            .get(position.ply)
+            .and_then(|lines| lines.as_ref())
+            .filter(|lines| !lines.is_empty());
+        if let (Some(scheduler), Some(best)) = (scheduler.as_mut(), known) {
+            scheduler.record(position.ply, vec![DepthSample::from_line(&best[0])], 0);
+            analysis.push(MoveAnalysis {
+                best: best.clone(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        // Each engine takes its share of the position's progress.
+        let search_started = Instant::now();
+        let (lines, samples) = search_engines(
+            engines,
+            engine_options(position.ply),
+            &first_go_mode,
+            is_cancelled,
+            |share| report(((i as f64 + share) / positions.len() as f64) * first_pass_share),
+        )
+        .await?;
+        // The search was stopped before its end, so its lines are left out.
+        if is_cancelled() {
+            kill_engines(engines).await?;
+            cancelled = true;
+            break;
+        }
+        if let Some(scheduler) = scheduler.as_mut() {
+            let elapsed = search_started.elapsed().as_millis() as u64;
+            scheduler.record(position.ply, samples, elapsed);
+        }
+        analysis.push(engine_analysis(lines, disagreement_cp));
+    }
+
+    // Then deepen the most interesting positions while the budget lasts.
+    if let Some(scheduler) = scheduler.as_mut().filter(|_| !cancelled) {
+        while let Some((ply, depth)) = scheduler.next(started.elapsed().as_millis() as u64) {
+            if is_cancelled() {
+                kill_engines(engines).await?;
+                cancelled = true;
+                break;
+            }
+            let progress = first_pass_share
+                + scheduler.progress(started.elapsed().as_millis() as u64)
+                    * (100.0 - first_pass_share);
+            report(progress)?;
+
+            // The budget covers the searches of every engine.
+            let search_started = Instant::now();
+            let (lines, samples) = search_engines(
+                engines,
+                engine_options(ply),
+                &GoMode::Depth(depth),
+                is_cancelled,
+                |_| Ok(()),
+            )
+            .await?;
+            if is_cancelled() {
+                kill_engines(engines).await?;
+                cancelled = true;
+                break;
+            }
+            scheduler.record(ply, samples, search_started.elapsed().as_millis() as u64);
+            let deepened = engine_analysis(lines, disagreement_cp);
+            let i = positions.iter().position(|p| p.ply == ply);
+            if let (Some(i), false) = (i, deepened.best.is_empty()) {
+                analysis[i].best = deepened.best;
+                analysis[i].engines_disagree = deepened.engines_disagree;
+                analysis[i].engine_lines = deepened.engine_lines;
+            }
+        }
+    }
+    for analysis in analysis.iter_mut() {
+        analysis.depth = analysis.best.first().map(|line| line.depth);
+    }
+
+    // Then measure the swindle chances of lost positions, within what is left of the
+    // time budget of adaptive analyses.
+    if options.swindle_chances && !cancelled {
+        let deadline = options
+            .adaptive
+            .as_ref()
+            .map(|config| started + Duration::from_millis(config.time_budget_ms.into()));
+        let losing_cp = options
+            .swindle_losing_cp
+            .unwrap_or(DEFAULT_SWINDLE_LOSING_CP);
+        // Replies are searched one at a time.
+        let mut reply_options = extra_options.clone();
+        reply_options.retain(|x| x.name != "MultiPV");
+        reply_options.push(EngineOption {
+            name: "MultiPV".to_string(),
+            value: "1".to_string(),
+        });
+        for (position, analysis) in positions.iter().zip(analysis.iter_mut()) {
+            if is_cancelled() {
+                kill_engines(engines).await?;
+                cancelled = true;
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                info!("Time budget ran out while measuring swindles");
+                break;
+            }
+            let (Some(played), Some(best)) =
+                (options.moves.get(position.ply), analysis.best.first())
+            else {
+                continue;
+            };
+            let chess: Chess = position.fen.clone().into_position(CastlingMode::Chess960)?;
+            if !is_lost(score_to_cp(&best.score), chess.turn().is_white(), losing_cp) {
+                continue;
+            }
+            let reply_options = EngineOptions {
+                extra_options: reply_options.clone(),
+                ..engine_options(position.ply)
+            };
+            analysis.swindle = swindle_chances(
+                &mut engines[0],
+                &chess,
+                &reply_options,
+                played,
+                &analysis.best,
+                losing_cp,
+            )
+            .await?;
+        }
+    }
+
+    Ok(GameAnalysis {
+        analysis,
+        cancelled,
+    })
+}
+
 /// Service for analyzing chess games using a UCI engine.
 pub struct GameAnalysisService;
 
@@ -25,142 +648,167 @@ impl GameAnalysisService {
     ///
     /// # Arguments
     /// * `id` - Unique analysis session identifier.
-    /// * `engine` - Path to the UCI engine binary.
-    /// * `go_mode` - Engine search mode (depth, time, etc).
+    /// * `engine` - Path to the UCI engine binary. With `AnalysisOptions::consensus_engines`,
+    ///   every position is also searched by those engines, one after the other, and the
+    ///   analysis holds their consensus.
+    /// * `go_mode` - Engine search mode (depth, time, etc), unless the analysis is adaptive.
     /// * `options` - Analysis options (FEN, moves, etc).
     /// * `uci_options` - Extra UCI engine options.
     /// * `state` - Application state for DB and engine process management.
     /// * `app` - Tauri app handle for event emission.
     ///
     /// # Returns
-    /// Vector of `MoveAnalysis` for each position in the game.
+    /// `MoveAnalysis` for each position in the game. An analysis stopped with `stop_analysis`
+    /// stops the running search and returns the positions it got to, marked as cancelled and
+    /// without the annotations of the whole game.
     ///
     /// # Errors
     /// Returns `Error` if engine or DB operations fail.
     pub async fn analyze_game(
         id: String,
         engine: String,
-        go_mode: super::types::GoMode,
+        go_mode: GoMode,
         options: AnalysisOptions,
         uci_options: Vec<EngineOption>,
         state: tauri::State<'_, AppState>,
         app: tauri::AppHandle,
-    ) -> Result<Vec<MoveAnalysis>, Error> {
-        let path = PathBuf::from(&engine);
-        let mut analysis: Vec<MoveAnalysis> = Vec::new();
-
-        let (mut proc, mut reader) = EngineProcess::new(path).await?;
+    ) -> Result<GameAnalysis, Error> {
+        let names: Vec<String> = std::iter::once(engine)
+            .chain(options.consensus_engines.iter().cloned())
+            .collect();
+        if names.len() > MAX_CONSENSUS_ENGINES {
+            return Err(Error::TooManyConsensusEngines(MAX_CONSENSUS_ENGINES));
+        }
+        let mut engines = Vec::with_capacity(names.len());
+        for name in names {
+            engines.push(AnalysisEngine::start(name, &state.path_scope).await?);
+        }
 
         let fen = Fen::from_ascii(options.fen.as_bytes())?;
 
-        // Build a list of FENs and moves for each position in the game, tracking sacrifices.
-        let mut chess: Chess = fen.clone().into_position(CastlingMode::Chess960)?;
-        let mut fens: Vec<(Fen, Vec<String>, bool)> = vec![(fen, vec![], false)];
-
-        options.moves.iter().enumerate().for_each(|(i, m)| {
-            let uci = UciMove::from_ascii(m.as_bytes()).unwrap();
-            let m = uci.to_move(&chess).unwrap();
-            let previous_pos = chess.clone();
-            chess.play_unchecked(&m);
-            let current_pos = chess.clone();
-            if !chess.is_game_over() {
-                // Detect sacrifices by comparing naive evals before and after the move.
-                let prev_eval = naive_eval(&previous_pos);
-                let cur_eval = -naive_eval(&current_pos);
-                fens.push((
-                    Fen::from_position(current_pos, EnPassantMode::Legal),
-                    options.moves.clone().into_iter().take(i + 1).collect(),
-                    prev_eval > cur_eval + 100, // Mark as sacrifice if eval drops by > 100.
-                ));
-            }
-        });
+        let max_ply = options.max_ply.map(|max| max as usize);
+        let (mut positions, truncated) = build_analysis_positions(&fen, &options.moves, max_ply)?;
+        if truncated {
+            info!(
+                "Analysis of {} stops after {} of {} moves",
+                id,
+                positions.len() - 1,
+                options.moves.len()
+            );
+        }
 
         if options.reversed {
-            fens.reverse();
+            positions.reverse();
         }
 
         let mut novelty_found = false;
+        let task = TaskHandle::start(&app, TaskKind::Analysis, &id, true);
+        let GameAnalysis {
+            mut analysis,
+            cancelled,
+        } = search_game(
+            &mut engines,
+            &positions,
+            &go_mode,
+            &options,
+            &uci_options,
+            &|| task.is_cancelled(),
+            |progress| {
+                ReportProgress {
+                    progress,
+                    id: id.clone(),
+                    finished: false,
+                }
+                .emit(&app)?;
+                task.report(progress, None);
+                Ok(())
+            },
+        )
+        .await?;
 
-        // Analyze each position using the engine, reporting progress.
-        for (i, (_, moves, _)) in fens.iter().enumerate() {
-            ReportProgress {
-                progress: (i as f64 / fens.len() as f64) * 100.0,
-                id: id.clone(),
-                finished: false,
-            }
-            .emit(&app)?;
+        // Positions a stopped analysis didn't get to are left out, or left without lines when
+        // it went backwards so that the analysis still starts with the game.
+        if options.reversed {
+            analysis.resize_with(positions.len(), Default::default);
+            analysis.reverse();
+            positions.reverse();
+        } else {
+            positions.truncate(analysis.len());
+        }
 
-            // Ensure MultiPV=2 for principal variation analysis.
-            let mut extra_options = uci_options.clone();
-            if !extra_options.iter().any(|x| x.name == "MultiPV") {
-                extra_options.push(EngineOption {
-                    name: "MultiPV".to_string(),
-                    value: "2".to_string(),
-                });
-            } else {
-                extra_options.iter_mut().for_each(|x| {
-                    if x.name == "MultiPV" {
-                        x.value = "2".to_string();
-                    }
-                });
+        let setups: Vec<_> = positions
+            .iter()
+            .map(|p| p.fen.clone().into_setup())
+            .collect();
+        if options.annotate_opening {
+            if let Some(opening) = find_opening_annotation(&setups) {
+                analysis[opening.ply as usize].opening = Some(opening.name);
             }
+        }
+        for (analysis, position) in analysis.iter_mut().zip(&positions) {
+            analysis.is_sacrifice = position.is_sacrifice;
+        }
 
-            proc.set_options(super::types::EngineOptions {
-                fen: options.fen.clone(),
-                moves: moves.clone(),
-                extra_options,
-            })
-            .await?;
-            proc.go(&go_mode).await?;
-
-            let mut current_analysis = MoveAnalysis::default();
-            // Read engine output and parse best moves for this position.
-            while let Ok(Some(line)) = reader.next_line().await {
-                match parse_one(&line) {
-                    vampirc_uci::UciMessage::Info(attrs) => {
-                        if let Ok(best_moves) =
-                            parse_uci_attrs(attrs, &proc.options.fen.parse()?, moves)
-                        {
-                            let multipv = best_moves.multipv;
-                            let cur_depth = best_moves.depth;
-                            if multipv as usize == proc.best_moves.len() + 1 {
-                                proc.best_moves.push(best_moves);
-                                if multipv == proc.real_multipv {
-                                    if proc.best_moves.iter().all(|x| x.depth == cur_depth)
-                                        && cur_depth >= proc.last_depth
-                                    {
-                                        current_analysis.best = proc.best_moves.clone();
-                                        proc.last_depth = cur_depth;
-                                    }
-                                    assert_eq!(proc.best_moves.len(), proc.real_multipv as usize);
-                                    proc.best_moves.clear();
-                                }
-                            }
-                        }
-                    }
-                    vampirc_uci::UciMessage::BestMove { .. } => {
-                        break;
-                    }
-                    _ => {}
-                }
+        // A stopped analysis is only of part of the game, too little to judge the game by,
+        // and is reported as cancelled when its task is dropped.
+        if cancelled {
+            ReportProgress {
+                progress: 100.0,
+                id: id.clone(),
+                finished: true,
             }
-            analysis.push(current_analysis);
+            .emit(&app)?;
+            return Ok(GameAnalysis {
+                analysis,
+                cancelled: true,
+            });
         }
 
-        if options.reversed {
-            analysis.reverse();
-            fens.reverse();
+        let ratings = ByColor {
+            white: options.white_rating.unwrap_or(DEFAULT_RATING),
+            black: options.black_rating.unwrap_or(DEFAULT_RATING),
+        };
+        let mut accuracy = game_accuracy(&setups, &analysis, ratings);
+        if options.swindle_chances {
+            let turns: Vec<_> = setups.iter().map(|setup| setup.turn).collect();
+            accuracy.swindles = Some(game_swindles(&turns, &analysis));
+        }
+        annotate_expected_points(&setups, &mut analysis, ratings);
+        let defaults = CpLossThresholds::default();
+        let thresholds = CpLossThresholds {
+            inaccuracy_cp: options.inaccuracy_cp.unwrap_or(defaults.inaccuracy_cp),
+            mistake_cp: options.mistake_cp.unwrap_or(defaults.mistake_cp),
+            blunder_cp: options.blunder_cp.unwrap_or(defaults.blunder_cp),
+        };
+        accuracy.cp_loss = Some(annotate_cp_loss(
+            &setups,
+            &options.moves,
+            &mut analysis,
+            thresholds,
+        ));
+        let defaults = OnlyMoveThresholds::default();
+        let thresholds = OnlyMoveThresholds {
+            gap_cp: options.only_move_gap_cp.unwrap_or(defaults.gap_cp),
+            survival_cp: options
+                .only_move_survival_cp
+                .unwrap_or(defaults.survival_cp),
+        };
+        annotate_only_moves(&positions, &options.moves, &mut analysis, thresholds);
+        if let Some(start) = analysis.first_mut() {
+            start.accuracy = Some(accuracy);
         }
 
-        // Annotate sacrifices and novelties for each analyzed position.
+        // Annotate novelties for each analyzed position.
+        let last = analysis.len().saturating_sub(1);
         for (i, analysis) in analysis.iter_mut().enumerate() {
-            let fen = &fens[i].0;
             let query = PositionQueryJs {
-                fen: fen.to_string(),
+                fen: positions[i].fen.to_string(),
                 type_: "exact".to_string(),
+                variant: CastlingMode::detect(positions[i].fen.as_setup()).into(),
+                side_to_move: None,
             };
 
-            analysis.is_sacrifice = fens[i].2;
+            analysis.truncated = truncated && i == last;
             if options.annotate_novelties && !novelty_found {
                 if let Some(reference) = options.reference_db.clone() {
                     analysis.novelty = !is_position_in_db(
@@ -184,6 +832,277 @@ pub async fn analyze_game(
             finished: true,
         }
         .emit(&app)?;
-        Ok(analysis)
+        task.finish();
+        Ok(GameAnalysis {
+            analysis,
+            cancelled: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moves(uci: &str) -> Vec<String> {
+        uci.split_whitespace().map(String::from).collect()
+    }
+
+    /// Write a UCI engine that logs each of its starts to `spawns` and exits on its
+    /// `crash_at`th search, if any.
+    #[cfg(unix)]
+    fn fake_engine(dir: &std::path::Path, crash_at: u32) -> (String, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let spawns = dir.join(format!("spawns-{}", crash_at));
+        let script = format!(
+            r#"#!/bin/sh
+echo started >> "{spawns}"
+searches=0
+while read -r line; do
+    case "$line" in
+        uci) echo "id name Fake"; echo uciok ;;
+        isready) echo readyok ;;
+        go*)
+            searches=$((searches + 1))
+            if [ "$searches" = {crash_at} ]; then exit 1; fi
+            echo "info depth 1 multipv 1 score cp 20 pv e2e4"
+            echo "info depth 1 multipv 2 score cp 10 pv d2d4"
+            echo "bestmove e2e4" ;;
+        quit) exit 0 ;;
+    esac
+done
+"#,
+            spawns = spawns.display(),
+        );
+        let path = dir.join(format!("fake-engine-{}", crash_at));
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (path.to_string_lossy().into_owned(), spawns)
+    }
+
+    #[cfg(unix)]
+    async fn search_start_position(engine: &mut AnalysisEngine<'_>, times: usize) -> Vec<usize> {
+        let options = EngineOptions {
+            fen: Fen::from_position(Chess::default(), EnPassantMode::Legal).to_string(),
+            moves: Vec::new(),
+            extra_options: vec![EngineOption {
+                name: "MultiPV".to_string(),
+                value: "2".to_string(),
+            }],
+        };
+        let mut found = Vec::new();
+        for _ in 0..times {
+            let engines = std::slice::from_mut(&mut *engine);
+            let (lines, _) = search_engines(
+                engines,
+                options.clone(),
+                &GoMode::Depth(1),
+                &|| false,
+                |_| Ok(()),
+            )
+            .await
+            .unwrap();
+            found.push(lines[0].best.len());
+        }
+        found
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn one_engine_process_searches_every_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let (name, spawns) = fake_engine(dir.path(), 0);
+        let scope = PathScope::for_root(dir.path());
+        let mut engine = AnalysisEngine::start(name, &scope).await.unwrap();
+        assert_eq!(search_start_position(&mut engine, 6).await, [2; 6]);
+        engine.proc.kill().await.unwrap();
+        assert_eq!(std::fs::read_to_string(spawns).unwrap().lines().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn crashed_engines_are_restarted() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every process dies on its second search.
+        let (name, spawns) = fake_engine(dir.path(), 2);
+        let scope = PathScope::for_root(dir.path());
+        let mut engine = AnalysisEngine::start(name, &scope).await.unwrap();
+        assert_eq!(search_start_position(&mut engine, 3).await, [2; 3]);
+        let _ = engine.proc.kill().await;
+        assert_eq!(engine.spawns, 3);
+        assert_eq!(std::fs::read_to_string(spawns).unwrap().lines().count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn engines_crashing_on_every_search_are_given_up_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let (name, _) = fake_engine(dir.path(), 1);
+        let scope = PathScope::for_root(dir.path());
+        let mut engine = AnalysisEngine::start(name, &scope).await.unwrap();
+        let options = EngineOptions {
+            fen: Fen::from_position(Chess::default(), EnPassantMode::Legal).to_string(),
+            moves: Vec::new(),
+            extra_options: Vec::new(),
+        };
+        // The first position gets no lines out of two processes, the next one runs out of
+        // restarts.
+        let (best, _) = engine
+            .search(options.clone(), &GoMode::Depth(1), &|| false)
+            .await
+            .unwrap();
+        assert!(best.is_empty());
+        assert!(matches!(
+            engine.search(options, &GoMode::Depth(1), &|| false).await,
+            Err(Error::EngineTimeout(_))
+        ));
+        assert_eq!(engine.spawns, MAX_ENGINE_RESTARTS + 1);
+    }
+
+    /// Write a UCI engine that doesn't end its `stall_at`th search until told to stop, and
+    /// logs each `stop` it gets to the returned file.
+    #[cfg(unix)]
+    fn stalling_engine(dir: &std::path::Path, stall_at: u32) -> (String, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let stops = dir.join("stops");
+        let script = format!(
+            r#"#!/bin/sh
+searches=0
+while read -r line; do
+    case "$line" in
+        uci) echo "id name Fake"; echo uciok ;;
+        isready) echo readyok ;;
+        go*)
+            searches=$((searches + 1))
+            echo "info depth 1 multipv 1 score cp 20 pv e2e4"
+            echo "info depth 1 multipv 2 score cp 10 pv d2d4"
+            if [ "$searches" != {stall_at} ]; then echo "bestmove e2e4"; fi ;;
+        stop) echo stopped >> "{stops}"; echo "bestmove e2e4" ;;
+        quit) exit 0 ;;
+    esac
+done
+"#,
+            stops = stops.display(),
+        );
+        let path = dir.join("stalling-engine");
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (path.to_string_lossy().into_owned(), stops)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cancelling_stops_the_running_search() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (name, stops) = stalling_engine(dir.path(), 3);
+        let scope = PathScope::for_root(dir.path());
+        let mut engines = vec![AnalysisEngine::start(name, &scope).await.unwrap()];
+        let fen = Fen::from_position(Chess::default(), EnPassantMode::Legal);
+        let game = moves("e2e4 e7e5 g1f3 b8c6");
+        let (positions, _) = build_analysis_positions(&fen, &game, None).unwrap();
+        let options = AnalysisOptions {
+            fen: fen.to_string(),
+            moves: game,
+            ..Default::default()
+        };
+
+        // The third search only ends when it is stopped, long before the engine would be
+        // taken for hung.
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = cancelled.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            search_game(
+                &mut engines,
+                &positions,
+                &GoMode::Infinite,
+                &options,
+                &[],
+                &|| cancelled.load(Ordering::Relaxed),
+                |_| Ok(()),
+            ),
+        )
+        .await
+        .expect("the search was not stopped")
+        .unwrap();
+
+        assert!(result.cancelled);
+        // The stopped position is left out along with those after it.
+        assert_eq!(result.analysis.len(), 2);
+        assert!(result
+            .analysis
+            .iter()
+            .all(|analysis| analysis.best.len() == 2));
+        assert_eq!(std::fs::read_to_string(stops).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn positions_stop_at_the_end_of_the_game() {
+        let fen = Fen::from_position(Chess::default(), EnPassantMode::Legal);
+        // Fool's mate, followed by moves that can no longer be played.
+        let game = moves("f2f3 e7e5 g2g4 d8h4 a2a3 a7a6");
+        let (positions, truncated) = build_analysis_positions(&fen, &game, None).unwrap();
+        assert!(!truncated);
+        assert_eq!(positions.len(), 5);
+        assert_eq!(positions[4].ply, 4);
+        assert_eq!(positions[4].termination, Some(GameTermination::Checkmate));
+    }
+
+    #[test]
+    fn max_ply_truncates_the_game() {
+        let fen = Fen::from_position(Chess::default(), EnPassantMode::Legal);
+        let game = moves("e2e4 e7e5 g1f3 b8c6 f1b5 a7a6");
+        let (positions, truncated) = build_analysis_positions(&fen, &game, Some(4)).unwrap();
+        assert!(truncated);
+        assert_eq!(positions.len(), 5);
+        assert_eq!(&game[..positions[4].ply], &game[..4]);
+
+        let (positions, truncated) = build_analysis_positions(&fen, &game, Some(6)).unwrap();
+        assert!(!truncated);
+        assert_eq!(positions.len(), 7);
+
+        // A game that ends at the cap is complete rather than truncated.
+        let mate = moves("f2f3 e7e5 g2g4 d8h4 a2a3");
+        let (_, truncated) = build_analysis_positions(&fen, &mate, Some(4)).unwrap();
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn several_engines_are_merged() {
+        let lines = |engine: &str, cp: i32| EngineLines {
+            engine: engine.to_string(),
+            best: vec![BestMoves {
+                score: vampirc_uci::uci::Score {
+                    value: vampirc_uci::uci::ScoreValue::Cp(cp),
+                    ..Default::default()
+                },
+                uci_moves: moves("e2e4"),
+                ..Default::default()
+            }],
+        };
+
+        let single = engine_analysis(vec![lines("a", 30)], 150);
+        assert_eq!(single.best.len(), 1);
+        assert!(!single.engines_disagree);
+        assert!(single.engine_lines.is_empty());
+
+        let merged = engine_analysis(vec![lines("a", 30), lines("b", 400)], 150);
+        assert_eq!(merged.best.len(), 1);
+        assert!(merged.engines_disagree);
+        let engines: Vec<&str> = merged
+            .engine_lines
+            .iter()
+            .map(|l| l.engine.as_str())
+            .collect();
+        assert_eq!(engines, ["a", "b"]);
     }
 }
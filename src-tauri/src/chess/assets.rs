@@ -0,0 +1,367 @@
+//! Engine network files.
+//!
+//! Stockfish evaluates positions with NNUE nets read from `.nnue` files, named by the
+//! default of its `EvalFile` option, and of `EvalFileSmall` in versions using a big and a
+//! small net. Builds with the nets embedded don't need the files, but others fall back to
+//! classical evaluation, or refuse to search, when one is missing.
+//!
+//! Stockfish looks for the files next to its binary and in its working directory, which is
+//! the binary's directory for engines started here. Nets are named after the first digits of
+//! their SHA-256 digest, so downloads can be checked against their name.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Serialize;
+use specta::Type;
+use vampirc_uci::uci::UciOptionConfig;
+
+use crate::error::Error;
+use crate::fs::download_resumable;
+use crate::scope::PathScope;
+use crate::tasks::{TaskHandle, TaskKind};
+use crate::AppState;
+
+use super::uci::UciCommunicator;
+
+/// Options naming the nets an engine loads.
+const EVAL_FILE_OPTIONS: [&str; 2] = ["EvalFile", "EvalFileSmall"];
+
+/// Where the official Stockfish nets are downloaded from, by file name.
+const OFFICIAL_NET_URL: &str = "https://tests.stockfishchess.org/api/nn/";
+
+/// Start of the line Stockfish sends for each net it evaluates with.
+const NNUE_INIT_LINE: &str = "NNUE evaluation using";
+
+/// Longest wait for the engine during the test search.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum AssetStatus {
+    /// The file is where the engine looks for it.
+    Present,
+    /// The file is missing, but the engine has the net built in.
+    Embedded,
+    /// The file is missing and the engine can't evaluate with the net.
+    Missing,
+}
+
+/// A net file expected by an engine.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct EngineAsset {
+    /// Option naming the file, e.g. `EvalFile`.
+    pub option: String,
+    pub file_name: String,
+    pub status: AssetStatus,
+    /// Where the file was found, or where it would be downloaded when missing.
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct EngineAssetsReport {
+    pub engine: String,
+    /// Nets the engine expects, empty for engines without NNUE options.
+    pub assets: Vec<EngineAsset>,
+    /// Nets the engine reported evaluating with during a short search.
+    pub loaded_nets: Vec<String>,
+}
+
+impl EngineAssetsReport {
+    pub fn missing(&self) -> impl Iterator<Item = &EngineAsset> {
+        self.assets
+            .iter()
+            .filter(|asset| asset.status == AssetStatus::Missing)
+    }
+}
+
+/// Net files named by the defaults of the engine's options, with the option naming them.
+pub fn expected_nets(options: &[UciOptionConfig]) -> Vec<(String, String)> {
+    options
+        .iter()
+        .filter_map(|option| match option {
+            UciOptionConfig::String {
+                name,
+                default: Some(default),
+            } if EVAL_FILE_OPTIONS
+                .iter()
+                .any(|eval_file| name.eq_ignore_ascii_case(eval_file))
+                && default.to_ascii_lowercase().ends_with(".nnue") =>
+            {
+                Some((name.clone(), default.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Leading digits of the SHA-256 digest of an official net, from its `nn-<digest>.nnue` name.
+pub fn net_checksum(file_name: &str) -> Option<&str> {
+    let digest = file_name.strip_prefix("nn-")?.strip_suffix(".nnue")?;
+    (digest.len() >= 12 && digest.chars().all(|c| c.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// Net named by a `NNUE evaluation using` line, e.g.
+/// `info string NNUE evaluation using nn-1111cefa1111.nnue (133MiB, (22528, 3072, 15, 32, 1))`.
+pub fn parse_loaded_net(line: &str) -> Option<String> {
+    let (_, rest) = line.split_once(NNUE_INIT_LINE)?;
+    let net = rest.split_whitespace().next()?;
+    Some(net.to_string())
+}
+
+/// Directory the engine reads its nets from.
+fn net_dir(engine: &Path) -> PathBuf {
+    engine.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// Find a net file the way the engine would: as given when absolute, otherwise in its
+/// directory.
+fn locate_net(engine: &Path, file_name: &str) -> Option<PathBuf> {
+    let file = Path::new(file_name);
+    let path = if file.is_absolute() {
+        file.to_path_buf()
+    } else {
+        net_dir(engine).join(file)
+    };
+    path.is_file().then_some(path)
+}
+
+/// Status of a net of the engine, given the nets it reported loading.
+fn classify_net(
+    engine: &Path,
+    option: String,
+    file_name: String,
+    loaded: &[String],
+) -> EngineAsset {
+    let (status, path) = match locate_net(engine, &file_name) {
+        Some(path) => (AssetStatus::Present, Some(path)),
+        None if loaded.iter().any(|net| net.ends_with(&file_name)) => (AssetStatus::Embedded, None),
+        None => (AssetStatus::Missing, Some(net_dir(engine).join(&file_name))),
+    };
+    EngineAsset {
+        option,
+        file_name,
+        status,
+        path: path.map(|path| path.to_string_lossy().to_string()),
+    }
+}
+
+/// Run a depth 1 search and collect the nets the engine reports evaluating with. An engine
+/// missing its nets may quit instead of searching.
+async fn probe_loaded_nets(comm: &mut UciCommunicator) -> Result<Vec<String>, Error> {
+    comm.write_line("isready\n").await?;
+    comm.write_line("position startpos\n").await?;
+    comm.write_line("go depth 1\n").await?;
+
+    let mut loaded = Vec::new();
+    let searched = tokio::time::timeout(PROBE_TIMEOUT, async {
+        while let Some(line) = comm.stdout_lines.next_line().await? {
+            if let Some(net) = parse_loaded_net(&line) {
+                loaded.push(net);
+            } else if line.trim_start().starts_with("bestmove") {
+                break;
+            }
+        }
+        Ok::<_, Error>(())
+    })
+    .await;
+    match searched {
+        Ok(result) => result?,
+        Err(_) => warn!("Engine did not finish its test search"),
+    }
+    Ok(loaded)
+}
+
+/// Check the nets of the engine at `path`.
+pub async fn check_assets(path: &Path, scope: &PathScope) -> Result<EngineAssetsReport, Error> {
+    let mut comm = UciCommunicator::spawn(path.to_path_buf(), scope).await?;
+    let handshake = comm.handshake().await?;
+    let nets = expected_nets(&handshake.options);
+    let loaded = if nets.is_empty() {
+        Vec::new()
+    } else {
+        probe_loaded_nets(&mut comm).await?
+    };
+    let _ = comm.write_line("quit\n").await;
+    if let Some(mut child) = comm.child.take() {
+        let _ = child.kill().await;
+    }
+
+    Ok(EngineAssetsReport {
+        engine: handshake.name.unwrap_or_default(),
+        assets: nets
+            .into_iter()
+            .map(|(option, file_name)| classify_net(path, option, file_name, &loaded))
+            .collect(),
+        loaded_nets: loaded,
+    })
+}
+
+/// Find the nets an engine expects and whether they're available.
+#[tauri::command]
+#[specta::specta]
+pub async fn check_engine_assets(
+    path: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<EngineAssetsReport, Error> {
+    check_assets(&path, &state.path_scope).await
+}
+
+/// Download the missing nets of an engine next to its binary, then check that the engine
+/// loads them.
+///
+/// Nets are downloaded from `url_override` followed by their file name when given, or from
+/// the official Stockfish server, and rejected when their digest doesn't match their name.
+#[tauri::command]
+#[specta::specta]
+pub async fn download_engine_asset(
+    engine_path: PathBuf,
+    url_override: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<EngineAssetsReport, Error> {
+    let report = check_assets(&engine_path, &state.path_scope).await?;
+    let mut base = url_override.unwrap_or_else(|| OFFICIAL_NET_URL.to_string());
+    if !base.ends_with('/') {
+        base.push('/');
+    }
+
+    for asset in report.missing() {
+        let checksum = net_checksum(&asset.file_name)
+            .ok_or_else(|| Error::NoAssetDownload(asset.file_name.clone()))?;
+        let target = net_dir(&engine_path).join(&asset.file_name);
+        info!("Downloading {} to {}", asset.file_name, target.display());
+        let task = TaskHandle::start(&app, TaskKind::Download, &asset.file_name, false);
+        download_resumable(
+            &format!("{}{}", base, asset.file_name),
+            &target,
+            Some(checksum),
+            &task,
+            &app,
+        )
+        .await?;
+        task.finish();
+    }
+
+    let report = check_assets(&engine_path, &state.path_scope).await?;
+    if let Some(asset) = report.missing().next() {
+        return Err(Error::EngineInitFailed(format!(
+            "{} is still missing",
+            asset.file_name
+        )));
+    }
+    if !report.assets.is_empty() && report.loaded_nets.is_empty() {
+        return Err(Error::EngineInitFailed(
+            "The engine doesn't report evaluating with NNUE".to_string(),
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_option(name: &str, default: &str) -> UciOptionConfig {
+        UciOptionConfig::String {
+            name: name.to_string(),
+            default: Some(default.to_string()),
+        }
+    }
+
+    #[test]
+    fn nets_come_from_eval_file_defaults() {
+        let options = vec![
+            UciOptionConfig::Spin {
+                name: "Hash".to_string(),
+                default: Some(16),
+                min: Some(1),
+                max: Some(33554432),
+            },
+            string_option("EvalFile", "nn-1111cefa1111.nnue"),
+            string_option("EvalFileSmall", "nn-37f18f62d772.nnue"),
+            string_option("SyzygyPath", "<empty>"),
+        ];
+        assert_eq!(
+            expected_nets(&options),
+            vec![
+                ("EvalFile".to_string(), "nn-1111cefa1111.nnue".to_string()),
+                (
+                    "EvalFileSmall".to_string(),
+                    "nn-37f18f62d772.nnue".to_string()
+                ),
+            ]
+        );
+        // Engines without a default net need nothing.
+        assert!(expected_nets(&[string_option("EvalFile", "<empty>")]).is_empty());
+    }
+
+    #[test]
+    fn official_nets_carry_their_checksum() {
+        assert_eq!(net_checksum("nn-1111cefa1111.nnue"), Some("1111cefa1111"));
+        assert_eq!(net_checksum("nn-custom.nnue"), None);
+        assert_eq!(net_checksum("mynet.nnue"), None);
+    }
+
+    #[test]
+    fn loaded_nets_are_read_from_init_lines() {
+        assert_eq!(
+            parse_loaded_net(
+                "info string NNUE evaluation using nn-1111cefa1111.nnue (133MiB, (22528, 3072, 15, 32, 1))"
+            ),
+            Some("nn-1111cefa1111.nnue".to_string())
+        );
+        assert_eq!(
+            parse_loaded_net("info string NNUE evaluation using nn-ad9b42354671.nnue enabled"),
+            Some("nn-ad9b42354671.nnue".to_string())
+        );
+        assert_eq!(
+            parse_loaded_net("info string classical evaluation enabled"),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_nets_are_told_apart_from_embedded_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = dir.path().join("stockfish");
+        std::fs::write(dir.path().join("nn-1111cefa1111.nnue"), b"net").unwrap();
+
+        let present = classify_net(
+            &engine,
+            "EvalFile".to_string(),
+            "nn-1111cefa1111.nnue".to_string(),
+            &[],
+        );
+        assert_eq!(present.status, AssetStatus::Present);
+
+        // A dual net engine with only the big net on disk.
+        let loaded = vec!["nn-37f18f62d772.nnue".to_string()];
+        let embedded = classify_net(
+            &engine,
+            "EvalFileSmall".to_string(),
+            "nn-37f18f62d772.nnue".to_string(),
+            &loaded,
+        );
+        assert_eq!(embedded.status, AssetStatus::Embedded);
+        assert_eq!(embedded.path, None);
+
+        let missing = classify_net(
+            &engine,
+            "EvalFileSmall".to_string(),
+            "nn-37f18f62d772.nnue".to_string(),
+            &[],
+        );
+        assert_eq!(missing.status, AssetStatus::Missing);
+        assert_eq!(
+            missing.path,
+            Some(
+                dir.path()
+                    .join("nn-37f18f62d772.nnue")
+                    .to_string_lossy()
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Quick evaluation of many positions, for the eval graph of games without analysis.
+//!
+//! `evaluate_positions_batch` shares the positions between a few engine processes, each
+//! started once and reused for every position it takes. Results are sent with
+//! `BatchEvaluationResult` as they come and returned in input order at the end. A position
+//! that can't be evaluated is marked as failed on its own, and an engine that dies is
+//! started again for the next position. Cancelling the task kills the engines right away,
+//! even in the middle of a search.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess, Position};
+use specta::Type;
+use tauri_specta::Event;
+use vampirc_uci::{parse_one, uci::Score, UciMessage};
+
+use crate::error::Error;
+use crate::scope::PathScope;
+use crate::tasks::{TaskHandle, TaskKind};
+use crate::AppState;
+
+use super::process::{parse_uci_attrs, EngineProcess};
+use super::types::{EngineOptions, GoMode};
+use super::uci::EngineStdout;
+
+/// Positions evaluated in one batch at most.
+pub const MAX_BATCH_POSITIONS: usize = 500;
+
+/// Engines running at once at most, whatever the number of cores.
+const MAX_BATCH_ENGINES: usize = 4;
+
+/// Move time used instead of an infinite search, which would never finish.
+const INFINITE_FALLBACK_MOVETIME: u32 = 200;
+
+/// Time between two checks for cancellation while an engine searches.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// Evaluation of one position of a batch.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PositionEvaluation {
+    /// Score from White's point of view.
+    Evaluated {
+        score: Score,
+        depth: u32,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Event payload sent for each position of a batch as soon as it is evaluated.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEvaluationResult {
+    pub id: String,
+    /// Index of the position in the batch.
+    pub index: u32,
+    pub evaluation: PositionEvaluation,
+}
+
+/// Number of engines to run for a batch of `positions`.
+fn engine_count(concurrency: u32, positions: usize) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    (concurrency as usize)
+        .min(MAX_BATCH_ENGINES)
+        .min(cores)
+        .min(positions)
+        .max(1)
+}
+
+/// A pool of engines sharing the positions of a batch.
+pub struct BatchEvaluator<'a> {
+    engine: PathBuf,
+    scope: &'a PathScope,
+    go_mode: GoMode,
+    engines: usize,
+    is_cancelled: &'a (dyn Fn() -> bool + Sync),
+    /// Index of the next position to take.
+    next: AtomicUsize,
+    /// Engines searching right now, and the most that ever were.
+    searching: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl<'a> BatchEvaluator<'a> {
+    pub fn new(
+        engine: PathBuf,
+        scope: &'a PathScope,
+        go_mode: GoMode,
+        engines: usize,
+        is_cancelled: &'a (dyn Fn() -> bool + Sync),
+    ) -> Self {
+        let go_mode = match go_mode {
+            GoMode::Infinite => GoMode::Time(INFINITE_FALLBACK_MOVETIME),
+            go_mode => go_mode,
+        };
+        Self {
+            engine,
+            scope,
+            go_mode,
+            engines: engines.max(1),
+            is_cancelled,
+            next: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Most engines that searched at the same time.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    /// Evaluate `fens`, calling `on_result` with each evaluation as it comes.
+    ///
+    /// Fails only if the batch was cancelled.
+    pub async fn run(
+        &self,
+        fens: &[String],
+        on_result: &(dyn Fn(usize, &PositionEvaluation) + Sync),
+    ) -> Result<Vec<PositionEvaluation>, Error> {
+        let results = Mutex::new(vec![None; fens.len()]);
+        join_all((0..self.engines).map(|_| self.worker(fens, &results, on_result))).await;
+        if (self.is_cancelled)() {
+            return Err(Error::TaskCancelled);
+        }
+        Ok(results
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| PositionEvaluation::Failed {
+                    error: "Not evaluated".to_string(),
+                })
+            })
+            .collect())
+    }
+
+    async fn worker(
+        &self,
+        fens: &[String],
+        results: &Mutex<Vec<Option<PositionEvaluation>>>,
+        on_result: &(dyn Fn(usize, &PositionEvaluation) + Sync),
+    ) {
+        let mut engine: Option<(EngineProcess, EngineStdout)> = None;
+        while !(self.is_cancelled)() {
+            let index = self.next.fetch_add(1, Ordering::SeqCst);
+            let Some(fen) = fens.get(index) else {
+                break;
+            };
+
+            let evaluation = match self.evaluate(&mut engine, fen).await {
+                Ok(evaluation) => evaluation,
+                Err(Error::TaskCancelled) => break,
+                Err(e) => PositionEvaluation::Failed {
+                    error: e.to_string(),
+                },
+            };
+            on_result(index, &evaluation);
+            if let Ok(mut results) = results.lock() {
+                results[index] = Some(evaluation);
+            }
+        }
+        if let Some((mut proc, _)) = engine {
+            let _ = proc.kill().await;
+        }
+    }
+
+    /// Evaluate a position with the worker's engine, starting it if needed. The engine is
+    /// dropped if it fails, so the next position gets a new one.
+    async fn evaluate(
+        &self,
+        engine: &mut Option<(EngineProcess, EngineStdout)>,
+        fen: &str,
+    ) -> Result<PositionEvaluation, Error> {
+        // Positions the engine can't search fail without disturbing it.
+        let parsed = Fen::from_ascii(fen.as_bytes())?;
+        let position: Chess = parsed.clone().into_position(CastlingMode::Chess960)?;
+        if position.legal_moves().is_empty() {
+            return Err(Error::NoMovesFound);
+        }
+
+        let running = match engine.take() {
+            Some(running) => running,
+            None => EngineProcess::new(self.engine.clone(), self.scope).await?,
+        };
+        let (proc, reader) = engine.insert(running);
+
+        let searching = self.searching.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(searching, Ordering::SeqCst);
+        let result = self.search(proc, reader, &parsed, fen).await;
+        self.searching.fetch_sub(1, Ordering::SeqCst);
+
+        if result.is_err() {
+            if let Some((mut proc, _)) = engine.take() {
+                let _ = proc.kill().await;
+            }
+        }
+        result
+    }
+
+    async fn search(
+        &self,
+        proc: &mut EngineProcess,
+        reader: &mut EngineStdout,
+        parsed: &Fen,
+        fen: &str,
+    ) -> Result<PositionEvaluation, Error> {
+        proc.set_options(EngineOptions {
+            fen: fen.to_string(),
+            moves: Vec::new(),
+            extra_options: Vec::new(),
+        })
+        .await?;
+        proc.go(&self.go_mode).await?;
+
+        let mut best = None;
+        loop {
+            if (self.is_cancelled)() {
+                return Err(Error::TaskCancelled);
+            }
+            let line = match tokio::time::timeout(CANCEL_POLL, reader.next_line()).await {
+                Err(_) => continue,
+                Ok(line) => line?.ok_or(Error::NoStdout)?,
+            };
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    if let Ok(line) = parse_uci_attrs(attrs, parsed, &Vec::new()) {
+                        if line.multipv == 1 {
+                            best = Some(line);
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => break,
+                _ => {}
+            }
+        }
+        let best = best.ok_or(Error::NoMovesFound)?;
+        Ok(PositionEvaluation::Evaluated {
+            score: best.score,
+            depth: best.depth,
+        })
+    }
+}
+
+/// Evaluate positions with a few engines at once, each searching every position it takes
+/// with `per_position`. Evaluations are also sent with `BatchEvaluationResult` as they
+/// come, and a position that fails doesn't fail the batch.
+#[tauri::command]
+#[specta::specta]
+pub async fn evaluate_positions_batch(
+    id: String,
+    engine: String,
+    fens: Vec<String>,
+    per_position: GoMode,
+    concurrency: u32,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Vec<PositionEvaluation>, Error> {
+    if fens.len() > MAX_BATCH_POSITIONS {
+        return Err(Error::BatchTooLarge(fens.len(), MAX_BATCH_POSITIONS));
+    }
+    let path = PathBuf::from(&engine);
+    // Engines are only started once positions come in, so fail before that.
+    state.path_scope.check_engine(&path)?;
+
+    let task = TaskHandle::start(&app, TaskKind::Analysis, &id, true);
+    let done = AtomicUsize::new(0);
+    let is_cancelled = || task.is_cancelled();
+    let on_result = |index: usize, evaluation: &PositionEvaluation| {
+        let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+        task.report(done as f64 / fens.len() as f64 * 100.0, None);
+        let _ = BatchEvaluationResult {
+            id: id.clone(),
+            index: index as u32,
+            evaluation: evaluation.clone(),
+        }
+        .emit(&app);
+    };
+
+    let evaluator = BatchEvaluator::new(
+        path,
+        &state.path_scope,
+        per_position,
+        engine_count(concurrency, fens.len()),
+        &is_cancelled,
+    );
+    let evaluations = evaluator.run(&fens, &on_result).await?;
+    task.finish();
+    Ok(evaluations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::builtin::BUILTIN_ENGINE;
+    use vampirc_uci::uci::ScoreValue;
+
+    const WHITE_UP_A_QUEEN: &str = "4k3/8/8/8/8/8/8/Q3K3 w - - 0 1";
+    const BLACK_UP_A_QUEEN: &str = "q3k3/8/8/8/8/8/8/4K3 w - - 0 1";
+
+    fn centipawns(evaluation: &PositionEvaluation) -> Option<i32> {
+        match evaluation {
+            PositionEvaluation::Evaluated { score, .. } => match score.value {
+                ScoreValue::Cp(cp) => Some(cp),
+                ScoreValue::Mate(mate) => Some(mate.signum() as i32 * 100_000),
+            },
+            PositionEvaluation::Failed { .. } => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_keep_their_order_and_isolate_failures() {
+        let mut fens = Vec::new();
+        for i in 0..8 {
+            fens.push(
+                if i % 2 == 0 {
+                    WHITE_UP_A_QUEEN
+                } else {
+                    BLACK_UP_A_QUEEN
+                }
+                .to_string(),
+            );
+        }
+        fens[3] = "not a position".to_string();
+        // Checkmated, nothing to search.
+        fens[6] = "R5k1/5ppp/8/8/8/8/8/6K1 b - - 0 1".to_string();
+
+        let reported = Mutex::new(Vec::new());
+        let never = || false;
+        let scope = PathScope::default();
+        let evaluator = BatchEvaluator::new(
+            PathBuf::from(BUILTIN_ENGINE),
+            &scope,
+            GoMode::Depth(1),
+            3,
+            &never,
+        );
+        let evaluations = evaluator
+            .run(&fens, &|index, _| reported.lock().unwrap().push(index))
+            .await
+            .unwrap();
+
+        assert_eq!(evaluations.len(), fens.len());
+        for (i, evaluation) in evaluations.iter().enumerate() {
+            match i {
+                3 | 6 => assert!(matches!(evaluation, PositionEvaluation::Failed { .. })),
+                i if i % 2 == 0 => assert!(centipawns(evaluation).unwrap() > 0),
+                _ => assert!(centipawns(evaluation).unwrap() < 0),
+            }
+        }
+        let mut reported = reported.into_inner().unwrap();
+        reported.sort();
+        assert_eq!(reported, (0..fens.len()).collect::<Vec<_>>());
+        assert!(evaluator.peak() >= 1);
+        assert!(evaluator.peak() <= 3);
+    }
+
+    #[tokio::test]
+    async fn cancelled_batches_stop() {
+        let fens = vec![WHITE_UP_A_QUEEN.to_string(); 20];
+        let cancelled = std::sync::atomic::AtomicBool::new(false);
+        let is_cancelled = || cancelled.load(Ordering::SeqCst);
+        let scope = PathScope::default();
+        let evaluator = BatchEvaluator::new(
+            PathBuf::from(BUILTIN_ENGINE),
+            &scope,
+            GoMode::Depth(1),
+            2,
+            &is_cancelled,
+        );
+        let evaluated = AtomicUsize::new(0);
+        let result = evaluator
+            .run(&fens, &|_, _| {
+                if evaluated.fetch_add(1, Ordering::SeqCst) == 2 {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::TaskCancelled)));
+        assert!(evaluated.load(Ordering::SeqCst) < fens.len());
+        assert!(evaluator.peak() <= 2);
+    }
+
+    #[test]
+    fn engine_counts_are_bounded() {
+        assert_eq!(engine_count(0, 10), 1);
+        assert_eq!(engine_count(16, 1), 1);
+        assert!(engine_count(16, 100) <= MAX_BATCH_ENGINES);
+    }
+}
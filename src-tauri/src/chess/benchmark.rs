@@ -0,0 +1,357 @@
+//! Rough strength estimate of an engine, from test positions built into the app.
+//!
+//! `estimate_engine_strength` searches each position for a fixed time with a single line
+//! and compares the engine's move with the known best moves. The positions go from mates
+//! in one to material won by a tactic a few moves deep, so weak engines solve the first ones
+//! only. The estimate is a band of ratings, not a measured rating: the positions are few and
+//! only tactical, and a fast machine makes any engine look stronger.
+//!
+//! The engine is kept in `AppState::engine_processes` under the benchmark's id, so
+//! `kill_engine` stops it. Whether it is killed or crashes, the positions searched until
+//! then still make a report.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use serde::Serialize;
+use specta::Type;
+use tauri_specta::Event;
+use tokio::sync::Mutex;
+use vampirc_uci::{parse_one, UciInfoAttribute, UciMessage};
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::process::EngineProcess;
+use super::types::{EngineOption, EngineOptions, GoMode, ReportProgress};
+use super::uci::EngineStdout;
+
+/// Time an engine may take past the search time before it is considered stuck.
+const BESTMOVE_GRACE: Duration = Duration::from_secs(5);
+
+/// A test position and the moves solving it, in UCI notation.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkPosition {
+    pub fen: &'static str,
+    pub best: &'static [&'static str],
+}
+
+const fn position(fen: &'static str, best: &'static [&'static str]) -> BenchmarkPosition {
+    BenchmarkPosition { fen, best }
+}
+
+/// Test positions, easiest first: mates in one, mates in two, then tactics winning material.
+pub const BENCHMARK_POSITIONS: &[BenchmarkPosition] = &[
+    // Mates in one.
+    position("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", &["a1a8"]),
+    position("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", &["d1d8"]),
+    position("7k/8/6K1/8/8/8/Q7/8 w - - 0 1", &["a2a8"]),
+    position("6rk/6pp/8/6N1/8/8/8/6K1 w - - 0 1", &["g5f7"]),
+    position("r5k1/5ppp/8/8/8/8/5PPP/6K1 b - - 0 1", &["a8a1"]),
+    position("k7/8/1K6/8/8/8/7Q/8 w - - 0 1", &["h2h8"]),
+    position(
+        "r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w - - 4 4",
+        &["h5f7"],
+    ),
+    position(
+        "rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b - - 0 2",
+        &["d8h4"],
+    ),
+    position("4k3/8/4K3/8/8/8/8/R7 w - - 0 1", &["a1a8"]),
+    position("5rk1/5p1p/8/8/8/8/1B6/4R2K w - - 0 1", &["e1g1"]),
+    // Mates in two.
+    position("7k/8/5K2/8/8/8/8/R7 w - - 0 1", &["f6f7", "f6g6"]),
+    position("7k/8/8/8/8/8/R7/1R4K1 w - - 0 1", &["a2a7", "b1b7"]),
+    position("2k5/8/3K4/8/8/8/8/7R w - - 0 1", &["h1b1"]),
+    position("7k/8/3R4/7K/8/8/8/8 w - - 0 1", &["h5g6"]),
+    position("8/8/8/1R6/8/5K2/8/4k3 w - - 0 1", &["b5d5"]),
+    position("K7/8/8/5p2/1rp5/3Q4/8/B6k w - - 0 1", &["d3h3"]),
+    position("k7/5K2/8/4Np1p/1R6/8/8/8 w - - 0 1", &["e5c6"]),
+    position("R2B2k1/8/2p2K2/8/8/8/7p/8 w - - 0 1", &["f6g6"]),
+    position("5K2/8/p5p1/8/4r3/2Q5/5B1k/8 w - - 0 1", &["c3g3"]),
+    position("8/8/8/8/8/7K/2Q5/5k2 w - - 0 1", &["c2d2"]),
+    // Tactics winning material.
+    position("8/1pk3b1/2r5/8/P6K/3Q4/8/8 w - - 0 1", &["d3g3"]),
+    position("3b4/8/2K5/8/2Q5/7p/1k3r1P/8 w - - 0 1", &["c4d4"]),
+    position("5q2/8/3Q3P/8/1K6/1b2k3/4Np2/8 w - - 0 1", &["d6f8"]),
+    position("8/1q5p/Q7/5k2/K1p5/3r1P1P/8/3R4 w - - 0 1", &["a6b7"]),
+    position("2n1k2B/K7/3r4/7p/5P2/2R5/8/8 w - - 0 1", &["c3c8"]),
+    position("3q4/5k2/8/1p2b3/5P2/QN6/4K3/8 w - - 0 1", &["f4e5"]),
+    position("8/3p3k/5K2/8/2Q5/P4b2/2q2N2/8 w - - 0 1", &["c4c2"]),
+    position("4k3/8/nPp5/8/P1pb4/1N6/B7/6K1 w - - 0 1", &["b3d4"]),
+    position("8/k2p1P2/8/K4b2/6N1/8/4q3/2Q5 w - - 0 1", &["c1c7"]),
+    position("8/P1pQ4/4R2r/8/8/2k1K2P/2p5/5q2 w - - 0 1", &["d7d2"]),
+];
+
+/// Ratings of engines solving a given share of the positions, at least.
+const ELO_BANDS: [(f64, u32, u32); 6] = [
+    (0.95, 3000, 3600),
+    (0.85, 2600, 3000),
+    (0.70, 2200, 2600),
+    (0.50, 1800, 2200),
+    (0.30, 1400, 1800),
+    (0.0, 800, 1400),
+];
+
+/// Range of ratings an engine is estimated at.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub struct EloBand {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Result of a benchmark.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineStrengthEstimate {
+    pub solved: u32,
+    /// Positions the engine searched, fewer than `total` if it stopped before the end.
+    pub searched: u32,
+    pub total: u32,
+    /// Average depth reached in the positions searched.
+    pub average_depth: f64,
+    /// Estimated from the positions searched, none if there are none.
+    pub elo: Option<EloBand>,
+}
+
+/// Rating band of an engine solving `solved` of `searched` positions.
+pub fn elo_band(solved: u32, searched: u32) -> Option<EloBand> {
+    if searched == 0 {
+        return None;
+    }
+    let share = f64::from(solved) / f64::from(searched);
+    ELO_BANDS
+        .iter()
+        .find(|(threshold, _, _)| share >= *threshold)
+        .map(|&(_, min, max)| EloBand { min, max })
+}
+
+/// Results of the positions searched so far.
+#[derive(Debug, Default)]
+pub struct BenchmarkTally {
+    solved: u32,
+    searched: u32,
+    depths: u64,
+}
+
+impl BenchmarkTally {
+    /// Record the move the engine chose in `position`, at `depth`.
+    pub fn record(&mut self, position: &BenchmarkPosition, best_move: &str, depth: u32) {
+        self.searched += 1;
+        self.depths += u64::from(depth);
+        if position.best.contains(&best_move) {
+            self.solved += 1;
+        }
+    }
+
+    pub fn estimate(&self, total: usize) -> EngineStrengthEstimate {
+        EngineStrengthEstimate {
+            solved: self.solved,
+            searched: self.searched,
+            total: total as u32,
+            average_depth: if self.searched == 0 {
+                0.0
+            } else {
+                self.depths as f64 / f64::from(self.searched)
+            },
+            elo: elo_band(self.solved, self.searched),
+        }
+    }
+}
+
+/// Read the engine's answer to a search: its move and the deepest depth it reported.
+///
+/// Returns `None` if the engine stopped or got stuck before answering.
+async fn read_search(reader: &mut EngineStdout, time: Duration) -> Option<(String, u32)> {
+    let mut depth = 0;
+    let answer = async {
+        while let Ok(Some(line)) = reader.next_line().await {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    for attr in attrs {
+                        if let UciInfoAttribute::Depth(d) = attr {
+                            depth = depth.max(d);
+                        }
+                    }
+                }
+                UciMessage::BestMove { best_move, .. } => return Some(best_move.to_string()),
+                _ => {}
+            }
+        }
+        None
+    };
+    let best_move = tokio::time::timeout(time + BESTMOVE_GRACE, answer)
+        .await
+        .ok()??;
+    Some((best_move, depth))
+}
+
+/// Search every test position with the engine of `process`, reporting progress under `id`.
+async fn run_benchmark(
+    id: &str,
+    process: &Mutex<EngineProcess>,
+    reader: &mut EngineStdout,
+    time: u32,
+    app: &tauri::AppHandle,
+) -> Result<BenchmarkTally, Error> {
+    let mut tally = BenchmarkTally::default();
+    let single_line = vec![EngineOption {
+        name: "MultiPV".to_string(),
+        value: "1".to_string(),
+    }];
+    for (i, position) in BENCHMARK_POSITIONS.iter().enumerate() {
+        ReportProgress {
+            progress: i as f64 / BENCHMARK_POSITIONS.len() as f64 * 100.0,
+            id: id.to_string(),
+            finished: false,
+        }
+        .emit(app)?;
+
+        let started = {
+            let mut proc = process.lock().await;
+            proc.set_options(EngineOptions {
+                fen: position.fen.to_string(),
+                moves: Vec::new(),
+                extra_options: single_line.clone(),
+            })
+            .await
+            .is_ok()
+                && proc.go(&GoMode::Time(time)).await.is_ok()
+        };
+        let answer = if started {
+            read_search(reader, Duration::from_millis(time.into())).await
+        } else {
+            None
+        };
+        let Some((best_move, depth)) = answer else {
+            info!(
+                "Engine of benchmark {} stopped after {} positions",
+                id, tally.searched
+            );
+            break;
+        };
+        tally.record(position, &best_move, depth);
+    }
+    Ok(tally)
+}
+
+/// Estimate the strength of an engine from how many test positions it solves, searching
+/// each for `time_per_position_ms`. Progress is sent with `ReportProgress` under `id`, and
+/// `kill_engine` with the engine's path and `id` as the tab stops the benchmark, which still
+/// reports the positions searched until then.
+#[tauri::command]
+#[specta::specta]
+pub async fn estimate_engine_strength(
+    id: String,
+    path: PathBuf,
+    time_per_position_ms: u64,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<EngineStrengthEstimate, Error> {
+    let key = (id.clone(), path.to_string_lossy().to_string());
+    let (mut process, mut reader) = EngineProcess::new(path, &state.path_scope).await?;
+    process.tab = id.clone();
+    let process = Arc::new(Mutex::new(process));
+    state.engine_processes.insert(key.clone(), process.clone());
+
+    let time = time_per_position_ms.clamp(1, u32::MAX.into()) as u32;
+    let result = run_benchmark(&id, &process, &mut reader, time, &app).await;
+
+    state
+        .engine_processes
+        .remove_if(&key, |_, current| Arc::ptr_eq(current, &process));
+    let _ = process.lock().await.kill().await;
+    let estimate = result?.estimate(BENCHMARK_POSITIONS.len());
+    ReportProgress {
+        progress: 100.0,
+        id,
+        finished: true,
+    }
+    .emit(&app)?;
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Position};
+
+    /// Mates in one at the start of `BENCHMARK_POSITIONS`.
+    const MATES_IN_ONE: usize = 10;
+
+    #[test]
+    fn positions_are_legal_with_legal_solutions() {
+        assert_eq!(BENCHMARK_POSITIONS.len(), 30);
+        for position in BENCHMARK_POSITIONS {
+            let chess: Chess = Fen::from_ascii(position.fen.as_bytes())
+                .unwrap()
+                .into_position(CastlingMode::Standard)
+                .unwrap();
+            assert!(!position.best.is_empty(), "{}", position.fen);
+            for best in position.best {
+                let m = UciMove::from_ascii(best.as_bytes()).unwrap();
+                assert!(m.to_move(&chess).is_ok(), "{} in {}", best, position.fen);
+            }
+        }
+    }
+
+    #[test]
+    fn mates_in_one_are_solved_by_mating() {
+        for position in &BENCHMARK_POSITIONS[..MATES_IN_ONE] {
+            let chess: Chess = Fen::from_ascii(position.fen.as_bytes())
+                .unwrap()
+                .into_position(CastlingMode::Standard)
+                .unwrap();
+            for m in chess.legal_moves() {
+                let mut after = chess.clone();
+                after.play_unchecked(&m);
+                let uci = m.to_uci(CastlingMode::Standard).to_string();
+                assert_eq!(
+                    after.is_checkmate(),
+                    position.best.contains(&uci.as_str()),
+                    "{} in {}",
+                    uci,
+                    position.fen
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tallies_count_solutions_and_depths() {
+        let position = BENCHMARK_POSITIONS[0];
+        let mut tally = BenchmarkTally::default();
+        assert_eq!(tally.estimate(30).elo, None);
+        tally.record(&position, position.best[0], 10);
+        tally.record(&position, "a1a2", 20);
+        let estimate = tally.estimate(30);
+        assert_eq!(estimate.solved, 1);
+        assert_eq!(estimate.searched, 2);
+        assert_eq!(estimate.total, 30);
+        assert_eq!(estimate.average_depth, 15.0);
+        assert_eq!(
+            estimate.elo,
+            Some(EloBand {
+                min: 1800,
+                max: 2200
+            })
+        );
+    }
+
+    #[test]
+    fn more_solutions_mean_higher_bands() {
+        assert_eq!(elo_band(0, 0), None);
+        assert_eq!(elo_band(0, 30).unwrap().min, 800);
+        assert_eq!(elo_band(9, 30).unwrap().min, 1400);
+        assert_eq!(elo_band(29, 30).unwrap().min, 3000);
+        assert_eq!(elo_band(30, 30).unwrap().max, 3600);
+        let mut previous = 0;
+        for solved in 0..=30 {
+            let band = elo_band(solved, 30).unwrap();
+            assert!(band.min >= previous);
+            previous = band.min;
+        }
+    }
+}
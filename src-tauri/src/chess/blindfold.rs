@@ -0,0 +1,295 @@
+//! Blindfold sessions: refereeing a game whose position the user can't see.
+//!
+//! Moves are validated and applied by the backend, which answers with as little as possible
+//! (whether the move was legal, a capture or a check). The position is only revealed through
+//! explicit peeks, which are counted, and at the end of the session.
+
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, EnPassantMode, Move};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::effects::MoveEffects;
+use super::evaluation::game_termination;
+use super::types::GameTermination;
+
+/// Feedback on a move, deliberately leaving out the position.
+#[derive(Serialize, Debug, Clone, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlindfoldMoveFeedback {
+    pub legal: bool,
+    pub capture: bool,
+    pub check: bool,
+    /// Set when the move ended the game.
+    pub termination: Option<GameTermination>,
+}
+
+/// The current position, revealed on request.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BlindfoldPeek {
+    pub fen: String,
+    /// Peeks used so far, including this one.
+    pub peeks: u32,
+}
+
+/// The full game of a finished session, with its stats.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BlindfoldSummary {
+    pub start_fen: String,
+    pub fen: String,
+    /// Moves played, in UCI notation.
+    pub moves: Vec<String>,
+    /// Moves played, in SAN notation.
+    pub san: Vec<String>,
+    pub termination: Option<GameTermination>,
+    pub illegal_attempts: u32,
+    pub peeks: u32,
+}
+
+/// A blindfold game in progress.
+pub struct BlindfoldSession {
+    start_fen: String,
+    history: Vec<Chess>,
+    moves: Vec<String>,
+    san: Vec<String>,
+    illegal_attempts: u32,
+    peeks: u32,
+}
+
+impl BlindfoldSession {
+    pub fn new(fen: String) -> Result<Self, Error> {
+        let parsed: Fen = fen.parse()?;
+        let position: Chess = parsed.into_position(CastlingMode::Chess960)?;
+        Ok(Self {
+            start_fen: fen,
+            history: vec![position],
+            moves: Vec::new(),
+            san: Vec::new(),
+            illegal_attempts: 0,
+            peeks: 0,
+        })
+    }
+
+    fn position(&self) -> &Chess {
+        self.history
+            .last()
+            .expect("history starts with the initial position")
+    }
+
+    /// Parse a move given in UCI or SAN notation.
+    fn parse_move(&self, mv: &str) -> Option<Move> {
+        let position = self.position();
+        UciMove::from_ascii(mv.as_bytes())
+            .ok()
+            .and_then(|uci| uci.to_move(position).ok())
+            .or_else(|| {
+                SanPlus::from_ascii(mv.trim().as_bytes())
+                    .ok()
+                    .and_then(|san| san.san.to_move(position).ok())
+            })
+    }
+
+    /// Validate and play a move, counting illegal attempts.
+    pub fn play(&mut self, mv: &str) -> BlindfoldMoveFeedback {
+        let parsed = match game_termination(&self.history) {
+            Some(_) => None,
+            None => self.parse_move(mv),
+        };
+        let Some(parsed) = parsed else {
+            self.illegal_attempts += 1;
+            return BlindfoldMoveFeedback {
+                legal: false,
+                capture: false,
+                check: false,
+                termination: None,
+            };
+        };
+
+        let mut position = self.position().clone();
+        let effects = MoveEffects::new(&position, &parsed);
+        let san = SanPlus::from_move_and_play_unchecked(&mut position, &parsed);
+        self.moves
+            .push(parsed.to_uci(CastlingMode::Standard).to_string());
+        self.san.push(san.to_string());
+        self.history.push(position);
+
+        BlindfoldMoveFeedback {
+            legal: true,
+            capture: effects.is_capture,
+            check: effects.is_check,
+            termination: game_termination(&self.history),
+        }
+    }
+
+    /// Reveal the current position, counting the peek.
+    pub fn peek(&mut self) -> BlindfoldPeek {
+        self.peeks += 1;
+        BlindfoldPeek {
+            fen: Fen::from_position(self.position().clone(), EnPassantMode::Legal).to_string(),
+            peeks: self.peeks,
+        }
+    }
+
+    pub fn summary(&self) -> BlindfoldSummary {
+        BlindfoldSummary {
+            start_fen: self.start_fen.clone(),
+            fen: Fen::from_position(self.position().clone(), EnPassantMode::Legal).to_string(),
+            moves: self.moves.clone(),
+            san: self.san.clone(),
+            termination: game_termination(&self.history),
+            illegal_attempts: self.illegal_attempts,
+            peeks: self.peeks,
+        }
+    }
+}
+
+/// Start a blindfold session from `fen`, returning the session identifier.
+#[tauri::command]
+#[specta::specta]
+pub async fn start_blindfold_session(
+    fen: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let session = BlindfoldSession::new(fen)?;
+    let id = Uuid::new_v4().to_string();
+    state.blindfold_sessions.insert(id.clone(), session);
+    Ok(id)
+}
+
+/// Play a move, in UCI or SAN notation, in a blindfold session.
+#[tauri::command]
+#[specta::specta]
+pub async fn blindfold_move(
+    session: String,
+    san_or_uci: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<BlindfoldMoveFeedback, Error> {
+    let mut blindfold = state
+        .blindfold_sessions
+        .get_mut(&session)
+        .ok_or(Error::BlindfoldSessionNotFound(session))?;
+    Ok(blindfold.play(&san_or_uci))
+}
+
+/// Reveal the position of a blindfold session.
+#[tauri::command]
+#[specta::specta]
+pub async fn blindfold_peek(
+    session: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<BlindfoldPeek, Error> {
+    let mut blindfold = state
+        .blindfold_sessions
+        .get_mut(&session)
+        .ok_or(Error::BlindfoldSessionNotFound(session))?;
+    Ok(blindfold.peek())
+}
+
+/// End a blindfold session, returning the full game.
+#[tauri::command]
+#[specta::specta]
+pub async fn finish_blindfold_session(
+    session: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<BlindfoldSummary, Error> {
+    let (_, blindfold) = state
+        .blindfold_sessions
+        .remove(&session)
+        .ok_or(Error::BlindfoldSessionNotFound(session))?;
+    Ok(blindfold.summary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn assert_no_position(feedback: &BlindfoldMoveFeedback, session: &BlindfoldSession) {
+        let json = serde_json::to_string(feedback).unwrap();
+        let fen = Fen::from_position(session.position().clone(), EnPassantMode::Legal).to_string();
+        let board = fen.split(' ').next().unwrap();
+        assert!(!json.contains(board), "{} leaks the position", json);
+        assert!(!json.contains("fen"), "{} leaks the position", json);
+    }
+
+    #[test]
+    fn moves_are_accepted_in_uci_and_san() {
+        let mut session = BlindfoldSession::new(START.to_string()).unwrap();
+        for mv in ["e2e4", "d5", "exd5", "Qxd5", "Nc3"] {
+            let feedback = session.play(mv);
+            assert!(feedback.legal, "{} should be legal", mv);
+            assert_no_position(&feedback, &session);
+        }
+        assert_eq!(session.san, vec!["e4", "d5", "exd5", "Qxd5", "Nc3"]);
+        assert_eq!(session.moves[3], "d8d5");
+    }
+
+    #[test]
+    fn feedback_reports_captures_and_checks() {
+        let mut session = BlindfoldSession::new(START.to_string()).unwrap();
+        session.play("e4");
+        session.play("d5");
+        let capture = session.play("exd5");
+        assert!(capture.capture);
+        assert!(!capture.check);
+
+        session.play("Qxd5");
+        session.play("Nc3");
+        let check = session.play("Qe5+");
+        assert!(!check.capture);
+        assert!(check.check);
+        assert_no_position(&check, &session);
+    }
+
+    #[test]
+    fn illegal_moves_are_counted_and_not_played() {
+        let mut session = BlindfoldSession::new(START.to_string()).unwrap();
+        let feedback = session.play("e2e5");
+        assert!(!feedback.legal);
+        assert_no_position(&feedback, &session);
+        assert!(!session.play("Nf6").legal);
+        assert!(!session.play("nonsense").legal);
+
+        let summary = session.summary();
+        assert_eq!(summary.illegal_attempts, 3);
+        assert!(summary.moves.is_empty());
+        assert_eq!(summary.fen, START);
+    }
+
+    #[test]
+    fn peeks_reveal_the_position_and_are_counted() {
+        let mut session = BlindfoldSession::new(START.to_string()).unwrap();
+        session.play("e4");
+        let peek = session.peek();
+        assert_eq!(
+            peek.fen,
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+        );
+        assert_eq!(peek.peeks, 1);
+        assert_eq!(session.peek().peeks, 2);
+        assert_eq!(session.summary().peeks, 2);
+    }
+
+    #[test]
+    fn moves_after_mate_are_illegal() {
+        let mut session = BlindfoldSession::new(START.to_string()).unwrap();
+        for mv in ["f3", "e5", "g4"] {
+            session.play(mv);
+        }
+        let mate = session.play("Qh4#");
+        assert!(mate.check);
+        assert_eq!(mate.termination, Some(GameTermination::Checkmate));
+
+        assert!(!session.play("a3").legal);
+        let summary = session.summary();
+        assert_eq!(summary.termination, Some(GameTermination::Checkmate));
+        assert_eq!(summary.san, vec!["f3", "e5", "g4", "Qh4#"]);
+        assert_eq!(summary.illegal_attempts, 1);
+    }
+}
@@ -0,0 +1,168 @@
+//! Engine book: an opening book built from the analysis cache.
+//!
+//! Every position ever analyzed gets the engines' preferred moves, so the explorer can show
+//! them instantly, without running an engine. When several engines or searches cover a move,
+//! the deepest one wins, and ties go to the newest engine version.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess, Color, Position};
+use specta::Type;
+use vampirc_uci::uci::Score;
+
+use crate::error::Error;
+
+use super::cache::{lines_for_position, open_analysis_cache, prune_lines, CachedLine, PositionKey};
+use super::evaluation::score_to_cp;
+
+/// A move of the engine book with its evaluation.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineBookMove {
+    pub uci: String,
+    pub san: String,
+    /// Evaluation from white's point of view.
+    pub score: Score,
+    pub depth: u32,
+    /// Name of the engine whose search is used.
+    pub engine: String,
+    /// Principal variation starting with the move, in SAN notation.
+    pub pv: Vec<String>,
+}
+
+/// Version numbers found in an engine name, e.g. `[17, 1]` for "Stockfish 17.1".
+fn engine_version(name: &str) -> Vec<u32> {
+    name.split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Pick one line per first move, preferring deeper searches and then newer engines, and sort
+/// the moves best first for the side to move.
+fn aggregate_book_moves(lines: Vec<CachedLine>, turn: Color) -> Vec<EngineBookMove> {
+    let mut best: HashMap<String, CachedLine> = HashMap::new();
+    for line in lines {
+        let Some(first) = line.uci_moves.split(' ').next().filter(|m| !m.is_empty()) else {
+            continue;
+        };
+        let rank = |l: &CachedLine| (l.depth, engine_version(&l.engine), l.updated_at);
+        match best.get(first) {
+            Some(existing) if rank(existing) >= rank(&line) => {}
+            _ => {
+                best.insert(first.to_string(), line);
+            }
+        }
+    }
+
+    let mut moves: Vec<EngineBookMove> = best
+        .into_iter()
+        .map(|(uci, line)| {
+            let pv: Vec<String> = line.san_moves.split(' ').map(str::to_string).collect();
+            EngineBookMove {
+                uci,
+                san: pv.first().cloned().unwrap_or_default(),
+                score: line.score(),
+                depth: line.depth as u32,
+                engine: line.engine,
+                pv,
+            }
+        })
+        .collect();
+
+    let sign = if turn == Color::White { 1 } else { -1 };
+    moves.sort_by(|a, b| {
+        (sign * score_to_cp(&b.score))
+            .cmp(&(sign * score_to_cp(&a.score)))
+            .then(b.depth.cmp(&a.depth))
+            .then(a.uci.cmp(&b.uci))
+    });
+    moves
+}
+
+/// Get the engine's preferred moves in `fen` from all analysis ever run at `min_depth` or more.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_engine_book_moves(
+    fen: String,
+    min_depth: u32,
+    app: tauri::AppHandle,
+) -> Result<Vec<EngineBookMove>, Error> {
+    let parsed: Fen = fen.parse()?;
+    let position: Chess = parsed.into_position(CastlingMode::Chess960)?;
+    let key = PositionKey::new(&position);
+
+    let db = &mut open_analysis_cache(&app)?;
+    let lines = lines_for_position(db, &key, min_depth)?;
+    Ok(aggregate_book_moves(lines, position.turn()))
+}
+
+/// Shrink the engine book to its `max_entries` most recent lines, returning how many were removed.
+#[tauri::command]
+#[specta::specta]
+pub async fn prune_engine_book(max_entries: u32, app: tauri::AppHandle) -> Result<u32, Error> {
+    let db = &mut open_analysis_cache(&app)?;
+    Ok(prune_lines(db, max_entries)? as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(engine: &str, depth: i32, score: &str, uci: &str, san: &str) -> CachedLine {
+        CachedLine {
+            engine: engine.to_string(),
+            line: 1,
+            depth,
+            score: score.to_string(),
+            uci_moves: uci.to_string(),
+            san_moves: san.to_string(),
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn engine_versions_are_compared_numerically() {
+        assert_eq!(engine_version("Stockfish 17.1"), vec![17, 1]);
+        assert!(engine_version("Stockfish 17") > engine_version("Stockfish 16.1"));
+        assert!(engine_version("Stockfish 9") < engine_version("Stockfish 10"));
+    }
+
+    #[test]
+    fn deepest_search_wins_then_newest_engine() {
+        let moves = aggregate_book_moves(
+            vec![
+                cached("Stockfish 16", 30, "cp 20", "e2e4 e7e5", "e4 e5"),
+                cached("Stockfish 17", 25, "cp 35", "e2e4 c7c5", "e4 c5"),
+                cached("Stockfish 16", 28, "cp 15", "d2d4 d7d5", "d4 d5"),
+                cached("Stockfish 17", 28, "cp 18", "d2d4 g8f6", "d4 Nf6"),
+            ],
+            Color::White,
+        );
+        assert_eq!(moves.len(), 2);
+
+        assert_eq!(moves[0].uci, "e2e4");
+        assert_eq!(moves[0].depth, 30);
+        assert_eq!(moves[0].engine, "Stockfish 16");
+        assert_eq!(moves[0].pv, vec!["e4", "e5"]);
+
+        assert_eq!(moves[1].uci, "d2d4");
+        assert_eq!(moves[1].engine, "Stockfish 17");
+        assert_eq!(moves[1].san, "d4");
+    }
+
+    #[test]
+    fn moves_are_sorted_for_the_side_to_move() {
+        let moves = aggregate_book_moves(
+            vec![
+                cached("Stockfish 17", 20, "cp 40", "e7e5", "e5"),
+                cached("Stockfish 17", 20, "cp 25", "c7c5", "c5"),
+                cached("Stockfish 17", 20, "mate 3", "f7f6", "f6"),
+            ],
+            Color::Black,
+        );
+        let order: Vec<_> = moves.iter().map(|m| m.uci.as_str()).collect();
+        assert_eq!(order, vec!["c7c5", "e7e5", "f7f6"]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Adaptive analysis budgets for game reports.
+//!
+//! Searching every position of a game to the same depth wastes time on forced recaptures
+//! and underspends on critical moments. Adaptive analysis first searches every position to
+//! a small depth, then spends what's left of the game's time budget deepening the positions
+//! that look interesting: the evaluation moved between depths, the best move changed, the
+//! move played was a sacrifice, or the evaluation swung from the previous position. The most
+//! interesting positions are deepened first, a step at a time, for as long as the next
+//! search is expected to fit in the remaining time.
+//!
+//! The scheduler only sees what searches reported and how long they took, so it can be
+//! driven by any engine.
+
+use serde::Deserialize;
+use specta::Type;
+
+use super::evaluation::score_to_cp;
+use super::types::BestMoves;
+
+/// Depth added to a position each time it's deepened, unless configured otherwise.
+const DEFAULT_DEPTH_STEP: u32 = 4;
+
+/// Evaluation change between the last two depths of a search, in centipawns, making a
+/// position unstable, unless configured otherwise.
+const DEFAULT_INSTABILITY_CP: i32 = 40;
+
+/// Evaluation change from the previous position, in centipawns, making a position critical,
+/// unless configured otherwise.
+const DEFAULT_SWING_CP: i32 = 150;
+
+/// How much longer a search gets per extra depth, for estimating the cost of deepening.
+const DEPTH_COST_GROWTH: f64 = 1.6;
+
+/// Budget for analysing a game adaptively.
+#[derive(Deserialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct AdaptiveConfig {
+    /// Depth every position is searched to first.
+    pub initial_depth: u32,
+    /// Depth interesting positions are deepened to at most.
+    pub max_depth: u32,
+    /// Time for analysing the whole game, in milliseconds.
+    pub time_budget_ms: u32,
+    /// Depth added each time a position is deepened.
+    #[specta(optional)]
+    pub depth_step: Option<u32>,
+    /// Evaluation change between depths, in centipawns, making a position unstable.
+    #[specta(optional)]
+    pub instability_cp: Option<i32>,
+    /// Evaluation change from the previous position, in centipawns, making it critical.
+    #[specta(optional)]
+    pub swing_cp: Option<i32>,
+}
+
+/// What a search reported once it completed a depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSample {
+    pub depth: u32,
+    /// Evaluation from White's point of view, in centipawns, capped as `score_to_cp` does.
+    pub cp: i32,
+    pub best_move: Option<String>,
+}
+
+impl DepthSample {
+    /// Sample of the first line of a search that completed a depth.
+    pub fn from_line(line: &BestMoves) -> Self {
+        Self {
+            depth: line.depth,
+            cp: score_to_cp(&line.score),
+            best_move: line.uci_moves.first().cloned(),
+        }
+    }
+}
+
+/// Why a position deserves a deeper search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Interest {
+    pub unstable: bool,
+    pub best_move_changed: bool,
+    pub sacrifice: bool,
+    pub swing: bool,
+}
+
+impl Interest {
+    fn score(&self) -> u32 {
+        [
+            self.unstable,
+            self.best_move_changed,
+            self.sacrifice,
+            self.swing,
+        ]
+        .into_iter()
+        .filter(|&flag| flag)
+        .count() as u32
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Searched {
+    /// Samples of the latest search, by increasing depth.
+    samples: Vec<DepthSample>,
+    /// Time the latest search took, in milliseconds.
+    elapsed_ms: u64,
+    /// Set when a search didn't get any deeper, so searching again wouldn't help.
+    exhausted: bool,
+}
+
+/// Decides which positions of a game to deepen, given as plies from the start position.
+#[derive(Debug)]
+pub struct AdaptiveScheduler {
+    config: AdaptiveConfig,
+    positions: Vec<Option<Searched>>,
+    sacrifices: Vec<bool>,
+}
+
+impl AdaptiveScheduler {
+    /// A scheduler for a game with `plies` positions, where `sacrifices` tells which were
+    /// reached by a sacrifice.
+    pub fn new(config: AdaptiveConfig, plies: usize, sacrifices: Vec<bool>) -> Self {
+        Self {
+            config,
+            positions: vec![None; plies],
+            sacrifices,
+        }
+    }
+
+    /// Depth the first search of every position goes to.
+    pub fn initial_depth(&self) -> u32 {
+        self.config.initial_depth
+    }
+
+    /// Record the samples of a search of the position at `ply`, replacing earlier ones.
+    ///
+    /// Positions aren't deepened again after a search that didn't go deeper than the one
+    /// before, as when it reported nothing or stopped early on a mate.
+    pub fn record(&mut self, ply: usize, samples: Vec<DepthSample>, elapsed_ms: u64) {
+        let Some(position) = self.positions.get_mut(ply) else {
+            return;
+        };
+        let previous_depth = position
+            .as_ref()
+            .and_then(|searched| searched.samples.last())
+            .map(|sample| sample.depth);
+        let depth = samples.last().map(|sample| sample.depth);
+        if depth <= previous_depth {
+            if let Some(searched) = position {
+                searched.exhausted = true;
+            }
+            return;
+        }
+        *position = Some(Searched {
+            samples,
+            elapsed_ms,
+            exhausted: false,
+        });
+    }
+
+    /// Depth reached in the position at `ply`, if it was searched.
+    pub fn depth(&self, ply: usize) -> Option<u32> {
+        self.final_sample(ply).map(|sample| sample.depth)
+    }
+
+    fn final_sample(&self, ply: usize) -> Option<&DepthSample> {
+        self.positions.get(ply)?.as_ref()?.samples.last()
+    }
+
+    /// Why the position at `ply` is interesting, judging from its latest search.
+    pub fn interest(&self, ply: usize) -> Interest {
+        let Some(Some(searched)) = self.positions.get(ply) else {
+            return Interest::default();
+        };
+        let instability = self.config.instability_cp.unwrap_or(DEFAULT_INSTABILITY_CP);
+        let swing = self.config.swing_cp.unwrap_or(DEFAULT_SWING_CP);
+
+        let (last, previous_depth) = match searched.samples.as_slice() {
+            [.., previous, last] => (last, Some(previous)),
+            [last] => (last, None),
+            [] => return Interest::default(),
+        };
+        let previous_position = ply
+            .checked_sub(1)
+            .and_then(|previous| self.final_sample(previous));
+        Interest {
+            unstable: previous_depth.is_some_and(|p| (last.cp - p.cp).abs() >= instability),
+            best_move_changed: previous_depth.is_some_and(|p| p.best_move != last.best_move),
+            sacrifice: self.sacrifices.get(ply).copied().unwrap_or(false),
+            swing: previous_position.is_some_and(|p| (last.cp - p.cp).abs() >= swing),
+        }
+    }
+
+    /// Position to deepen next and the depth to search it to, after `spent_ms` of the time
+    /// budget were used. `None` once nothing interesting is left or nothing fits anymore.
+    ///
+    /// The most interesting positions go first, then the shallowest, then the earliest.
+    pub fn next(&self, spent_ms: u64) -> Option<(usize, u32)> {
+        let remaining = (self.config.time_budget_ms as u64).checked_sub(spent_ms)?;
+        let step = self.config.depth_step.unwrap_or(DEFAULT_DEPTH_STEP).max(1);
+        self.positions
+            .iter()
+            .enumerate()
+            .filter_map(|(ply, searched)| {
+                let searched = searched.as_ref().filter(|searched| !searched.exhausted)?;
+                let depth = searched.samples.last()?.depth;
+                if depth >= self.config.max_depth {
+                    return None;
+                }
+                let score = self.interest(ply).score();
+                if score == 0 {
+                    return None;
+                }
+                let target = (depth + step).min(self.config.max_depth);
+                let cost = searched.elapsed_ms.max(1) as f64
+                    * DEPTH_COST_GROWTH.powi((target - depth) as i32);
+                (cost <= remaining as f64).then_some((ply, target, score, depth))
+            })
+            .max_by(|a, b| a.2.cmp(&b.2).then(b.3.cmp(&a.3)).then(b.0.cmp(&a.0)))
+            .map(|(ply, target, _, _)| (ply, target))
+    }
+
+    /// Share of the time budget used, from 0 to 1.
+    pub fn progress(&self, spent_ms: u64) -> f64 {
+        (spent_ms as f64 / self.config.time_budget_ms.max(1) as f64).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AdaptiveConfig {
+        AdaptiveConfig {
+            initial_depth: 8,
+            max_depth: 16,
+            time_budget_ms: 1_000,
+            depth_step: None,
+            instability_cp: None,
+            swing_cp: None,
+        }
+    }
+
+    /// Samples of a search ending at `depth`, with these evaluations and best moves at the
+    /// last depths.
+    fn search(depth: u32, evals: &[(i32, &str)]) -> Vec<DepthSample> {
+        evals
+            .iter()
+            .enumerate()
+            .map(|(i, &(cp, best_move))| DepthSample {
+                depth: depth + 1 + i as u32 - evals.len() as u32,
+                cp,
+                best_move: Some(best_move.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interesting_positions() {
+        let sacrifices = vec![false, false, false, true, false];
+        let mut scheduler = AdaptiveScheduler::new(config(), 5, sacrifices);
+        scheduler.record(0, search(8, &[(20, "e2e4"), (25, "e2e4")]), 10);
+        // The evaluation moved between the last two depths.
+        scheduler.record(1, search(8, &[(20, "e7e5"), (-30, "e7e5")]), 10);
+        // The best move changed.
+        scheduler.record(2, search(8, &[(10, "g1f3"), (15, "b1c3")]), 10);
+        // Reached by a sacrifice, and a big swing from the previous position.
+        scheduler.record(3, search(8, &[(200, "d8h4"), (210, "d8h4")]), 10);
+        // Searches that reported nothing don't count.
+        scheduler.record(4, Vec::new(), 10);
+
+        assert_eq!(scheduler.interest(0), Interest::default());
+        assert_eq!(
+            scheduler.interest(1),
+            Interest {
+                unstable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            scheduler.interest(2),
+            Interest {
+                best_move_changed: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            scheduler.interest(3),
+            Interest {
+                sacrifice: true,
+                swing: true,
+                ..Default::default()
+            }
+        );
+        // Positions that weren't searched, like a checkmate, are never deepened.
+        assert_eq!(scheduler.interest(4), Interest::default());
+        assert_eq!(scheduler.depth(4), None);
+        assert_eq!(scheduler.depth(3), Some(8));
+    }
+
+    #[test]
+    fn deepening_follows_interest_until_the_budget_runs_out() {
+        let mut scheduler = AdaptiveScheduler::new(config(), 4, vec![false; 4]);
+        // A quiet game, except for a swing at ply 2 and an unstable ply 3.
+        scheduler.record(0, search(8, &[(20, "e2e4"), (20, "e2e4")]), 50);
+        scheduler.record(1, search(8, &[(20, "e7e5"), (20, "e7e5")]), 50);
+        scheduler.record(2, search(8, &[(300, "d1h5"), (310, "d1h5")]), 50);
+        scheduler.record(3, search(8, &[(300, "g8f6"), (200, "g8f6")]), 50);
+
+        // Both are interesting for one reason, so the earlier goes first.
+        let spent = 200;
+        assert_eq!(scheduler.next(spent), Some((2, 12)));
+        scheduler.record(2, search(12, &[(305, "d1h5"), (305, "d1h5")]), 100);
+        // Ply 2 still swings, but it's deeper than ply 3 now.
+        let spent = spent + 100;
+        assert_eq!(scheduler.next(spent), Some((3, 12)));
+        scheduler.record(3, search(12, &[(310, "g8f6"), (305, "g8f6")]), 100);
+        // Ply 3 settled down, and ply 2 is expected to take more than what's left.
+        let spent = spent + 100;
+        assert_eq!(scheduler.next(spent), None);
+        assert_eq!(scheduler.progress(spent), 0.4);
+        // With more time, ply 2 is deepened up to the maximum depth.
+        assert_eq!(scheduler.next(0), Some((2, 16)));
+        assert_eq!(scheduler.next(2_000), None);
+        // Unless the search stops short, e.g. on a mate.
+        scheduler.record(2, search(12, &[(305, "d1h5")]), 10);
+        assert_eq!(scheduler.next(0), None);
+        assert_eq!(scheduler.depth(2), Some(12));
+    }
+}
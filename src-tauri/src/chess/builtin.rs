@@ -0,0 +1,951 @@
+//! Built-in engine, so analysis works before any engine is installed.
+//!
+//! The engine is a small alpha-beta search with iterative deepening and a quiescence search,
+//! evaluating material and piece placement. It is weak, but it speaks UCI over in-memory pipes
+//! instead of a process's stdin and stdout, so everything built on `EngineProcess` (analysis,
+//! the eval bar, game reports, play sessions) works with it unchanged. The UCI loop runs as a
+//! task and each search on a blocking worker.
+//!
+//! Strength (the maximum depth) and the node limit are UCI options. Moves that score the same
+//! are ordered by the `Seed` option, so searches limited by depth or nodes always give the
+//! same result for the same seed.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use shakmaty::{
+    fen::Fen, uci::UciMove, CastlingMode, Chess, Color, Move, Position, PositionError, Role,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use super::evaluation::piece_value;
+use super::uci::{EngineStdin, EngineStdout};
+
+/// Engine path selecting the built-in engine.
+pub const BUILTIN_ENGINE: &str = "built-in";
+
+const ENGINE_NAME: &str = "Pawn Appetit Built-in";
+
+/// Maximum depth, by default and at most.
+const DEFAULT_STRENGTH: u32 = 4;
+const MAX_STRENGTH: u32 = 8;
+
+/// Nodes searched at most, by default and at most.
+const DEFAULT_NODE_LIMIT: u64 = 200_000;
+const MAX_NODE_LIMIT: u64 = 10_000_000;
+
+const MAX_MULTIPV: usize = 64;
+
+/// Score of a mate on the board, from the mated side's point of view.
+const MATE: i32 = 30_000;
+const INFINITY: i32 = MATE + 1;
+
+/// Depth after which the quiescence search stands pat.
+const MAX_PLY: u32 = 64;
+
+/// Nodes between checks of the stop flag and the clock.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Capacity of the pipes between the engine and its user.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Options set with `setoption`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EngineSettings {
+    strength: u32,
+    node_limit: u64,
+    multipv: usize,
+    seed: u64,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        Self {
+            strength: DEFAULT_STRENGTH,
+            node_limit: DEFAULT_NODE_LIMIT,
+            multipv: 1,
+            seed: 0,
+        }
+    }
+}
+
+impl EngineSettings {
+    fn set(&mut self, name: &str, value: &str) {
+        let Ok(value) = value.parse::<u64>() else {
+            return;
+        };
+        if name.eq_ignore_ascii_case("Strength") {
+            self.strength = (value as u32).clamp(1, MAX_STRENGTH);
+        } else if name.eq_ignore_ascii_case("NodeLimit") {
+            self.node_limit = value.clamp(1, MAX_NODE_LIMIT);
+        } else if name.eq_ignore_ascii_case("MultiPV") {
+            self.multipv = (value as usize).clamp(1, MAX_MULTIPV);
+        } else if name.eq_ignore_ascii_case("Seed") {
+            self.seed = value;
+        }
+    }
+}
+
+/// Parameters of a `go` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct GoParams {
+    depth: Option<u32>,
+    nodes: Option<u64>,
+    movetime: Option<u64>,
+    wtime: Option<u64>,
+    btime: Option<u64>,
+    winc: Option<u64>,
+    binc: Option<u64>,
+    infinite: bool,
+    ponder: bool,
+    searchmoves: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Command {
+    Uci,
+    IsReady,
+    SetOption { name: String, value: String },
+    Position(Box<Chess>),
+    Go(GoParams),
+    PonderHit,
+    Stop,
+    Quit,
+}
+
+/// Parse a command sent to the engine. Unknown commands and invalid positions are ignored,
+/// as engines do.
+fn parse_command(line: &str) -> Option<Command> {
+    let mut words = line.split_whitespace();
+    Some(match words.next()? {
+        "uci" => Command::Uci,
+        "isready" => Command::IsReady,
+        "stop" => Command::Stop,
+        "ponderhit" => Command::PonderHit,
+        "quit" => Command::Quit,
+        "setoption" => {
+            let words: Vec<&str> = words.collect();
+            let value_at = words.iter().position(|word| *word == "value");
+            let name = words.get(1..value_at.unwrap_or(words.len()))?.join(" ");
+            let value = value_at
+                .map(|at| words[at + 1..].join(" "))
+                .unwrap_or_default();
+            Command::SetOption { name, value }
+        }
+        "position" => match parse_position(words) {
+            Ok(position) => Command::Position(Box::new(position)),
+            Err(e) => {
+                warn!("Built-in engine ignores position: {}", e);
+                return None;
+            }
+        },
+        "go" => {
+            let mut go = GoParams::default();
+            while let Some(word) = words.next() {
+                let mut number = || words.next().and_then(|value| value.parse().ok());
+                match word {
+                    "depth" => go.depth = number().map(|depth: u64| depth as u32),
+                    "nodes" => go.nodes = number(),
+                    // A mate in n moves takes at most 2n - 1 plies.
+                    "mate" => {
+                        go.depth = number().map(|moves: u32| (2 * moves).saturating_sub(1).max(1))
+                    }
+                    "movetime" => go.movetime = number(),
+                    "wtime" => go.wtime = number(),
+                    "btime" => go.btime = number(),
+                    "winc" => go.winc = number(),
+                    "binc" => go.binc = number(),
+                    "infinite" => go.infinite = true,
+                    "ponder" => go.ponder = true,
+                    "searchmoves" => {
+                        go.searchmoves = words.by_ref().map(str::to_string).collect();
+                    }
+                    _ => {}
+                }
+            }
+            Command::Go(go)
+        }
+        _ => return None,
+    })
+}
+
+fn parse_position<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Chess, String> {
+    let mut position = match words.next() {
+        Some("startpos") => Chess::default(),
+        Some("fen") => {
+            let fen: Vec<&str> = words.by_ref().take_while(|word| *word != "moves").collect();
+            let fen: Fen = fen.join(" ").parse().map_err(|e| format!("{}", e))?;
+            return play_moves(
+                fen.into_position(CastlingMode::Chess960)
+                    .or_else(PositionError::ignore_too_much_material)
+                    .map_err(|e| e.to_string())?,
+                words,
+            );
+        }
+        _ => return Err("missing startpos or fen".to_string()),
+    };
+    if words.next() == Some("moves") {
+        position = play_moves(position, words)?;
+    }
+    Ok(position)
+}
+
+fn play_moves<'a>(
+    mut position: Chess,
+    moves: impl Iterator<Item = &'a str>,
+) -> Result<Chess, String> {
+    for uci in moves {
+        let m = UciMove::from_ascii(uci.as_bytes())
+            .map_err(|e| e.to_string())?
+            .to_move(&position)
+            .map_err(|e| e.to_string())?;
+        position.play_unchecked(&m);
+    }
+    Ok(position)
+}
+
+/// Limits of a single search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SearchLimits {
+    depth: u32,
+    nodes: u64,
+    time: Option<Duration>,
+    multipv: usize,
+    seed: u64,
+}
+
+impl SearchLimits {
+    fn new(settings: &EngineSettings, go: &GoParams, turn: Color) -> Self {
+        let (time, increment) = match turn {
+            Color::White => (go.wtime, go.winc),
+            Color::Black => (go.btime, go.binc),
+        };
+        // Without a move time, spend a thirtieth of the clock and half the increment.
+        let clock = time.map(|time| {
+            (time / 30 + increment.unwrap_or(0) / 2).min(time.saturating_sub(50).max(10))
+        });
+        Self {
+            depth: go.depth.unwrap_or(MAX_STRENGTH).clamp(1, settings.strength),
+            nodes: go
+                .nodes
+                .unwrap_or(settings.node_limit)
+                .min(settings.node_limit),
+            time: go.movetime.or(clock).map(Duration::from_millis),
+            multipv: settings.multipv,
+            seed: settings.seed,
+        }
+    }
+}
+
+/// Line found for a root move.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RootLine {
+    score: i32,
+    pv: Vec<Move>,
+}
+
+/// Lines of a completed iteration, best first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Iteration {
+    depth: u32,
+    seldepth: u32,
+    nodes: u64,
+    lines: Vec<RootLine>,
+}
+
+/// Material and piece placement, from the point of view of the side to move.
+fn evaluate(position: &Chess) -> i32 {
+    let board = position.board();
+    let mut score = 0;
+    for square in board.occupied() {
+        let Some(piece) = board.piece_at(square) else {
+            continue;
+        };
+        let index = u32::from(square);
+        let (file, rank) = ((index % 8) as i32, (index / 8) as i32);
+        let centrality = file.min(7 - file) + rank.min(7 - rank);
+        let advance = match piece.color {
+            Color::White => rank,
+            Color::Black => 7 - rank,
+        };
+        let placement = match piece.role {
+            Role::Pawn => advance * 6 + if (3..=4).contains(&file) { 4 } else { 0 },
+            Role::Knight => centrality * 5,
+            Role::Bishop => centrality * 3,
+            Role::Rook if advance == 6 => 15,
+            Role::Queen => centrality,
+            Role::Rook | Role::King => 0,
+        };
+        let value = piece_value(piece.role) + placement;
+        score += if piece.color == position.turn() {
+            value
+        } else {
+            -value
+        };
+    }
+    score
+}
+
+/// Ordering of moves: captures by victim then attacker, then promotions, then the rest.
+fn move_order(m: &Move) -> i32 {
+    let capture = m
+        .capture()
+        .map(|victim| 10 * piece_value(victim) - piece_value(m.role()))
+        .unwrap_or(0);
+    let promotion = m.promotion().map(piece_value).unwrap_or(0);
+    -(capture + promotion)
+}
+
+/// Signals from the UCI loop to a running search.
+#[derive(Debug)]
+struct SearchSignals {
+    stop: AtomicBool,
+    /// When the time of the search started counting, `None` while it ponders.
+    clock_started: Mutex<Option<Instant>>,
+}
+
+impl SearchSignals {
+    fn new(ponder: bool) -> Self {
+        Self {
+            stop: AtomicBool::new(false),
+            clock_started: Mutex::new((!ponder).then(Instant::now)),
+        }
+    }
+
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// The move pondered on was played, so the time of the search starts counting.
+    fn ponderhit(&self) {
+        if let Ok(mut clock_started) = self.clock_started.lock() {
+            clock_started.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Time counted against the search, `None` while it ponders.
+    fn elapsed(&self) -> Option<Duration> {
+        let clock_started = self.clock_started.lock().ok()?;
+        clock_started.map(|started| started.elapsed())
+    }
+}
+
+struct Searcher<'a> {
+    limits: &'a SearchLimits,
+    signals: &'a SearchSignals,
+    started: Instant,
+    nodes: u64,
+    seldepth: u32,
+    /// Whether a search may be cut short. The first iteration always completes, so that there
+    /// is a move to play.
+    may_abort: bool,
+    aborted: bool,
+}
+
+impl Searcher<'_> {
+    /// Whether the node limit, the time or a `stop` ended the search.
+    fn exhausted(&self) -> bool {
+        self.nodes >= self.limits.nodes
+            || self.signals.stopped()
+            || self.limits.time.is_some_and(|time| {
+                self.signals
+                    .elapsed()
+                    .is_some_and(|elapsed| elapsed >= time)
+            })
+    }
+
+    fn out_of_budget(&mut self) -> bool {
+        if self.may_abort && !self.aborted {
+            self.aborted = self.nodes >= self.limits.nodes
+                || (self.nodes % CHECK_INTERVAL == 0 && self.exhausted());
+        }
+        self.aborted
+    }
+
+    fn ordered_moves(position: &Chess) -> Vec<Move> {
+        let mut moves: Vec<Move> = position.legal_moves().into_iter().collect();
+        moves.sort_by_key(move_order);
+        moves
+    }
+
+    fn quiesce(&mut self, position: &Chess, ply: u32, mut alpha: i32, beta: i32) -> i32 {
+        if self.out_of_budget() {
+            return 0;
+        }
+        self.nodes += 1;
+        self.seldepth = self.seldepth.max(ply);
+
+        let in_check = position.is_check();
+        let moves = Self::ordered_moves(position);
+        if moves.is_empty() {
+            return if in_check { ply as i32 - MATE } else { 0 };
+        }
+        if !in_check || ply >= MAX_PLY {
+            let stand_pat = evaluate(position);
+            if stand_pat >= beta || ply >= MAX_PLY {
+                return stand_pat.min(beta);
+            }
+            alpha = alpha.max(stand_pat);
+        }
+
+        for m in moves {
+            // In check, every evasion is searched.
+            if !in_check && !m.is_capture() && !m.is_promotion() {
+                continue;
+            }
+            let mut child = position.clone();
+            child.play_unchecked(&m);
+            let score = -self.quiesce(&child, ply + 1, -beta, -alpha);
+            if self.aborted {
+                return 0;
+            }
+            if score >= beta {
+                return beta;
+            }
+            alpha = alpha.max(score);
+        }
+        alpha
+    }
+
+    fn negamax(
+        &mut self,
+        position: &Chess,
+        depth: u32,
+        ply: u32,
+        mut alpha: i32,
+        beta: i32,
+        pv: &mut Vec<Move>,
+    ) -> i32 {
+        if depth == 0 {
+            return self.quiesce(position, ply, alpha, beta);
+        }
+        if self.out_of_budget() {
+            return 0;
+        }
+        self.nodes += 1;
+        self.seldepth = self.seldepth.max(ply);
+
+        let moves = Self::ordered_moves(position);
+        if moves.is_empty() {
+            return if position.is_check() {
+                ply as i32 - MATE
+            } else {
+                0
+            };
+        }
+        if position.halfmoves() >= 100 || position.is_insufficient_material() {
+            return 0;
+        }
+
+        for m in moves {
+            let mut child = position.clone();
+            child.play_unchecked(&m);
+            let mut child_pv = Vec::new();
+            let score = -self.negamax(&child, depth - 1, ply + 1, -beta, -alpha, &mut child_pv);
+            if self.aborted {
+                return 0;
+            }
+            if score >= beta {
+                return beta;
+            }
+            if score > alpha {
+                alpha = score;
+                pv.clear();
+                pv.push(m);
+                pv.extend(child_pv);
+            }
+        }
+        alpha
+    }
+
+    /// Search every root move to `depth`, keeping the best `multipv` lines with exact scores.
+    fn search_root(
+        &mut self,
+        position: &Chess,
+        moves: &[Move],
+        depth: u32,
+    ) -> Option<Vec<RootLine>> {
+        let mut lines: Vec<RootLine> = Vec::new();
+        for m in moves {
+            // Moves that can't make it into the lines only need a bound.
+            let alpha = match lines.get(self.limits.multipv - 1) {
+                Some(line) => line.score,
+                None => -INFINITY,
+            };
+            let mut child = position.clone();
+            child.play_unchecked(m);
+            let mut continuation = Vec::new();
+            let score = -self.negamax(&child, depth - 1, 1, -INFINITY, -alpha, &mut continuation);
+            if self.aborted {
+                return None;
+            }
+            if score > alpha {
+                let mut pv = vec![m.clone()];
+                pv.extend(continuation);
+                // After the lines of equal score, so that ties keep the move order.
+                let at = lines.partition_point(|line| line.score >= score);
+                lines.insert(at, RootLine { score, pv });
+                lines.truncate(self.limits.multipv);
+            }
+        }
+        Some(lines)
+    }
+}
+
+/// Run an iterative deepening search of `position`, reporting each completed iteration.
+///
+/// Returns the last completed iteration, or `None` if there are no legal moves.
+fn search(
+    position: &Chess,
+    searchmoves: &[String],
+    limits: &SearchLimits,
+    signals: &SearchSignals,
+    mut report: impl FnMut(&Iteration, Duration),
+) -> Option<Iteration> {
+    let mut moves: Vec<Move> = position
+        .legal_moves()
+        .into_iter()
+        .filter(|m| searchmoves.is_empty() || searchmoves.contains(&uci(m)))
+        .collect();
+    if moves.is_empty() {
+        return None;
+    }
+    moves.shuffle(&mut StdRng::seed_from_u64(limits.seed));
+    moves.sort_by_key(move_order);
+
+    let mut searcher = Searcher {
+        limits,
+        signals,
+        started: Instant::now(),
+        nodes: 0,
+        seldepth: 0,
+        may_abort: false,
+        aborted: false,
+    };
+    let mut last = None;
+    for depth in 1..=limits.depth {
+        let Some(lines) = searcher.search_root(position, &moves, depth) else {
+            break;
+        };
+        // The best lines are searched first next time.
+        let best: Vec<Move> = lines.iter().map(|line| line.pv[0].clone()).collect();
+        moves.sort_by_key(|m| best.iter().position(|b| b == m).unwrap_or(best.len()));
+
+        let iteration = Iteration {
+            depth,
+            seldepth: searcher.seldepth.max(depth),
+            nodes: searcher.nodes,
+            lines,
+        };
+        report(&iteration, searcher.started.elapsed());
+        let mate_found = iteration.lines[0].score.abs() >= MATE - MAX_PLY as i32;
+        last = Some(iteration);
+        searcher.may_abort = true;
+        if mate_found || searcher.exhausted() {
+            break;
+        }
+    }
+    last
+}
+
+fn format_score(score: i32) -> String {
+    if score.abs() >= MATE - MAX_PLY as i32 {
+        // Plies to mate, as moves, negative when getting mated.
+        let plies = MATE - score.abs();
+        let moves = (plies + 1) / 2;
+        format!("mate {}", if score > 0 { moves } else { -moves })
+    } else {
+        format!("cp {}", score)
+    }
+}
+
+fn uci(m: &Move) -> String {
+    m.to_uci(CastlingMode::Standard).to_string()
+}
+
+/// UCI `info` lines of an iteration.
+fn info_lines(iteration: &Iteration, elapsed: Duration) -> Vec<String> {
+    let millis = elapsed.as_millis().max(1) as u64;
+    iteration
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {} time {} pv {}\n",
+                iteration.depth,
+                iteration.seldepth,
+                i + 1,
+                format_score(line.score),
+                iteration.nodes,
+                iteration.nodes * 1000 / millis,
+                millis,
+                line.pv.iter().map(uci).collect::<Vec<_>>().join(" ")
+            )
+        })
+        .collect()
+}
+
+/// Search on a blocking worker, sending `info` lines and then the best move.
+///
+/// Infinite searches wait to be stopped, and ponder searches for the move pondered on to be
+/// played or for a stop, before sending the best move, as UCI requires. A ponder search is
+/// only timed from `ponderhit` on.
+fn start_search(
+    position: Chess,
+    go: GoParams,
+    settings: EngineSettings,
+    output: UnboundedSender<String>,
+) -> (Arc<SearchSignals>, JoinHandle<()>) {
+    let signals = Arc::new(SearchSignals::new(go.ponder));
+    let shared = signals.clone();
+    let worker = tokio::task::spawn_blocking(move || {
+        let limits = SearchLimits::new(&settings, &go, position.turn());
+        let result = search(
+            &position,
+            &go.searchmoves,
+            &limits,
+            &shared,
+            |iteration, elapsed| {
+                for line in info_lines(iteration, elapsed) {
+                    output.send(line).ok();
+                }
+            },
+        );
+        while !shared.stopped()
+            && (go.infinite || shared.elapsed().is_none())
+            && !output.is_closed()
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let best = match result {
+            Some(iteration) => {
+                let pv = &iteration.lines[0].pv;
+                match pv.get(1) {
+                    Some(ponder) => format!("bestmove {} ponder {}\n", uci(&pv[0]), uci(ponder)),
+                    None => format!("bestmove {}\n", uci(&pv[0])),
+                }
+            }
+            None => {
+                let score = if position.is_check() {
+                    "mate 0"
+                } else {
+                    "cp 0"
+                };
+                output.send(format!("info depth 0 score {}\n", score)).ok();
+                "bestmove (none)\n".to_string()
+            }
+        };
+        output.send(best).ok();
+    });
+    (signals, worker)
+}
+
+async fn stop_search(search: &mut Option<(Arc<SearchSignals>, JoinHandle<()>)>) {
+    if let Some((signals, worker)) = search.take() {
+        signals.stop();
+        worker.await.ok();
+    }
+}
+
+/// Answer UCI commands from `input` on `output` until `quit` or the end of the input.
+async fn run(input: impl AsyncRead + Unpin, mut output: impl AsyncWrite + Unpin + Send + 'static) {
+    let (sender, mut receiver) = unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = receiver.recv().await {
+            if output.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut settings = EngineSettings::default();
+    let mut position = Chess::default();
+    let mut current: Option<(Arc<SearchSignals>, JoinHandle<()>)> = None;
+    let mut lines = BufReader::new(input).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match parse_command(&line) {
+            Some(Command::Uci) => {
+                let handshake = [
+                    format!("id name {}\n", ENGINE_NAME),
+                    "id author the Pawn Appetit developers\n".to_string(),
+                    format!(
+                        "option name Strength type spin default {} min 1 max {}\n",
+                        DEFAULT_STRENGTH, MAX_STRENGTH
+                    ),
+                    format!(
+                        "option name NodeLimit type spin default {} min 1 max {}\n",
+                        DEFAULT_NODE_LIMIT, MAX_NODE_LIMIT
+                    ),
+                    format!(
+                        "option name MultiPV type spin default 1 min 1 max {}\n",
+                        MAX_MULTIPV
+                    ),
+                    "option name Seed type spin default 0 min 0 max 2147483647\n".to_string(),
+                    "uciok\n".to_string(),
+                ];
+                for line in handshake {
+                    sender.send(line).ok();
+                }
+            }
+            Some(Command::IsReady) => {
+                sender.send("readyok\n".to_string()).ok();
+            }
+            Some(Command::SetOption { name, value }) => settings.set(&name, &value),
+            Some(Command::Position(new_position)) => {
+                stop_search(&mut current).await;
+                position = *new_position;
+            }
+            Some(Command::Go(go)) => {
+                stop_search(&mut current).await;
+                current = Some(start_search(position.clone(), go, settings, sender.clone()));
+            }
+            Some(Command::PonderHit) => {
+                if let Some((signals, _)) = &current {
+                    signals.ponderhit();
+                }
+            }
+            Some(Command::Stop) => stop_search(&mut current).await,
+            Some(Command::Quit) => break,
+            None => {}
+        }
+    }
+    stop_search(&mut current).await;
+}
+
+/// Start the built-in engine, returning its input and output as if it were a process.
+pub fn spawn() -> (EngineStdin, EngineStdout) {
+    let (user, engine) = tokio::io::duplex(PIPE_CAPACITY);
+    let (engine_input, engine_output) = tokio::io::split(engine);
+    let (user_output, user_input) = tokio::io::split(user);
+    tokio::spawn(run(engine_input, engine_output));
+    (
+        Box::new(user_input),
+        BufReader::new(Box::new(user_output) as Box<dyn AsyncRead + Send + Unpin>).lines(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::process::{parse_uci_attrs, EngineProcess};
+    use crate::chess::types::{EngineOption, EngineOptions, GoMode, PlayersTime};
+    use crate::scope::PathScope;
+    use std::path::PathBuf;
+    use vampirc_uci::{parse_one, UciMessage};
+
+    fn position(fen: &str) -> Chess {
+        parse_position(format!("fen {}", fen).split_whitespace()).unwrap()
+    }
+
+    fn limits(depth: u32, multipv: usize, seed: u64) -> SearchLimits {
+        SearchLimits {
+            depth,
+            nodes: DEFAULT_NODE_LIMIT,
+            time: None,
+            multipv,
+            seed,
+        }
+    }
+
+    fn best_lines(position: &Chess, limits: &SearchLimits) -> Iteration {
+        search(position, &[], limits, &SearchSignals::new(false), |_, _| {}).unwrap()
+    }
+
+    #[test]
+    fn finds_mates_and_wins_material() {
+        let back_rank = position("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1");
+        let iteration = best_lines(&back_rank, &limits(3, 1, 0));
+        assert_eq!(uci(&iteration.lines[0].pv[0]), "a1a8");
+        assert_eq!(format_score(iteration.lines[0].score), "mate 1");
+
+        let hanging_queen =
+            position("rnb1kbnr/pppp1ppp/8/4p1q1/3P4/2N5/PPP1PPPP/R1BQKBNR w KQkq - 0 3");
+        let iteration = best_lines(&hanging_queen, &limits(3, 1, 0));
+        assert_eq!(uci(&iteration.lines[0].pv[0]), "c1g5");
+    }
+
+    #[test]
+    fn searches_are_deterministic_for_a_seed() {
+        let start = Chess::default();
+        let first = best_lines(&start, &limits(3, 3, 7));
+        assert_eq!(first, best_lines(&start, &limits(3, 3, 7)));
+        assert_eq!(first.lines.len(), 3);
+        assert!(first
+            .lines
+            .windows(2)
+            .all(|pair| pair[0].score >= pair[1].score));
+
+        let capped = SearchLimits {
+            nodes: 2_000,
+            ..limits(MAX_STRENGTH, 1, 7)
+        };
+        let mut depths = Vec::new();
+        let iteration = search(
+            &start,
+            &[],
+            &capped,
+            &SearchSignals::new(false),
+            |iteration, _| depths.push(iteration.depth),
+        )
+        .unwrap();
+        assert!(iteration.depth < MAX_STRENGTH);
+        assert_eq!(depths, (1..=iteration.depth).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mate_and_ponder_searches_are_understood() {
+        let Some(Command::Go(go)) = parse_command("go mate 3") else {
+            panic!("expected go");
+        };
+        assert_eq!(go.depth, Some(5));
+        assert!(!go.infinite && !go.ponder);
+
+        let Some(Command::Go(go)) = parse_command("go ponder wtime 60000 btime 30000") else {
+            panic!("expected go");
+        };
+        assert!(go.ponder);
+        assert!(matches!(
+            parse_command("ponderhit"),
+            Some(Command::PonderHit)
+        ));
+    }
+
+    #[test]
+    fn go_commands_follow_the_settings() {
+        let mut settings = EngineSettings::default();
+        let Some(Command::SetOption { name, value }) =
+            parse_command("setoption name Strength value 2")
+        else {
+            panic!("expected an option");
+        };
+        settings.set(&name, &value);
+        settings.set("NodeLimit", "5000");
+
+        let Some(Command::Go(go)) = parse_command("go depth 20 nodes 100000 searchmoves e2e4 d2d4")
+        else {
+            panic!("expected go");
+        };
+        let limits = SearchLimits::new(&settings, &go, Color::White);
+        assert_eq!((limits.depth, limits.nodes, limits.time), (2, 5000, None));
+        assert_eq!(go.searchmoves, vec!["e2e4", "d2d4"]);
+
+        let Some(Command::Go(go)) = parse_command("go wtime 60000 btime 30000 winc 1000 binc 1000")
+        else {
+            panic!("expected go");
+        };
+        let limits = SearchLimits::new(&settings, &go, Color::Black);
+        assert_eq!(limits.time, Some(Duration::from_millis(1500)));
+
+        let iteration = search(
+            &Chess::default(),
+            &["g1f3".to_string()],
+            &limits,
+            &SearchSignals::new(false),
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(uci(&iteration.lines[0].pv[0]), "g1f3");
+    }
+
+    #[tokio::test]
+    async fn works_as_an_engine_process() {
+        let (mut process, mut reader) =
+            EngineProcess::new(PathBuf::from(BUILTIN_ENGINE), &PathScope::default())
+                .await
+                .unwrap();
+        assert_eq!(process.engine_name().as_deref(), Some(ENGINE_NAME));
+        assert_eq!(process.advertised_options().len(), 4);
+
+        let options = EngineOptions {
+            fen: "6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1".to_string(),
+            moves: vec![],
+            extra_options: vec![EngineOption {
+                name: "MultiPV".to_string(),
+                value: "2".to_string(),
+            }],
+        };
+        process.set_options(options.clone()).await.unwrap();
+        process.go(&GoMode::Depth(2)).await.unwrap();
+
+        let fen = options.fen.parse().unwrap();
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    lines.push(parse_uci_attrs(attrs, &fen, &options.moves).unwrap())
+                }
+                UciMessage::BestMove { best_move, .. } => {
+                    assert_eq!(best_move.to_string(), "a1a8");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].san_moves[0], "Ra8#");
+        assert_eq!(lines[1].multipv, 2);
+
+        process.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ponders_until_the_move_is_played() {
+        let (mut process, mut reader) =
+            EngineProcess::new(PathBuf::from(BUILTIN_ENGINE), &PathScope::default())
+                .await
+                .unwrap();
+        let players_time = |ponder| {
+            GoMode::PlayersTime(PlayersTime {
+                white: 60_000,
+                black: 60_000,
+                winc: 0,
+                binc: 0,
+                ponder,
+            })
+        };
+        process
+            .set_options(EngineOptions {
+                fen: "6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1".to_string(),
+                moves: vec![],
+                extra_options: vec![],
+            })
+            .await
+            .unwrap();
+        process.go(&players_time(true)).await.unwrap();
+
+        // The mate is found at once, but the best move waits for the move to be played.
+        let early = tokio::time::timeout(Duration::from_millis(300), async {
+            while let Some(line) = reader.next_line().await.unwrap() {
+                if line.starts_with("bestmove") {
+                    return line;
+                }
+            }
+            String::new()
+        })
+        .await;
+        assert!(early.is_err());
+
+        process.ponderhit(&players_time(false)).await.unwrap();
+        let best = loop {
+            let line = reader.next_line().await.unwrap().unwrap();
+            if line.starts_with("bestmove") {
+                break line;
+            }
+        };
+        assert_eq!(best, "bestmove a1a8");
+
+        process.kill().await.unwrap();
+    }
+}
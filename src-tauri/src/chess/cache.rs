@@ -0,0 +1,525 @@
+//! Persistent analysis cache.
+//!
+//! The final lines of every finished engine search are stored in a SQLite database in the app
+//! data directory, keyed by position and engine. Only the deepest search of each engine is
+//! kept per position. Positions are indexed by their Zobrist hash for fast lookups.
+//!
+//! Depth-limited searches are answered from the cache when the engine already searched the
+//! position deep enough with as many lines, without starting it. Lines not updated for
+//! `ANALYSIS_CACHE_MAX_AGE_SECS` are dropped as new searches are stored.
+
+use std::fs::create_dir_all;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Integer, Text},
+};
+use log::warn;
+use shakmaty::{
+    fen::Fen,
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    CastlingMode, Chess, Color, EnPassantMode, Position,
+};
+use tauri::{path::BaseDirectory, Manager};
+use vampirc_uci::uci::{Score, ScoreValue};
+
+use crate::error::Error;
+
+use super::evaluation::{format_score, ScoreStyle};
+use super::types::{BestMoves, EngineOptions, GoMode};
+
+const ANALYSIS_CACHE_SQL: &str = include_str!("../../../database/schema/analysis_cache.sql");
+
+/// Cache database, relative to the app data directory.
+const ANALYSIS_CACHE_FILE: &str = "analysis_cache.db3";
+
+/// Age, in seconds, after which cached lines are dropped.
+pub const ANALYSIS_CACHE_MAX_AGE_SECS: i64 = 90 * 24 * 60 * 60;
+
+/// A cached engine line.
+#[derive(QueryableByName, Debug, Clone, PartialEq)]
+pub struct CachedLine {
+    #[diesel(sql_type = Text, column_name = "Engine")]
+    pub engine: String,
+    #[diesel(sql_type = Integer, column_name = "Line")]
+    pub line: i32,
+    #[diesel(sql_type = Integer, column_name = "Depth")]
+    pub depth: i32,
+    #[diesel(sql_type = Text, column_name = "Score")]
+    pub score: String,
+    #[diesel(sql_type = Text, column_name = "UciMoves")]
+    pub uci_moves: String,
+    #[diesel(sql_type = Text, column_name = "SanMoves")]
+    pub san_moves: String,
+    #[diesel(sql_type = BigInt, column_name = "UpdatedAt")]
+    pub updated_at: i64,
+}
+
+impl CachedLine {
+    pub fn score(&self) -> Score {
+        decode_score(&self.score).unwrap_or_default()
+    }
+
+    /// The line as the engine reported it, scored from White's point of view.
+    pub fn best_moves(&self) -> BestMoves {
+        let score = self.score();
+        let moves = |moves: &str| moves.split_whitespace().map(str::to_string).collect();
+        BestMoves {
+            depth: self.depth as u32,
+            display: format_score(&score, Color::White, ScoreStyle::Pawns),
+            score,
+            uci_moves: moves(&self.uci_moves),
+            san_moves: moves(&self.san_moves),
+            multipv: self.line as u16,
+            ..Default::default()
+        }
+    }
+}
+
+/// Cache key of a position: its Zobrist hash and its FEN without move counters.
+pub struct PositionKey {
+    pub hash: i64,
+    pub fen: String,
+}
+
+impl PositionKey {
+    pub fn new(position: &Chess) -> Self {
+        let Zobrist64(hash) = position.zobrist_hash(EnPassantMode::Legal);
+        let fen = Fen::from_position(position.clone(), EnPassantMode::Legal).to_string();
+        Self {
+            hash: hash as i64,
+            fen: fen.split(' ').take(4).collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// Key of the position reached after `moves` from `fen`.
+    pub fn from_moves(fen: &str, moves: &[String]) -> Result<Self, Error> {
+        Ok(Self::new(&position_after(fen, moves)?))
+    }
+}
+
+fn position_after(fen: &str, moves: &[String]) -> Result<Chess, Error> {
+    let parsed: Fen = fen.parse()?;
+    let mut position: Chess = parsed.into_position(CastlingMode::Chess960)?;
+    for m in moves {
+        let mv = UciMove::from_ascii(m.as_bytes())?.to_move(&position)?;
+        position.play_unchecked(&mv);
+    }
+    Ok(position)
+}
+
+fn encode_score(score: &Score) -> String {
+    match score.value {
+        ScoreValue::Cp(cp) => format!("cp {}", cp),
+        ScoreValue::Mate(mate) => format!("mate {}", mate),
+    }
+}
+
+fn decode_score(score: &str) -> Option<Score> {
+    let (kind, value) = score.split_once(' ')?;
+    let value = match kind {
+        "cp" => ScoreValue::Cp(value.parse().ok()?),
+        "mate" => ScoreValue::Mate(value.parse().ok()?),
+        _ => return None,
+    };
+    Some(Score {
+        value,
+        ..Default::default()
+    })
+}
+
+/// Open the analysis cache, creating it on first use.
+pub fn open_analysis_cache(app: &tauri::AppHandle) -> Result<SqliteConnection, Error> {
+    let path = app
+        .path()
+        .resolve(ANALYSIS_CACHE_FILE, BaseDirectory::AppData)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut db = SqliteConnection::establish(&path.to_string_lossy())?;
+    db.batch_execute(ANALYSIS_CACHE_SQL)?;
+    Ok(db)
+}
+
+/// Store the final lines of a search, unless the engine already searched the position deeper.
+pub fn store_lines(
+    db: &mut SqliteConnection,
+    key: &PositionKey,
+    engine: &str,
+    lines: &[BestMoves],
+) -> Result<(), Error> {
+    let Some(depth) = lines.iter().map(|line| line.depth).min() else {
+        return Ok(());
+    };
+    let existing: Option<i32> = sql_query(
+        "SELECT MAX(Depth) AS Depth FROM AnalysisCache WHERE Fen = ? AND Engine = ? AND Line = 1",
+    )
+    .bind::<Text, _>(&key.fen)
+    .bind::<Text, _>(engine)
+    .get_result::<MaxDepth>(db)
+    .optional()?
+    .and_then(|row| row.depth);
+    if existing.is_some_and(|existing| existing > depth as i32) {
+        return Ok(());
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    db.transaction::<_, Error, _>(|db| {
+        sql_query("DELETE FROM AnalysisCache WHERE Fen = ? AND Engine = ?")
+            .bind::<Text, _>(&key.fen)
+            .bind::<Text, _>(engine)
+            .execute(db)?;
+        for line in lines {
+            sql_query(
+                "INSERT INTO AnalysisCache \
+                 (PositionHash, Fen, Engine, Line, Depth, Score, UciMoves, SanMoves, UpdatedAt) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind::<BigInt, _>(key.hash)
+            .bind::<Text, _>(&key.fen)
+            .bind::<Text, _>(engine)
+            .bind::<Integer, _>(line.multipv as i32)
+            .bind::<Integer, _>(line.depth as i32)
+            .bind::<Text, _>(encode_score(&line.score))
+            .bind::<Text, _>(line.uci_moves.join(" "))
+            .bind::<Text, _>(line.san_moves.join(" "))
+            .bind::<BigInt, _>(now)
+            .execute(db)?;
+        }
+        Ok(())
+    })
+}
+
+#[derive(QueryableByName)]
+struct MaxDepth {
+    #[diesel(sql_type = diesel::sql_types::Nullable<Integer>, column_name = "Depth")]
+    depth: Option<i32>,
+}
+
+/// Cached lines of a position searched at least to `min_depth`.
+pub fn lines_for_position(
+    db: &mut SqliteConnection,
+    key: &PositionKey,
+    min_depth: u32,
+) -> Result<Vec<CachedLine>, Error> {
+    Ok(sql_query(
+        "SELECT Engine, Line, Depth, Score, UciMoves, SanMoves, UpdatedAt FROM AnalysisCache \
+         WHERE PositionHash = ? AND Fen = ? AND Depth >= ? ORDER BY Engine, Line",
+    )
+    .bind::<BigInt, _>(key.hash)
+    .bind::<Text, _>(&key.fen)
+    .bind::<Integer, _>(min_depth as i32)
+    .load(db)?)
+}
+
+/// Lines 1 to `multipv` of the search `engine` stored for a position, if it reached
+/// `min_depth` and had that many lines.
+pub fn cached_search(
+    db: &mut SqliteConnection,
+    key: &PositionKey,
+    engine: &str,
+    multipv: u16,
+    min_depth: u32,
+) -> Result<Option<Vec<BestMoves>>, Error> {
+    let lines: Vec<CachedLine> = sql_query(
+        "SELECT Engine, Line, Depth, Score, UciMoves, SanMoves, UpdatedAt FROM AnalysisCache \
+         WHERE PositionHash = ? AND Fen = ? AND Engine = ? AND Line <= ? AND Depth >= ? \
+         ORDER BY Line",
+    )
+    .bind::<BigInt, _>(key.hash)
+    .bind::<Text, _>(&key.fen)
+    .bind::<Text, _>(engine)
+    .bind::<Integer, _>(multipv as i32)
+    .bind::<Integer, _>(min_depth as i32)
+    .load(db)?;
+    if lines.len() < multipv as usize {
+        return Ok(None);
+    }
+    Ok(Some(lines.iter().map(CachedLine::best_moves).collect()))
+}
+
+/// Depth a cached search must have reached to answer a search in `go_mode`. Searches limited
+/// by time or nodes, looking for a mate, or not limited at all, say nothing of the depth they
+/// want.
+pub fn required_depth(go_mode: &GoMode) -> Option<u32> {
+    match go_mode {
+        GoMode::Depth(depth) => Some(*depth),
+        GoMode::PlayersTime(_)
+        | GoMode::Time(_)
+        | GoMode::Nodes(_)
+        | GoMode::Mate(_)
+        | GoMode::Infinite => None,
+    }
+}
+
+/// Cached lines answering a search of `engine` with `options` in `go_mode`, if any.
+pub fn lookup_analysis(
+    db: &mut SqliteConnection,
+    engine: &str,
+    options: &EngineOptions,
+    go_mode: &GoMode,
+) -> Result<Option<Vec<BestMoves>>, Error> {
+    let Some(min_depth) = required_depth(go_mode) else {
+        return Ok(None);
+    };
+    let position = position_after(&options.fen, &options.moves)?;
+    let requested: u16 = options
+        .extra_options
+        .iter()
+        .find(|x| x.name == "MultiPV")
+        .map(|x| x.value.parse().unwrap_or(1))
+        .unwrap_or(1);
+    let multipv = requested.min(position.legal_moves().len() as u16).max(1);
+    cached_search(db, &PositionKey::new(&position), engine, multipv, min_depth)
+}
+
+/// Remember the name the engine at `path` reported, which its lines are stored under.
+pub fn store_engine_name(db: &mut SqliteConnection, path: &str, engine: &str) -> Result<(), Error> {
+    sql_query("INSERT OR REPLACE INTO AnalysisCacheEngines (Path, Engine) VALUES (?, ?)")
+        .bind::<Text, _>(path)
+        .bind::<Text, _>(engine)
+        .execute(db)?;
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct EngineName {
+    #[diesel(sql_type = Text, column_name = "Engine")]
+    engine: String,
+}
+
+/// Name the engine at `path` reported when it last finished a search.
+pub fn engine_name_for_path(
+    db: &mut SqliteConnection,
+    path: &str,
+) -> Result<Option<String>, Error> {
+    Ok(
+        sql_query("SELECT Engine FROM AnalysisCacheEngines WHERE Path = ?")
+            .bind::<Text, _>(path)
+            .get_result::<EngineName>(db)
+            .optional()?
+            .map(|row| row.engine),
+    )
+}
+
+/// Drop the lines last updated before `cutoff`, a Unix timestamp, returning how many were
+/// removed.
+pub fn prune_lines_before(db: &mut SqliteConnection, cutoff: i64) -> Result<usize, Error> {
+    Ok(sql_query("DELETE FROM AnalysisCache WHERE UpdatedAt < ?")
+        .bind::<BigInt, _>(cutoff)
+        .execute(db)?)
+}
+
+/// Keep the `max_entries` most recently updated lines, returning how many were removed.
+pub fn prune_lines(db: &mut SqliteConnection, max_entries: u32) -> Result<usize, Error> {
+    Ok(sql_query(
+        "DELETE FROM AnalysisCache WHERE rowid NOT IN \
+         (SELECT rowid FROM AnalysisCache ORDER BY UpdatedAt DESC LIMIT ?)",
+    )
+    .bind::<BigInt, _>(max_entries as i64)
+    .execute(db)?)
+}
+
+/// Store the final lines of a search of the engine at `path`, logging failures as the cache
+/// is best-effort.
+pub fn record_analysis(
+    app: &tauri::AppHandle,
+    path: &str,
+    engine: &str,
+    fen: &str,
+    moves: &[String],
+    lines: &[BestMoves],
+) {
+    let result = PositionKey::from_moves(fen, moves).and_then(|key| {
+        let mut db = open_analysis_cache(app)?;
+        store_engine_name(&mut db, path, engine)?;
+        store_lines(&mut db, &key, engine, lines)?;
+        prune_lines_before(
+            &mut db,
+            chrono::Utc::now().timestamp() - ANALYSIS_CACHE_MAX_AGE_SECS,
+        )?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Failed to cache analysis: {}", e);
+    }
+}
+
+/// Cached lines answering a search of the engine at `path`, logging failures as the cache is
+/// best-effort.
+pub fn cached_analysis(
+    app: &tauri::AppHandle,
+    path: &str,
+    options: &EngineOptions,
+    go_mode: &GoMode,
+) -> Option<Vec<BestMoves>> {
+    let result =
+        open_analysis_cache(app).and_then(|mut db| match engine_name_for_path(&mut db, path)? {
+            Some(engine) => lookup_analysis(&mut db, &engine, options, go_mode),
+            None => Ok(None),
+        });
+    result.unwrap_or_else(|e| {
+        warn!("Failed to read cached analysis: {}", e);
+        None
+    })
+}
+
+/// Delete every cached line, returning how many were removed, and give the space back to the
+/// file system.
+#[tauri::command]
+#[specta::specta]
+pub async fn clear_analysis_cache(app: tauri::AppHandle) -> Result<u32, Error> {
+    let db = &mut open_analysis_cache(&app)?;
+    let removed = sql_query("DELETE FROM AnalysisCache").execute(db)?;
+    sql_query("DELETE FROM AnalysisCacheEngines").execute(db)?;
+    db.batch_execute("VACUUM")?;
+    Ok(removed as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chess::types::EngineOption, db::memory_db};
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn cache() -> SqliteConnection {
+        memory_db(ANALYSIS_CACHE_SQL)
+    }
+
+    fn line(multipv: u16, depth: u32, cp: i32, moves: &[&str]) -> BestMoves {
+        BestMoves {
+            depth,
+            multipv,
+            score: Score {
+                value: ScoreValue::Cp(cp),
+                ..Default::default()
+            },
+            uci_moves: moves.iter().map(|m| m.to_string()).collect(),
+            san_moves: moves.iter().map(|m| m.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn transpositions_share_a_key() {
+        let a =
+            PositionKey::from_moves(START, &["g1f3".into(), "g8f6".into(), "d2d4".into()]).unwrap();
+        let b =
+            PositionKey::from_moves(START, &["d2d4".into(), "g8f6".into(), "g1f3".into()]).unwrap();
+        assert_eq!(a.hash, b.hash);
+        assert_eq!(a.fen, b.fen);
+        assert_eq!(
+            a.fen,
+            "rnbqkb1r/pppppppp/5n2/8/3P4/5N2/PPP1PPPP/RNBQKB1R b KQkq -"
+        );
+    }
+
+    #[test]
+    fn shallower_searches_do_not_replace_deeper_ones() {
+        let mut db = cache();
+        let key = PositionKey::from_moves(START, &[]).unwrap();
+        store_lines(
+            &mut db,
+            &key,
+            "Stockfish 17",
+            &[line(1, 30, 25, &["e2e4"]), line(2, 30, 20, &["d2d4"])],
+        )
+        .unwrap();
+        store_lines(&mut db, &key, "Stockfish 17", &[line(1, 12, 40, &["g1f3"])]).unwrap();
+
+        let lines = lines_for_position(&mut db, &key, 0).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].uci_moves, "e2e4");
+        assert!(matches!(lines[0].score().value, ScoreValue::Cp(25)));
+
+        store_lines(&mut db, &key, "Stockfish 17", &[line(1, 35, 18, &["d2d4"])]).unwrap();
+        let lines = lines_for_position(&mut db, &key, 0).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].depth, 35);
+        assert!(lines_for_position(&mut db, &key, 40).unwrap().is_empty());
+    }
+
+    fn options(moves: &[&str], multipv: u16) -> EngineOptions {
+        EngineOptions {
+            fen: START.to_string(),
+            moves: moves.iter().map(|m| m.to_string()).collect(),
+            extra_options: vec![EngineOption {
+                name: "MultiPV".to_string(),
+                value: multipv.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn deep_enough_searches_are_answered_from_the_cache() {
+        let mut db = cache();
+        let key = PositionKey::from_moves(START, &["e2e4".into()]).unwrap();
+        store_lines(
+            &mut db,
+            &key,
+            "Stockfish 17",
+            &[line(1, 24, -30, &["c7c5"]), line(2, 24, -35, &["e7e5"])],
+        )
+        .unwrap();
+
+        let lookup = |db: &mut SqliteConnection, engine, multipv, go_mode| {
+            lookup_analysis(db, engine, &options(&["e2e4"], multipv), &go_mode).unwrap()
+        };
+        let lines = lookup(&mut db, "Stockfish 17", 2, GoMode::Depth(20)).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].uci_moves, ["c7c5"]);
+        assert_eq!(lines[1].multipv, 2);
+        assert_eq!(lines[0].depth, 24);
+        assert_eq!(lines[0].display, "-0.30");
+
+        let single = lookup(&mut db, "Stockfish 17", 1, GoMode::Depth(24)).unwrap();
+        assert_eq!(single.len(), 1);
+        assert!(lookup(&mut db, "Stockfish 17", 2, GoMode::Depth(25)).is_none());
+        assert!(lookup(&mut db, "Stockfish 17", 3, GoMode::Depth(20)).is_none());
+        assert!(lookup(&mut db, "Komodo", 1, GoMode::Depth(20)).is_none());
+        assert!(lookup(&mut db, "Stockfish 17", 1, GoMode::Infinite).is_none());
+        assert!(lookup(&mut db, "Stockfish 17", 1, GoMode::Time(1000)).is_none());
+    }
+
+    #[test]
+    fn engines_are_found_by_path() {
+        let mut db = cache();
+        assert_eq!(engine_name_for_path(&mut db, "/engines/sf").unwrap(), None);
+        store_engine_name(&mut db, "/engines/sf", "Stockfish 16").unwrap();
+        store_engine_name(&mut db, "/engines/sf", "Stockfish 17").unwrap();
+        assert_eq!(
+            engine_name_for_path(&mut db, "/engines/sf").unwrap(),
+            Some("Stockfish 17".to_string())
+        );
+    }
+
+    #[test]
+    fn old_lines_are_pruned() {
+        let mut db = cache();
+        let key = PositionKey::from_moves(START, &[]).unwrap();
+        store_lines(&mut db, &key, "Stockfish 17", &[line(1, 20, 20, &["e2e4"])]).unwrap();
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(prune_lines_before(&mut db, now - 60).unwrap(), 0);
+        assert_eq!(prune_lines_before(&mut db, now + 60).unwrap(), 1);
+        assert!(lines_for_position(&mut db, &key, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scores_round_trip() {
+        for value in [ScoreValue::Cp(-37), ScoreValue::Mate(-3)] {
+            let score = Score {
+                value,
+                ..Default::default()
+            };
+            let encoded = encode_score(&score);
+            assert_eq!(
+                decode_score(&encoded).map(|s| encode_score(&s)),
+                Some(encoded)
+            );
+        }
+    }
+}
@@ -5,19 +5,28 @@
 
 use std::path::PathBuf;
 
-use vampirc_uci::parse_one;
-
 use crate::error::Error;
+use crate::tasks::TaskKind;
 use crate::AppState;
 
 use super::analysis::GameAnalysisService;
+use super::evalbar::{EvalBarLimits, EvalBarManager};
 use super::manager::EngineManager;
+use super::options::{EngineOptionApplier, EngineOptionResult};
+use super::pin::LinePinner;
+use super::play::PlaySessionManager;
+use super::refutation::{Refutation, RefutationFinder};
+use super::tab_policy::{TabEnginePolicy, TabEngineScheduler};
+use super::tabs::TabManager;
+use super::time_usage::{build_time_usage_report, TimeUsageReport};
 use super::types::*;
+use super::uci::UciCommunicator;
 
 /// Kill all engine processes associated with a given tab.
 #[tauri::command]
 #[specta::specta]
 pub async fn kill_engines(tab: String, state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    state.engine_pool.drop_queued(&tab, None);
     let keys: Vec<_> = state
         .engine_processes
         .iter()
@@ -25,14 +34,16 @@ pub async fn kill_engines(tab: String, state: tauri::State<'_, AppState>) -> Res
         .collect();
     for key in keys.clone() {
         if key.0.starts_with(&tab) {
-            {
-                let process = state.engine_processes.get_mut(&key).unwrap();
-                let mut process = process.lock().await;
-                process.kill().await?;
-            }
+            let Some(process) = state.engine_processes.get(&key).map(|p| p.clone()) else {
+                continue;
+            };
+            process.lock().await.kill().await?;
             state.engine_processes.remove(&key);
         }
     }
+    LinePinner::new(state.clone()).unpin(&tab, None).await?;
+    RefutationFinder::new(state.clone()).kill(&tab).await?;
+    EvalBarManager::new(state).kill(&tab).await?;
     Ok(())
 }
 
@@ -44,8 +55,10 @@ pub async fn kill_engine(
     tab: String,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), Error> {
+    state.engine_pool.drop_queued(&tab, Some(&engine));
     let key = (tab, engine);
-    if let Some(process) = state.engine_processes.get(&key) {
+    let process = state.engine_processes.get(&key).map(|p| p.clone());
+    if let Some(process) = process {
         let mut process = process.lock().await;
         process.kill().await?;
     }
@@ -60,8 +73,10 @@ pub async fn stop_engine(
     tab: String,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), Error> {
+    state.engine_pool.drop_queued(&tab, Some(&engine));
     let key = (tab, engine);
-    if let Some(process) = state.engine_processes.get(&key) {
+    let process = state.engine_processes.get(&key).map(|p| p.clone());
+    if let Some(process) = process {
         let mut process = process.lock().await;
         process.stop().await?;
     }
@@ -77,7 +92,8 @@ pub async fn get_engine_logs(
     state: tauri::State<'_, AppState>,
 ) -> Result<Vec<EngineLog>, Error> {
     let key = (tab, engine);
-    if let Some(process) = state.engine_processes.get(&key) {
+    let process = state.engine_processes.get(&key).map(|p| p.clone());
+    if let Some(process) = process {
         let process = process.lock().await;
         Ok(process.logs.clone())
     } else {
@@ -94,14 +110,161 @@ pub async fn get_best_moves(
     tab: String,
     go_mode: GoMode,
     options: EngineOptions,
+    force_refresh: Option<bool>,
     app: tauri::AppHandle,
     state: tauri::State<'_, AppState>,
 ) -> Result<Option<(f32, Vec<BestMoves>)>, Error> {
     EngineManager::new(state)
-        .get_best_moves(id, engine, tab, go_mode, options, app)
+        .get_best_moves(
+            id,
+            engine,
+            tab,
+            go_mode,
+            options,
+            force_refresh.unwrap_or(false),
+            app,
+        )
+        .await
+}
+
+/// Get the opponent's best reply to a candidate move, without disturbing the tab's analysis.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_refutation(
+    engine: String,
+    tab: String,
+    fen: String,
+    moves: Vec<String>,
+    candidate_uci: String,
+    go_mode: GoMode,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<Refutation>, Error> {
+    RefutationFinder::new(state)
+        .get_refutation(engine, tab, fen, moves, candidate_uci, go_mode)
+        .await
+}
+
+/// Keep tracking the line starting with `uci_first_move` in a tab's analysis, even once it
+/// drops out of the MultiPV window.
+#[tauri::command]
+#[specta::specta]
+pub async fn pin_line(
+    tab: String,
+    engine: String,
+    uci_first_move: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    LinePinner::new(state)
+        .pin(tab, engine, uci_first_move)
+        .await
+}
+
+/// Stop tracking the pinned line of a tab's analysis.
+#[tauri::command]
+#[specta::specta]
+pub async fn unpin_line(
+    tab: String,
+    engine: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    LinePinner::new(state).unpin(&tab, Some(&engine)).await
+}
+
+/// Set whether the engines of a tab start on their own, saving it with the session.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_tab_engine_policy(
+    tab: String,
+    policy: TabEnginePolicy,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    TabEngineScheduler::new(state).set_policy(&app, tab, policy)
+}
+
+/// Move everything kept for a tab to its new id, without stopping its engines.
+#[tauri::command]
+#[specta::specta]
+pub async fn rename_tab(
+    old_id: String,
+    new_id: String,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    TabManager::new(state).rename(&app, &old_id, &new_id).await
+}
+
+/// Give a new tab the engine policy and pinned lines of the tab it duplicates.
+#[tauri::command]
+#[specta::specta]
+pub async fn duplicate_tab(
+    src_id: String,
+    new_id: String,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    TabManager::new(state).duplicate(&app, &src_id, &new_id)
+}
+
+/// Report a tab as shown, resuming its last analysis if its policy asks for it.
+///
+/// Returns whether an analysis was started.
+#[tauri::command]
+#[specta::specta]
+pub async fn tab_ready(
+    tab: String,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, Error> {
+    TabEngineScheduler::new(state).ready(app, tab).await
+}
+
+/// Report a tab as hidden, stopping its engines unless its policy keeps them running.
+#[tauri::command]
+#[specta::specta]
+pub async fn tab_hidden(tab: String, state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    TabEngineScheduler::new(state).hidden(&tab).await
+}
+
+/// Use a dedicated engine for the eval bar of a tab, replacing the one it used before.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_evalbar_engine(
+    tab: String,
+    engine: String,
+    limits: EvalBarLimits,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    EvalBarManager::new(state)
+        .set_engine(app, tab, engine, limits)
+        .await
+}
+
+/// Report the position shown in a tab to its eval bar engine.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_evalbar_position(
+    tab: String,
+    fen: String,
+    moves: Vec<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    EvalBarManager::new(state)
+        .set_position(&tab, fen, moves)
         .await
 }
 
+/// Turn off the eval bar engine of a tab.
+#[tauri::command]
+#[specta::specta]
+pub async fn clear_evalbar_engine(
+    tab: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    EvalBarManager::new(state).kill(&tab).await
+}
+
 /// Analyze a game using the engine, returning move-by-move analysis.
 #[tauri::command]
 #[specta::specta]
@@ -113,49 +276,159 @@ pub async fn analyze_game(
     uci_options: Vec<EngineOption>,
     state: tauri::State<'_, AppState>,
     app: tauri::AppHandle,
-) -> Result<Vec<MoveAnalysis>, Error> {
+) -> Result<GameAnalysis, Error> {
     GameAnalysisService::analyze_game(id, engine, go_mode, options, uci_options, state, app).await
 }
 
+/// Analyze the game of a play session, reusing the positions it already evaluated as the
+/// first pass of an adaptive analysis. The FEN and moves of `options` are those of the session,
+/// and progress is reported under its identifier.
+#[tauri::command]
+#[specta::specta]
+pub async fn analyze_play_session(
+    session: String,
+    engine: String,
+    go_mode: GoMode,
+    options: AnalysisOptions,
+    uci_options: Vec<EngineOption>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<GameAnalysis, Error> {
+    let (fen, moves, first_pass) = PlaySessionManager::new(state.clone())
+        .report_input(&session)
+        .await?;
+    let options = AnalysisOptions {
+        fen,
+        moves,
+        first_pass,
+        ..options
+    };
+    GameAnalysisService::analyze_game(session, engine, go_mode, options, uci_options, state, app)
+        .await
+}
+
+/// Stop a running game analysis, which then returns the positions it got to. Returns whether
+/// the analysis was found.
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_analysis(id: String, state: tauri::State<'_, AppState>) -> Result<bool, Error> {
+    Ok(state.tasks.cancel(TaskKind::Analysis, &id))
+}
+
+/// Start a play session against an engine, returning its identifier.
+#[tauri::command]
+#[specta::specta]
+pub async fn start_play_session(
+    engine: String,
+    config: PlaySessionConfig,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<String, Error> {
+    PlaySessionManager::new(state)
+        .start(engine, config, app)
+        .await
+}
+
+/// Play the user's move in a play session, returning the new session generation.
+#[tauri::command]
+#[specta::specta]
+pub async fn submit_player_move(
+    session: String,
+    uci: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32, Error> {
+    PlaySessionManager::new(state)
+        .submit_player_move(&session, &uci, app)
+        .await
+}
+
+/// Take back moves in a play session, returning the new session generation.
+#[tauri::command]
+#[specta::specta]
+pub async fn takeback(
+    session: String,
+    plies: u32,
+    state: tauri::State<'_, AppState>,
+) -> Result<u32, Error> {
+    PlaySessionManager::new(state)
+        .takeback(&session, plies as usize)
+        .await
+}
+
+/// The game of a play session so far as PGN, with the headers of its odds if any.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_play_session_pgn(
+    session: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    PlaySessionManager::new(state).pgn(&session).await
+}
+
+/// End a play session and kill its engine.
+#[tauri::command]
+#[specta::specta]
+pub async fn end_play_session(
+    session: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    PlaySessionManager::new(state).end(&session).await
+}
+
+/// Build a time usage report for a database game from its `%clk` and `%eval` comments.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_time_usage_report(
+    file: PathBuf,
+    game_id: i32,
+    time_trouble_seconds: Option<f64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<TimeUsageReport, Error> {
+    build_time_usage_report(file, game_id, time_trouble_seconds, &state)
+}
+
+/// Set a UCI option on every configured engine and, live, on the running ones.
+///
+/// Running engines that are searching receive the option with their next configuration,
+/// or right away with their search restarted if `restart_running` is set. Stored profiles
+/// are left to the frontend: their results carry the setting to store.
+#[tauri::command]
+#[specta::specta]
+pub async fn apply_option_to_all_engines(
+    option_name: String,
+    value: String,
+    only_running: bool,
+    restart_running: Option<bool>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Vec<EngineOptionResult>, Error> {
+    EngineOptionApplier::new(state, app)
+        .apply(
+            option_name,
+            value,
+            only_running,
+            restart_running.unwrap_or(false),
+        )
+        .await
+}
+
 /// Query a UCI engine for its configuration (name and options).
 #[tauri::command]
 #[specta::specta]
-pub async fn get_engine_config(path: PathBuf) -> Result<EngineConfig, Error> {
-    use tokio::io::AsyncBufReadExt;
-
-    let mut command = tokio::process::Command::new(&path);
-    command.current_dir(path.parent().unwrap());
-    command
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped());
-    #[cfg(target_os = "windows")]
-    command.creation_flags(super::process::CREATE_NO_WINDOW);
-    let mut child = command.spawn()?;
-    let mut stdin = child.stdin.take().ok_or(Error::NoStdin)?;
-    let stdout = child.stdout.take().ok_or(Error::NoStdout)?;
-    let mut stdout = tokio::io::BufReader::new(stdout).lines();
-
-    use tokio::io::AsyncWriteExt;
-    stdin.write_all(b"uci\n").await?;
-
-    let mut config = EngineConfig::default();
-    loop {
-        if let Some(line) = stdout.next_line().await? {
-            if let vampirc_uci::UciMessage::Id {
-                name: Some(name),
-                author: _,
-            } = parse_one(&line)
-            {
-                config.name = name;
-            }
-            if let vampirc_uci::UciMessage::Option(opt) = parse_one(&line) {
-                config.options.push(opt);
-            }
-            if let vampirc_uci::UciMessage::UciOk = parse_one(&line) {
-                break;
-            }
-        }
-    }
-    Ok(config)
+pub async fn get_engine_config(
+    path: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<EngineConfig, Error> {
+    let mut comm = UciCommunicator::spawn(path.clone(), &state.path_scope).await?;
+    let handshake = comm.handshake().await?;
+
+    Ok(EngineConfig {
+        name: handshake.name.unwrap_or_default(),
+        options: handshake.options,
+        multipv_limit: state
+            .engine_multipv_limits
+            .get(&path.to_string_lossy().to_string())
+            .map(|limit| *limit),
+    })
 }
@@ -0,0 +1,189 @@
+//! Consensus of several engines.
+//!
+//! A single engine sometimes misjudges fortresses or closed positions, so a game can be
+//! analysed by up to three engines. Their evaluations are merged into the median, which the
+//! annotations are then derived from, and positions where the engines are far apart are
+//! flagged while keeping every engine's lines.
+
+use shakmaty::Color;
+use vampirc_uci::uci::{Score, ScoreValue};
+
+use super::evaluation::score_to_cp;
+use super::evaluation::{format_score, ScoreStyle};
+use super::types::{BestMoves, EngineLines};
+
+/// Most engines a game can be analysed by at once.
+pub const MAX_CONSENSUS_ENGINES: usize = 3;
+
+/// Spread of the engines' evaluations, in centipawns, above which they disagree.
+pub const DEFAULT_DISAGREEMENT_CP: i32 = 150;
+
+/// Ranks mates beyond any centipawn score.
+const MATE_RANK: i64 = 1_000_000;
+
+/// Orders scores from White's point of view: mating sooner is better, being mated later is
+/// better, and any mate for White beats any centipawn score.
+fn rank(score: &Score) -> i64 {
+    match score.value {
+        ScoreValue::Cp(cp) => cp as i64,
+        ScoreValue::Mate(mate) if mate > 0 => MATE_RANK - mate as i64,
+        ScoreValue::Mate(mate) => -MATE_RANK - mate as i64,
+    }
+}
+
+/// Median of scores from White's point of view.
+///
+/// With an even number of scores, two centipawn scores in the middle are averaged; when one
+/// of them is a mate, the less extreme of the two is taken rather than inventing a score.
+pub fn median_score(scores: &[Score]) -> Option<Score> {
+    let mut scores = scores.to_vec();
+    scores.sort_by_key(rank);
+    let middle = scores.len() / 2;
+    if scores.len() % 2 == 1 {
+        return scores.get(middle).cloned();
+    }
+    let (low, high) = (scores.get(middle.checked_sub(1)?)?, &scores[middle]);
+    Some(match (&low.value, &high.value) {
+        (ScoreValue::Cp(low), ScoreValue::Cp(high)) => Score {
+            value: ScoreValue::Cp((low + high) / 2),
+            ..Default::default()
+        },
+        _ if rank(low).abs() <= rank(high).abs() => low.clone(),
+        _ => high.clone(),
+    })
+}
+
+/// Whether the evaluations, capped as for reports, spread over more than `disagreement_cp`.
+pub fn engines_disagree(scores: &[Score], disagreement_cp: i32) -> bool {
+    let evals = scores.iter().map(score_to_cp);
+    match (evals.clone().min(), evals.max()) {
+        (Some(min), Some(max)) => max - min > disagreement_cp,
+        _ => false,
+    }
+}
+
+/// Lines merged from several engines.
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    /// Lines of the engine closest to the median, its best line carrying the median score.
+    pub best: Vec<BestMoves>,
+    pub engines_disagree: bool,
+}
+
+/// Merge the lines several engines found in a position, ignoring engines without any.
+pub fn merge_consensus(lines: &[EngineLines], disagreement_cp: i32) -> Consensus {
+    let searched: Vec<&Vec<BestMoves>> = lines
+        .iter()
+        .map(|engine| &engine.best)
+        .filter(|best| !best.is_empty())
+        .collect();
+    let scores: Vec<Score> = searched.iter().map(|best| best[0].score.clone()).collect();
+    let Some(median) = median_score(&scores) else {
+        return Consensus {
+            best: Vec::new(),
+            engines_disagree: false,
+        };
+    };
+
+    let closest = searched
+        .iter()
+        .min_by_key(|best| (rank(&best[0].score) - rank(&median)).abs())
+        .expect("a median comes from at least one engine");
+    let mut best = (*closest).clone();
+    if rank(&best[0].score) != rank(&median) {
+        best[0].display = format_score(&median, Color::White, ScoreStyle::Pawns);
+        best[0].score = median;
+    }
+    Consensus {
+        best,
+        engines_disagree: engines_disagree(&scores, disagreement_cp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ScoreValue::{Cp, Mate};
+
+    fn score(value: ScoreValue) -> Score {
+        Score {
+            value,
+            ..Default::default()
+        }
+    }
+
+    fn median(values: &[ScoreValue]) -> ScoreValue {
+        let scores: Vec<Score> = values.iter().map(|value| score(value.clone())).collect();
+        median_score(&scores).unwrap().value
+    }
+
+    fn engine(name: &str, value: ScoreValue, first_move: &str) -> EngineLines {
+        EngineLines {
+            engine: name.to_string(),
+            best: vec![BestMoves {
+                score: score(value),
+                uci_moves: vec![first_move.to_string()],
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn median_of_centipawns_and_mates() {
+        assert!(matches!(median(&[Cp(500), Cp(10), Cp(30)]), Cp(30)));
+        assert!(matches!(median(&[Mate(3), Cp(200), Cp(100)]), Cp(200)));
+        assert!(matches!(median(&[Mate(3), Mate(5), Cp(0)]), Mate(5)));
+        assert!(matches!(median(&[Mate(-3), Mate(-5), Cp(0)]), Mate(-5)));
+        assert!(matches!(median(&[Cp(20), Cp(-40)]), Cp(-10)));
+        assert!(matches!(median(&[Mate(2), Cp(300)]), Cp(300)));
+        assert!(matches!(median(&[Mate(-2), Cp(-300)]), Cp(-300)));
+        assert!(matches!(median(&[Mate(2), Mate(-2)]), Mate(-2)));
+        assert!(median_score(&[]).is_none());
+    }
+
+    #[test]
+    fn disagreement_above_the_threshold() {
+        let scores = |values: &[ScoreValue]| -> Vec<Score> {
+            values.iter().map(|value| score(value.clone())).collect()
+        };
+        assert!(!engines_disagree(&scores(&[Cp(20), Cp(90), Cp(160)]), 150));
+        assert!(engines_disagree(&scores(&[Cp(20), Cp(90), Cp(180)]), 150));
+        // A mate against a clear advantage is an agreement once capped.
+        assert!(!engines_disagree(&scores(&[Mate(4), Cp(1200)]), 150));
+        assert!(engines_disagree(&scores(&[Mate(4), Cp(0)]), 150));
+        assert!(!engines_disagree(&scores(&[Cp(0)]), 0));
+    }
+
+    #[test]
+    fn merged_lines_carry_the_median() {
+        let lines = [
+            engine("a", Cp(40), "e2e4"),
+            engine("b", Cp(350), "d2d4"),
+            engine("c", Cp(60), "g1f3"),
+            EngineLines {
+                engine: "d".to_string(),
+                best: Vec::new(),
+            },
+        ];
+        let consensus = merge_consensus(&lines, DEFAULT_DISAGREEMENT_CP);
+        assert!(consensus.engines_disagree);
+        assert_eq!(consensus.best[0].uci_moves, vec!["g1f3"]);
+        assert!(matches!(consensus.best[0].score.value, Cp(60)));
+
+        // A single engine is its own consensus.
+        let consensus = merge_consensus(&lines[..1], DEFAULT_DISAGREEMENT_CP);
+        assert!(matches!(consensus.best[0].score.value, Cp(40)));
+
+        // Two engines agreeing are averaged, keeping the lines of the closest.
+        let consensus = merge_consensus(
+            &[engine("a", Cp(40), "e2e4"), engine("c", Cp(70), "g1f3")],
+            DEFAULT_DISAGREEMENT_CP,
+        );
+        assert!(!consensus.engines_disagree);
+        assert!(matches!(consensus.best[0].score.value, Cp(55)));
+        assert_eq!(consensus.best[0].display, "+0.55");
+
+        let consensus = merge_consensus(&lines[3..], DEFAULT_DISAGREEMENT_CP);
+        assert!(consensus.best.is_empty());
+    }
+}
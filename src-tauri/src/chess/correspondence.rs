@@ -0,0 +1,686 @@
+//! Correspondence games.
+//!
+//! Correspondence players send conditional moves ahead of time: "if he plays Nf3 I reply d5;
+//! if c4 then e6". Each sequence alternates opponent moves and replies, starting from a
+//! position with the opponent to move, and is stored per game in a SQLite database in the
+//! app data directory, keyed by the Zobrist hash of that position. When an opponent move
+//! arrives, `check_conditionals` looks up the premapped reply and reports the sequences the
+//! move made stale. Sequences are exchanged with other software as a PGN variation block,
+//! the format ICCF servers use.
+//!
+//! Each game also records whether its rules allow engines and tablebases, defaulting to
+//! ICCF rules, which allow both.
+
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Bool, Text},
+};
+use pgn_reader::{BufferedReader, SanPlus, Skip, Visitor};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Position};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+
+use super::cache::PositionKey;
+
+const CORRESPONDENCE_SQL: &str = include_str!("../../../database/schema/correspondence.sql");
+
+/// Correspondence database, relative to the app data directory.
+const CORRESPONDENCE_FILE: &str = "correspondence.db3";
+
+/// What the rules of a correspondence game allow during play.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CorrespondenceRules {
+    pub engines_allowed: bool,
+    pub tablebases_allowed: bool,
+}
+
+impl Default for CorrespondenceRules {
+    /// ICCF rules.
+    fn default() -> Self {
+        Self {
+            engines_allowed: true,
+            tablebases_allowed: true,
+        }
+    }
+}
+
+/// A conditional sequence: opponent moves and replies, alternating, from `fen`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct ConditionalSequence {
+    pub fen: String,
+    pub moves: Vec<String>,
+    pub san: Vec<String>,
+}
+
+/// The premapped reply to the move that reached a position.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct ConditionalReply {
+    pub uci: String,
+    pub san: String,
+    /// Moves of the sequence after the reply.
+    pub continuation: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct ConditionalCheck {
+    pub reply: Option<ConditionalReply>,
+    /// Sequences of the game that no longer apply after the move.
+    pub stale: Vec<ConditionalSequence>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidConditional(message.into())
+}
+
+fn parse_position(fen: &str) -> Result<Chess, Error> {
+    let fen: Fen = fen.parse()?;
+    Ok(fen.into_position(CastlingMode::Chess960)?)
+}
+
+/// Check that `moves` is a legal sequence of opponent moves and replies from `position`,
+/// returning it in SAN.
+pub fn validate_sequence(position: &Chess, moves: &[String]) -> Result<Vec<String>, Error> {
+    if moves.is_empty() || moves.len() % 2 != 0 {
+        return Err(invalid(format!(
+            "{} must pair every opponent move with a reply",
+            moves.join(" ")
+        )));
+    }
+    let mut position = position.clone();
+    moves
+        .iter()
+        .map(|m| {
+            let mv = UciMove::from_ascii(m.as_bytes())
+                .ok()
+                .and_then(|uci| uci.to_move(&position).ok())
+                .ok_or_else(|| invalid(format!("{} is illegal in {}", m, moves.join(" "))))?;
+            Ok(SanPlus::from_move_and_play_unchecked(&mut position, &mv).to_string())
+        })
+        .collect()
+}
+
+/// Check that sequences from the same position never answer the same moves differently.
+pub fn check_branches(sequences: &[Vec<String>]) -> Result<(), Error> {
+    let mut replies: HashMap<&[String], &String> = HashMap::new();
+    for moves in sequences {
+        for i in (1..moves.len()).step_by(2) {
+            let reply = replies.entry(&moves[..i]).or_insert(&moves[i]);
+            if *reply != &moves[i] {
+                return Err(invalid(format!(
+                    "{} is answered by both {} and {}",
+                    moves[..i].join(" "),
+                    reply,
+                    moves[i]
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the reply to the move that reached `position`, and the sequences it made stale.
+///
+/// A sequence applies while `position` is one of the positions it reaches after an opponent
+/// move; every other sequence of the game is stale, as conditionals always start from the
+/// position the game was in when they were sent.
+pub fn match_conditionals(sequences: &[ConditionalSequence], position: &Chess) -> ConditionalCheck {
+    let key = PositionKey::new(position);
+    let mut reply = None;
+    let mut stale = Vec::new();
+    for sequence in sequences {
+        let found = parse_position(&sequence.fen).ok().and_then(|mut pos| {
+            for (i, m) in sequence.moves.iter().enumerate() {
+                let mv = UciMove::from_ascii(m.as_bytes()).ok()?.to_move(&pos).ok()?;
+                pos.play_unchecked(&mv);
+                if i % 2 == 0 && i + 1 < sequence.moves.len() {
+                    let reached = PositionKey::new(&pos);
+                    if reached.hash == key.hash && reached.fen == key.fen {
+                        return Some(i + 1);
+                    }
+                }
+            }
+            None
+        });
+        match found {
+            Some(i) => {
+                reply.get_or_insert_with(|| ConditionalReply {
+                    uci: sequence.moves[i].clone(),
+                    san: sequence.san[i].clone(),
+                    continuation: sequence.moves[i + 1..].to_vec(),
+                });
+            }
+            None => stale.push(sequence.clone()),
+        }
+    }
+    ConditionalCheck { reply, stale }
+}
+
+/// Moves sharing a prefix, merged into a tree for PGN export.
+struct Branch {
+    uci: String,
+    children: Vec<Branch>,
+}
+
+fn insert_branch(branches: &mut Vec<Branch>, moves: &[String]) {
+    let Some((first, rest)) = moves.split_first() else {
+        return;
+    };
+    let index = match branches.iter().position(|b| &b.uci == first) {
+        Some(index) => index,
+        None => {
+            branches.push(Branch {
+                uci: first.clone(),
+                children: Vec::new(),
+            });
+            branches.len() - 1
+        }
+    };
+    insert_branch(&mut branches[index].children, rest);
+}
+
+fn write_move(
+    out: &mut String,
+    position: &mut Chess,
+    uci: &str,
+    numbered: bool,
+) -> Result<(), Error> {
+    let mv = UciMove::from_ascii(uci.as_bytes())?.to_move(position)?;
+    if !out.is_empty() && !out.ends_with('(') {
+        out.push(' ');
+    }
+    let number = position.fullmoves().get();
+    if position.turn().is_white() {
+        out.push_str(&format!("{}. ", number));
+    } else if numbered {
+        out.push_str(&format!("{}... ", number));
+    }
+    out.push_str(&SanPlus::from_move_and_play_unchecked(position, &mv).to_string());
+    Ok(())
+}
+
+fn write_branches(
+    out: &mut String,
+    position: &Chess,
+    branches: &[Branch],
+    numbered: bool,
+) -> Result<(), Error> {
+    let Some((main, alternatives)) = branches.split_first() else {
+        return Ok(());
+    };
+    let mut after_main = position.clone();
+    write_move(out, &mut after_main, &main.uci, numbered)?;
+    for alternative in alternatives {
+        out.push_str(" (");
+        let mut after = position.clone();
+        write_move(out, &mut after, &alternative.uci, true)?;
+        write_branches(out, &after, &alternative.children, false)?;
+        out.push(')');
+    }
+    write_branches(out, &after_main, &main.children, !alternatives.is_empty())
+}
+
+/// Write the sequences from `fen` as PGN movetext, the first one as the main line.
+pub fn sequences_to_pgn(fen: &str, sequences: &[Vec<String>]) -> Result<String, Error> {
+    let position = parse_position(fen)?;
+    let mut branches = Vec::new();
+    for moves in sequences {
+        insert_branch(&mut branches, moves);
+    }
+    let mut out = String::new();
+    write_branches(&mut out, &position, &branches, true)?;
+    Ok(out)
+}
+
+/// Collects every line of a PGN variation block, in UCI.
+struct SequenceReader {
+    /// Positions along the current line, starting with the root.
+    positions: Vec<Chess>,
+    line: Vec<String>,
+    stack: Vec<(Vec<Chess>, Vec<String>)>,
+    sequences: Vec<Vec<String>>,
+    error: Option<Error>,
+}
+
+impl Visitor for SequenceReader {
+    type Result = ();
+
+    fn san(&mut self, san: SanPlus) {
+        if self.error.is_some() {
+            return;
+        }
+        let Some(position) = self.positions.last() else {
+            return;
+        };
+        match san.san.to_move(position) {
+            Ok(mv) => {
+                let mut position = position.clone();
+                position.play_unchecked(&mv);
+                self.positions.push(position);
+                self.line
+                    .push(mv.to_uci(CastlingMode::Standard).to_string());
+            }
+            Err(_) => self.error = Some(invalid(format!("{} is illegal", san))),
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        self.stack.push((self.positions.clone(), self.line.clone()));
+        // A variation replaces the last move of the enclosing line.
+        self.positions.pop();
+        self.line.pop();
+        if self.positions.is_empty() {
+            self.error = Some(invalid("variation before the first move"));
+        }
+        Skip(false)
+    }
+
+    fn end_variation(&mut self) {
+        self.sequences.push(std::mem::take(&mut self.line));
+        if let Some((positions, line)) = self.stack.pop() {
+            self.positions = positions;
+            self.line = line;
+        }
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        if !self.line.is_empty() {
+            self.sequences.push(std::mem::take(&mut self.line));
+        }
+    }
+}
+
+/// Read the sequences of a PGN variation block starting from `fen`, validating them.
+pub fn sequences_from_pgn(fen: &str, pgn: &str) -> Result<Vec<Vec<String>>, Error> {
+    let position = parse_position(fen)?;
+    let mut reader = SequenceReader {
+        positions: vec![position.clone()],
+        line: Vec::new(),
+        stack: Vec::new(),
+        sequences: Vec::new(),
+        error: None,
+    };
+    let movetext = format!("{} *\n", pgn.trim());
+    BufferedReader::new_cursor(movetext.as_bytes()).read_game(&mut reader)?;
+    if let Some(error) = reader.error {
+        return Err(error);
+    }
+    for moves in &reader.sequences {
+        validate_sequence(&position, moves)?;
+    }
+    check_branches(&reader.sequences)?;
+    Ok(reader.sequences)
+}
+
+/// Open the correspondence database, creating it on first use.
+pub fn open_correspondence_db(app: &tauri::AppHandle) -> Result<SqliteConnection, Error> {
+    let path = app
+        .path()
+        .resolve(CORRESPONDENCE_FILE, BaseDirectory::AppData)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut db = SqliteConnection::establish(&path.to_string_lossy())?;
+    db.batch_execute(CORRESPONDENCE_SQL)?;
+    Ok(db)
+}
+
+#[derive(QueryableByName)]
+struct StoredSequence {
+    #[diesel(sql_type = Text, column_name = "Fen")]
+    fen: String,
+    #[diesel(sql_type = Text, column_name = "Moves")]
+    moves: String,
+}
+
+#[derive(QueryableByName)]
+struct StoredRules {
+    #[diesel(sql_type = Bool, column_name = "EnginesAllowed")]
+    engines_allowed: bool,
+    #[diesel(sql_type = Bool, column_name = "TablebasesAllowed")]
+    tablebases_allowed: bool,
+}
+
+/// Replace the conditional sequences of a game from `fen`, after validating them.
+pub fn store_conditionals(
+    db: &mut SqliteConnection,
+    game: &str,
+    fen: &str,
+    sequences: &[Vec<String>],
+) -> Result<(), Error> {
+    let position = parse_position(fen)?;
+    for moves in sequences {
+        validate_sequence(&position, moves)?;
+    }
+    check_branches(sequences)?;
+
+    let key = PositionKey::new(&position);
+    db.transaction::<_, Error, _>(|db| {
+        sql_query("DELETE FROM ConditionalMoves WHERE GameRef = ? AND PositionHash = ?")
+            .bind::<Text, _>(game)
+            .bind::<BigInt, _>(key.hash)
+            .execute(db)?;
+        for moves in sequences {
+            sql_query(
+                "INSERT OR REPLACE INTO ConditionalMoves (GameRef, PositionHash, Fen, Moves) \
+                 VALUES (?, ?, ?, ?)",
+            )
+            .bind::<Text, _>(game)
+            .bind::<BigInt, _>(key.hash)
+            .bind::<Text, _>(fen)
+            .bind::<Text, _>(moves.join(" "))
+            .execute(db)?;
+        }
+        Ok(())
+    })
+}
+
+/// Conditional sequences of a game, optionally only those from `fen`.
+pub fn load_conditionals(
+    db: &mut SqliteConnection,
+    game: &str,
+    fen: Option<&str>,
+) -> Result<Vec<ConditionalSequence>, Error> {
+    let rows: Vec<StoredSequence> = match fen {
+        Some(fen) => sql_query(
+            "SELECT Fen, Moves FROM ConditionalMoves WHERE GameRef = ? AND PositionHash = ? \
+             ORDER BY rowid",
+        )
+        .bind::<Text, _>(game)
+        .bind::<BigInt, _>(PositionKey::new(&parse_position(fen)?).hash)
+        .load(db)?,
+        None => {
+            sql_query("SELECT Fen, Moves FROM ConditionalMoves WHERE GameRef = ? ORDER BY rowid")
+                .bind::<Text, _>(game)
+                .load(db)?
+        }
+    };
+    rows.into_iter()
+        .map(|row| {
+            let moves: Vec<String> = row.moves.split(' ').map(str::to_string).collect();
+            let san = validate_sequence(&parse_position(&row.fen)?, &moves)?;
+            Ok(ConditionalSequence {
+                fen: row.fen,
+                moves,
+                san,
+            })
+        })
+        .collect()
+}
+
+/// Remove the conditional sequences of a game, optionally only those from `fen`.
+pub fn remove_conditionals(
+    db: &mut SqliteConnection,
+    game: &str,
+    fen: Option<&str>,
+) -> Result<usize, Error> {
+    Ok(match fen {
+        Some(fen) => {
+            sql_query("DELETE FROM ConditionalMoves WHERE GameRef = ? AND PositionHash = ?")
+                .bind::<Text, _>(game)
+                .bind::<BigInt, _>(PositionKey::new(&parse_position(fen)?).hash)
+                .execute(db)?
+        }
+        None => sql_query("DELETE FROM ConditionalMoves WHERE GameRef = ?")
+            .bind::<Text, _>(game)
+            .execute(db)?,
+    })
+}
+
+fn sequence_moves(sequences: &[ConditionalSequence]) -> Vec<Vec<String>> {
+    sequences.iter().map(|s| s.moves.clone()).collect()
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn set_conditional_moves(
+    game: String,
+    fen: String,
+    sequences: Vec<Vec<String>>,
+    app: tauri::AppHandle,
+) -> Result<(), Error> {
+    let mut db = open_correspondence_db(&app)?;
+    store_conditionals(&mut db, &game, &fen, &sequences)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn list_conditional_moves(
+    game: String,
+    app: tauri::AppHandle,
+) -> Result<Vec<ConditionalSequence>, Error> {
+    let mut db = open_correspondence_db(&app)?;
+    load_conditionals(&mut db, &game, None)
+}
+
+/// Remove the conditional sequences of a game, only those from `fen` if given.
+#[tauri::command]
+#[specta::specta]
+pub async fn clear_conditional_moves(
+    game: String,
+    fen: Option<String>,
+    app: tauri::AppHandle,
+) -> Result<u32, Error> {
+    let mut db = open_correspondence_db(&app)?;
+    Ok(remove_conditionals(&mut db, &game, fen.as_deref())? as u32)
+}
+
+/// Look up the premapped reply once an opponent move reached `fen`.
+#[tauri::command]
+#[specta::specta]
+pub async fn check_conditionals(
+    game: String,
+    fen: String,
+    app: tauri::AppHandle,
+) -> Result<ConditionalCheck, Error> {
+    let position = parse_position(&fen)?;
+    let mut db = open_correspondence_db(&app)?;
+    let sequences = load_conditionals(&mut db, &game, None)?;
+    Ok(match_conditionals(&sequences, &position))
+}
+
+/// Export the conditional sequences of a game from `fen` as a PGN variation block.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_conditional_moves(
+    game: String,
+    fen: String,
+    app: tauri::AppHandle,
+) -> Result<String, Error> {
+    let mut db = open_correspondence_db(&app)?;
+    let sequences = load_conditionals(&mut db, &game, Some(&fen))?;
+    sequences_to_pgn(&fen, &sequence_moves(&sequences))
+}
+
+/// Replace the conditional sequences of a game from `fen` with those of a PGN variation block.
+#[tauri::command]
+#[specta::specta]
+pub async fn import_conditional_moves(
+    game: String,
+    fen: String,
+    pgn: String,
+    app: tauri::AppHandle,
+) -> Result<Vec<ConditionalSequence>, Error> {
+    let sequences = sequences_from_pgn(&fen, &pgn)?;
+    let mut db = open_correspondence_db(&app)?;
+    store_conditionals(&mut db, &game, &fen, &sequences)?;
+    load_conditionals(&mut db, &game, Some(&fen))
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_correspondence_rules(
+    game: String,
+    app: tauri::AppHandle,
+) -> Result<CorrespondenceRules, Error> {
+    let mut db = open_correspondence_db(&app)?;
+    let rules: Option<StoredRules> = sql_query(
+        "SELECT EnginesAllowed, TablebasesAllowed FROM CorrespondenceRules WHERE GameRef = ?",
+    )
+    .bind::<Text, _>(&game)
+    .get_result(&mut db)
+    .optional()?;
+    Ok(
+        rules.map_or_else(CorrespondenceRules::default, |rules| CorrespondenceRules {
+            engines_allowed: rules.engines_allowed,
+            tablebases_allowed: rules.tablebases_allowed,
+        }),
+    )
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn set_correspondence_rules(
+    game: String,
+    rules: CorrespondenceRules,
+    app: tauri::AppHandle,
+) -> Result<(), Error> {
+    let mut db = open_correspondence_db(&app)?;
+    sql_query(
+        "INSERT OR REPLACE INTO CorrespondenceRules (GameRef, EnginesAllowed, TablebasesAllowed) \
+         VALUES (?, ?, ?)",
+    )
+    .bind::<Text, _>(&game)
+    .bind::<Bool, _>(rules.engines_allowed)
+    .bind::<Bool, _>(rules.tablebases_allowed)
+    .execute(&mut db)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory_db;
+
+    /// After 1. e4 e5 2. Nf3, with Black to move.
+    const ROOT: &str = "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2";
+
+    fn moves(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    fn correspondence_db() -> SqliteConnection {
+        memory_db(CORRESPONDENCE_SQL)
+    }
+
+    fn after(line: &str) -> Chess {
+        let mut position = parse_position(ROOT).unwrap();
+        for m in moves(line) {
+            let mv = UciMove::from_ascii(m.as_bytes())
+                .unwrap()
+                .to_move(&position)
+                .unwrap();
+            position.play_unchecked(&mv);
+        }
+        position
+    }
+
+    #[test]
+    fn sequences_must_be_legal_reply_pairs() {
+        let root = parse_position(ROOT).unwrap();
+        assert_eq!(
+            validate_sequence(&root, &moves("b8c6 f1b5 a7a6 b5a4")).unwrap(),
+            vec!["Nc6", "Bb5", "a6", "Ba4"]
+        );
+        assert!(validate_sequence(&root, &moves("b8c6")).is_err());
+        assert!(validate_sequence(&root, &[]).is_err());
+        // White to move after Nc6, so e7e5 is not a legal reply.
+        assert!(validate_sequence(&root, &moves("b8c6 e7e5")).is_err());
+    }
+
+    #[test]
+    fn branches_may_not_answer_the_same_move_twice() {
+        let ok = vec![
+            moves("b8c6 f1b5 a7a6 b5a4"),
+            moves("b8c6 f1b5 g8f6 e1g1"),
+            moves("g8f6 f3e5"),
+        ];
+        assert!(check_branches(&ok).is_ok());
+
+        let conflicting = vec![moves("b8c6 f1b5"), moves("b8c6 f1c4")];
+        assert!(check_branches(&conflicting).is_err());
+    }
+
+    #[test]
+    fn multi_branch_conditionals_suggest_the_matching_reply() {
+        let mut db = correspondence_db();
+        let sequences = vec![
+            moves("b8c6 f1b5 a7a6 b5a4"),
+            moves("b8c6 f1b5 g8f6 e1g1"),
+            moves("g8f6 f3e5"),
+        ];
+        store_conditionals(&mut db, "game", ROOT, &sequences).unwrap();
+        let stored = load_conditionals(&mut db, "game", None).unwrap();
+        assert_eq!(stored.len(), 3);
+
+        let check = match_conditionals(&stored, &after("g8f6"));
+        let reply = check.reply.unwrap();
+        assert_eq!((reply.uci.as_str(), reply.san.as_str()), ("f3e5", "Nxe5"));
+        assert!(reply.continuation.is_empty());
+        assert_eq!(check.stale.len(), 2);
+
+        // Deeper in the tree, after 2... Nc6 3. Bb5 Nf6.
+        let check = match_conditionals(&stored, &after("b8c6 f1b5 g8f6"));
+        let reply = check.reply.unwrap();
+        assert_eq!(reply.uci, "e1g1");
+        assert_eq!(reply.san, "O-O");
+        // The 3... a6 line no longer applies either.
+        assert_eq!(check.stale, vec![stored[0].clone(), stored[2].clone()]);
+    }
+
+    #[test]
+    fn unexpected_moves_make_every_conditional_stale() {
+        let mut db = correspondence_db();
+        store_conditionals(&mut db, "game", ROOT, &[moves("b8c6 f1b5")]).unwrap();
+        store_conditionals(&mut db, "other", ROOT, &[moves("d7d6 d2d4")]).unwrap();
+        let stored = load_conditionals(&mut db, "game", None).unwrap();
+
+        let check = match_conditionals(&stored, &after("d7d6"));
+        assert_eq!(check.reply, None);
+        assert_eq!(check.stale, stored);
+
+        assert_eq!(remove_conditionals(&mut db, "game", Some(ROOT)).unwrap(), 1);
+        assert!(load_conditionals(&mut db, "game", None).unwrap().is_empty());
+        assert_eq!(load_conditionals(&mut db, "other", None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn conflicting_sequences_are_not_stored() {
+        let mut db = correspondence_db();
+        store_conditionals(&mut db, "game", ROOT, &[moves("b8c6 f1b5")]).unwrap();
+        let conflicting = vec![moves("b8c6 f1b5"), moves("b8c6 d2d4")];
+        assert!(store_conditionals(&mut db, "game", ROOT, &conflicting).is_err());
+        assert_eq!(load_conditionals(&mut db, "game", None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sequences_round_trip_through_pgn() {
+        let sequences = vec![
+            moves("b8c6 f1b5 a7a6 b5a4"),
+            moves("b8c6 f1b5 g8f6 e1g1"),
+            moves("g8f6 f3e5"),
+        ];
+        let pgn = sequences_to_pgn(ROOT, &sequences).unwrap();
+        assert_eq!(
+            pgn,
+            "2... Nc6 (2... Nf6 3. Nxe5) 3. Bb5 a6 (3... Nf6 4. O-O) 4. Ba4"
+        );
+
+        let mut read = sequences_from_pgn(ROOT, &pgn).unwrap();
+        read.sort();
+        let mut expected = sequences;
+        expected.sort();
+        assert_eq!(read, expected);
+
+        assert!(sequences_from_pgn(ROOT, "2... Nc6 3. Bb5 (3. Bc4) 3... a6").is_err());
+        assert!(sequences_from_pgn(ROOT, "2... Nc6 3. Qh8").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+//! Centipawn loss: what each move of an analysed game gave away by the engine's evaluation.
+//!
+//! Moves are classified by the centipawns they lost, from the point of view of the player who
+//! made them, against thresholds defaulting to lichess' own. The engine's first choice is the
+//! best move whatever the evaluations say, as those come from separate searches. Mates count
+//! as a thousand centipawns, as they do for accuracy, so a faster mate is no better than a
+//! slower one and missing a mate costs at most a thousand.
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{ByColor, CastlingMode, Chess, Color, FromSetup, Setup};
+use specta::Type;
+
+use super::accuracy::move_evaluations;
+use super::only_move::same_move;
+use super::types::MoveAnalysis;
+
+/// Centipawn losses from which a move is an inaccuracy, a mistake or a blunder.
+pub const DEFAULT_INACCURACY_CP: i32 = 50;
+pub const DEFAULT_MISTAKE_CP: i32 = 100;
+pub const DEFAULT_BLUNDER_CP: i32 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpLossThresholds {
+    pub inaccuracy_cp: i32,
+    pub mistake_cp: i32,
+    pub blunder_cp: i32,
+}
+
+impl Default for CpLossThresholds {
+    fn default() -> Self {
+        Self {
+            inaccuracy_cp: DEFAULT_INACCURACY_CP,
+            mistake_cp: DEFAULT_MISTAKE_CP,
+            blunder_cp: DEFAULT_BLUNDER_CP,
+        }
+    }
+}
+
+/// How a move compares to the engine's choice, by the centipawns it lost.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveClassification {
+    /// The engine's first choice.
+    Best,
+    Good,
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl MoveClassification {
+    pub fn classify(cp_loss: i32, best: bool, thresholds: CpLossThresholds) -> Self {
+        if best {
+            MoveClassification::Best
+        } else if cp_loss >= thresholds.blunder_cp {
+            MoveClassification::Blunder
+        } else if cp_loss >= thresholds.mistake_cp {
+            MoveClassification::Mistake
+        } else if cp_loss >= thresholds.inaccuracy_cp {
+            MoveClassification::Inaccuracy
+        } else {
+            MoveClassification::Good
+        }
+    }
+}
+
+/// Centipawn loss of one player's moves over a game.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct PlayerCpLoss {
+    /// Average centipawn loss, zero without any move.
+    pub acpl: f64,
+    /// Moves with evaluations on both sides.
+    pub moves: u32,
+    pub best: u32,
+    pub good: u32,
+    pub inaccuracies: u32,
+    pub mistakes: u32,
+    pub blunders: u32,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GameCpLoss {
+    pub white: PlayerCpLoss,
+    pub black: PlayerCpLoss,
+}
+
+/// Whether `played` is the first move of the engine's best line in `position`.
+fn is_engine_choice(position: &Setup, played: Option<&String>, analysis: &MoveAnalysis) -> bool {
+    let best = analysis
+        .best
+        .first()
+        .and_then(|line| line.uci_moves.first());
+    let (Some(played), Some(best)) = (played, best) else {
+        return false;
+    };
+    Chess::from_setup(position.clone(), CastlingMode::Chess960)
+        .is_ok_and(|chess| same_move(&chess, played, best))
+}
+
+/// Record on each position the centipawns lost by the move leading to it and how it was
+/// classified, returning the totals of each player. `positions` and `analysis` go together,
+/// the start position first, and `moves` are the game moves in UCI.
+pub fn annotate_cp_loss(
+    positions: &[Setup],
+    moves: &[String],
+    analysis: &mut [MoveAnalysis],
+    thresholds: CpLossThresholds,
+) -> GameCpLoss {
+    let mut players = ByColor::<PlayerCpLoss>::default();
+    let mut totals = ByColor::<i64>::default();
+    let plies = positions.len().min(analysis.len()).saturating_sub(1);
+    for ply in 0..plies {
+        let Some((color, before, after)) = move_evaluations(positions, analysis, ply) else {
+            continue;
+        };
+        let cp_loss = (before - after).max(0);
+        let best = is_engine_choice(&positions[ply], moves.get(ply), &analysis[ply]);
+        let classification = MoveClassification::classify(cp_loss, best, thresholds);
+        analysis[ply + 1].cp_loss = Some(cp_loss);
+        analysis[ply + 1].classification = Some(classification);
+
+        let player = players.get_mut(color);
+        player.moves += 1;
+        match classification {
+            MoveClassification::Best => player.best += 1,
+            MoveClassification::Good => player.good += 1,
+            MoveClassification::Inaccuracy => player.inaccuracies += 1,
+            MoveClassification::Mistake => player.mistakes += 1,
+            MoveClassification::Blunder => player.blunders += 1,
+        }
+        *totals.get_mut(color) += i64::from(cp_loss);
+    }
+    for color in Color::ALL {
+        let player = players.get_mut(color);
+        if player.moves > 0 {
+            player.acpl = *totals.get(color) as f64 / f64::from(player.moves);
+        }
+    }
+    GameCpLoss {
+        white: players.white,
+        black: players.black,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{san::San, EnPassantMode, Position};
+    use vampirc_uci::uci::{Score, ScoreValue};
+
+    use crate::chess::types::BestMoves;
+
+    /// The game's positions, the start position first, and its moves in UCI.
+    fn game(san: &str) -> (Vec<Setup>, Vec<String>) {
+        let mut chess = Chess::default();
+        let mut positions = vec![chess.clone().into_setup(EnPassantMode::Legal)];
+        let mut moves = Vec::new();
+        for san in san.split_whitespace() {
+            let m = san.parse::<San>().unwrap().to_move(&chess).unwrap();
+            moves.push(m.to_uci(CastlingMode::Standard).to_string());
+            chess.play_unchecked(&m);
+            positions.push(chess.clone().into_setup(EnPassantMode::Legal));
+        }
+        (positions, moves)
+    }
+
+    /// Analysis of a position scored `value` from White's point of view, `best` first.
+    fn analysis(value: ScoreValue, best: &str) -> MoveAnalysis {
+        MoveAnalysis {
+            best: vec![BestMoves {
+                score: Score {
+                    value,
+                    ..Default::default()
+                },
+                uci_moves: vec![best.to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn losses_are_classified_by_threshold() {
+        let thresholds = CpLossThresholds::default();
+        let classify = |cp_loss| MoveClassification::classify(cp_loss, false, thresholds);
+        assert_eq!(classify(0), MoveClassification::Good);
+        assert_eq!(classify(49), MoveClassification::Good);
+        assert_eq!(classify(50), MoveClassification::Inaccuracy);
+        assert_eq!(classify(100), MoveClassification::Mistake);
+        assert_eq!(classify(300), MoveClassification::Blunder);
+        assert_eq!(
+            MoveClassification::classify(500, true, thresholds),
+            MoveClassification::Best
+        );
+        let strict = CpLossThresholds {
+            inaccuracy_cp: 20,
+            ..thresholds
+        };
+        assert_eq!(
+            MoveClassification::classify(20, false, strict),
+            MoveClassification::Inaccuracy
+        );
+    }
+
+    #[test]
+    fn moves_are_annotated_and_added_up_by_player() {
+        use ScoreValue::Cp;
+        let (positions, moves) = game("e4 e5 Nf3 f6");
+        let mut evals = vec![
+            analysis(Cp(20), "e2e4"),
+            analysis(Cp(30), "c7c5"),
+            analysis(Cp(40), "g1f3"),
+            analysis(Cp(40), "b8c6"),
+            analysis(Cp(400), "f3e5"),
+        ];
+        let totals = annotate_cp_loss(&positions, &moves, &mut evals, CpLossThresholds::default());
+
+        assert_eq!(evals[0].cp_loss, None);
+        let classified: Vec<_> = evals[1..]
+            .iter()
+            .map(|a| (a.cp_loss.unwrap(), a.classification.unwrap()))
+            .collect();
+        assert_eq!(
+            classified,
+            [
+                (0, MoveClassification::Best),
+                (10, MoveClassification::Good),
+                (0, MoveClassification::Best),
+                (360, MoveClassification::Blunder),
+            ]
+        );
+        assert_eq!(
+            totals.white,
+            PlayerCpLoss {
+                moves: 2,
+                best: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            totals.black,
+            PlayerCpLoss {
+                acpl: 185.0,
+                moves: 2,
+                good: 1,
+                blunders: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn mates_count_as_a_thousand_centipawns() {
+        use ScoreValue::{Cp, Mate};
+        let (positions, moves) = game("e4 e5 a3");
+        let mut evals = vec![
+            analysis(Mate(2), "d2d4"),
+            analysis(Mate(5), "d7d5"),
+            analysis(Mate(9), "d2d4"),
+            analysis(Cp(150), "d7d5"),
+        ];
+        annotate_cp_loss(&positions, &moves, &mut evals, CpLossThresholds::default());
+        // A slower mate loses nothing, and neither can the player getting mated.
+        assert_eq!(evals[1].cp_loss, Some(0));
+        assert_eq!(evals[2].cp_loss, Some(0));
+        // Letting the mate go loses what is left over the evaluation.
+        assert_eq!(evals[3].cp_loss, Some(850));
+        assert_eq!(evals[3].classification, Some(MoveClassification::Blunder));
+
+        // Positions without engine lines are skipped.
+        let mut evals = vec![analysis(Cp(0), "e2e4"), MoveAnalysis::default()];
+        let totals = annotate_cp_loss(&positions[..2], &moves, &mut evals, Default::default());
+        assert_eq!(evals[1].cp_loss, None);
+        assert_eq!(totals, GameCpLoss::default());
+    }
+}
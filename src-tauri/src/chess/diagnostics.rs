@@ -0,0 +1,126 @@
+//! Runtime checks of engine behaviour.
+//!
+//! Some engines accept `setoption name MultiPV` but still only ever report one line, with
+//! `multipv 1` or without the attribute at all. `MultiPvDiagnostic` watches the info lines
+//! of a search and notices once a few depth iterations went by without a second line, so
+//! the user can be told why fewer lines show up than requested.
+
+/// Depth iterations to complete with only `multipv 1` lines before the engine is considered
+/// to ignore MultiPV.
+const MULTIPV_CHECK_DEPTHS: u32 = 3;
+
+/// Tracks the MultiPV lines an engine reports against the number requested.
+#[derive(Debug, Default)]
+pub struct MultiPvDiagnostic {
+    requested: u16,
+    max_seen: u16,
+    depth: u32,
+    completed_depths: u32,
+    limit: Option<u16>,
+}
+
+impl MultiPvDiagnostic {
+    /// Start watching a new search that requested `requested` lines.
+    pub fn start(&mut self, requested: u16) {
+        self.requested = requested;
+        self.max_seen = 0;
+        self.depth = 0;
+        self.completed_depths = 0;
+    }
+
+    /// Record an info line, returning the number of lines the engine actually reports the
+    /// first time it turns out to ignore MultiPV.
+    pub fn observe(&mut self, multipv: u16, depth: u32) -> Option<u16> {
+        self.max_seen = self.max_seen.max(multipv);
+        if depth > self.depth {
+            if self.depth > 0 {
+                self.completed_depths += 1;
+            }
+            self.depth = depth;
+        }
+        if self.limit.is_none()
+            && self.requested > 1
+            && self.max_seen == 1
+            && self.completed_depths >= MULTIPV_CHECK_DEPTHS
+        {
+            self.limit = Some(self.max_seen);
+            return self.limit;
+        }
+        None
+    }
+
+    /// Lines the engine reports at most, once it was found to ignore MultiPV.
+    pub fn limit(&self) -> Option<u16> {
+        self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::process::parse_uci_attrs;
+    use vampirc_uci::{parse_one, UciMessage};
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    /// Feed engine output through the diagnostic like the analysis loop does, returning the
+    /// warnings it raised.
+    fn run(diagnostic: &mut MultiPvDiagnostic, output: &[String]) -> Vec<u16> {
+        let fen = START.parse().unwrap();
+        output
+            .iter()
+            .filter_map(|line| match parse_one(line) {
+                UciMessage::Info(attrs) => parse_uci_attrs(attrs, &fen, &Vec::new()).ok(),
+                _ => None,
+            })
+            .filter_map(|best| diagnostic.observe(best.multipv, best.depth))
+            .collect()
+    }
+
+    fn info_lines(depths: u32, multipv: &[Option<u16>]) -> Vec<String> {
+        (1..=depths)
+            .flat_map(|depth| {
+                multipv.iter().map(move |multipv| {
+                    let multipv = multipv.map_or(String::new(), |m| format!(" multipv {}", m));
+                    format!(
+                        "info depth {}{} score cp 20 nodes 1000 pv e2e4 e7e5",
+                        depth, multipv
+                    )
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn engines_ignoring_multipv_are_reported_once() {
+        let mut diagnostic = MultiPvDiagnostic::default();
+        diagnostic.start(5);
+        assert_eq!(run(&mut diagnostic, &info_lines(10, &[Some(1)])), vec![1]);
+        assert_eq!(diagnostic.limit(), Some(1));
+
+        // Later searches keep the limit without warning again.
+        diagnostic.start(5);
+        assert!(run(&mut diagnostic, &info_lines(10, &[None])).is_empty());
+        assert_eq!(diagnostic.limit(), Some(1));
+    }
+
+    #[test]
+    fn missing_multipv_attributes_count_as_a_single_line() {
+        let mut diagnostic = MultiPvDiagnostic::default();
+        diagnostic.start(3);
+        assert!(run(&mut diagnostic, &info_lines(3, &[None])).is_empty());
+        assert_eq!(run(&mut diagnostic, &info_lines(5, &[None])), vec![1]);
+    }
+
+    #[test]
+    fn engines_honouring_multipv_are_not_reported() {
+        let mut diagnostic = MultiPvDiagnostic::default();
+        diagnostic.start(3);
+        let lines = info_lines(20, &[Some(1), Some(2), Some(3)]);
+        assert!(run(&mut diagnostic, &lines).is_empty());
+
+        diagnostic.start(1);
+        assert!(run(&mut diagnostic, &info_lines(20, &[Some(1)])).is_empty());
+        assert_eq!(diagnostic.limit(), None);
+    }
+}
@@ -0,0 +1,504 @@
+//! Line drills: replaying an engine line from memory.
+//!
+//! The user plays the side to move in the starting position and has to reproduce the moves of
+//! a target line, while the opponent's moves are played automatically. Moves that differ from
+//! the line are checked with a short engine search: if they are within the tolerance they are
+//! accepted (and the drill continues along the line), otherwise the drill ends.
+
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Position};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use vampirc_uci::{parse_one, UciInfoAttribute, UciMessage};
+
+use crate::error::Error;
+use crate::scope::PathScope;
+use crate::AppState;
+
+use super::effects::MoveEffects;
+use super::evaluation::score_to_cp;
+use super::process::EngineProcess;
+use super::types::GoMode;
+use super::uci::EngineStdout;
+
+/// Default centipawn loss up to which a move that differs from the line is accepted.
+const DEFAULT_TOLERANCE_CP: i32 = 30;
+
+/// Drills untouched for this long are discarded along with their engine.
+const DRILL_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// Search time of the engine check comparing a deviation with the expected move, in ms.
+const CHECK_MOVETIME: u32 = 300;
+
+/// Result of comparing a submitted move with the line.
+#[derive(Serialize, Debug, Clone, Copy, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DrillVerdict {
+    /// The move of the line was played.
+    Exact,
+    /// A different move of about the same value was played.
+    Alternative,
+    /// A clearly worse move was played; the drill is over.
+    Mistake,
+}
+
+/// Final results of a drill.
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DrillSummary {
+    /// Moves of the line reproduced exactly.
+    pub recalled: u32,
+    /// Deviations accepted by the engine check.
+    pub alternatives: u32,
+    /// Moves the user had to find in the whole line.
+    pub total: u32,
+    /// Percentage of the line's moves recalled exactly.
+    pub accuracy: f64,
+    /// Whether the end of the line was reached.
+    pub completed: bool,
+}
+
+/// Outcome of a move submitted to a drill.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct DrillMoveResult {
+    pub verdict: DrillVerdict,
+    pub expected_san: String,
+    /// What the submitted move does, for sound and speech cues.
+    pub effects: MoveEffects,
+    /// Centipawns lost compared to the expected move, if an engine check was needed.
+    pub eval_difference: Option<i32>,
+    pub note: Option<String>,
+    /// Opponent move played automatically from the line, in UCI notation.
+    pub reply: Option<String>,
+    pub reply_effects: Option<MoveEffects>,
+    /// Moves played so far along the line, in UCI notation.
+    pub moves: Vec<String>,
+    /// Set once the drill is over.
+    pub summary: Option<DrillSummary>,
+}
+
+/// Progress through a target line. Engine-independent so it can be tested on its own.
+pub struct LineDrill {
+    pub fen: String,
+    target: Vec<String>,
+    position: Chess,
+    index: usize,
+    /// Effects of the last move played along the line.
+    last_effects: Option<MoveEffects>,
+    recalled: u32,
+    alternatives: u32,
+    finished: bool,
+}
+
+impl LineDrill {
+    /// Create a drill, checking that the target line is legal from `fen`.
+    pub fn new(fen: String, target: Vec<String>) -> Result<Self, Error> {
+        let parsed: Fen = fen.parse()?;
+        let position: Chess = parsed.into_position(CastlingMode::Chess960)?;
+
+        let mut check = position.clone();
+        for m in &target {
+            let mv = UciMove::from_ascii(m.as_bytes())?.to_move(&check)?;
+            check.play_unchecked(&mv);
+        }
+        if target.is_empty() {
+            return Err(Error::NoMovesFound);
+        }
+
+        Ok(Self {
+            fen,
+            target,
+            position,
+            index: 0,
+            last_effects: None,
+            recalled: 0,
+            alternatives: 0,
+            finished: false,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn position(&self) -> &Chess {
+        &self.position
+    }
+
+    /// Moves played so far along the line.
+    pub fn moves(&self) -> Vec<String> {
+        self.target[..self.index].to_vec()
+    }
+
+    /// The move the user is expected to play next.
+    pub fn expected(&self) -> Option<&str> {
+        self.target.get(self.index).map(String::as_str)
+    }
+
+    /// Number of moves the user has to find in the whole line.
+    fn total(&self) -> u32 {
+        self.target.len().div_ceil(2) as u32
+    }
+
+    pub fn summary(&self) -> DrillSummary {
+        DrillSummary {
+            recalled: self.recalled,
+            alternatives: self.alternatives,
+            total: self.total(),
+            accuracy: self.recalled as f64 / self.total() as f64 * 100.0,
+            completed: self.index >= self.target.len(),
+        }
+    }
+
+    /// Whether `uci` is the expected move. Fails if the move is illegal.
+    pub fn is_expected(&self, uci: &str) -> Result<bool, Error> {
+        let uci = UciMove::from_ascii(uci.as_bytes())?;
+        let mv = uci.to_move(&self.position)?;
+        let expected = UciMove::from_ascii(self.expected().unwrap_or_default().as_bytes())?
+            .to_move(&self.position)?;
+        Ok(mv == expected)
+    }
+
+    fn play_expected(&mut self) {
+        let mv = UciMove::from_ascii(self.target[self.index].as_bytes())
+            .ok()
+            .and_then(|uci| uci.to_move(&self.position).ok())
+            .expect("target line was validated");
+        self.index += 1;
+        self.last_effects = Some(MoveEffects::new(&self.position, &mv));
+        self.position.play_unchecked(&mv);
+    }
+
+    /// Effects of the last move played along the line, which is the opponent's reply after
+    /// `record` returned one.
+    pub fn last_move_effects(&self) -> Option<MoveEffects> {
+        self.last_effects
+    }
+
+    /// Record the verdict for the user's move.
+    ///
+    /// Accepted moves continue along the line and the opponent's reply is played
+    /// automatically; a mistake ends the drill. Returns the reply, if any.
+    pub fn record(&mut self, verdict: DrillVerdict) -> Option<String> {
+        match verdict {
+            DrillVerdict::Exact => self.recalled += 1,
+            DrillVerdict::Alternative => self.alternatives += 1,
+            DrillVerdict::Mistake => {
+                self.finished = true;
+                return None;
+            }
+        }
+        self.play_expected();
+
+        let reply = self.expected().map(str::to_string);
+        if reply.is_some() {
+            self.play_expected();
+        }
+        if self.index >= self.target.len() {
+            self.finished = true;
+        }
+        reply
+    }
+}
+
+/// Classify a deviation from the line by how much worse it is than the expected move.
+pub fn deviation_verdict(eval_difference: i32, tolerance_cp: i32) -> DrillVerdict {
+    if eval_difference <= tolerance_cp {
+        DrillVerdict::Alternative
+    } else {
+        DrillVerdict::Mistake
+    }
+}
+
+/// A drill stored in the application state, with the engine used for its checks.
+pub struct DrillSession {
+    pub tab: String,
+    engine: String,
+    drill: LineDrill,
+    tolerance_cp: i32,
+    record: bool,
+    last_activity: Instant,
+    /// Spawned on the first deviation and reused for every later check.
+    process: Option<(EngineProcess, EngineStdout)>,
+}
+
+impl DrillSession {
+    /// Evaluate the position after `moves` with a short search, from the point of view of
+    /// the player who made the last move.
+    async fn quick_eval(&mut self, moves: Vec<String>, scope: &PathScope) -> Result<i32, Error> {
+        if self.process.is_none() {
+            self.process = Some(EngineProcess::new(PathBuf::from(&self.engine), scope).await?);
+        }
+        let (process, reader) = self.process.as_mut().unwrap();
+
+        process.set_position(&self.drill.fen, &moves).await?;
+        process.go(&GoMode::Time(CHECK_MOVETIME)).await?;
+
+        let mut score = None;
+        while let Some(line) = reader.next_line().await? {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    for attr in attrs {
+                        if let UciInfoAttribute::Score(s) = attr {
+                            score = Some(s);
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => break,
+                _ => {}
+            }
+        }
+        process.running = false;
+
+        // Scores are reported for the side to move, i.e. the opponent of the last mover.
+        Ok(score.map(|s| -score_to_cp(&s)).unwrap_or_default())
+    }
+}
+
+/// Kill the engines of drills that were abandoned.
+async fn purge_expired_drills(state: &tauri::State<'_, AppState>) {
+    let expired: Vec<String> = state
+        .line_drills
+        .iter()
+        .filter(|entry| {
+            entry
+                .value()
+                .try_lock()
+                .is_ok_and(|session| session.last_activity.elapsed() > DRILL_EXPIRY)
+        })
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    for id in expired {
+        if let Some((_, session)) = state.line_drills.remove(&id) {
+            info!("Line drill expired: {}", id);
+            if let Some((process, _)) = session.lock().await.process.as_mut() {
+                process.kill().await.ok();
+            }
+        }
+    }
+}
+
+fn record_summary(app: &tauri::AppHandle, drill: &LineDrill, summary: &DrillSummary) {
+    let result = (|| -> Result<(), Error> {
+        let path = app
+            .path()
+            .resolve("training/line_drills.jsonl", BaseDirectory::AppData)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let entry = serde_json::json!({
+            "fen": drill.fen,
+            "line": drill.target,
+            "summary": summary,
+            "timestamp": std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        });
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        log::warn!("Failed to record line drill: {}", e);
+    }
+}
+
+/// Start drilling `target_line` from `fen`, returning the drill identifier.
+#[tauri::command]
+#[specta::specta]
+pub async fn start_line_drill(
+    tab: String,
+    engine: String,
+    fen: String,
+    target_line: Vec<String>,
+    tolerance_cp: Option<i32>,
+    record: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    purge_expired_drills(&state).await;
+    // The engine is only started on the first deviation, so fail now rather than then.
+    state.path_scope.check_engine(&engine)?;
+
+    let drill = LineDrill::new(fen, target_line)?;
+    let id = Uuid::new_v4().to_string();
+    state.line_drills.insert(
+        id.clone(),
+        Arc::new(Mutex::new(DrillSession {
+            tab,
+            engine,
+            drill,
+            tolerance_cp: tolerance_cp.unwrap_or(DEFAULT_TOLERANCE_CP),
+            record: record.unwrap_or(false),
+            last_activity: Instant::now(),
+            process: None,
+        })),
+    );
+    Ok(id)
+}
+
+/// Submit the user's next move in a drill.
+#[tauri::command]
+#[specta::specta]
+pub async fn submit_drill_move(
+    drill: String,
+    uci: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<DrillMoveResult, Error> {
+    purge_expired_drills(&state).await;
+
+    let session = state
+        .line_drills
+        .get(&drill)
+        .map(|s| s.clone())
+        .ok_or_else(|| Error::DrillNotFound(drill.clone()))?;
+    let mut session = session.lock().await;
+    session.last_activity = Instant::now();
+
+    let expected = session
+        .drill
+        .expected()
+        .ok_or(Error::NoMovesFound)?
+        .to_string();
+    let expected_san = {
+        let mv = UciMove::from_ascii(expected.as_bytes())?.to_move(session.drill.position())?;
+        SanPlus::from_move(session.drill.position().clone(), &mv).to_string()
+    };
+
+    let effects = MoveEffects::from_uci(session.drill.position(), &uci)?;
+    let (verdict, eval_difference, note) = if session.drill.is_expected(&uci)? {
+        (DrillVerdict::Exact, None, None)
+    } else {
+        let moves = session.drill.moves();
+        let expected_eval = session
+            .quick_eval(
+                [moves.clone(), vec![expected.clone()]].concat(),
+                &state.path_scope,
+            )
+            .await?;
+        let played_eval = session
+            .quick_eval([moves, vec![uci.clone()]].concat(), &state.path_scope)
+            .await?;
+
+        let difference = (expected_eval - played_eval).max(0);
+        let verdict = deviation_verdict(difference, session.tolerance_cp);
+        let note = match verdict {
+            DrillVerdict::Alternative => Some(format!(
+                "Also good, but the line continues with {}",
+                expected_san
+            )),
+            _ => Some(format!(
+                "The line continues with {} ({} cp better)",
+                expected_san, difference
+            )),
+        };
+        (verdict, Some(difference), note)
+    };
+
+    let reply = session.drill.record(verdict);
+    let reply_effects = reply
+        .as_ref()
+        .and_then(|_| session.drill.last_move_effects());
+    let summary = session.drill.is_finished().then(|| session.drill.summary());
+
+    if let Some(summary) = &summary {
+        if session.record {
+            record_summary(&app, &session.drill, summary);
+        }
+        if let Some((process, _)) = session.process.as_mut() {
+            process.kill().await?;
+        }
+        state.line_drills.remove(&drill);
+    }
+
+    Ok(DrillMoveResult {
+        verdict,
+        expected_san,
+        effects,
+        eval_difference,
+        note,
+        reply,
+        reply_effects,
+        moves: session.drill.moves(),
+        summary,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn line(moves: &[&str]) -> Vec<String> {
+        moves.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn illegal_target_line_is_rejected() {
+        assert!(LineDrill::new(START.to_string(), line(&["e2e4", "e2e4"])).is_err());
+        assert!(LineDrill::new(START.to_string(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn exact_moves_advance_with_automatic_replies() {
+        let mut drill = LineDrill::new(START.to_string(), line(&["e2e4", "e7e5", "g1f3"])).unwrap();
+        assert!(drill.is_expected("e2e4").unwrap());
+        assert!(!drill.is_expected("d2d4").unwrap());
+
+        assert_eq!(drill.record(DrillVerdict::Exact), Some("e7e5".to_string()));
+        assert!(!drill.is_finished());
+        assert_eq!(drill.expected(), Some("g1f3"));
+
+        assert_eq!(drill.record(DrillVerdict::Exact), None);
+        assert!(drill.is_finished());
+        let summary = drill.summary();
+        assert_eq!(summary.recalled, 2);
+        assert_eq!(summary.total, 2);
+        assert!(summary.completed);
+        assert_eq!(summary.accuracy, 100.0);
+    }
+
+    #[test]
+    fn mistake_ends_drill() {
+        let mut drill =
+            LineDrill::new(START.to_string(), line(&["e2e4", "e7e5", "g1f3", "b8c6"])).unwrap();
+        drill.record(DrillVerdict::Alternative);
+        assert_eq!(drill.record(DrillVerdict::Mistake), None);
+        assert!(drill.is_finished());
+
+        let summary = drill.summary();
+        assert_eq!(summary.recalled, 0);
+        assert_eq!(summary.alternatives, 1);
+        assert!(!summary.completed);
+        assert_eq!(drill.moves(), line(&["e2e4", "e7e5"]));
+    }
+
+    #[test]
+    fn replies_are_classified() {
+        let mut drill =
+            LineDrill::new(START.to_string(), line(&["e2e4", "d7d5", "e4d5", "d8d5"])).unwrap();
+        drill.record(DrillVerdict::Exact);
+        assert_eq!(drill.last_move_effects(), Some(MoveEffects::default()));
+        assert_eq!(drill.record(DrillVerdict::Exact), Some("d8d5".to_string()));
+        assert!(drill.last_move_effects().unwrap().is_capture);
+    }
+
+    #[test]
+    fn deviations_are_judged_by_tolerance() {
+        assert_eq!(deviation_verdict(10, 30), DrillVerdict::Alternative);
+        assert_eq!(deviation_verdict(30, 30), DrillVerdict::Alternative);
+        assert_eq!(deviation_verdict(31, 30), DrillVerdict::Mistake);
+    }
+}
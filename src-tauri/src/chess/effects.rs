@@ -0,0 +1,124 @@
+//! What a move does on the board, for sound and speech cues.
+//!
+//! Cues used to be guessed from SAN, which gets promotions with check and en passant wrong.
+//! Every place the backend plays or reports a move classifies it here instead, so they all
+//! agree, and `classify_move` covers moves the frontend applies on its own.
+
+use serde::Serialize;
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Move, Position};
+use specta::Type;
+
+use crate::error::Error;
+
+/// Semantic effects of a move.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveEffects {
+    /// Takes a piece, en passant included.
+    pub is_capture: bool,
+    pub is_check: bool,
+    pub is_checkmate: bool,
+    pub is_castle: bool,
+    pub is_promotion: bool,
+    pub is_en_passant: bool,
+}
+
+impl MoveEffects {
+    /// Classify a legal move `m` played from `position`.
+    pub fn new(position: &Chess, m: &Move) -> Self {
+        let mut after = position.clone();
+        after.play_unchecked(m);
+        Self {
+            is_capture: m.is_capture(),
+            is_check: after.is_check(),
+            is_checkmate: after.is_checkmate(),
+            is_castle: m.is_castle(),
+            is_promotion: m.is_promotion(),
+            is_en_passant: m.is_en_passant(),
+        }
+    }
+
+    /// Classify a move given in UCI notation, failing if it isn't legal in `position`.
+    pub fn from_uci(position: &Chess, uci: &str) -> Result<Self, Error> {
+        let m = UciMove::from_ascii(uci.as_bytes())?.to_move(position)?;
+        Ok(Self::new(position, &m))
+    }
+}
+
+/// Classify a move the frontend applied itself.
+#[tauri::command]
+#[specta::specta]
+pub fn classify_move(fen: String, uci: String) -> Result<MoveEffects, Error> {
+    let position: Chess = Fen::from_ascii(fen.as_bytes())?.into_position(CastlingMode::Chess960)?;
+    MoveEffects::from_uci(&position, &uci)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(fen: &str, uci: &str) -> MoveEffects {
+        classify_move(fen.to_string(), uci.to_string()).unwrap()
+    }
+
+    #[test]
+    fn quiet_moves_have_no_effects() {
+        assert_eq!(
+            classify(
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "e2e4"
+            ),
+            MoveEffects::default()
+        );
+    }
+
+    #[test]
+    fn en_passant_with_discovered_check() {
+        // Taking the d-pawn opens the e-file for the rook.
+        assert_eq!(
+            classify("4k3/8/8/3pP3/8/8/8/4R1K1 w - d6 0 2", "e5d6"),
+            MoveEffects {
+                is_capture: true,
+                is_check: true,
+                is_en_passant: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn underpromotion_with_mate() {
+        assert_eq!(
+            classify("7k/P5pp/8/8/8/8/8/K7 w - - 0 1", "a7a8r"),
+            MoveEffects {
+                is_check: true,
+                is_checkmate: true,
+                is_promotion: true,
+                ..Default::default()
+            }
+        );
+        // Taking on the way.
+        let effects = classify("1r5k/P5pp/8/8/8/8/8/K7 w - - 0 1", "a7b8n");
+        assert!(effects.is_capture && effects.is_promotion && !effects.is_check);
+    }
+
+    #[test]
+    fn castling() {
+        assert_eq!(
+            classify("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1"),
+            MoveEffects {
+                is_castle: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn illegal_moves_are_rejected() {
+        assert!(classify_move(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1".to_string(),
+            "e2e5".to_string()
+        )
+        .is_err());
+    }
+}
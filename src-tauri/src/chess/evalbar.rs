@@ -0,0 +1,422 @@
+//! Eval bar engine.
+//!
+//! A tab can have an engine dedicated to its eval bar, separate from the engines analysing
+//! in it, so the bar stays live while the main engine runs a deep MultiPV search. It searches
+//! the position the frontend reports to a shallow depth and only sends the score, on its own
+//! `EvalBarUpdate` event, at a bounded rate.
+//!
+//! Eval bar engines aren't part of the engines the user configures: they always run on a
+//! single thread with a small hash table, whatever the settings of the engine they use.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use shakmaty::fen::Fen;
+use specta::Type;
+use tauri::Manager;
+use tauri_specta::Event;
+use tokio::sync::Mutex;
+use vampirc_uci::{parse_one, uci::Score, UciMessage};
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::process::{parse_uci_attrs, EngineProcess};
+use super::types::{BestMoves, GoMode};
+
+/// Depth searched when the limits don't set one.
+const DEFAULT_DEPTH: u32 = 12;
+/// Deepest search an eval bar may ask for.
+const MAX_DEPTH: u32 = 20;
+/// Resources given to every eval bar engine.
+const THREADS: u16 = 1;
+const HASH_MB: u16 = 16;
+/// Minimum time between two updates of an eval bar.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Search limits of an eval bar engine.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EvalBarLimits {
+    /// Depth to search each position to, 12 by default and at most 20.
+    pub depth: Option<u32>,
+}
+
+impl EvalBarLimits {
+    fn go_mode(&self) -> GoMode {
+        GoMode::Depth(self.depth.unwrap_or(DEFAULT_DEPTH).clamp(1, MAX_DEPTH))
+    }
+}
+
+/// Event payload for eval bar updates.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct EvalBarUpdate {
+    pub tab: String,
+    pub fen: String,
+    pub moves: Vec<String>,
+    /// Score from White's point of view.
+    pub score: Score,
+    /// Score as displayed.
+    pub display: String,
+    pub depth: u32,
+    /// Whether the search of the position is over.
+    pub done: bool,
+}
+
+/// Picks the engine output worth sending to the eval bar.
+///
+/// Output of searches stopped because the position changed is dropped, and updates are
+/// throttled, except for the last one of each search.
+#[derive(Debug)]
+struct EvalBarFeed {
+    interval: Duration,
+    /// Whether a search was started and its `bestmove` hasn't been read yet.
+    searching: bool,
+    /// Searches abandoned for a newer position whose `bestmove` hasn't been read yet.
+    stale: u32,
+    /// Latest line of the current search.
+    latest: Option<BestMoves>,
+    last_sent: Option<Instant>,
+}
+
+impl EvalBarFeed {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            searching: false,
+            stale: 0,
+            latest: None,
+            last_sent: None,
+        }
+    }
+
+    /// Note that a search of a new position was started.
+    fn start(&mut self) {
+        if self.searching {
+            self.stale += 1;
+        }
+        self.searching = true;
+        self.latest = None;
+        self.last_sent = None;
+    }
+
+    /// Whether the output read now belongs to an abandoned search.
+    fn is_stale(&self) -> bool {
+        self.stale > 0
+    }
+
+    /// Take a line of the current search, returning it if it should be sent now.
+    fn line(&mut self, line: BestMoves, now: Instant) -> Option<BestMoves> {
+        if self.is_stale() || line.multipv != 1 {
+            return None;
+        }
+        self.latest = Some(line.clone());
+        if self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return None;
+        }
+        self.last_sent = Some(now);
+        Some(line)
+    }
+
+    /// Take the end of a search, returning its last line if it belongs to the current one.
+    fn best_move(&mut self) -> Option<BestMoves> {
+        if self.stale > 0 {
+            self.stale -= 1;
+            return None;
+        }
+        self.searching = false;
+        self.latest.take()
+    }
+}
+
+/// Engine process dedicated to the eval bar of a tab.
+pub struct EvalBarEngine {
+    engine: String,
+    process: EngineProcess,
+    limits: EvalBarLimits,
+    feed: EvalBarFeed,
+    /// Position last reported by the frontend, as a FEN and the moves played from it.
+    position: Option<(String, Vec<String>)>,
+}
+
+impl EvalBarEngine {
+    /// Start searching the last reported position.
+    async fn search(&mut self) -> Result<(), Error> {
+        let Some((fen, moves)) = self.position.clone() else {
+            return Ok(());
+        };
+        if self.feed.searching {
+            self.process.stop().await?;
+        }
+        self.feed.start();
+        self.process.set_position(&fen, &moves).await?;
+        self.process.go(&self.limits.go_mode()).await
+    }
+
+    fn update(&self, line: BestMoves, done: bool) -> EvalBarUpdate {
+        EvalBarUpdate {
+            tab: self.process.tab.clone(),
+            fen: self.process.options.fen.clone(),
+            moves: self.process.options.moves.clone(),
+            score: line.score,
+            display: line.display,
+            depth: line.depth,
+            done,
+        }
+    }
+
+    /// Turn a line of engine output into an update of the eval bar, if one is due.
+    fn read(&mut self, line: &str) -> Option<EvalBarUpdate> {
+        match parse_one(line) {
+            UciMessage::Info(attrs) => {
+                if self.feed.is_stale() {
+                    return None;
+                }
+                let fen: Fen = self.process.options.fen.parse().ok()?;
+                let best = parse_uci_attrs(attrs, &fen, &self.process.options.moves).ok()?;
+                let best = self.feed.line(best, Instant::now())?;
+                Some(self.update(best, false))
+            }
+            UciMessage::BestMove { .. } => {
+                let best = self.feed.best_move();
+                self.process.running = self.feed.searching;
+                Some(self.update(best?, true))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Runs the eval bar engines of tabs.
+pub struct EvalBarManager<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> EvalBarManager<'a> {
+    /// Create a new `EvalBarManager` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    /// Use `engine` for the eval bar of a tab, replacing the engine it used before.
+    ///
+    /// Keeps the process when only the limits change, searching the current position again.
+    pub async fn set_engine(
+        &self,
+        app: tauri::AppHandle,
+        tab: String,
+        engine: String,
+        limits: EvalBarLimits,
+    ) -> Result<(), Error> {
+        let existing = self.state.evalbar_engines.get(&tab).map(|x| x.clone());
+        let mut position = None;
+        if let Some(existing) = existing {
+            let mut existing = existing.lock().await;
+            if existing.engine == engine {
+                existing.limits = limits;
+                return existing.search().await;
+            }
+            position = existing.position.take();
+            existing.process.kill().await?;
+        }
+
+        let path = PathBuf::from(&engine);
+        info!("Starting eval bar engine: tab={} engine={}", tab, engine);
+        let (mut process, mut reader) = EngineProcess::new(path, &self.state.path_scope).await?;
+        process.set_option("Threads", THREADS).await?;
+        process.set_option("Hash", HASH_MB).await?;
+        process.tab = tab.clone();
+        let mut evalbar = EvalBarEngine {
+            engine,
+            process,
+            limits,
+            feed: EvalBarFeed::new(UPDATE_INTERVAL),
+            position,
+        };
+        evalbar.search().await?;
+
+        let evalbar = Arc::new(Mutex::new(evalbar));
+        self.state
+            .evalbar_engines
+            .insert(tab.clone(), evalbar.clone());
+
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = reader.next_line().await {
+                let update = evalbar.lock().await.read(&line);
+                if let Some(update) = update {
+                    update.emit(&app).ok();
+                }
+            }
+            // The tab may have been renamed since.
+            let tab = evalbar.lock().await.process.tab.clone();
+            info!("Eval bar engine finished: tab={}", tab);
+            // The slot may hold a newer engine by now.
+            app.state::<AppState>()
+                .evalbar_engines
+                .remove_if(&tab, |_, current| Arc::ptr_eq(current, &evalbar));
+        });
+        Ok(())
+    }
+
+    /// Follow the position shown in a tab. Does nothing if the tab has no eval bar engine.
+    pub async fn set_position(
+        &self,
+        tab: &str,
+        fen: String,
+        moves: Vec<String>,
+    ) -> Result<(), Error> {
+        let _: Fen = fen.parse()?;
+        let Some(evalbar) = self.state.evalbar_engines.get(tab).map(|x| x.clone()) else {
+            return Ok(());
+        };
+        let mut evalbar = evalbar.lock().await;
+        if evalbar.position.as_ref() == Some(&(fen.clone(), moves.clone())) {
+            return Ok(());
+        }
+        evalbar.position = Some((fen, moves));
+        evalbar.search().await
+    }
+
+    /// Search the current position of a tab again, once the tab is shown.
+    pub async fn resume(&self, tab: &str) -> Result<(), Error> {
+        let Some(evalbar) = self.state.evalbar_engines.get(tab).map(|x| x.clone()) else {
+            return Ok(());
+        };
+        let mut evalbar = evalbar.lock().await;
+        if evalbar.process.running {
+            return Ok(());
+        }
+        evalbar.search().await
+    }
+
+    /// Stop the search of a tab's eval bar engine, keeping the process.
+    pub async fn stop(&self, tab: &str) -> Result<(), Error> {
+        let Some(evalbar) = self.state.evalbar_engines.get(tab).map(|x| x.clone()) else {
+            return Ok(());
+        };
+        let mut evalbar = evalbar.lock().await;
+        if evalbar.process.running {
+            evalbar.process.stop().await?;
+        }
+        Ok(())
+    }
+
+    /// Move the eval bar engine of tab `old` to tab `new`, keeping its search.
+    pub async fn rename(&self, old: &str, new: &str) {
+        let Some(evalbar) = self.state.evalbar_engines.get(old).map(|x| x.clone()) else {
+            return;
+        };
+        let mut evalbar_engine = evalbar.lock().await;
+        if self
+            .state
+            .evalbar_engines
+            .remove_if(old, |_, current| Arc::ptr_eq(current, &evalbar))
+            .is_some()
+        {
+            self.state
+                .evalbar_engines
+                .insert(new.to_string(), evalbar.clone());
+            evalbar_engine.process.tab = new.to_string();
+        }
+    }
+
+    /// Kill the eval bar engines of a tab.
+    pub async fn kill(&self, tab: &str) -> Result<(), Error> {
+        let keys: Vec<_> = self
+            .state
+            .evalbar_engines
+            .iter()
+            .map(|x| x.key().clone())
+            .filter(|key| key.starts_with(tab))
+            .collect();
+        for key in keys {
+            if let Some((_, evalbar)) = self.state.evalbar_engines.remove(&key) {
+                if let Err(e) = evalbar.lock().await.process.kill().await {
+                    warn!("Failed to kill the eval bar engine of tab {}: {}", key, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(depth: u32, multipv: u16) -> BestMoves {
+        BestMoves {
+            depth,
+            multipv,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn updates_are_throttled_but_the_end_of_a_search_always_goes_through() {
+        let mut feed = EvalBarFeed::new(Duration::from_millis(500));
+        let start = Instant::now();
+        feed.start();
+
+        assert_eq!(feed.line(line(1, 1), start).map(|x| x.depth), Some(1));
+        assert!(feed
+            .line(line(2, 1), start + Duration::from_millis(100))
+            .is_none());
+        assert!(feed
+            .line(line(3, 2), start + Duration::from_millis(600))
+            .is_none());
+        assert!(feed
+            .line(line(4, 1), start + Duration::from_millis(400))
+            .is_none());
+        assert_eq!(
+            feed.line(line(5, 1), start + Duration::from_millis(600))
+                .map(|x| x.depth),
+            Some(5)
+        );
+        assert!(feed
+            .line(line(6, 1), start + Duration::from_millis(700))
+            .is_none());
+        assert_eq!(feed.best_move().map(|x| x.depth), Some(6));
+        assert!(!feed.searching);
+    }
+
+    #[test]
+    fn output_of_abandoned_searches_is_dropped() {
+        let mut feed = EvalBarFeed::new(Duration::ZERO);
+        let now = Instant::now();
+        feed.start();
+        assert!(feed.line(line(8, 1), now).is_some());
+
+        // The position changes twice before the first search acknowledges its stop.
+        feed.start();
+        feed.start();
+        assert!(feed.is_stale());
+        assert!(feed.line(line(9, 1), now).is_none());
+        assert!(feed.best_move().is_none());
+        assert!(feed.best_move().is_none());
+        assert!(!feed.is_stale());
+
+        assert!(feed.line(line(3, 1), now).is_some());
+        assert_eq!(feed.best_move().map(|x| x.depth), Some(3));
+        // Stopping a finished search doesn't leave anything to drop.
+        feed.start();
+        assert!(!feed.is_stale());
+    }
+
+    #[test]
+    fn limits_keep_searches_shallow() {
+        assert_eq!(EvalBarLimits::default().go_mode(), GoMode::Depth(12));
+        assert_eq!(
+            EvalBarLimits { depth: Some(40) }.go_mode(),
+            GoMode::Depth(MAX_DEPTH)
+        );
+        assert_eq!(EvalBarLimits { depth: Some(0) }.go_mode(), GoMode::Depth(1));
+    }
+}
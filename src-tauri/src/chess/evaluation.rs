@@ -2,11 +2,19 @@
 //!
 //! This module provides a simple static evaluation and quiescence search for chess positions.
 //! Used for quick, engine-independent heuristics (e.g., sacrifice detection).
+//! It also detects the rules-based end of a game, so that engines aren't asked to evaluate
+//! positions that are already decided, and caps engine scores for computations and formats
+//! them for display.
 
-use shakmaty::{ByColor, Chess, Color, Position, Role};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Epd, ByColor, Chess, Color, EnPassantMode, Position, Role};
+use specta::Type;
+use vampirc_uci::uci::{Score, ScoreValue};
+
+use super::types::GameTermination;
 
 /// Return the material value for a given piece role.
-fn piece_value(role: Role) -> i32 {
+pub(crate) fn piece_value(role: Role) -> i32 {
     match role {
         Role::Pawn => 90,
         Role::Knight => 300,
@@ -94,6 +102,130 @@ pub fn naive_eval(pos: &Chess) -> i32 {
         .unwrap_or(i32::MIN)
 }
 
+/// Classify how the game is over in the last of `positions`, if it is.
+///
+/// `positions` is the game history from its starting position up to the current one, used to
+/// detect threefold repetition. Checkmate takes precedence over the fifty-move rule, since a
+/// mate delivered on the hundredth half-move still counts.
+///
+/// Insufficient material follows the rules: lone knights or same-colored bishops can't mate,
+/// but opposite-colored bishops or a knight against a minor piece can (with help).
+pub fn game_termination(positions: &[Chess]) -> Option<GameTermination> {
+    let pos = positions.last()?;
+
+    if pos.is_checkmate() {
+        return Some(GameTermination::Checkmate);
+    }
+    if pos.is_stalemate() {
+        return Some(GameTermination::Stalemate);
+    }
+    if pos.is_insufficient_material() {
+        return Some(GameTermination::InsufficientMaterial);
+    }
+
+    // Positions can only repeat since the last capture or pawn move.
+    let key = Epd::from_position(pos.clone(), EnPassantMode::Legal).to_string();
+    let repetitions = positions
+        .iter()
+        .rev()
+        .take(pos.halfmoves() as usize + 1)
+        .filter(|p| Epd::from_position((*p).clone(), EnPassantMode::Legal).to_string() == key)
+        .count();
+    if repetitions >= 3 {
+        return Some(GameTermination::ThreefoldRepetition);
+    }
+
+    if pos.halfmoves() >= 100 {
+        return Some(GameTermination::FiftyMoveRule);
+    }
+
+    None
+}
+
+/// Evaluations are capped at this many centipawns, and mates count as this much, so that
+/// mate scores don't dominate averages and differences.
+pub(crate) const EVAL_CAP: i32 = 1000;
+
+/// Convert a score to centipawns capped at `EVAL_CAP`, from the score's point of view.
+/// A mate counts as the cap, and `mate 0`, the side to move being mated, as minus the cap.
+pub(crate) fn score_to_cp(score: &Score) -> i32 {
+    match score.value {
+        ScoreValue::Cp(cp) => cp.clamp(-EVAL_CAP, EVAL_CAP),
+        ScoreValue::Mate(mate) if mate > 0 => EVAL_CAP,
+        ScoreValue::Mate(_) => -EVAL_CAP,
+    }
+}
+
+/// How engine scores are written for display.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ScoreStyle {
+    /// `+1.24`
+    #[default]
+    Pawns,
+    /// `+124`
+    Centipawns,
+}
+
+/// Largest evaluation displayed, in centipawns; anything beyond is shown as this.
+const DISPLAY_CAP: i32 = 10_000;
+
+/// Slope of the logistic curve mapping centipawns to expected points, as used by lichess.
+const WDL_SCALE: f64 = 0.00368208;
+
+/// Centipawn equivalent of a win/draw/loss estimate, through the expected points.
+fn wdl_to_cp(win: f64, draw: f64, loss: f64) -> i32 {
+    let total = win + draw + loss;
+    if total == 0.0 {
+        return 0;
+    }
+    let expected = (win + draw / 2.0) / total;
+    if expected <= 0.0 {
+        return -DISPLAY_CAP;
+    }
+    if expected >= 1.0 {
+        return DISPLAY_CAP;
+    }
+    ((expected / (1.0 - expected)).ln() / WDL_SCALE).round() as i32
+}
+
+/// Format an engine score from White's point of view, e.g. `+1.24`, `-0.50`, `#5` or `#-3`.
+///
+/// `score` is relative to `perspective`, the side to move when it was reported, as UCI engines
+/// send it. Mate 0 means that side is already mated and shows the result instead. Evaluations
+/// are capped at a hundred pawns, and engines reporting only WDL (leaving the value at `cp 0`)
+/// are displayed through the equivalent centipawns. The output never depends on the locale.
+pub fn format_score(score: &Score, perspective: Color, style: ScoreStyle) -> String {
+    let sign = if perspective == Color::White { 1 } else { -1 };
+    let cp = match score.value {
+        ScoreValue::Mate(0) => {
+            return match perspective {
+                Color::White => "0-1",
+                Color::Black => "1-0",
+            }
+            .to_string();
+        }
+        ScoreValue::Mate(mate) => return format!("#{}", sign * mate),
+        ScoreValue::Cp(0) => score
+            .wdl
+            .map(|(w, d, l)| wdl_to_cp(w as f64, d as f64, l as f64))
+            .unwrap_or(0),
+        ScoreValue::Cp(cp) => cp,
+    };
+
+    let cp = (sign * cp).clamp(-DISPLAY_CAP, DISPLAY_CAP);
+    let prefix = match cp.signum() {
+        1 => "+",
+        -1 => "-",
+        _ => "",
+    };
+    let cp = cp.abs();
+    match style {
+        ScoreStyle::Pawns => format!("{}{}.{:02}", prefix, cp / 100, cp % 100),
+        ScoreStyle::Centipawns => format!("{}{}", prefix, cp),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,6 +236,21 @@ fn pos(fen: &str) -> Chess {
         Chess::from_setup(fen.into_setup(), CastlingMode::Chess960).unwrap()
     }
 
+    #[test]
+    fn scores_are_capped_and_mates_count_as_the_cap() {
+        let cp = |value| {
+            score_to_cp(&Score {
+                value,
+                ..Default::default()
+            })
+        };
+        assert_eq!(cp(ScoreValue::Cp(35)), 35);
+        assert_eq!(cp(ScoreValue::Cp(-2500)), -EVAL_CAP);
+        assert_eq!(cp(ScoreValue::Mate(3)), EVAL_CAP);
+        assert_eq!(cp(ScoreValue::Mate(-3)), -EVAL_CAP);
+        assert_eq!(cp(ScoreValue::Mate(0)), -EVAL_CAP);
+    }
+
     #[test]
     fn eval_start_pos() {
         assert_eq!(naive_eval(&Chess::default()), 0);
@@ -156,4 +303,173 @@ fn eval_opera_game2() {
         let position = pos("4kb1r/p2rqppp/5n2/1B2p1B1/4P3/1Q6/PPP2PPP/2KR4 b k - 1 14");
         assert_eq!(naive_eval(&position), 0);
     }
+
+    fn termination(fen: &str) -> Option<GameTermination> {
+        game_termination(&[pos(fen)])
+    }
+
+    #[test]
+    fn termination_checkmate() {
+        assert_eq!(
+            termination("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3"),
+            Some(GameTermination::Checkmate)
+        );
+    }
+
+    #[test]
+    fn termination_stalemate() {
+        assert_eq!(
+            termination("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"),
+            Some(GameTermination::Stalemate)
+        );
+    }
+
+    #[test]
+    fn termination_insufficient_material() {
+        let insufficient = Some(GameTermination::InsufficientMaterial);
+        // Bare kings
+        assert_eq!(termination("4k3/8/8/8/8/8/8/4K3 w - - 0 1"), insufficient);
+        // Lone minor piece
+        assert_eq!(termination("4k3/8/8/8/8/8/8/1N2K3 w - - 0 1"), insufficient);
+        assert_eq!(termination("4k3/8/8/8/8/8/8/2B1K3 w - - 0 1"), insufficient);
+        // Bishops on the same color
+        assert_eq!(
+            termination("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1"),
+            insufficient
+        );
+    }
+
+    #[test]
+    fn termination_sufficient_material() {
+        // Bishops on opposite colors can still mate
+        assert_eq!(termination("2b1k3/8/8/8/8/8/8/2B1K3 w - - 0 1"), None);
+        // Two knights can mate with help
+        assert_eq!(termination("4k3/8/8/8/8/8/8/1N2K1N1 w - - 0 1"), None);
+        // A knight can mate a king with a knight blocking its escape
+        assert_eq!(termination("1n2k3/8/8/8/8/8/8/1N2K3 w - - 0 1"), None);
+        assert_eq!(termination("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"), None);
+    }
+
+    #[test]
+    fn termination_fifty_move_rule() {
+        assert_eq!(
+            termination("4k3/8/8/8/8/8/8/R3K3 w - - 100 80"),
+            Some(GameTermination::FiftyMoveRule)
+        );
+        assert_eq!(termination("4k3/8/8/8/8/8/8/R3K3 w - - 99 80"), None);
+    }
+
+    #[test]
+    fn termination_threefold_repetition() {
+        use shakmaty::uci::UciMove;
+
+        let mut positions = vec![Chess::default()];
+        for m in ["g1f3", "g8f6", "f3g1", "f6g8"].iter().cycle().take(8) {
+            let mut next = positions.last().unwrap().clone();
+            let mv = UciMove::from_ascii(m.as_bytes())
+                .unwrap()
+                .to_move(&next)
+                .unwrap();
+            next.play_unchecked(&mv);
+            positions.push(next);
+        }
+
+        assert_eq!(game_termination(&positions[..5]), None);
+        assert_eq!(
+            game_termination(&positions),
+            Some(GameTermination::ThreefoldRepetition)
+        );
+    }
+
+    fn score(value: ScoreValue) -> Score {
+        Score {
+            value,
+            ..Default::default()
+        }
+    }
+
+    fn pawns(value: ScoreValue, perspective: Color) -> String {
+        format_score(&score(value), perspective, ScoreStyle::Pawns)
+    }
+
+    #[test]
+    fn scores_in_pawns_and_centipawns() {
+        assert_eq!(pawns(ScoreValue::Cp(124), Color::White), "+1.24");
+        assert_eq!(pawns(ScoreValue::Cp(-5), Color::White), "-0.05");
+        assert_eq!(pawns(ScoreValue::Cp(124), Color::Black), "-1.24");
+        assert_eq!(pawns(ScoreValue::Cp(0), Color::White), "0.00");
+        assert_eq!(pawns(ScoreValue::Cp(0), Color::Black), "0.00");
+
+        let centipawns = |cp| {
+            format_score(
+                &score(ScoreValue::Cp(cp)),
+                Color::White,
+                ScoreStyle::Centipawns,
+            )
+        };
+        assert_eq!(centipawns(124), "+124");
+        assert_eq!(centipawns(-80), "-80");
+        assert_eq!(centipawns(0), "0");
+    }
+
+    #[test]
+    fn huge_scores_are_clamped() {
+        assert_eq!(pawns(ScoreValue::Cp(15_000), Color::White), "+100.00");
+        assert_eq!(pawns(ScoreValue::Cp(15_000), Color::Black), "-100.00");
+        assert_eq!(
+            format_score(
+                &score(ScoreValue::Cp(-32_000)),
+                Color::White,
+                ScoreStyle::Centipawns
+            ),
+            "-10000"
+        );
+    }
+
+    #[test]
+    fn mates_are_from_whites_point_of_view() {
+        assert_eq!(pawns(ScoreValue::Mate(5), Color::White), "#5");
+        assert_eq!(pawns(ScoreValue::Mate(-3), Color::White), "#-3");
+        // Black to move mates in three.
+        assert_eq!(pawns(ScoreValue::Mate(3), Color::Black), "#-3");
+        assert_eq!(pawns(ScoreValue::Mate(-2), Color::Black), "#2");
+    }
+
+    #[test]
+    fn mate_in_zero_shows_the_result() {
+        assert_eq!(pawns(ScoreValue::Mate(0), Color::White), "0-1");
+        assert_eq!(pawns(ScoreValue::Mate(0), Color::Black), "1-0");
+    }
+
+    #[test]
+    fn wdl_only_scores_use_expected_points() {
+        let wdl = |value, wdl, perspective| {
+            let mut score = score(value);
+            score.wdl = Some(wdl);
+            format_score(&score, perspective, ScoreStyle::Pawns)
+        };
+        assert_eq!(
+            wdl(ScoreValue::Cp(0), (500, 400, 100), Color::White),
+            "+2.30"
+        );
+        assert_eq!(
+            wdl(ScoreValue::Cp(0), (500, 400, 100), Color::Black),
+            "-2.30"
+        );
+        assert_eq!(
+            wdl(ScoreValue::Cp(0), (100, 800, 100), Color::White),
+            "0.00"
+        );
+        assert_eq!(
+            wdl(ScoreValue::Cp(0), (1000, 0, 0), Color::White),
+            "+100.00"
+        );
+        assert_eq!(
+            wdl(ScoreValue::Cp(0), (0, 0, 1000), Color::White),
+            "-100.00"
+        );
+        assert_eq!(wdl(ScoreValue::Cp(0), (0, 0, 0), Color::White), "0.00");
+        // A reported value wins over the WDL.
+        assert_eq!(wdl(ScoreValue::Cp(40), (0, 0, 1000), Color::White), "+0.40");
+    }
 }
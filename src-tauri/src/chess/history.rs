@@ -0,0 +1,437 @@
+//! Recently visited positions.
+//!
+//! Whenever the frontend settles on a position (debounced on its side), it records a visit
+//! with the tab it was shown in and the game it comes from, if any. Visits are stored in a
+//! SQLite database in the app data directory, newest last. Consecutive visits of the same
+//! position are merged and only the most recent `MAX_HISTORY_ENTRIES` are kept, so the
+//! history stays small. Positions can be found again by a fragment of their FEN or by the
+//! name of their opening, which is resolved when the visit is recorded.
+//!
+//! Recording can be disabled, which also wipes the history.
+
+use std::fs::create_dir_all;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
+};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, CastlingMode, Chess};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+use crate::opening::get_opening_from_fen;
+
+const POSITION_HISTORY_SQL: &str = include_str!("../../../database/schema/position_history.sql");
+
+/// Position history database, relative to the app data directory.
+const POSITION_HISTORY_FILE: &str = "position_history.db3";
+
+/// Visits kept, the oldest ones are pruned beyond this.
+pub const MAX_HISTORY_ENTRIES: u32 = 5000;
+
+const VISIT_COLUMNS: &str = "ID, VisitedAt, Fen, Tab, GameFile, GameID, Opening";
+
+/// Where a position was looked at.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct VisitContext {
+    /// Tab the position was shown in.
+    pub tab: String,
+    /// Database the game comes from, if the position is part of a database game.
+    pub game_file: Option<String>,
+    pub game_id: Option<i32>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, QueryableByName, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionVisit {
+    #[diesel(sql_type = BigInt, column_name = "ID")]
+    pub id: i64,
+    /// Unix timestamp, in seconds.
+    #[diesel(sql_type = BigInt, column_name = "VisitedAt")]
+    pub visited_at: i64,
+    #[diesel(sql_type = Text, column_name = "Fen")]
+    pub fen: String,
+    #[diesel(sql_type = Text, column_name = "Tab")]
+    pub tab: String,
+    #[diesel(sql_type = Nullable<Text>, column_name = "GameFile")]
+    pub game_file: Option<String>,
+    #[diesel(sql_type = Nullable<Integer>, column_name = "GameID")]
+    pub game_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>, column_name = "Opening")]
+    pub opening: Option<String>,
+}
+
+/// Restricts the listed visits. Unset fields match everything.
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionHistoryFilter {
+    pub tab: Option<String>,
+    pub game_file: Option<String>,
+    /// Unix timestamp, in seconds, of the oldest visit to list.
+    pub since: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct StoredSetting {
+    #[diesel(sql_type = Bool, column_name = "Enabled")]
+    enabled: bool,
+}
+
+/// Open the position history, creating it on first use.
+pub fn open_position_history(app: &tauri::AppHandle) -> Result<SqliteConnection, Error> {
+    let path = app
+        .path()
+        .resolve(POSITION_HISTORY_FILE, BaseDirectory::AppData)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut db = SqliteConnection::establish(&path.to_string_lossy())?;
+    db.batch_execute(POSITION_HISTORY_SQL)?;
+    Ok(db)
+}
+
+/// Whether visits are recorded, which they are unless disabled.
+pub fn history_enabled(db: &mut SqliteConnection) -> Result<bool, Error> {
+    Ok(
+        sql_query("SELECT Enabled FROM PositionHistorySettings WHERE ID = 1")
+            .get_result::<StoredSetting>(db)
+            .optional()?
+            .map(|setting| setting.enabled)
+            .unwrap_or(true),
+    )
+}
+
+/// Enable or disable recording. Disabling also removes every recorded visit.
+pub fn set_history_enabled(db: &mut SqliteConnection, enabled: bool) -> Result<(), Error> {
+    db.transaction::<_, Error, _>(|db| {
+        sql_query("INSERT OR REPLACE INTO PositionHistorySettings (ID, Enabled) VALUES (1, ?)")
+            .bind::<Bool, _>(enabled)
+            .execute(db)?;
+        if !enabled {
+            sql_query("DELETE FROM PositionHistory").execute(db)?;
+        }
+        Ok(())
+    })
+}
+
+/// Record a visit of `fen` at `now`, keeping the `max_entries` most recent visits.
+///
+/// A visit of the position visited last only moves that visit to the new context and time.
+/// Returns whether the visit was recorded, which it isn't while recording is disabled.
+pub fn record_visit(
+    db: &mut SqliteConnection,
+    fen: &str,
+    context: &VisitContext,
+    now: i64,
+    max_entries: u32,
+) -> Result<bool, Error> {
+    if !history_enabled(db)? {
+        return Ok(false);
+    }
+    let fen = fen.trim();
+    Fen::from_ascii(fen.as_bytes())?.into_position::<Chess>(CastlingMode::Chess960)?;
+
+    db.transaction::<_, Error, _>(|db| {
+        let last: Option<PositionVisit> = sql_query(format!(
+            "SELECT {} FROM PositionHistory ORDER BY ID DESC LIMIT 1",
+            VISIT_COLUMNS
+        ))
+        .get_result(db)
+        .optional()?;
+        if let Some(last) = last.filter(|last| last.fen == fen) {
+            sql_query(
+                "UPDATE PositionHistory SET VisitedAt = ?, Tab = ?, GameFile = ?, GameID = ? \
+                 WHERE ID = ?",
+            )
+            .bind::<BigInt, _>(now)
+            .bind::<Text, _>(&context.tab)
+            .bind::<Nullable<Text>, _>(&context.game_file)
+            .bind::<Nullable<Integer>, _>(context.game_id)
+            .bind::<BigInt, _>(last.id)
+            .execute(db)?;
+            return Ok(());
+        }
+
+        sql_query(
+            "INSERT INTO PositionHistory (VisitedAt, Fen, Tab, GameFile, GameID, Opening) \
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind::<BigInt, _>(now)
+        .bind::<Text, _>(fen)
+        .bind::<Text, _>(&context.tab)
+        .bind::<Nullable<Text>, _>(&context.game_file)
+        .bind::<Nullable<Integer>, _>(context.game_id)
+        .bind::<Nullable<Text>, _>(get_opening_from_fen(fen).ok())
+        .execute(db)?;
+        sql_query(
+            "DELETE FROM PositionHistory WHERE ID <= \
+             (SELECT ID FROM PositionHistory ORDER BY ID DESC LIMIT 1 OFFSET ?)",
+        )
+        .bind::<BigInt, _>(max_entries as i64)
+        .execute(db)?;
+        Ok(())
+    })?;
+    Ok(true)
+}
+
+/// The `limit` most recent visits matching `filter`, newest first.
+pub fn recent_visits(
+    db: &mut SqliteConnection,
+    limit: u32,
+    filter: &PositionHistoryFilter,
+) -> Result<Vec<PositionVisit>, Error> {
+    Ok(sql_query(format!(
+        "SELECT {} FROM PositionHistory \
+         WHERE (? IS NULL OR Tab = ?) AND (? IS NULL OR GameFile = ?) \
+         AND (? IS NULL OR VisitedAt >= ?) \
+         ORDER BY ID DESC LIMIT ?",
+        VISIT_COLUMNS
+    ))
+    .bind::<Nullable<Text>, _>(&filter.tab)
+    .bind::<Nullable<Text>, _>(&filter.tab)
+    .bind::<Nullable<Text>, _>(&filter.game_file)
+    .bind::<Nullable<Text>, _>(&filter.game_file)
+    .bind::<Nullable<BigInt>, _>(filter.since)
+    .bind::<Nullable<BigInt>, _>(filter.since)
+    .bind::<BigInt, _>(limit as i64)
+    .load(db)?)
+}
+
+/// The last visit of each position whose FEN contains `query` or whose opening name does,
+/// ignoring case for the name only, newest first.
+pub fn search_visits(
+    db: &mut SqliteConnection,
+    query: &str,
+    limit: u32,
+) -> Result<Vec<PositionVisit>, Error> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let name_pattern = format!(
+        "%{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    Ok(sql_query(format!(
+        "SELECT {} FROM PositionHistory WHERE ID IN \
+         (SELECT MAX(ID) FROM PositionHistory \
+          WHERE instr(Fen, ?) > 0 OR Opening LIKE ? ESCAPE '\\' GROUP BY Fen) \
+         ORDER BY ID DESC LIMIT ?",
+        VISIT_COLUMNS
+    ))
+    .bind::<Text, _>(query)
+    .bind::<Text, _>(name_pattern)
+    .bind::<BigInt, _>(limit as i64)
+    .load(db)?)
+}
+
+/// Record that the user settled on `fen`. Returns whether it was recorded.
+#[tauri::command]
+#[specta::specta]
+pub async fn record_position_visit(
+    fen: String,
+    context: VisitContext,
+    app: tauri::AppHandle,
+) -> Result<bool, Error> {
+    let mut db = open_position_history(&app)?;
+    record_visit(
+        &mut db,
+        &fen,
+        &context,
+        chrono::Utc::now().timestamp(),
+        MAX_HISTORY_ENTRIES,
+    )
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_position_history(
+    limit: u32,
+    filter: Option<PositionHistoryFilter>,
+    app: tauri::AppHandle,
+) -> Result<Vec<PositionVisit>, Error> {
+    let mut db = open_position_history(&app)?;
+    recent_visits(&mut db, limit, &filter.unwrap_or_default())
+}
+
+/// Find visited positions by a part of their FEN or the name of their opening.
+#[tauri::command]
+#[specta::specta]
+pub async fn search_position_history(
+    query: String,
+    limit: u32,
+    app: tauri::AppHandle,
+) -> Result<Vec<PositionVisit>, Error> {
+    let mut db = open_position_history(&app)?;
+    search_visits(&mut db, &query, limit)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_position_history_enabled(app: tauri::AppHandle) -> Result<bool, Error> {
+    let mut db = open_position_history(&app)?;
+    history_enabled(&mut db)
+}
+
+/// Enable or disable the position history. Disabling it wipes the recorded visits.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_position_history_enabled(
+    enabled: bool,
+    app: tauri::AppHandle,
+) -> Result<(), Error> {
+    let mut db = open_position_history(&app)?;
+    set_history_enabled(&mut db, enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory_db;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    const E4: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+    const RUY_LOPEZ: &str = "r1bqkbnr/pppp1ppp/2n5/1B2p3/4P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3";
+
+    fn history_db() -> SqliteConnection {
+        memory_db(POSITION_HISTORY_SQL)
+    }
+
+    fn tab(tab: &str) -> VisitContext {
+        VisitContext {
+            tab: tab.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn visit(db: &mut SqliteConnection, fen: &str, context: &VisitContext, now: i64) {
+        assert!(record_visit(db, fen, context, now, MAX_HISTORY_ENTRIES).unwrap());
+    }
+
+    fn fens(visits: &[PositionVisit]) -> Vec<&str> {
+        visits.iter().map(|v| v.fen.as_str()).collect()
+    }
+
+    #[test]
+    fn consecutive_visits_of_a_position_are_merged() {
+        let mut db = history_db();
+        visit(&mut db, START, &tab("analysis"), 10);
+        visit(&mut db, START, &tab("analysis"), 11);
+        visit(&mut db, E4, &tab("analysis"), 12);
+        let game = VisitContext {
+            tab: "game".to_string(),
+            game_file: Some("/db/games.db3".to_string()),
+            game_id: Some(7),
+        };
+        visit(&mut db, START, &game, 13);
+        visit(&mut db, START, &game, 14);
+
+        let visits = recent_visits(&mut db, 10, &PositionHistoryFilter::default()).unwrap();
+        assert_eq!(fens(&visits), vec![START, E4, START]);
+        assert_eq!(visits[0].visited_at, 14);
+        assert_eq!(visits[0].game_id, Some(7));
+
+        let filtered = recent_visits(
+            &mut db,
+            10,
+            &PositionHistoryFilter {
+                tab: Some("analysis".to_string()),
+                since: Some(11),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(fens(&filtered), vec![E4, START]);
+        assert_eq!(
+            recent_visits(&mut db, 1, &PositionHistoryFilter::default())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn oldest_visits_are_pruned() {
+        let mut db = history_db();
+        for i in 0..5 {
+            let fen = if i % 2 == 0 { START } else { E4 };
+            assert!(record_visit(&mut db, fen, &tab(&i.to_string()), i, 3).unwrap());
+        }
+        let visits = recent_visits(&mut db, 10, &PositionHistoryFilter::default()).unwrap();
+        assert_eq!(
+            visits.iter().map(|v| v.tab.as_str()).collect::<Vec<_>>(),
+            vec!["4", "3", "2"]
+        );
+    }
+
+    #[test]
+    fn invalid_positions_are_rejected() {
+        let mut db = history_db();
+        assert!(record_visit(&mut db, "not a fen", &tab("a"), 0, 10).is_err());
+        assert!(
+            recent_visits(&mut db, 10, &PositionHistoryFilter::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn positions_are_found_by_fen_or_opening() {
+        let mut db = history_db();
+        visit(&mut db, RUY_LOPEZ, &tab("a"), 1);
+        visit(&mut db, E4, &tab("a"), 2);
+        visit(&mut db, RUY_LOPEZ, &tab("b"), 3);
+
+        let found = search_visits(&mut db, "ruy lopez", 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].tab, "b");
+        assert!(found[0]
+            .opening
+            .as_deref()
+            .is_some_and(|name| name.starts_with("Ruy Lopez")));
+
+        // FENs are matched with case, since it tells the colors apart.
+        assert_eq!(
+            fens(&search_visits(&mut db, "4P3", 10).unwrap()),
+            vec![RUY_LOPEZ, E4]
+        );
+        assert_eq!(
+            fens(&search_visits(&mut db, "1B2p3", 10).unwrap()),
+            vec![RUY_LOPEZ]
+        );
+        assert!(search_visits(&mut db, "1b2P3", 10).unwrap().is_empty());
+        assert!(search_visits(&mut db, "100%", 10).unwrap().is_empty());
+        assert!(search_visits(&mut db, " ", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn disabling_wipes_and_stops_recording() {
+        let mut db = history_db();
+        assert!(history_enabled(&mut db).unwrap());
+        visit(&mut db, START, &tab("a"), 1);
+
+        set_history_enabled(&mut db, false).unwrap();
+        assert!(!history_enabled(&mut db).unwrap());
+        assert!(!record_visit(&mut db, E4, &tab("a"), 2, MAX_HISTORY_ENTRIES).unwrap());
+        assert!(
+            recent_visits(&mut db, 10, &PositionHistoryFilter::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        set_history_enabled(&mut db, true).unwrap();
+        visit(&mut db, E4, &tab("a"), 3);
+        assert_eq!(
+            fens(&recent_visits(&mut db, 10, &PositionHistoryFilter::default()).unwrap()),
+            vec![E4]
+        );
+    }
+}
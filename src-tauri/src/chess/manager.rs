@@ -3,18 +3,30 @@
 //! This module provides the `EngineManager` struct, which manages engine processes, handles best-move queries,
 //! and spawns background tasks for engine output parsing and progress reporting.
 
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use dashmap::DashMap;
+use log::{debug, info, warn};
+use tauri::Manager;
 use tauri_specta::Event;
 use tokio::sync::Mutex;
+use vampirc_uci::{uci::ScoreValue, UciInfoAttribute};
 
 use crate::error::Error;
 use crate::AppState;
 
+use super::cache::{cached_analysis, record_analysis};
+use super::pin::apply_pinned_line;
+use super::pool::QueuedSearch;
 use super::process::EngineProcess;
-use super::types::{EngineLog, EngineOptions, GoMode};
+use super::tab_policy::{AnalysisSnapshot, TabEngineScheduler};
+use super::types::{
+    AnalysisCompletion, AnalysisStatistics, BestMoves, BestMovesPayload, EngineCapabilityWarning,
+    EngineOptions, GoMode,
+};
 
 /// Manager for UCI engine processes, handling best-move queries and process lifecycle.
 pub struct EngineManager<'a> {
@@ -30,14 +42,16 @@ pub fn new(state: tauri::State<'a, AppState>) -> Self {
     /// Get best moves from the engine for a given position and options.
     ///
     /// If an engine process is already running for the given key, it will reuse or update it as needed.
-    /// Otherwise, it spawns a new process and background reader task.
+    /// Otherwise, it spawns a new process and background reader task. Once as many engines
+    /// are searching as the concurrency limit allows, the search is queued instead.
     ///
     /// # Arguments
     /// * `id` - Unique analysis session identifier.
-    /// * `engine` - Path to the UCI engine binary.
+    /// * `engine` - Path to the UCI engine binary, or `BUILTIN_ENGINE`.
     /// * `tab` - Tab identifier for engine process grouping.
     /// * `go_mode` - Engine search mode (depth, time, etc).
     /// * `options` - Engine options (FEN, moves, etc).
+    /// * `force_refresh` - Search even if the analysis cache holds a deep enough search.
     /// * `app` - Tauri app handle for event emission.
     ///
     /// # Returns
@@ -52,13 +66,73 @@ pub async fn get_best_moves(
         tab: String,
         go_mode: GoMode,
         options: EngineOptions,
+        force_refresh: bool,
         app: tauri::AppHandle,
     ) -> Result<Option<(f32, Vec<super::types::BestMoves>)>, Error> {
         let path = PathBuf::from(&engine);
         let key = (tab.clone(), engine.clone());
 
+        if !force_refresh {
+            if let Some(lines) = cached_analysis(&app, &engine, &options, &go_mode) {
+                // The engine would otherwise keep reporting on the tab's previous position.
+                let existing = self.state.engine_processes.get(&key).map(|p| p.clone());
+                if let Some(process_arc) = existing {
+                    let mut process = process_arc.lock().await;
+                    if process.is_pondering() {
+                        process.stop_pondering().await?;
+                    } else if process.running {
+                        process.stop().await?;
+                    }
+                }
+                BestMovesPayload {
+                    best_lines: lines.clone(),
+                    engine: id,
+                    tab,
+                    fen: options.fen,
+                    moves: options.moves,
+                    progress: 100.0,
+                    completion: Some(AnalysisCompletion::TargetReached),
+                    statistics: None,
+                    partial: false,
+                    ponder: None,
+                }
+                .emit(&app)?;
+                return Ok(Some((100.0, lines)));
+            }
+        }
+
+        TabEngineScheduler::new(self.state.clone()).record(
+            &app,
+            &tab,
+            &AnalysisSnapshot {
+                id: id.clone(),
+                engine: engine.clone(),
+                go_mode: go_mode.clone(),
+                options: options.clone(),
+            },
+        );
+
+        // Searches past the concurrency limit wait for another to finish.
+        let pool = &self.state.engine_pool;
+        let _admission = pool.admission.lock().await;
+        if pool
+            .must_wait(&self.state.engine_processes, &key, &options, &go_mode)
+            .await
+        {
+            info!("Queued engine search: tab={} engine={}", tab, engine);
+            pool.enqueue(QueuedSearch {
+                id,
+                engine,
+                tab,
+                go_mode,
+                options,
+            });
+            return Ok(None);
+        }
+
         // If an engine process already exists for this key, reuse or update it.
-        if let Some(process_arc) = self.state.engine_processes.get(&key) {
+        let existing = self.state.engine_processes.get(&key).map(|p| p.clone());
+        if let Some(process_arc) = existing {
             let mut process = process_arc.lock().await;
 
             // If options and mode match and engine is running, return cached result.
@@ -69,8 +143,17 @@ pub async fn get_best_moves(
                 )));
             }
 
-            // Otherwise, stop and reconfigure the engine.
-            process.stop().await?;
+            if process.is_pondering() {
+                // The move the engine pondered on was played: its search goes on.
+                if options == process.options && is_ponder_hit(&go_mode) {
+                    process.ponderhit(&go_mode).await?;
+                    return Ok(None);
+                }
+                process.stop_pondering().await?;
+            } else {
+                // Otherwise, stop and reconfigure the engine.
+                process.stop().await?;
+            }
 
             // Wait for stop to complete (engine should respond quickly)
             // This is more reliable than a fixed sleep
@@ -78,8 +161,10 @@ pub async fn get_best_moves(
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
 
             // Re-acquire lock and reconfigure
-            if let Some(process_arc) = self.state.engine_processes.get(&key) {
+            let existing = self.state.engine_processes.get(&key).map(|p| p.clone());
+            if let Some(process_arc) = existing {
                 let mut process = process_arc.lock().await;
+                self.attach_recorder(&key, &id, &mut process);
                 process.set_options(options.clone()).await?;
                 process.go(&go_mode).await?;
                 return Ok(None);
@@ -89,7 +174,9 @@ pub async fn get_best_moves(
             }
         }
 
-        let (mut process, mut reader) = EngineProcess::new(path).await?;
+        let (mut process, mut reader) = EngineProcess::new(path, &self.state.path_scope).await?;
+        process.tab = tab.clone();
+        self.attach_recorder(&key, &id, &mut process);
         process.set_options(options.clone()).await?;
         process.go(&go_mode).await?;
 
@@ -99,123 +186,1012 @@ pub async fn get_best_moves(
             .insert(key.clone(), process.clone());
 
         // Spawn background reader task so multiple engines can run concurrently.
-        let app_cloned = app.clone();
-        let id_cloned = id.clone();
-        let tab_cloned = tab.clone();
         let key_cloned = key.clone();
-        let engines_map = self.state.engine_processes.clone();
+        let state_app = app.clone();
+        let mut handler = AnalysisHandler::new(
+            id,
+            tab,
+            engine,
+            HandlerClock::Wall(Instant::now()),
+            AppSink {
+                app,
+                key: key.clone(),
+            },
+        );
         tokio::spawn(async move {
+            let engines_map = &state_app.state::<AppState>().inner().engine_processes;
+            let pool = &state_app.state::<AppState>().inner().engine_pool;
             info!(
                 "Engine loop started: tab={} engine={}",
                 key_cloned.0, key_cloned.1
             );
-            // Limit event emission rate to avoid UI flooding.
-            let lim = governor::RateLimiter::direct(governor::Quota::per_second(
-                nonzero_ext::nonzero!(5u32),
-            ));
-            while let Ok(Some(line)) = reader.next_line().await {
+            let exit = loop {
+                let line = match reader.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break EngineExit::Closed,
+                    Err(e) => break EngineExit::ReadFailed(e.to_string()),
+                };
                 debug!(
                     "[engine-stdout tab={} engine={}] {}",
                     key_cloned.0, key_cloned.1, line
                 );
-                if let Some(proc_arc) = engines_map.get(&key_cloned) {
-                    let mut proc = proc_arc.lock().await;
-                    match vampirc_uci::parse_one(&line) {
-                        vampirc_uci::UciMessage::Info(attrs) => {
-                            // Parse FEN safely without unwrap
-                            match proc.options.fen.parse() {
-                                Ok(fen) => {
-                                    if let Ok(best_moves) = super::process::parse_uci_attrs(
-                                        attrs,
-                                        &fen,
-                                        &proc.options.moves,
-                                    ) {
-                                        let multipv = best_moves.multipv;
-                                        let cur_depth = best_moves.depth;
-                                        let cur_nodes = best_moves.nodes;
-                                        if multipv as usize == proc.best_moves.len() + 1 {
-                                            proc.best_moves.push(best_moves);
-                                            if multipv == proc.real_multipv {
-                                                // Only emit if all lines are at the same depth and rate limit allows.
-                                                if proc
-                                                    .best_moves
-                                                    .iter()
-                                                    .all(|x| x.depth == cur_depth)
-                                                    && cur_depth >= proc.last_depth
-                                                    && lim.check().is_ok()
-                                                {
-                                                    let progress = match proc.go_mode {
-                                                        GoMode::Depth(depth) => {
-                                                            (cur_depth as f64 / depth as f64)
-                                                                * 100.0
-                                                        }
-                                                        GoMode::Time(time) => {
-                                                            (proc.start.elapsed().as_millis()
-                                                                as f64
-                                                                / time as f64)
-                                                                * 100.0
-                                                        }
-                                                        GoMode::Nodes(nodes) => {
-                                                            (cur_nodes as f64 / nodes as f64)
-                                                                * 100.0
-                                                        }
-                                                        GoMode::PlayersTime(_) => 99.99,
-                                                        GoMode::Infinite => 99.99,
-                                                    };
-                                                    super::types::BestMovesPayload {
-                                                        best_lines: proc.best_moves.clone(),
-                                                        engine: id_cloned.clone(),
-                                                        tab: tab_cloned.clone(),
-                                                        fen: proc.options.fen.clone(),
-                                                        moves: proc.options.moves.clone(),
-                                                        progress,
-                                                    }
-                                                    .emit(&app_cloned)
-                                                    .ok();
-                                                    proc.last_depth = cur_depth;
-                                                    proc.last_best_moves = proc.best_moves.clone();
-                                                    proc.last_progress = progress as f32;
-                                                }
-                                                proc.best_moves.clear();
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    log::error!(
-                                        "Failed to parse FEN in engine output: {} - FEN: {}",
-                                        e,
-                                        proc.options.fen
-                                    );
-                                }
-                            }
-                        }
-                        vampirc_uci::UciMessage::BestMove { .. } => {
-                            // Emit final result when engine signals best move.
-                            super::types::BestMovesPayload {
-                                best_lines: proc.last_best_moves.clone(),
-                                engine: id_cloned.clone(),
-                                tab: tab_cloned.clone(),
-                                fen: proc.options.fen.clone(),
-                                moves: proc.options.moves.clone(),
-                                progress: 100.0,
-                            }
-                            .emit(&app_cloned)
-                            .ok();
-                            proc.last_progress = 100.0;
-                        }
-                        _ => {}
-                    }
-                    proc.logs.push(EngineLog::Engine(line));
+                let answered = line.starts_with("bestmove");
+                handle_engine_line(engines_map, &process, &mut handler, line).await;
+                // A finished search frees a slot for a queued one.
+                if answered {
+                    pool.wake();
                 }
-            }
+            };
             info!(
                 "Engine process finished: tab: {}, engine: {}",
                 key_cloned.0, key_cloned.1
             );
-            engines_map.remove(&key_cloned);
+
+            let mut proc = process.lock().await;
+            if let Some(recorder) = &proc.recorder {
+                if let Ok(mut recorder) = recorder.lock() {
+                    recorder.closed(&exit);
+                }
+            }
+            handler.set_tab(&proc.tab);
+            handler.handle_exit(&mut proc, &exit);
+            let key = (proc.tab.clone(), key_cloned.1);
+            drop(proc);
+            // The slot may hold a newer engine by now.
+            engines_map.remove_if(&key, |_, current| Arc::ptr_eq(current, &process));
+            pool.wake();
         });
 
         Ok(None)
     }
+
+    /// Have the running recording of `key`, if any, record the conversation with `process`.
+    fn attach_recorder(&self, key: &(String, String), id: &str, process: &mut EngineProcess) {
+        process.recorder = self
+            .state
+            .uci_recordings
+            .get(key)
+            .map(|recorder| recorder.clone());
+        if let Some(recorder) = &process.recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.set_analysis(id);
+            }
+        }
+    }
+}
+
+/// Whether a search under `go_mode` can take over a ponder search of the same position.
+fn is_ponder_hit(go_mode: &GoMode) -> bool {
+    matches!(go_mode, GoMode::PlayersTime(time) if !time.ponder)
+}
+
+/// Handle a line read from the engine of `process`, unless the process was removed from
+/// `engines` in the meantime.
+///
+/// The process stays locked while the line is handled, so a tab renamed in between can't
+/// have payloads sent under its old id.
+async fn handle_engine_line<S: AnalysisSink>(
+    engines: &DashMap<(String, String), Arc<Mutex<EngineProcess>>>,
+    process: &Arc<Mutex<EngineProcess>>,
+    handler: &mut AnalysisHandler<S>,
+    line: String,
+) {
+    let mut proc = process.lock().await;
+    if !engines.contains_key(&(proc.tab.clone(), handler.engine.clone())) {
+        return;
+    }
+    handler.set_tab(&proc.tab);
+    handler.handle_line(&mut proc, line);
+}
+
+/// Move the engines of tab `old` to tab `new` without interrupting their searches.
+///
+/// Each process is locked while it changes keys, so its reader can't handle a line in
+/// between, and everything it sends afterwards goes under the new tab.
+pub(crate) async fn rekey_processes(
+    engines: &DashMap<(String, String), Arc<Mutex<EngineProcess>>>,
+    old: &str,
+    new: &str,
+) {
+    let keys: Vec<_> = engines
+        .iter()
+        .filter(|entry| entry.key().0 == old)
+        .map(|entry| entry.key().clone())
+        .collect();
+    for key in keys {
+        let Some(process) = engines.get(&key).map(|p| p.clone()) else {
+            continue;
+        };
+        let mut proc = process.lock().await;
+        if engines
+            .remove_if(&key, |_, current| Arc::ptr_eq(current, &process))
+            .is_some()
+        {
+            engines.insert((new.to_string(), key.1), process.clone());
+            proc.tab = new.to_string();
+        }
+    }
+}
+
+/// Where an `AnalysisHandler` sends what it makes of engine output.
+pub trait AnalysisSink {
+    fn best_moves(&mut self, payload: BestMovesPayload);
+
+    fn capability_warning(&mut self, warning: EngineCapabilityWarning);
+
+    /// Follow the tab of the analysis to its new id.
+    fn set_tab(&mut self, _tab: &str) {}
+
+    /// Merge the pinned line of the tab into lines about to be sent.
+    fn pin_lines(&mut self, _options: &EngineOptions, _lines: &mut Vec<BestMoves>) {}
+
+    /// The final lines of a search, reported by `engine_name`.
+    fn search_finished(
+        &mut self,
+        _engine_name: &str,
+        _options: &EngineOptions,
+        _lines: &[BestMoves],
+    ) {
+    }
+}
+
+/// Sends the output of a live search to the frontend, sharing what it learns with the app.
+struct AppSink {
+    app: tauri::AppHandle,
+    key: (String, String),
+}
+
+impl AnalysisSink for AppSink {
+    fn best_moves(&mut self, payload: BestMovesPayload) {
+        payload.emit(&self.app).ok();
+    }
+
+    fn capability_warning(&mut self, warning: EngineCapabilityWarning) {
+        self.app
+            .state::<AppState>()
+            .engine_multipv_limits
+            .insert(self.key.1.clone(), warning.observed_multipv);
+        warning.emit(&self.app).ok();
+    }
+
+    fn set_tab(&mut self, tab: &str) {
+        self.key.0 = tab.to_string();
+    }
+
+    fn pin_lines(&mut self, options: &EngineOptions, lines: &mut Vec<BestMoves>) {
+        apply_pinned_line(&self.app, &self.key, options, lines);
+    }
+
+    fn search_finished(&mut self, engine_name: &str, options: &EngineOptions, lines: &[BestMoves]) {
+        record_analysis(
+            &self.app,
+            &self.key.1,
+            engine_name,
+            &options.fen,
+            &options.moves,
+            lines,
+        );
+    }
+}
+
+/// Time as an `AnalysisHandler` sees it.
+#[derive(Debug, Clone)]
+pub enum HandlerClock {
+    /// Wall time, since the handler started.
+    Wall(Instant),
+    /// Times of a recording, when replaying it.
+    Recorded {
+        now: Duration,
+        search_started: Duration,
+    },
+}
+
+impl HandlerClock {
+    fn now(&self) -> Duration {
+        match self {
+            HandlerClock::Wall(start) => start.elapsed(),
+            HandlerClock::Recorded { now, .. } => *now,
+        }
+    }
+
+    fn search_elapsed(&self, proc: &EngineProcess) -> Duration {
+        match self {
+            HandlerClock::Wall(_) => proc.start.elapsed(),
+            HandlerClock::Recorded {
+                now,
+                search_started,
+            } => now.saturating_sub(*search_started),
+        }
+    }
+}
+
+/// How long the lines of a depth are held back waiting for the others, before the ones
+/// in are sent as a partial set.
+const PARTIAL_LINES_AFTER: Duration = Duration::from_millis(500);
+
+/// Payloads sent in any one second, to avoid flooding the UI.
+const PAYLOADS_PER_SECOND: usize = 5;
+
+/// Lets payloads through at most `PAYLOADS_PER_SECOND` at a time.
+#[derive(Debug, Default)]
+struct PayloadLimiter {
+    sent: VecDeque<Duration>,
+}
+
+impl PayloadLimiter {
+    fn check(&mut self, now: Duration) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.saturating_sub(*sent) >= Duration::from_secs(1))
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= PAYLOADS_PER_SECOND {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+/// Turns the output of an engine into the payloads of its analysis.
+///
+/// Live searches feed it the lines read from the engine; replays feed it recorded lines at
+/// their recorded times, which gives the same payloads again.
+pub struct AnalysisHandler<S: AnalysisSink> {
+    /// Analysis id the payloads are sent under.
+    id: String,
+    tab: String,
+    /// Engine path, as in the key of the process.
+    engine: String,
+    pub clock: HandlerClock,
+    limiter: PayloadLimiter,
+    sink: S,
+}
+
+impl<S: AnalysisSink> AnalysisHandler<S> {
+    pub fn new(id: String, tab: String, engine: String, clock: HandlerClock, sink: S) -> Self {
+        Self {
+            id,
+            tab,
+            engine,
+            clock,
+            limiter: PayloadLimiter::default(),
+            sink,
+        }
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Send the payloads from now on under `tab`, once the tab was renamed.
+    pub fn set_tab(&mut self, tab: &str) {
+        if self.tab != tab {
+            self.tab = tab.to_string();
+            self.sink.set_tab(tab);
+        }
+    }
+
+    fn payload(
+        &self,
+        proc: &EngineProcess,
+        best_lines: Vec<BestMoves>,
+        progress: f64,
+    ) -> BestMovesPayload {
+        BestMovesPayload {
+            best_lines,
+            engine: self.id.clone(),
+            tab: self.tab.clone(),
+            fen: proc.options.fen.clone(),
+            moves: proc.options.moves.clone(),
+            progress,
+            completion: None,
+            statistics: None,
+            partial: false,
+            ponder: None,
+        }
+    }
+
+    /// Handle a line read from the engine of `proc`.
+    pub fn handle_line(&mut self, proc: &mut EngineProcess, line: String) {
+        match vampirc_uci::parse_one(&line) {
+            // What a discarded search still reports goes nowhere.
+            vampirc_uci::UciMessage::Info(_) if proc.discarded_searches > 0 => {}
+            vampirc_uci::UciMessage::BestMove { .. } if proc.discarded_searches > 0 => {
+                proc.discarded_searches -= 1;
+                proc.unanswered_searches = proc.unanswered_searches.saturating_sub(1);
+                proc.best_moves.clear();
+            }
+            vampirc_uci::UciMessage::Info(attrs) => self.handle_info(proc, attrs),
+            vampirc_uci::UciMessage::BestMove { ponder, .. } => {
+                self.handle_best_move(proc, ponder.map(|m| m.to_string()))
+            }
+            _ => {}
+        }
+        proc.log_engine(line);
+    }
+
+    fn handle_info(&mut self, proc: &mut EngineProcess, attrs: Vec<UciInfoAttribute>) {
+        // Parse FEN safely without unwrap
+        let fen = match proc.options.fen.parse() {
+            Ok(fen) => fen,
+            Err(e) => {
+                log::error!(
+                    "Failed to parse FEN in engine output: {} - FEN: {}",
+                    e,
+                    proc.options.fen
+                );
+                return;
+            }
+        };
+        let Ok(best_moves) = super::process::parse_uci_attrs(attrs, &fen, &proc.options.moves)
+        else {
+            return;
+        };
+        let multipv = best_moves.multipv;
+        let cur_depth = best_moves.depth;
+        if let Some(observed) = proc.multipv_diagnostic.observe(multipv, cur_depth) {
+            warn!(
+                "Engine {} ignores MultiPV {}",
+                self.engine, proc.real_multipv
+            );
+            // Show the lines the engine does report.
+            let requested = proc.real_multipv;
+            proc.real_multipv = observed;
+            proc.best_moves.clear();
+            self.sink.capability_warning(EngineCapabilityWarning {
+                engine: self.id.clone(),
+                tab: self.tab.clone(),
+                requested_multipv: requested,
+                observed_multipv: observed,
+            });
+        }
+        if multipv == 0 || multipv > proc.real_multipv {
+            return;
+        }
+
+        let now = self.clock.now();
+        let set_depth = proc.best_moves.first().map(|line| line.depth);
+        if multipv == 1 || set_depth.is_some_and(|depth| cur_depth > depth) {
+            // A new set of lines starts, so the rest of the previous one isn't coming.
+            if !proc.best_moves.is_empty() {
+                self.send_lines(proc, now);
+                proc.best_moves.clear();
+            }
+        } else if set_depth.is_some_and(|depth| cur_depth < depth) {
+            // A late line of a depth already done.
+            return;
+        }
+        if proc.best_moves.is_empty() {
+            proc.best_moves_since = now;
+        }
+        match proc
+            .best_moves
+            .binary_search_by_key(&multipv, |line| line.multipv)
+        {
+            Ok(i) => proc.best_moves[i] = best_moves,
+            Err(i) => proc.best_moves.insert(i, best_moves),
+        }
+
+        if multipv == proc.real_multipv {
+            // Engines report lines in order, so indices skipped so far won't come anymore.
+            self.send_lines(proc, now);
+            proc.best_moves.clear();
+        } else if now.saturating_sub(proc.best_moves_since) >= PARTIAL_LINES_AFTER {
+            self.send_lines(proc, now);
+            proc.best_moves_since = now;
+        }
+    }
+
+    /// Send the lines of the depth being reported, if none deeper were sent and the rate
+    /// limit allows. They are marked partial when fewer than requested.
+    fn send_lines(&mut self, proc: &mut EngineProcess, now: Duration) {
+        let Some(last) = proc.best_moves.last() else {
+            return;
+        };
+        let (depth, nodes) = (last.depth, last.nodes);
+        if depth < proc.last_depth || !self.limiter.check(now) {
+            return;
+        }
+        let progress = match proc.go_mode {
+            GoMode::Depth(target) => (depth as f64 / target as f64) * 100.0,
+            GoMode::Time(time) => {
+                (self.clock.search_elapsed(proc).as_millis() as f64 / time as f64) * 100.0
+            }
+            GoMode::Nodes(target) => (nodes as f64 / target as f64) * 100.0,
+            // A mate in n moves takes at most 2n - 1 plies.
+            GoMode::Mate(moves) => {
+                (depth as f64 / (2 * moves).saturating_sub(1).max(1) as f64 * 100.0).min(99.99)
+            }
+            GoMode::PlayersTime(_) => 99.99,
+            GoMode::Infinite => 99.99,
+        };
+        let partial = proc.best_moves.len() < proc.real_multipv as usize;
+        let mut best_lines = proc.best_moves.clone();
+        self.sink.pin_lines(&proc.options, &mut best_lines);
+        let payload = BestMovesPayload {
+            partial,
+            ..self.payload(proc, best_lines.clone(), progress)
+        };
+        self.sink.best_moves(payload);
+        proc.last_depth = depth;
+        proc.last_best_moves = best_lines;
+        proc.last_partial = partial;
+        proc.last_progress = progress as f32;
+    }
+
+    fn handle_best_move(&mut self, proc: &mut EngineProcess, ponder: Option<String>) {
+        // Lines of the last depth still waiting for others go with the final result.
+        if proc
+            .best_moves
+            .first()
+            .is_some_and(|line| line.depth >= proc.last_depth)
+        {
+            let mut best_lines = std::mem::take(&mut proc.best_moves);
+            proc.last_partial = best_lines.len() < proc.real_multipv as usize;
+            self.sink.pin_lines(&proc.options, &mut best_lines);
+            proc.last_best_moves = best_lines;
+        }
+        proc.best_moves.clear();
+        // Emit final result when engine signals best move.
+        proc.unanswered_searches = proc.unanswered_searches.saturating_sub(1);
+        let statistics = search_statistics(proc, self.clock.search_elapsed(proc));
+        let completion = search_completion(
+            proc.stop_reason.take(),
+            &EngineExit::BestMove,
+            true,
+            &proc.go_mode,
+            &statistics,
+            &proc.last_best_moves,
+        );
+        let payload = BestMovesPayload {
+            completion,
+            statistics: Some(statistics),
+            partial: proc.last_partial,
+            ponder,
+            ..self.payload(proc, proc.last_best_moves.clone(), 100.0)
+        };
+        self.sink.best_moves(payload);
+        proc.last_progress = 100.0;
+        // Pinned lines searched on their own don't belong in the cache.
+        let lines: Vec<_> = proc
+            .last_best_moves
+            .iter()
+            .filter(|line| line.multipv <= proc.real_multipv)
+            .cloned()
+            .collect();
+        if !lines.is_empty() {
+            let engine_name = proc.engine_name().unwrap_or_else(|| self.engine.clone());
+            self.sink
+                .search_finished(&engine_name, &proc.options, &lines);
+        }
+    }
+
+    /// Handle the engine of `proc` going away.
+    pub fn handle_exit(&mut self, proc: &mut EngineProcess, exit: &EngineExit) {
+        // A search cut short by the engine going away still gets its last payload.
+        let statistics = search_statistics(proc, self.clock.search_elapsed(proc));
+        let completion = search_completion(
+            proc.stop_reason.take(),
+            exit,
+            proc.unanswered_searches > proc.discarded_searches,
+            &proc.go_mode,
+            &statistics,
+            &proc.last_best_moves,
+        );
+        if let Some(completion) = completion {
+            warn!(
+                "Engine search ended without a best move: tab={} engine={} ({:?})",
+                self.tab, self.engine, completion
+            );
+            let payload = BestMovesPayload {
+                completion: Some(completion),
+                statistics: Some(statistics),
+                partial: proc.last_partial,
+                ..self.payload(
+                    proc,
+                    proc.last_best_moves.clone(),
+                    proc.last_progress as f64,
+                )
+            };
+            self.sink.best_moves(payload);
+        }
+    }
+}
+
+/// How the reader loop of an engine stopped reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineExit {
+    /// The engine reported its best move, ending the search.
+    BestMove,
+    /// The engine closed its output.
+    Closed,
+    /// The engine's output couldn't be read.
+    ReadFailed(String),
+}
+
+/// Totals of the search of `proc`, `elapsed` since it started, from its last lines.
+fn search_statistics(proc: &EngineProcess, elapsed: Duration) -> AnalysisStatistics {
+    let lines = &proc.last_best_moves;
+    AnalysisStatistics {
+        nodes: lines.iter().map(|line| line.nodes).max().unwrap_or(0),
+        elapsed_ms: elapsed.as_millis().min(u32::MAX as u128) as u32,
+        max_depth: lines.iter().map(|line| line.depth).max().unwrap_or(0),
+        multipv: lines
+            .iter()
+            .filter(|line| line.multipv <= proc.real_multipv)
+            .count() as u16,
+    }
+}
+
+/// Why a search ended, or `None` when the engine went away while not `searching`.
+///
+/// Stops and kills record their reason before the engine answers, so a reason is only
+/// inferred from the search itself when nobody asked it to end.
+fn search_completion(
+    requested: Option<AnalysisCompletion>,
+    exit: &EngineExit,
+    searching: bool,
+    go_mode: &GoMode,
+    statistics: &AnalysisStatistics,
+    lines: &[BestMoves],
+) -> Option<AnalysisCompletion> {
+    if let Some(requested) = requested {
+        return Some(requested);
+    }
+    match exit {
+        EngineExit::BestMove => Some(natural_completion(go_mode, statistics, lines)),
+        _ if !searching => None,
+        EngineExit::Closed => Some(AnalysisCompletion::EngineCrashed),
+        EngineExit::ReadFailed(e) => Some(AnalysisCompletion::Error(e.clone())),
+    }
+}
+
+/// Why a search ended on its own, from the limit it was given and what it found.
+fn natural_completion(
+    go_mode: &GoMode,
+    statistics: &AnalysisStatistics,
+    lines: &[BestMoves],
+) -> AnalysisCompletion {
+    let mate = lines
+        .first()
+        .is_some_and(|line| matches!(line.score.value, ScoreValue::Mate(_)));
+    match go_mode {
+        GoMode::Depth(depth) if statistics.max_depth >= *depth => AnalysisCompletion::TargetReached,
+        GoMode::Nodes(nodes) if statistics.nodes >= *nodes => AnalysisCompletion::BudgetExhausted,
+        GoMode::Time(time) if statistics.elapsed_ms >= *time => AnalysisCompletion::BudgetExhausted,
+        _ if mate => AnalysisCompletion::AutoStoppedMate,
+        GoMode::Depth(_) | GoMode::Mate(_) | GoMode::Infinite => AnalysisCompletion::TargetReached,
+        GoMode::Nodes(_) | GoMode::Time(_) | GoMode::PlayersTime(_) => {
+            AnalysisCompletion::BudgetExhausted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::types::{EngineOption, PlayersTime};
+    use vampirc_uci::uci::Score;
+
+    fn line(depth: u32, value: ScoreValue) -> BestMoves {
+        BestMoves {
+            depth,
+            nodes: 1000,
+            score: Score {
+                value,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn statistics(lines: &[BestMoves]) -> AnalysisStatistics {
+        AnalysisStatistics {
+            nodes: 1000,
+            elapsed_ms: 200,
+            max_depth: lines.iter().map(|line| line.depth).max().unwrap_or(0),
+            multipv: lines.len() as u16,
+        }
+    }
+
+    #[test]
+    fn natural_best_move_completes_the_search() {
+        let lines = [line(20, ScoreValue::Cp(30))];
+        let completion = |go_mode| {
+            search_completion(
+                None,
+                &EngineExit::BestMove,
+                true,
+                &go_mode,
+                &statistics(&lines),
+                &lines,
+            )
+        };
+        assert_eq!(
+            completion(GoMode::Depth(20)),
+            Some(AnalysisCompletion::TargetReached)
+        );
+        assert_eq!(
+            completion(GoMode::Nodes(1000)),
+            Some(AnalysisCompletion::BudgetExhausted)
+        );
+        assert_eq!(
+            completion(GoMode::Time(150)),
+            Some(AnalysisCompletion::BudgetExhausted)
+        );
+        // A mate search finding none still searched as far as it was asked.
+        assert_eq!(
+            completion(GoMode::Mate(3)),
+            Some(AnalysisCompletion::TargetReached)
+        );
+
+        // Engines stop short of the target once they see a mate.
+        let mate = [line(12, ScoreValue::Mate(3))];
+        assert_eq!(
+            search_completion(
+                None,
+                &EngineExit::BestMove,
+                true,
+                &GoMode::Depth(20),
+                &statistics(&mate),
+                &mate,
+            ),
+            Some(AnalysisCompletion::AutoStoppedMate)
+        );
+    }
+
+    #[test]
+    fn user_stop_is_attributed_to_the_request() {
+        let lines = [line(8, ScoreValue::Cp(30))];
+        // The best move answering a stop, and an engine killed while searching.
+        for exit in [EngineExit::BestMove, EngineExit::Closed] {
+            assert_eq!(
+                search_completion(
+                    Some(AnalysisCompletion::UserStopped),
+                    &exit,
+                    false,
+                    &GoMode::Depth(20),
+                    &statistics(&lines),
+                    &lines,
+                ),
+                Some(AnalysisCompletion::UserStopped)
+            );
+        }
+    }
+
+    #[test]
+    fn engine_going_away_mid_search_is_a_crash() {
+        let lines = [line(8, ScoreValue::Cp(30))];
+        let completion = |exit, searching| {
+            search_completion(
+                None,
+                &exit,
+                searching,
+                &GoMode::Infinite,
+                &statistics(&lines),
+                &lines,
+            )
+        };
+        assert_eq!(
+            completion(EngineExit::Closed, true),
+            Some(AnalysisCompletion::EngineCrashed)
+        );
+        assert_eq!(
+            completion(EngineExit::ReadFailed("broken pipe".to_string()), true),
+            Some(AnalysisCompletion::Error("broken pipe".to_string()))
+        );
+        // Nothing was running, so there's no search to end.
+        assert_eq!(completion(EngineExit::Closed, false), None);
+    }
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    const PVS: [&str; 4] = ["e2e4", "d2d4", "g1f3", "c2c4"];
+
+    #[derive(Default)]
+    struct Collected(Vec<BestMovesPayload>);
+
+    impl AnalysisSink for Collected {
+        fn best_moves(&mut self, payload: BestMovesPayload) {
+            self.0.push(payload);
+        }
+
+        fn capability_warning(&mut self, _warning: EngineCapabilityWarning) {}
+    }
+
+    fn info(depth: u32, multipv: u16) -> String {
+        format!(
+            "info depth {} multipv {} score cp 20 nodes 1000 time 5 pv {}",
+            depth,
+            multipv,
+            PVS[multipv as usize - 1]
+        )
+    }
+
+    /// Feed `lines` at their times in milliseconds to a search of `multipv` lines.
+    fn feed(multipv: u16, lines: &[(u64, String)]) -> Vec<BestMovesPayload> {
+        let mut proc = EngineProcess::detached();
+        proc.options.fen = START.to_string();
+        proc.go_mode = GoMode::Depth(20);
+        proc.real_multipv = multipv;
+        let mut handler = AnalysisHandler::new(
+            "analysis".to_string(),
+            "tab".to_string(),
+            "engine".to_string(),
+            HandlerClock::Recorded {
+                now: Duration::ZERO,
+                search_started: Duration::ZERO,
+            },
+            Collected::default(),
+        );
+        for (at, line) in lines {
+            handler.clock = HandlerClock::Recorded {
+                now: Duration::from_millis(*at),
+                search_started: Duration::ZERO,
+            };
+            handler.handle_line(&mut proc, line.clone());
+        }
+        handler.into_sink().0
+    }
+
+    #[test]
+    fn the_expected_reply_comes_with_the_best_move() {
+        let payloads = feed(
+            1,
+            &[
+                (1000, info(8, 1)),
+                (2000, "bestmove e2e4 ponder e7e5".to_string()),
+            ],
+        );
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].ponder, None);
+        assert_eq!(payloads[1].ponder.as_deref(), Some("e7e5"));
+        assert_eq!(
+            feed(1, &[(2000, "bestmove e2e4".to_string())])[0].ponder,
+            None
+        );
+    }
+
+    fn players_time(ponder: bool) -> GoMode {
+        GoMode::PlayersTime(PlayersTime {
+            white: 60_000,
+            black: 60_000,
+            winc: 0,
+            binc: 0,
+            ponder,
+        })
+    }
+
+    #[tokio::test]
+    async fn pondering_goes_on_after_a_hit_and_is_discarded_after_a_miss() {
+        let mut proc = EngineProcess::detached();
+        proc.options.fen = START.to_string();
+        proc.real_multipv = 1;
+        let mut handler = AnalysisHandler::new(
+            "analysis".to_string(),
+            "tab".to_string(),
+            "engine".to_string(),
+            HandlerClock::Wall(Instant::now()),
+            Collected::default(),
+        );
+
+        proc.go(&players_time(true)).await.unwrap();
+        assert!(proc.is_pondering());
+        proc.ponderhit(&players_time(false)).await.unwrap();
+        assert!(!proc.is_pondering());
+        handler.handle_line(&mut proc, info(8, 1));
+        handler.handle_line(&mut proc, "bestmove e2e4 ponder e7e5".to_string());
+
+        proc.go(&players_time(true)).await.unwrap();
+        proc.stop_pondering().await.unwrap();
+        handler.handle_line(&mut proc, info(9, 1));
+        handler.handle_line(&mut proc, "bestmove d2d4".to_string());
+        assert_eq!(proc.unanswered_searches, 0);
+
+        let payloads = handler.into_sink().0;
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(indices(&payloads[0]), vec![(8, 1)]);
+        assert_eq!(payloads[1].ponder.as_deref(), Some("e7e5"));
+    }
+
+    fn indices(payload: &BestMovesPayload) -> Vec<(u32, u16)> {
+        payload
+            .best_lines
+            .iter()
+            .map(|line| (line.depth, line.multipv))
+            .collect()
+    }
+
+    #[test]
+    fn skipped_multipv_indices_dont_stall_the_lines() {
+        // The third line never comes, at any depth.
+        let lines: Vec<_> = (1..=3)
+            .flat_map(|depth| [1, 2, 4].map(|multipv| (depth as u64 * 300, info(depth, multipv))))
+            .chain([(1000, "bestmove e2e4".to_string())])
+            .collect();
+        let payloads = feed(4, &lines);
+        assert_eq!(payloads.len(), 4);
+        for (depth, payload) in (1..=3).zip(&payloads) {
+            assert_eq!(indices(payload), [(depth, 1), (depth, 2), (depth, 4)]);
+            assert!(payload.partial);
+        }
+        assert!(payloads[3].completion.is_some() && payloads[3].partial);
+        assert_eq!(payloads[3].best_lines.len(), 3);
+
+        // Nor does the last one, so each depth waits for the next to start.
+        let lines = [
+            (0, info(1, 1)),
+            (10, info(1, 2)),
+            (300, info(2, 1)),
+            (310, info(2, 2)),
+            (320, "bestmove e2e4".to_string()),
+        ];
+        let payloads = feed(3, &lines);
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(indices(&payloads[0]), [(1, 1), (1, 2)]);
+        assert!(payloads[0].partial && payloads[0].completion.is_none());
+        // The lines of the last depth go with the best move.
+        assert_eq!(indices(&payloads[1]), [(2, 1), (2, 2)]);
+        assert!(payloads[1].partial && payloads[1].completion.is_some());
+    }
+
+    #[test]
+    fn interleaved_depths_dont_stall_the_lines() {
+        let lines = [
+            (0, info(5, 1)),
+            (10, info(5, 2)),
+            (20, info(6, 1)),
+            // A late line of the depth before.
+            (30, info(5, 3)),
+            (40, info(6, 2)),
+            (50, info(6, 3)),
+        ];
+        let payloads = feed(3, &lines);
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(indices(&payloads[0]), [(5, 1), (5, 2)]);
+        assert!(payloads[0].partial);
+        assert_eq!(indices(&payloads[1]), [(6, 1), (6, 2), (6, 3)]);
+        assert!(!payloads[1].partial);
+    }
+
+    #[test]
+    fn slow_lines_are_sent_before_the_others() {
+        let lines = [
+            (0, info(8, 1)),
+            (100, info(8, 2)),
+            (600, info(8, 3)),
+            (700, info(8, 4)),
+        ];
+        let payloads = feed(4, &lines);
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(indices(&payloads[0]), [(8, 1), (8, 2), (8, 3)]);
+        assert!(payloads[0].partial);
+        assert_eq!(payloads[1].best_lines.len(), 4);
+        assert!(!payloads[1].partial);
+    }
+
+    fn tab_process(tab: &str) -> Arc<Mutex<EngineProcess>> {
+        let mut proc = EngineProcess::detached();
+        proc.options.fen = START.to_string();
+        proc.go_mode = GoMode::Depth(20);
+        proc.real_multipv = 1;
+        proc.tab = tab.to_string();
+        Arc::new(Mutex::new(proc))
+    }
+
+    #[tokio::test]
+    async fn renaming_a_tab_keeps_its_analysis_running() {
+        let engines = Arc::new(DashMap::new());
+        let process = tab_process("old");
+        engines.insert(("old".to_string(), "engine".to_string()), process.clone());
+        let mut handler = AnalysisHandler::new(
+            "analysis".to_string(),
+            "old".to_string(),
+            "engine".to_string(),
+            HandlerClock::Recorded {
+                now: Duration::ZERO,
+                search_started: Duration::ZERO,
+            },
+            Collected::default(),
+        );
+
+        // Stands for the reader task of the engine, handling lines as they come.
+        let (lines, mut reader) = tokio::sync::mpsc::unbounded_channel::<u32>();
+        let reader = tokio::spawn({
+            let engines = engines.clone();
+            let process = process.clone();
+            async move {
+                while let Some(depth) = reader.recv().await {
+                    handler.clock = HandlerClock::Recorded {
+                        now: Duration::from_millis(depth as u64 * 300),
+                        search_started: Duration::ZERO,
+                    };
+                    handle_engine_line(&engines, &process, &mut handler, info(depth, 1)).await;
+                    tokio::task::yield_now().await;
+                }
+                handler.into_sink().0
+            }
+        });
+
+        for depth in 1..=3 {
+            lines.send(depth).unwrap();
+        }
+        // Let the reader get through some of them, whichever it is in the middle of.
+        tokio::task::yield_now().await;
+        tokio::task::yield_now().await;
+        rekey_processes(&engines, "old", "new").await;
+        for depth in 4..=6 {
+            lines.send(depth).unwrap();
+        }
+        drop(lines);
+        let payloads = reader.await.unwrap();
+
+        assert_eq!(payloads.len(), 6);
+        let renamed_at = payloads
+            .iter()
+            .position(|payload| payload.tab == "new")
+            .unwrap();
+        assert!(renamed_at <= 3);
+        assert!(payloads[..renamed_at].iter().all(|p| p.tab == "old"));
+        assert!(payloads[renamed_at..].iter().all(|p| p.tab == "new"));
+        assert!(!engines.contains_key(&("old".to_string(), "engine".to_string())));
+        assert_eq!(process.lock().await.tab, "new");
+    }
+
+    #[tokio::test]
+    async fn renaming_only_moves_the_tab_itself() {
+        let engines = DashMap::new();
+        let renamed = tab_process("tab-1");
+        let other = tab_process("tab-10");
+        engines.insert(("tab-1".to_string(), "a".to_string()), renamed.clone());
+        engines.insert(("tab-1".to_string(), "b".to_string()), renamed.clone());
+        engines.insert(("tab-10".to_string(), "a".to_string()), other.clone());
+
+        rekey_processes(&engines, "tab-1", "tab-2").await;
+        let mut keys: Vec<_> = engines.iter().map(|e| e.key().clone()).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                ("tab-10".to_string(), "a".to_string()),
+                ("tab-2".to_string(), "a".to_string()),
+                ("tab-2".to_string(), "b".to_string()),
+            ]
+        );
+        assert_eq!(other.lock().await.tab, "tab-10");
+
+        // Lines of a process no longer in the map are dropped.
+        let mut handler = AnalysisHandler::new(
+            "analysis".to_string(),
+            "tab-10".to_string(),
+            "a".to_string(),
+            HandlerClock::Recorded {
+                now: Duration::ZERO,
+                search_started: Duration::ZERO,
+            },
+            Collected::default(),
+        );
+        engines.clear();
+        handle_engine_line(&engines, &other, &mut handler, info(1, 1)).await;
+        assert!(handler.into_sink().0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn multipv_is_capped_at_the_advertised_maximum() {
+        let mut proc = EngineProcess::detached();
+        proc.log_engine("option name MultiPV type spin default 1 min 1 max 2".to_string());
+        proc.set_options(EngineOptions {
+            fen: START.to_string(),
+            moves: Vec::new(),
+            extra_options: vec![EngineOption {
+                name: "MultiPV".to_string(),
+                value: "50".to_string(),
+            }],
+        })
+        .await
+        .unwrap();
+        assert_eq!(proc.real_multipv, 2);
+    }
 }
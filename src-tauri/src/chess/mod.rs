@@ -3,13 +3,55 @@
 //! This module re-exports all core chess logic, including UCI engine process management, analysis routines,
 //! evaluation, and Tauri command handlers. It serves as the main entry point for chess-related backend features.
 
+pub mod accuracy;
 pub mod analysis;
+pub mod assets;
+pub mod batch;
+pub mod benchmark;
+pub mod blindfold;
+pub mod book;
+pub mod budget;
+pub mod builtin;
+pub mod cache;
 pub mod commands;
+pub mod consensus;
+pub mod correspondence;
+pub mod cp_loss;
+pub mod diagnostics;
+pub mod drill;
+pub mod effects;
+pub mod evalbar;
 pub mod evaluation;
+pub mod history;
 pub mod manager;
+pub mod odds;
+pub mod only_move;
+pub mod options;
+pub mod perft;
+pub mod pin;
+pub mod play;
+pub mod pool;
+pub mod position_notes;
 pub mod process;
+pub mod recording;
+pub mod refutation;
+pub mod swindle;
+pub mod tab_policy;
+pub mod tablebase;
+pub mod tabs;
+pub mod time_control;
+pub mod time_usage;
+pub mod timeline;
 pub mod types;
 pub mod uci;
+pub mod vision_drills;
 
 #[allow(unused_imports)]
-pub use {analysis::*, commands::*, evaluation::*, manager::*, process::*, types::*, uci::*};
+pub use {
+    accuracy::*, analysis::*, assets::*, batch::*, benchmark::*, blindfold::*, book::*, budget::*,
+    builtin::*, cache::*, commands::*, consensus::*, correspondence::*, cp_loss::*, diagnostics::*,
+    drill::*, effects::*, evalbar::*, evaluation::*, history::*, manager::*, odds::*, only_move::*,
+    options::*, perft::*, pin::*, play::*, pool::*, position_notes::*, process::*, recording::*,
+    refutation::*, swindle::*, tab_policy::*, tablebase::*, tabs::*, time_control::*,
+    time_usage::*, timeline::*, types::*, uci::*, vision_drills::*,
+};
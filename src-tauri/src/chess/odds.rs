@@ -0,0 +1,237 @@
+//! Starting positions of odds games.
+//!
+//! The stronger player gives odds by starting without some of their pieces. The standard
+//! odds remove the f-pawn, the queen's knight, the queen's rook or the queen, from the side
+//! of whoever gives them; other combinations name the squares to empty. Castling rights go
+//! with a removed rook. Odds givers move first, except in pawn and move, where the receiver
+//! also gets the first move.
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, Bitboard, CastlingMode, Chess, Color, EnPassantMode, Role, Setup, Square,
+};
+use specta::Type;
+
+use crate::error::Error;
+
+use super::types::EngineColor;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Odds {
+    Pawn,
+    PawnAndMove,
+    Knight,
+    Rook,
+    Queen,
+    /// Pieces of the odds giver on these squares, such as `["b1", "g1"]` for two knights.
+    Pieces {
+        squares: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct OddsSpec {
+    pub odds: Odds,
+    /// Side playing without the pieces.
+    pub giver: EngineColor,
+}
+
+impl OddsSpec {
+    /// As written in the `Odds` header of the game, such as "Knight odds given by White".
+    pub fn description(&self) -> String {
+        let odds = match &self.odds {
+            Odds::Pawn => "Pawn odds".to_string(),
+            Odds::PawnAndMove => "Pawn and move odds".to_string(),
+            Odds::Knight => "Knight odds".to_string(),
+            Odds::Rook => "Rook odds".to_string(),
+            Odds::Queen => "Queen odds".to_string(),
+            Odds::Pieces { squares } => {
+                let squares: Vec<&str> = squares.iter().map(|square| square.trim()).collect();
+                format!("Odds of the pieces on {}", squares.join(", "))
+            }
+        };
+        let giver = match self.giver {
+            EngineColor::White => "White",
+            EngineColor::Black => "Black",
+        };
+        format!("{} given by {}", odds, giver)
+    }
+
+    /// The starting position, checked to be legal.
+    pub fn position(&self) -> Result<Chess, Error> {
+        let giver = Color::from(self.giver);
+        let relative = |square: Square| match giver {
+            Color::White => square,
+            Color::Black => square.flip_vertical(),
+        };
+        let squares = match &self.odds {
+            Odds::Pawn | Odds::PawnAndMove => vec![relative(Square::F2)],
+            Odds::Knight => vec![relative(Square::B1)],
+            Odds::Rook => vec![relative(Square::A1)],
+            Odds::Queen => vec![relative(Square::D1)],
+            Odds::Pieces { squares } => squares
+                .iter()
+                .map(|square| {
+                    square
+                        .trim()
+                        .parse::<Square>()
+                        .map_err(|_| Error::InvalidOdds(format!("{} is not a square", square)))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        if squares.is_empty() {
+            return Err(Error::InvalidOdds("no piece is removed".to_string()));
+        }
+
+        let mut setup = Setup::initial();
+        for square in squares {
+            match setup.board.piece_at(square) {
+                None => {
+                    return Err(Error::InvalidOdds(format!("{} is empty", square)));
+                }
+                Some(piece) if piece.color != giver => {
+                    return Err(Error::InvalidOdds(format!(
+                        "{} holds a piece of the odds receiver",
+                        square
+                    )));
+                }
+                Some(piece) if piece.role == Role::King => {
+                    return Err(Error::InvalidOdds("the king can't be removed".to_string()));
+                }
+                Some(_) => {
+                    setup.board.discard_piece_at(square);
+                    setup.castling_rights &= !Bitboard::from_square(square);
+                }
+            }
+        }
+        setup.turn = match self.odds {
+            Odds::PawnAndMove => !giver,
+            _ => giver,
+        };
+        Chess::from_setup(setup, CastlingMode::Standard)
+            .map_err(|e| Error::InvalidOdds(e.to_string()))
+    }
+
+    pub fn fen(&self) -> Result<String, Error> {
+        Ok(Fen::from_position(self.position()?, EnPassantMode::Legal).to_string())
+    }
+}
+
+/// The starting FEN of an odds game.
+#[tauri::command]
+#[specta::specta]
+pub async fn create_odds_position(odds: OddsSpec) -> Result<String, Error> {
+    odds.fen()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(odds: Odds, giver: EngineColor) -> OddsSpec {
+        OddsSpec { odds, giver }
+    }
+
+    fn fen(odds: Odds, giver: EngineColor) -> String {
+        let fen = spec(odds, giver).fen().unwrap();
+        // Read back as a legal position.
+        fen.parse::<Fen>()
+            .unwrap()
+            .into_position::<Chess>(CastlingMode::Standard)
+            .unwrap();
+        fen
+    }
+
+    #[test]
+    fn standard_odds() {
+        let cases = [
+            (
+                Odds::Pawn,
+                EngineColor::White,
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPP1PP/RNBQKBNR w KQkq - 0 1",
+            ),
+            (
+                Odds::PawnAndMove,
+                EngineColor::Black,
+                "rnbqkbnr/ppppp1pp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            ),
+            (
+                Odds::PawnAndMove,
+                EngineColor::White,
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPP1PP/RNBQKBNR b KQkq - 0 1",
+            ),
+            (
+                Odds::Knight,
+                EngineColor::White,
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/R1BQKBNR w KQkq - 0 1",
+            ),
+            (
+                Odds::Knight,
+                EngineColor::Black,
+                "r1bqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1",
+            ),
+            (
+                Odds::Rook,
+                EngineColor::White,
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/1NBQKBNR w Kkq - 0 1",
+            ),
+            (
+                Odds::Rook,
+                EngineColor::Black,
+                "1nbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQk - 0 1",
+            ),
+            (
+                Odds::Queen,
+                EngineColor::White,
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNB1KBNR w KQkq - 0 1",
+            ),
+        ];
+        for (odds, giver, expected) in cases {
+            assert_eq!(fen(odds.clone(), giver), expected, "{:?}", odds);
+        }
+    }
+
+    #[test]
+    fn chosen_pieces() {
+        let odds = Odds::Pieces {
+            squares: vec!["b1".to_string(), "g1".to_string(), " h1 ".to_string()],
+        };
+        assert_eq!(
+            fen(odds.clone(), EngineColor::White),
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/R1BQKB2 w Qkq - 0 1"
+        );
+        assert_eq!(
+            spec(odds, EngineColor::White).description(),
+            "Odds of the pieces on b1, g1, h1 given by White"
+        );
+        assert_eq!(
+            spec(Odds::Knight, EngineColor::Black).description(),
+            "Knight odds given by Black"
+        );
+    }
+
+    #[test]
+    fn nonsensical_odds_are_rejected() {
+        let pieces = |squares: &[&str], giver| {
+            spec(
+                Odds::Pieces {
+                    squares: squares.iter().map(|s| s.to_string()).collect(),
+                },
+                giver,
+            )
+            .fen()
+        };
+        for result in [
+            pieces(&["e1"], EngineColor::White),
+            pieces(&["e8"], EngineColor::Black),
+            pieces(&["d4"], EngineColor::White),
+            pieces(&["a8"], EngineColor::White),
+            pieces(&["z9"], EngineColor::White),
+            pieces(&[], EngineColor::White),
+        ] {
+            assert!(matches!(result, Err(Error::InvalidOdds(_))), "{:?}", result);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Only moves: positions where a single move holds the evaluation.
+//!
+//! Game analysis searches two lines per position. When the best one is much better than the
+//! second and still leaves the player standing, finding it was an achievement, and missing
+//! it usually decided the game. Evaluations are capped before being compared, so a mate is
+//! no better than a decisive advantage and never overflows, and a position with a single
+//! legal move isn't counted: there the move is forced, not found.
+
+use shakmaty::{uci::UciMove, CastlingMode, Chess, Position};
+
+use super::analysis::AnalysisPosition;
+use super::evaluation::score_to_cp;
+use super::types::{BestMoves, MoveAnalysis};
+
+/// Evaluation gap between the two best moves, in centipawns, making the best one the only
+/// move.
+pub const DEFAULT_ONLY_MOVE_GAP_CP: i32 = 150;
+
+/// Evaluation after the best move, in centipawns from the player's point of view, below
+/// which the position is lost anyway.
+pub const DEFAULT_SURVIVAL_CP: i32 = -200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OnlyMoveThresholds {
+    pub gap_cp: i32,
+    pub survival_cp: i32,
+}
+
+impl Default for OnlyMoveThresholds {
+    fn default() -> Self {
+        Self {
+            gap_cp: DEFAULT_ONLY_MOVE_GAP_CP,
+            survival_cp: DEFAULT_SURVIVAL_CP,
+        }
+    }
+}
+
+/// Whether the best of `lines` is the only move for the side to move, given the number of
+/// legal moves in the position.
+pub fn is_only_move(
+    lines: &[BestMoves],
+    white_to_move: bool,
+    legal_moves: usize,
+    thresholds: OnlyMoveThresholds,
+) -> bool {
+    if legal_moves < 2 {
+        return false;
+    }
+    let (Some(best), Some(second)) = (lines.first(), lines.get(1)) else {
+        return false;
+    };
+    // Scores are from White's point of view, and capped well within range.
+    let sign = if white_to_move { 1 } else { -1 };
+    let best = sign * score_to_cp(&best.score);
+    let second = sign * score_to_cp(&second.score);
+    best >= thresholds.survival_cp && best - second >= thresholds.gap_cp
+}
+
+pub(super) fn same_move(position: &Chess, a: &str, b: &str) -> bool {
+    let parse = |uci: &str| {
+        UciMove::from_ascii(uci.as_bytes())
+            .ok()?
+            .to_move(position)
+            .ok()
+    };
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Mark the only moves of an analysed game, and whether the game move was each of them.
+/// `positions` and `analysis` go together, the start position first.
+pub fn annotate_only_moves(
+    positions: &[AnalysisPosition],
+    moves: &[String],
+    analysis: &mut [MoveAnalysis],
+    thresholds: OnlyMoveThresholds,
+) {
+    for (position, analysis) in positions.iter().zip(analysis.iter_mut()) {
+        if position.termination.is_some() {
+            continue;
+        }
+        let Ok(chess) = position
+            .fen
+            .clone()
+            .into_position::<Chess>(CastlingMode::Chess960)
+        else {
+            continue;
+        };
+        analysis.only_move = is_only_move(
+            &analysis.best,
+            chess.turn().is_white(),
+            chess.legal_moves().len(),
+            thresholds,
+        );
+        analysis.found_only_move = analysis.only_move
+            && match (moves.get(position.ply), analysis.best[0].uci_moves.first()) {
+                (Some(played), Some(best)) => same_move(&chess, played, best),
+                _ => false,
+            };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::analysis::build_analysis_positions;
+    use shakmaty::{fen::Fen, EnPassantMode};
+    use vampirc_uci::uci::{Score, ScoreValue};
+
+    fn line(value: ScoreValue, uci: &str) -> BestMoves {
+        BestMoves {
+            score: Score {
+                value,
+                ..Default::default()
+            },
+            uci_moves: vec![uci.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn only(best: ScoreValue, second: ScoreValue, white_to_move: bool) -> bool {
+        is_only_move(
+            &[line(best, "a1a2"), line(second, "a1a3")],
+            white_to_move,
+            20,
+            OnlyMoveThresholds::default(),
+        )
+    }
+
+    #[test]
+    fn only_moves_need_a_gap_and_a_surviving_best_move() {
+        use ScoreValue::*;
+        assert!(only(Cp(0), Cp(-300), true));
+        assert!(!only(Cp(0), Cp(-100), true));
+        // Black to move: the scores are White's.
+        assert!(only(Cp(0), Cp(300), false));
+        assert!(!only(Cp(0), Cp(-300), false));
+        // Lost either way.
+        assert!(!only(Cp(-500), Cp(-900), true));
+        assert!(only(Cp(-150), Cp(-900), true));
+
+        // A single legal move is forced, and a single line can't be compared.
+        let lines = [line(Cp(0), "a1a2"), line(Cp(-300), "a1a3")];
+        assert!(!is_only_move(
+            &lines,
+            true,
+            1,
+            OnlyMoveThresholds::default()
+        ));
+        assert!(!is_only_move(
+            &lines[..1],
+            true,
+            20,
+            OnlyMoveThresholds::default()
+        ));
+
+        let strict = OnlyMoveThresholds {
+            gap_cp: 400,
+            survival_cp: 0,
+        };
+        assert!(!is_only_move(&lines, true, 20, strict));
+    }
+
+    #[test]
+    fn mates_are_compared_without_overflow() {
+        use ScoreValue::*;
+        // Mating either way leaves a choice.
+        assert!(!only(Mate(3), Mate(7), true));
+        assert!(!only(Mate(1), Cp(950), true));
+        // The only move that doesn't get mated.
+        assert!(only(Cp(0), Mate(-1), true));
+        assert!(only(Cp(0), Mate(1), false));
+        assert!(only(Mate(i8::MAX), Mate(i8::MIN), true));
+        assert!(only(Cp(i32::MAX), Cp(i32::MIN), true));
+        assert!(!only(Cp(i32::MIN), Cp(i32::MIN), false));
+    }
+
+    #[test]
+    fn game_moves_are_checked_against_the_only_move() {
+        use ScoreValue::*;
+        let fen = Fen::from_position(Chess::default(), EnPassantMode::Legal);
+        let moves: Vec<String> = ["e2e4", "e7e5", "g1f3"]
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        let (positions, _) = build_analysis_positions(&fen, &moves, None).unwrap();
+        // Holding the balance, where anything else loses a piece.
+        let analysis_of = |best: &str, white: bool| {
+            let sign = if white { 1 } else { -1 };
+            MoveAnalysis {
+                best: vec![line(Cp(sign * 20), best), line(Cp(sign * -400), "a2a3")],
+                ..Default::default()
+            }
+        };
+        let mut analysis = vec![
+            analysis_of("e2e4", true),
+            analysis_of("d7d5", false),
+            analysis_of("g1f3", true),
+            analysis_of("b8c6", false),
+        ];
+        annotate_only_moves(
+            &positions,
+            &moves,
+            &mut analysis,
+            OnlyMoveThresholds::default(),
+        );
+        assert!(analysis[0].only_move && analysis[0].found_only_move);
+        assert!(analysis[1].only_move && !analysis[1].found_only_move);
+        assert!(analysis[2].only_move && analysis[2].found_only_move);
+        // No game move from the last position.
+        assert!(analysis[3].only_move && !analysis[3].found_only_move);
+
+        // Castling is recognized whichever way it is written.
+        let fen: Fen = "4k3/8/8/8/8/8/8/4K2R w K - 0 1".parse().unwrap();
+        let chess: Chess = fen.into_position(CastlingMode::Chess960).unwrap();
+        assert!(same_move(&chess, "e1g1", "e1h1"));
+        assert!(!same_move(&chess, "e1g1", "e1f1"));
+    }
+}
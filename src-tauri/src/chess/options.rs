@@ -0,0 +1,377 @@
+//! Applying a UCI option to every engine at once.
+//!
+//! The option is checked against the stored engine profiles, so that the frontend, which owns
+//! them, can store it where it is supported, and sent to the analysis engines that are currently
+//! running. Values are checked against what each engine advertises, and engines that don't
+//! support them are skipped.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+use vampirc_uci::uci::UciOptionConfig;
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::commands::get_engine_config;
+use super::types::EngineOption;
+
+/// Engine profiles as stored by the frontend, relative to the app data directory.
+const ENGINES_FILE: &str = "engines/engines.json";
+
+/// Time given to an engine to list its options.
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What happened to the option for one engine.
+#[derive(Serialize, Debug, Clone, Type, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum OptionApplyStatus {
+    /// The option was stored or sent to the engine.
+    Applied,
+    /// The engine is searching; the option is sent with its next configuration.
+    Deferred,
+    /// The engine doesn't support the option or value.
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Value of an engine profile setting, kept typed as profiles store it.
+#[derive(Serialize, Debug, Clone, Type, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SettingValue {
+    Number(i64),
+    Bool(bool),
+    Text(String),
+}
+
+/// Setting for the frontend to store in an engine profile.
+#[derive(Serialize, Debug, Clone, Type, PartialEq, Eq)]
+pub struct ProfileSetting {
+    /// Name of the option as the engine advertises it.
+    pub name: String,
+    pub value: SettingValue,
+}
+
+/// Result of applying an option to a stored engine profile or a running engine.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineOptionResult {
+    /// Name of the engine profile, or path of the running engine.
+    pub engine: String,
+    /// Tab of the running engine, `None` for stored profiles.
+    pub tab: Option<String>,
+    /// Setting to store in the profile, for stored profiles the option was applied to.
+    pub setting: Option<ProfileSetting>,
+    #[serde(flatten)]
+    pub status: OptionApplyStatus,
+}
+
+/// Check `value` against the option as advertised by an engine.
+///
+/// Returns the advertised option, whose name may differ in case, or why it can't be applied.
+pub fn validate_option<'a>(
+    options: &'a [UciOptionConfig],
+    name: &str,
+    value: &str,
+) -> Result<&'a UciOptionConfig, String> {
+    let option = options
+        .iter()
+        .find(|option| option.get_name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Option {} is not supported", name))?;
+
+    match option {
+        UciOptionConfig::Spin { min, max, .. } => {
+            let value: i64 = value
+                .parse()
+                .map_err(|_| format!("{} is not a number", value))?;
+            if min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max) {
+                return Err(format!(
+                    "{} is outside {}..{}",
+                    value,
+                    min.map(|m| m.to_string()).unwrap_or_default(),
+                    max.map(|m| m.to_string()).unwrap_or_default()
+                ));
+            }
+        }
+        UciOptionConfig::Check { .. } => {
+            if value != "true" && value != "false" {
+                return Err(format!("{} is not a boolean", value));
+            }
+        }
+        UciOptionConfig::Combo { var, .. } => {
+            if !var.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+                return Err(format!("{} is not one of {}", value, var.join(", ")));
+            }
+        }
+        UciOptionConfig::Button { .. } => {
+            return Err("Buttons can't be set to a value".to_string());
+        }
+        UciOptionConfig::String { .. } => {}
+    }
+    Ok(option)
+}
+
+/// The setting as stored in engine profiles, which keep numbers and booleans typed.
+fn profile_setting(option: &UciOptionConfig, value: &str) -> ProfileSetting {
+    let value = match option {
+        UciOptionConfig::Spin { .. } => value
+            .parse()
+            .map(SettingValue::Number)
+            .unwrap_or_else(|_| SettingValue::Text(value.to_string())),
+        UciOptionConfig::Check { .. } => SettingValue::Bool(value == "true"),
+        _ => SettingValue::Text(value.to_string()),
+    };
+    ProfileSetting {
+        name: option.get_name().to_string(),
+        value,
+    }
+}
+
+/// Applies an option to every configured and running engine.
+pub struct EngineOptionApplier<'a> {
+    state: tauri::State<'a, AppState>,
+    app: tauri::AppHandle,
+    /// Options advertised by each engine binary, queried at most once.
+    configs: HashMap<String, Result<Vec<UciOptionConfig>, String>>,
+}
+
+impl<'a> EngineOptionApplier<'a> {
+    /// Create a new `EngineOptionApplier` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>, app: tauri::AppHandle) -> Self {
+        Self {
+            state,
+            app,
+            configs: HashMap::new(),
+        }
+    }
+
+    async fn advertised_options(&mut self, path: &str) -> Result<Vec<UciOptionConfig>, String> {
+        if let Some(config) = self.configs.get(path) {
+            return config.clone();
+        }
+        let config = match tokio::time::timeout(
+            CONFIG_TIMEOUT,
+            get_engine_config(PathBuf::from(path), self.state.clone()),
+        )
+        .await
+        {
+            Ok(Ok(config)) => Ok(config.options),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("Engine did not list its options in time".to_string()),
+        };
+        self.configs.insert(path.to_string(), config.clone());
+        config
+    }
+
+    /// Apply the option everywhere, returning one result per profile and running engine.
+    /// Profiles aren't changed here: their results carry the setting to store.
+    ///
+    /// Running engines that are searching get the option with their next configuration,
+    /// unless `restart_running` is set, in which case their search is stopped and restarted
+    /// with the new value.
+    pub async fn apply(
+        mut self,
+        name: String,
+        value: String,
+        only_running: bool,
+        restart_running: bool,
+    ) -> Result<Vec<EngineOptionResult>, Error> {
+        let mut results = Vec::new();
+        if !only_running {
+            results.extend(self.apply_to_profiles(&name, &value).await?);
+        }
+        results.extend(self.apply_to_running(&name, &value, restart_running).await);
+        Ok(results)
+    }
+
+    async fn apply_to_profiles(
+        &mut self,
+        name: &str,
+        value: &str,
+    ) -> Result<Vec<EngineOptionResult>, Error> {
+        let path = self
+            .app
+            .path()
+            .resolve(ENGINES_FILE, BaseDirectory::AppData)?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let profiles: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        let Some(profiles) = profiles.as_array() else {
+            return Ok(Vec::new());
+        };
+
+        let mut results = Vec::new();
+        for profile in profiles {
+            let engine = profile
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let local_path = match profile.get("type").and_then(Value::as_str) {
+                Some("local") => profile.get("path").and_then(Value::as_str),
+                _ => None,
+            };
+            let mut setting = None;
+            let status = match local_path {
+                None => OptionApplyStatus::Skipped {
+                    reason: "Only local engines have UCI options".to_string(),
+                },
+                Some(local_path) => match self.advertised_options(local_path).await {
+                    Err(error) => OptionApplyStatus::Failed { error },
+                    Ok(options) => match validate_option(&options, name, value) {
+                        Err(reason) => OptionApplyStatus::Skipped { reason },
+                        Ok(option) => {
+                            setting = Some(profile_setting(option, value));
+                            OptionApplyStatus::Applied
+                        }
+                    },
+                },
+            };
+            results.push(EngineOptionResult {
+                engine,
+                tab: None,
+                setting,
+                status,
+            });
+        }
+        Ok(results)
+    }
+
+    async fn apply_to_running(
+        &self,
+        name: &str,
+        value: &str,
+        restart_running: bool,
+    ) -> Vec<EngineOptionResult> {
+        let processes: Vec<_> = self
+            .state
+            .engine_processes
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        let mut results = Vec::new();
+        for ((tab, engine), process) in processes {
+            let mut process = process.lock().await;
+            let advertised = process.advertised_options();
+            let status = match validate_option(&advertised, name, value) {
+                Err(reason) => OptionApplyStatus::Skipped { reason },
+                Ok(option) => {
+                    let option = EngineOption {
+                        name: option.get_name().to_string(),
+                        value: value.to_string(),
+                    };
+                    if process.running && !restart_running {
+                        process.pending_options.retain(|o| o.name != option.name);
+                        process.pending_options.push(option);
+                        OptionApplyStatus::Deferred
+                    } else {
+                        let applied = async {
+                            let was_running = process.running;
+                            if was_running {
+                                process.stop().await?;
+                            }
+                            process.set_option(&option.name, &option.value).await?;
+                            if was_running {
+                                let go_mode = process.go_mode.clone();
+                                process.go(&go_mode).await?;
+                            }
+                            Ok::<_, Error>(())
+                        }
+                        .await;
+                        match applied {
+                            Ok(()) => OptionApplyStatus::Applied,
+                            Err(e) => OptionApplyStatus::Failed {
+                                error: e.to_string(),
+                            },
+                        }
+                    }
+                }
+            };
+            results.push(EngineOptionResult {
+                engine,
+                tab: Some(tab),
+                setting: None,
+                status,
+            });
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Vec<UciOptionConfig> {
+        vec![
+            UciOptionConfig::Spin {
+                name: "Hash".to_string(),
+                default: Some(16),
+                min: Some(1),
+                max: Some(33554432),
+            },
+            UciOptionConfig::Check {
+                name: "Ponder".to_string(),
+                default: Some(false),
+            },
+            UciOptionConfig::Combo {
+                name: "Style".to_string(),
+                default: Some("Normal".to_string()),
+                var: vec!["Solid".to_string(), "Normal".to_string()],
+            },
+        ]
+    }
+
+    #[test]
+    fn spin_values_are_range_checked() {
+        let options = options();
+        assert!(validate_option(&options, "hash", "256").is_ok());
+        assert!(validate_option(&options, "Hash", "0").is_err());
+        assert!(validate_option(&options, "Hash", "lots").is_err());
+        assert!(validate_option(&options, "Threads", "4").is_err());
+    }
+
+    #[test]
+    fn check_and_combo_values_are_validated() {
+        let options = options();
+        assert!(validate_option(&options, "Ponder", "true").is_ok());
+        assert!(validate_option(&options, "Ponder", "yes").is_err());
+        assert!(validate_option(&options, "Style", "solid").is_ok());
+        assert!(validate_option(&options, "Style", "Wild").is_err());
+    }
+
+    #[test]
+    fn profile_settings_keep_their_type() {
+        let options = options();
+        let setting = |name: &str, value: &str| {
+            serde_json::to_value(profile_setting(
+                validate_option(&options, name, value).unwrap(),
+                value,
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            setting("hash", "256"),
+            serde_json::json!({ "name": "Hash", "value": 256 })
+        );
+        assert_eq!(
+            setting("Ponder", "true"),
+            serde_json::json!({ "name": "Ponder", "value": true })
+        );
+        assert_eq!(
+            setting("Style", "Solid"),
+            serde_json::json!({ "name": "Style", "value": "Solid" })
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Perft, to check move generation against reference node counts.
+//!
+//! When a legal move can't be played, counting the positions reachable from the position
+//! and comparing them with a reference engine tells whether our FEN parsing and castling
+//! mode agree with it. Positions are parsed as everywhere else, in Chess960 mode only when
+//! standard castling can't describe them. Depths are searched one after the other until the
+//! depth asked for or the time limit, and the last one searched is split by root move, as
+//! `divide` in most engines.
+
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::San, CastlingMode, Chess, EnPassantMode, Position};
+use specta::Type;
+
+use crate::error::Error;
+
+pub const MAX_PERFT_DEPTH: u32 = 6;
+
+const PERFT_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct PerftMove {
+    pub uci: String,
+    pub san: String,
+    pub nodes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PerftReport {
+    /// The position as parsed.
+    pub fen: String,
+    pub chess960: bool,
+    /// Positions reachable at each depth, from depth 1.
+    pub nodes: Vec<u64>,
+    /// Positions reachable after each root move at the deepest depth searched.
+    pub divide: Vec<PerftMove>,
+    /// Whether the time limit stopped the search before the depth asked for.
+    pub timed_out: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct PerftDivergence {
+    pub depth: u32,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PerftComparison {
+    pub report: PerftReport,
+    /// First depth whose node count differs from the expected one.
+    pub first_divergence: Option<PerftDivergence>,
+}
+
+/// Positions reachable from `position` in `depth` moves, or `None` past the deadline.
+fn count(position: &Chess, depth: u32, deadline: Option<Instant>) -> Option<u64> {
+    if depth == 0 {
+        return Some(1);
+    }
+    let moves = position.legal_moves();
+    if depth == 1 {
+        return Some(moves.len() as u64);
+    }
+    if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+        return None;
+    }
+    let mut nodes = 0;
+    for m in &moves {
+        let mut child = position.clone();
+        child.play_unchecked(m);
+        nodes += count(&child, depth - 1, deadline)?;
+    }
+    Some(nodes)
+}
+
+/// Node counts after each root move, searched in parallel.
+fn divide(position: &Chess, depth: u32, deadline: Option<Instant>) -> Option<Vec<PerftMove>> {
+    let mode = position.castles().mode();
+    let moves = position.legal_moves();
+    moves
+        .par_iter()
+        .map(|m| {
+            let mut child = position.clone();
+            child.play_unchecked(m);
+            Some(PerftMove {
+                uci: m.to_uci(mode).to_string(),
+                san: San::from_move(position, m).to_string(),
+                nodes: count(&child, depth - 1, deadline)?,
+            })
+        })
+        .collect()
+}
+
+fn parse_position(fen: &str) -> Result<Chess, Error> {
+    let fen = Fen::from_ascii(fen.as_bytes())?;
+    let mode = CastlingMode::detect(fen.as_setup());
+    Ok(fen.into_position(mode)?)
+}
+
+/// Perft of a position up to `depth`, capped at `MAX_PERFT_DEPTH`, stopping at `deadline`.
+pub fn run_perft(fen: &str, depth: u32, deadline: Option<Instant>) -> Result<PerftReport, Error> {
+    let position = parse_position(fen)?;
+    let mut report = PerftReport {
+        fen: Fen::from_position(position.clone(), EnPassantMode::Legal).to_string(),
+        chess960: position.castles().mode() == CastlingMode::Chess960,
+        nodes: Vec::new(),
+        divide: Vec::new(),
+        timed_out: false,
+    };
+    for depth in 1..=depth.clamp(1, MAX_PERFT_DEPTH) {
+        match divide(&position, depth, deadline) {
+            Some(divide) => {
+                report.nodes.push(divide.iter().map(|m| m.nodes).sum());
+                report.divide = divide;
+            }
+            None => {
+                report.timed_out = true;
+                break;
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn first_divergence(nodes: &[u64], expected: &[u64]) -> Option<PerftDivergence> {
+    nodes
+        .iter()
+        .zip(expected)
+        .enumerate()
+        .find(|(_, (actual, expected))| actual != expected)
+        .map(|(i, (&actual, &expected))| PerftDivergence {
+            depth: i as u32 + 1,
+            expected,
+            actual,
+        })
+}
+
+async fn perft_with_time_limit(fen: String, depth: u32) -> Result<PerftReport, Error> {
+    let deadline = Instant::now() + PERFT_TIME_LIMIT;
+    tokio::task::spawn_blocking(move || run_perft(&fen, depth, Some(deadline)))
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+}
+
+/// Node counts of a position at each depth up to `depth`, and by root move at the last one,
+/// for the debug panel.
+#[tauri::command]
+#[specta::specta]
+pub async fn perft(fen: String, depth: u32) -> Result<PerftReport, Error> {
+    perft_with_time_limit(fen, depth).await
+}
+
+/// Perft of a position compared with reference node counts, from depth 1.
+#[tauri::command]
+#[specta::specta]
+pub async fn compare_perft(
+    fen: String,
+    depth: u32,
+    expected: Vec<u64>,
+) -> Result<PerftComparison, Error> {
+    let report = perft_with_time_limit(fen, depth).await?;
+    Ok(PerftComparison {
+        first_divergence: first_divergence(&report.nodes, &expected),
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    const KIWIPETE: &str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+    #[test]
+    fn reference_counts() {
+        let report = run_perft(START, 5, None).unwrap();
+        assert_eq!(report.nodes, vec![20, 400, 8902, 197281, 4865609]);
+        assert!(!report.chess960);
+        assert_eq!(report.divide.len(), 20);
+        let e4 = report.divide.iter().find(|m| m.uci == "e2e4").unwrap();
+        assert_eq!(e4.san, "e4");
+        assert_eq!(e4.nodes, 405385);
+
+        let report = run_perft(KIWIPETE, 5, None).unwrap();
+        assert_eq!(report.nodes, vec![48, 2039, 97862, 4085603, 193690690]);
+        assert!(report
+            .divide
+            .iter()
+            .any(|m| m.uci == "e1g1" && m.san == "O-O"));
+    }
+
+    #[test]
+    fn chess960_and_limits() {
+        // Castling rights only Chess960 can describe, with the king on f1.
+        let report = run_perft("1r2k3/8/8/8/8/8/8/1R3K1R w HBb - 0 1", 1, None).unwrap();
+        assert!(report.chess960);
+        assert!(report
+            .divide
+            .iter()
+            .any(|m| m.uci == "f1h1" && m.san == "O-O"));
+
+        let report = run_perft(START, 9, Some(Instant::now())).unwrap();
+        assert_eq!(report.nodes, vec![20, 400]);
+        assert!(report.timed_out);
+        assert!(run_perft("nonsense", 1, None).is_err());
+    }
+
+    #[test]
+    fn divergences_are_found() {
+        assert_eq!(first_divergence(&[20, 400, 8902], &[20, 400, 8902]), None);
+        assert_eq!(
+            first_divergence(&[20, 400, 8901], &[20, 400, 8902, 197281]),
+            Some(PerftDivergence {
+                depth: 3,
+                expected: 8902,
+                actual: 8901
+            })
+        );
+    }
+}
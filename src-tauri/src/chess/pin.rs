@@ -0,0 +1,436 @@
+//! Pinned engine lines.
+//!
+//! A pinned line keeps a chosen first move in a tab's analysis even after it drops out of
+//! the MultiPV window. As long as the main search still reports the move, its line is just
+//! flagged as pinned. Otherwise the move is searched on its own with `go searchmoves` on a
+//! helper process, so the tab's main analysis is never interrupted, and the result is added
+//! to every best-moves payload for that position.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{info, warn};
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Position};
+use tauri::Manager;
+use tokio::sync::Mutex;
+use vampirc_uci::{parse_one, UciMessage};
+
+use crate::error::Error;
+use crate::scope::PathScope;
+use crate::AppState;
+
+use super::process::{parse_uci_attrs, EngineProcess};
+use super::tabs::rekey_tab_engines;
+use super::types::{BestMoves, EngineOptions, GoMode};
+use super::uci::EngineStdout;
+
+/// Deepest search of a pinned move outside the MultiPV window.
+const PINNED_MAX_DEPTH: u32 = 18;
+/// Depths the main analysis must gain before a pinned move is searched again.
+const PINNED_REFRESH_DEPTHS: u32 = 4;
+
+/// Engine process dedicated to searching pinned moves.
+pub struct PinEngine {
+    process: EngineProcess,
+    reader: EngineStdout,
+}
+
+impl PinEngine {
+    /// Search only `uci` in the position after `moves`, returning its principal variation.
+    async fn search(
+        &mut self,
+        fen: &str,
+        moves: &Vec<String>,
+        uci: &str,
+        depth: u32,
+    ) -> Result<Option<BestMoves>, Error> {
+        let parsed: Fen = fen.parse()?;
+        self.process.set_position(fen, moves).await?;
+        self.process
+            .go_searchmoves(&GoMode::Depth(depth), &[uci.to_string()])
+            .await?;
+
+        let mut best = None;
+        while let Some(line) = self.reader.next_line().await? {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    if let Ok(line) = parse_uci_attrs(attrs, &parsed, moves) {
+                        if line.multipv == 1 && line.uci_moves.first().is_some_and(|m| m == uci) {
+                            best = Some(line);
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => break,
+                _ => {}
+            }
+        }
+        self.process.running = false;
+        Ok(best)
+    }
+}
+
+/// First move pinned in a tab's analysis, and its evaluation in the current position.
+#[derive(Default)]
+pub struct PinnedLine {
+    uci: String,
+    /// Position (FEN and moves) the evaluation belongs to.
+    position: (String, Vec<String>),
+    evaluation: Option<BestMoves>,
+    /// A search of the pinned move is running.
+    searching: bool,
+    /// A search of the pinned move finished for this position, even if it found nothing.
+    searched: bool,
+    helper: Arc<Mutex<Option<PinEngine>>>,
+}
+
+impl PinnedLine {
+    pub fn new(uci: String) -> Self {
+        Self {
+            uci,
+            ..Default::default()
+        }
+    }
+
+    /// Pin of the same move for another tab, which searches it with its own helper.
+    pub fn duplicate(&self) -> Self {
+        Self::new(self.uci.clone())
+    }
+
+    /// Flag the pinned line among `lines`, or add its last known evaluation when the engine
+    /// no longer reports it.
+    ///
+    /// Returns the depth to search the pinned move to when its evaluation is missing or
+    /// lagging well behind the main analysis.
+    pub fn merge(
+        &mut self,
+        fen: &str,
+        moves: &[String],
+        lines: &mut Vec<BestMoves>,
+    ) -> Option<u32> {
+        if self.position.0 != fen || self.position.1 != moves {
+            self.position = (fen.to_string(), moves.to_vec());
+            self.evaluation = None;
+            self.searched = false;
+        }
+
+        let depth = lines.iter().map(|line| line.depth).max().unwrap_or(0);
+        for line in lines.iter_mut() {
+            line.pinned = line.uci_moves.first() == Some(&self.uci);
+        }
+        if let Some(line) = lines.iter().find(|line| line.pinned) {
+            self.evaluation = Some(line.clone());
+            return None;
+        }
+
+        if let Some(evaluation) = &self.evaluation {
+            lines.push(BestMoves {
+                multipv: lines.len() as u16 + 1,
+                pinned: true,
+                ..evaluation.clone()
+            });
+        }
+
+        let stale = match &self.evaluation {
+            None => !self.searched,
+            Some(evaluation) => {
+                evaluation.depth < PINNED_MAX_DEPTH
+                    && evaluation.depth + PINNED_REFRESH_DEPTHS <= depth
+            }
+        };
+        if stale && !self.searching {
+            self.searching = true;
+            Some(depth.clamp(1, PINNED_MAX_DEPTH))
+        } else {
+            None
+        }
+    }
+
+    /// Record the result of a search of `uci` in the position after `moves`.
+    ///
+    /// Results for another move or a position the analysis moved away from are dropped.
+    pub fn finish(&mut self, fen: &str, moves: &[String], uci: &str, line: Option<BestMoves>) {
+        if uci != self.uci {
+            return;
+        }
+        self.searching = false;
+        if self.position.0 != fen || self.position.1 != moves {
+            return;
+        }
+        self.searched = true;
+        if line.is_some() {
+            self.evaluation = line;
+        }
+    }
+}
+
+/// Whether `uci` is a legal move in the position after `moves`.
+fn is_legal(fen: &str, moves: &[String], uci: &str) -> Result<bool, Error> {
+    let fen: Fen = fen.parse()?;
+    let mut position: Chess = fen.into_position(CastlingMode::Chess960)?;
+    for m in moves {
+        let mv = UciMove::from_ascii(m.as_bytes())?.to_move(&position)?;
+        position.play_unchecked(&mv);
+    }
+    Ok(UciMove::from_ascii(uci.as_bytes())?
+        .to_move(&position)
+        .is_ok())
+}
+
+/// Merge the pinned line of a tab's engine, if any, into lines about to be emitted,
+/// starting a search of the pinned move when needed.
+pub fn apply_pinned_line(
+    app: &tauri::AppHandle,
+    key: &(String, String),
+    options: &EngineOptions,
+    lines: &mut Vec<BestMoves>,
+) {
+    let state = app.state::<AppState>();
+    let Some(mut pin) = state.pinned_lines.get_mut(key) else {
+        return;
+    };
+    let Some(depth) = pin.merge(&options.fen, &options.moves, lines) else {
+        return;
+    };
+
+    let app = app.clone();
+    let key = key.clone();
+    let (fen, moves) = (options.fen.clone(), options.moves.clone());
+    let uci = pin.uci.clone();
+    let helper = pin.helper.clone();
+    drop(pin);
+    tokio::spawn(async move {
+        let state = app.state::<AppState>();
+        let line = search_pinned(&helper, &state.path_scope, &key, &fen, &moves, &uci, depth)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to search pinned move {}: {}", uci, e);
+                None
+            });
+        // Found by its helper, as the tab may have been renamed in the meantime.
+        if let Some(mut pin) = state
+            .pinned_lines
+            .iter_mut()
+            .find(|pin| Arc::ptr_eq(&pin.helper, &helper))
+        {
+            pin.finish(&fen, &moves, &uci, line);
+        }
+    });
+}
+
+/// Search a pinned move on the helper process of a tab's engine, spawning it on first use.
+async fn search_pinned(
+    helper: &Mutex<Option<PinEngine>>,
+    scope: &PathScope,
+    key: &(String, String),
+    fen: &str,
+    moves: &Vec<String>,
+    uci: &str,
+    depth: u32,
+) -> Result<Option<BestMoves>, Error> {
+    if !is_legal(fen, moves, uci)? {
+        return Ok(None);
+    }
+    let mut helper = helper.lock().await;
+    if helper.is_none() {
+        info!("Starting pin engine: tab={} engine={}", key.0, key.1);
+        let (process, reader) = EngineProcess::new(PathBuf::from(&key.1), scope).await?;
+        *helper = Some(PinEngine { process, reader });
+    }
+    match helper.as_mut() {
+        Some(engine) => engine.search(fen, moves, uci, depth).await,
+        None => Ok(None),
+    }
+}
+
+/// Pins engine lines of analysis tabs.
+pub struct LinePinner<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> LinePinner<'a> {
+    /// Create a new `LinePinner` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    /// Pin `uci` as the first move to keep tracking in a tab's analysis with `engine`,
+    /// replacing any move pinned before.
+    ///
+    /// # Errors
+    /// Returns `Error` if `uci` is not a UCI move.
+    pub async fn pin(&self, tab: String, engine: String, uci: String) -> Result<(), Error> {
+        UciMove::from_ascii(uci.as_bytes())?;
+        // The helper engine is only started later, so fail now rather than then.
+        self.state.path_scope.check_engine(&engine)?;
+        let previous = self
+            .state
+            .pinned_lines
+            .insert((tab, engine), PinnedLine::new(uci));
+        if let Some(previous) = previous {
+            kill_helper(previous).await?;
+        }
+        Ok(())
+    }
+
+    /// Move the pinned lines of tab `old` to tab `new`, with their evaluations and helpers.
+    pub fn rename(&self, old: &str, new: &str) {
+        rekey_tab_engines(&self.state.pinned_lines, old, new);
+    }
+
+    /// Pin the moves pinned in tab `src` in tab `new` as well.
+    pub fn duplicate(&self, src: &str, new: &str) {
+        let pins: Vec<_> = self
+            .state
+            .pinned_lines
+            .iter()
+            .filter(|pin| pin.key().0 == src)
+            .map(|pin| (pin.key().1.clone(), pin.duplicate()))
+            .collect();
+        for (engine, pin) in pins {
+            self.state
+                .pinned_lines
+                .insert((new.to_string(), engine), pin);
+        }
+    }
+
+    /// Clear the pinned lines of tabs starting with `tab`, for `engine` or every engine.
+    pub async fn unpin(&self, tab: &str, engine: Option<&str>) -> Result<(), Error> {
+        let keys: Vec<_> = self
+            .state
+            .pinned_lines
+            .iter()
+            .map(|x| x.key().clone())
+            .filter(|key| key.0.starts_with(tab) && engine.is_none_or(|engine| key.1 == engine))
+            .collect();
+        for key in keys {
+            if let Some((_, pin)) = self.state.pinned_lines.remove(&key) {
+                kill_helper(pin).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn kill_helper(pin: PinnedLine) -> Result<(), Error> {
+    if let Some(mut engine) = pin.helper.lock().await.take() {
+        engine.process.kill().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vampirc_uci::uci::{Score, ScoreValue};
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn line(multipv: u16, depth: u32, cp: i32, moves: &[&str]) -> BestMoves {
+        BestMoves {
+            depth,
+            multipv,
+            score: Score {
+                value: ScoreValue::Cp(cp),
+                ..Default::default()
+            },
+            uci_moves: moves.iter().map(|m| m.to_string()).collect(),
+            san_moves: moves.iter().map(|m| m.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn payload(depth: u32) -> Vec<BestMoves> {
+        vec![
+            line(1, depth, 30, &["e2e4", "e7e5"]),
+            line(2, depth, 25, &["d2d4", "d7d5"]),
+            line(3, depth, 20, &["g1f3", "g8f6"]),
+        ]
+    }
+
+    #[test]
+    fn pinned_lines_in_the_multipv_window_are_flagged() {
+        let mut pin = PinnedLine::new("g1f3".to_string());
+        let mut lines = payload(10);
+        assert_eq!(pin.merge(START, &[], &mut lines), None);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines.iter().map(|l| l.pinned).collect::<Vec<_>>(),
+            [false, false, true]
+        );
+
+        // Once it drops out of the window, the last evaluation stands in for it.
+        let mut lines = payload(12);
+        lines.truncate(2);
+        assert_eq!(pin.merge(START, &[], &mut lines), None);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].pinned);
+        assert_eq!(lines[2].multipv, 3);
+        assert_eq!(lines[2].depth, 10);
+        assert_eq!(lines[2].uci_moves, ["g1f3", "g8f6"]);
+
+        // Far behind the main analysis, it is searched again.
+        let mut lines = payload(14);
+        lines.truncate(2);
+        assert_eq!(pin.merge(START, &[], &mut lines), Some(14));
+        assert!(lines[2].pinned);
+    }
+
+    #[test]
+    fn pinned_moves_outside_the_window_are_searched_once() {
+        let mut pin = PinnedLine::new("b1c3".to_string());
+        let mut lines = payload(20);
+        assert_eq!(pin.merge(START, &[], &mut lines), Some(PINNED_MAX_DEPTH));
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| !l.pinned));
+
+        // No second search while the first one runs.
+        let mut lines = payload(21);
+        assert_eq!(pin.merge(START, &[], &mut lines), None);
+        assert_eq!(lines.len(), 3);
+
+        pin.finish(START, &[], "b1c3", Some(line(1, 18, 10, &["b1c3", "d7d5"])));
+        let mut lines = payload(22);
+        assert_eq!(pin.merge(START, &[], &mut lines), None);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3].multipv, 4);
+        assert!(lines[3].pinned);
+        assert_eq!(lines[3].uci_moves, ["b1c3", "d7d5"]);
+
+        // A search that found nothing is not repeated for the same position.
+        let moves = vec!["e2e4".to_string()];
+        let mut lines = payload(5);
+        assert_eq!(pin.merge(START, &moves, &mut lines), Some(5));
+        pin.finish(START, &moves, "b1c3", None);
+        let mut lines = payload(30);
+        assert_eq!(pin.merge(START, &moves, &mut lines), None);
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn stale_search_results_are_dropped() {
+        let mut pin = PinnedLine::new("b1c3".to_string());
+        let mut lines = payload(8);
+        assert_eq!(pin.merge(START, &[], &mut lines), Some(8));
+
+        // The analysis moved on before the search finished.
+        let moves = vec!["e2e4".to_string()];
+        let mut lines = payload(8);
+        assert_eq!(pin.merge(START, &moves, &mut lines), None);
+        pin.finish(START, &[], "b1c3", Some(line(1, 8, 10, &["b1c3"])));
+        let mut lines = payload(8);
+        assert_eq!(pin.merge(START, &moves, &mut lines), Some(8));
+        assert_eq!(lines.len(), 3);
+
+        // Results for a move that is no longer pinned are ignored.
+        pin.finish(START, &moves, "d2d4", Some(line(1, 8, 10, &["d2d4"])));
+        assert!(pin.searching);
+        assert!(pin.evaluation.is_none());
+    }
+
+    #[test]
+    fn pinned_moves_must_be_legal() {
+        assert!(is_legal(START, &[], "g1f3").unwrap());
+        assert!(!is_legal(START, &["g1f3".to_string()], "g1f3").unwrap());
+        assert!(is_legal(START, &["e2e4".to_string()], "e7e5").unwrap());
+    }
+}
@@ -0,0 +1,874 @@
+//! Play sessions against a UCI engine.
+//!
+//! A `PlaySession` holds the authoritative state of a game between the user and an engine.
+//! Every change to the move list bumps a generation counter, and each `go` sent to the engine
+//! is queued with the generation it was issued for. Since UCI answers every `go` with exactly
+//! one `bestmove`, results are matched to their search in order and stale ones (e.g. a search
+//! that was still running when the user took back a move) are discarded.
+//!
+//! Timed sessions keep a `GameClock` for each position, so taking moves back also gives the
+//! time back, and tell the engine the time left on both clocks instead of using `go_mode`.
+//!
+//! Sessions can also judge the player's moves as the game goes. A second engine process
+//! quickly evaluates the positions around each of them in the background, and the lines it
+//! finds are kept by ply so the full report at the end of the game doesn't search them again.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Position,
+    Setup,
+};
+use tauri::Manager;
+use tauri_specta::Event;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::accuracy::{judge_moves, DEFAULT_RATING};
+use super::analysis::search_position;
+use super::effects::MoveEffects;
+use super::evaluation::game_termination;
+use super::process::EngineProcess;
+use super::time_control::{GameClock, TimeControl};
+use super::types::{
+    BestMoves, EngineColor, EngineLog, EngineMovePlayed, EngineOption, EngineOptions,
+    GameTermination, GoMode, MoveAnalysis, PlayAccuracy, PlayAccuracyUpdated, PlayClock,
+    PlaySessionConfig,
+};
+use super::uci::EngineStdout;
+
+/// A move accepted from the engine for the current generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayedMove {
+    pub uci: String,
+    pub san: String,
+    pub effects: MoveEffects,
+    pub generation: u32,
+}
+
+/// Authoritative state of a game played against an engine.
+pub struct PlaySession {
+    pub config: PlaySessionConfig,
+    /// Positions from the start up to the current one, used for repetition detection.
+    history: Vec<Chess>,
+    moves: Vec<String>,
+    generation: u32,
+    pending: VecDeque<u32>,
+    /// Lines of the quick evaluation of each position of `history`, once evaluated.
+    evaluations: Vec<Option<Vec<BestMoves>>>,
+    /// Clocks in each position of `history`, in timed sessions.
+    clocks: Vec<GameClock>,
+    /// When the side to move started thinking.
+    turn_started: Instant,
+}
+
+impl PlaySession {
+    /// Create a session starting from the configured FEN, or from the position of its odds.
+    pub fn new(mut config: PlaySessionConfig) -> Result<Self, Error> {
+        if let Some(odds) = &config.odds {
+            config.fen = odds.fen()?;
+        }
+        let fen: Fen = config.fen.parse()?;
+        let start: Chess = fen.into_position(CastlingMode::Chess960)?;
+        Ok(Self {
+            config,
+            history: vec![start],
+            moves: Vec::new(),
+            generation: 0,
+            pending: VecDeque::new(),
+            evaluations: vec![None],
+            clocks: Vec::new(),
+            turn_started: Instant::now(),
+        })
+    }
+
+    /// Play with clocks set for `control`, from the current position.
+    pub fn set_time_control(&mut self, control: &TimeControl) {
+        self.clocks = GameClock::new(control).into_iter().collect();
+        self.turn_started = Instant::now();
+    }
+
+    fn clock(&self) -> Option<&GameClock> {
+        self.clocks.last()
+    }
+
+    /// Time left on both clocks, in timed sessions.
+    pub fn clock_state(&self) -> Option<PlayClock> {
+        let clock = self.clock()?;
+        let millis = |color| clock.remaining(color).as_millis().min(u32::MAX.into()) as u32;
+        Some(PlayClock {
+            white: millis(Color::White),
+            black: millis(Color::Black),
+            flagged: clock.flagged().map(|color| match color {
+                Color::White => EngineColor::White,
+                Color::Black => EngineColor::Black,
+            }),
+        })
+    }
+
+    /// Search mode of the engine's next move.
+    pub fn go_mode(&self) -> GoMode {
+        match self.clock() {
+            Some(clock) => clock.go_mode(),
+            None => self.config.go_mode.clone(),
+        }
+    }
+
+    fn position(&self) -> &Chess {
+        self.history
+            .last()
+            .expect("history holds the starting position")
+    }
+
+    /// Moves played since the starting position, in UCI notation.
+    pub fn moves(&self) -> &Vec<String> {
+        &self.moves
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// How the game is over, if it is.
+    pub fn termination(&self) -> Option<GameTermination> {
+        if self.clock().is_some_and(|clock| clock.flagged().is_some()) {
+            return Some(GameTermination::Timeout);
+        }
+        game_termination(&self.history)
+    }
+
+    /// Side played by the user.
+    pub fn player_color(&self) -> Color {
+        !Color::from(self.config.engine_color)
+    }
+
+    /// Whether the engine should be searching for a move in the current position.
+    pub fn engine_to_move(&self) -> bool {
+        self.position().turn() == self.config.engine_color.into() && self.termination().is_none()
+    }
+
+    /// The game so far as PGN. Games not starting from the standard position record it in
+    /// `SetUp` and `FEN` headers, and odds games also name their odds in an `Odds` header.
+    pub fn pgn(&self) -> String {
+        let mut pgn = String::new();
+        if let Some(clock) = self.clocks.first() {
+            pgn.push_str(&format!("[TimeControl \"{}\"]\n", clock.control().to_pgn()));
+        }
+        if let Some(odds) = &self.config.odds {
+            pgn.push_str(&format!("[Odds \"{}\"]\n", odds.description()));
+        }
+        let start = &self.history[0];
+        if start.clone().into_setup(EnPassantMode::Legal) != Setup::initial() {
+            let fen = Fen::from_position(start.clone(), EnPassantMode::Legal);
+            pgn.push_str("[SetUp \"1\"]\n");
+            pgn.push_str(&format!("[FEN \"{}\"]\n", fen));
+        }
+        let result = match self.termination() {
+            None => "*",
+            Some(GameTermination::Checkmate) => match self.position().turn() {
+                Color::White => "0-1",
+                Color::Black => "1-0",
+            },
+            // Running out of time only loses if the opponent could still mate.
+            Some(GameTermination::Timeout)
+                if !self
+                    .position()
+                    .has_insufficient_material(!self.position().turn()) =>
+            {
+                match self.position().turn() {
+                    Color::White => "0-1",
+                    Color::Black => "1-0",
+                }
+            }
+            Some(_) => "1/2-1/2",
+        };
+        pgn.push_str(&format!("[Result \"{}\"]\n\n", result));
+
+        let mut movetext = Vec::new();
+        for (ply, (position, uci)) in self.history.iter().zip(&self.moves).enumerate() {
+            let number = start.fullmoves().get() as usize + (ply + start.turn().fold_wb(0, 1)) / 2;
+            if position.turn() == Color::White {
+                movetext.push(format!("{}.", number));
+            } else if ply == 0 {
+                movetext.push(format!("{}...", number));
+            }
+            let m = UciMove::from_ascii(uci.as_bytes())
+                .ok()
+                .and_then(|uci| uci.to_move(position).ok())
+                .expect("moves of the session are legal");
+            movetext.push(SanPlus::from_move(position.clone(), &m).to_string());
+        }
+        movetext.push(result.to_string());
+        pgn.push_str(&movetext.join(" "));
+        pgn.push('\n');
+        pgn
+    }
+
+    /// Play a move the side to move thought about for `elapsed`, unless their time ran out.
+    fn play(&mut self, uci: &str, elapsed: Duration) -> Result<(SanPlus, MoveEffects), Error> {
+        let uci = UciMove::from_ascii(uci.as_bytes())?;
+        let mut position = self.position().clone();
+        let m = uci.to_move(&position)?;
+        if let Some(clock) = self.clocks.last_mut() {
+            let mut after = clock.clone();
+            if !after.press(position.turn(), elapsed) {
+                // The flag stays down in this position.
+                *clock = after;
+                return Err(Error::TimeForfeit);
+            }
+            self.clocks.push(after);
+        }
+        self.turn_started = Instant::now();
+        let effects = MoveEffects::new(&position, &m);
+        let san = SanPlus::from_move_and_play_unchecked(&mut position, &m);
+        self.history.push(position);
+        self.moves.push(uci.to_string());
+        self.evaluations.push(None);
+        Ok((san, effects))
+    }
+
+    /// Apply a move made by the player, returning the new generation.
+    pub fn submit_player_move(&mut self, uci: &str) -> Result<u32, Error> {
+        if self.termination().is_some() || self.engine_to_move() {
+            return Err(Error::NotPlayerTurn);
+        }
+        self.play(uci, self.turn_started.elapsed())?;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(self.generation)
+    }
+
+    /// Rewind the last `plies` moves, returning the new generation.
+    pub fn takeback(&mut self, plies: usize) -> u32 {
+        let keep = self.moves.len().saturating_sub(plies);
+        self.moves.truncate(keep);
+        self.history.truncate(keep + 1);
+        self.evaluations.truncate(keep + 1);
+        if !self.clocks.is_empty() {
+            self.clocks.truncate(keep + 1);
+            self.turn_started = Instant::now();
+        }
+        self.generation = self.generation.wrapping_add(1);
+        self.generation
+    }
+
+    /// Record that a search was started for the current generation.
+    pub fn begin_search(&mut self) -> u32 {
+        self.pending.push_back(self.generation);
+        self.generation
+    }
+
+    /// Handle a `bestmove` from the engine.
+    ///
+    /// Returns the played move if the search it answers was issued for the current
+    /// generation; results of outdated searches are discarded.
+    pub fn on_best_move(&mut self, uci: &str) -> Option<PlayedMove> {
+        let generation = self.pending.pop_front()?;
+        if generation != self.generation || !self.engine_to_move() {
+            debug!(
+                "Discarding stale bestmove {} (generation {}, current {})",
+                uci, generation, self.generation
+            );
+            return None;
+        }
+        let (san, effects) = self.play(uci, self.turn_started.elapsed()).ok()?;
+        self.generation = self.generation.wrapping_add(1);
+        Some(PlayedMove {
+            uci: uci.to_string(),
+            san: san.to_string(),
+            effects,
+            generation: self.generation,
+        })
+    }
+
+    /// Plies of the positions still to evaluate before and after the player's moves, earliest
+    /// first. Positions where the game is over need no evaluation.
+    pub fn positions_to_evaluate(&self) -> Vec<usize> {
+        let player = self.player_color();
+        let mut plies: Vec<usize> = (0..self.moves.len())
+            .filter(|&ply| self.history[ply].turn() == player)
+            .flat_map(|ply| [ply, ply + 1])
+            .filter(|&ply| {
+                self.evaluations[ply].is_none() && game_termination(&self.history[..=ply]).is_none()
+            })
+            .collect();
+        plies.dedup();
+        plies
+    }
+
+    /// Record the lines found in the position reached by `moves` after `ply` of them.
+    ///
+    /// Returns `false` if the moves were taken back in the meantime, in which case the lines
+    /// are dropped.
+    pub fn record_evaluation(
+        &mut self,
+        ply: usize,
+        moves: &[String],
+        lines: Vec<BestMoves>,
+    ) -> bool {
+        if moves.len() != ply || !self.moves.starts_with(moves) {
+            return false;
+        }
+        self.evaluations[ply] = Some(lines);
+        true
+    }
+
+    /// Lines of the positions evaluated so far, by ply.
+    pub fn evaluations(&self) -> &Vec<Option<Vec<BestMoves>>> {
+        &self.evaluations
+    }
+
+    /// Accuracy of the player's moves evaluated so far.
+    pub fn accuracy(&self) -> PlayAccuracy {
+        let setups: Vec<_> = self
+            .history
+            .iter()
+            .map(|position| position.clone().into_setup(EnPassantMode::Legal))
+            .collect();
+        let termination = self.termination();
+        let last = self.evaluations.len() - 1;
+        let analysis: Vec<_> = self
+            .evaluations
+            .iter()
+            .enumerate()
+            .map(|(ply, lines)| MoveAnalysis {
+                best: lines.clone().unwrap_or_default(),
+                termination: termination.filter(|_| ply == last),
+                ..Default::default()
+            })
+            .collect();
+        let rating = self
+            .config
+            .live_accuracy
+            .as_ref()
+            .and_then(|config| config.rating)
+            .unwrap_or(DEFAULT_RATING);
+        let (player, moves) = judge_moves(&setups, &analysis, self.player_color(), rating);
+        PlayAccuracy {
+            current_accuracy: player.accuracy,
+            expected_points_lost: player.expected_points_lost,
+            moves,
+        }
+    }
+}
+
+/// Engine process quickly evaluating the positions of a play session.
+pub struct Evaluator {
+    process: EngineProcess,
+    reader: EngineStdout,
+}
+
+/// A play session and the engine process serving it.
+#[derive(Clone)]
+pub struct PlaySessionHandle {
+    pub session: Arc<Mutex<PlaySession>>,
+    pub process: Arc<Mutex<EngineProcess>>,
+    pub engine: PathBuf,
+    /// Spawned with the first position to evaluate.
+    pub evaluator: Arc<Mutex<Option<Evaluator>>>,
+}
+
+/// Manager for play sessions stored in the application state.
+pub struct PlaySessionManager<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> PlaySessionManager<'a> {
+    /// Create a new `PlaySessionManager` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    fn handle(&self, id: &str) -> Result<PlaySessionHandle, Error> {
+        self.state
+            .play_sessions
+            .get(id)
+            .map(|h| h.clone())
+            .ok_or_else(|| Error::PlaySessionNotFound(id.to_string()))
+    }
+
+    /// Spawn the engine for a new session and start searching if it moves first.
+    ///
+    /// Returns the identifier of the new session.
+    pub async fn start(
+        &self,
+        engine: String,
+        config: PlaySessionConfig,
+        app: tauri::AppHandle,
+    ) -> Result<String, Error> {
+        let time_control = config
+            .time_control
+            .as_ref()
+            .map(|choice| choice.resolve(&app))
+            .transpose()?;
+        let mut session = PlaySession::new(config)?;
+        if let Some(time_control) = &time_control {
+            session.set_time_control(time_control);
+        }
+        let (mut process, mut reader) =
+            EngineProcess::new(PathBuf::from(&engine), &self.state.path_scope).await?;
+        for option in &session.config.extra_options {
+            process.set_option(&option.name, &option.value).await?;
+        }
+        let process = Arc::new(Mutex::new(process));
+        search_if_engine_to_move(&mut session, &process).await?;
+
+        let id = Uuid::new_v4().to_string();
+        let handle = PlaySessionHandle {
+            session: Arc::new(Mutex::new(session)),
+            process,
+            engine: PathBuf::from(&engine),
+            evaluator: Arc::new(Mutex::new(None)),
+        };
+        self.state.play_sessions.insert(id.clone(), handle.clone());
+
+        let id_cloned = id.clone();
+        tokio::spawn(async move {
+            info!("Play session started: {} engine={}", id_cloned, engine);
+            while let Ok(Some(line)) = reader.next_line().await {
+                // Matched textually so `bestmove (none)` still consumes its pending search.
+                if let Some(best_move) = line
+                    .strip_prefix("bestmove")
+                    .and_then(|rest| rest.split_whitespace().next())
+                {
+                    let mut session = handle.session.lock().await;
+                    if let Some(played) = session.on_best_move(best_move) {
+                        let accuracy = session
+                            .config
+                            .live_accuracy
+                            .is_some()
+                            .then(|| session.accuracy());
+                        EngineMovePlayed {
+                            session: id_cloned.clone(),
+                            uci: played.uci,
+                            san: played.san,
+                            effects: played.effects,
+                            fen: session.config.fen.clone(),
+                            moves: session.moves().clone(),
+                            generation: played.generation,
+                            termination: session.termination(),
+                            accuracy,
+                            clock: session.clock_state(),
+                        }
+                        .emit(&app)
+                        .ok();
+                    }
+                    drop(session);
+                    handle.process.lock().await.running = false;
+                }
+                handle
+                    .process
+                    .lock()
+                    .await
+                    .logs
+                    .push(EngineLog::Engine(line));
+            }
+            info!("Play session engine finished: {}", id_cloned);
+        });
+
+        Ok(id)
+    }
+
+    /// Apply the player's move and let the engine answer, returning the new generation.
+    ///
+    /// If the session shows the player's accuracy, the move is also evaluated in the
+    /// background and `PlayAccuracyUpdated` is emitted once that's done.
+    pub async fn submit_player_move(
+        &self,
+        id: &str,
+        uci: &str,
+        app: tauri::AppHandle,
+    ) -> Result<u32, Error> {
+        let handle = self.handle(id)?;
+        let mut session = handle.session.lock().await;
+        let generation = session.submit_player_move(uci)?;
+        search_if_engine_to_move(&mut session, &handle.process).await?;
+        if session.config.live_accuracy.is_some() {
+            let id = id.to_string();
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = evaluate_player_moves(&id, &handle, &app).await {
+                    error!("Failed to evaluate moves of play session {}: {}", id, e);
+                }
+            });
+        }
+        Ok(generation)
+    }
+
+    /// The start position, moves and quick evaluations of a session, for its full report.
+    pub async fn report_input(
+        &self,
+        id: &str,
+    ) -> Result<(String, Vec<String>, Vec<Option<Vec<BestMoves>>>), Error> {
+        let handle = self.handle(id)?;
+        let session = handle.session.lock().await;
+        Ok((
+            session.config.fen.clone(),
+            session.moves().clone(),
+            session.evaluations().clone(),
+        ))
+    }
+
+    /// The game of a session so far as PGN.
+    pub async fn pgn(&self, id: &str) -> Result<String, Error> {
+        let handle = self.handle(id)?;
+        let session = handle.session.lock().await;
+        Ok(session.pgn())
+    }
+
+    /// Take back `plies` moves, stopping any search in progress.
+    pub async fn takeback(&self, id: &str, plies: usize) -> Result<u32, Error> {
+        let handle = self.handle(id)?;
+        let mut session = handle.session.lock().await;
+        let generation = session.takeback(plies);
+        {
+            let mut process = handle.process.lock().await;
+            if process.running {
+                process.stop().await?;
+            }
+        }
+        search_if_engine_to_move(&mut session, &handle.process).await?;
+        Ok(generation)
+    }
+
+    /// Remove a session and kill its engine.
+    pub async fn end(&self, id: &str) -> Result<(), Error> {
+        if let Some((_, handle)) = self.state.play_sessions.remove(id) {
+            handle.process.lock().await.kill().await?;
+            if let Some(evaluator) = handle.evaluator.lock().await.as_mut() {
+                evaluator.process.kill().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send the session's position to the engine and start a search if it's the engine's turn.
+async fn search_if_engine_to_move(
+    session: &mut PlaySession,
+    process: &Mutex<EngineProcess>,
+) -> Result<(), Error> {
+    if !session.engine_to_move() {
+        return Ok(());
+    }
+    let mut process = process.lock().await;
+    process
+        .set_position(&session.config.fen, session.moves())
+        .await?;
+    process.go(&session.go_mode()).await?;
+    // Queued only once `go` was sent, so every pending entry gets exactly one `bestmove`.
+    session.begin_search();
+    Ok(())
+}
+
+/// Evaluate the positions around the player's moves that weren't evaluated yet, then emit
+/// the player's accuracy.
+///
+/// Holding the evaluator for the whole run keeps runs for consecutive moves from
+/// interleaving; a later run finds the positions an earlier one already evaluated.
+async fn evaluate_player_moves(
+    id: &str,
+    handle: &PlaySessionHandle,
+    app: &tauri::AppHandle,
+) -> Result<(), Error> {
+    let mut evaluator = handle.evaluator.lock().await;
+    loop {
+        let (fen, moves, nodes) = {
+            let session = handle.session.lock().await;
+            let Some(config) = session.config.live_accuracy.as_ref() else {
+                return Ok(());
+            };
+            let Some(&ply) = session.positions_to_evaluate().first() else {
+                break;
+            };
+            (
+                session.config.fen.clone(),
+                session.moves()[..ply].to_vec(),
+                config.nodes,
+            )
+        };
+        if evaluator.is_none() {
+            let state = app.state::<AppState>();
+            let (process, reader) =
+                EngineProcess::new(handle.engine.clone(), &state.path_scope).await?;
+            *evaluator = Some(Evaluator { process, reader });
+        }
+        let Evaluator { process, reader } = evaluator.as_mut().expect("evaluator was spawned");
+        let options = EngineOptions {
+            fen,
+            moves: moves.clone(),
+            extra_options: vec![EngineOption {
+                name: "MultiPV".to_string(),
+                value: "2".to_string(),
+            }],
+        };
+        let (lines, _) = search_position(process, reader, options, &GoMode::Nodes(nodes)).await?;
+        let mut session = handle.session.lock().await;
+        if !session.record_evaluation(moves.len(), &moves, lines) {
+            debug!("Dropping evaluation of a taken back position in {}", id);
+        }
+    }
+
+    let session = handle.session.lock().await;
+    PlayAccuracyUpdated {
+        session: id.to_string(),
+        generation: session.generation(),
+        accuracy: session.accuracy(),
+    }
+    .emit(app)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::accuracy::MoveJudgement;
+    use crate::chess::odds::{Odds, OddsSpec};
+    use crate::chess::types::{EngineColor, GoMode};
+    use vampirc_uci::uci::{Score, ScoreValue};
+
+    fn session(engine_color: EngineColor) -> PlaySession {
+        PlaySession::new(PlaySessionConfig {
+            fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1".to_string(),
+            engine_color,
+            go_mode: GoMode::Depth(10),
+            extra_options: Vec::new(),
+            live_accuracy: None,
+            odds: None,
+            time_control: None,
+        })
+        .unwrap()
+    }
+
+    fn moves(uci: &str) -> Vec<String> {
+        uci.split_whitespace().map(String::from).collect()
+    }
+
+    fn line(cp: i32) -> Vec<BestMoves> {
+        vec![BestMoves {
+            score: Score {
+                value: ScoreValue::Cp(cp),
+                ..Default::default()
+            },
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn engine_move_for_current_generation_is_played() {
+        let mut s = session(EngineColor::Black);
+        s.submit_player_move("e2e4").unwrap();
+        assert!(s.engine_to_move());
+        s.begin_search();
+
+        let played = s.on_best_move("e7e5").unwrap();
+        assert_eq!(played.san, "e5");
+        assert_eq!(played.effects, MoveEffects::default());
+        assert_eq!(s.moves(), &vec!["e2e4".to_string(), "e7e5".to_string()]);
+        assert!(!s.engine_to_move());
+    }
+
+    #[test]
+    fn player_cannot_move_on_engine_turn() {
+        let mut s = session(EngineColor::White);
+        assert!(matches!(
+            s.submit_player_move("e2e4"),
+            Err(Error::NotPlayerTurn)
+        ));
+    }
+
+    #[test]
+    fn takeback_racing_bestmove_discards_result() {
+        let mut s = session(EngineColor::Black);
+        s.submit_player_move("e2e4").unwrap();
+        s.begin_search();
+
+        // The user takes back while the engine is still thinking.
+        s.takeback(1);
+        assert!(s.moves().is_empty());
+
+        // The engine answers the stopped search afterwards.
+        assert_eq!(s.on_best_move("e7e5"), None);
+        assert!(s.moves().is_empty());
+    }
+
+    #[test]
+    fn stale_bestmove_is_not_applied_to_new_position() {
+        let mut s = session(EngineColor::Black);
+        s.submit_player_move("e2e4").unwrap();
+        s.begin_search();
+        s.takeback(1);
+        s.submit_player_move("d2d4").unwrap();
+        s.begin_search();
+
+        // e7e5 is legal after d4 too, so only the generation tells them apart.
+        assert_eq!(s.on_best_move("e7e5"), None);
+        let played = s.on_best_move("d7d5").unwrap();
+        assert_eq!(played.generation, s.generation());
+        assert_eq!(s.moves(), &vec!["d2d4".to_string(), "d7d5".to_string()]);
+    }
+
+    #[test]
+    fn bestmove_without_pending_search_is_ignored() {
+        let mut s = session(EngineColor::Black);
+        s.submit_player_move("e2e4").unwrap();
+        assert_eq!(s.on_best_move("e7e5"), None);
+        assert_eq!(s.moves().len(), 1);
+    }
+
+    #[test]
+    fn positions_around_player_moves_are_evaluated_once() {
+        let mut s = session(EngineColor::Black);
+        assert!(s.positions_to_evaluate().is_empty());
+        s.submit_player_move("e2e4").unwrap();
+        assert_eq!(s.positions_to_evaluate(), vec![0, 1]);
+
+        assert!(s.record_evaluation(0, &[], line(20)));
+        assert!(s.record_evaluation(1, &moves("e2e4"), line(30)));
+        s.begin_search();
+        s.on_best_move("e7e5").unwrap();
+        s.submit_player_move("g1f3").unwrap();
+        // The position after the engine's move comes before the player's next one.
+        assert_eq!(s.positions_to_evaluate(), vec![2, 3]);
+
+        // Evaluations finished after a takeback belong to moves no longer played.
+        s.takeback(3);
+        assert!(!s.record_evaluation(2, &moves("e2e4 e7e5"), line(25)));
+        s.submit_player_move("d2d4").unwrap();
+        assert!(!s.record_evaluation(1, &moves("e2e4"), line(30)));
+        assert_eq!(s.positions_to_evaluate(), vec![1]);
+    }
+
+    #[test]
+    fn accuracy_judges_the_player_moves_evaluated_so_far() {
+        let mut s = session(EngineColor::White);
+        s.begin_search();
+        s.on_best_move("e2e4").unwrap();
+        s.submit_player_move("f7f6").unwrap();
+        s.begin_search();
+        s.on_best_move("d2d4").unwrap();
+        s.submit_player_move("g7g5").unwrap();
+        assert_eq!(s.positions_to_evaluate(), vec![1, 2, 3, 4]);
+        assert!(s.accuracy().moves.is_empty());
+
+        s.record_evaluation(1, &moves("e2e4"), line(30));
+        s.record_evaluation(2, &moves("e2e4 f7f6"), line(80));
+        let accuracy = s.accuracy();
+        assert_eq!(accuracy.moves.len(), 1);
+        assert_eq!(accuracy.moves[0].ply, 1);
+
+        s.record_evaluation(3, &moves("e2e4 f7f6 d2d4"), line(90));
+        s.begin_search();
+        // The mate that follows needs no evaluation.
+        s.on_best_move("d1h5").unwrap();
+        assert_eq!(s.termination(), Some(GameTermination::Checkmate));
+        assert_eq!(s.positions_to_evaluate(), vec![4]);
+        s.record_evaluation(4, &moves("e2e4 f7f6 d2d4 g7g5"), line(2000));
+        assert!(s.positions_to_evaluate().is_empty());
+
+        let accuracy = s.accuracy();
+        assert_eq!(accuracy.moves.len(), 2);
+        assert_eq!(accuracy.moves[1].judgement, MoveJudgement::Blunder);
+        assert!(accuracy.current_accuracy < accuracy.moves[0].accuracy);
+    }
+
+    #[test]
+    fn odds_sessions_start_from_the_odds_position() {
+        let mut s = PlaySession::new(PlaySessionConfig {
+            fen: String::new(),
+            engine_color: EngineColor::White,
+            go_mode: GoMode::Depth(10),
+            extra_options: Vec::new(),
+            live_accuracy: None,
+            odds: Some(OddsSpec {
+                odds: Odds::PawnAndMove,
+                giver: EngineColor::White,
+            }),
+            time_control: None,
+        })
+        .unwrap();
+        assert_eq!(
+            s.config.fen,
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPP1PP/RNBQKBNR b KQkq - 0 1"
+        );
+        // The receiver moves first.
+        assert!(!s.engine_to_move());
+        s.submit_player_move("e7e5").unwrap();
+        s.begin_search();
+        s.on_best_move("e2e4").unwrap();
+        assert_eq!(
+            s.pgn(),
+            "[Odds \"Pawn and move odds given by White\"]\n\
+             [SetUp \"1\"]\n\
+             [FEN \"rnbqkbnr/pppppppp/8/8/8/8/PPPPP1PP/RNBQKBNR b KQkq - 0 1\"]\n\
+             [Result \"*\"]\n\n\
+             1... e5 2. e4 *\n"
+        );
+    }
+
+    #[test]
+    fn standard_sessions_have_no_setup_headers() {
+        let mut s = session(EngineColor::Black);
+        s.submit_player_move("f2f3").unwrap();
+        s.begin_search();
+        s.on_best_move("e7e5").unwrap();
+        s.submit_player_move("g2g4").unwrap();
+        s.begin_search();
+        s.on_best_move("d8h4").unwrap();
+        assert_eq!(s.pgn(), "[Result \"0-1\"]\n\n1. f3 e5 2. g4 Qh4# 0-1\n");
+    }
+
+    fn timed(spec: &str) -> PlaySession {
+        let mut s = session(EngineColor::Black);
+        s.set_time_control(&TimeControl::parse(spec).unwrap());
+        s
+    }
+
+    #[test]
+    fn timed_sessions_search_on_the_clock() {
+        let mut s = timed("60+1");
+        assert!(matches!(s.go_mode(), GoMode::PlayersTime(_)));
+        s.play("e2e4", Duration::from_secs(10)).unwrap();
+        let clock = s.clock_state().unwrap();
+        assert_eq!(clock.white, 51_000);
+        assert_eq!(clock.black, 60_000);
+        assert_eq!(clock.flagged, None);
+
+        assert!(matches!(
+            session(EngineColor::Black).go_mode(),
+            GoMode::Depth(10)
+        ));
+    }
+
+    #[test]
+    fn takeback_gives_the_time_back() {
+        let mut s = timed("60");
+        s.play("e2e4", Duration::from_secs(10)).unwrap();
+        s.play("e7e5", Duration::from_secs(20)).unwrap();
+        s.takeback(2);
+        let clock = s.clock_state().unwrap();
+        assert_eq!((clock.white, clock.black), (60_000, 60_000));
+    }
+
+    #[test]
+    fn running_out_of_time_ends_the_game() {
+        let mut s = timed("60");
+        assert!(matches!(
+            s.play("e2e4", Duration::from_secs(61)),
+            Err(Error::TimeForfeit)
+        ));
+        assert!(s.moves().is_empty());
+        assert_eq!(s.termination(), Some(GameTermination::Timeout));
+        assert_eq!(s.clock_state().unwrap().flagged, Some(EngineColor::White));
+        assert!(s.submit_player_move("e2e4").is_err());
+        let pgn = s.pgn();
+        assert!(pgn.contains("[TimeControl \"60\"]"));
+        assert!(pgn.contains("[Result \"0-1\"]"));
+    }
+}
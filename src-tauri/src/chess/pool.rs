@@ -0,0 +1,347 @@
+//! Limit on the number of engines searching at once.
+//!
+//! Several engines in several tabs all searching together can take the whole machine, so
+//! searches started with `get_best_moves` once the limit is reached wait in a queue instead.
+//! They start in order as other searches finish or are stopped. A search queued again for the
+//! same engine of a tab replaces the one waiting, and stopping, killing or hiding the engines of
+//! a tab drops what it had queued.
+//!
+//! The limit defaults to half the physical cores. It isn't saved: the frontend sets it from its
+//! settings with `set_engine_concurrency_limit`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use dashmap::DashMap;
+use log::warn;
+use serde::Serialize;
+use specta::Type;
+use sysinfo::{System, SystemExt};
+use tauri::Manager;
+use tokio::sync::Notify;
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::manager::EngineManager;
+use super::process::EngineProcess;
+use super::types::{EngineOptions, GoMode};
+
+/// Engine processes of the tabs, by tab and engine.
+type TabEngines = DashMap<(String, String), Arc<tokio::sync::Mutex<EngineProcess>>>;
+
+/// Half the physical cores of the machine, and at least one.
+pub fn default_concurrency_limit() -> u32 {
+    let cores = System::new()
+        .physical_core_count()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    (cores / 2).max(1) as u32
+}
+
+/// A search waiting for an engine slot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedSearch {
+    pub id: String,
+    pub engine: String,
+    pub tab: String,
+    pub go_mode: GoMode,
+    pub options: EngineOptions,
+}
+
+/// How many engines may search at once, and how many do.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineConcurrencyStatus {
+    pub limit: u32,
+    /// Engines currently searching.
+    pub busy: u32,
+    /// Searches waiting for one of them to finish.
+    pub queued: u32,
+}
+
+pub struct EnginePool {
+    limit: AtomicU32,
+    queue: Mutex<VecDeque<QueuedSearch>>,
+    /// Held from the check of the limit until the search is started, so searches started
+    /// together can't all take the last slot.
+    pub(super) admission: tokio::sync::Mutex<()>,
+    wake: Notify,
+}
+
+impl Default for EnginePool {
+    fn default() -> Self {
+        Self {
+            limit: AtomicU32::new(default_concurrency_limit()),
+            queue: Mutex::new(VecDeque::new()),
+            admission: tokio::sync::Mutex::new(()),
+            wake: Notify::new(),
+        }
+    }
+}
+
+impl EnginePool {
+    pub fn limit(&self) -> u32 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Change the limit, starting queued searches if it went up.
+    pub fn set_limit(&self, limit: u32) {
+        self.limit.store(limit.max(1), Ordering::Relaxed);
+        self.wake();
+    }
+
+    /// Have queued searches start if there is room for them.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub fn queued(&self) -> u32 {
+        self.queue.lock().unwrap().len() as u32
+    }
+
+    /// Queue a search, in place of the one already waiting for the same engine of the tab.
+    pub fn enqueue(&self, search: QueuedSearch) {
+        let mut queue = self.queue.lock().unwrap();
+        match queue
+            .iter_mut()
+            .find(|queued| queued.tab == search.tab && queued.engine == search.engine)
+        {
+            Some(queued) => *queued = search,
+            None => queue.push_back(search),
+        }
+    }
+
+    /// Take up to `count` searches from the front of the queue.
+    fn take(&self, count: usize) -> Vec<QueuedSearch> {
+        let mut queue = self.queue.lock().unwrap();
+        let count = count.min(queue.len());
+        queue.drain(..count).collect()
+    }
+
+    /// Drop the searches queued for `tab`, only those of `engine` if one is given.
+    pub fn drop_queued(&self, tab: &str, engine: Option<&str>) {
+        self.queue.lock().unwrap().retain(|queued| {
+            queued.tab != tab || engine.is_some_and(|engine| queued.engine != engine)
+        });
+    }
+
+    /// Move the searches queued for tab `old` to tab `new`.
+    pub fn rename_tab(&self, old: &str, new: &str) {
+        for queued in self.queue.lock().unwrap().iter_mut() {
+            if queued.tab == old {
+                queued.tab = new.to_string();
+            }
+        }
+    }
+
+    /// Whether a search of `key` for `options` and `go_mode` must wait for a slot.
+    ///
+    /// An engine already searching keeps its slot for its new search, and one asked again for
+    /// the analysis it is running needs no slot at all.
+    pub(super) async fn must_wait(
+        &self,
+        engines: &TabEngines,
+        key: &(String, String),
+        options: &EngineOptions,
+        go_mode: &GoMode,
+    ) -> bool {
+        let mut busy = 0;
+        for (process_key, process) in processes(engines) {
+            let process = process.lock().await;
+            let searching = process.unanswered_searches > 0;
+            if process_key != *key {
+                busy += u32::from(searching);
+            } else if searching
+                || (process.running && process.options == *options && process.go_mode == *go_mode)
+            {
+                return false;
+            }
+        }
+        busy >= self.limit()
+    }
+}
+
+fn processes(
+    engines: &TabEngines,
+) -> Vec<((String, String), Arc<tokio::sync::Mutex<EngineProcess>>)> {
+    engines
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
+
+/// Number of engines waiting for the result of a search.
+async fn busy_engines(engines: &TabEngines) -> u32 {
+    let mut busy = 0;
+    for (_, process) in processes(engines) {
+        busy += u32::from(process.lock().await.unanswered_searches > 0);
+    }
+    busy
+}
+
+/// Start queued searches as slots free up, for as long as the app runs.
+pub async fn run_engine_queue(app: tauri::AppHandle) {
+    let state = app.state::<AppState>();
+    loop {
+        state.engine_pool.wake.notified().await;
+        // Searches answered from the cache leave their slot to the next ones, and those still
+        // finding no slot go back in the queue.
+        loop {
+            let free = state
+                .engine_pool
+                .limit()
+                .saturating_sub(busy_engines(&state.engine_processes).await);
+            let searches = state.engine_pool.take(free as usize);
+            if searches.is_empty() {
+                break;
+            }
+            for search in searches {
+                let started = EngineManager::new(state.clone())
+                    .get_best_moves(
+                        search.id,
+                        search.engine,
+                        search.tab,
+                        search.go_mode,
+                        search.options,
+                        false,
+                        app.clone(),
+                    )
+                    .await;
+                if let Err(e) = started {
+                    warn!("Failed to start a queued engine search: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Set how many engines may search at once, at least one.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_engine_concurrency_limit(
+    limit: u32,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    state.engine_pool.set_limit(limit);
+    Ok(())
+}
+
+/// How many engines may search at once, how many do and how many searches wait for them.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_engine_concurrency_status(
+    state: tauri::State<'_, AppState>,
+) -> Result<EngineConcurrencyStatus, Error> {
+    Ok(EngineConcurrencyStatus {
+        limit: state.engine_pool.limit(),
+        busy: busy_engines(&state.engine_processes).await,
+        queued: state.engine_pool.queued(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn options() -> EngineOptions {
+        EngineOptions {
+            fen: START.to_string(),
+            moves: Vec::new(),
+            extra_options: Vec::new(),
+        }
+    }
+
+    fn search(tab: &str, engine: &str, depth: u32) -> QueuedSearch {
+        QueuedSearch {
+            id: engine.to_string(),
+            engine: engine.to_string(),
+            tab: tab.to_string(),
+            go_mode: GoMode::Depth(depth),
+            options: options(),
+        }
+    }
+
+    fn pool(limit: u32) -> EnginePool {
+        let pool = EnginePool::default();
+        pool.set_limit(limit);
+        pool
+    }
+
+    /// Add an engine process to `engines`, searching or done with its search.
+    fn add_process(engines: &TabEngines, tab: &str, engine: &str, searching: bool) {
+        let mut proc = EngineProcess::detached();
+        proc.options = options();
+        proc.go_mode = GoMode::Depth(20);
+        proc.running = true;
+        proc.unanswered_searches = u32::from(searching);
+        proc.tab = tab.to_string();
+        engines.insert(
+            (tab.to_string(), engine.to_string()),
+            Arc::new(tokio::sync::Mutex::new(proc)),
+        );
+    }
+
+    #[test]
+    fn searches_queue_once_per_engine_of_a_tab() {
+        let pool = pool(1);
+        pool.enqueue(search("a", "sf", 10));
+        pool.enqueue(search("b", "sf", 10));
+        pool.enqueue(search("a", "sf", 20));
+        pool.enqueue(search("a", "lc0", 10));
+        assert_eq!(pool.queued(), 3);
+        assert_eq!(pool.take(1), [search("a", "sf", 20)]);
+
+        pool.rename_tab("a", "c");
+        pool.drop_queued("b", None);
+        pool.drop_queued("c", Some("sf"));
+        assert_eq!(pool.take(5), [search("c", "lc0", 10)]);
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[test]
+    fn the_limit_is_at_least_one() {
+        assert!(default_concurrency_limit() >= 1);
+        assert_eq!(pool(0).limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn searches_wait_once_the_limit_is_reached() {
+        let engines = TabEngines::default();
+        add_process(&engines, "a", "sf", true);
+        add_process(&engines, "b", "sf", false);
+        let pool = pool(2);
+        let key = |tab: &str| (tab.to_string(), "sf".to_string());
+        let depth = GoMode::Depth(20);
+        assert_eq!(busy_engines(&engines).await, 1);
+        assert!(
+            !pool
+                .must_wait(&engines, &key("c"), &options(), &depth)
+                .await
+        );
+
+        add_process(&engines, "c", "sf", true);
+        assert!(
+            pool.must_wait(&engines, &key("d"), &options(), &depth)
+                .await
+        );
+        // Engines already searching keep their slot.
+        assert!(
+            !pool
+                .must_wait(&engines, &key("a"), &options(), &GoMode::Depth(30))
+                .await
+        );
+        // The analysis an engine is done with needs none, but a new one does.
+        assert!(
+            !pool
+                .must_wait(&engines, &key("b"), &options(), &depth)
+                .await
+        );
+        assert!(
+            pool.must_wait(&engines, &key("b"), &options(), &GoMode::Depth(30))
+                .await
+        );
+    }
+}
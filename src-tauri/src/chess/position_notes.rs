@@ -0,0 +1,557 @@
+//! Notes on positions, independent of any game.
+//!
+//! A note holds some text and the arrows and circles drawn on the board, and belongs to a
+//! position wherever it is reached: positions are keyed by their Zobrist hash and their FEN
+//! without move counters, so transpositions share their note. Shapes are stored as the
+//! `%csl` and `%cal` commands of PGN comments, which is how the board exports them, so a
+//! note renders the same as a commented move. Notes live in a SQLite database in the app
+//! data directory and can be exported as a PGN file, one game per note.
+
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Nullable, Text},
+};
+use serde::{Deserialize, Serialize};
+use shakmaty::Square;
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::cache::PositionKey;
+
+const POSITION_NOTES_SQL: &str = include_str!("../../../database/schema/position_notes.sql");
+
+/// Notes database, relative to the app data directory.
+const POSITION_NOTES_FILE: &str = "position_notes.db3";
+
+const NOTE_COLUMNS: &str = "Fen, Note, Shapes, UpdatedAt";
+
+/// Colour of a shape, named as the board's brushes are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ShapeBrush {
+    Green,
+    Red,
+    Yellow,
+    Blue,
+}
+
+impl ShapeBrush {
+    fn letter(self) -> char {
+        match self {
+            ShapeBrush::Green => 'G',
+            ShapeBrush::Red => 'R',
+            ShapeBrush::Yellow => 'Y',
+            ShapeBrush::Blue => 'B',
+        }
+    }
+
+    fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'G' => Some(ShapeBrush::Green),
+            'R' => Some(ShapeBrush::Red),
+            'Y' => Some(ShapeBrush::Yellow),
+            'B' => Some(ShapeBrush::Blue),
+            _ => None,
+        }
+    }
+}
+
+/// An arrow from `orig` to `dest`, or a circle on `orig` without `dest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct BoardShape {
+    pub orig: String,
+    pub dest: Option<String>,
+    pub brush: ShapeBrush,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionNote {
+    /// The position, without move counters.
+    pub fen: String,
+    pub text: String,
+    pub shapes: Vec<BoardShape>,
+    /// Unix timestamp, in seconds.
+    pub updated_at: i64,
+}
+
+/// Restricts the listed notes. Unset fields match everything.
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionNoteFilter {
+    /// Part of the text of the note, ignoring case.
+    pub query: Option<String>,
+    /// Unix timestamp, in seconds, of the oldest update to list.
+    pub since: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct StoredNote {
+    #[diesel(sql_type = Text, column_name = "Fen")]
+    fen: String,
+    #[diesel(sql_type = Text, column_name = "Note")]
+    note: String,
+    #[diesel(sql_type = Text, column_name = "Shapes")]
+    shapes: String,
+    #[diesel(sql_type = BigInt, column_name = "UpdatedAt")]
+    updated_at: i64,
+}
+
+impl From<StoredNote> for PositionNote {
+    fn from(stored: StoredNote) -> Self {
+        Self {
+            fen: stored.fen,
+            text: stored.note,
+            // Only valid shapes are stored.
+            shapes: parse_shapes(&stored.shapes).unwrap_or_default(),
+            updated_at: stored.updated_at,
+        }
+    }
+}
+
+fn parse_square(square: &str) -> Result<String, Error> {
+    square
+        .parse::<Square>()
+        .map(|square| square.to_string())
+        .map_err(|_| Error::InvalidShape(format!("{} is not a square", square)))
+}
+
+fn check_shape(shape: &BoardShape) -> Result<(), Error> {
+    parse_square(&shape.orig)?;
+    if let Some(dest) = &shape.dest {
+        parse_square(dest)?;
+    }
+    Ok(())
+}
+
+/// Shapes as the `%csl` and `%cal` commands of a PGN comment.
+pub fn format_shapes(shapes: &[BoardShape]) -> String {
+    let format = |arrows: bool| {
+        shapes
+            .iter()
+            .filter(|shape| shape.dest.is_some() == arrows)
+            .map(|shape| {
+                format!(
+                    "{}{}{}",
+                    shape.brush.letter(),
+                    shape.orig,
+                    shape.dest.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let mut markup = String::new();
+    let circles = format(false);
+    if !circles.is_empty() {
+        markup.push_str(&format!("[%csl {}]", circles));
+    }
+    let arrows = format(true);
+    if !arrows.is_empty() {
+        markup.push_str(&format!("[%cal {}]", arrows));
+    }
+    markup
+}
+
+/// Shapes of the `%csl` and `%cal` commands in `markup`.
+pub fn parse_shapes(markup: &str) -> Result<Vec<BoardShape>, Error> {
+    let mut shapes = Vec::new();
+    let mut rest = markup;
+    while let Some(start) = rest.find("[%") {
+        let end = rest[start..]
+            .find(']')
+            .ok_or_else(|| Error::InvalidShape(format!("unclosed command in {}", markup)))?;
+        let command = &rest[start + 2..start + end];
+        rest = &rest[start + end + 1..];
+        let (name, arguments) = command.split_once(' ').unwrap_or((command, ""));
+        let arrows = match name {
+            "csl" => false,
+            "cal" => true,
+            _ => continue,
+        };
+        for shape in arguments
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let mut chars = shape.chars();
+            let brush = chars
+                .next()
+                .and_then(ShapeBrush::from_letter)
+                .ok_or_else(|| Error::InvalidShape(format!("{} has no colour", shape)))?;
+            let squares = chars.as_str();
+            let (orig, dest) = match (arrows, squares.len()) {
+                (false, 2) => (squares, None),
+                (true, 4) if squares.is_char_boundary(2) => {
+                    (&squares[..2], Some(parse_square(&squares[2..])?))
+                }
+                _ => return Err(Error::InvalidShape(shape.to_string())),
+            };
+            shapes.push(BoardShape {
+                orig: parse_square(orig)?,
+                dest,
+                brush,
+            });
+        }
+    }
+    Ok(shapes)
+}
+
+/// Open the notes database, creating it on first use.
+pub fn open_position_notes(app: &tauri::AppHandle) -> Result<SqliteConnection, Error> {
+    let path = app
+        .path()
+        .resolve(POSITION_NOTES_FILE, BaseDirectory::AppData)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut db = SqliteConnection::establish(&path.to_string_lossy())?;
+    db.batch_execute(POSITION_NOTES_SQL)?;
+    Ok(db)
+}
+
+/// The note on `fen`'s position, whatever its move counters.
+pub fn read_note(db: &mut SqliteConnection, fen: &str) -> Result<Option<PositionNote>, Error> {
+    let key = PositionKey::from_moves(fen.trim(), &[])?;
+    Ok(sql_query(format!(
+        "SELECT {} FROM PositionNotes WHERE PositionHash = ? AND Fen = ?",
+        NOTE_COLUMNS
+    ))
+    .bind::<BigInt, _>(key.hash)
+    .bind::<Text, _>(&key.fen)
+    .get_result::<StoredNote>(db)
+    .optional()?
+    .map(PositionNote::from))
+}
+
+/// Set the note on `fen`'s position at `now`, or remove it when it has neither text nor
+/// shapes. Returns the note stored.
+pub fn write_note(
+    db: &mut SqliteConnection,
+    fen: &str,
+    text: &str,
+    shapes: &[BoardShape],
+    now: i64,
+) -> Result<Option<PositionNote>, Error> {
+    let key = PositionKey::from_moves(fen.trim(), &[])?;
+    shapes.iter().try_for_each(check_shape)?;
+    let text = text.trim();
+    if text.is_empty() && shapes.is_empty() {
+        sql_query("DELETE FROM PositionNotes WHERE Fen = ?")
+            .bind::<Text, _>(&key.fen)
+            .execute(db)?;
+        return Ok(None);
+    }
+    sql_query(
+        "INSERT OR REPLACE INTO PositionNotes (Fen, PositionHash, Note, Shapes, UpdatedAt) \
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind::<Text, _>(&key.fen)
+    .bind::<BigInt, _>(key.hash)
+    .bind::<Text, _>(text)
+    .bind::<Text, _>(format_shapes(shapes))
+    .bind::<BigInt, _>(now)
+    .execute(db)?;
+    Ok(Some(PositionNote {
+        fen: key.fen,
+        text: text.to_string(),
+        shapes: shapes.to_vec(),
+        updated_at: now,
+    }))
+}
+
+/// The notes on each of `fens`, in order. Invalid FENs have no note.
+pub fn read_notes(
+    db: &mut SqliteConnection,
+    fens: &[String],
+) -> Result<Vec<Option<PositionNote>>, Error> {
+    db.transaction::<_, Error, _>(|db| {
+        fens.iter()
+            .map(|fen| match read_note(db, fen) {
+                Err(Error::Fen(_)) | Err(Error::ChessPosition(_)) => Ok(None),
+                note => note,
+            })
+            .collect()
+    })
+}
+
+/// The `limit` most recently updated notes matching `filter`, newest first.
+pub fn list_notes(
+    db: &mut SqliteConnection,
+    limit: u32,
+    filter: &PositionNoteFilter,
+) -> Result<Vec<PositionNote>, Error> {
+    let pattern = filter.query.as_deref().map(|query| {
+        format!(
+            "%{}%",
+            query
+                .trim()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        )
+    });
+    Ok(sql_query(format!(
+        "SELECT {} FROM PositionNotes \
+         WHERE (? IS NULL OR Note LIKE ? ESCAPE '\\') AND (? IS NULL OR UpdatedAt >= ?) \
+         ORDER BY UpdatedAt DESC, Fen LIMIT ?",
+        NOTE_COLUMNS
+    ))
+    .bind::<Nullable<Text>, _>(&pattern)
+    .bind::<Nullable<Text>, _>(&pattern)
+    .bind::<Nullable<BigInt>, _>(filter.since)
+    .bind::<Nullable<BigInt>, _>(filter.since)
+    .bind::<BigInt, _>(limit as i64)
+    .load::<StoredNote>(db)?
+    .into_iter()
+    .map(PositionNote::from)
+    .collect())
+}
+
+/// Write each note as a game starting from its position, with the note as its comment.
+pub fn write_notes_pgn(notes: &[PositionNote], writer: &mut impl Write) -> Result<(), Error> {
+    for note in notes {
+        let date = chrono::DateTime::from_timestamp(note.updated_at, 0)
+            .map(|date| date.format("%Y.%m.%d").to_string())
+            .unwrap_or_else(|| "????.??.??".to_string());
+        writeln!(writer, "[Event \"Position note\"]")?;
+        writeln!(writer, "[Date \"{}\"]", date)?;
+        writeln!(writer, "[Result \"*\"]")?;
+        writeln!(writer, "[SetUp \"1\"]")?;
+        writeln!(writer, "[FEN \"{} 0 1\"]", note.fen)?;
+        writeln!(writer)?;
+        // Comments end at the first closing brace.
+        let comment = format!(
+            "{} {}",
+            format_shapes(&note.shapes),
+            note.text.replace('}', ")")
+        );
+        writeln!(writer, "{{{}}} *", comment.trim())?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Set the note on a position, or remove it by leaving it empty.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_position_note(
+    fen: String,
+    text: String,
+    shapes: Vec<BoardShape>,
+    app: tauri::AppHandle,
+) -> Result<Option<PositionNote>, Error> {
+    let mut db = open_position_notes(&app)?;
+    write_note(
+        &mut db,
+        &fen,
+        &text,
+        &shapes,
+        chrono::Utc::now().timestamp(),
+    )
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_position_note(
+    fen: String,
+    app: tauri::AppHandle,
+) -> Result<Option<PositionNote>, Error> {
+    let mut db = open_position_notes(&app)?;
+    read_note(&mut db, &fen)
+}
+
+/// The notes on several positions at once, in the order of `fens`, for move lists.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_position_notes_bulk(
+    fens: Vec<String>,
+    app: tauri::AppHandle,
+) -> Result<Vec<Option<PositionNote>>, Error> {
+    let mut db = open_position_notes(&app)?;
+    read_notes(&mut db, &fens)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn list_position_notes(
+    limit: u32,
+    filter: Option<PositionNoteFilter>,
+    app: tauri::AppHandle,
+) -> Result<Vec<PositionNote>, Error> {
+    let mut db = open_position_notes(&app)?;
+    list_notes(&mut db, limit, &filter.unwrap_or_default())
+}
+
+/// Export every note to a PGN file. Returns the number of notes exported.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_position_notes(
+    dest_file: PathBuf,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<usize, Error> {
+    state.path_scope.check(&dest_file)?;
+    let mut db = open_position_notes(&app)?;
+    let notes = list_notes(&mut db, u32::MAX, &PositionNoteFilter::default())?;
+    let mut writer = BufWriter::new(File::create(dest_file)?);
+    write_notes_pgn(&notes, &mut writer)?;
+    writer.flush()?;
+    Ok(notes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory_db;
+    use shakmaty::{fen::Fen, san::San, CastlingMode, Chess, EnPassantMode, Position};
+
+    fn notes_db() -> SqliteConnection {
+        memory_db(POSITION_NOTES_SQL)
+    }
+
+    fn after(moves: &[&str]) -> String {
+        let mut position = Chess::default();
+        for san in moves {
+            let m = san.parse::<San>().unwrap().to_move(&position).unwrap();
+            position.play_unchecked(&m);
+        }
+        // As the board may send it, with the square of any double step.
+        Fen::from_position(position, EnPassantMode::Always).to_string()
+    }
+
+    fn arrow(orig: &str, dest: &str, brush: ShapeBrush) -> BoardShape {
+        BoardShape {
+            orig: orig.to_string(),
+            dest: Some(dest.to_string()),
+            brush,
+        }
+    }
+
+    #[test]
+    fn transpositions_share_their_note() {
+        let db = &mut notes_db();
+        let italian = after(&["e4", "e5", "Nf3", "Nc6", "Bc4"]);
+        let transposed = after(&["Nf3", "Nc6", "e4", "e5", "Bc4"]);
+        let later = after(&["e4", "e5", "Nf3", "Nc6", "Bc4", "Nf6", "Ng1", "Ng8", "Nf3"]);
+        let d4_last = after(&["e4", "Nf6", "d4"]);
+        let e4_last = after(&["d4", "Nf6", "e4"]);
+
+        let shapes = [arrow("c4", "f7", ShapeBrush::Red)];
+        let note = write_note(db, &italian, " Eyes on f7 ", &shapes, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            note.fen,
+            "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq -"
+        );
+        assert_eq!(note.text, "Eyes on f7");
+        for fen in [&transposed, &later] {
+            assert_ne!(fen, &italian);
+            assert_eq!(read_note(db, fen).unwrap().as_ref(), Some(&note));
+        }
+
+        // En passant squares that can't be taken don't make different positions.
+        assert!(d4_last.contains(" d3 ") && e4_last.contains(" e3 "));
+        let other = write_note(db, &d4_last, "Alekhine", &[], 15)
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_note(db, &e4_last).unwrap().as_ref(), Some(&other));
+
+        let notes = read_notes(
+            db,
+            &[transposed.clone(), "nonsense".to_string(), after(&["e4"])],
+        )
+        .unwrap();
+        assert_eq!(notes, vec![Some(note), None, None]);
+        assert!(read_note(db, "nonsense").is_err());
+
+        // Emptied notes are removed.
+        assert_eq!(write_note(db, &later, "", &[], 20).unwrap(), None);
+        assert_eq!(read_note(db, &italian).unwrap(), None);
+    }
+
+    #[test]
+    fn shapes_round_trip_as_pgn_commands() {
+        let shapes = vec![
+            arrow("e2", "e4", ShapeBrush::Green),
+            BoardShape {
+                orig: "d5".to_string(),
+                dest: None,
+                brush: ShapeBrush::Yellow,
+            },
+            arrow("g1", "f3", ShapeBrush::Blue),
+        ];
+        let markup = format_shapes(&shapes);
+        assert_eq!(markup, "[%csl Yd5][%cal Ge2e4,Bg1f3]");
+        let parsed = parse_shapes(&markup).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(shapes.iter().all(|shape| parsed.contains(shape)));
+        assert_eq!(
+            parse_shapes("[%clk 0:01:00] [%cal Re7e5] text").unwrap(),
+            vec![arrow("e7", "e5", ShapeBrush::Red)]
+        );
+        assert!(parse_shapes("[%cal Xe2e4]").is_err());
+        assert!(parse_shapes("[%csl Ge9]").is_err());
+
+        let db = &mut notes_db();
+        let start = Fen::from_position(Chess::default(), EnPassantMode::Legal).to_string();
+        assert!(write_note(db, &start, "", &[arrow("e2", "z9", ShapeBrush::Green)], 1).is_err());
+        write_note(db, &start, "", &shapes, 1).unwrap();
+        let note = read_note(db, &start).unwrap().unwrap();
+        assert_eq!(note.shapes.len(), 3);
+    }
+
+    #[test]
+    fn notes_are_listed_and_exported() {
+        let db = &mut notes_db();
+        let start = Fen::from_position(Chess::default(), EnPassantMode::Legal).to_string();
+        let e4 = after(&["e4"]);
+        write_note(db, &start, "Start {of it all}", &[], 86_400).unwrap();
+        write_note(
+            db,
+            &e4,
+            "Best by test",
+            &[arrow("e7", "e5", ShapeBrush::Green)],
+            2 * 86_400,
+        )
+        .unwrap();
+
+        let all = list_notes(db, 10, &PositionNoteFilter::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].text, "Best by test");
+        let filter = PositionNoteFilter {
+            query: Some("BEST".to_string()),
+            since: None,
+        };
+        assert_eq!(list_notes(db, 10, &filter).unwrap().len(), 1);
+        let filter = PositionNoteFilter {
+            query: None,
+            since: Some(2 * 86_400),
+        };
+        assert_eq!(list_notes(db, 10, &filter).unwrap()[0].text, "Best by test");
+
+        let mut pgn = Vec::new();
+        write_notes_pgn(&all, &mut pgn).unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+        assert!(
+            pgn.contains("[FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"]")
+        );
+        assert!(pgn.contains("[Date \"1970.01.03\"]"));
+        assert!(pgn.contains("{[%cal Ge7e5] Best by test} *"));
+        assert!(pgn.contains("{Start {of it all)} *"));
+
+        // Exported FENs are complete positions.
+        let fen: Fen = format!("{} 0 1", all[1].fen).parse().unwrap();
+        fen.into_position::<Chess>(CastlingMode::Standard).unwrap();
+    }
+}
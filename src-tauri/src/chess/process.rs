@@ -4,15 +4,23 @@
 //! sending commands, updating options, and parsing engine output for best-move analysis.
 
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use tokio::io::AsyncWriteExt;
-use vampirc_uci::{uci::ScoreValue, UciInfoAttribute};
+use vampirc_uci::{
+    parse_one,
+    uci::{ScoreValue, UciOptionConfig},
+    UciInfoAttribute, UciMessage,
+};
 
 use crate::error::Error;
+use crate::scope::PathScope;
 
-use super::types::{BestMoves, EngineLog, EngineOptions, GoMode};
-use super::uci::UciCommunicator;
+use super::diagnostics::MultiPvDiagnostic;
+use super::evaluation::{format_score, ScoreStyle};
+use super::recording::SharedRecorder;
+use super::types::{AnalysisCompletion, BestMoves, EngineLog, EngineOption, EngineOptions, GoMode};
+use super::uci::{EngineStdin, EngineStdout, HandshakeSignal, UciCommunicator, UciHandshake};
 use shakmaty::{fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Color, Position};
 
 #[cfg(target_os = "windows")]
@@ -20,11 +28,17 @@
 
 /// Represents a running UCI engine process and its state.
 pub struct EngineProcess {
-    pub child: tokio::process::Child,
-    pub stdin: tokio::process::ChildStdin,
+    /// The engine process, `None` for the built-in engine.
+    pub child: Option<tokio::process::Child>,
+    pub stdin: EngineStdin,
     pub last_depth: u32,
+    /// Lines of the depth being reported, by MultiPV index.
     pub best_moves: Vec<BestMoves>,
+    /// When the first of `best_moves` came in, on the clock of the analysis handler.
+    pub best_moves_since: Duration,
     pub last_best_moves: Vec<BestMoves>,
+    /// Whether `last_best_moves` holds fewer lines than requested.
+    pub last_partial: bool,
     pub last_progress: f32,
     pub options: EngineOptions,
     pub go_mode: GoMode,
@@ -32,25 +46,32 @@ pub struct EngineProcess {
     pub real_multipv: u16,
     pub logs: Vec<EngineLog>,
     pub start: Instant,
+    /// Options to send before the next configuration, set while a search was running.
+    pub pending_options: Vec<EngineOption>,
+    pub multipv_diagnostic: MultiPvDiagnostic,
+    /// Why the running search was ended from outside, until its result comes in.
+    pub stop_reason: Option<AnalysisCompletion>,
+    /// Searches started and not answered with a `bestmove` yet, for readers that count them.
+    pub unanswered_searches: u32,
+    /// Stopped searches whose output is thrown away, up to their `bestmove`.
+    pub discarded_searches: u32,
+    /// Recording of the conversation with the engine, while one is running.
+    pub recorder: Option<SharedRecorder>,
+    /// Tab the engine analyses for, as in its key in `AppState::engine_processes`.
+    pub tab: String,
 }
 
 impl EngineProcess {
-    /// Spawn a new UCI engine process and initialize it.
+    /// Spawn a new UCI engine process and initialize it. `path` may also be `BUILTIN_ENGINE`,
+    /// and must otherwise be in `scope`.
     ///
     /// Returns the process and a line reader for its stdout.
     ///
     /// # Errors
-    /// Returns `Error::EngineTimeout` if engine doesn't respond within 10 seconds.
-    pub async fn new(
-        path: PathBuf,
-    ) -> Result<
-        (
-            Self,
-            tokio::io::Lines<tokio::io::BufReader<tokio::process::ChildStdout>>,
-        ),
-        Error,
-    > {
-        let mut comm = UciCommunicator::spawn(path).await?;
+    /// Returns `Error::PathNotAuthorized` if the engine is outside `scope`, and
+    /// `Error::EngineTimeout` if engine doesn't respond within 10 seconds.
+    pub async fn new(path: PathBuf, scope: &PathScope) -> Result<(Self, EngineStdout), Error> {
+        let mut comm = UciCommunicator::spawn(path, scope).await?;
 
         let mut logs = Vec::new();
 
@@ -60,10 +81,12 @@ pub async fn new(
 
         // Wait for uciok with timeout (10 seconds)
         let uci_timeout = tokio::time::Duration::from_secs(10);
+        let mut handshake = UciHandshake::default();
         let uciok_received = tokio::time::timeout(uci_timeout, async {
             while let Some(line) = comm.stdout_lines.next_line().await? {
-                logs.push(EngineLog::Engine(line.clone()));
-                if line == "uciok" {
+                let signal = handshake.feed(&line);
+                logs.extend(handshake.log_line(&line));
+                if signal == Some(HandshakeSignal::UciOk) {
                     return Ok::<_, Error>(true);
                 }
             }
@@ -81,8 +104,9 @@ pub async fn new(
                 let ready_timeout = tokio::time::Duration::from_secs(5);
                 let readyok_received = tokio::time::timeout(ready_timeout, async {
                     while let Some(line_is_ready) = comm.stdout_lines.next_line().await? {
-                        logs.push(EngineLog::Engine(line_is_ready.clone()));
-                        if line_is_ready == "readyok" {
+                        let signal = handshake.feed(&line_is_ready);
+                        logs.extend(handshake.log_line(&line_is_ready));
+                        if signal == Some(HandshakeSignal::ReadyOk) {
                             return Ok::<_, Error>(true);
                         }
                     }
@@ -125,24 +149,67 @@ pub async fn new(
         }
 
         Ok((
-            Self {
-                child: comm.child,
-                stdin: comm.stdin,
-                last_depth: 0,
-                best_moves: Vec::new(),
-                last_best_moves: Vec::new(),
-                last_progress: 0.0,
-                logs,
-                options: EngineOptions::default(),
-                real_multipv: 0,
-                go_mode: GoMode::Infinite,
-                running: false,
-                start: Instant::now(),
-            },
+            Self::with_stdin(comm.child, comm.stdin, logs),
             comm.stdout_lines,
         ))
     }
 
+    /// A process state without an engine behind it, whose commands go nowhere, for replaying
+    /// recorded engine output.
+    pub fn detached() -> Self {
+        Self::with_stdin(None, Box::new(tokio::io::sink()), Vec::new())
+    }
+
+    fn with_stdin(
+        child: Option<tokio::process::Child>,
+        stdin: EngineStdin,
+        logs: Vec<EngineLog>,
+    ) -> Self {
+        Self {
+            child,
+            stdin,
+            last_depth: 0,
+            best_moves: Vec::new(),
+            best_moves_since: Duration::ZERO,
+            last_best_moves: Vec::new(),
+            last_partial: false,
+            last_progress: 0.0,
+            logs,
+            options: EngineOptions::default(),
+            real_multipv: 0,
+            go_mode: GoMode::Infinite,
+            running: false,
+            start: Instant::now(),
+            pending_options: Vec::new(),
+            multipv_diagnostic: MultiPvDiagnostic::default(),
+            stop_reason: None,
+            unanswered_searches: 0,
+            discarded_searches: 0,
+            recorder: None,
+            tab: String::new(),
+        }
+    }
+
+    /// Log a command sent to the engine.
+    fn log_gui(&mut self, msg: String) {
+        if let Some(recorder) = &self.recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.gui(&msg);
+            }
+        }
+        self.logs.push(EngineLog::Gui(msg));
+    }
+
+    /// Log a line read from the engine.
+    pub fn log_engine(&mut self, line: String) {
+        if let Some(recorder) = &self.recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.engine(&line);
+            }
+        }
+        self.logs.push(EngineLog::Engine(line));
+    }
+
     /// Set a single UCI option for the engine.
     pub async fn set_option<T>(&mut self, name: &str, value: T) -> Result<(), Error>
     where
@@ -150,10 +217,53 @@ pub async fn set_option<T>(&mut self, name: &str, value: T) -> Result<(), Error>
     {
         let msg = format!("setoption name {} value {}\n", name, value);
         self.stdin.write_all(msg.as_bytes()).await?;
-        self.logs.push(EngineLog::Gui(msg));
+        self.log_gui(msg);
         Ok(())
     }
 
+    /// Options the engine advertised during the UCI handshake.
+    pub fn advertised_options(&self) -> Vec<UciOptionConfig> {
+        self.logs
+            .iter()
+            .filter_map(|log| match log {
+                EngineLog::Engine(line) => match parse_one(line) {
+                    UciMessage::Option(option) => Some(option),
+                    _ => None,
+                },
+                EngineLog::Gui(_) => None,
+            })
+            .collect()
+    }
+
+    /// Most MultiPV lines the engine advertised it can report.
+    pub fn advertised_multipv_max(&self) -> Option<u16> {
+        self.advertised_options()
+            .into_iter()
+            .find_map(|option| match option {
+                UciOptionConfig::Spin {
+                    name,
+                    max: Some(max),
+                    ..
+                } if name.eq_ignore_ascii_case("MultiPV") => {
+                    Some(max.clamp(1, u16::MAX as i64) as u16)
+                }
+                _ => None,
+            })
+    }
+
+    /// Name the engine reported during the UCI handshake.
+    pub fn engine_name(&self) -> Option<String> {
+        self.logs.iter().find_map(|log| match log {
+            EngineLog::Engine(line) => match parse_one(line) {
+                UciMessage::Id {
+                    name: Some(name), ..
+                } => Some(name),
+                _ => None,
+            },
+            EngineLog::Gui(_) => None,
+        })
+    }
+
     /// Set all engine options, including FEN, moves, and extra UCI options.
     /// Updates multipv and resets best-move tracking.
     pub async fn set_options(&mut self, options: EngineOptions) -> Result<(), Error> {
@@ -167,18 +277,33 @@ pub async fn set_options(&mut self, options: EngineOptions) -> Result<(), Error>
             let mv = uci.to_move(&pos)?;
             pos.play_unchecked(&mv);
         }
-        let multipv = options
+        let advertised_max = self.advertised_multipv_max();
+        let mut multipv = options
             .extra_options
             .iter()
             .find(|x| x.name == "MultiPV")
             .map(|x| x.value.parse().unwrap_or(1))
             .unwrap_or(1);
+        if let Some(max) = advertised_max {
+            multipv = multipv.min(max);
+        }
 
         self.real_multipv = multipv.min(pos.legal_moves().len() as u16);
+        self.multipv_diagnostic.start(self.real_multipv);
+        if let Some(limit) = self.multipv_diagnostic.limit() {
+            self.real_multipv = self.real_multipv.min(limit);
+        }
 
+        for option in std::mem::take(&mut self.pending_options) {
+            self.set_option(&option.name, &option.value).await?;
+        }
         for option in &options.extra_options {
             if !self.options.extra_options.contains(option) {
-                self.set_option(&option.name, &option.value).await?;
+                if option.name == "MultiPV" && advertised_max.is_some() {
+                    self.set_option(&option.name, multipv).await?;
+                } else {
+                    self.set_option(&option.name, &option.value).await?;
+                }
             }
         }
 
@@ -189,6 +314,7 @@ pub async fn set_options(&mut self, options: EngineOptions) -> Result<(), Error>
         self.options = options.clone();
         self.best_moves.clear();
         self.last_best_moves.clear();
+        self.last_partial = false;
         Ok(())
     }
 
@@ -202,45 +328,108 @@ pub async fn set_position(&mut self, fen: &str, moves: &Vec<String>) -> Result<(
         self.stdin.write_all(msg.as_bytes()).await?;
         self.options.fen = fen.to_string();
         self.options.moves = moves.clone();
-        self.logs.push(EngineLog::Gui(msg));
+        self.log_gui(msg);
         Ok(())
     }
 
     /// Start engine search with the given mode (depth, time, etc).
     pub async fn go(&mut self, mode: &GoMode) -> Result<(), Error> {
+        self.go_searchmoves(mode, &[]).await
+    }
+
+    /// Start engine search with the given mode, considering only `searchmoves` if any are given.
+    pub async fn go_searchmoves(
+        &mut self,
+        mode: &GoMode,
+        searchmoves: &[String],
+    ) -> Result<(), Error> {
         self.go_mode = mode.clone();
-        let msg = match mode {
+        let mut msg = match mode {
             GoMode::Depth(depth) => format!("go depth {}\n", depth),
             GoMode::Time(time) => format!("go movetime {}\n", time),
             GoMode::Nodes(nodes) => format!("go nodes {}\n", nodes),
+            GoMode::Mate(moves) => format!("go mate {}\n", moves),
             GoMode::PlayersTime(super::types::PlayersTime {
                 white,
                 black,
                 winc,
                 binc,
+                ponder,
             }) => {
                 format!(
-                    "go wtime {} btime {} winc {} binc {} movetime 1000\n",
-                    white, black, winc, binc
+                    "go {}wtime {} btime {} winc {} binc {} movetime 1000\n",
+                    if *ponder { "ponder " } else { "" },
+                    white,
+                    black,
+                    winc,
+                    binc
                 )
             }
             GoMode::Infinite => "go infinite\n".to_string(),
         };
+        if let Some(recorder) = &self.recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.search(&self.options, mode);
+            }
+        }
+        if !searchmoves.is_empty() {
+            msg.insert_str(
+                msg.len() - 1,
+                &format!(" searchmoves {}", searchmoves.join(" ")),
+            );
+        }
         self.stdin.write_all(msg.as_bytes()).await?;
-        self.logs.push(EngineLog::Gui(msg));
+        self.log_gui(msg);
         self.running = true;
+        self.unanswered_searches += 1;
         self.start = Instant::now();
         Ok(())
     }
 
+    /// Whether the engine ponders on the move it expects the opponent to play.
+    pub fn is_pondering(&self) -> bool {
+        self.running && matches!(&self.go_mode, GoMode::PlayersTime(time) if time.ponder)
+    }
+
+    /// Tell the pondering engine the expected move was played, so that its search goes on
+    /// as the search for its move, under `mode`.
+    pub async fn ponderhit(&mut self, mode: &GoMode) -> Result<(), Error> {
+        self.stdin.write_all(b"ponderhit\n").await?;
+        self.log_gui("ponderhit\n".to_string());
+        self.go_mode = mode.clone();
+        self.start = Instant::now();
+        Ok(())
+    }
+
+    /// Stop pondering after another move than the expected one was played. The search
+    /// was for a position that didn't come, so its output is thrown away.
+    pub async fn stop_pondering(&mut self) -> Result<(), Error> {
+        self.stdin.write_all(b"stop\n").await?;
+        self.log_gui("stop\n".to_string());
+        self.running = false;
+        self.discarded_searches += 1;
+        self.best_moves.clear();
+        Ok(())
+    }
+
     /// Stop the engine's current search.
     pub async fn stop(&mut self) -> Result<(), Error> {
+        self.record_stop_reason();
         self.stdin.write_all(b"stop\n").await?;
-        self.logs.push(EngineLog::Gui("stop\n".to_string()));
+        self.log_gui("stop\n".to_string());
         self.running = false;
         Ok(())
     }
 
+    /// Attribute the end of the running search to a request, so its last payload doesn't
+    /// report it as finished. Stopping an idle engine leaves nothing to attribute.
+    fn record_stop_reason(&mut self) {
+        if self.unanswered_searches > 0 {
+            self.stop_reason
+                .get_or_insert(AnalysisCompletion::UserStopped);
+        }
+    }
+
     /// Kill the engine process gracefully, with force-kill fallback.
     ///
     /// First sends "quit" command and waits up to 2 seconds for graceful shutdown.
@@ -248,18 +437,24 @@ pub async fn stop(&mut self) -> Result<(), Error> {
     pub async fn kill(&mut self) -> Result<(), Error> {
         use log::warn;
 
+        self.record_stop_reason();
         // Try graceful shutdown first
         if let Err(e) = self.stdin.write_all(b"quit\n").await {
             warn!("Failed to send quit command to engine: {}", e);
         } else {
-            self.logs.push(EngineLog::Gui("quit\n".to_string()));
+            self.log_gui("quit\n".to_string());
         }
 
         self.running = false;
 
+        // The built-in engine stops once it reads `quit`.
+        let Some(child) = self.child.as_mut() else {
+            return Ok(());
+        };
+
         // Wait for process to exit gracefully (2 second timeout)
         let wait_result =
-            tokio::time::timeout(tokio::time::Duration::from_secs(2), self.child.wait()).await;
+            tokio::time::timeout(tokio::time::Duration::from_secs(2), child.wait()).await;
 
         match wait_result {
             Ok(Ok(status)) => {
@@ -269,16 +464,16 @@ pub async fn kill(&mut self) -> Result<(), Error> {
             Ok(Err(e)) => {
                 warn!("Error waiting for engine process: {}", e);
                 // Try force kill
-                self.child.kill().await?;
+                child.kill().await?;
                 log::info!("Engine process force-killed");
                 Ok(())
             }
             Err(_) => {
                 // Timeout - force kill
                 warn!("Engine did not exit gracefully, force-killing");
-                self.child.kill().await?;
+                child.kill().await?;
                 // Wait for kill to complete
-                let _ = self.child.wait().await;
+                let _ = child.wait().await;
                 log::info!("Engine process force-killed after timeout");
                 Ok(())
             }
@@ -364,6 +559,8 @@ pub fn parse_uci_attrs(
         return Err(Error::NoMovesFound);
     }
 
+    // Formatted first: mate 0 is only meaningful relative to the side to move.
+    best_moves.display = format_score(&best_moves.score, turn, ScoreStyle::Pawns);
     if turn == Color::Black {
         best_moves.score = invert_score(best_moves.score);
     }
@@ -0,0 +1,535 @@
+//! Recordings of the UCI conversation with an engine, for bug reports.
+//!
+//! While the engine of a tab is recorded, every line sent to it and read from it is written
+//! with its time to a compressed file in the app data, along with the configuration of each
+//! search. Stopping the recording puts a header in front of the records, with the engine,
+//! its last configuration, the app version and a hash of the records, so that damaged or
+//! edited recordings are refused. Replaying a recording feeds the engine's lines back through
+//! `AnalysisHandler` at their recorded times, which sends the payloads the user saw again
+//! without running the engine.
+//!
+//! Recordings are zstd streams of JSON lines: the header is a frame of its own, followed by
+//! the frames of the records as they were written.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+use tauri_specta::Event;
+
+use crate::error::Error;
+use crate::fs::{partial_path, unique_path};
+use crate::AppState;
+
+use super::manager::{AnalysisHandler, AnalysisSink, EngineExit, HandlerClock};
+use super::process::EngineProcess;
+use super::types::{BestMovesPayload, EngineCapabilityWarning, EngineOptions, GoMode};
+
+/// Version of the recording format, in the header of every recording.
+pub const RECORDING_FORMAT: u32 = 1;
+
+/// Directory of recordings, in the app data.
+const RECORDINGS_DIR: &str = "recordings";
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A recorder shared by the commands controlling it and the engine it records.
+pub type SharedRecorder = Arc<Mutex<UciRecorder>>;
+
+/// A line of a recording, timed in milliseconds since the recording started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum UciRecord {
+    /// A line sent to the engine.
+    Gui { at: u64, line: String },
+    /// A line read from the engine.
+    Engine { at: u64, line: String },
+    /// A search started, with the configuration the app gave it.
+    #[serde(rename_all = "camelCase")]
+    Search {
+        at: u64,
+        options: EngineOptions,
+        go_mode: GoMode,
+    },
+    /// The engine's output ended, or couldn't be read anymore.
+    Closed { at: u64, error: Option<String> },
+}
+
+impl UciRecord {
+    fn at(&self) -> u64 {
+        match self {
+            UciRecord::Gui { at, .. }
+            | UciRecord::Engine { at, .. }
+            | UciRecord::Search { at, .. }
+            | UciRecord::Closed { at, .. } => *at,
+        }
+    }
+}
+
+/// First line of a recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct UciRecordingHeader {
+    pub format: u32,
+    pub app_version: String,
+    pub tab: String,
+    /// Path of the engine.
+    pub engine: String,
+    /// Name the engine reported, when it was still running at the end of the recording.
+    pub engine_name: Option<String>,
+    /// Analysis id the payloads were sent under.
+    pub analysis: Option<String>,
+    /// Unix timestamp in milliseconds.
+    pub started_at: i64,
+    pub duration_ms: u64,
+    /// Configuration of the last search recorded.
+    pub options: Option<EngineOptions>,
+    pub go_mode: Option<GoMode>,
+    pub records: u32,
+    /// SHA-256 of the records as stored, in lowercase hex.
+    pub sha256: String,
+}
+
+/// Writes a recording as the conversation with an engine goes on.
+///
+/// Recording is best-effort: once a record can't be written, the recording stops and
+/// finishing it fails, but the analysis goes on.
+pub struct UciRecorder {
+    tab: String,
+    engine: String,
+    analysis: Option<String>,
+    path: PathBuf,
+    /// Records, written to the partial file of the recording until it's finished.
+    records: Option<zstd::Encoder<'static, BufWriter<File>>>,
+    hasher: Sha256,
+    count: u32,
+    started: Instant,
+    started_at: i64,
+    last_search: Option<(EngineOptions, GoMode)>,
+}
+
+impl UciRecorder {
+    /// Start a recording, to be written at `path` once finished.
+    pub fn create(path: PathBuf, tab: String, engine: String) -> Result<Self, Error> {
+        let file = File::create(partial_path(&path))?;
+        Ok(Self {
+            tab,
+            engine,
+            analysis: None,
+            records: Some(zstd::Encoder::new(BufWriter::new(file), COMPRESSION_LEVEL)?),
+            path,
+            hasher: Sha256::new(),
+            count: 0,
+            started: Instant::now(),
+            started_at: chrono::Utc::now().timestamp_millis(),
+            last_search: None,
+        })
+    }
+
+    pub fn set_analysis(&mut self, id: &str) {
+        self.analysis = Some(id.to_string());
+    }
+
+    fn at(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn gui(&mut self, line: &str) {
+        let at = self.at();
+        self.write(&UciRecord::Gui {
+            at,
+            line: line.trim_end().to_string(),
+        });
+    }
+
+    pub fn engine(&mut self, line: &str) {
+        let at = self.at();
+        self.write(&UciRecord::Engine {
+            at,
+            line: line.trim_end().to_string(),
+        });
+    }
+
+    pub fn search(&mut self, options: &EngineOptions, go_mode: &GoMode) {
+        self.last_search = Some((options.clone(), go_mode.clone()));
+        let at = self.at();
+        self.write(&UciRecord::Search {
+            at,
+            options: options.clone(),
+            go_mode: go_mode.clone(),
+        });
+    }
+
+    pub fn closed(&mut self, exit: &EngineExit) {
+        let error = match exit {
+            EngineExit::ReadFailed(e) => Some(e.clone()),
+            EngineExit::BestMove | EngineExit::Closed => None,
+        };
+        let at = self.at();
+        self.write(&UciRecord::Closed { at, error });
+    }
+
+    fn write(&mut self, record: &UciRecord) {
+        let Some(records) = self.records.as_mut() else {
+            return;
+        };
+        let result = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                records.write_all(line.as_bytes())?;
+                Ok(line)
+            });
+        match result {
+            Ok(line) => {
+                self.hasher.update(line.as_bytes());
+                self.count += 1;
+            }
+            Err(e) => {
+                warn!("Stopping UCI recording {}: {}", self.path.display(), e);
+                self.records = None;
+            }
+        }
+    }
+
+    /// Write the header of the recording in front of its records, returning its path.
+    ///
+    /// Nothing is recorded afterwards.
+    pub fn finish(
+        &mut self,
+        app_version: String,
+        engine_name: Option<String>,
+    ) -> Result<PathBuf, Error> {
+        let records = self.records.take().ok_or_else(|| {
+            Error::InvalidRecording(format!("{} couldn't be written", self.path.display()))
+        })?;
+        records.finish()?.flush()?;
+
+        let (options, go_mode) = self.last_search.clone().unzip();
+        let header = UciRecordingHeader {
+            format: RECORDING_FORMAT,
+            app_version,
+            tab: self.tab.clone(),
+            engine: self.engine.clone(),
+            engine_name,
+            analysis: self.analysis.clone(),
+            started_at: self.started_at,
+            duration_ms: self.at(),
+            options,
+            go_mode,
+            records: self.count,
+            sha256: format!("{:x}", self.hasher.clone().finalize()),
+        };
+        let mut header_line = serde_json::to_string(&header)?;
+        header_line.push('\n');
+
+        let records_path = partial_path(&self.path);
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        out.write_all(&zstd::encode_all(
+            header_line.as_bytes(),
+            COMPRESSION_LEVEL,
+        )?)?;
+        io::copy(&mut File::open(&records_path)?, &mut out)?;
+        out.flush()?;
+        fs::remove_file(records_path)?;
+        Ok(self.path.clone())
+    }
+}
+
+/// Read a recording, checking its records against its header.
+pub fn read_recording(reader: impl Read) -> Result<(UciRecordingHeader, Vec<UciRecord>), Error> {
+    let mut lines = BufReader::new(zstd::Decoder::new(reader)?).lines();
+    let header: UciRecordingHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(Error::InvalidRecording("no header".to_string())),
+    };
+    if header.format != RECORDING_FORMAT {
+        return Err(Error::InvalidRecording(format!(
+            "unsupported format {}",
+            header.format
+        )));
+    }
+
+    let lines = lines.collect::<io::Result<Vec<_>>>()?;
+    let mut hasher = Sha256::new();
+    for line in &lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    if lines.len() != header.records as usize || format!("{:x}", hasher.finalize()) != header.sha256
+    {
+        return Err(Error::InvalidRecording(
+            "the records don't match the header".to_string(),
+        ));
+    }
+    let records = lines
+        .iter()
+        .map(|line| serde_json::from_str(line))
+        .collect::<Result<_, _>>()?;
+    Ok((header, records))
+}
+
+/// Feed the records of a recording through an `AnalysisHandler` at their recorded times,
+/// returning the sink with what it was sent.
+///
+/// Commands are only replayed for their effect on the state of the analysis: a search
+/// starting, or a stop or quit the engine is about to answer.
+pub async fn replay_records<S: AnalysisSink>(
+    header: &UciRecordingHeader,
+    records: &[UciRecord],
+    sink: S,
+) -> Result<S, Error> {
+    let mut process = EngineProcess::detached();
+    let id = header
+        .analysis
+        .clone()
+        .unwrap_or_else(|| header.engine.clone());
+    let mut handler = AnalysisHandler::new(
+        id,
+        header.tab.clone(),
+        header.engine.clone(),
+        HandlerClock::Recorded {
+            now: Duration::ZERO,
+            search_started: Duration::ZERO,
+        },
+        sink,
+    );
+    let mut search_started = Duration::ZERO;
+    for record in records {
+        let now = Duration::from_millis(record.at());
+        if let UciRecord::Search { .. } = record {
+            search_started = now;
+        }
+        handler.clock = HandlerClock::Recorded {
+            now,
+            search_started,
+        };
+        match record {
+            UciRecord::Search {
+                options, go_mode, ..
+            } => {
+                process.set_options(options.clone()).await?;
+                process.go(go_mode).await?;
+            }
+            UciRecord::Gui { line, .. } => match line.as_str() {
+                "stop" => process.stop().await?,
+                "quit" => process.kill().await?,
+                _ => {}
+            },
+            UciRecord::Engine { line, .. } => handler.handle_line(&mut process, line.clone()),
+            UciRecord::Closed { error, .. } => {
+                let exit = match error {
+                    Some(e) => EngineExit::ReadFailed(e.clone()),
+                    None => EngineExit::Closed,
+                };
+                handler.handle_exit(&mut process, &exit);
+            }
+        }
+    }
+    Ok(handler.into_sink())
+}
+
+/// Sends replayed payloads to the frontend like a live search, leaving pinned lines and the
+/// analysis cache alone.
+struct ReplaySink {
+    app: tauri::AppHandle,
+}
+
+impl AnalysisSink for ReplaySink {
+    fn best_moves(&mut self, payload: BestMovesPayload) {
+        payload.emit(&self.app).ok();
+    }
+
+    fn capability_warning(&mut self, warning: EngineCapabilityWarning) {
+        warning.emit(&self.app).ok();
+    }
+}
+
+/// Start recording the conversation with the engine of a tab, returning where the recording
+/// will be written. A running engine is recorded from its next line on.
+#[tauri::command]
+#[specta::specta]
+pub async fn start_uci_recording(
+    tab: String,
+    engine: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<PathBuf, Error> {
+    let key = (tab.clone(), engine.clone());
+    let dir = app.path().resolve(RECORDINGS_DIR, BaseDirectory::AppData)?;
+    fs::create_dir_all(&dir)?;
+
+    let recorder = match state.uci_recordings.entry(key.clone()) {
+        Entry::Occupied(_) => return Err(Error::RecordingInProgress(engine)),
+        Entry::Vacant(entry) => {
+            let name = format!("uci-{}.ucirec", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+            let (path, _) = unique_path(&dir, &name);
+            let recorder = Arc::new(Mutex::new(UciRecorder::create(path, tab, engine)?));
+            entry.insert(recorder.clone());
+            recorder
+        }
+    };
+    let path = recorder
+        .lock()
+        .map_err(|e| Error::MutexLockFailed(e.to_string()))?
+        .path
+        .clone();
+
+    let process = state.engine_processes.get(&key).map(|p| p.clone());
+    if let Some(process) = process {
+        process.lock().await.recorder = Some(recorder);
+    }
+    Ok(path)
+}
+
+/// Stop recording the engine of a tab, writing the header of the recording. Returns the path
+/// of the recording.
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_uci_recording(
+    tab: String,
+    engine: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<PathBuf, Error> {
+    let key = (tab, engine);
+    let Some((_, recorder)) = state.uci_recordings.remove(&key) else {
+        return Err(Error::RecordingNotFound(key.1));
+    };
+
+    let mut engine_name = None;
+    let process = state.engine_processes.get(&key).map(|p| p.clone());
+    if let Some(process) = process {
+        let mut process = process.lock().await;
+        process.recorder = None;
+        engine_name = process.engine_name();
+    }
+
+    let mut recorder = recorder
+        .lock()
+        .map_err(|e| Error::MutexLockFailed(e.to_string()))?;
+    recorder.finish(app.package_info().version.to_string(), engine_name)
+}
+
+/// Replay a recording, emitting the `BestMovesPayload` events of its searches again without
+/// running the engine. Returns the header of the recording.
+#[tauri::command]
+#[specta::specta]
+pub async fn replay_uci_recording(
+    path: PathBuf,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<UciRecordingHeader, Error> {
+    state.path_scope.check(&path)?;
+    let (header, records) = read_recording(File::open(&path)?)?;
+    replay_records(&header, &records, ReplaySink { app }).await?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::types::AnalysisCompletion;
+
+    /// Two searches: one reaching its depth, then one stopped by the user.
+    const FIXTURE: &[u8] = include_bytes!("../../fixtures/uci/depth_then_stop.ucirec");
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    #[derive(Default)]
+    struct Collected(Vec<BestMovesPayload>);
+
+    impl AnalysisSink for Collected {
+        fn best_moves(&mut self, payload: BestMovesPayload) {
+            self.0.push(payload);
+        }
+
+        fn capability_warning(&mut self, _warning: EngineCapabilityWarning) {}
+    }
+
+    async fn replay_fixture() -> Vec<BestMovesPayload> {
+        let (header, records) = read_recording(FIXTURE).unwrap();
+        replay_records(&header, &records, Collected::default())
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn replays_send_the_same_payloads() {
+        let payloads = replay_fixture().await;
+        let as_json = |payloads: &[BestMovesPayload]| serde_json::to_value(payloads).unwrap();
+        assert_eq!(as_json(&payloads), as_json(&replay_fixture().await));
+
+        // The sixth depth of the first search came too fast after the others to be sent.
+        let progress: Vec<_> = payloads.iter().map(|p| p.progress.round()).collect();
+        assert_eq!(
+            progress,
+            [17.0, 33.0, 50.0, 67.0, 83.0, 100.0, 5.0, 10.0, 100.0]
+        );
+        assert!(payloads.iter().all(|p| p.engine == "analysis-1"));
+
+        let first = &payloads[5];
+        assert_eq!(first.completion, Some(AnalysisCompletion::TargetReached));
+        let statistics = first.statistics.as_ref().unwrap();
+        assert_eq!((statistics.elapsed_ms, statistics.max_depth), (31, 5));
+        assert_eq!(first.best_lines[0].san_moves[0], "d4");
+
+        let stopped = &payloads[8];
+        assert_eq!(stopped.completion, Some(AnalysisCompletion::UserStopped));
+        assert_eq!(stopped.statistics.as_ref().unwrap().elapsed_ms, 302);
+        assert_eq!(stopped.moves, ["e2e4"]);
+        assert_eq!(stopped.best_lines[0].san_moves, ["c5", "Nf3"]);
+        assert_eq!(stopped.best_lines[0].display, "+0.28");
+    }
+
+    #[test]
+    fn finished_recordings_are_checked_against_their_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.ucirec");
+        let mut recorder =
+            UciRecorder::create(path.clone(), "tab".to_string(), "engine".to_string()).unwrap();
+        let options = EngineOptions {
+            fen: START.to_string(),
+            ..Default::default()
+        };
+        recorder.search(&options, &GoMode::Depth(1));
+        recorder.gui("go depth 1\n");
+        recorder.engine("bestmove e2e4");
+        recorder
+            .finish("1.0.0".to_string(), Some("Engine".to_string()))
+            .unwrap();
+        // Finished recordings take nothing more.
+        recorder.engine("bestmove d2d4");
+        assert!(!partial_path(&path).exists());
+
+        let (header, records) = read_recording(File::open(&path).unwrap()).unwrap();
+        assert_eq!(header.records, 3);
+        assert_eq!(header.options, Some(options));
+        assert_eq!(header.go_mode, Some(GoMode::Depth(1)));
+        assert_eq!(
+            records[1],
+            UciRecord::Gui {
+                at: records[1].at(),
+                line: "go depth 1".to_string()
+            }
+        );
+
+        let mut content = Vec::new();
+        zstd::stream::copy_decode(File::open(&path).unwrap(), &mut content).unwrap();
+        let edited = String::from_utf8(content).unwrap().replace("e2e4", "d2d4");
+        let edited = zstd::encode_all(edited.as_bytes(), COMPRESSION_LEVEL).unwrap();
+        assert!(matches!(
+            read_recording(&edited[..]),
+            Err(Error::InvalidRecording(_))
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+//! Refutations of candidate moves.
+//!
+//! To show why a move is bad, the candidate is forced and the opponent's best reply is searched
+//! for. These searches run on a separate engine process per tab and engine, so they never
+//! interrupt the tab's main analysis, and concurrent requests queue behind each other on it.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::info;
+use serde::Serialize;
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, EnPassantMode, Position};
+use specta::Type;
+use tokio::sync::Mutex;
+use vampirc_uci::{parse_one, UciMessage};
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::process::{parse_uci_attrs, EngineProcess};
+use super::types::{BestMoves, GoMode};
+use super::uci::EngineStdout;
+
+/// Search time used instead of an infinite search, in ms.
+const INFINITE_FALLBACK_MOVETIME: u32 = 1000;
+
+/// Best reply to a candidate move.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct Refutation {
+    /// The refuted candidate move, in UCI notation.
+    pub refutes: String,
+    #[serde(flatten)]
+    pub line: BestMoves,
+}
+
+/// Cache key of a refutation: the engine, the position before the candidate, the candidate
+/// and the search.
+pub type RefutationKey = (String, String, String, GoMode);
+
+/// Check that `candidate` can be played after `moves` from `fen`, returning the cache key of
+/// its refutation by `engine`, or `None` when the candidate ends the game.
+fn refutation_key(
+    engine: &str,
+    fen: &str,
+    moves: &[String],
+    candidate: &str,
+    go_mode: &GoMode,
+) -> Result<Option<RefutationKey>, Error> {
+    let parsed: Fen = fen.parse()?;
+    let mut position: Chess = parsed.into_position(CastlingMode::Chess960)?;
+    for m in moves {
+        let mv = UciMove::from_ascii(m.as_bytes())?.to_move(&position)?;
+        position.play_unchecked(&mv);
+    }
+    let key_fen = Fen::from_position(position.clone(), EnPassantMode::Legal).to_string();
+    let candidate_move = UciMove::from_ascii(candidate.as_bytes())?.to_move(&position)?;
+    position.play_unchecked(&candidate_move);
+    if position.is_game_over() {
+        return Ok(None);
+    }
+    Ok(Some((
+        engine.to_string(),
+        key_fen,
+        candidate.to_string(),
+        go_mode.clone(),
+    )))
+}
+
+/// Engine process dedicated to refutation searches.
+pub struct RefutationEngine {
+    process: EngineProcess,
+    reader: EngineStdout,
+}
+
+impl RefutationEngine {
+    /// Search the position after `moves`, returning the principal variation.
+    async fn search(
+        &mut self,
+        fen: &str,
+        moves: &Vec<String>,
+        go_mode: &GoMode,
+    ) -> Result<Option<BestMoves>, Error> {
+        let parsed: Fen = fen.parse()?;
+        self.process.set_position(fen, moves).await?;
+        self.process.go(go_mode).await?;
+
+        let mut best = None;
+        while let Some(line) = self.reader.next_line().await? {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    if let Ok(line) = parse_uci_attrs(attrs, &parsed, moves) {
+                        if line.multipv == 1 && !line.uci_moves.is_empty() {
+                            best = Some(line);
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => break,
+                _ => {}
+            }
+        }
+        self.process.running = false;
+        Ok(best)
+    }
+}
+
+/// Finds refutations of candidate moves, reusing an engine process per tab and engine.
+pub struct RefutationFinder<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> RefutationFinder<'a> {
+    /// Create a new `RefutationFinder` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    /// Get the opponent's best reply after `candidate` is played from `fen` and `moves`.
+    ///
+    /// Returns `None` when the candidate ends the game, as there is nothing to reply.
+    ///
+    /// # Errors
+    /// Returns `Error` if the candidate is illegal or engine operations fail.
+    pub async fn get_refutation(
+        &self,
+        engine: String,
+        tab: String,
+        fen: String,
+        moves: Vec<String>,
+        candidate: String,
+        go_mode: GoMode,
+    ) -> Result<Option<Refutation>, Error> {
+        let go_mode = match go_mode {
+            GoMode::Infinite => GoMode::Time(INFINITE_FALLBACK_MOVETIME),
+            go_mode => go_mode,
+        };
+        // Validate everything before the engine sees it.
+        let Some(key) = refutation_key(&engine, &fen, &moves, &candidate, &go_mode)? else {
+            return Ok(None);
+        };
+        if let Some(cached) = self.state.refutation_cache.lock().unwrap().get(&key) {
+            return Ok(Some(cached.clone()));
+        }
+
+        let engine_arc = self.engine(&tab, &engine).await?;
+        let line = engine_arc
+            .lock()
+            .await
+            .search(&fen, &[moves, vec![candidate.clone()]].concat(), &go_mode)
+            .await?;
+
+        let refutation = line.map(|line| Refutation {
+            refutes: candidate,
+            line,
+        });
+        if let Some(refutation) = &refutation {
+            self.state
+                .refutation_cache
+                .lock()
+                .unwrap()
+                .put(key, refutation.clone());
+        }
+        Ok(refutation)
+    }
+
+    /// Get the refutation engine of a tab, spawning it on first use.
+    async fn engine(&self, tab: &str, engine: &str) -> Result<Arc<Mutex<RefutationEngine>>, Error> {
+        let key = (tab.to_string(), engine.to_string());
+        if let Some(existing) = self.state.refutation_engines.get(&key) {
+            return Ok(existing.clone());
+        }
+
+        info!("Starting refutation engine: tab={} engine={}", tab, engine);
+        let (process, reader) =
+            EngineProcess::new(PathBuf::from(engine), &self.state.path_scope).await?;
+        let created = Arc::new(Mutex::new(RefutationEngine { process, reader }));
+        let existing = self
+            .state
+            .refutation_engines
+            .entry(key)
+            .or_insert(created.clone())
+            .clone();
+        // Another request may have spawned one in the meantime.
+        if !Arc::ptr_eq(&existing, &created) {
+            created.lock().await.process.kill().await?;
+        }
+        Ok(existing)
+    }
+
+    /// Kill the refutation engines of a tab.
+    pub async fn kill(&self, tab: &str) -> Result<(), Error> {
+        let keys: Vec<_> = self
+            .state
+            .refutation_engines
+            .iter()
+            .map(|x| x.key().clone())
+            .filter(|key| key.0.starts_with(tab))
+            .collect();
+        for key in keys {
+            if let Some((_, engine)) = self.state.refutation_engines.remove(&key) {
+                engine.lock().await.process.kill().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn moves(uci: &str) -> Vec<String> {
+        uci.split_whitespace().map(String::from).collect()
+    }
+
+    fn refutation(refutes: &str) -> Refutation {
+        Refutation {
+            refutes: refutes.to_string(),
+            line: BestMoves::default(),
+        }
+    }
+
+    #[test]
+    fn illegal_moves_are_rejected() {
+        let depth = GoMode::Depth(10);
+        assert!(refutation_key("sf", START, &[], "e2e5", &depth).is_err());
+        assert!(refutation_key("sf", START, &moves("e2e4 e2e4"), "d7d5", &depth).is_err());
+        assert!(refutation_key("sf", START, &[], "not a move", &depth).is_err());
+        assert!(refutation_key("sf", "not a fen", &[], "e2e4", &depth).is_err());
+    }
+
+    #[test]
+    fn candidates_ending_the_game_have_nothing_to_refute() {
+        let key = refutation_key(
+            "sf",
+            START,
+            &moves("f2f3 e7e5 g2g4"),
+            "d8h4",
+            &GoMode::Depth(10),
+        );
+        assert!(matches!(key, Ok(None)));
+    }
+
+    #[test]
+    fn keys_are_of_the_position_reached() {
+        let depth = GoMode::Depth(10);
+        let by_moves = refutation_key("sf", START, &moves("e2e4 e7e5"), "g1f3", &depth);
+        let by_fen = refutation_key(
+            "sf",
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2",
+            &[],
+            "g1f3",
+            &depth,
+        );
+        assert_eq!(by_moves.unwrap(), by_fen.unwrap());
+    }
+
+    #[test]
+    fn cached_refutations_are_not_shared_between_engines() {
+        let depth = GoMode::Depth(10);
+        let key = |engine| {
+            refutation_key(engine, START, &[], "e2e4", &depth)
+                .unwrap()
+                .unwrap()
+        };
+        let mut cache = lru::LruCache::new(std::num::NonZeroUsize::new(8).unwrap());
+        cache.put(key("/engines/stockfish"), refutation("e2e4"));
+
+        assert!(cache.get(&key("/engines/stockfish")).is_some());
+        assert!(cache.get(&key("/engines/komodo")).is_none());
+        let deeper = refutation_key("/engines/stockfish", START, &[], "e2e4", &GoMode::Depth(20));
+        assert!(cache.get(&deeper.unwrap().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,255 @@
+//! Swindle chances: how hard a lost player made the win for their opponent.
+//!
+//! In a position the engine judges lost, the objective evaluation says little about a move:
+//! most lose anyway. What matters in practice is how many natural replies let the win slip.
+//! After the game move, and after the engine's best moves for comparison, the opponent's
+//! most plausible replies are searched one by one, and the share of them leaving the
+//! opponent short of a win is the move's resourcefulness. Plausible means forcing first, as
+//! players look at checks and captures before quiet moves, and earlier replies weigh more.
+//! Draw scores count as throwing the win away, which covers perpetual checks and fortresses
+//! the engine sees, and replies stalemating the player are counted on their own.
+
+use serde::Serialize;
+use shakmaty::{ByColor, Chess, Color, Move, Position, Role};
+use specta::Type;
+
+use super::types::MoveAnalysis;
+
+/// Evaluation against the player, in centipawns, from which they are lost, and below which
+/// their opponent no longer wins.
+pub const DEFAULT_SWINDLE_LOSING_CP: i32 = 300;
+
+/// Replies of the opponent searched after each move of a lost position.
+pub const SWINDLE_REPLIES: usize = 4;
+
+/// Depth each reply is searched to.
+pub const SWINDLE_DEPTH: u32 = 10;
+
+/// Practical chances a move of a lost position left.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct SwindleChances {
+    /// Share of the opponent's plausible replies throwing the win away, weighted by how
+    /// plausible they are, from 0 to 1.
+    pub resourcefulness: f64,
+    /// Replies searched.
+    pub replies: u32,
+    /// Replies throwing the win away.
+    pub throwaways: u32,
+    /// Legal replies stalemating the player.
+    pub stalemates: u32,
+    /// Whether no move the engine suggested set more traps than the game move.
+    pub best_try: bool,
+}
+
+/// Swindle chances of one player over a game.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct PlayerSwindles {
+    /// Lost positions the player moved in.
+    pub positions: u32,
+    /// Average resourcefulness of their moves there, zero without any.
+    pub resourcefulness: f64,
+    /// Moves that set at least as many traps as the engine's suggestions.
+    pub best_tries: u32,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GameSwindles {
+    pub white: PlayerSwindles,
+    pub black: PlayerSwindles,
+}
+
+/// Whether the side to move is lost, given an evaluation from White's point of view.
+pub fn is_lost(cp: i32, white_to_move: bool, losing_cp: i32) -> bool {
+    let sign = if white_to_move { 1 } else { -1 };
+    sign * cp <= -losing_cp
+}
+
+fn value(role: Role) -> u32 {
+    match role {
+        Role::Pawn => 1,
+        Role::Knight | Role::Bishop => 3,
+        Role::Rook => 5,
+        Role::Queen => 9,
+        Role::King => 0,
+    }
+}
+
+/// The `count` most plausible legal moves: checks, then captures of the most valuable
+/// pieces and promotions, then quiet moves in generation order.
+pub fn plausible_replies(position: &Chess, count: usize) -> Vec<Move> {
+    let mut moves: Vec<(bool, u32, Move)> = position
+        .legal_moves()
+        .into_iter()
+        .map(|m| {
+            let mut after = position.clone();
+            after.play_unchecked(&m);
+            let gain = m.capture().map_or(0, value) + m.promotion().map_or(0, value);
+            (after.is_check(), gain, m)
+        })
+        .collect();
+    // Stable, so equally forcing moves keep their order.
+    moves.sort_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)));
+    moves.into_iter().take(count).map(|(_, _, m)| m).collect()
+}
+
+/// Legal replies in `position` leaving the other side stalemated.
+pub fn stalemating_replies(position: &Chess) -> u32 {
+    position
+        .legal_moves()
+        .into_iter()
+        .filter(|m| {
+            let mut after = position.clone();
+            after.play_unchecked(m);
+            after.is_stalemate()
+        })
+        .count() as u32
+}
+
+/// Resourcefulness of a move, and how many replies throw the win away, from the evaluations
+/// after each reply, in centipawns from the opponent's point of view and in order of
+/// plausibility. The reply ranked `i` weighs `1 / (i + 1)`.
+pub fn resourcefulness(reply_cps: &[i32], losing_cp: i32) -> (f64, u32) {
+    let mut total = 0.0;
+    let mut thrown = 0.0;
+    let mut throwaways = 0;
+    for (i, cp) in reply_cps.iter().enumerate() {
+        let weight = 1.0 / (i + 1) as f64;
+        total += weight;
+        if *cp < losing_cp {
+            thrown += weight;
+            throwaways += 1;
+        }
+    }
+    if total == 0.0 {
+        return (0.0, 0);
+    }
+    (thrown / total, throwaways)
+}
+
+/// Totals of the swindle chances of each player. `turns` holds the side to move in each
+/// position of `analysis`.
+pub fn game_swindles(turns: &[Color], analysis: &[MoveAnalysis]) -> GameSwindles {
+    let mut players = ByColor::<PlayerSwindles>::default();
+    let mut sums = ByColor::<f64>::default();
+    for (turn, analysis) in turns.iter().zip(analysis) {
+        let Some(chances) = &analysis.swindle else {
+            continue;
+        };
+        let player = players.get_mut(*turn);
+        player.positions += 1;
+        player.best_tries += u32::from(chances.best_try);
+        *sums.get_mut(*turn) += chances.resourcefulness;
+    }
+    for color in Color::ALL {
+        let player = players.get_mut(color);
+        if player.positions > 0 {
+            player.resourcefulness = sums.get(color) / f64::from(player.positions);
+        }
+    }
+    GameSwindles {
+        white: players.white,
+        black: players.black,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode};
+
+    fn chess(fen: &str) -> Chess {
+        fen.parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap()
+    }
+
+    fn uci(m: &Move) -> String {
+        m.to_uci(CastlingMode::Standard).to_string()
+    }
+
+    fn chances(resourcefulness: f64, best_try: bool) -> MoveAnalysis {
+        MoveAnalysis {
+            swindle: Some(SwindleChances {
+                resourcefulness,
+                replies: 4,
+                throwaways: 1,
+                stalemates: 0,
+                best_try,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lost_positions_are_judged_for_the_side_to_move() {
+        assert!(is_lost(-300, true, 300));
+        assert!(!is_lost(-299, true, 300));
+        assert!(is_lost(500, false, 300));
+        assert!(!is_lost(-500, false, 300));
+    }
+
+    #[test]
+    fn forcing_replies_come_first() {
+        // After 1. e4 d5, Bb5+ is the only check and exd5 the only capture.
+        let position = chess("rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+        let replies: Vec<String> = plausible_replies(&position, 2).iter().map(uci).collect();
+        assert_eq!(replies, ["f1b5", "e4d5"]);
+        // A capture with check goes before a check alone.
+        let position = chess("3r2k1/8/8/8/8/8/8/3Q2K1 w - - 0 1");
+        assert_eq!(uci(&plausible_replies(&position, 1)[0]), "d1d8");
+        assert_eq!(
+            plausible_replies(&position, 100).len(),
+            position.legal_moves().len()
+        );
+    }
+
+    #[test]
+    fn stalemate_traps_are_counted() {
+        // Qb6 leaves the a8 king without a move.
+        let position = chess("k7/8/2K5/8/8/8/8/1Q6 w - - 0 1");
+        assert_eq!(stalemating_replies(&position), 1);
+        assert_eq!(stalemating_replies(&Chess::default()), 0);
+    }
+
+    #[test]
+    fn likelier_replies_weigh_more() {
+        assert_eq!(resourcefulness(&[], 300), (0.0, 0));
+        assert_eq!(resourcefulness(&[900, 800], 300), (0.0, 0));
+        assert_eq!(resourcefulness(&[0, -100], 300), (1.0, 2));
+        let (first, _) = resourcefulness(&[0, 900, 900], 300);
+        let (last, _) = resourcefulness(&[900, 900, 0], 300);
+        assert!((first - 6.0 / 11.0).abs() < 1e-9);
+        assert!((last - 2.0 / 11.0).abs() < 1e-9);
+        // Still winning, just less so.
+        assert_eq!(resourcefulness(&[300], 300), (0.0, 0));
+    }
+
+    #[test]
+    fn swindles_add_up_by_player() {
+        let turns = [Color::White, Color::Black, Color::White, Color::Black];
+        let analysis = [
+            chances(0.5, true),
+            MoveAnalysis::default(),
+            chances(0.25, false),
+            chances(1.0, true),
+        ];
+        let swindles = game_swindles(&turns, &analysis);
+        assert_eq!(
+            swindles.white,
+            PlayerSwindles {
+                positions: 2,
+                resourcefulness: 0.375,
+                best_tries: 1,
+            }
+        );
+        assert_eq!(
+            swindles.black,
+            PlayerSwindles {
+                positions: 1,
+                resourcefulness: 1.0,
+                best_tries: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Per-tab engine auto-start policies.
+//!
+//! Each tab says whether its engines may start on their own. Puzzle tabs turn them `Off`,
+//! while analysis tabs can `AutoResumeLast`: the last analysis started in the tab is
+//! remembered and started again once the tab is shown after the session is restored.
+//! Policies are saved with the session in the app data directory.
+//!
+//! Only tabs the frontend reported as ready start engines, so restored background tabs don't
+//! compete for the CPU. Tabs that don't keep engines running in the background stop their
+//! analysis when hidden, and auto-resuming tabs pick it up again when shown.
+
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::evalbar::EvalBarManager;
+use super::manager::EngineManager;
+use super::types::{EngineOptions, GoMode};
+
+/// Engine policies of the session's tabs, relative to the app data directory.
+const TAB_POLICIES_FILE: &str = "session/tab_engines.json";
+
+/// Analysis to start again in a tab.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisSnapshot {
+    /// Engine identifier reported with the best moves.
+    pub id: String,
+    /// Path to the engine binary.
+    pub engine: String,
+    pub go_mode: GoMode,
+    pub options: EngineOptions,
+}
+
+/// Whether the engines of a tab start on their own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TabEnginePolicy {
+    /// Engines never start on their own and stop when the tab is hidden.
+    Off,
+    /// Engines start when asked and keep running in the background.
+    #[default]
+    Manual,
+    /// The last analysis starts again when the tab is shown and stops when it is hidden.
+    ///
+    /// Without `last`, the next analysis started in the tab is remembered.
+    AutoResumeLast { last: Option<AnalysisSnapshot> },
+}
+
+impl TabEnginePolicy {
+    /// Whether the engines of the tab are stopped while it is hidden.
+    pub fn stops_when_hidden(&self) -> bool {
+        !matches!(self, TabEnginePolicy::Manual)
+    }
+
+    /// Analysis to start when the tab is shown.
+    pub fn resume(&self) -> Option<&AnalysisSnapshot> {
+        match self {
+            TabEnginePolicy::AutoResumeLast { last } => last.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Switch to `policy`, keeping the analysis remembered so far when `policy` resumes the
+    /// last analysis without naming it.
+    fn update(&mut self, mut policy: TabEnginePolicy) {
+        if let (
+            TabEnginePolicy::AutoResumeLast { last: new },
+            TabEnginePolicy::AutoResumeLast { last: old },
+        ) = (&mut policy, &mut *self)
+        {
+            if new.is_none() {
+                *new = old.take();
+            }
+        }
+        *self = policy;
+    }
+
+    /// Remember `snapshot` as the last analysis if the policy resumes it, returning whether
+    /// anything changed.
+    fn record(&mut self, snapshot: &AnalysisSnapshot) -> bool {
+        match self {
+            TabEnginePolicy::AutoResumeLast { last } if last.as_ref() != Some(snapshot) => {
+                *last = Some(snapshot.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Engine policy of a tab and whether the tab is shown.
+#[derive(Debug, Default)]
+pub struct TabEngineState {
+    pub policy: TabEnginePolicy,
+    pub visible: bool,
+}
+
+fn policies_path(app: &tauri::AppHandle) -> Result<PathBuf, Error> {
+    Ok(app
+        .path()
+        .resolve(TAB_POLICIES_FILE, BaseDirectory::AppData)?)
+}
+
+/// Starts and stops the engines of tabs according to their policies.
+pub struct TabEngineScheduler<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> TabEngineScheduler<'a> {
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    /// Load the policies saved with the previous session. Restored tabs count as hidden until
+    /// the frontend reports them ready.
+    pub fn restore(&self, app: &tauri::AppHandle) -> Result<(), Error> {
+        let path = policies_path(app)?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let policies: HashMap<String, TabEnginePolicy> =
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        for (tab, policy) in policies {
+            self.state.tab_engines.entry(tab).or_default().policy = policy;
+        }
+        Ok(())
+    }
+
+    fn save(&self, app: &tauri::AppHandle) -> Result<(), Error> {
+        let path = policies_path(app)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let policies: HashMap<String, TabEnginePolicy> = self
+            .state
+            .tab_engines
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.policy.clone()))
+            .collect();
+        std::fs::write(&path, serde_json::to_string(&policies)?)?;
+        Ok(())
+    }
+
+    /// Set the engine policy of a tab and save it with the session.
+    pub fn set_policy(
+        &self,
+        app: &tauri::AppHandle,
+        tab: String,
+        policy: TabEnginePolicy,
+    ) -> Result<(), Error> {
+        self.state
+            .tab_engines
+            .entry(tab)
+            .or_default()
+            .policy
+            .update(policy);
+        self.save(app)
+    }
+
+    /// Move the policy of tab `old` to tab `new`, saving it under its new id.
+    pub fn rename(&self, app: &tauri::AppHandle, old: &str, new: &str) -> Result<(), Error> {
+        let Some((_, entry)) = self.state.tab_engines.remove(old) else {
+            return Ok(());
+        };
+        self.state.tab_engines.insert(new.to_string(), entry);
+        self.save(app)
+    }
+
+    /// Give tab `new` the policy of tab `src`. The new tab counts as hidden until the
+    /// frontend reports it ready.
+    pub fn duplicate(&self, app: &tauri::AppHandle, src: &str, new: &str) -> Result<(), Error> {
+        let Some(policy) = self.state.tab_engines.get(src).map(|e| e.policy.clone()) else {
+            return Ok(());
+        };
+        self.state
+            .tab_engines
+            .entry(new.to_string())
+            .or_default()
+            .policy = policy;
+        self.save(app)
+    }
+
+    /// Remember an analysis started in a tab, for tabs that resume it.
+    pub fn record(&self, app: &tauri::AppHandle, tab: &str, snapshot: &AnalysisSnapshot) {
+        let changed = self
+            .state
+            .tab_engines
+            .get_mut(tab)
+            .is_some_and(|mut entry| entry.policy.record(snapshot));
+        if changed {
+            if let Err(e) = self.save(app) {
+                warn!("Failed to save the engine policy of tab {}: {}", tab, e);
+            }
+        }
+    }
+
+    /// Mark a tab as shown, resuming its eval bar, and its last analysis if its policy asks
+    /// for it.
+    ///
+    /// Returns whether an analysis was started.
+    pub async fn ready(&self, app: tauri::AppHandle, tab: String) -> Result<bool, Error> {
+        let snapshot = {
+            let mut entry = self.state.tab_engines.entry(tab.clone()).or_default();
+            entry.visible = true;
+            entry.policy.resume().cloned()
+        };
+        EvalBarManager::new(self.state.clone()).resume(&tab).await?;
+        let Some(snapshot) = snapshot else {
+            return Ok(false);
+        };
+        EngineManager::new(self.state.clone())
+            .get_best_moves(
+                snapshot.id,
+                snapshot.engine,
+                tab,
+                snapshot.go_mode,
+                snapshot.options,
+                false,
+                app,
+            )
+            .await?;
+        Ok(true)
+    }
+
+    /// Mark a tab as hidden, stopping its eval bar, and its engines unless its policy keeps
+    /// them running.
+    pub async fn hidden(&self, tab: &str) -> Result<(), Error> {
+        EvalBarManager::new(self.state.clone()).stop(tab).await?;
+        let stops = {
+            let mut entry = self.state.tab_engines.entry(tab.to_string()).or_default();
+            entry.visible = false;
+            entry.policy.stops_when_hidden()
+        };
+        if !stops {
+            return Ok(());
+        }
+        self.state.engine_pool.drop_queued(tab, None);
+        let processes: Vec<_> = self
+            .state
+            .engine_processes
+            .iter()
+            .filter(|entry| entry.key().0 == tab)
+            .map(|entry| entry.value().clone())
+            .collect();
+        for process in processes {
+            process.lock().await.stop().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(fen: &str) -> AnalysisSnapshot {
+        AnalysisSnapshot {
+            id: "stockfish".to_string(),
+            engine: "/usr/bin/stockfish".to_string(),
+            go_mode: GoMode::Infinite,
+            options: EngineOptions {
+                fen: fen.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn only_auto_resuming_tabs_resume_and_manual_tabs_keep_running() {
+        let last = Some(snapshot("8/8/8/8/8/8/8/8 w - - 0 1"));
+        assert_eq!(TabEnginePolicy::Off.resume(), None);
+        assert_eq!(TabEnginePolicy::Manual.resume(), None);
+        let auto = TabEnginePolicy::AutoResumeLast { last: last.clone() };
+        assert_eq!(auto.resume(), last.as_ref());
+
+        assert!(TabEnginePolicy::Off.stops_when_hidden());
+        assert!(!TabEnginePolicy::Manual.stops_when_hidden());
+        assert!(auto.stops_when_hidden());
+    }
+
+    #[test]
+    fn auto_resuming_tabs_remember_the_last_analysis() {
+        let first = snapshot("8/8/8/8/8/8/8/8 w - - 0 1");
+        let second = snapshot("8/8/8/8/8/8/8/8 b - - 0 1");
+
+        let mut policy = TabEnginePolicy::Manual;
+        assert!(!policy.record(&first));
+        assert_eq!(policy, TabEnginePolicy::Manual);
+
+        policy.update(TabEnginePolicy::AutoResumeLast { last: None });
+        assert!(policy.record(&first));
+        assert!(!policy.record(&first));
+        assert!(policy.record(&second));
+        assert_eq!(policy.resume(), Some(&second));
+
+        // Choosing the policy again without a snapshot keeps the one remembered.
+        policy.update(TabEnginePolicy::AutoResumeLast { last: None });
+        assert_eq!(policy.resume(), Some(&second));
+
+        policy.update(TabEnginePolicy::Off);
+        policy.update(TabEnginePolicy::AutoResumeLast { last: None });
+        assert_eq!(policy.resume(), None);
+    }
+
+    #[test]
+    fn policies_round_trip_through_the_session_file() {
+        let policies = HashMap::from([
+            ("puzzles".to_string(), TabEnginePolicy::Off),
+            (
+                "analysis".to_string(),
+                TabEnginePolicy::AutoResumeLast {
+                    last: Some(snapshot("8/8/8/8/8/8/8/8 w - - 0 1")),
+                },
+            ),
+        ]);
+        let json = serde_json::to_string(&policies).unwrap();
+        assert!(json.contains(r#""type":"autoResumeLast""#));
+        assert!(json.contains(r#""goMode":{"t":"Infinite"}"#));
+        let restored: HashMap<String, TabEnginePolicy> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, policies);
+    }
+}
@@ -0,0 +1,345 @@
+//! Syzygy tablebase probing.
+//!
+//! The app doesn't read tablebases itself: an engine with a `SyzygyPath` option is pointed at
+//! them and searches the position briefly. Engines like Stockfish probe the tablebases at the
+//! root, keep only the moves holding on to the result and report the tablebase hits along with
+//! a score telling the result, with `UCI_ShowWDL` as win, draw and loss chances.
+//!
+//! UCI has no way to report the distance to zeroing, so the result comes with the distance to
+//! mate instead when the engine sees the mate.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess, Position};
+use specta::Type;
+use vampirc_uci::{
+    parse_one,
+    uci::{Score, ScoreValue, UciOptionConfig},
+    UciInfoAttribute, UciMessage,
+};
+
+use crate::error::Error;
+use crate::scope::PathScope;
+use crate::AppState;
+
+use super::uci::UciCommunicator;
+
+/// Most pieces, kings included, of a position in the Syzygy tablebases.
+pub const MAX_TABLEBASE_PIECES: usize = 7;
+
+/// Depth searched once the tablebases are loaded, which is all root probing needs.
+const PROBE_DEPTH: u32 = 1;
+
+/// Longest wait for the engine's answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Smallest centipawn score engines give tablebase wins, well above any evaluation.
+const TABLEBASE_WIN_CP: i32 = 10_000;
+
+/// Result of a position with best play, for the side to move.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum TablebaseWdl {
+    Win,
+    Draw,
+    Loss,
+}
+
+impl TablebaseWdl {
+    /// Result told by an engine score, from the win, draw and loss chances when given.
+    fn from_score(score: &Score) -> Self {
+        if let Some((win, draw, loss)) = score.wdl {
+            return if win > draw.max(loss) {
+                TablebaseWdl::Win
+            } else if loss > draw.max(win) {
+                TablebaseWdl::Loss
+            } else {
+                TablebaseWdl::Draw
+            };
+        }
+        match score.value {
+            ScoreValue::Mate(moves) if moves > 0 => TablebaseWdl::Win,
+            ScoreValue::Mate(_) => TablebaseWdl::Loss,
+            ScoreValue::Cp(cp) if cp >= TABLEBASE_WIN_CP => TablebaseWdl::Win,
+            ScoreValue::Cp(cp) if cp <= -TABLEBASE_WIN_CP => TablebaseWdl::Loss,
+            ScoreValue::Cp(_) => TablebaseWdl::Draw,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TablebaseProbe {
+    pub wdl: TablebaseWdl,
+    /// A move keeping the result in UCI, none once the game is over.
+    pub best_move: Option<String>,
+    /// Moves to mate, positive when the side to move mates, if the engine saw it.
+    pub mate: Option<i32>,
+    /// Tablebase positions the engine probed.
+    pub tb_hits: u64,
+}
+
+/// Fail unless `position` may be in the tablebases.
+fn check_probeable(position: &Chess) -> Result<(), Error> {
+    let pieces = position.board().occupied().count();
+    if pieces > MAX_TABLEBASE_PIECES {
+        return Err(Error::NotInTablebase(format!(
+            "{} pieces, the tablebases go up to {}",
+            pieces, MAX_TABLEBASE_PIECES
+        )));
+    }
+    if !position.castles().is_empty() {
+        return Err(Error::NotInTablebase(
+            "castling is still possible".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The result of a finished game, which needs no probing.
+fn game_over_probe(position: &Chess) -> Option<TablebaseProbe> {
+    let wdl = if position.is_checkmate() {
+        TablebaseWdl::Loss
+    } else if position.is_stalemate() || position.is_insufficient_material() {
+        TablebaseWdl::Draw
+    } else {
+        return None;
+    };
+    Some(TablebaseProbe {
+        wdl,
+        best_move: None,
+        mate: None,
+        tb_hits: 0,
+    })
+}
+
+fn supports_syzygy(options: &[UciOptionConfig]) -> bool {
+    options
+        .iter()
+        .any(|option| option.get_name().eq_ignore_ascii_case("SyzygyPath"))
+}
+
+/// Tablebase hits of an `info` line, which not every parser knows about.
+fn parse_tb_hits(line: &str) -> Option<u64> {
+    let mut words = line.split_whitespace();
+    words.find(|word| *word == "tbhits")?;
+    words.next()?.parse().ok()
+}
+
+/// What the engine said about the position so far.
+#[derive(Debug, Default)]
+struct TablebaseSearch {
+    score: Option<Score>,
+    tb_hits: u64,
+    best_move: Option<String>,
+}
+
+impl TablebaseSearch {
+    /// Take in a line of engine output, returning whether the search is over.
+    fn feed(&mut self, line: &str) -> bool {
+        match parse_one(line) {
+            UciMessage::Info(attrs) => {
+                self.tb_hits = self.tb_hits.max(parse_tb_hits(line).unwrap_or(0));
+                for attr in attrs {
+                    if let UciInfoAttribute::Score(score) = attr {
+                        self.score = Some(score);
+                    }
+                }
+                false
+            }
+            UciMessage::BestMove { best_move, .. } => {
+                self.best_move = Some(best_move.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn probe(self) -> Result<TablebaseProbe, Error> {
+        let Some(score) = self.score.filter(|_| self.tb_hits > 0) else {
+            return Err(Error::NotInTablebase(
+                "the engine found no tablebase for the position".to_string(),
+            ));
+        };
+        Ok(TablebaseProbe {
+            wdl: TablebaseWdl::from_score(&score),
+            best_move: self.best_move,
+            mate: match score.value {
+                ScoreValue::Mate(moves) => Some(moves),
+                ScoreValue::Cp(_) => None,
+            },
+            tb_hits: self.tb_hits,
+        })
+    }
+}
+
+/// Have the engine at `engine` search `fen` with the tablebases of `tb_path`.
+async fn probe_with_engine(
+    engine: &Path,
+    scope: &PathScope,
+    fen: &Fen,
+    tb_path: &Path,
+) -> Result<TablebaseProbe, Error> {
+    let mut comm = UciCommunicator::spawn(engine.to_path_buf(), scope).await?;
+    let handshake = comm.handshake().await?;
+    let result = if supports_syzygy(&handshake.options) {
+        search(&mut comm, fen, tb_path).await
+    } else {
+        Err(Error::NoTablebaseSupport(
+            handshake
+                .name
+                .unwrap_or_else(|| engine.display().to_string()),
+        ))
+    };
+    let _ = comm.write_line("quit\n").await;
+    if let Some(mut child) = comm.child.take() {
+        let _ = child.kill().await;
+    }
+    result
+}
+
+async fn search(
+    comm: &mut UciCommunicator,
+    fen: &Fen,
+    tb_path: &Path,
+) -> Result<TablebaseProbe, Error> {
+    comm.write_line(&format!(
+        "setoption name SyzygyPath value {}\n",
+        tb_path.display()
+    ))
+    .await?;
+    comm.write_line("setoption name UCI_ShowWDL value true\n")
+        .await?;
+    comm.write_line(&format!("position fen {}\n", fen)).await?;
+    comm.write_line(&format!("go depth {}\n", PROBE_DEPTH))
+        .await?;
+
+    let mut search = TablebaseSearch::default();
+    let answered = tokio::time::timeout(PROBE_TIMEOUT, async {
+        while let Some(line) = comm.stdout_lines.next_line().await? {
+            if search.feed(&line) {
+                return Ok::<_, Error>(true);
+            }
+        }
+        Ok(false)
+    })
+    .await;
+    match answered {
+        Ok(Ok(true)) => search.probe(),
+        Ok(Ok(false)) => Err(Error::EngineInitFailed(
+            "Engine closed before answering".to_string(),
+        )),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(Error::EngineTimeout(
+            "Engine did not finish probing the tablebases".to_string(),
+        )),
+    }
+}
+
+/// Look a position of at most 7 pieces up in the Syzygy tablebases of `tb_path`, through
+/// the engine at `engine`, which must have a `SyzygyPath` option.
+#[tauri::command]
+#[specta::specta]
+pub async fn probe_tablebase(
+    engine: PathBuf,
+    fen: String,
+    tb_path: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<TablebaseProbe, Error> {
+    let fen = Fen::from_ascii(fen.as_bytes())?;
+    let position: Chess = fen.clone().into_position(CastlingMode::Chess960)?;
+    check_probeable(&position)?;
+    if let Some(probe) = game_over_probe(&position) {
+        return Ok(probe);
+    }
+    state.path_scope.check(&tb_path)?;
+    probe_with_engine(&engine, &state.path_scope, &fen, &tb_path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(fen: &str) -> Chess {
+        Fen::from_ascii(fen.as_bytes())
+            .unwrap()
+            .into_position(CastlingMode::Chess960)
+            .unwrap()
+    }
+
+    fn feed(lines: &[&str]) -> Result<TablebaseProbe, Error> {
+        let mut search = TablebaseSearch::default();
+        for line in lines {
+            if search.feed(line) {
+                break;
+            }
+        }
+        search.probe()
+    }
+
+    #[test]
+    fn only_small_positions_without_castling_are_probed() {
+        assert!(check_probeable(&position("8/8/8/8/8/4k3/8/4K2R w - - 0 1")).is_ok());
+        assert!(matches!(
+            check_probeable(&position("4k3/8/8/8/8/8/8/4K2R w K - 0 1")),
+            Err(Error::NotInTablebase(_))
+        ));
+        assert!(matches!(
+            check_probeable(&position(
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+            )),
+            Err(Error::NotInTablebase(_))
+        ));
+    }
+
+    #[test]
+    fn finished_games_need_no_engine() {
+        let mated = position("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(game_over_probe(&mated).unwrap().wdl, TablebaseWdl::Loss);
+        let stalemate = position("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(game_over_probe(&stalemate).unwrap().wdl, TablebaseWdl::Draw);
+        assert_eq!(
+            game_over_probe(&position("8/8/8/8/8/4k3/8/4K2R w - - 0 1")),
+            None
+        );
+    }
+
+    #[test]
+    fn results_are_read_from_wdl_or_scores() {
+        let probe = feed(&[
+            "info string Found 35 WDL and 35 DTZ tablebase files (up to 4-man).",
+            "info depth 1 seldepth 1 multipv 1 score cp 20000 wdl 1000 0 0 nodes 4 tbhits 14 pv h1h8",
+            "bestmove h1h8",
+        ])
+        .unwrap();
+        assert_eq!(probe.wdl, TablebaseWdl::Win);
+        assert_eq!(probe.best_move.as_deref(), Some("h1h8"));
+        assert_eq!((probe.mate, probe.tb_hits), (None, 14));
+
+        let probe = feed(&[
+            "info depth 1 score mate -12 nodes 4 tbhits 3 pv e3e4",
+            "bestmove e3e4",
+        ])
+        .unwrap();
+        assert_eq!((probe.wdl, probe.mate), (TablebaseWdl::Loss, Some(-12)));
+
+        let probe = feed(&[
+            "info depth 1 score cp 0 nodes 2 tbhits 2 pv a1a2",
+            "bestmove a1a2",
+        ]);
+        assert_eq!(probe.unwrap().wdl, TablebaseWdl::Draw);
+    }
+
+    #[test]
+    fn searches_without_tablebase_hits_fail() {
+        assert!(matches!(
+            feed(&[
+                "info depth 1 score cp 850 nodes 20 pv h1h8",
+                "bestmove h1h8"
+            ]),
+            Err(Error::NotInTablebase(_))
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+//! Renaming and duplicating tabs.
+//!
+//! The backend keeps the engines of a tab, its eval bar, pinned lines, refutation engines,
+//! UCI recordings, engine policy and unsaved changes under the tab's id. Renaming a tab moves
+//! all of it to the new id without interrupting any search: events sent once the rename is
+//! done carry the new id. Duplicating a tab copies its engine policy and pinned lines, and
+//! the new tab starts engines of its own when it analyses.
+
+use dashmap::DashMap;
+
+use crate::error::Error;
+use crate::AppState;
+
+use super::evalbar::EvalBarManager;
+use super::manager::rekey_processes;
+use super::pin::LinePinner;
+use super::tab_policy::TabEngineScheduler;
+
+/// Move the entries of tab `old`, for every engine, to tab `new`.
+pub(crate) fn rekey_tab_engines<V>(map: &DashMap<(String, String), V>, old: &str, new: &str) {
+    let keys: Vec<_> = map
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|key| key.0 == old)
+        .collect();
+    for key in keys {
+        if let Some(((_, engine), value)) = map.remove(&key) {
+            map.insert((new.to_string(), engine), value);
+        }
+    }
+}
+
+/// Moves and copies the backend state of tabs.
+pub struct TabManager<'a> {
+    state: tauri::State<'a, AppState>,
+}
+
+impl<'a> TabManager<'a> {
+    /// Create a new `TabManager` with the given application state.
+    pub fn new(state: tauri::State<'a, AppState>) -> Self {
+        Self { state }
+    }
+
+    /// Whether anything is kept for `tab` yet.
+    fn in_use(&self, tab: &str) -> bool {
+        let state = &self.state;
+        state.engine_processes.iter().any(|e| e.key().0 == tab)
+            || state.pinned_lines.iter().any(|e| e.key().0 == tab)
+            || state.refutation_engines.iter().any(|e| e.key().0 == tab)
+            || state.uci_recordings.iter().any(|e| e.key().0 == tab)
+            || state.evalbar_engines.contains_key(tab)
+            || state.tab_engines.contains_key(tab)
+    }
+
+    /// Move everything kept for tab `old` to tab `new`.
+    ///
+    /// # Errors
+    /// Returns `Error::TabInUse` if `new` is already a tab.
+    pub async fn rename(&self, app: &tauri::AppHandle, old: &str, new: &str) -> Result<(), Error> {
+        if old == new {
+            return Ok(());
+        }
+        if self.in_use(new) {
+            return Err(Error::TabInUse(new.to_string()));
+        }
+        rekey_processes(&self.state.engine_processes, old, new).await;
+        self.state.engine_pool.rename_tab(old, new);
+        EvalBarManager::new(self.state.clone())
+            .rename(old, new)
+            .await;
+        LinePinner::new(self.state.clone()).rename(old, new);
+        rekey_tab_engines(&self.state.refutation_engines, old, new);
+        rekey_tab_engines(&self.state.uci_recordings, old, new);
+        self.state.dirty_tabs.rename(old, new);
+        TabEngineScheduler::new(self.state.clone()).rename(app, old, new)
+    }
+
+    /// Give tab `new` the engine policy and pinned lines of tab `src`.
+    ///
+    /// # Errors
+    /// Returns `Error::TabInUse` if `new` is already a tab.
+    pub fn duplicate(&self, app: &tauri::AppHandle, src: &str, new: &str) -> Result<(), Error> {
+        if self.in_use(new) {
+            return Err(Error::TabInUse(new.to_string()));
+        }
+        LinePinner::new(self.state.clone()).duplicate(src, new);
+        TabEngineScheduler::new(self.state.clone()).duplicate(app, src, new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(tab: &str, engine: &str) -> (String, String) {
+        (tab.to_string(), engine.to_string())
+    }
+
+    #[test]
+    fn entries_of_every_engine_move_to_the_new_tab() {
+        let map = DashMap::new();
+        map.insert(key("tab-1", "a"), 1);
+        map.insert(key("tab-1", "b"), 2);
+        map.insert(key("tab-10", "a"), 10);
+        rekey_tab_engines(&map, "tab-1", "tab-2");
+        assert_eq!(map.len(), 3);
+        assert_eq!(*map.get(&key("tab-2", "a")).unwrap(), 1);
+        assert_eq!(*map.get(&key("tab-2", "b")).unwrap(), 2);
+        assert_eq!(*map.get(&key("tab-10", "a")).unwrap(), 10);
+    }
+}
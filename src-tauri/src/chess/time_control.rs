@@ -0,0 +1,610 @@
+//! Time controls, their presets and the clock enforcing them.
+//!
+//! A `TimeControl` reads and writes the PGN `TimeControl` header: `?` when unknown, `-`
+//! for untimed games, `*180` for a sandclock, and otherwise periods separated by `:`, each
+//! either `moves/seconds` or a sudden death `seconds`, with an optional `+increment`. So the
+//! classical "40 moves in 90 minutes, then 30 minutes, with 30 seconds per move" is
+//! `40/5400+30:1800+30`. Delays have no syntax in the header and are left out of it.
+//!
+//! Presets are saved by name in `time_control_presets.json` in the app data, and anything
+//! taking a time control accepts either a preset name or an inline control.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{ByColor, Color};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+
+use super::types::{GoMode, PlayersTime};
+
+const PRESETS_FILE: &str = "time_control_presets.json";
+
+/// Time given back or not charged on each move.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Delay {
+    /// The clock only starts once the delay is over.
+    Simple { seconds: u32 },
+    /// Time spent is given back after the move, up to the delay.
+    Bronstein { seconds: u32 },
+}
+
+/// Period of a time control.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub struct TimeControlStage {
+    /// Moves to make in the period, or none for the rest of the game. A last period with a
+    /// number of moves repeats.
+    pub moves: Option<u32>,
+    /// Time added at the start of the period.
+    pub seconds: u32,
+    /// Time added after each move of the period.
+    pub increment: u32,
+    pub delay: Option<Delay>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum TimeControl {
+    Unknown,
+    Untimed,
+    /// Time spent by one side is added to the other's.
+    Sandclock {
+        seconds: u32,
+    },
+    Stages {
+        stages: Vec<TimeControlStage>,
+    },
+}
+
+fn invalid(time_control: &str, reason: &str) -> Error {
+    Error::InvalidTimeControl(format!("{}: {}", time_control, reason))
+}
+
+fn parse_number(time_control: &str, number: &str) -> Result<u32, Error> {
+    number
+        .trim()
+        .parse()
+        .map_err(|_| invalid(time_control, &format!("{} is not a number", number)))
+}
+
+impl TimeControl {
+    /// Read the value of a PGN `TimeControl` header.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidTimeControl` if it doesn't follow the header's grammar.
+    pub fn parse(time_control: &str) -> Result<Self, Error> {
+        let value = time_control.trim();
+        match value {
+            "?" => return Ok(TimeControl::Unknown),
+            "-" => return Ok(TimeControl::Untimed),
+            _ => {}
+        }
+        if let Some(seconds) = value.strip_prefix('*') {
+            return Ok(TimeControl::Sandclock {
+                seconds: parse_number(time_control, seconds)?,
+            });
+        }
+
+        let fields: Vec<&str> = value.split(':').collect();
+        let mut stages = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            let (moves, time) = match field.split_once('/') {
+                Some((moves, time)) => (Some(parse_number(time_control, moves)?), time),
+                None => (None, *field),
+            };
+            if moves == Some(0) {
+                return Err(invalid(time_control, "a period needs at least one move"));
+            }
+            if moves.is_none() && i + 1 < fields.len() {
+                return Err(invalid(
+                    time_control,
+                    "only the last period can be sudden death",
+                ));
+            }
+            let (seconds, increment) = match time.split_once('+') {
+                Some((seconds, increment)) => (seconds, parse_number(time_control, increment)?),
+                None => (time, 0),
+            };
+            stages.push(TimeControlStage {
+                moves,
+                seconds: parse_number(time_control, seconds)?,
+                increment,
+                delay: None,
+            });
+        }
+        Ok(TimeControl::Stages { stages })
+    }
+
+    /// The value of a PGN `TimeControl` header, without the delays.
+    pub fn to_pgn(&self) -> String {
+        match self {
+            TimeControl::Unknown => "?".to_string(),
+            TimeControl::Untimed => "-".to_string(),
+            TimeControl::Sandclock { seconds } => format!("*{}", seconds),
+            TimeControl::Stages { stages } => stages
+                .iter()
+                .map(|stage| {
+                    let mut field = match stage.moves {
+                        Some(moves) => format!("{}/{}", moves, stage.seconds),
+                        None => stage.seconds.to_string(),
+                    };
+                    if stage.increment > 0 {
+                        field.push_str(&format!("+{}", stage.increment));
+                    }
+                    field
+                })
+                .collect::<Vec<_>>()
+                .join(":"),
+        }
+    }
+
+    /// Check a control given inline, which doesn't go through `parse`.
+    fn validate(&self) -> Result<(), Error> {
+        let TimeControl::Stages { stages } = self else {
+            return Ok(());
+        };
+        let pgn = self.to_pgn();
+        if stages.is_empty() {
+            return Err(invalid(&pgn, "no period"));
+        }
+        if stages.iter().any(|stage| stage.moves == Some(0)) {
+            return Err(invalid(&pgn, "a period needs at least one move"));
+        }
+        if stages[..stages.len() - 1]
+            .iter()
+            .any(|stage| stage.moves.is_none())
+        {
+            return Err(invalid(&pgn, "only the last period can be sudden death"));
+        }
+        Ok(())
+    }
+}
+
+/// The period of a side's next move, after `played` moves, and whether that move ends it.
+fn stage_at(stages: &[TimeControlStage], played: u32) -> (&TimeControlStage, bool) {
+    let mut start = 0;
+    for stage in stages {
+        match stage.moves {
+            None => return (stage, false),
+            Some(moves) if played < start + moves => return (stage, played + 1 == start + moves),
+            Some(moves) => start += moves,
+        }
+    }
+    let last = stages.last().expect("time controls have a period");
+    let moves = last.moves.unwrap_or(1);
+    (last, (played - start) % moves + 1 == moves)
+}
+
+/// Clocks of both sides of a game.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameClock {
+    control: TimeControl,
+    remaining: ByColor<Duration>,
+    /// Moves made by each side.
+    moves: ByColor<u32>,
+    flagged: Option<Color>,
+}
+
+impl GameClock {
+    /// Clocks set for `control`, or none for untimed games and unknown controls.
+    pub fn new(control: &TimeControl) -> Option<Self> {
+        let start = match control {
+            TimeControl::Unknown | TimeControl::Untimed => return None,
+            TimeControl::Sandclock { seconds } => *seconds,
+            TimeControl::Stages { stages } => stages.first()?.seconds,
+        };
+        Some(Self {
+            control: control.clone(),
+            remaining: ByColor::new_with(|_| Duration::from_secs(start.into())),
+            moves: ByColor::default(),
+            flagged: None,
+        })
+    }
+
+    /// The time control the clocks follow.
+    pub fn control(&self) -> &TimeControl {
+        &self.control
+    }
+
+    pub fn remaining(&self, color: Color) -> Duration {
+        *self.remaining.get(color)
+    }
+
+    /// Side whose time ran out, if any.
+    pub fn flagged(&self) -> Option<Color> {
+        self.flagged
+    }
+
+    /// Stop the clock of `color` after a move that took `elapsed`.
+    ///
+    /// Returns `false`, leaving the clock at zero, if the time ran out before the move.
+    pub fn press(&mut self, color: Color, elapsed: Duration) -> bool {
+        if self.flagged.is_some() {
+            return false;
+        }
+        let (charged, refund, increment, next) = match &self.control {
+            TimeControl::Stages { stages } => {
+                let played = *self.moves.get(color);
+                let (stage, ends) = stage_at(stages, played);
+                let (charged, refund) = match stage.delay {
+                    Some(Delay::Simple { seconds }) => (
+                        elapsed.saturating_sub(Duration::from_secs(seconds.into())),
+                        Duration::ZERO,
+                    ),
+                    Some(Delay::Bronstein { seconds }) => {
+                        (elapsed, elapsed.min(Duration::from_secs(seconds.into())))
+                    }
+                    None => (elapsed, Duration::ZERO),
+                };
+                let next = ends.then(|| stage_at(stages, played + 1).0.seconds);
+                (charged, refund, stage.increment, next)
+            }
+            _ => (elapsed, Duration::ZERO, 0, None),
+        };
+
+        let remaining = self.remaining.get_mut(color);
+        if charged >= *remaining && !charged.is_zero() {
+            *remaining = Duration::ZERO;
+            self.flagged = Some(color);
+            return false;
+        }
+        *remaining -= charged;
+        *remaining += refund + Duration::from_secs(increment.into());
+        if let Some(seconds) = next {
+            *remaining += Duration::from_secs(seconds.into());
+        }
+        if matches!(self.control, TimeControl::Sandclock { .. }) {
+            *self.remaining.get_mut(!color) += elapsed;
+        }
+        *self.moves.get_mut(color) += 1;
+        true
+    }
+
+    /// Increment of the period `color` is in.
+    fn increment(&self, color: Color) -> u32 {
+        match &self.control {
+            TimeControl::Stages { stages } => stage_at(stages, *self.moves.get(color)).0.increment,
+            _ => 0,
+        }
+    }
+
+    /// Search mode telling an engine the time left on both clocks.
+    pub fn go_mode(&self) -> GoMode {
+        let millis = |duration: Duration| duration.as_millis().min(u32::MAX.into()) as u32;
+        GoMode::PlayersTime(PlayersTime {
+            white: millis(self.remaining(Color::White)),
+            black: millis(self.remaining(Color::Black)),
+            winc: self.increment(Color::White) * 1000,
+            binc: self.increment(Color::Black) * 1000,
+            ponder: false,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct TimeControlPreset {
+    pub name: String,
+    pub spec: TimeControl,
+}
+
+/// A time control given by the name of a preset or inline.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum TimeControlChoice {
+    Preset { name: String },
+    Inline { spec: TimeControl },
+}
+
+impl TimeControlChoice {
+    /// The time control chosen, reading presets from `path`.
+    pub fn resolve_in(&self, path: &Path) -> Result<TimeControl, Error> {
+        let spec = match self {
+            TimeControlChoice::Inline { spec } => spec.clone(),
+            TimeControlChoice::Preset { name } => load_presets(path)?
+                .into_iter()
+                .find(|preset| &preset.name == name)
+                .map(|preset| preset.spec)
+                .ok_or_else(|| Error::TimeControlPresetNotFound(name.clone()))?,
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn resolve(&self, app: &tauri::AppHandle) -> Result<TimeControl, Error> {
+        self.resolve_in(&presets_path(app)?)
+    }
+}
+
+fn presets_path(app: &tauri::AppHandle) -> Result<PathBuf, Error> {
+    Ok(app.path().resolve(PRESETS_FILE, BaseDirectory::AppData)?)
+}
+
+fn load_presets(path: &Path) -> Result<Vec<TimeControlPreset>, Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn store_presets(path: &Path, presets: &[TimeControlPreset]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(presets)?)?;
+    Ok(())
+}
+
+/// Save `spec` under `name`, replacing the preset of that name if there is one.
+fn save_preset(path: &Path, name: &str, spec: TimeControl) -> Result<(), Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::InvalidTimeControl("presets need a name".to_string()));
+    }
+    spec.validate()?;
+    let mut presets = load_presets(path)?;
+    presets.retain(|preset| preset.name != name);
+    presets.push(TimeControlPreset {
+        name: name.to_string(),
+        spec,
+    });
+    presets.sort_by(|a, b| a.name.cmp(&b.name));
+    store_presets(path, &presets)
+}
+
+/// Delete the preset called `name`, returning whether there was one.
+fn delete_preset(path: &Path, name: &str) -> Result<bool, Error> {
+    let mut presets = load_presets(path)?;
+    let before = presets.len();
+    presets.retain(|preset| preset.name != name);
+    if presets.len() == before {
+        return Ok(false);
+    }
+    store_presets(path, &presets)?;
+    Ok(true)
+}
+
+/// Save a time control preset, replacing the one of the same name.
+#[tauri::command]
+#[specta::specta]
+pub async fn save_time_control_preset(
+    name: String,
+    spec: TimeControl,
+    app: tauri::AppHandle,
+) -> Result<(), Error> {
+    save_preset(&presets_path(&app)?, &name, spec)
+}
+
+/// Time control presets, by name.
+#[tauri::command]
+#[specta::specta]
+pub async fn list_time_control_presets(
+    app: tauri::AppHandle,
+) -> Result<Vec<TimeControlPreset>, Error> {
+    load_presets(&presets_path(&app)?)
+}
+
+/// Delete a time control preset, returning whether it existed.
+#[tauri::command]
+#[specta::specta]
+pub async fn delete_time_control_preset(
+    name: String,
+    app: tauri::AppHandle,
+) -> Result<bool, Error> {
+    delete_preset(&presets_path(&app)?, &name)
+}
+
+/// Read the value of a PGN `TimeControl` header.
+#[tauri::command]
+#[specta::specta]
+pub async fn parse_time_control_header(header: String) -> Result<TimeControl, Error> {
+    TimeControl::parse(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(moves: Option<u32>, seconds: u32, increment: u32) -> TimeControlStage {
+        TimeControlStage {
+            moves,
+            seconds,
+            increment,
+            delay: None,
+        }
+    }
+
+    fn stages(spec: &str) -> Vec<TimeControlStage> {
+        match TimeControl::parse(spec).unwrap() {
+            TimeControl::Stages { stages } => stages,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn headers_follow_the_pgn_grammar() {
+        assert_eq!(TimeControl::parse("?").unwrap(), TimeControl::Unknown);
+        assert_eq!(TimeControl::parse("-").unwrap(), TimeControl::Untimed);
+        assert_eq!(
+            TimeControl::parse("*180").unwrap(),
+            TimeControl::Sandclock { seconds: 180 }
+        );
+        assert_eq!(stages("300+3"), [stage(None, 300, 3)]);
+        assert_eq!(stages("4500"), [stage(None, 4500, 0)]);
+        assert_eq!(stages("40/9000"), [stage(Some(40), 9000, 0)]);
+        assert_eq!(
+            stages("40/5400+30:1800+30"),
+            [stage(Some(40), 5400, 30), stage(None, 1800, 30)]
+        );
+        assert_eq!(
+            stages("40/7200:20/3600:900+30"),
+            [
+                stage(Some(40), 7200, 0),
+                stage(Some(20), 3600, 0),
+                stage(None, 900, 30)
+            ]
+        );
+
+        for invalid in [
+            "",
+            "abc",
+            "300+",
+            "0/300",
+            "300:40/300",
+            "*",
+            "40/",
+            "?+3",
+            "5+-1",
+        ] {
+            assert!(TimeControl::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for header in [
+            "?",
+            "-",
+            "*180",
+            "300+3",
+            "4500",
+            "40/9000",
+            "40/5400+30:1800+30",
+        ] {
+            assert_eq!(TimeControl::parse(header).unwrap().to_pgn(), header);
+        }
+        // Delays have no place in the header.
+        let control = TimeControl::Stages {
+            stages: vec![TimeControlStage {
+                delay: Some(Delay::Simple { seconds: 5 }),
+                ..stage(None, 600, 0)
+            }],
+        };
+        assert_eq!(control.to_pgn(), "600");
+    }
+
+    #[test]
+    fn periods_start_at_their_move() {
+        let control = TimeControl::parse("40/5400+30:1800+30").unwrap();
+        let mut clock = GameClock::new(&control).unwrap();
+        assert_eq!(clock.remaining(Color::White), secs(5400));
+        for _ in 0..39 {
+            assert!(clock.press(Color::White, secs(100)));
+        }
+        assert_eq!(clock.remaining(Color::White), secs(5400 - 39 * 70));
+        // The 40th move adds the time of the next period.
+        assert!(clock.press(Color::White, secs(100)));
+        assert_eq!(clock.remaining(Color::White), secs(5400 - 40 * 70 + 1800));
+        assert!(clock.press(Color::White, secs(100)));
+        assert_eq!(clock.remaining(Color::White), secs(5400 - 41 * 70 + 1800));
+        // Black's clock didn't move.
+        assert_eq!(clock.remaining(Color::Black), secs(5400));
+    }
+
+    #[test]
+    fn last_periods_with_moves_repeat() {
+        let mut clock = GameClock::new(&TimeControl::parse("2/60").unwrap()).unwrap();
+        for expected in [50, 100, 90, 140, 130] {
+            assert!(clock.press(Color::Black, secs(10)));
+            assert_eq!(clock.remaining(Color::Black), secs(expected));
+        }
+    }
+
+    #[test]
+    fn delays_and_flags() {
+        let with_delay = |delay| TimeControl::Stages {
+            stages: vec![TimeControlStage {
+                delay: Some(delay),
+                ..stage(None, 60, 0)
+            }],
+        };
+        let mut clock = GameClock::new(&with_delay(Delay::Simple { seconds: 5 })).unwrap();
+        assert!(clock.press(Color::White, secs(3)));
+        assert_eq!(clock.remaining(Color::White), secs(60));
+        assert!(clock.press(Color::White, secs(8)));
+        assert_eq!(clock.remaining(Color::White), secs(57));
+
+        let mut clock = GameClock::new(&with_delay(Delay::Bronstein { seconds: 5 })).unwrap();
+        assert!(clock.press(Color::White, secs(3)));
+        assert_eq!(clock.remaining(Color::White), secs(60));
+        assert!(clock.press(Color::White, secs(8)));
+        assert_eq!(clock.remaining(Color::White), secs(57));
+
+        let mut clock = GameClock::new(&TimeControl::parse("60+2").unwrap()).unwrap();
+        assert!(!clock.press(Color::White, secs(61)));
+        assert_eq!(clock.flagged(), Some(Color::White));
+        assert_eq!(clock.remaining(Color::White), Duration::ZERO);
+        assert!(!clock.press(Color::Black, secs(1)));
+    }
+
+    #[test]
+    fn sandclocks_pass_the_time_over() {
+        let mut clock = GameClock::new(&TimeControl::parse("*60").unwrap()).unwrap();
+        assert!(clock.press(Color::White, secs(20)));
+        assert_eq!(clock.remaining(Color::White), secs(40));
+        assert_eq!(clock.remaining(Color::Black), secs(80));
+        assert!(GameClock::new(&TimeControl::Untimed).is_none());
+    }
+
+    #[test]
+    fn engines_are_told_the_clocks() {
+        let mut clock = GameClock::new(&TimeControl::parse("40/5400:1800+30").unwrap()).unwrap();
+        assert!(clock.press(Color::White, secs(10)));
+        match clock.go_mode() {
+            GoMode::PlayersTime(time) => {
+                assert_eq!((time.white, time.black), (5_390_000, 5_400_000));
+                assert_eq!((time.winc, time.binc), (0, 0));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn presets_are_saved_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(PRESETS_FILE);
+        let blitz = TimeControl::parse("300+3").unwrap();
+        let classical = TimeControl::parse("40/5400+30:1800+30").unwrap();
+
+        save_preset(&path, "Blitz", TimeControl::parse("180").unwrap()).unwrap();
+        save_preset(&path, "Classical", classical.clone()).unwrap();
+        save_preset(&path, " Blitz ", blitz.clone()).unwrap();
+        assert!(save_preset(&path, "", blitz.clone()).is_err());
+        let names: Vec<_> = load_presets(&path)
+            .unwrap()
+            .into_iter()
+            .map(|preset| preset.name)
+            .collect();
+        assert_eq!(names, ["Blitz", "Classical"]);
+
+        let preset = |name: &str| TimeControlChoice::Preset {
+            name: name.to_string(),
+        };
+        assert_eq!(preset("Blitz").resolve_in(&path).unwrap(), blitz);
+        assert!(matches!(
+            preset("Bullet").resolve_in(&path),
+            Err(Error::TimeControlPresetNotFound(_))
+        ));
+        let inline = TimeControlChoice::Inline {
+            spec: classical.clone(),
+        };
+        assert_eq!(inline.resolve_in(&path).unwrap(), classical);
+        let invalid = TimeControlChoice::Inline {
+            spec: TimeControl::Stages {
+                stages: vec![stage(None, 60, 0), stage(Some(40), 60, 0)],
+            },
+        };
+        assert!(invalid.resolve_in(&path).is_err());
+
+        assert!(delete_preset(&path, "Blitz").unwrap());
+        assert!(!delete_preset(&path, "Blitz").unwrap());
+        assert_eq!(load_presets(&path).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,421 @@
+//! Time usage analysis for finished games.
+//!
+//! This module correlates the clock readings of both players with the quality of their moves,
+//! producing a report of where time was spent and how time pressure affected the play.
+//! Clock times and evaluations are read from the `[%clk ...]` and `[%eval ...]` commands
+//! stored in the comments of the game's main line.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+use shakmaty::{Color, Position};
+use specta::Type;
+use vampirc_uci::uci::{Score, ScoreValue};
+
+use crate::db::get_game_main_line;
+use crate::error::Error;
+use crate::AppState;
+
+use super::evaluation::score_to_cp;
+use super::types::{BestMoves, MoveAnalysis};
+
+/// Centipawn loss from which a move is counted as a blunder.
+const BLUNDER_THRESHOLD: i32 = 300;
+
+/// Evaluation (from the mover's point of view) above which a position is considered winning.
+const WINNING_THRESHOLD: i32 = 150;
+
+/// Number of longest thinks reported per player.
+const BIGGEST_THINKS: usize = 3;
+
+/// Default remaining time (in seconds) below which a player is considered in time trouble.
+pub const DEFAULT_TIME_TROUBLE_SECONDS: f64 = 30.0;
+
+/// Time and quality data for a single move.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveTimeUsage {
+    pub ply: u32,
+    pub san: String,
+    pub is_white: bool,
+    /// Seconds spent on the move, if both surrounding clock readings are known.
+    pub time_spent: Option<f64>,
+    /// Seconds left on the mover's clock before the move was played.
+    pub clock_before: Option<f64>,
+    /// Evaluation before the move, in centipawns from the mover's point of view.
+    pub eval_before: Option<i32>,
+    pub cp_loss: Option<i32>,
+    pub is_blunder: bool,
+    pub in_time_trouble: bool,
+}
+
+/// Aggregated time usage for one player.
+#[derive(Serialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerTimeUsage {
+    pub moves: u32,
+    pub total_time: f64,
+    pub average_time: Option<f64>,
+    pub biggest_thinks: Vec<MoveTimeUsage>,
+    pub blunders: u32,
+    pub time_trouble_moves: u32,
+    pub time_trouble_blunders: u32,
+    pub time_trouble_blunder_rate: Option<f64>,
+    pub average_time_winning: Option<f64>,
+    pub average_time_losing: Option<f64>,
+}
+
+/// Time usage report for both players of a game.
+#[derive(Serialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeUsageReport {
+    pub has_clock_data: bool,
+    pub has_analysis: bool,
+    /// Set when either the clock or the evaluation series is missing.
+    pub partial: bool,
+    pub moves: Vec<MoveTimeUsage>,
+    pub white: PlayerTimeUsage,
+    pub black: PlayerTimeUsage,
+}
+
+/// Parse a PGN `TimeControl` header into base time and increment, in seconds.
+///
+/// Multi-stage controls (`40/5400+30:1800+30`) only use their first stage.
+pub fn parse_time_control(time_control: &str) -> Option<(f64, f64)> {
+    let stage = time_control.split(':').next()?;
+    let stage = stage.rsplit('/').next()?;
+    let mut parts = stage.split('+');
+    let base: f64 = parts.next()?.trim().parse().ok()?;
+    let increment: f64 = match parts.next() {
+        Some(inc) => inc.trim().parse().ok()?,
+        None => 0.0,
+    };
+    Some((base, increment))
+}
+
+/// Extract the value of a `[%cmd value]` command from a PGN comment.
+fn comment_command<'a>(comment: &'a str, cmd: &str) -> Option<&'a str> {
+    let start = comment.find(&format!("[%{} ", cmd))? + cmd.len() + 3;
+    let end = comment[start..].find(']')? + start;
+    Some(comment[start..end].trim())
+}
+
+/// Parse a `[%clk h:mm:ss]` command into seconds.
+pub fn parse_clock(comment: &str) -> Option<f64> {
+    let value = comment_command(comment, "clk")?;
+    value.split(':').try_fold(0.0, |acc, part| {
+        part.parse::<f64>().ok().map(|x| acc * 60.0 + x)
+    })
+}
+
+/// Parse a `[%eval ...]` command into a score from white's point of view.
+pub fn parse_eval(comment: &str) -> Option<Score> {
+    let value = comment_command(comment, "eval")?;
+    let value = value.split(',').next()?;
+    let value = if let Some(mate) = value.strip_prefix('#') {
+        ScoreValue::Mate(mate.parse().ok()?)
+    } else {
+        ScoreValue::Cp((value.parse::<f64>().ok()? * 100.0).round() as i32)
+    };
+    Some(Score {
+        value,
+        ..Default::default()
+    })
+}
+
+fn position_eval(analysis: &[MoveAnalysis], index: usize) -> Option<i32> {
+    analysis
+        .get(index)
+        .and_then(|a| a.best.first())
+        .map(|best| score_to_cp(&best.score))
+}
+
+fn average(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+fn summarize_player(moves: &[&MoveTimeUsage], winning: &[f64], losing: &[f64]) -> PlayerTimeUsage {
+    let timed: Vec<f64> = moves.iter().filter_map(|m| m.time_spent).collect();
+    let mut biggest: Vec<MoveTimeUsage> = moves
+        .iter()
+        .filter(|m| m.time_spent.is_some())
+        .map(|m| (*m).clone())
+        .collect();
+    biggest.sort_by(|a, b| {
+        b.time_spent
+            .partial_cmp(&a.time_spent)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    biggest.truncate(BIGGEST_THINKS);
+
+    let time_trouble_moves = moves.iter().filter(|m| m.in_time_trouble).count() as u32;
+    let time_trouble_blunders = moves
+        .iter()
+        .filter(|m| m.in_time_trouble && m.is_blunder)
+        .count() as u32;
+
+    PlayerTimeUsage {
+        moves: moves.len() as u32,
+        total_time: timed.iter().sum(),
+        average_time: average(&timed),
+        biggest_thinks: biggest,
+        blunders: moves.iter().filter(|m| m.is_blunder).count() as u32,
+        time_trouble_moves,
+        time_trouble_blunders,
+        time_trouble_blunder_rate: if time_trouble_moves > 0 {
+            Some(time_trouble_blunders as f64 / time_trouble_moves as f64)
+        } else {
+            None
+        },
+        average_time_winning: average(winning),
+        average_time_losing: average(losing),
+    }
+}
+
+/// Correlate clock readings with move quality.
+///
+/// # Arguments
+/// * `sans` - Main line moves in SAN.
+/// * `clocks` - Clock of the mover after each move, in seconds.
+/// * `analysis` - Analysis of each position, starting with the initial one (as returned by `analyze_game`).
+/// * `first_to_move` - Side making the first move.
+/// * `time_control` - Base time and increment, used to time the first move of each side.
+/// * `time_trouble_seconds` - Remaining time below which a move counts as played in time trouble.
+pub fn correlate_time_usage(
+    sans: &[String],
+    clocks: &[Option<f64>],
+    analysis: &[MoveAnalysis],
+    first_to_move: Color,
+    time_control: Option<(f64, f64)>,
+    time_trouble_seconds: f64,
+) -> TimeUsageReport {
+    let increment = time_control.map(|(_, inc)| inc).unwrap_or(0.0);
+    let has_clock_data = clocks.iter().any(|c| c.is_some());
+    let has_analysis = analysis.iter().any(|a| !a.best.is_empty());
+
+    let mut moves = Vec::with_capacity(sans.len());
+    let mut winning = (Vec::new(), Vec::new());
+    let mut losing = (Vec::new(), Vec::new());
+
+    for (i, san) in sans.iter().enumerate() {
+        let color = if i % 2 == 0 {
+            first_to_move
+        } else {
+            !first_to_move
+        };
+        let sign = if color.is_white() { 1 } else { -1 };
+
+        let clock_after = clocks.get(i).copied().flatten();
+        let clock_before = if i >= 2 {
+            clocks.get(i - 2).copied().flatten()
+        } else {
+            time_control.map(|(base, _)| base)
+        };
+        let time_spent = match (clock_before, clock_after) {
+            (Some(before), Some(after)) => Some((before - after + increment).max(0.0)),
+            _ => None,
+        };
+
+        let eval_before = position_eval(analysis, i).map(|e| e * sign);
+        let eval_after = position_eval(analysis, i + 1).map(|e| e * sign);
+        let cp_loss = match (eval_before, eval_after) {
+            (Some(before), Some(after)) => Some((before - after).max(0)),
+            _ => None,
+        };
+
+        let usage = MoveTimeUsage {
+            ply: i as u32 + 1,
+            san: san.clone(),
+            is_white: color.is_white(),
+            time_spent,
+            clock_before,
+            eval_before,
+            cp_loss,
+            is_blunder: cp_loss.is_some_and(|loss| loss >= BLUNDER_THRESHOLD),
+            in_time_trouble: clock_before.is_some_and(|c| c < time_trouble_seconds),
+        };
+
+        if let (Some(spent), Some(eval)) = (time_spent, eval_before) {
+            let bucket = if eval >= WINNING_THRESHOLD {
+                Some(&mut winning)
+            } else if eval <= -WINNING_THRESHOLD {
+                Some(&mut losing)
+            } else {
+                None
+            };
+            if let Some((white, black)) = bucket {
+                if color.is_white() {
+                    white.push(spent);
+                } else {
+                    black.push(spent);
+                }
+            }
+        }
+
+        moves.push(usage);
+    }
+
+    let white_moves: Vec<&MoveTimeUsage> = moves.iter().filter(|m| m.is_white).collect();
+    let black_moves: Vec<&MoveTimeUsage> = moves.iter().filter(|m| !m.is_white).collect();
+    let white = summarize_player(&white_moves, &winning.0, &losing.0);
+    let black = summarize_player(&black_moves, &winning.1, &losing.1);
+
+    TimeUsageReport {
+        has_clock_data,
+        has_analysis,
+        partial: !has_clock_data || !has_analysis,
+        moves,
+        white,
+        black,
+    }
+}
+
+/// Build a time usage report for a game stored in a database.
+pub fn build_time_usage_report(
+    file: PathBuf,
+    game_id: i32,
+    time_trouble_seconds: Option<f64>,
+    state: &tauri::State<'_, AppState>,
+) -> Result<TimeUsageReport, Error> {
+    let main_line = get_game_main_line(state, &file, game_id)?;
+
+    let clocks: Vec<Option<f64>> = main_line.comments.iter().map(|c| parse_clock(c)).collect();
+
+    // Evaluations follow the move they refer to, so the initial position is unknown.
+    let mut analysis = vec![MoveAnalysis::default()];
+    analysis.extend(main_line.comments.iter().map(|c| {
+        MoveAnalysis {
+            best: parse_eval(c)
+                .map(|score| {
+                    vec![BestMoves {
+                        score,
+                        ..Default::default()
+                    }]
+                })
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }));
+
+    Ok(correlate_time_usage(
+        &main_line.moves,
+        &clocks,
+        &analysis,
+        main_line.start_position.turn(),
+        main_line
+            .time_control
+            .as_deref()
+            .and_then(parse_time_control),
+        time_trouble_seconds.unwrap_or(DEFAULT_TIME_TROUBLE_SECONDS),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cp(value: i32) -> MoveAnalysis {
+        MoveAnalysis {
+            best: vec![BestMoves {
+                score: Score {
+                    value: ScoreValue::Cp(value),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn sans(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("m{}", i)).collect()
+    }
+
+    #[test]
+    fn parses_time_controls() {
+        assert_eq!(parse_time_control("300+3"), Some((300.0, 3.0)));
+        assert_eq!(parse_time_control("600"), Some((600.0, 0.0)));
+        assert_eq!(
+            parse_time_control("40/5400+30:1800+30"),
+            Some((5400.0, 30.0))
+        );
+        assert_eq!(parse_time_control("-"), None);
+    }
+
+    #[test]
+    fn parses_clock_and_eval_commands() {
+        assert_eq!(parse_clock("[%eval 0.2] [%clk 0:05:03]"), Some(303.0));
+        assert_eq!(parse_clock("[%clk 1:00:00.5]"), Some(3600.5));
+        assert_eq!(parse_clock("no clock here"), None);
+
+        let score = parse_eval("[%eval -1.25,22] [%clk 0:01:00]").unwrap();
+        assert!(matches!(score.value, ScoreValue::Cp(-125)));
+        let score = parse_eval("[%eval #-3]").unwrap();
+        assert!(matches!(score.value, ScoreValue::Mate(-3)));
+    }
+
+    #[test]
+    fn time_trouble_blunder_is_detected() {
+        // White blunders on its third move with 20 seconds left.
+        let clocks = vec![
+            Some(58.0),
+            Some(55.0),
+            Some(20.0),
+            Some(50.0),
+            Some(18.0),
+            Some(49.0),
+        ];
+        let analysis = vec![cp(20), cp(20), cp(20), cp(20), cp(20), cp(-400), cp(-400)];
+        let report = correlate_time_usage(
+            &sans(6),
+            &clocks,
+            &analysis,
+            Color::White,
+            Some((60.0, 0.0)),
+            30.0,
+        );
+
+        assert!(!report.partial);
+        assert_eq!(report.moves[0].time_spent, Some(2.0));
+        assert_eq!(report.moves[2].time_spent, Some(38.0));
+        assert_eq!(report.white.biggest_thinks[0].ply, 3);
+        assert_eq!(report.white.blunders, 1);
+        assert_eq!(report.white.time_trouble_moves, 1);
+        assert_eq!(report.white.time_trouble_blunders, 1);
+        assert_eq!(report.white.time_trouble_blunder_rate, Some(1.0));
+        assert_eq!(report.black.blunders, 0);
+        assert_eq!(report.black.average_time_winning, Some(1.0));
+    }
+
+    #[test]
+    fn missing_series_yield_partial_report() {
+        let report = correlate_time_usage(
+            &sans(2),
+            &[None, None],
+            &[cp(0), cp(0), cp(0)],
+            Color::White,
+            None,
+            30.0,
+        );
+        assert!(report.partial);
+        assert!(!report.has_clock_data);
+        assert!(report.has_analysis);
+        assert_eq!(report.white.average_time, None);
+
+        let report = correlate_time_usage(
+            &sans(2),
+            &[Some(10.0), Some(10.0)],
+            &[],
+            Color::White,
+            None,
+            30.0,
+        );
+        assert!(report.partial);
+        assert!(!report.has_analysis);
+        assert_eq!(report.moves[0].cp_loss, None);
+    }
+}
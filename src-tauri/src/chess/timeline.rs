@@ -0,0 +1,287 @@
+//! Positions along a line of a game, for the navigation bar.
+//!
+//! The frontend kept its own FEN for each ply, which drifted from the moves after takebacks
+//! and variation promotion. `compute_position_timeline` replays the moves in one pass
+//! instead, and `validate_timeline` lets development builds check the frontend's copy
+//! against it. Both take the start position as is, so Chess960 and games from a custom
+//! position work the same as standard ones.
+
+use serde::Serialize;
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, EnPassantMode, Position,
+};
+use specta::Type;
+
+use crate::error::Error;
+
+/// A position of the timeline.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelinePly {
+    /// Moves played since the start position.
+    pub ply: u32,
+    /// Move number of the move leading here, or of the next move for the start position.
+    pub move_number: u32,
+    /// Whether White played the move leading here, or moves first from the start position.
+    pub white: bool,
+    /// Move leading here, empty for the start position.
+    pub san: String,
+    pub uci: String,
+    pub fen: String,
+    /// Path of the position in the game tree, as child indices from the start.
+    pub path: Vec<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionTimeline {
+    /// The start position, then the position after each move that could be played.
+    pub plies: Vec<TimelinePly>,
+    /// Index in the moves of the first one that isn't legal, if any.
+    pub invalid_ply: Option<u32>,
+}
+
+/// How a timeline kept by the frontend compares to its moves.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineCheck {
+    /// Index in the FENs of the first one that doesn't follow from the moves.
+    pub first_mismatch: Option<u32>,
+    /// FEN expected at `first_mismatch`, if the moves got that far.
+    pub expected_fen: Option<String>,
+    /// Index in the moves of the first one that isn't legal, if any.
+    pub invalid_ply: Option<u32>,
+}
+
+/// Parse a start position, in Chess960 mode only when standard castling can't describe it
+/// so castling moves come out as `e1g1` in standard games.
+fn parse_position(fen: &str) -> Result<Chess, Error> {
+    let fen = Fen::from_ascii(fen.as_bytes())?;
+    let mode = CastlingMode::detect(fen.as_setup());
+    Ok(fen.into_position(mode)?)
+}
+
+fn fen_of(position: &Chess) -> String {
+    Fen::from_position(position.clone(), EnPassantMode::Legal).to_string()
+}
+
+/// Replay `moves` from `start`, stopping at the first illegal one.
+pub fn position_timeline(
+    start: &Chess,
+    moves: &[String],
+    variation_path: Option<&[u32]>,
+) -> PositionTimeline {
+    let mut position = start.clone();
+    let mut plies = Vec::with_capacity(moves.len() + 1);
+    plies.push(TimelinePly {
+        ply: 0,
+        move_number: position.fullmoves().get(),
+        white: position.turn().is_white(),
+        san: String::new(),
+        uci: String::new(),
+        fen: fen_of(&position),
+        path: Vec::new(),
+    });
+
+    let mut invalid_ply = None;
+    for (i, uci) in moves.iter().enumerate() {
+        let m = match UciMove::from_ascii(uci.as_bytes())
+            .ok()
+            .and_then(|uci| uci.to_move(&position).ok())
+        {
+            Some(m) => m,
+            None => {
+                invalid_ply = Some(i as u32);
+                break;
+            }
+        };
+        let move_number = position.fullmoves().get();
+        let white = position.turn().is_white();
+        let uci = m.to_uci(position.castles().mode()).to_string();
+        let san = SanPlus::from_move_and_play_unchecked(&mut position, &m).to_string();
+        let path = match variation_path {
+            Some(path) => path[..=i].to_vec(),
+            None => vec![0; i + 1],
+        };
+        plies.push(TimelinePly {
+            ply: i as u32 + 1,
+            move_number,
+            white,
+            san,
+            uci,
+            fen: fen_of(&position),
+            path,
+        });
+    }
+
+    PositionTimeline { plies, invalid_ply }
+}
+
+/// Check that `fens` holds the start position followed by the position after each move.
+pub fn check_timeline(fens: &[String], moves: &[String]) -> Result<TimelineCheck, Error> {
+    let Some(start) = fens.first() else {
+        return Ok(TimelineCheck {
+            first_mismatch: Some(0),
+            expected_fen: None,
+            invalid_ply: None,
+        });
+    };
+    let timeline = position_timeline(&parse_position(start)?, moves, None);
+
+    // FENs are compared once normalized, so an en passant square that can't be taken
+    // doesn't count as a difference.
+    let first_mismatch = (0..fens.len().max(timeline.plies.len())).find(|&i| {
+        let normalized = fens
+            .get(i)
+            .and_then(|fen| parse_position(fen).ok())
+            .map(|position| fen_of(&position));
+        normalized.as_ref() != timeline.plies.get(i).map(|ply| &ply.fen)
+    });
+    Ok(TimelineCheck {
+        first_mismatch: first_mismatch.map(|i| i as u32),
+        expected_fen: first_mismatch
+            .and_then(|i| timeline.plies.get(i))
+            .map(|ply| ply.fen.clone()),
+        invalid_ply: timeline.invalid_ply,
+    })
+}
+
+/// FENs, SANs and move numbers of a line of UCI moves from `fen`. `variation_path` is the
+/// path of the last position in the frontend's game tree, one child index per move.
+#[tauri::command]
+#[specta::specta]
+pub fn compute_position_timeline(
+    fen: String,
+    moves: Vec<String>,
+    variation_path: Option<Vec<u32>>,
+) -> Result<PositionTimeline, Error> {
+    if let Some(path) = &variation_path {
+        if path.len() != moves.len() {
+            return Err(Error::VariationPathMismatch(path.len(), moves.len()));
+        }
+    }
+    Ok(position_timeline(
+        &parse_position(&fen)?,
+        &moves,
+        variation_path.as_deref(),
+    ))
+}
+
+/// Check the frontend's FENs for a line against its moves, for development builds.
+#[tauri::command]
+#[specta::specta]
+pub fn validate_timeline(fens: Vec<String>, moves: Vec<String>) -> Result<TimelineCheck, Error> {
+    check_timeline(&fens, &moves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn moves(uci: &str) -> Vec<String> {
+        uci.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn timeline_of_a_variation() {
+        let timeline = compute_position_timeline(
+            START.to_string(),
+            moves("e2e4 e7e5 d1h5 b8c6 f1c4 g8f6 h5f7"),
+            Some(vec![0, 0, 1, 0, 0, 2, 0]),
+        )
+        .unwrap();
+        assert_eq!(timeline.invalid_ply, None);
+        assert_eq!(timeline.plies.len(), 8);
+        let last = &timeline.plies[7];
+        assert_eq!(last.san, "Qxf7#");
+        assert_eq!(last.move_number, 4);
+        assert!(last.white);
+        assert_eq!(last.path, vec![0, 0, 1, 0, 0, 2, 0]);
+        assert_eq!(timeline.plies[2].path, vec![0, 0]);
+        assert_eq!(
+            timeline.plies[2].fen,
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"
+        );
+
+        assert!(compute_position_timeline(START.to_string(), moves("e2e4"), Some(vec![])).is_err());
+    }
+
+    #[test]
+    fn custom_starts_and_chess960() {
+        // Black to move from a custom position.
+        let timeline = compute_position_timeline(
+            "4k3/8/8/8/8/8/4P3/4K3 b - - 10 40".to_string(),
+            moves("e8d7 e2e4"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(timeline.plies[0].move_number, 40);
+        assert_eq!(timeline.plies[1].move_number, 40);
+        assert!(!timeline.plies[1].white);
+        assert_eq!(timeline.plies[2].move_number, 41);
+        assert_eq!(timeline.plies[2].path, vec![0, 0]);
+
+        // Castling in Chess960 notation, the king taking its own rook.
+        let timeline = compute_position_timeline(
+            "1r2k3/8/8/8/8/8/8/1R3K1R w HBb - 0 1".to_string(),
+            moves("f1h1 b8a8"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(timeline.invalid_ply, None);
+        assert_eq!(timeline.plies[1].san, "O-O");
+        assert_eq!(timeline.plies[1].uci, "f1h1");
+        assert_eq!(timeline.plies[1].fen, "1r2k3/8/8/8/8/8/8/1R3RK1 b q - 1 1");
+
+        // Standard castling keeps its usual notation.
+        let timeline = compute_position_timeline(
+            "4k3/8/8/8/8/8/8/4K2R w K - 0 1".to_string(),
+            moves("e1g1"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(timeline.plies[1].uci, "e1g1");
+    }
+
+    #[test]
+    fn timelines_stop_at_the_first_illegal_move() {
+        let timeline =
+            compute_position_timeline(START.to_string(), moves("e2e4 e7e5 e4e5 d7d5"), None)
+                .unwrap();
+        assert_eq!(timeline.invalid_ply, Some(2));
+        assert_eq!(timeline.plies.len(), 3);
+
+        let timeline =
+            compute_position_timeline(START.to_string(), moves("e2e4 nonsense"), None).unwrap();
+        assert_eq!(timeline.invalid_ply, Some(1));
+    }
+
+    #[test]
+    fn validation_finds_the_first_drifting_fen() {
+        let line = moves("e2e4 d7d5");
+        let fens = vec![
+            START.to_string(),
+            // An en passant square that can't be taken is no difference.
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1".to_string(),
+            "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2".to_string(),
+        ];
+        let check = validate_timeline(fens.clone(), line.clone()).unwrap();
+        assert_eq!(check.first_mismatch, None);
+
+        // After a takeback, the frontend kept the position of the removed move.
+        let check = validate_timeline(fens.clone(), moves("e2e4")).unwrap();
+        assert_eq!(check.first_mismatch, Some(2));
+        assert_eq!(check.expected_fen, None);
+
+        let mut drifted = fens;
+        drifted[2] = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2".to_string();
+        let check = validate_timeline(drifted, line).unwrap();
+        assert_eq!(check.first_mismatch, Some(2));
+        assert_eq!(
+            check.expected_fen.as_deref(),
+            Some("rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2")
+        );
+    }
+}
@@ -9,6 +9,14 @@
 use tauri_specta::Event;
 use vampirc_uci::uci::{Score, UciOptionConfig};
 
+use super::accuracy::{GameAccuracy, JudgedMove};
+use super::budget::AdaptiveConfig;
+use super::cp_loss::MoveClassification;
+use super::effects::MoveEffects;
+use super::odds::OddsSpec;
+use super::swindle::SwindleChances;
+use super::time_control::TimeControlChoice;
+
 /// Log entry for engine GUI or engine output.
 #[derive(Debug, Clone, Serialize, Type)]
 #[serde(tag = "type", content = "value", rename_all = "camelCase")]
@@ -25,7 +33,7 @@ pub struct EngineOption {
 }
 
 /// Options for configuring engine analysis (FEN, moves, extra UCI options).
-#[derive(Deserialize, Debug, Clone, Type, Derivative, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Derivative, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 #[derivative(Default)]
 pub struct EngineOptions {
@@ -35,23 +43,30 @@ pub struct EngineOptions {
 }
 
 /// Engine search mode (depth, time, nodes, etc).
-#[derive(Deserialize, Debug, Clone, Type, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Type, PartialEq, Eq, Hash)]
 #[serde(tag = "t", content = "c")]
 pub enum GoMode {
     PlayersTime(PlayersTime),
     Depth(u32),
     Time(u32),
     Nodes(u32),
+    /// Search for a mate in at most this many moves.
+    Mate(u32),
     Infinite,
 }
 
 /// Player time controls for GoMode::PlayersTime.
-#[derive(Deserialize, Debug, Clone, Type, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Type, PartialEq, Eq, Hash)]
 pub struct PlayersTime {
     pub white: u32,
     pub black: u32,
     pub winc: u32,
     pub binc: u32,
+    /// Search while the opponent thinks, on the move the engine expects, until the move is
+    /// played (`ponderhit`) or another one is (`stop`).
+    #[serde(default)]
+    #[specta(optional)]
+    pub ponder: bool,
 }
 
 /// Best-move line from engine output, including PV, score, and stats.
@@ -68,6 +83,10 @@ pub struct BestMoves {
     #[derivative(Default(value = "1"))]
     pub multipv: u16,
     pub nps: u32,
+    /// Line of the move pinned in the tab, possibly searched outside the MultiPV window.
+    pub pinned: bool,
+    /// Score as displayed, from White's point of view.
+    pub display: String,
 }
 
 /// Event payload for best-move updates (emitted to frontend).
@@ -80,6 +99,78 @@ pub struct BestMovesPayload {
     pub fen: String,
     pub moves: Vec<String>,
     pub progress: f64,
+    /// Why the search ended, on the last payload of a search.
+    pub completion: Option<AnalysisCompletion>,
+    /// Totals of the whole search, on the last payload of a search.
+    pub statistics: Option<AnalysisStatistics>,
+    /// Fewer lines than requested, the engine not having reported the others at this depth.
+    pub partial: bool,
+    /// Reply the engine expects to its best move, on the last payload of a search.
+    pub ponder: Option<String>,
+}
+
+/// Why an engine search ended.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+pub enum AnalysisCompletion {
+    /// The search reached the requested depth, or ended on its own without a limit.
+    TargetReached,
+    /// The search was stopped or its engine killed on request.
+    UserStopped,
+    /// The engine exited in the middle of the search.
+    EngineCrashed,
+    /// The engine stopped early after finding a forced mate.
+    AutoStoppedMate,
+    /// The search used up its time or nodes.
+    BudgetExhausted,
+    /// The engine's output couldn't be read anymore.
+    Error(String),
+}
+
+/// Totals of an engine search, reported when it ends.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisStatistics {
+    pub nodes: u32,
+    /// Wall time since the search started, in milliseconds.
+    pub elapsed_ms: u32,
+    pub max_depth: u32,
+    /// Lines the engine actually reported.
+    pub multipv: u16,
+}
+
+/// Event payload sent once an engine turns out to report fewer lines than requested.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineCapabilityWarning {
+    pub engine: String,
+    pub tab: String,
+    pub requested_multipv: u16,
+    pub observed_multipv: u16,
+}
+
+/// Rules-based reason a game is over (or can be claimed as drawn).
+#[derive(Serialize, Debug, Clone, Copy, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameTermination {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    FiftyMoveRule,
+    ThreefoldRepetition,
+    /// A player ran out of time.
+    Timeout,
+}
+
+/// Result of a game analysis.
+#[derive(Serialize, Debug, Type)]
+pub struct GameAnalysis {
+    /// Analysis of each position of the game.
+    pub analysis: Vec<MoveAnalysis>,
+    /// Whether the analysis was stopped with `stop_analysis`. Positions it didn't get to are
+    /// then left out, or left without lines when it went backwards, and the annotations of the
+    /// whole game such as accuracy and novelties are left unset.
+    pub cancelled: bool,
 }
 
 /// Analysis result for a single move/position.
@@ -88,6 +179,42 @@ pub struct MoveAnalysis {
     pub best: Vec<BestMoves>,
     pub novelty: bool,
     pub is_sacrifice: bool,
+    /// Set instead of engine lines when the game is over in this position.
+    pub termination: Option<GameTermination>,
+    /// Set on the last analysed position when the rest of the game was left out because of
+    /// `AnalysisOptions::max_ply`.
+    pub truncated: bool,
+    /// Name of the opening, on the last book position of the game.
+    pub opening: Option<String>,
+    /// Accuracy of the whole game, by player and phase, on the start position.
+    pub accuracy: Option<GameAccuracy>,
+    /// Expected points lost by the move leading here, at the rating of the player who made it.
+    pub expected_points_lost: Option<f64>,
+    /// Centipawns lost by the move leading here, from the point of view of the player who
+    /// made it.
+    pub cp_loss: Option<i32>,
+    /// How the move leading here compares to the engine's choice.
+    pub classification: Option<MoveClassification>,
+    /// Depth the engine reached in this position.
+    pub depth: Option<u32>,
+    /// Whether a single move holds the evaluation here, all others losing much more.
+    pub only_move: bool,
+    /// Whether the game move from this position was the only move.
+    pub found_only_move: bool,
+    /// Whether the engines of a consensus analysis are far apart here.
+    pub engines_disagree: bool,
+    /// Lines of each engine of a consensus analysis, `best` holding the merged ones.
+    pub engine_lines: Vec<EngineLines>,
+    /// Practical chances left by the game move, when the player was lost here and
+    /// `AnalysisOptions::swindle_chances` is set.
+    pub swindle: Option<SwindleChances>,
+}
+
+/// Lines one engine of a consensus analysis found in a position.
+#[derive(Serialize, Debug, Clone, Type)]
+pub struct EngineLines {
+    pub engine: String,
+    pub best: Vec<BestMoves>,
 }
 
 /// Options for full-game analysis (FEN, moves, novelty annotation, etc).
@@ -99,6 +226,65 @@ pub struct AnalysisOptions {
     pub annotate_novelties: bool,
     pub reference_db: Option<std::path::PathBuf>,
     pub reversed: bool,
+    /// Analyse at most this many moves of the game, which keeps reports of very long games
+    /// affordable.
+    #[specta(optional)]
+    pub max_ply: Option<u32>,
+    /// Name the opening on the last book position of the game.
+    #[serde(default)]
+    #[specta(optional)]
+    pub annotate_opening: bool,
+    /// Rating of White, which moves are judged at. `DEFAULT_RATING` if unknown.
+    #[specta(optional)]
+    pub white_rating: Option<u32>,
+    /// Rating of Black, which moves are judged at. `DEFAULT_RATING` if unknown.
+    #[specta(optional)]
+    pub black_rating: Option<u32>,
+    /// Search every position to a small depth, then deepen the interesting ones for as long
+    /// as the time budget allows. The search mode of the analysis is then ignored.
+    #[specta(optional)]
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Evaluation gap between the two best moves, in centipawns, making the best one an
+    /// only move. `DEFAULT_ONLY_MOVE_GAP_CP` if unset.
+    #[specta(optional)]
+    pub only_move_gap_cp: Option<i32>,
+    /// Evaluation the only move must keep, in centipawns from the player's point of view.
+    /// `DEFAULT_SURVIVAL_CP` if unset.
+    #[specta(optional)]
+    pub only_move_survival_cp: Option<i32>,
+    /// Lines already found in positions of the game, by ply, used instead of searching them
+    /// again in the first pass of an adaptive analysis. Filled from a play session's quick
+    /// evaluations.
+    #[serde(skip)]
+    pub first_pass: Vec<Option<Vec<BestMoves>>>,
+    /// Other engines searching every position as well, for an evaluation agreed between up to
+    /// `MAX_CONSENSUS_ENGINES` engines in all.
+    #[serde(default)]
+    #[specta(optional)]
+    pub consensus_engines: Vec<String>,
+    /// Spread of the engines' evaluations, in centipawns, above which they disagree.
+    /// `DEFAULT_DISAGREEMENT_CP` if unset.
+    #[specta(optional)]
+    pub disagreement_cp: Option<i32>,
+    /// Measure the swindle chances of the moves of lost positions, searching the opponent's
+    /// plausible replies after each. Adaptive analyses spend what is left of their time
+    /// budget on it.
+    #[serde(default)]
+    #[specta(optional)]
+    pub swindle_chances: bool,
+    /// Evaluation against the player, in centipawns, from which a position is lost.
+    /// `DEFAULT_SWINDLE_LOSING_CP` if unset.
+    #[specta(optional)]
+    pub swindle_losing_cp: Option<i32>,
+    /// Centipawn loss from which a move is an inaccuracy. `DEFAULT_INACCURACY_CP` if unset.
+    #[specta(optional)]
+    pub inaccuracy_cp: Option<i32>,
+    /// Centipawn loss from which a move is a mistake. `DEFAULT_MISTAKE_CP` if unset.
+    #[specta(optional)]
+    pub mistake_cp: Option<i32>,
+    /// Centipawn loss from which a move is a blunder. `DEFAULT_BLUNDER_CP` if unset.
+    #[specta(optional)]
+    pub blunder_cp: Option<i32>,
 }
 
 /// Event payload for reporting analysis progress.
@@ -123,4 +309,107 @@ pub struct AnalysisCacheKey {
 pub struct EngineConfig {
     pub name: String,
     pub options: Vec<UciOptionConfig>,
+    /// Lines the engine reports at most, if it was seen ignoring MultiPV.
+    pub multipv_limit: Option<u16>,
+}
+
+/// Side played by the engine in a play session.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Type, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EngineColor {
+    White,
+    Black,
+}
+
+impl From<EngineColor> for shakmaty::Color {
+    fn from(color: EngineColor) -> Self {
+        match color {
+            EngineColor::White => shakmaty::Color::White,
+            EngineColor::Black => shakmaty::Color::Black,
+        }
+    }
+}
+
+/// Configuration for a play session against an engine.
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaySessionConfig {
+    pub fen: String,
+    pub engine_color: EngineColor,
+    pub go_mode: GoMode,
+    pub extra_options: Vec<EngineOption>,
+    /// Evaluate the player's moves in the background to show their accuracy so far.
+    #[serde(default)]
+    #[specta(optional)]
+    pub live_accuracy: Option<LiveAccuracyConfig>,
+    /// Start from the position of these odds instead of `fen`.
+    #[serde(default)]
+    #[specta(optional)]
+    pub odds: Option<OddsSpec>,
+    /// Play with clocks, the engine being told the time left instead of using `go_mode`.
+    #[serde(default)]
+    #[specta(optional)]
+    pub time_control: Option<TimeControlChoice>,
+}
+
+/// Quick evaluations behind the accuracy shown during a play session.
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveAccuracyConfig {
+    /// Nodes searched in each position.
+    pub nodes: u32,
+    /// Rating of the player, which moves are judged at. `DEFAULT_RATING` if unknown.
+    #[specta(optional)]
+    pub rating: Option<u32>,
+}
+
+/// Accuracy of the player's moves evaluated so far in a play session.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayAccuracy {
+    /// Average move accuracy, from 0 to 100.
+    pub current_accuracy: f64,
+    pub expected_points_lost: f64,
+    pub moves: Vec<JudgedMove>,
+}
+
+/// Event payload for a move played by the engine in a play session.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineMovePlayed {
+    pub session: String,
+    pub uci: String,
+    pub san: String,
+    /// What the move does, for sound and speech cues.
+    pub effects: MoveEffects,
+    pub fen: String,
+    pub moves: Vec<String>,
+    pub generation: u32,
+    /// Set when the engine's move ended the game.
+    pub termination: Option<GameTermination>,
+    /// Accuracy of the player so far, if the session evaluates it.
+    pub accuracy: Option<PlayAccuracy>,
+    /// Clocks after the move, if the session is timed.
+    pub clock: Option<PlayClock>,
+}
+
+/// Clocks of a timed play session.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayClock {
+    /// Time left to White, in milliseconds.
+    pub white: u32,
+    /// Time left to Black, in milliseconds.
+    pub black: u32,
+    /// Side whose time ran out, if any.
+    pub flagged: Option<EngineColor>,
+}
+
+/// Event payload for new quick evaluations of the player's moves in a play session.
+#[derive(Serialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayAccuracyUpdated {
+    pub session: String,
+    pub generation: u32,
+    pub accuracy: PlayAccuracy,
 }
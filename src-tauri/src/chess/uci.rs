@@ -6,30 +6,56 @@
 use log::{error, info};
 use std::path::PathBuf;
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
-use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, Command};
+use vampirc_uci::{parse_one, uci::UciOptionConfig, UciMessage};
 
 use crate::error::Error;
+use crate::scope::PathScope;
+
+use super::builtin::{self, BUILTIN_ENGINE};
+use super::types::EngineLog;
+
+/// Input of an engine: the stdin of its process, or the pipe to the built-in engine.
+pub type EngineStdin = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Output of an engine, line by line.
+pub type EngineStdout = Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
 
 /// Async communicator for a running UCI engine process.
 pub struct UciCommunicator {
-    pub child: Child,
-    pub stdin: ChildStdin,
-    pub stdout_lines: Lines<BufReader<ChildStdout>>,
+    /// The engine process, `None` for the built-in engine.
+    pub child: Option<Child>,
+    pub stdin: EngineStdin,
+    pub stdout_lines: EngineStdout,
 }
 
 impl UciCommunicator {
     /// Spawn a new UCI engine process and set up async I/O.
     ///
     /// # Arguments
-    /// * `path` - Path to the engine binary.
+    /// * `path` - Path to the engine binary, or `BUILTIN_ENGINE` for the built-in engine.
+    /// * `scope` - Paths the user approved, which the engine binary must be in.
     ///
     /// # Returns
     /// `UciCommunicator` with stdin and stdout line reader.
     ///
     /// # Errors
-    /// Returns `Error` if process or I/O setup fails.
-    pub async fn spawn(path: PathBuf) -> Result<Self, Error> {
+    /// Returns `Error::PathNotAuthorized` if the engine is outside `scope`, or `Error` if
+    /// process or I/O setup fails.
+    pub async fn spawn(path: PathBuf, scope: &PathScope) -> Result<Self, Error> {
+        scope.check_engine(&path)?;
+        if path.as_os_str() == BUILTIN_ENGINE {
+            info!("Starting built-in engine");
+            let (stdin, stdout_lines) = builtin::spawn();
+            return Ok(Self {
+                child: None,
+                stdin,
+                stdout_lines,
+            });
+        }
+
         let mut command = Command::new(&path);
         command.current_dir(path.parent().unwrap());
         command
@@ -44,7 +70,8 @@ pub async fn spawn(path: PathBuf) -> Result<Self, Error> {
         info!("Starting engine process: {:?}", &path);
         let stdin = child.stdin.take().ok_or(Error::NoStdin)?;
         let stdout = child.stdout.take().ok_or(Error::NoStdout)?;
-        let stdout_lines = BufReader::new(stdout).lines();
+        let stdout_lines =
+            BufReader::new(Box::new(stdout) as Box<dyn AsyncRead + Send + Unpin>).lines();
 
         // Drain stderr to avoid deadlocks when buffer fills up
         let stderr = child.stderr.take();
@@ -58,8 +85,8 @@ pub async fn spawn(path: PathBuf) -> Result<Self, Error> {
         });
 
         Ok(Self {
-            child,
-            stdin,
+            child: Some(child),
+            stdin: Box::new(stdin),
             stdout_lines,
         })
     }
@@ -76,4 +103,297 @@ pub async fn write_line(&mut self, line: &str) -> Result<(), Error> {
         self.stdin.write_all(line.as_bytes()).await?;
         Ok(())
     }
+
+    /// Send `uci` and read the engine's name and options.
+    ///
+    /// # Errors
+    /// Returns `Error` if the engine doesn't answer with `uciok` within 10 seconds.
+    pub async fn handshake(&mut self) -> Result<UciHandshake, Error> {
+        self.write_line("uci\n").await?;
+
+        let mut handshake = UciHandshake::default();
+        let uciok_received = tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(line) = self.stdout_lines.next_line().await? {
+                if handshake.feed(&line) == Some(HandshakeSignal::UciOk) {
+                    return Ok::<_, Error>(true);
+                }
+            }
+            Ok(false)
+        })
+        .await;
+        match uciok_received {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+                return Err(Error::EngineInitFailed(
+                    "Engine closed before sending uciok".to_string(),
+                ));
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                return Err(Error::EngineTimeout(
+                    "Engine did not respond to uci command".to_string(),
+                ));
+            }
+        }
+
+        // Some engines keep sending `id` and `option` lines after `uciok`.
+        while handshake.lines < MAX_HANDSHAKE_LINES {
+            match tokio::time::timeout(HANDSHAKE_QUIET_PERIOD, self.stdout_lines.next_line()).await
+            {
+                Ok(Ok(Some(line))) => {
+                    handshake.feed(&line);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(handshake)
+    }
+}
+
+/// Engine lines longer than this are truncated during the handshake.
+const MAX_HANDSHAKE_LINE_LEN: usize = 1024;
+
+/// Engine lines kept in the logs during the handshake, so a spamming engine can't exhaust memory.
+pub const MAX_HANDSHAKE_LINES: usize = 1000;
+
+/// How long to keep reading `id` and `option` lines sent after `uciok`.
+pub const HANDSHAKE_QUIET_PERIOD: Duration = Duration::from_millis(250);
+
+/// Handshake replies recognized by [`UciHandshake::feed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeSignal {
+    UciOk,
+    ReadyOk,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdField {
+    Name,
+    Author,
+}
+
+/// Tolerant parser for the engine side of the UCI handshake.
+///
+/// Some engines don't follow the protocol to the letter: they pad replies with whitespace,
+/// use other casings, interleave `info string` lines, or split an `id` line in two. Lines
+/// are trimmed and compared case-insensitively, and a line that isn't a UCI message right
+/// after an `id` line is taken as its continuation.
+#[derive(Debug, Default)]
+pub struct UciHandshake {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub options: Vec<UciOptionConfig>,
+    /// Lines received so far.
+    pub lines: usize,
+    last_id: Option<IdField>,
+}
+
+impl UciHandshake {
+    /// Trim a line and cap its length.
+    fn normalize(line: &str) -> &str {
+        let line = line.trim();
+        match line.char_indices().nth(MAX_HANDSHAKE_LINE_LEN) {
+            Some((end, _)) => &line[..end],
+            None => line,
+        }
+    }
+
+    /// Log entry for a line, until `MAX_HANDSHAKE_LINES` lines have been received.
+    pub fn log_line(&self, line: &str) -> Option<EngineLog> {
+        (self.lines <= MAX_HANDSHAKE_LINES)
+            .then(|| EngineLog::Engine(Self::normalize(line).to_string()))
+    }
+
+    /// Parse a line sent by the engine, returning the handshake reply it contains, if any.
+    pub fn feed(&mut self, line: &str) -> Option<HandshakeSignal> {
+        self.lines += 1;
+        let line = Self::normalize(line);
+        let (keyword, rest) = line
+            .split_once(char::is_whitespace)
+            .map(|(keyword, rest)| (keyword, rest.trim()))
+            .unwrap_or((line, ""));
+        let last_id = self.last_id.take();
+
+        if keyword.eq_ignore_ascii_case("uciok") {
+            return Some(HandshakeSignal::UciOk);
+        }
+        if keyword.eq_ignore_ascii_case("readyok") {
+            return Some(HandshakeSignal::ReadyOk);
+        }
+        if keyword.eq_ignore_ascii_case("id") {
+            let (field, value) = rest
+                .split_once(char::is_whitespace)
+                .map(|(field, value)| (field, value.trim()))
+                .unwrap_or((rest, ""));
+            let field = if field.eq_ignore_ascii_case("name") {
+                IdField::Name
+            } else if field.eq_ignore_ascii_case("author") {
+                IdField::Author
+            } else {
+                return None;
+            };
+            *self.id_field(field) = Some(value.to_string());
+            self.last_id = Some(field);
+        } else if keyword.eq_ignore_ascii_case("option") {
+            if let UciMessage::Option(option) = parse_one(&format!("option {}", rest)) {
+                self.options.push(option);
+            }
+        } else if let (Some(field), false) = (last_id, line.is_empty() || is_uci_keyword(keyword)) {
+            let value = self.id_field(field).get_or_insert_with(String::new);
+            if !value.is_empty() {
+                value.push(' ');
+            }
+            value.push_str(line);
+            self.last_id = Some(field);
+        }
+        None
+    }
+
+    fn id_field(&mut self, field: IdField) -> &mut Option<String> {
+        match field {
+            IdField::Name => &mut self.name,
+            IdField::Author => &mut self.author,
+        }
+    }
+}
+
+/// Whether a word starts a message an engine may send.
+fn is_uci_keyword(word: &str) -> bool {
+    [
+        "id",
+        "uciok",
+        "readyok",
+        "bestmove",
+        "copyprotection",
+        "registration",
+        "info",
+        "option",
+    ]
+    .iter()
+    .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a transcript through the handshake as `EngineProcess::new` does, returning it
+    /// once both `uciok` and `readyok` were received.
+    fn handshake(transcript: &str) -> Option<UciHandshake> {
+        let mut handshake = UciHandshake::default();
+        let mut lines = transcript.split('\n');
+        lines
+            .by_ref()
+            .find(|line| handshake.feed(line) == Some(HandshakeSignal::UciOk))?;
+        lines
+            .find(|line| handshake.feed(line) == Some(HandshakeSignal::ReadyOk))
+            .map(|_| handshake)
+    }
+
+    #[test]
+    fn recorded_transcripts_initialize() {
+        // (engine, transcript, expected name, expected author, expected options)
+        let transcripts = [
+            (
+                "standard",
+                "id name Stockfish 17\nid author the Stockfish developers\n\
+                 option name Threads type spin default 1 min 1 max 1024\n\
+                 option name Hash type spin default 16 min 1 max 33554432\nuciok\nreadyok",
+                "Stockfish 17",
+                "the Stockfish developers",
+                2,
+            ),
+            (
+                "trailing whitespace and carriage returns",
+                "id name Hobby 0.3 \r\nid author someone\r\n\
+                 option name Hash type spin default 32 min 1 max 512 \r\nuciok \r\nreadyok \r\n",
+                "Hobby 0.3",
+                "someone",
+                1,
+            ),
+            (
+                "uppercase replies",
+                "id name Shouty\nid author nobody\nUCIOK\nReadyOk",
+                "Shouty",
+                "nobody",
+                0,
+            ),
+            (
+                "info string before uciok",
+                "info string loading network\nid name Neural 2\n\
+                 info string network loaded\nid author a team\nuciok\n\
+                 info string ready soon\nreadyok",
+                "Neural 2",
+                "a team",
+                0,
+            ),
+            (
+                "id author split across lines",
+                "id name Rybka-era 2.3\nid author Some\nOne Else\n\
+                 option name Hash type spin default 64 min 1 max 1024\nuciok\nreadyok",
+                "Rybka-era 2.3",
+                "Some One Else",
+                1,
+            ),
+            (
+                "options after uciok",
+                "id name Late\nid author late author\nuciok\n\
+                 option name Hash type spin default 16 min 1 max 256\n\
+                 option name Ponder type check default false\nreadyok",
+                "Late",
+                "late author",
+                2,
+            ),
+        ];
+
+        for (engine, transcript, name, author, options) in transcripts {
+            let handshake =
+                handshake(transcript).unwrap_or_else(|| panic!("{} failed to initialize", engine));
+            assert_eq!(handshake.name.as_deref(), Some(name), "{}", engine);
+            assert_eq!(handshake.author.as_deref(), Some(author), "{}", engine);
+            assert_eq!(handshake.options.len(), options, "{}", engine);
+        }
+    }
+
+    #[test]
+    fn incomplete_handshakes_fail() {
+        assert!(handshake("id name Mute\nid author nobody").is_none());
+        assert!(handshake("id name Half\nuciok\ninfo string crashed").is_none());
+        assert!(handshake("uciokay\nreadyok").is_none());
+    }
+
+    #[test]
+    fn spamming_engines_are_capped() {
+        let mut handshake = UciHandshake::default();
+        let spam = "x".repeat(10 * MAX_HANDSHAKE_LINE_LEN);
+        let mut logs = Vec::new();
+        for _ in 0..2 * MAX_HANDSHAKE_LINES {
+            handshake.feed(&spam);
+            logs.extend(handshake.log_line(&spam));
+        }
+        assert_eq!(logs.len(), MAX_HANDSHAKE_LINES);
+        let EngineLog::Engine(line) = &logs[0] else {
+            panic!("expected an engine line");
+        };
+        assert_eq!(line.len(), MAX_HANDSHAKE_LINE_LEN);
+    }
+
+    #[tokio::test]
+    async fn engines_outside_the_scope_are_not_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let engines = dir.path().join("engines");
+        std::fs::create_dir_all(&engines).unwrap();
+        std::fs::write(dir.path().join("engine"), "").unwrap();
+        let scope = PathScope::for_root(&engines);
+
+        let spawned = UciCommunicator::spawn(dir.path().join("engine"), &scope).await;
+        assert!(matches!(spawned, Err(Error::PathNotAuthorized(_))));
+        // The built-in engine has no path to approve.
+        assert!(
+            UciCommunicator::spawn(PathBuf::from(BUILTIN_ENGINE), &scope)
+                .await
+                .is_ok()
+        );
+    }
 }
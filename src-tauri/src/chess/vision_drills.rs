@@ -0,0 +1,634 @@
+//! Board vision drills.
+//!
+//! Drills for beginners that aren't puzzles: naming squares, finding the shortest way for a
+//! knight between two squares, and spotting the pieces left hanging in a position. Random
+//! drills are drawn from a seed, returned with the drill, so a session can be replayed.
+//!
+//! Capture drills are built piece by piece: each hanging piece is placed with an attacker,
+//! and every other piece is only kept if the pieces hanging so far stay the same and the
+//! position stays legal. The finished position is checked again from its legal moves, so
+//! the hanging pieces are exactly the ones a legal capture wins for free.
+//!
+//! Checked answers are appended to `training/vision_drills.jsonl` in the app data.
+
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    attacks, fen::Fen, Bitboard, Board, CastlingMode, Chess, Color, EnPassantMode, Piece, Position,
+    Rank, Role, Setup, Square,
+};
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+
+use super::types::EngineColor;
+
+/// Most squares asked in one coordinate drill.
+const MAX_COORDINATE_PROMPTS: u32 = 200;
+/// Positions started over before giving up on a capture drill.
+const BUILD_ATTEMPTS: usize = 200;
+/// Squares tried for each piece of a capture drill.
+const PLACEMENT_ATTEMPTS: usize = 64;
+/// Pieces a capture drill may leave hanging or use as distractors.
+const DRILL_ROLES: [Role; 5] = [
+    Role::Pawn,
+    Role::Knight,
+    Role::Bishop,
+    Role::Rook,
+    Role::Queen,
+];
+
+fn parse_square(square: &str) -> Result<Square, Error> {
+    square
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidVisionDrill(format!("{} is not a square", square)))
+}
+
+fn engine_color(color: Color) -> EngineColor {
+    match color {
+        Color::White => EngineColor::White,
+        Color::Black => EngineColor::Black,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CoordinatePrompt {
+    pub square: String,
+    /// Side the board is seen from.
+    pub orientation: EngineColor,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CoordinateDrill {
+    pub seed: u64,
+    pub prompts: Vec<CoordinatePrompt>,
+}
+
+/// Squares to name, never the same one twice in a row. Without a `side`, the board is seen
+/// from either side at random.
+pub fn coordinate_drill(count: u32, side: Option<EngineColor>, seed: u64) -> CoordinateDrill {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut prompts: Vec<CoordinatePrompt> = Vec::new();
+    for _ in 0..count.min(MAX_COORDINATE_PROMPTS) {
+        let square = loop {
+            let square = Square::new(rng.gen_range(0..64)).to_string();
+            if prompts.last().is_none_or(|last| last.square != square) {
+                break square;
+            }
+        };
+        let orientation = side.unwrap_or_else(|| engine_color(Color::from_white(rng.gen())));
+        prompts.push(CoordinatePrompt {
+            square,
+            orientation,
+        });
+    }
+    CoordinateDrill { seed, prompts }
+}
+
+/// A shortest path of a knight from `from` to `to`, both included.
+///
+/// Squares are explored in order, so the same squares always give the same path.
+pub fn knight_path(from: Square, to: Square) -> Vec<Square> {
+    let mut previous: [Option<Square>; 64] = [None; 64];
+    let mut seen = Bitboard::from_square(from);
+    let mut queue = std::collections::VecDeque::from([from]);
+    while let Some(square) = queue.pop_front() {
+        if square == to {
+            break;
+        }
+        for next in attacks::knight_attacks(square) & !seen {
+            seen.add(next);
+            previous[usize::from(next)] = Some(square);
+            queue.push_back(next);
+        }
+    }
+
+    let mut path = vec![to];
+    let mut square = to;
+    while let Some(before) = previous[usize::from(square)] {
+        path.push(before);
+        square = before;
+    }
+    path.reverse();
+    path
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct KnightPathDrill {
+    pub from: String,
+    pub to: String,
+    /// Fewest knight moves between the squares.
+    pub moves: u32,
+    /// A shortest path, from `from` to `to` included.
+    pub path: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct KnightPathResult {
+    /// Whether every step is a knight move and the path ends on the target.
+    pub reached: bool,
+    /// Whether it does so in the fewest moves.
+    pub shortest: bool,
+    pub moves: u32,
+    pub shortest_moves: u32,
+    /// What is wrong with the path, if it doesn't reach the target.
+    pub error: Option<String>,
+}
+
+/// Check the squares a knight went through from `from`, in order, as submitted by the user.
+pub fn check_path(from: Square, to: Square, path: &[Square]) -> KnightPathResult {
+    let shortest_moves = knight_path(from, to).len() as u32 - 1;
+    let mut error = None;
+    let mut current = from;
+    for &square in path {
+        if !attacks::knight_attacks(current).contains(square) {
+            error = Some(format!("{} to {} is not a knight move", current, square));
+            break;
+        }
+        current = square;
+    }
+    if error.is_none() && current != to {
+        error = Some(format!("The path ends on {} instead of {}", current, to));
+    }
+    let reached = error.is_none();
+    KnightPathResult {
+        reached,
+        shortest: reached && path.len() as u32 == shortest_moves,
+        moves: path.len() as u32,
+        shortest_moves,
+        error,
+    }
+}
+
+/// Pieces of the side not to move that a legal capture wins for free: nothing defends them.
+pub fn hanging_pieces(position: &Chess) -> Bitboard {
+    let board = position.board();
+    let defender = !position.turn();
+    position
+        .legal_moves()
+        .iter()
+        .filter(|m| m.is_capture())
+        .map(|m| m.to())
+        .filter(|&square| {
+            board
+                .attacks_to(square, defender, board.occupied())
+                .is_empty()
+        })
+        .collect()
+}
+
+/// The position of `board` with `turn` to move, if it is legal and not in check.
+fn playable(board: &Board, turn: Color) -> Option<Chess> {
+    let setup = Setup {
+        board: board.clone(),
+        turn,
+        ..Setup::empty()
+    };
+    let position: Chess = Fen::from_setup(setup)
+        .into_position(CastlingMode::Standard)
+        .ok()?;
+    position.checkers().is_empty().then_some(position)
+}
+
+/// Hanging pieces and distractors of a capture drill of `difficulty`, from 1 to 5.
+fn drill_shape(difficulty: u8) -> (usize, usize) {
+    match difficulty.clamp(1, 5) {
+        1 => (1, 2),
+        2 => (1, 4),
+        3 => (2, 4),
+        4 => (2, 6),
+        _ => (3, 6),
+    }
+}
+
+fn random_empty_square(rng: &mut StdRng, board: &Board, role: Role) -> Option<Square> {
+    let free: Vec<Square> = Square::ALL
+        .into_iter()
+        .filter(|&square| board.piece_at(square).is_none())
+        .filter(|square| role != Role::Pawn || !matches!(square.rank(), Rank::First | Rank::Eighth))
+        .collect();
+    free.choose(rng).copied()
+}
+
+/// Place a piece with `place`, keeping it only if the position stays playable with exactly
+/// `hanging` hanging.
+fn place_with(
+    rng: &mut StdRng,
+    board: &mut Board,
+    turn: Color,
+    hanging: Bitboard,
+    mut place: impl FnMut(&mut StdRng, &mut Board) -> Option<Bitboard>,
+) -> Option<Bitboard> {
+    for _ in 0..PLACEMENT_ATTEMPTS {
+        let mut candidate = board.clone();
+        let Some(expected) = place(rng, &mut candidate).map(|added| hanging | added) else {
+            continue;
+        };
+        if playable(&candidate, turn).is_some_and(|p| hanging_pieces(&p) == expected) {
+            *board = candidate;
+            return Some(expected);
+        }
+    }
+    None
+}
+
+/// One attempt at a position with `count` hanging pieces and `distractors` other pieces.
+fn build_capture_drill(
+    rng: &mut StdRng,
+    turn: Color,
+    count: usize,
+    distractors: usize,
+) -> Option<(Chess, Bitboard)> {
+    let mut board = Board::empty();
+    let king = Square::new(rng.gen_range(0..64));
+    let other_king = random_empty_square(rng, &board, Role::King)
+        .filter(|&square| square != king && !attacks::king_attacks(king).contains(square))?;
+    board.set_piece_at(king, turn.king());
+    board.set_piece_at(other_king, (!turn).king());
+
+    let mut hanging = Bitboard::EMPTY;
+    for _ in 0..count {
+        hanging = place_with(rng, &mut board, turn, hanging, |rng, board| {
+            let target = Piece {
+                color: !turn,
+                role: *DRILL_ROLES.choose(rng)?,
+            };
+            let target_square = random_empty_square(rng, board, target.role)?;
+            board.set_piece_at(target_square, target);
+            let attacker = Piece {
+                color: turn,
+                role: *DRILL_ROLES.choose(rng)?,
+            };
+            let attacking: Vec<Square> = Square::ALL
+                .into_iter()
+                .filter(|&square| board.piece_at(square).is_none())
+                .filter(|square| {
+                    attacker.role != Role::Pawn
+                        || !matches!(square.rank(), Rank::First | Rank::Eighth)
+                })
+                .filter(|&square| {
+                    attacks::attacks(square, attacker, board.occupied()).contains(target_square)
+                })
+                .collect();
+            board.set_piece_at(*attacking.choose(rng)?, attacker);
+            Some(Bitboard::from_square(target_square))
+        })?;
+    }
+    for _ in 0..distractors {
+        hanging = place_with(rng, &mut board, turn, hanging, |rng, board| {
+            let piece = Piece {
+                color: Color::from_white(rng.gen()),
+                role: *DRILL_ROLES.choose(rng)?,
+            };
+            board.set_piece_at(random_empty_square(rng, board, piece.role)?, piece);
+            Some(Bitboard::EMPTY)
+        })?;
+    }
+
+    let position = playable(&board, turn)?;
+    (hanging_pieces(&position) == hanging && hanging.count() == count)
+        .then_some((position, hanging))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CaptureVisionDrill {
+    pub seed: u64,
+    pub difficulty: u8,
+    pub fen: String,
+    /// Squares of the pieces the side to move can take for free.
+    pub hanging: Vec<String>,
+}
+
+/// A legal position where the side to move can win exactly `difficulty`-dependent pieces
+/// for free, among defended and unrelated pieces.
+///
+/// # Errors
+/// Returns `Error::InvalidVisionDrill` if no position was found from `seed`.
+pub fn capture_vision_drill(difficulty: u8, seed: u64) -> Result<CaptureVisionDrill, Error> {
+    let difficulty = difficulty.clamp(1, 5);
+    let (count, distractors) = drill_shape(difficulty);
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..BUILD_ATTEMPTS {
+        let turn = Color::from_white(rng.gen());
+        if let Some((position, hanging)) = build_capture_drill(&mut rng, turn, count, distractors) {
+            return Ok(CaptureVisionDrill {
+                seed,
+                difficulty,
+                fen: Fen::from_position(position, EnPassantMode::Legal).to_string(),
+                hanging: hanging.into_iter().map(|s| s.to_string()).collect(),
+            });
+        }
+    }
+    Err(Error::InvalidVisionDrill(format!(
+        "no position found from seed {}",
+        seed
+    )))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CaptureVisionResult {
+    /// Hanging pieces the user found.
+    pub found: Vec<String>,
+    /// Hanging pieces the user missed.
+    pub missed: Vec<String>,
+    /// Squares the user named that don't hold a hanging piece.
+    pub wrong: Vec<String>,
+}
+
+/// Compare the squares the user named with the hanging pieces of `fen`.
+pub fn check_captures(fen: &str, squares: &[Square]) -> Result<CaptureVisionResult, Error> {
+    let position: Chess = fen.parse::<Fen>()?.into_position(CastlingMode::Standard)?;
+    let hanging = hanging_pieces(&position);
+    let named: Bitboard = squares.iter().copied().collect();
+    let names = |squares: Bitboard| squares.into_iter().map(|s| s.to_string()).collect();
+    Ok(CaptureVisionResult {
+        found: names(named & hanging),
+        missed: names(hanging & !named),
+        wrong: names(named & !hanging),
+    })
+}
+
+/// Append a checked drill to the training store.
+fn record_result(
+    app: &tauri::AppHandle,
+    kind: &str,
+    drill: serde_json::Value,
+    result: impl Serialize,
+) {
+    let outcome = (|| -> Result<(), Error> {
+        let path = app
+            .path()
+            .resolve("training/vision_drills.jsonl", BaseDirectory::AppData)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let entry = serde_json::json!({
+            "kind": kind,
+            "drill": drill,
+            "result": result,
+            "timestamp": std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        });
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    })();
+    if let Err(e) = outcome {
+        log::warn!("Failed to record vision drill: {}", e);
+    }
+}
+
+/// Draw `count` squares to name, seen from `side` or from either side at random.
+#[tauri::command]
+#[specta::specta]
+pub async fn generate_coordinate_drill(
+    count: u32,
+    side: Option<EngineColor>,
+    seed: Option<u64>,
+) -> Result<CoordinateDrill, Error> {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    Ok(coordinate_drill(count, side, seed))
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CoordinateResult {
+    pub correct: u32,
+    pub total: u32,
+}
+
+/// Check the squares the user named for each prompt of a coordinate drill.
+#[tauri::command]
+#[specta::specta]
+pub async fn submit_coordinate_drill(
+    drill: CoordinateDrill,
+    answers: Vec<String>,
+    app: tauri::AppHandle,
+) -> Result<CoordinateResult, Error> {
+    let correct = drill
+        .prompts
+        .iter()
+        .zip(&answers)
+        .filter(|(prompt, answer)| prompt.square.eq_ignore_ascii_case(answer.trim()))
+        .count() as u32;
+    let result = CoordinateResult {
+        correct,
+        total: drill.prompts.len() as u32,
+    };
+    record_result(
+        &app,
+        "coordinates",
+        serde_json::json!({ "seed": drill.seed }),
+        &result,
+    );
+    Ok(result)
+}
+
+/// The fewest knight moves from `from` to `to`, with a path taking them.
+#[tauri::command]
+#[specta::specta]
+pub async fn generate_knight_path_drill(
+    from: String,
+    to: String,
+) -> Result<KnightPathDrill, Error> {
+    let path = knight_path(parse_square(&from)?, parse_square(&to)?);
+    Ok(KnightPathDrill {
+        from,
+        to,
+        moves: path.len() as u32 - 1,
+        path: path.into_iter().map(|s| s.to_string()).collect(),
+    })
+}
+
+/// Check a knight path submitted by the user: the squares visited after `from`, in order.
+#[tauri::command]
+#[specta::specta]
+pub async fn check_knight_path(
+    from: String,
+    to: String,
+    path: Vec<String>,
+    app: tauri::AppHandle,
+) -> Result<KnightPathResult, Error> {
+    let squares = path
+        .iter()
+        .map(|square| parse_square(square))
+        .collect::<Result<Vec<_>, _>>()?;
+    let result = check_path(parse_square(&from)?, parse_square(&to)?, &squares);
+    record_result(
+        &app,
+        "knight_path",
+        serde_json::json!({ "from": from, "to": to }),
+        &result,
+    );
+    Ok(result)
+}
+
+/// A position with pieces hanging for the side to move, more of them and more distractors
+/// as `difficulty` goes from 1 to 5.
+#[tauri::command]
+#[specta::specta]
+pub async fn generate_capture_vision_drill(
+    difficulty: u8,
+    seed: Option<u64>,
+) -> Result<CaptureVisionDrill, Error> {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    capture_vision_drill(difficulty, seed)
+}
+
+/// Check the squares of the hanging pieces the user found in a capture drill.
+#[tauri::command]
+#[specta::specta]
+pub async fn check_capture_vision(
+    drill: CaptureVisionDrill,
+    squares: Vec<String>,
+    app: tauri::AppHandle,
+) -> Result<CaptureVisionResult, Error> {
+    let squares = squares
+        .iter()
+        .map(|square| parse_square(square))
+        .collect::<Result<Vec<_>, _>>()?;
+    let result = check_captures(&drill.fen, &squares)?;
+    record_result(
+        &app,
+        "captures",
+        serde_json::json!({ "seed": drill.seed, "difficulty": drill.difficulty, "fen": drill.fen }),
+        &result,
+    );
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(name: &str) -> Square {
+        name.parse().unwrap()
+    }
+
+    fn squares(names: &[&str]) -> Vec<Square> {
+        names.iter().map(|name| square(name)).collect()
+    }
+
+    #[test]
+    fn coordinate_drills_follow_their_seed() {
+        let drill = coordinate_drill(30, None, 7);
+        assert_eq!(drill.prompts.len(), 30);
+        assert_eq!(coordinate_drill(30, None, 7), drill);
+        assert_ne!(coordinate_drill(30, None, 8).prompts, drill.prompts);
+        assert!(drill
+            .prompts
+            .windows(2)
+            .all(|pair| pair[0].square != pair[1].square));
+
+        let drill = coordinate_drill(10, Some(EngineColor::Black), 7);
+        assert!(drill
+            .prompts
+            .iter()
+            .all(|prompt| prompt.orientation == EngineColor::Black));
+        assert_eq!(coordinate_drill(1000, None, 1).prompts.len(), 200);
+    }
+
+    #[test]
+    fn knight_paths_are_shortest() {
+        assert_eq!(knight_path(square("a1"), square("a1")), squares(&["a1"]));
+        assert_eq!(
+            knight_path(square("g1"), square("f3")),
+            squares(&["g1", "f3"])
+        );
+        // The corner to its diagonal neighbour is the longest short distance.
+        assert_eq!(knight_path(square("a1"), square("b2")).len(), 5);
+        assert_eq!(knight_path(square("a1"), square("h8")).len(), 7);
+
+        for from in Square::ALL {
+            for to in [square("d4"), square("h1")] {
+                let path = knight_path(from, to);
+                assert_eq!(path.first(), Some(&from));
+                assert_eq!(path.last(), Some(&to));
+                assert!(path
+                    .windows(2)
+                    .all(|hop| attacks::knight_attacks(hop[0]).contains(hop[1])));
+            }
+        }
+    }
+
+    #[test]
+    fn submitted_knight_paths_are_checked() {
+        let result = check_path(square("b1"), square("d4"), &squares(&["c3", "e2", "d4"]));
+        assert!(result.reached && result.shortest);
+        assert_eq!((result.moves, result.shortest_moves), (3, 3));
+
+        let detour = squares(&["a3", "b5", "c3", "e2", "d4"]);
+        let result = check_path(square("b1"), square("d4"), &detour);
+        assert!(result.reached && !result.shortest);
+        assert_eq!(result.moves, 5);
+        let result = check_path(square("b1"), square("d4"), &squares(&["c3", "d4"]));
+        assert!(!result.reached);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("c3 to d4 is not a knight move")
+        );
+        let result = check_path(square("b1"), square("d4"), &squares(&["a3", "c2"]));
+        assert_eq!(
+            result.error.as_deref(),
+            Some("The path ends on c2 instead of d4")
+        );
+        let result = check_path(square("b1"), square("d4"), &squares(&[]));
+        assert!(!result.reached);
+    }
+
+    #[test]
+    fn hanging_pieces_need_a_legal_capture_and_no_defender() {
+        let hanging = |fen: &str| -> Vec<String> {
+            let position: Chess = fen
+                .parse::<Fen>()
+                .unwrap()
+                .into_position(CastlingMode::Standard)
+                .unwrap();
+            hanging_pieces(&position)
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect()
+        };
+        // The knight on c6 is defended, the one on f6 isn't.
+        assert_eq!(hanging("4k3/1p6/2n2n2/8/8/2Q5/8/4K3 w - - 0 1"), ["f6"]);
+        // The rook can't leave the e-file, being pinned to its king, and the rook it could
+        // take there is defended.
+        assert!(hanging("3kr3/8/8/8/8/1n2R3/8/4K3 w - - 0 1").is_empty());
+    }
+
+    #[test]
+    fn capture_drills_have_their_hanging_pieces() {
+        for difficulty in 1..=5 {
+            let (count, _) = drill_shape(difficulty);
+            for seed in 0..40 {
+                let drill = capture_vision_drill(difficulty, seed).unwrap();
+                let position: Chess = drill
+                    .fen
+                    .parse::<Fen>()
+                    .unwrap()
+                    .into_position(CastlingMode::Standard)
+                    .unwrap();
+                assert!(position.checkers().is_empty());
+                assert_eq!(drill.hanging.len(), count, "{}", drill.fen);
+
+                let result = check_captures(&drill.fen, &squares(&[])).unwrap();
+                assert_eq!(result.missed, drill.hanging, "{}", drill.fen);
+                let named: Vec<&str> = drill.hanging.iter().map(|s| s.as_str()).collect();
+                let result = check_captures(&drill.fen, &squares(&named)).unwrap();
+                assert!(result.missed.is_empty() && result.wrong.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn capture_drills_follow_their_seed() {
+        let drill = capture_vision_drill(3, 11).unwrap();
+        assert_eq!(capture_vision_drill(3, 11).unwrap(), drill);
+        assert_eq!(capture_vision_drill(9, 11).unwrap().difficulty, 5);
+    }
+}
@@ -0,0 +1,546 @@
+//! Games and positions pasted by the user.
+//!
+//! Pasted text can be a PGN, a bare move list, a FEN, or a Lichess or Chess.com game URL,
+//! often with typographic quotes, figurines or evaluation glyphs from wherever it was copied.
+//! The text is cleaned up, then each interpretation that applies is scored; when the best
+//! ones come close, the UI asks which one was meant.
+//!
+//! Lichess games are downloaded from its public export API. Chess.com has no public API for
+//! single games, so its URLs are returned for the frontend to open.
+
+use std::time::Duration;
+
+use log::warn;
+use reqwest::Client;
+use serde::Serialize;
+use shakmaty::{fen::Fen, san::SanPlus, CastlingMode, Chess, Position};
+use specta::Type;
+
+use crate::error::Error;
+use crate::lexer::{lex, Token};
+use crate::online_stats::{OnlinePlatform, USER_AGENT};
+
+/// Interpretations scoring within this much of the best one are offered to the user too.
+const AMBIGUITY_MARGIN: f32 = 0.25;
+
+/// Confidence in a game URL found among other text, rather than pasted alone.
+const EMBEDDED_URL_CONFIDENCE: f32 = 0.7;
+
+/// Lichess pages whose path looks like a game ID.
+const RESERVED_LICHESS_PATHS: [&str; 6] = [
+    "training", "analysis", "practice", "streamer", "tutorial", "insights",
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ClipboardKind {
+    Pgn,
+    MoveList,
+    Fen,
+    GameUrl,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClipboardContent {
+    /// A game, with the Seven Tag Roster filled in for bare move lists.
+    Game {
+        pgn: String,
+        tokens: Vec<Token>,
+        /// Move to show first, from the anchor of a game URL.
+        ply: Option<u32>,
+    },
+    Position {
+        fen: String,
+    },
+    /// A game URL that wasn't downloaded here.
+    Fetch {
+        platform: OnlinePlatform,
+        game_id: String,
+        url: String,
+        /// Public PGN export of the game, if the site has one.
+        export_url: Option<String>,
+        ply: Option<u32>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct ClipboardCandidate {
+    pub kind: ClipboardKind,
+    /// From 0 to 1.
+    pub confidence: f32,
+    pub content: ClipboardContent,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct ClipboardParse {
+    /// Interpretations of the text, most likely first. Empty if nothing was recognized.
+    pub candidates: Vec<ClipboardCandidate>,
+    /// Whether several candidates are about as likely, so the user should pick one.
+    pub ambiguous: bool,
+}
+
+/// Replace the typography of rich text editors and chess sites with plain PGN.
+fn normalize(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{00AB}' | '\u{00BB}' => out.push('"'),
+            '\u{2018}' | '\u{2019}' => out.push('\''),
+            '\u{00A0}' | '\u{2007}' | '\u{202F}' | '\t' => out.push(' '),
+            '\u{200B}' | '\u{FEFF}' | '\r' => {}
+            '\u{2010}'..='\u{2015}' | '\u{2212}' => out.push('-'),
+            '\u{2026}' => out.push_str("..."),
+            '\u{00BD}' => out.push_str("1/2"),
+            '\u{2654}' | '\u{265A}' => out.push('K'),
+            '\u{2655}' | '\u{265B}' => out.push('Q'),
+            '\u{2656}' | '\u{265C}' => out.push('R'),
+            '\u{2657}' | '\u{265D}' => out.push('B'),
+            '\u{2658}' | '\u{265E}' => out.push('N'),
+            '\u{2659}' | '\u{265F}' => {}
+            // Evaluation glyphs, as their NAGs.
+            '\u{00B1}' => out.push_str(" $16 "),
+            '\u{2213}' => out.push_str(" $17 "),
+            '\u{2A72}' => out.push_str(" $14 "),
+            '\u{2A71}' => out.push_str(" $15 "),
+            '\u{221E}' => out.push_str(" $13 "),
+            '\u{25A1}' => out.push_str(" $7 "),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Separate move numbers from the moves written against them, as in `1.e4` or `12...Nf6`.
+fn split_move_numbers(movetext: &str) -> String {
+    let mut tokens = Vec::new();
+    for token in movetext.split_whitespace() {
+        let digits = token.len() - token.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let dots = token[digits..].len() - token[digits..].trim_start_matches('.').len();
+        if digits > 0 && dots > 0 && digits + dots < token.len() {
+            tokens.push(&token[..digits + dots]);
+            tokens.push(&token[digits + dots..]);
+        } else {
+            tokens.push(token);
+        }
+    }
+    tokens.join(" ")
+}
+
+fn is_header_line(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('[') && line.ends_with(']') && line.contains('"')
+}
+
+/// Main line moves of a game, and how many of them could be played before an illegal one.
+fn count_legal_moves(tokens: &[Token]) -> (usize, usize) {
+    let mut position = tokens
+        .iter()
+        .find_map(|token| match token {
+            Token::Header { tag, value } if tag.eq_ignore_ascii_case("FEN") => {
+                Fen::from_ascii(value.as_bytes())
+                    .ok()?
+                    .into_position::<Chess>(CastlingMode::Chess960)
+                    .ok()
+            }
+            _ => None,
+        })
+        .unwrap_or_default();
+    let (mut depth, mut legal, mut total) = (0, 0, 0);
+    let mut playing = true;
+    for token in tokens {
+        match token {
+            Token::ParenOpen => depth += 1,
+            Token::ParenClose => depth -= 1,
+            Token::San(san) if depth == 0 => {
+                total += 1;
+                if !playing {
+                    continue;
+                }
+                let played = SanPlus::from_ascii(san.as_bytes())
+                    .ok()
+                    .and_then(|san| san.san.to_move(&position).ok());
+                match played {
+                    Some(m) => {
+                        position.play_unchecked(&m);
+                        legal += 1;
+                    }
+                    None => playing = false,
+                }
+            }
+            _ => {}
+        }
+    }
+    (legal, total)
+}
+
+/// Lex a game and score it by the share of its main line that can be played.
+fn game_candidate(
+    kind: ClipboardKind,
+    pgn: String,
+    ply: Option<u32>,
+) -> Option<ClipboardCandidate> {
+    let tokens = lex(&pgn).ok()?;
+    let (legal, total) = count_legal_moves(&tokens);
+    let confidence = match kind {
+        ClipboardKind::Pgn if total == 0 => 0.5,
+        ClipboardKind::Pgn => 0.5 + 0.5 * legal as f32 / total as f32,
+        _ if legal == 0 => return None,
+        _ => 0.9 * legal as f32 / total as f32,
+    };
+    Some(ClipboardCandidate {
+        kind,
+        confidence,
+        content: ClipboardContent::Game { pgn, tokens, ply },
+    })
+}
+
+/// A bare move list as a PGN, with an unknown event and players. URLs pasted along with
+/// the moves are left out.
+fn move_list_pgn(text: &str) -> String {
+    let movetext: Vec<&str> = text
+        .split_whitespace()
+        .filter(|token| !token.contains("://") && !token.starts_with("www."))
+        .collect();
+    let movetext = split_move_numbers(&movetext.join(" "));
+    let result = ["1-0", "0-1", "1/2-1/2", "*"]
+        .into_iter()
+        .find(|result| movetext.ends_with(result))
+        .unwrap_or("*");
+    let mut pgn = String::new();
+    for (tag, value) in [
+        ("Event", "?"),
+        ("Site", "?"),
+        ("Date", "????.??.??"),
+        ("Round", "?"),
+        ("White", "?"),
+        ("Black", "?"),
+        ("Result", result),
+    ] {
+        pgn.push_str(&format!("[{} \"{}\"]\n", tag, value));
+    }
+    pgn.push('\n');
+    pgn.push_str(&movetext);
+    if !movetext.ends_with(result) {
+        pgn.push(' ');
+        pgn.push_str(result);
+    }
+    pgn.push('\n');
+    pgn
+}
+
+fn fen_candidate(text: &str) -> Option<ClipboardCandidate> {
+    let text = text.trim();
+    if text.lines().count() != 1 || !text.contains('/') {
+        return None;
+    }
+    let fen = Fen::from_ascii(text.as_bytes()).ok()?;
+    let legal = fen
+        .clone()
+        .into_position::<Chess>(CastlingMode::Chess960)
+        .is_ok();
+    Some(ClipboardCandidate {
+        kind: ClipboardKind::Fen,
+        confidence: if legal { 0.95 } else { 0.5 },
+        content: ClipboardContent::Position {
+            fen: fen.to_string(),
+        },
+    })
+}
+
+/// Move to show from a `#12` anchor or a `move=12` parameter.
+fn url_ply(url: &str) -> Option<u32> {
+    if let Some((_, anchor)) = url.split_once('#') {
+        return anchor.parse().ok();
+    }
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("move="))
+        .and_then(|ply| ply.parse().ok())
+}
+
+/// Recognize a Lichess or Chess.com game URL.
+fn parse_game_url(url: &str) -> Option<ClipboardContent> {
+    let rest = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    let (host, path) = rest.split_once('/')?;
+    let path = path.split(['#', '?']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let ply = url_ply(url);
+
+    match host {
+        "lichess.org" => {
+            let id = *segments.first()?;
+            // Player URLs add four characters to the 8 character game ID.
+            let id = match id.len() {
+                8 => id,
+                12 => &id[..8],
+                _ => return None,
+            };
+            if !id.chars().all(|c| c.is_ascii_alphanumeric())
+                || RESERVED_LICHESS_PATHS.contains(&id)
+            {
+                return None;
+            }
+            Some(ClipboardContent::Fetch {
+                platform: OnlinePlatform::Lichess,
+                game_id: id.to_string(),
+                url: format!("https://lichess.org/{}", id),
+                export_url: Some(format!(
+                    "https://lichess.org/game/export/{}?clocks=false&evals=false",
+                    id
+                )),
+                ply,
+            })
+        }
+        "chess.com" => {
+            // game/live/1, game/daily/1, live/game/1 and analysis/game/live/1
+            let kind = segments.iter().find(|s| **s == "live" || **s == "daily")?;
+            let id = *segments.last()?;
+            if !id.chars().all(|c| c.is_ascii_digit()) || !segments.contains(&"game") {
+                return None;
+            }
+            Some(ClipboardContent::Fetch {
+                platform: OnlinePlatform::Chesscom,
+                game_id: id.to_string(),
+                url: format!("https://www.chess.com/game/{}/{}", kind, id),
+                export_url: None,
+                ply,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn url_candidate(text: &str) -> Option<ClipboardCandidate> {
+    let trimmed = text.trim();
+    if !trimmed.contains(char::is_whitespace) {
+        if let Some(content) = parse_game_url(trimmed) {
+            return Some(ClipboardCandidate {
+                kind: ClipboardKind::GameUrl,
+                confidence: 1.0,
+                content,
+            });
+        }
+    }
+    // Header lines, such as a PGN's Site, don't count.
+    let content = text
+        .lines()
+        .filter(|line| !is_header_line(line))
+        .flat_map(str::split_whitespace)
+        .find_map(parse_game_url)?;
+    Some(ClipboardCandidate {
+        kind: ClipboardKind::GameUrl,
+        confidence: EMBEDDED_URL_CONFIDENCE,
+        content,
+    })
+}
+
+/// Interpretations of pasted text, most likely first.
+pub fn detect(text: &str) -> ClipboardParse {
+    let text = normalize(text);
+    let mut candidates = Vec::new();
+    candidates.extend(url_candidate(&text));
+    candidates.extend(fen_candidate(&text));
+    if text.lines().any(is_header_line) {
+        candidates.extend(game_candidate(
+            ClipboardKind::Pgn,
+            text.trim().to_string(),
+            None,
+        ));
+    } else if fen_candidate(&text).is_none() {
+        candidates.extend(game_candidate(
+            ClipboardKind::MoveList,
+            move_list_pgn(text.trim()),
+            None,
+        ));
+    }
+    candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    let ambiguous = candidates.len() > 1
+        && candidates[0].confidence - candidates[1].confidence < AMBIGUITY_MARGIN;
+    ClipboardParse {
+        candidates,
+        ambiguous,
+    }
+}
+
+async fn fetch_pgn(url: &str) -> Result<String, Error> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(15))
+        .user_agent(USER_AGENT)
+        .build()?;
+    let res = client
+        .get(url)
+        .header("Accept", "application/x-chess-pgn")
+        .send()
+        .await?;
+    Ok(res.error_for_status()?.text().await?)
+}
+
+/// Recognize pasted text, downloading the games of Lichess URLs.
+#[tauri::command]
+#[specta::specta]
+pub async fn parse_clipboard_content(text: String) -> Result<ClipboardParse, Error> {
+    let mut parsed = detect(&text);
+    for candidate in &mut parsed.candidates {
+        let ClipboardContent::Fetch {
+            export_url: Some(url),
+            ply,
+            ..
+        } = &candidate.content
+        else {
+            continue;
+        };
+        let (url, ply) = (url.clone(), *ply);
+        match fetch_pgn(&url).await {
+            Ok(pgn) => {
+                if let Some(game) = game_candidate(ClipboardKind::GameUrl, pgn, ply) {
+                    candidate.content = game.content;
+                }
+            }
+            // The frontend can still open the game.
+            Err(e) => warn!("Could not download {}: {}", url, e),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sans(content: &ClipboardContent) -> Vec<String> {
+        let ClipboardContent::Game { tokens, .. } = content else {
+            panic!("not a game: {:?}", content);
+        };
+        tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::San(san) => Some(san.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pgn_with_smart_quotes() {
+        let parsed = detect(
+            "[Event \u{201C}Casual game\u{201D}]\r\n[White \u{201C}O\u{2019}Kelly\u{201D}]\r\n\
+             [Black \u{201C}Tal\u{201D}]\r\n[Site \u{201C}https://lichess.org/abcdEFGH\u{201D}]\r\n\r\n\
+             1. e4 e5 2. Nf3 Nc6 \u{00BD}\u{2013}\u{00BD}",
+        );
+        assert!(!parsed.ambiguous);
+        assert_eq!(parsed.candidates.len(), 1);
+        let best = &parsed.candidates[0];
+        assert_eq!(best.kind, ClipboardKind::Pgn);
+        assert_eq!(best.confidence, 1.0);
+        let ClipboardContent::Game { pgn, tokens, .. } = &best.content else {
+            panic!("not a game");
+        };
+        assert!(pgn.contains("[White \"O'Kelly\"]"));
+        assert!(tokens.contains(&Token::Outcome("1/2-1/2".to_string())));
+        assert_eq!(sans(&best.content), ["e4", "e5", "Nf3", "Nc6"]);
+    }
+
+    #[test]
+    fn move_list_with_glyphs() {
+        let parsed = detect("1.e4 e5 2.Nf3!? \u{265E}c6 3.\u{2657}b5 a6 \u{00B1} 4.Ba4 Nf6");
+        let best = &parsed.candidates[0];
+        assert_eq!(best.kind, ClipboardKind::MoveList);
+        assert_eq!(best.confidence, 0.9);
+        assert_eq!(
+            sans(&best.content),
+            ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Ba4", "Nf6"]
+        );
+        let ClipboardContent::Game { pgn, tokens, .. } = &best.content else {
+            panic!("not a game");
+        };
+        assert!(pgn.starts_with("[Event \"?\"]\n"));
+        assert!(pgn.contains("[Result \"*\"]"));
+        assert!(tokens.contains(&Token::Nag("$16".to_string())));
+        assert!(tokens.contains(&Token::Nag("$5".to_string())));
+    }
+
+    #[test]
+    fn move_lists_keep_their_result() {
+        let pgn = move_list_pgn("1. d4 d5 2. c4 1-0");
+        assert!(pgn.contains("[Result \"1-0\"]"));
+        assert!(pgn.ends_with("2. c4 1-0\n"));
+        assert_eq!(split_move_numbers("12...Nf6 13.O-O"), "12... Nf6 13. O-O");
+    }
+
+    #[test]
+    fn illegal_moves_lower_confidence() {
+        let parsed = detect("1. e4 e5 2. Nf3 Ke7 3. Bc4 Qxh5");
+        let best = &parsed.candidates[0];
+        assert_eq!(best.kind, ClipboardKind::MoveList);
+        // Qxh5 can't be played.
+        assert!((best.confidence - 0.9 * 5.0 / 6.0).abs() < 1e-6);
+        // Prose has no moves to play.
+        assert!(detect("Thanks for the game, see you tomorrow!")
+            .candidates
+            .is_empty());
+    }
+
+    #[test]
+    fn fen_positions() {
+        let parsed =
+            detect("\u{00A0}rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1 \n");
+        assert_eq!(parsed.candidates.len(), 1);
+        assert_eq!(
+            parsed.candidates[0].content,
+            ClipboardContent::Position {
+                fen: "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1".to_string()
+            }
+        );
+        assert_eq!(parsed.candidates[0].confidence, 0.95);
+
+        // Parses, but no position can be set up without kings.
+        let parsed = detect("8/8/8/8/8/8/8/8 w - - 0 1");
+        assert_eq!(parsed.candidates[0].kind, ClipboardKind::Fen);
+        assert_eq!(parsed.candidates[0].confidence, 0.5);
+    }
+
+    #[test]
+    fn game_urls_with_move_anchors() {
+        let parsed = detect("https://lichess.org/abcdEFGH1234/black#12");
+        assert_eq!(
+            parsed.candidates[0].content,
+            ClipboardContent::Fetch {
+                platform: OnlinePlatform::Lichess,
+                game_id: "abcdEFGH".to_string(),
+                url: "https://lichess.org/abcdEFGH".to_string(),
+                export_url: Some(
+                    "https://lichess.org/game/export/abcdEFGH?clocks=false&evals=false".to_string()
+                ),
+                ply: Some(12),
+            }
+        );
+        assert_eq!(parsed.candidates[0].confidence, 1.0);
+
+        assert_eq!(
+            parse_game_url("https://www.chess.com/analysis/game/live/123456789?tab=review&move=5"),
+            Some(ClipboardContent::Fetch {
+                platform: OnlinePlatform::Chesscom,
+                game_id: "123456789".to_string(),
+                url: "https://www.chess.com/game/live/123456789".to_string(),
+                export_url: None,
+                ply: Some(5),
+            })
+        );
+        assert_eq!(parse_game_url("https://lichess.org/training"), None);
+        assert_eq!(parse_game_url("https://lichess.org/@/thibault"), None);
+        assert_eq!(parse_game_url("https://www.chess.com/member/hikaru"), None);
+    }
+
+    #[test]
+    fn urls_next_to_moves_are_ambiguous() {
+        let parsed = detect("https://lichess.org/abcdEFGH#3\n1. e4 e5 2. Nf3");
+        assert!(parsed.ambiguous);
+        let kinds: Vec<_> = parsed.candidates.iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, [ClipboardKind::MoveList, ClipboardKind::GameUrl]);
+    }
+}
@@ -0,0 +1,204 @@
+//! Evaluations from the Lichess cloud.
+//!
+//! Lichess keeps deep evaluations of the positions its users analysed, which stand in for
+//! an engine when none is configured. Positions the cloud doesn't have, and answers held back
+//! by rate limiting, come back as no lines rather than an error. Nothing is asked when
+//! telemetry is turned off, as that setting also decides whether the app goes online on its
+//! own.
+//!
+//! Answers are kept in an LRU cache by FEN and number of lines.
+
+use std::sync::Mutex;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use shakmaty::{fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Color, PositionError};
+use vampirc_uci::uci::{Score, ScoreValue};
+
+use crate::chess::{format_score, BestMoves, ScoreStyle};
+use crate::error::Error;
+use crate::online_stats::fetch_json;
+use crate::telemetry::TelemetryConfig;
+use crate::AppState;
+
+const CLOUD_EVAL_URL: &str = "https://lichess.org/api/cloud-eval";
+
+/// Most lines the cloud returns.
+pub const MAX_CLOUD_EVAL_LINES: u8 = 5;
+
+/// Cached cloud evaluations, by FEN and number of lines.
+pub type CloudEvalCache = Mutex<lru::LruCache<(String, u8), Vec<BestMoves>>>;
+
+#[derive(Deserialize)]
+struct CloudEval {
+    knodes: u64,
+    depth: u32,
+    pvs: Vec<CloudPv>,
+}
+
+#[derive(Deserialize)]
+struct CloudPv {
+    moves: String,
+    cp: Option<i32>,
+    mate: Option<i32>,
+}
+
+/// The lines of a cloud evaluation of `position`, scored from White's point of view like
+/// those of engines. Lines with illegal moves end before them.
+fn cloud_lines(eval: CloudEval, position: &Chess) -> Vec<BestMoves> {
+    let nodes = eval.knodes.saturating_mul(1000).min(u32::MAX.into()) as u32;
+    eval.pvs
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, pv)| {
+            let value = match (pv.mate, pv.cp) {
+                (Some(mate), _) => ScoreValue::Mate(mate),
+                (None, Some(cp)) => ScoreValue::Cp(cp),
+                (None, None) => return None,
+            };
+            let score = Score {
+                value,
+                ..Default::default()
+            };
+            let mut pos = position.clone();
+            let mut uci_moves = Vec::new();
+            let mut san_moves = Vec::new();
+            for uci in pv.moves.split_whitespace() {
+                let Some(m) = UciMove::from_ascii(uci.as_bytes())
+                    .ok()
+                    .and_then(|uci| uci.to_move(&pos).ok())
+                else {
+                    break;
+                };
+                san_moves.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m).to_string());
+                uci_moves.push(uci.to_string());
+            }
+            (!uci_moves.is_empty()).then(|| BestMoves {
+                nodes,
+                depth: eval.depth,
+                display: format_score(&score, Color::White, ScoreStyle::Pawns),
+                score,
+                uci_moves,
+                san_moves,
+                multipv: i as u16 + 1,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Ask the cloud for `multipv` lines of `fen`, with none when it doesn't have the position
+/// or won't answer for now.
+async fn fetch_cloud_eval(
+    fen: &Fen,
+    multipv: u8,
+    position: &Chess,
+) -> Result<Vec<BestMoves>, Error> {
+    // Spaces are the only characters of a valid FEN needing escaping in a query.
+    let url = format!(
+        "{}?fen={}&multiPv={}",
+        CLOUD_EVAL_URL,
+        fen.to_string().replace(' ', "%20"),
+        multipv
+    );
+    match fetch_json::<CloudEval>(&url, None).await {
+        Ok(eval) => Ok(cloud_lines(eval, position)),
+        Err(Error::RateLimited(_)) => Ok(Vec::new()),
+        Err(Error::Reqwest(e)) if e.status() == Some(StatusCode::NOT_FOUND) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get up to `multipv` lines of the Lichess cloud evaluation of `fen`, at most 5. Positions
+/// the cloud doesn't have, rate limiting and turned off telemetry all give no lines.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_cloud_eval(
+    fen: String,
+    multipv: u8,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Vec<BestMoves>, Error> {
+    let parsed = Fen::from_ascii(fen.as_bytes())?;
+    let position: Chess = parsed
+        .clone()
+        .into_position(CastlingMode::Chess960)
+        .or_else(PositionError::ignore_too_much_material)?;
+    let multipv = multipv.clamp(1, MAX_CLOUD_EVAL_LINES);
+    let key = (fen.clone(), multipv);
+    if let Some(lines) = state.cloud_evals.lock().unwrap().get(&key) {
+        return Ok(lines.clone());
+    }
+    if !TelemetryConfig::load(&app).is_ok_and(|config| config.enabled) {
+        return Ok(Vec::new());
+    }
+
+    let lines = fetch_cloud_eval(&parsed, multipv, &position).await?;
+    // Positions may reach the cloud later, and rate limiting ends.
+    if !lines.is_empty() {
+        state.cloud_evals.lock().unwrap().put(key, lines.clone());
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITALIAN: &str = "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3";
+
+    fn position(fen: &str) -> Chess {
+        Fen::from_ascii(fen.as_bytes())
+            .unwrap()
+            .into_position(CastlingMode::Chess960)
+            .unwrap()
+    }
+
+    #[test]
+    fn cloud_answers_become_engine_lines() {
+        let eval: CloudEval = serde_json::from_str(
+            r#"{
+                "fen": "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3",
+                "knodes": 84937,
+                "depth": 38,
+                "pvs": [
+                    { "moves": "g8f6 d2d3 f8c5", "cp": 26 },
+                    { "moves": "f8c5 c2c3 g8f6", "cp": 31 },
+                    { "moves": "f7f5 e4f5", "mate": -12 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let lines = cloud_lines(eval, &position(ITALIAN));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].san_moves, ["Nf6", "d3", "Bc5"]);
+        assert_eq!(lines[0].uci_moves, ["g8f6", "d2d3", "f8c5"]);
+        assert_eq!((lines[0].depth, lines[0].nodes), (38, 84_937_000));
+        assert!(matches!(lines[0].score.value, ScoreValue::Cp(26)));
+        assert_eq!(lines[1].multipv, 2);
+        assert!(matches!(lines[2].score.value, ScoreValue::Mate(-12)));
+    }
+
+    #[test]
+    fn illegal_moves_cut_lines_short() {
+        let eval = CloudEval {
+            knodes: 1,
+            depth: 20,
+            pvs: vec![
+                CloudPv {
+                    moves: "g8f6 e1e8".to_string(),
+                    cp: Some(0),
+                    mate: None,
+                },
+                CloudPv {
+                    moves: "a1a8".to_string(),
+                    cp: Some(0),
+                    mate: None,
+                },
+            ],
+        };
+        let lines = cloud_lines(eval, &position(ITALIAN));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].uci_moves, ["g8f6"]);
+    }
+}
@@ -0,0 +1,380 @@
+//! Anonymizing games exported for sharing.
+//!
+//! Player names become pseudonyms that hold for the whole export, so that a player keeps
+//! one pseudonym in every game. Ratings are left out, and comments lose the names of the
+//! players of the database along with e-mail addresses, FIDE IDs and ratings. Names are
+//! also recognized by their parts, so that "Carlsen, Magnus" is found as "Magnus Carlsen"
+//! or "Carlsen's", but never inside a longer word. The pseudonyms can be written to a key
+//! file for whoever exported the games to tell who is who.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use specta::Type;
+
+use crate::error::Result;
+
+/// Written for names that belong to more than one player.
+const AMBIGUOUS_NAME: &str = "[name]";
+
+/// Capitalized words of annotations that are never taken for part of a name.
+const COMMON_WORDS: [&str; 14] = [
+    "White", "Black", "King", "Queen", "Rook", "Bishop", "Knight", "Pawn", "Player", "The", "Van",
+    "Von", "Der", "Del",
+];
+
+lazy_static! {
+    static ref EMAIL: Regex = Regex::new(r"[\w.%+-]+@[\w-]+(?:\.[\w-]+)+").unwrap();
+    static ref FIDE_ID: Regex =
+        Regex::new(r"(?i)\bFIDE(?:[\s-]*ID)?\s*(?:no\.?|[:#])?\s*\d{5,10}\b").unwrap();
+    static ref RATING: Regex = Regex::new(r"(?i)\b(?:elo|rated|rating)\s*:?\s*\d{3,4}\b").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum PseudonymStyle {
+    /// "Player A", "Player B" and so on, in order of appearance.
+    #[default]
+    Letters,
+    /// "Player " and a hash of the name, salted for each export.
+    Hashed,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct AnonymizeOptions {
+    #[serde(default)]
+    pub pseudonyms: PseudonymStyle,
+    /// Keep only the year of dates.
+    #[serde(default)]
+    pub year_only: bool,
+    /// JSON file to write the pseudonyms to, with the names they stand for.
+    pub key_file: Option<PathBuf>,
+}
+
+/// Who a name found in a comment stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mention {
+    Player(usize),
+    Ambiguous,
+}
+
+#[derive(Serialize)]
+struct KeyEntry<'a> {
+    pseudonym: &'a str,
+    name: &'a str,
+}
+
+pub struct Anonymizer {
+    style: PseudonymStyle,
+    year_only: bool,
+    salt: [u8; 16],
+    names: Vec<String>,
+    players: HashMap<String, usize>,
+    pseudonyms: Vec<Option<String>>,
+    /// Players in the order they got their pseudonym.
+    assigned: Vec<usize>,
+    mentions: HashMap<String, Mention>,
+    /// Matches every name of `mentions`, longest first.
+    pattern: Option<Regex>,
+}
+
+/// Spellings of `name` to look for in comments, the name itself first.
+fn name_variants(name: &str) -> Vec<String> {
+    let mut variants = vec![name.to_string()];
+    if let Some((surname, given)) = name.split_once(',') {
+        variants.push(format!("{} {}", given.trim(), surname.trim()));
+    }
+    variants.extend(
+        name.split([',', ' '])
+            .map(|part| part.trim_matches(|c: char| !c.is_alphanumeric()))
+            .filter(|part| {
+                part.chars().count() >= 3
+                    && part.starts_with(char::is_uppercase)
+                    && !COMMON_WORDS.contains(part)
+            })
+            .map(str::to_string),
+    );
+    variants.dedup();
+    variants
+}
+
+/// Whether `name` names anyone, unlike an empty or unknown (`?`) name.
+fn is_named(name: &str) -> bool {
+    name.chars().any(char::is_alphanumeric)
+}
+
+/// "A" to "Z", then "AA" and so on.
+fn letters(mut n: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'A' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    letters.reverse();
+    String::from_utf8(letters).unwrap()
+}
+
+/// A PGN date with only its year, or unknown.
+pub fn year_only(date: &str) -> String {
+    match date.trim().get(..4) {
+        Some(year) if year.chars().all(|c| c.is_ascii_digit()) => format!("{}.??.??", year),
+        _ => "????.??.??".to_string(),
+    }
+}
+
+impl Anonymizer {
+    /// Anonymize games whose comments may mention any of `names`, hashing pseudonyms with
+    /// `salt`.
+    pub fn new(options: &AnonymizeOptions, names: Vec<String>, salt: [u8; 16]) -> Self {
+        let mut anonymizer = Self {
+            style: options.pseudonyms,
+            year_only: options.year_only,
+            salt,
+            names: Vec::new(),
+            players: HashMap::new(),
+            pseudonyms: Vec::new(),
+            assigned: Vec::new(),
+            mentions: HashMap::new(),
+            pattern: None,
+        };
+        for name in names.iter().filter(|name| is_named(name)) {
+            anonymizer.player(name);
+        }
+
+        // Whole names win over parts of other names, and parts shared by several players
+        // can't be told apart.
+        let mut parts = HashMap::new();
+        for (player, name) in anonymizer.names.iter().enumerate() {
+            let whole = name.trim_matches(|c: char| !c.is_alphanumeric());
+            anonymizer
+                .mentions
+                .insert(whole.to_string(), Mention::Player(player));
+            for variant in name_variants(name).into_iter().skip(1) {
+                parts
+                    .entry(variant)
+                    .and_modify(|mention| {
+                        if *mention != Mention::Player(player) {
+                            *mention = Mention::Ambiguous;
+                        }
+                    })
+                    .or_insert(Mention::Player(player));
+            }
+        }
+        for (part, mention) in parts {
+            anonymizer.mentions.entry(part).or_insert(mention);
+        }
+
+        let mut spellings: Vec<&String> = anonymizer.mentions.keys().collect();
+        spellings.sort_by_key(|spelling| std::cmp::Reverse(spelling.len()));
+        if !spellings.is_empty() {
+            let alternatives: Vec<String> = spellings.iter().map(|s| regex::escape(s)).collect();
+            anonymizer.pattern =
+                Some(Regex::new(&format!(r"\b(?:{})\b", alternatives.join("|"))).unwrap());
+        }
+        anonymizer
+    }
+
+    fn player(&mut self, name: &str) -> usize {
+        let name = name.trim();
+        if let Some(player) = self.players.get(name) {
+            return *player;
+        }
+        self.names.push(name.to_string());
+        self.pseudonyms.push(None);
+        self.players.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    fn pseudonym(&mut self, player: usize) -> String {
+        if let Some(pseudonym) = &self.pseudonyms[player] {
+            return pseudonym.clone();
+        }
+        let pseudonym = match self.style {
+            PseudonymStyle::Letters => format!("Player {}", letters(self.assigned.len())),
+            PseudonymStyle::Hashed => {
+                let digest = Sha256::new()
+                    .chain_update(self.salt)
+                    .chain_update(self.names[player].as_bytes())
+                    .finalize();
+                format!("Player {}", &format!("{:x}", digest)[..8])
+            }
+        };
+        self.pseudonyms[player] = Some(pseudonym.clone());
+        self.assigned.push(player);
+        pseudonym
+    }
+
+    /// The pseudonym of a player, keeping unknown players as they are.
+    pub fn name(&mut self, name: &str) -> String {
+        if !is_named(name) {
+            return name.to_string();
+        }
+        let player = self.player(name);
+        self.pseudonym(player)
+    }
+
+    pub fn date(&self, date: &str) -> String {
+        if self.year_only {
+            year_only(date)
+        } else {
+            date.to_string()
+        }
+    }
+
+    fn redact(&mut self, text: &str) -> String {
+        let text = EMAIL.replace_all(text, "[email]");
+        let text = FIDE_ID.replace_all(&text, "[FIDE ID]");
+        let text = RATING.replace_all(&text, "[rating]");
+        let Some(pattern) = self.pattern.clone() else {
+            return text.into_owned();
+        };
+        pattern
+            .replace_all(&text, |caps: &Captures| match self.mentions[&caps[0]] {
+                Mention::Player(player) => self.pseudonym(player),
+                Mention::Ambiguous => AMBIGUOUS_NAME.to_string(),
+            })
+            .into_owned()
+    }
+
+    /// Movetext with personal data taken out of its comments.
+    pub fn movetext(&mut self, movetext: &str) -> String {
+        let mut out = String::with_capacity(movetext.len());
+        let mut rest = movetext;
+        while let Some(start) = rest.find(['{', ';']) {
+            let (before, comment) = rest.split_at(start + 1);
+            out.push_str(before);
+            let end = if before.ends_with('{') {
+                comment.find('}')
+            } else {
+                comment.find('\n')
+            }
+            .unwrap_or(comment.len());
+            out.push_str(&self.redact(&comment[..end]));
+            rest = &comment[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Write the pseudonyms given so far and the names they stand for, as JSON.
+    pub fn write_key(&self, writer: impl Write) -> Result<()> {
+        let entries: Vec<KeyEntry> = self
+            .assigned
+            .iter()
+            .map(|&player| KeyEntry {
+                pseudonym: self.pseudonyms[player].as_deref().unwrap_or_default(),
+                name: &self.names[player],
+            })
+            .collect();
+        serde_json::to_writer_pretty(writer, &entries)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anonymizer(style: PseudonymStyle, names: &[&str]) -> Anonymizer {
+        let options = AnonymizeOptions {
+            pseudonyms: style,
+            ..Default::default()
+        };
+        Anonymizer::new(
+            &options,
+            names.iter().map(|name| name.to_string()).collect(),
+            [7; 16],
+        )
+    }
+
+    #[test]
+    fn players_keep_one_pseudonym() {
+        let mut letters = anonymizer(PseudonymStyle::Letters, &["Carlsen, Magnus", "Nakamura"]);
+        assert_eq!(letters.name("Nakamura"), "Player A");
+        assert_eq!(letters.name(" Carlsen, Magnus "), "Player B");
+        assert_eq!(letters.name("Nakamura"), "Player A");
+        assert_eq!(letters.name("Firouzja"), "Player C");
+        assert_eq!(letters.name("?"), "?");
+
+        let mut hashed = anonymizer(PseudonymStyle::Hashed, &["Carlsen, Magnus"]);
+        let pseudonym = hashed.name("Carlsen, Magnus");
+        assert!(pseudonym.starts_with("Player ") && pseudonym.len() == 15);
+        assert_eq!(hashed.name("Carlsen, Magnus"), pseudonym);
+        assert_ne!(hashed.name("Nakamura"), pseudonym);
+        assert_eq!(letters(27), "AB");
+
+        let mut key = Vec::new();
+        letters.write_key(&mut key).unwrap();
+        let key: serde_json::Value = serde_json::from_slice(&key).unwrap();
+        assert_eq!(key[0]["pseudonym"], "Player A");
+        assert_eq!(key[0]["name"], "Nakamura");
+        assert_eq!(key[1]["name"], "Carlsen, Magnus");
+    }
+
+    #[test]
+    fn names_in_comments() {
+        let mut anonymizer = anonymizer(
+            PseudonymStyle::Letters,
+            &[
+                "Carlsen, Magnus",
+                "Li",
+                "Black, John",
+                "Vachier-Lagrave, Maxime",
+            ],
+        );
+        assert_eq!(anonymizer.name("Vachier-Lagrave, Maxime"), "Player A");
+        assert_eq!(
+            anonymizer.movetext(
+                "1. e4 {Magnus Carlsen's favourite, as Carlsen, Magnus said} e5 \
+                 2. Nf3 {Vachier-Lagrave's Najdorf? Maxime prefers it} *"
+            ),
+            "1. e4 {Player B's favourite, as Player B said} e5 \
+             2. Nf3 {Player A's Najdorf? Player A prefers it} *"
+        );
+        // Names never match inside longer words, and chess words stay.
+        assert_eq!(
+            anonymizer.movetext("1. d4 {Line of Li: Black to move, Carlsenesque} d5 *"),
+            "1. d4 {Line of Player C: Black to move, Carlsenesque} d5 *"
+        );
+        assert_eq!(
+            anonymizer.movetext("1. c4 ; John plays the English\n1... e5 {John} *"),
+            "1. c4 ; Player D plays the English\n1... e5 {Player D} *"
+        );
+    }
+
+    #[test]
+    fn shared_name_parts_are_ambiguous() {
+        let mut anonymizer = anonymizer(
+            PseudonymStyle::Letters,
+            &["Carlsen, Magnus", "Carlsen, Henrik", "Henrik"],
+        );
+        assert_eq!(
+            anonymizer.movetext("{Carlsen and Magnus, coached by Henrik}"),
+            "{[name] and Player A, coached by Player B}"
+        );
+        assert_eq!(anonymizer.name("Henrik"), "Player B");
+    }
+
+    #[test]
+    fn personal_data_in_comments() {
+        let mut anonymizer = anonymizer(PseudonymStyle::Letters, &[]);
+        assert_eq!(
+            anonymizer.movetext(
+                "1. e4 {Mail coach.name+pgn@club.example.org, FIDE ID: 1503014, rated 2310} \
+                 e5 {[%clk 0:05:00]} *"
+            ),
+            "1. e4 {Mail [email], [FIDE ID], [rating]} e5 {[%clk 0:05:00]} *"
+        );
+        assert_eq!(year_only("2024.04.05"), "2024.??.??");
+        assert_eq!(year_only("????.??.??"), "????.??.??");
+    }
+}
@@ -0,0 +1,257 @@
+//! Attribution of engine analysis
+//!
+//! Evaluations written into a game, such as `+0.45/20 Stockfish 17`, are only as good as
+//! the engine and settings behind them. Every analysis stored in a game is recorded in
+//! `AnalysisAttributions` with the engine, its search mode and the date, and exports write
+//! the latest one as the `Annotator` header, so shared games say where their evaluations
+//! come from. Analysing again with other settings adds to the game's history, of which the
+//! last few entries are kept; the same settings again only refresh the date.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    chess::GoMode,
+    db::{get_db_or_create, schema::analysis_attributions, ConnectionOptions},
+    error::Result,
+    AppState,
+};
+
+const ANALYSIS_ATTRIBUTIONS_SQL: &str =
+    include_str!("../../../database/schema/analysis_attributions.sql");
+
+/// Analyses kept per game.
+pub const MAX_ATTRIBUTIONS: usize = 5;
+
+const APP_NAME: &str = "Pawn Appétit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisAttribution {
+    /// Engine as it names itself, version included.
+    pub engine: String,
+    pub go_mode: GoMode,
+    /// Unix timestamp in seconds of the analysis.
+    pub analyzed_at: i64,
+}
+
+impl AnalysisAttribution {
+    /// Analysis made now by `engine`.
+    pub fn now(engine: &str, go_mode: GoMode) -> Self {
+        Self {
+            engine: engine.to_string(),
+            go_mode,
+            analyzed_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// Value of the `Annotator` header, e.g. `Stockfish 17 dev, depth 24, Pawn Appétit`.
+    pub fn annotator(&self) -> String {
+        let search = match &self.go_mode {
+            GoMode::Depth(depth) => format!("depth {}", depth),
+            GoMode::Time(ms) => format!("{} ms per move", ms),
+            GoMode::Nodes(nodes) => format!("{} nodes per move", nodes),
+            GoMode::Mate(moves) => format!("mate in {}", moves),
+            GoMode::PlayersTime(_) => "game clock".to_string(),
+            GoMode::Infinite => "infinite search".to_string(),
+        };
+        format!("{}, {}, {}", self.engine, search, APP_NAME)
+    }
+}
+
+#[derive(Queryable)]
+struct AttributionRow {
+    id: i32,
+    game_id: i32,
+    engine: String,
+    go_mode: String,
+    analyzed_at: i64,
+}
+
+impl AttributionRow {
+    fn attribution(self) -> Result<AnalysisAttribution> {
+        Ok(AnalysisAttribution {
+            engine: self.engine,
+            go_mode: serde_json::from_str(&self.go_mode)?,
+            analyzed_at: self.analyzed_at,
+        })
+    }
+}
+
+/// Databases created before analyses were attributed don't have the table yet.
+pub(crate) fn ensure_attributions_table(db: &mut SqliteConnection) -> Result<()> {
+    db.batch_execute(ANALYSIS_ATTRIBUTIONS_SQL)?;
+    Ok(())
+}
+
+/// Record an analysis written into a game, dropping the oldest ones beyond
+/// `MAX_ATTRIBUTIONS`.
+pub fn record_attribution(
+    db: &mut SqliteConnection,
+    game_id: i32,
+    attribution: &AnalysisAttribution,
+) -> Result<()> {
+    ensure_attributions_table(db)?;
+    let go_mode = serde_json::to_string(&attribution.go_mode)?;
+    db.transaction(|db| {
+        let latest: Option<AttributionRow> = analysis_attributions::table
+            .filter(analysis_attributions::game_id.eq(game_id))
+            .order(analysis_attributions::id.desc())
+            .first(db)
+            .optional()?;
+        match latest {
+            Some(latest) if latest.engine == attribution.engine && latest.go_mode == go_mode => {
+                diesel::update(analysis_attributions::table.find(latest.id))
+                    .set(analysis_attributions::analyzed_at.eq(attribution.analyzed_at))
+                    .execute(db)?;
+            }
+            _ => {
+                diesel::insert_into(analysis_attributions::table)
+                    .values((
+                        analysis_attributions::game_id.eq(game_id),
+                        analysis_attributions::engine.eq(&attribution.engine),
+                        analysis_attributions::go_mode.eq(&go_mode),
+                        analysis_attributions::analyzed_at.eq(attribution.analyzed_at),
+                    ))
+                    .execute(db)?;
+                let kept: Vec<i32> = analysis_attributions::table
+                    .filter(analysis_attributions::game_id.eq(game_id))
+                    .order(analysis_attributions::id.desc())
+                    .limit(MAX_ATTRIBUTIONS as i64)
+                    .select(analysis_attributions::id)
+                    .load(db)?;
+                diesel::delete(
+                    analysis_attributions::table
+                        .filter(analysis_attributions::game_id.eq(game_id))
+                        .filter(analysis_attributions::id.ne_all(kept)),
+                )
+                .execute(db)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Analyses of a game, the latest first.
+pub fn game_attributions(
+    db: &mut SqliteConnection,
+    game_id: i32,
+) -> Result<Vec<AnalysisAttribution>> {
+    ensure_attributions_table(db)?;
+    analysis_attributions::table
+        .filter(analysis_attributions::game_id.eq(game_id))
+        .order(analysis_attributions::id.desc())
+        .load::<AttributionRow>(db)?
+        .into_iter()
+        .map(AttributionRow::attribution)
+        .collect()
+}
+
+/// `Annotator` header of every analysed game, by game.
+pub(crate) fn all_annotators(db: &mut SqliteConnection) -> Result<HashMap<i32, String>> {
+    ensure_attributions_table(db)?;
+    let rows: Vec<AttributionRow> = analysis_attributions::table
+        .order(analysis_attributions::id.asc())
+        .load(db)?;
+    let mut annotators = HashMap::new();
+    for row in rows {
+        // Later analyses replace earlier ones.
+        annotators.insert(row.game_id, row.attribution()?.annotator());
+    }
+    Ok(annotators)
+}
+
+/// Drop the analyses of a game about to be deleted.
+///
+/// Foreign keys do the same, but aren't enabled on every connection.
+pub(crate) fn forget_game(db: &mut SqliteConnection, game_id: i32) -> Result<()> {
+    ensure_attributions_table(db)?;
+    diesel::delete(analysis_attributions::table.filter(analysis_attributions::game_id.eq(game_id)))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Engines and settings that produced the evaluations of a game, the latest first.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_analysis_attribution(
+    file: PathBuf,
+    game_id: i32,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<AnalysisAttribution>> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    game_attributions(db, game_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    fn analysis(engine: &str, depth: u32, analyzed_at: i64) -> AnalysisAttribution {
+        AnalysisAttribution {
+            engine: engine.to_string(),
+            go_mode: GoMode::Depth(depth),
+            analyzed_at,
+        }
+    }
+
+    #[test]
+    fn annotators_name_the_engine_and_search() {
+        assert_eq!(
+            analysis("Stockfish 17 dev", 24, 0).annotator(),
+            "Stockfish 17 dev, depth 24, Pawn Appétit"
+        );
+        let timed = AnalysisAttribution {
+            go_mode: GoMode::Time(1500),
+            ..analysis("Komodo Dragon 3.3", 0, 0)
+        };
+        assert_eq!(
+            timed.annotator(),
+            "Komodo Dragon 3.3, 1500 ms per move, Pawn Appétit"
+        );
+    }
+
+    #[test]
+    fn previous_analyses_are_kept_up_to_a_limit() {
+        let db = &mut test_db("");
+
+        record_attribution(db, 1, &analysis("Stockfish 16", 20, 100)).unwrap();
+        record_attribution(db, 1, &analysis("Stockfish 17", 24, 200)).unwrap();
+        // The same settings again only update the date.
+        record_attribution(db, 1, &analysis("Stockfish 17", 24, 300)).unwrap();
+        record_attribution(db, 2, &analysis("Komodo", 18, 150)).unwrap();
+        assert_eq!(
+            game_attributions(db, 1).unwrap(),
+            vec![
+                analysis("Stockfish 17", 24, 300),
+                analysis("Stockfish 16", 20, 100)
+            ]
+        );
+
+        for depth in 0..MAX_ATTRIBUTIONS as u32 {
+            record_attribution(db, 1, &analysis("Leela", depth, 400)).unwrap();
+        }
+        let history = game_attributions(db, 1).unwrap();
+        assert_eq!(history.len(), MAX_ATTRIBUTIONS);
+        assert_eq!(
+            history[0].go_mode,
+            GoMode::Depth(MAX_ATTRIBUTIONS as u32 - 1)
+        );
+        assert!(history.iter().all(|a| a.engine == "Leela"));
+
+        let annotators = all_annotators(db).unwrap();
+        assert_eq!(annotators[&2], "Komodo, depth 18, Pawn Appétit");
+        assert_eq!(
+            annotators[&1],
+            format!("Leela, depth {}, Pawn Appétit", MAX_ATTRIBUTIONS - 1)
+        );
+
+        forget_game(db, 2).unwrap();
+        assert!(game_attributions(db, 2).unwrap().is_empty());
+    }
+}
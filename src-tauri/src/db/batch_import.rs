@@ -0,0 +1,334 @@
+//! Importing many PGN files into one database.
+//!
+//! Files are imported one after the other, each in a transaction of its own, so a file that
+//! can't be read leaves none of its games behind and is reported instead of stopping the
+//! others. With deduplication, games the database already holds, from before, from an earlier
+//! file or from earlier in the same file, are left out before being inserted. They are matched
+//! the way `delete_duplicated_games` matches exact duplicates.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use log::{info, warn};
+use pgn_reader::BufferedReader;
+use serde::Serialize;
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+use super::duplicates::exact_duplicate_of;
+use super::pgn::Importer;
+use super::provenance::{self, GameProvenance};
+use super::{
+    common_start_fen, core, decompress, get_db_or_create, header_rules, insert_to_db, links,
+    rarity, set_start_fen, structure, update_info_counts, write_lock, ConnectionOptions,
+    DatabaseProgress, JournalMode, INDEXES_SQL,
+};
+
+/// Games imported between two progress reports.
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// A file of a batch that couldn't be imported.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct BatchImportFailure {
+    pub file: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq, Type)]
+pub struct BatchImportReport {
+    pub imported: u32,
+    /// Games left out as exact duplicates of games already in the database.
+    pub duplicates: u32,
+    /// Files that couldn't be imported, none of their games being kept.
+    pub failures: Vec<BatchImportFailure>,
+}
+
+/// Games of one file imported and left out.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct FileImport {
+    pub(super) imported: u32,
+    pub(super) duplicates: u32,
+}
+
+/// Reader counting the bytes read through it, to tell how far into a file an import is.
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Import the games of `pgn` into `db` in one transaction, recording them as coming from
+/// `source`, and leaving out exact duplicates if `dedupe` is set.
+///
+/// `progress` is called every `PROGRESS_INTERVAL` games.
+pub(super) fn import_games(
+    db: &mut SqliteConnection,
+    pgn: impl Read,
+    importer: &mut Importer,
+    source: &GameProvenance,
+    dedupe: bool,
+    progress: impl Fn(),
+) -> Result<FileImport> {
+    db.transaction::<_, Error, _>(|db| {
+        let mut import = FileImport::default();
+        let mut links = links::LinkImport::default();
+        for (i, game) in BufferedReader::new(pgn).into_iter(importer).enumerate() {
+            // Games with illegal moves are skipped, but a file that can't be read fails.
+            let Some(game) = game? else {
+                continue;
+            };
+            if i % PROGRESS_INTERVAL == 0 {
+                progress();
+            }
+            let original = if dedupe {
+                exact_duplicate_of(db, &game)?
+            } else {
+                None
+            };
+            // Links from a game left out start from the game it duplicates.
+            let id = match original {
+                Some(original) => {
+                    import.duplicates += 1;
+                    original
+                }
+                None => {
+                    let id = insert_to_db(db, &game)?;
+                    provenance::record_source(db, id, source)?;
+                    import.imported += 1;
+                    id
+                }
+            };
+            links.record(id, &game);
+        }
+        links.finish(db)?;
+        Ok(import)
+    })
+}
+
+/// Import the PGN file `file`, compressed or not, calling `progress` with the part of it
+/// read so far.
+fn import_file(
+    db: &mut SqliteConnection,
+    file: &Path,
+    dedupe: bool,
+    app: &tauri::AppHandle,
+    progress: impl Fn(f64),
+) -> Result<FileImport> {
+    let opened = File::open(file)?;
+    let size = opened.metadata()?.len().max(1);
+    let read = Arc::new(AtomicU64::new(0));
+    let pgn = decompress(
+        CountingReader {
+            inner: opened,
+            read: read.clone(),
+        },
+        file.extension(),
+    )?;
+    let mut importer = Importer::new(None).header_rules(header_rules::load_header_rules(app)?);
+    import_games(
+        db,
+        pgn,
+        &mut importer,
+        &GameProvenance::file(file),
+        dedupe,
+        || progress((read.load(Ordering::Relaxed) as f64 / size as f64).min(1.0)),
+    )
+}
+
+/// Import the PGN files `files` into the database at `destination`, creating it if needed,
+/// one file after the other. With `dedupe`, games the database already holds, including those
+/// of earlier files, are left out.
+///
+/// Progress is reported through `DatabaseProgress` events whose id is the database path and
+/// whose stage is the name of the file being imported.
+#[tauri::command]
+#[specta::specta]
+pub async fn convert_pgn_batch(
+    files: Vec<PathBuf>,
+    destination: PathBuf,
+    dedupe: bool,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<BatchImportReport> {
+    for file in &files {
+        state.path_scope.check(file)?;
+    }
+    let id = destination.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let db_exists = destination.exists();
+    let db = &mut get_db_or_create(
+        &state,
+        &id,
+        ConnectionOptions {
+            enable_foreign_keys: false,
+            journal_mode: JournalMode::Off,
+            ..Default::default()
+        },
+    )?;
+    if !db_exists {
+        let title = destination
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        core::init_db(db, &title, "")?;
+    }
+    structure::ensure_structure_table(db)?;
+    provenance::ensure_sources_table(db)?;
+    rarity::ensure_frequency_table(db)?;
+    if dedupe {
+        // Duplicates are looked up by player.
+        db.batch_execute(INDEXES_SQL)?;
+    }
+
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, false);
+    let mut report = BatchImportReport::default();
+    for (i, file) in files.iter().enumerate() {
+        let name = file
+            .file_name()
+            .unwrap_or(file.as_os_str())
+            .to_string_lossy()
+            .to_string();
+        let report_progress = |done: f64| {
+            let progress = (i as f64 + done) / files.len() as f64 * 100_f64;
+            DatabaseProgress {
+                id: id.clone(),
+                progress,
+                stage: Some(name.clone()),
+            }
+            .emit(&app)
+            .ok();
+            task.report(progress, Some(name.clone()));
+        };
+        report_progress(0.0);
+        match import_file(db, file, dedupe, &app, &report_progress) {
+            Ok(import) => {
+                report.imported += import.imported;
+                report.duplicates += import.duplicates;
+            }
+            Err(e) => {
+                warn!("Failed to import {}: {}", file.display(), e);
+                report.failures.push(BatchImportFailure {
+                    file: file.to_string_lossy().to_string(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    if !db_exists {
+        db.batch_execute(INDEXES_SQL)?;
+    }
+    update_info_counts(db)?;
+    if !db_exists {
+        if let Some(fen) = common_start_fen(db)? {
+            info!("All imported games start from {}", fen);
+            set_start_fen(db, Some(&fen))?;
+        }
+    }
+
+    // Pools are cached per path, so drop the unjournaled import pool to make later
+    // commands connect with the default options again.
+    state.connection_pool.remove(&id);
+    DatabaseProgress {
+        id: id.clone(),
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(&app)?;
+    task.finish();
+    state.db_watcher.touch(&destination);
+
+    info!(
+        "Imported {} games from {} files into {} ({} duplicates, {} failed files)",
+        report.imported,
+        files.len(),
+        destination.display(),
+        report.duplicates,
+        report.failures.len()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{schema::games, test_db};
+
+    const CLUB: &str = "[Event \"Club\"]\n[White \"Ann\"]\n[Black \"Bob\"]\n[Result \"1-0\"]\n\n\
+                        1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n\n\
+                        [Event \"Club\"]\n[White \"Bob\"]\n[Black \"Ann\"]\n[Result \"*\"]\n\n\
+                        1. d4 d5 *\n\n";
+
+    /// Reader failing once everything before it was read.
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk unplugged"))
+        }
+    }
+
+    fn import(db: &mut SqliteConnection, pgn: impl Read, dedupe: bool) -> Result<FileImport> {
+        let source = GameProvenance::file(Path::new("club.pgn"));
+        import_games(db, pgn, &mut Importer::new(None), &source, dedupe, || {})
+    }
+
+    fn game_count(db: &mut SqliteConnection) -> i64 {
+        games::table.count().get_result(db).unwrap()
+    }
+
+    #[test]
+    fn duplicates_are_left_out_across_files() {
+        let mut db = test_db("");
+        assert_eq!(
+            import(&mut db, CLUB.as_bytes(), true).unwrap(),
+            FileImport {
+                imported: 2,
+                duplicates: 0
+            }
+        );
+        // The second file repeats the first game twice, with one new game.
+        let second = format!("{}{}", CLUB.replace("1. d4 d5", "1. c4 c5"), CLUB);
+        assert_eq!(
+            import(&mut db, second.as_bytes(), true).unwrap(),
+            FileImport {
+                imported: 1,
+                duplicates: 3
+            }
+        );
+        assert_eq!(game_count(&mut db), 3);
+
+        assert_eq!(import(&mut db, CLUB.as_bytes(), false).unwrap().imported, 2);
+        assert_eq!(game_count(&mut db), 5);
+    }
+
+    #[test]
+    fn unreadable_files_leave_nothing_behind() {
+        let mut db = test_db("");
+        let result = import(&mut db, CLUB.as_bytes().chain(Failing), true);
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert_eq!(game_count(&mut db), 0);
+
+        // Other files still import.
+        assert_eq!(import(&mut db, CLUB.as_bytes(), true).unwrap().imported, 2);
+    }
+}
@@ -0,0 +1,472 @@
+//! Copying a selection of games into another database.
+//!
+//! Games are copied row by row, keeping their encoded moves, so building a study database
+//! from a filtered list is much faster than going through PGN. Players, events and sites are
+//! matched by name in the target, and games it already holds are skipped. Copied games keep
+//! the source they were originally imported from.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use log::info;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+use super::models::{Event, Game, NewGame, Player, Site};
+use super::ops::{create_event, create_player, create_site};
+use super::provenance::{self, GameProvenance};
+use super::schema::{events, game_structures, games, players, sites};
+use super::{
+    core, get_db_or_create, get_games, structure, update_info_counts, write_lock,
+    ConnectionOptions, DatabaseProgress, GameQueryJs, INDEXES_SQL,
+};
+
+/// Games copied per transaction.
+const CLONE_BATCH_SIZE: usize = 500;
+
+/// Games to copy.
+#[derive(Deserialize, Debug, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum GameSelection {
+    Ids(Vec<i32>),
+    /// Every game matched by a games list query, as returned by `get_games`.
+    Query(GameQueryJs),
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, Type)]
+pub struct CloneReport {
+    pub inserted: u32,
+    /// Selected games already in the target or missing from the source.
+    pub skipped: u32,
+}
+
+/// Target IDs of the players, events and sites copied so far, by source ID.
+#[derive(Default)]
+struct EntityMap {
+    players: HashMap<i32, i32>,
+    events: HashMap<i32, i32>,
+    sites: HashMap<i32, i32>,
+}
+
+impl EntityMap {
+    fn player(&mut self, db: &mut SqliteConnection, player: &Player) -> Result<i32> {
+        if let Some(id) = self.players.get(&player.id) {
+            return Ok(*id);
+        }
+        let id = match &player.name {
+            Some(name) => create_player(db, name)?.id,
+            None => 0,
+        };
+        self.players.insert(player.id, id);
+        Ok(id)
+    }
+
+    fn event(&mut self, db: &mut SqliteConnection, event: &Event) -> Result<i32> {
+        if let Some(id) = self.events.get(&event.id) {
+            return Ok(*id);
+        }
+        let id = match &event.name {
+            Some(name) => create_event(db, name)?.id,
+            None => 0,
+        };
+        self.events.insert(event.id, id);
+        Ok(id)
+    }
+
+    fn site(&mut self, db: &mut SqliteConnection, site: &Site) -> Result<i32> {
+        if let Some(id) = self.sites.get(&site.id) {
+            return Ok(*id);
+        }
+        let id = match &site.name {
+            Some(name) => create_site(db, name)?.id,
+            None => 0,
+        };
+        self.sites.insert(site.id, id);
+        Ok(id)
+    }
+}
+
+/// Whether the target already holds `game` with the given players and event.
+fn is_duplicate(
+    db: &mut SqliteConnection,
+    game: &Game,
+    white_id: i32,
+    black_id: i32,
+    event_id: i32,
+) -> Result<bool> {
+    let candidates: Vec<(Option<String>, Option<String>)> = games::table
+        .filter(games::white_id.eq(white_id))
+        .filter(games::black_id.eq(black_id))
+        .filter(games::event_id.eq(event_id))
+        .filter(games::moves.eq(&game.moves))
+        .select((games::date, games::round))
+        .load(db)?;
+    Ok(candidates
+        .iter()
+        .any(|(date, round)| *date == game.date && *round == game.round))
+}
+
+/// Copy the games `ids` of `source` into `target`, one transaction per batch.
+///
+/// `progress` is called with the number of selected games handled after each batch.
+pub(crate) fn clone_games(
+    source: &mut SqliteConnection,
+    target: &mut SqliteConnection,
+    ids: &[i32],
+    mut progress: impl FnMut(usize),
+) -> Result<CloneReport> {
+    structure::ensure_structure_table(source)?;
+    structure::ensure_structure_table(target)?;
+    provenance::ensure_sources_table(source)?;
+    provenance::ensure_sources_table(target)?;
+
+    let (white_players, black_players) = diesel::alias!(players as white, players as black);
+    let mut entities = EntityMap::default();
+    let mut report = CloneReport::default();
+    for (i, batch) in ids.chunks(CLONE_BATCH_SIZE).enumerate() {
+        let rows: Vec<(Game, Player, Player, Event, Site)> = games::table
+            .inner_join(white_players.on(games::white_id.eq(white_players.field(players::id))))
+            .inner_join(black_players.on(games::black_id.eq(black_players.field(players::id))))
+            .inner_join(events::table.on(games::event_id.eq(events::id)))
+            .inner_join(sites::table.on(games::site_id.eq(sites::id)))
+            .filter(games::id.eq_any(batch))
+            .order(games::id)
+            .load(source)?;
+        let structures: HashMap<i32, String> = game_structures::table
+            .filter(game_structures::game_id.eq_any(batch))
+            .load::<(i32, String)>(source)?
+            .into_iter()
+            .collect();
+        let sources = provenance::load_sources(source, batch)?;
+
+        let inserted = target.transaction::<_, Error, _>(|db| {
+            let mut inserted = 0;
+            for (game, white, black, event, site) in &rows {
+                let white_id = entities.player(db, white)?;
+                let black_id = entities.player(db, black)?;
+                let event_id = entities.event(db, event)?;
+                let site_id = entities.site(db, site)?;
+                if is_duplicate(db, game, white_id, black_id, event_id)? {
+                    continue;
+                }
+
+                let new_game = core::add_game(
+                    db,
+                    NewGame {
+                        event_id,
+                        site_id,
+                        date: game.date.as_deref(),
+                        time: game.time.as_deref(),
+                        round: game.round.as_deref(),
+                        white_id,
+                        white_elo: game.white_elo,
+                        black_id,
+                        black_elo: game.black_elo,
+                        white_material: game.white_material,
+                        black_material: game.black_material,
+                        result: game.result.as_deref(),
+                        time_control: game.time_control.as_deref(),
+                        eco: game.eco.as_deref(),
+                        ply_count: game.ply_count.unwrap_or_default(),
+                        fen: game.fen.as_deref(),
+                        moves: &game.moves,
+                        pawn_home: game.pawn_home,
+                    },
+                )?;
+                if let Some(label) = structures.get(&game.id) {
+                    diesel::replace_into(game_structures::table)
+                        .values((
+                            game_structures::game_id.eq(new_game.id),
+                            game_structures::structure.eq(label),
+                        ))
+                        .execute(db)?;
+                }
+                let origin = match sources.get(&game.id) {
+                    Some(origin) => origin.clone(),
+                    None => GameProvenance::unknown(),
+                };
+                provenance::record_source(db, new_game.id, &origin)?;
+                inserted += 1;
+            }
+            Ok(inserted)
+        })?;
+
+        report.inserted += inserted;
+        report.skipped += (batch.len() - inserted as usize) as u32;
+        progress((i * CLONE_BATCH_SIZE + batch.len()).min(ids.len()));
+    }
+    update_info_counts(target)?;
+    Ok(report)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Copy games of `source_file` into `target_file`, creating it with the standard schema if
+/// it doesn't exist and `create_if_missing` is set.
+///
+/// Progress is reported through `DatabaseProgress` events whose id is the target path.
+#[tauri::command]
+#[specta::specta]
+pub async fn clone_games_to_database(
+    source_file: PathBuf,
+    selection: GameSelection,
+    target_file: PathBuf,
+    create_if_missing: bool,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<CloneReport> {
+    if same_file(&source_file, &target_file) {
+        return Err(Error::SameDatabase);
+    }
+    let target_exists = target_file.exists();
+    if !target_exists && !create_if_missing {
+        return Err(Error::DatabaseNotFound(
+            target_file.to_string_lossy().to_string(),
+        ));
+    }
+
+    let ids: Vec<i32> = match selection {
+        GameSelection::Ids(ids) => ids,
+        GameSelection::Query(query) => get_games(source_file.clone(), query, state.clone())
+            .await?
+            .data
+            .into_iter()
+            .map(|game| game.id)
+            .collect(),
+    };
+
+    let id = target_file.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let source = &mut get_db_or_create(
+        &state,
+        source_file.to_str().unwrap(),
+        ConnectionOptions::default(),
+    )?;
+    let target = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    if !target_exists {
+        let title = target_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        core::init_db(target, &title, "")?;
+        target.batch_execute(INDEXES_SQL)?;
+    }
+
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, false);
+    let report = clone_games(source, target, &ids, |done| {
+        let progress = done as f64 / ids.len() as f64 * 100_f64;
+        DatabaseProgress {
+            id: id.clone(),
+            progress,
+            stage: Some("clone".to_string()),
+        }
+        .emit(&app)
+        .ok();
+        task.report(progress, Some("clone".to_string()));
+    })?;
+    DatabaseProgress {
+        id: id.clone(),
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(&app)?;
+    task.finish();
+    state.db_watcher.touch(&target_file);
+
+    info!(
+        "Copied {} games from {} to {} ({} skipped)",
+        report.inserted,
+        source_file.display(),
+        target_file.display(),
+        report.skipped
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{import_test_games, test_db};
+
+    const GAMES: &str = r#"[Event "Candidates"]
+[Site "Toronto"]
+[Date "2024.04.04"]
+[White "Gukesh"]
+[Black "Vidit"]
+[WhiteElo "2743"]
+[Result "1-0"]
+
+1. d4 Nf6 2. c4 e6 { Solid } 3. Nc3 (3. Nf3 d5) 3... Bb4 1-0
+
+[Event "Candidates"]
+[White "Nepo"]
+[Black "Caruana"]
+[Result "1/2-1/2"]
+
+1. e4 e5 2. Nf3 Nc6 1/2-1/2
+
+[Event "Blitz"]
+[White "Carlsen"]
+[Black "Gukesh"]
+[FEN "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"]
+[SetUp "1"]
+[Result "*"]
+
+1. e4 *
+"#;
+
+    fn player_names(db: &mut SqliteConnection) -> Vec<Option<String>> {
+        players::table
+            .select(players::name)
+            .order(players::name)
+            .load(db)
+            .unwrap()
+    }
+
+    #[test]
+    fn copied_games_open_identically() {
+        let mut source = test_db("");
+        let ids = import_test_games(&mut source, GAMES);
+        let mut target = test_db("");
+        // Gukesh is already in the target under another ID.
+        import_test_games(
+            &mut target,
+            "[White \"Gukesh\"]\n[Black \"Ding\"]\n\n1. c4 *\n",
+        );
+
+        let mut handled = Vec::new();
+        let selected = [ids[0], ids[2]];
+        let report = clone_games(&mut source, &mut target, &selected, |done| {
+            handled.push(done)
+        })
+        .unwrap();
+        assert_eq!(
+            report,
+            CloneReport {
+                inserted: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(handled, [2]);
+
+        let copies: Vec<i32> = games::table
+            .select(games::id)
+            .order(games::id)
+            .load(&mut target)
+            .unwrap();
+        assert_eq!(copies.len(), 3);
+        for (original, copy) in selected.iter().zip(&copies[1..]) {
+            let original = core::get_game(&mut source, *original).unwrap();
+            let copy = core::get_game(&mut target, *copy).unwrap();
+            assert_eq!(copy.moves, original.moves);
+            assert_eq!(copy.fen, original.fen);
+            assert_eq!(copy.white, original.white);
+            assert_eq!(copy.black, original.black);
+            assert_eq!(copy.event, original.event);
+            assert_eq!(copy.site, original.site);
+            assert_eq!(copy.date, original.date);
+            assert_eq!(copy.white_elo, original.white_elo);
+            assert_eq!(copy.ply_count, original.ply_count);
+            assert!(copy.result == original.result);
+        }
+
+        // Players are matched by name rather than copied again.
+        let names = player_names(&mut target);
+        let gukesh = names
+            .iter()
+            .filter(|name| name.as_deref() == Some("Gukesh"))
+            .count();
+        assert_eq!(gukesh, 1);
+        assert!(!names.contains(&Some("Nepo".to_string())));
+    }
+
+    #[test]
+    fn games_already_copied_are_skipped() {
+        let mut source = test_db("");
+        let ids = import_test_games(&mut source, GAMES);
+        let mut target = test_db("");
+
+        let first = clone_games(&mut source, &mut target, &ids, |_| {}).unwrap();
+        assert_eq!(first.inserted, 3);
+        let again = clone_games(&mut source, &mut target, &[ids[1], 1000], |_| {}).unwrap();
+        assert_eq!(
+            again,
+            CloneReport {
+                inserted: 0,
+                skipped: 2
+            }
+        );
+        let count: i64 = games::table.count().get_result(&mut target).unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn copied_games_keep_their_source() {
+        let mut source = test_db("");
+        let candidates = GameProvenance::file(Path::new("/downloads/candidates.pgn"));
+        let ids = import_test_games(&mut source, GAMES);
+        for id in &ids {
+            provenance::record_source(&mut source, *id, &candidates).unwrap();
+        }
+        let mut target = test_db("");
+        let lichess = GameProvenance::online(&provenance::OnlineAccount {
+            platform: "lichess".to_string(),
+            username: "someone".to_string(),
+        });
+        let own = import_test_games(
+            &mut target,
+            "[White \"Gukesh\"]\n[Black \"Ding\"]\n\n1. c4 *\n",
+        );
+        provenance::record_source(&mut target, own[0], &lichess).unwrap();
+
+        clone_games(&mut source, &mut target, &ids[..2], |_| {}).unwrap();
+
+        let sources: Vec<_> = (1..=3)
+            .map(|id| core::get_game(&mut target, id).unwrap().source.unwrap())
+            .collect();
+        assert_eq!(sources, [lichess.clone(), candidates.clone(), candidates]);
+
+        let query = provenance::ProvenanceQuery {
+            kind: provenance::ProvenanceKind::File,
+            name: Some("candidates.pgn".to_string()),
+            account: None,
+        };
+        let from_file: Vec<i32> = games::table
+            .filter(games::id.eq_any(provenance::games_from(&query)))
+            .select(games::id)
+            .order(games::id)
+            .load(&mut target)
+            .unwrap();
+        assert_eq!(from_file, [2, 3]);
+
+        let counts = provenance::source_counts(&mut target).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            (counts[0].name.as_str(), counts[0].count),
+            ("candidates.pgn", 2)
+        );
+        assert_eq!(counts[1].account.as_deref(), Some("someone"));
+    }
+
+    #[test]
+    fn a_database_is_not_copied_into_itself() {
+        let path = Path::new("games.db3");
+        assert!(same_file(path, Path::new("games.db3")));
+        assert!(!same_file(path, Path::new("study.db3")));
+    }
+}
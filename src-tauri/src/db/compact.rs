@@ -0,0 +1,294 @@
+//! Move blob compaction
+//!
+//! Games keep the move blob they were imported with, so a database accumulates blobs of
+//! every encoding version it has seen. `compact_database` rewrites the games that aren't on
+//! `ENCODING_VERSION` yet: each blob is decoded with the decoder of its version, encoded
+//! again and decoded back, and only replaced when the result has the same moves and
+//! annotations. Games failing that check keep their blob and version and are reported.
+//! Batches are committed one at a time, so a cancelled compaction leaves every game either
+//! untouched or fully migrated.
+
+use std::path::PathBuf;
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use serde::Serialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess};
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{
+        encoding::{decode_versioned, ENCODING_VERSION},
+        get_db_or_create, get_start_position,
+        schema::{game_encodings, games},
+        write_lock, ConnectionOptions, DatabaseProgress,
+    },
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+const GAME_ENCODINGS_SQL: &str = include_str!("../../../database/schema/game_encodings.sql");
+
+const COMPACT_BATCH_SIZE: i64 = 5_000;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct CompactFailure {
+    pub game_id: i32,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default, Type)]
+pub struct CompactReport {
+    /// Games moved to the current encoding version.
+    pub migrated: u32,
+    /// Migrated games whose blob changed when encoded again.
+    pub rewritten: u32,
+    /// Games left on their old version because their blob couldn't be rewritten faithfully.
+    pub failed: Vec<CompactFailure>,
+}
+
+/// Databases created before encoding versions were tracked don't have the table yet.
+pub(crate) fn ensure_encodings_table(db: &mut SqliteConnection) -> Result<()> {
+    db.batch_execute(GAME_ENCODINGS_SQL)?;
+    Ok(())
+}
+
+/// The blob of a game in the current encoding, or why it can't be rewritten.
+fn rewrite(moves: &[u8], version: i32, position: Chess) -> std::result::Result<Vec<u8>, String> {
+    let tree =
+        decode_versioned(moves, version, Some(position.clone())).map_err(|e| e.to_string())?;
+    let mut rewritten = Vec::new();
+    tree.encode(&mut rewritten, Some(position.clone()));
+    let decoded = decode_versioned(&rewritten, ENCODING_VERSION, Some(position))
+        .map_err(|e| format!("Rewritten moves don't decode: {}", e))?;
+    if decoded != tree {
+        return Err("Rewritten moves differ from the original ones".to_string());
+    }
+    Ok(rewritten)
+}
+
+/// Rewrite the games of `db` that aren't on the current encoding version.
+///
+/// `progress` is called with the number of games handled after each batch, out of the
+/// number of games to migrate; compaction stops with `TaskCancelled` once `is_cancelled`
+/// is set, keeping the batches already committed.
+pub(crate) fn compact_games(
+    db: &mut SqliteConnection,
+    mut progress: impl FnMut(usize, i64),
+    is_cancelled: impl Fn() -> bool,
+) -> Result<CompactReport> {
+    ensure_encodings_table(db)?;
+    let start = get_start_position(db)?;
+    let outdated = || {
+        games::table
+            .left_join(game_encodings::table.on(game_encodings::game_id.eq(games::id)))
+            .filter(
+                game_encodings::version
+                    .is_null()
+                    .or(game_encodings::version.lt(ENCODING_VERSION)),
+            )
+    };
+    let total: i64 = outdated().count().get_result(db)?;
+
+    let mut report = CompactReport::default();
+    let mut handled = 0;
+    let mut last_id = 0;
+    loop {
+        if is_cancelled() {
+            return Err(Error::TaskCancelled);
+        }
+        let batch: Vec<(i32, Option<String>, Vec<u8>, Option<i32>)> = outdated()
+            .select((
+                games::id,
+                games::fen,
+                games::moves,
+                game_encodings::version.nullable(),
+            ))
+            .filter(games::id.gt(last_id))
+            .order(games::id)
+            .limit(COMPACT_BATCH_SIZE)
+            .load(db)?;
+        let Some((batch_last, _, _, _)) = batch.last() else {
+            break;
+        };
+        last_id = *batch_last;
+
+        let batch_report = db.immediate_transaction::<_, Error, _>(|db| {
+            let mut batch_report = CompactReport::default();
+            for (game_id, fen, moves, version) in &batch {
+                let position = match fen {
+                    Some(fen) => Fen::from_ascii(fen.as_bytes())?
+                        .into_position::<Chess>(CastlingMode::Chess960)?,
+                    None => start.clone(),
+                };
+                let rewritten = match rewrite(moves, version.unwrap_or(0), position) {
+                    Ok(rewritten) => rewritten,
+                    Err(error) => {
+                        batch_report.failed.push(CompactFailure {
+                            game_id: *game_id,
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                if &rewritten != moves {
+                    diesel::update(games::table.filter(games::id.eq(game_id)))
+                        .set(games::moves.eq(&rewritten))
+                        .execute(db)?;
+                    batch_report.rewritten += 1;
+                }
+                diesel::replace_into(game_encodings::table)
+                    .values((
+                        game_encodings::game_id.eq(game_id),
+                        game_encodings::version.eq(ENCODING_VERSION),
+                    ))
+                    .execute(db)?;
+                batch_report.migrated += 1;
+            }
+            Ok(batch_report)
+        })?;
+        report.migrated += batch_report.migrated;
+        report.rewritten += batch_report.rewritten;
+        report.failed.extend(batch_report.failed);
+
+        handled += batch.len();
+        progress(handled, total);
+    }
+    Ok(report)
+}
+
+/// Rewrite every game of a database in the current move encoding.
+///
+/// Progress is reported through `DatabaseProgress` events whose id is the database path.
+/// Cancelling keeps the games migrated so far; running it again picks up the rest.
+#[tauri::command]
+#[specta::specta]
+pub async fn compact_database(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<CompactReport> {
+    let id = file.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, true);
+    let report = compact_games(
+        db,
+        |handled, total| {
+            let progress = (handled as f64 / total.max(1) as f64) * 100_f64;
+            let _ = DatabaseProgress {
+                id: id.clone(),
+                progress,
+                stage: Some("compact".to_string()),
+            }
+            .emit(&app);
+            task.report(progress, Some("compact".to_string()));
+        },
+        || task.is_cancelled(),
+    )?;
+
+    DatabaseProgress {
+        id,
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(&app)?;
+    task.finish();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use std::cell::Cell;
+
+    const GAMES: &str = "[Result \"*\"]\n\n1. e4 e5 2. Nf3 $1 { Developing } ( 2. Bc4 Nf6 ) 2... Nc6 *\n\n\
+                         [Result \"*\"]\n\n1. d4 d5 2. c4 dxc4 *\n\n\
+                         [Result \"*\"]\n[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 w - - 0 1\"]\n\n1. e4 Kd7 *\n";
+
+    /// A database as written before encoding versions were tracked.
+    fn fixture() -> SqliteConnection {
+        test_db(GAMES)
+    }
+
+    fn blobs(db: &mut SqliteConnection) -> Vec<(i32, Vec<u8>)> {
+        games::table
+            .select((games::id, games::moves))
+            .order(games::id)
+            .load(db)
+            .unwrap()
+    }
+
+    fn versions(db: &mut SqliteConnection) -> Vec<(i32, i32)> {
+        game_encodings::table
+            .select((game_encodings::game_id, game_encodings::version))
+            .order(game_encodings::game_id)
+            .load(db)
+            .unwrap()
+    }
+
+    #[test]
+    fn old_databases_end_up_on_the_current_version() {
+        let mut db = fixture();
+        let before = blobs(&mut db);
+
+        let report = compact_games(&mut db, |_, _| {}, || false).unwrap();
+        assert_eq!(report.migrated, 3);
+        assert!(report.failed.is_empty());
+        assert_eq!(blobs(&mut db), before);
+        assert_eq!(
+            versions(&mut db),
+            vec![
+                (1, ENCODING_VERSION),
+                (2, ENCODING_VERSION),
+                (3, ENCODING_VERSION)
+            ]
+        );
+
+        // Nothing is left to do the second time.
+        let report = compact_games(&mut db, |_, _| {}, || false).unwrap();
+        assert_eq!(report.migrated, 0);
+    }
+
+    #[test]
+    fn unreadable_games_are_reported_and_left_alone() {
+        let mut db = fixture();
+        diesel::update(games::table.filter(games::id.eq(2)))
+            .set(games::moves.eq(vec![12_u8, 200]))
+            .execute(&mut db)
+            .unwrap();
+
+        let report = compact_games(&mut db, |_, _| {}, || false).unwrap();
+        assert_eq!(report.migrated, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].game_id, 2);
+        assert_eq!(blobs(&mut db)[1].1, vec![12_u8, 200]);
+        assert_eq!(
+            versions(&mut db),
+            vec![(1, ENCODING_VERSION), (3, ENCODING_VERSION)]
+        );
+    }
+
+    #[test]
+    fn cancelling_keeps_committed_batches() {
+        let mut db = fixture();
+        let batches = Cell::new(0);
+        let result = compact_games(
+            &mut db,
+            |_, _| batches.set(batches.get() + 1),
+            || batches.get() > 0,
+        );
+        assert!(matches!(result, Err(Error::TaskCancelled)));
+        // All three games fit in the first batch, which was committed before cancelling.
+        assert_eq!(versions(&mut db).len(), 3);
+
+        let mut db = fixture();
+        let result = compact_games(&mut db, |_, _| {}, || true);
+        assert!(matches!(result, Err(Error::TaskCancelled)));
+        assert!(versions(&mut db).is_empty());
+    }
+}
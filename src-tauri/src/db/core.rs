@@ -1,13 +1,17 @@
 use super::{
-    create_event, create_player, create_site,
-    models::{Event, Game, NewGame, NormalizedGame, Outcome, Player, Site, UpdateGame},
-    pgn::{GameTree, Importer},
+    create_event, create_player, create_site, get_pawn_home,
+    models::{
+        Event, Game, GamePatchOp, NewGame, NormalizedGame, Outcome, Player, Site, UpdateGame,
+    },
+    pgn::{get_material_count, GameTree, Importer},
     schema::{events, games, players, sites},
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::opening::{find_opening_annotation, OpeningAnnotation};
 use diesel::{connection::SimpleConnection, prelude::*};
-use pgn_reader::BufferedReader;
-use shakmaty::{fen::Fen, CastlingMode, Chess, FromSetup};
+use log::warn;
+use pgn_reader::{BufferedReader, Nag};
+use shakmaty::{fen::Fen, CastlingMode, Chess, EnPassantMode, FromSetup, Position, Setup};
 use std::str::FromStr;
 use std::string::ToString;
 
@@ -23,6 +27,11 @@ pub fn init_db(conn: &mut SqliteConnection, title: &str, description: &str) -> R
     // Create tables
     conn.batch_execute(CREATE_TABLES_SQL)?;
 
+    super::structure::ensure_structure_table(conn)?;
+    super::links::ensure_links_table(conn)?;
+    super::provenance::ensure_sources_table(conn)?;
+    super::rarity::ensure_frequency_table(conn)?;
+
     // Insert initial seed data
     conn.batch_execute(INITIAL_DATA_SQL)?;
 
@@ -49,6 +58,18 @@ pub fn normalize_game(
         .map(|f| Fen::from_ascii(f.as_bytes()).unwrap())
         .unwrap_or_default();
 
+    // Show what can be read of a corrupted game instead of failing the whole listing
+    let (moves, decode_error) = GameTree::from_bytes_partial(
+        &game.moves,
+        Some(Chess::from_setup(
+            fen.clone().into(),
+            CastlingMode::Chess960,
+        )?),
+    );
+    if let Some(err) = &decode_error {
+        warn!("Game {} has corrupted move data: {}", game.id, err);
+    }
+
     Ok(NormalizedGame {
         id: game.id,
         event: event.name.unwrap_or_default(),
@@ -69,11 +90,10 @@ pub fn normalize_game(
         eco: game.eco,
         ply_count: game.ply_count,
         fen: fen.to_string(),
-        moves: GameTree::from_bytes(
-            &game.moves,
-            Some(Chess::from_setup(fen.into(), CastlingMode::Chess960)?),
-        )?
-        .to_string(),
+        moves: moves.to_string(),
+        decode_warnings: decode_error.is_some() as u32,
+        links: None,
+        source: None,
     })
 }
 
@@ -96,7 +116,10 @@ pub fn get_game(conn: &mut SqliteConnection, id: i32) -> Result<NormalizedGame>
         .filter(games::id.eq(id))
         .first(conn)?;
 
-    normalize_game(game, white, black, event, site)
+    let mut game = normalize_game(game, white, black, event, site)?;
+    game.links = Some(super::links::linked_games(conn, id)?);
+    super::provenance::attach_sources(conn, std::slice::from_mut(&mut game))?;
+    Ok(game)
 }
 
 pub fn update_game(conn: &mut SqliteConnection, id: i32, data: &UpdateGame) -> Result<()> {
@@ -137,7 +160,209 @@ pub fn update_game(conn: &mut SqliteConnection, id: i32, data: &UpdateGame) -> R
     Ok(())
 }
 
+/// FNV-1a hash, stable across runs and platforms.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHash(u64);
+
+impl Default for StableHash {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl StableHash {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+    }
+
+    pub fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// Revision of a game's moves, used to detect concurrent edits.
+pub fn game_revision(moves: &[u8]) -> String {
+    let mut hash = StableHash::default();
+    hash.update(moves);
+    hash.hex()
+}
+
+fn set_header(conn: &mut SqliteConnection, id: i32, name: &str, value: Option<&str>) -> Result<()> {
+    let game = games::table.find(id);
+    let name_value = value.unwrap_or_default();
+    let elo = || value.map(str::parse::<i32>).transpose();
+    match name.to_ascii_lowercase().as_str() {
+        "event" => diesel::update(game)
+            .set(games::event_id.eq(create_event(conn, name_value)?.id))
+            .execute(conn)?,
+        "site" => diesel::update(game)
+            .set(games::site_id.eq(create_site(conn, name_value)?.id))
+            .execute(conn)?,
+        "white" => diesel::update(game)
+            .set(games::white_id.eq(create_player(conn, name_value)?.id))
+            .execute(conn)?,
+        "black" => diesel::update(game)
+            .set(games::black_id.eq(create_player(conn, name_value)?.id))
+            .execute(conn)?,
+        "whiteelo" => diesel::update(game)
+            .set(games::white_elo.eq(elo()?))
+            .execute(conn)?,
+        "blackelo" => diesel::update(game)
+            .set(games::black_elo.eq(elo()?))
+            .execute(conn)?,
+        "date" => diesel::update(game)
+            .set(games::date.eq(value))
+            .execute(conn)?,
+        "time" | "utctime" => diesel::update(game)
+            .set(games::time.eq(value))
+            .execute(conn)?,
+        "round" => diesel::update(game)
+            .set(games::round.eq(value))
+            .execute(conn)?,
+        "result" => diesel::update(game)
+            .set(games::result.eq(Outcome::from_str(name_value)?.to_string()))
+            .execute(conn)?,
+        "timecontrol" => diesel::update(game)
+            .set(games::time_control.eq(value))
+            .execute(conn)?,
+        "eco" => diesel::update(game)
+            .set(games::eco.eq(value))
+            .execute(conn)?,
+        _ => {
+            return Err(Error::InvalidPatch(format!(
+                "Header {} can't be patched",
+                name
+            )))
+        }
+    };
+    Ok(())
+}
+
+/// Apply `ops` to a game, re-encoding its moves once.
+///
+/// Fails with `Error::GameConflict` if the moves no longer match `expected_revision`, and
+/// applies nothing if any operation fails. Returns the new revision.
+pub fn patch_game(
+    conn: &mut SqliteConnection,
+    id: i32,
+    expected_revision: &str,
+    ops: &[GamePatchOp],
+) -> Result<String> {
+    conn.immediate_transaction(|conn| {
+        let game: Game = games::table.find(id).first(conn)?;
+        let revision = game_revision(&game.moves);
+        if revision != expected_revision {
+            return Err(Error::GameConflict(revision));
+        }
+
+        let start = game
+            .fen
+            .as_deref()
+            .map(|fen| -> Result<Chess> {
+                let fen = Fen::from_ascii(fen.as_bytes())?;
+                Ok(Chess::from_setup(fen.into(), CastlingMode::Chess960)?)
+            })
+            .transpose()?;
+        let mut tree = GameTree::from_bytes(&game.moves, start.clone())?;
+        let mut moves_changed = false;
+        for op in ops {
+            match op {
+                GamePatchOp::SetCommentAtPly { ply, comment } => {
+                    tree.set_comment(*ply as usize, comment)?;
+                }
+                GamePatchOp::AddNagAtPly { ply, nag } => tree.add_nag(*ply as usize, Nag(*nag))?,
+                GamePatchOp::RemoveVariation { ply, index } => {
+                    tree.remove_variation(*ply as usize, *index as usize)?;
+                }
+                GamePatchOp::AppendMovesToMainline { moves } => {
+                    tree.append_moves(moves, start.clone())?;
+                }
+                GamePatchOp::SetHeader { name, value } => {
+                    set_header(conn, id, name, value.as_deref())?;
+                    continue;
+                }
+            }
+            moves_changed = true;
+        }
+        if !moves_changed {
+            return Ok(revision);
+        }
+
+        let mut moves = Vec::new();
+        tree.encode(&mut moves, start.clone());
+        let start_material = get_material_count(start.clone().unwrap_or_default().board());
+        let end = tree.main_line_end(start)?;
+        let end_material = get_material_count(end.board());
+        diesel::update(games::table.find(id))
+            .set((
+                games::moves.eq(&moves),
+                games::ply_count.eq(tree.count_main_line_moves() as i32),
+                games::white_material.eq(start_material.white.min(end_material.white) as i32),
+                games::black_material.eq(start_material.black.min(end_material.black) as i32),
+                games::pawn_home.eq(get_pawn_home(end.board()) as i32),
+            ))
+            .execute(conn)?;
+        Ok(game_revision(&moves))
+    })
+}
+
+/// Replace the encoded moves of a game whose annotations changed but whose main line did not.
+///
+/// Fails with `GameConflict` if the game no longer matches `expected_revision`.
+pub fn replace_moves(
+    conn: &mut SqliteConnection,
+    id: i32,
+    expected_revision: &str,
+    moves: &[u8],
+) -> Result<String> {
+    conn.immediate_transaction(|conn| {
+        let game: Game = games::table.find(id).first(conn)?;
+        let revision = game_revision(&game.moves);
+        if revision != expected_revision {
+            return Err(Error::GameConflict(revision));
+        }
+        diesel::update(games::table.find(id))
+            .set(games::moves.eq(moves))
+            .execute(conn)?;
+        Ok(game_revision(moves))
+    })
+}
+
+/// Comment the move where a game leaves opening theory with the name of its opening.
+///
+/// Games from a custom start or that never reach a named position are left unchanged.
+pub fn annotate_opening(conn: &mut SqliteConnection, id: i32) -> Result<Option<OpeningAnnotation>> {
+    let game: Game = games::table.find(id).first(conn)?;
+    let start = game
+        .fen
+        .as_deref()
+        .map(|fen| -> Result<Chess> {
+            let fen = Fen::from_ascii(fen.as_bytes())?;
+            Ok(Chess::from_setup(fen.into(), CastlingMode::Chess960)?)
+        })
+        .transpose()?;
+    let mut tree = GameTree::from_bytes(&game.moves, start.clone())?;
+    let positions: Vec<Setup> = tree
+        .main_line_positions(start.clone())?
+        .into_iter()
+        .map(|position| position.into_setup(EnPassantMode::Legal))
+        .collect();
+    let Some(annotation) = find_opening_annotation(&positions) else {
+        return Ok(None);
+    };
+
+    tree.add_comment(annotation.ply as usize, &annotation.comment())?;
+    let mut moves = Vec::new();
+    tree.encode(&mut moves, start);
+    replace_moves(conn, id, &game_revision(&game.moves), &moves)?;
+    Ok(Some(annotation))
+}
+
 pub fn remove_game(conn: &mut SqliteConnection, id: i32) -> Result<()> {
+    super::links::detach_game(conn, id)?;
+    super::attribution::forget_game(conn, id)?;
     diesel::delete(games::table.filter(games::id.eq(id))).execute(conn)?;
 
     Ok(())
@@ -146,25 +371,154 @@ pub fn remove_game(conn: &mut SqliteConnection, id: i32) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::test_db;
     use diesel::{sql_query, sql_types::Text};
     use serde::Serialize;
 
-    fn test_db() -> SqliteConnection {
-        let mut conn = SqliteConnection::establish(":memory:").unwrap();
-        init_db(&mut conn, "Test", "Test").unwrap();
-
-        conn
-    }
-
     #[derive(QueryableByName, Debug, Serialize)]
     struct IndexInfo {
         #[diesel(sql_type = Text, column_name = "name")]
         _name: String,
     }
 
+    fn insert_game(db: &mut SqliteConnection, movetext: &str) -> Game {
+        let mut importer = Importer::new(None);
+        let tree = BufferedReader::new_cursor(movetext)
+            .read_game(&mut importer)
+            .unwrap()
+            .flatten()
+            .unwrap()
+            .tree;
+        let mut moves = Vec::new();
+        tree.encode(&mut moves, None);
+
+        add_game(
+            db,
+            NewGame {
+                event_id: 0,
+                site_id: 0,
+                date: None,
+                time: None,
+                round: None,
+                white_id: 0,
+                white_elo: None,
+                black_id: 0,
+                black_elo: None,
+                white_material: 39,
+                black_material: 39,
+                result: Some("*"),
+                time_control: None,
+                eco: None,
+                ply_count: tree.count_main_line_moves() as i32,
+                fen: None,
+                moves: &moves,
+                pawn_home: 0,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn patch_game_applies_ops_and_bumps_revision() {
+        let mut db = test_db("");
+        let game = insert_game(&mut db, "1. e4 e5 2. Nf3 *");
+        let revision = game_revision(&game.moves);
+
+        let ops = vec![
+            GamePatchOp::SetCommentAtPly {
+                ply: 2,
+                comment: "Open game".to_string(),
+            },
+            GamePatchOp::AppendMovesToMainline {
+                moves: vec!["Nc6".to_string(), "Bb5".to_string()],
+            },
+            GamePatchOp::SetHeader {
+                name: "WhiteElo".to_string(),
+                value: Some("2450".to_string()),
+            },
+            GamePatchOp::SetHeader {
+                name: "Result".to_string(),
+                value: Some("1-0".to_string()),
+            },
+        ];
+        let new_revision = patch_game(&mut db, game.id, &revision, &ops).unwrap();
+        assert_ne!(new_revision, revision);
+
+        let patched: Game = games::table.find(game.id).first(&mut db).unwrap();
+        assert_eq!(game_revision(&patched.moves), new_revision);
+        assert_eq!(patched.ply_count, Some(5));
+        assert_eq!(patched.white_elo, Some(2450));
+        assert_eq!(patched.result.as_deref(), Some("1-0"));
+        assert_eq!(
+            GameTree::from_bytes(&patched.moves, None)
+                .unwrap()
+                .to_string(),
+            "1.e4 e5 {Open game}  2.Nf3 Nc6 3.Bb5"
+        );
+    }
+
+    #[test]
+    fn annotate_opening_comments_the_last_book_move_once() {
+        let mut db = test_db("");
+        let game = insert_game(&mut db, "1. c4 e6 2. Nc3 d5 3. d4 Nf6 {Solid} 4. h4 *");
+
+        for _ in 0..2 {
+            let annotation = annotate_opening(&mut db, game.id).unwrap().unwrap();
+            assert_eq!(annotation.ply, 6);
+            let annotated: Game = games::table.find(game.id).first(&mut db).unwrap();
+            assert_eq!(
+                GameTree::from_bytes(&annotated.moves, None)
+                    .unwrap()
+                    .to_string(),
+                "1.c4 e6 2.Nc3 d5 3.d4 Nf6 {Solid}  \
+                 {Theory ends here: Queen's Gambit Declined: Normal Defense}  4.h4"
+            );
+        }
+    }
+
+    #[test]
+    fn patch_game_rejects_stale_revisions() {
+        let mut db = test_db("");
+        let game = insert_game(&mut db, "1. d4 d5 *");
+        let revision = game_revision(&game.moves);
+        let append = |m: &str| {
+            vec![GamePatchOp::AppendMovesToMainline {
+                moves: vec![m.to_string()],
+            }]
+        };
+
+        let current = patch_game(&mut db, game.id, &revision, &append("c4")).unwrap();
+        match patch_game(&mut db, game.id, &revision, &append("Nf3")) {
+            Err(Error::GameConflict(actual)) => assert_eq!(actual, current),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        let stored: Game = games::table.find(game.id).first(&mut db).unwrap();
+        assert_eq!(game_revision(&stored.moves), current);
+    }
+
+    #[test]
+    fn failed_patch_changes_nothing() {
+        let mut db = test_db("");
+        let game = insert_game(&mut db, "1. e4 *");
+        let revision = game_revision(&game.moves);
+        let ops = vec![
+            GamePatchOp::SetHeader {
+                name: "Round".to_string(),
+                value: Some("3".to_string()),
+            },
+            GamePatchOp::RemoveVariation { ply: 1, index: 0 },
+        ];
+        assert!(patch_game(&mut db, game.id, &revision, &ops).is_err());
+
+        let stored: Game = games::table.find(game.id).first(&mut db).unwrap();
+        assert_eq!(stored.round, None);
+        assert_eq!(game_revision(&stored.moves), revision);
+    }
+
     #[test]
     fn test_add_game() {
-        let mut db = test_db();
+        let mut db = test_db("");
 
         let query = sql_query(GAMES_CHECK_INDEXES);
         let indexes: Vec<IndexInfo> = query.load(&mut db).unwrap();
@@ -0,0 +1,301 @@
+//! Creating an empty database.
+//!
+//! Databases used to be created only by importing a PGN into a new file. `create_database`
+//! sets one up with the same schema the importer creates, so games can be imported into it
+//! right away, along with the tables that databases otherwise only get on first use. The
+//! page size is picked from the expected number of games, since SQLite can only change it
+//! later with a `VACUUM`. The journal mode isn't: every connection of the pool sets its own.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use serde::Deserialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess};
+use specta::Type;
+
+use crate::{
+    db::{
+        attribution, core, metadata, player_metadata, set_start_fen, trash, update_info_counts,
+        write_lock, INDEXES_SQL,
+    },
+    error::{Error, Result},
+    AppState,
+};
+
+const DB_EXTENSION: &str = "db3";
+
+/// Games above which larger pages make reading games faster.
+const LARGE_DATABASE_GAMES: u32 = 100_000;
+const HUGE_DATABASE_GAMES: u32 = 1_000_000;
+
+/// Tables and indexes to create with the database instead of on first use.
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DatabaseExtras {
+    /// Search indexes, which imports into an existing database don't create.
+    pub indexes: bool,
+    /// Notes, source, license and tags of the database.
+    pub metadata: bool,
+    /// Photos and profiles of players.
+    pub player_metadata: bool,
+    /// Engines and settings of the analyses stored in games.
+    pub analysis_attributions: bool,
+}
+
+impl Default for DatabaseExtras {
+    fn default() -> Self {
+        Self {
+            indexes: true,
+            metadata: false,
+            player_metadata: false,
+            analysis_attributions: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct NewDatabaseOptions {
+    /// Defaults to the file name.
+    #[specta(optional)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    /// Position games stored without a FEN start from, for themed databases.
+    #[specta(optional)]
+    pub start_fen: Option<String>,
+    /// Tags of the database, creating its metadata.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Number of games the database is expected to hold.
+    #[specta(optional)]
+    pub expected_games: Option<u32>,
+    #[serde(default)]
+    pub extras: DatabaseExtras,
+    /// Move an existing database at the path to the trash instead of failing.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+fn page_size(expected_games: Option<u32>) -> u32 {
+    match expected_games.unwrap_or(0) {
+        n if n >= HUGE_DATABASE_GAMES => 16384,
+        n if n >= LARGE_DATABASE_GAMES => 8192,
+        _ => 4096,
+    }
+}
+
+fn check_new_path(path: &Path) -> Result<()> {
+    if path.extension() != Some(DB_EXTENSION.as_ref()) {
+        return Err(Error::InvalidDatabasePath(format!(
+            "{} is not a .{} file",
+            path.display(),
+            DB_EXTENSION
+        )));
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err(Error::InvalidDatabasePath(format!(
+            "the folder of {} doesn't exist",
+            path.display()
+        )));
+    }
+    if path.exists() {
+        return Err(Error::DatabaseExists(path.display().to_string()));
+    }
+    Ok(())
+}
+
+/// Create the database at `path`, which must not exist yet.
+pub(crate) fn create_database_file(path: &Path, options: &NewDatabaseOptions) -> Result<()> {
+    check_new_path(path)?;
+    if let Some(fen) = &options.start_fen {
+        Fen::from_ascii(fen.as_bytes())?.into_position::<Chess>(CastlingMode::Chess960)?;
+    }
+    let title = match &options.title {
+        Some(title) => title.clone(),
+        None => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+
+    let created = (|| -> Result<()> {
+        let db = &mut SqliteConnection::establish(&path.to_string_lossy())?;
+        // Only takes effect before the first table is created.
+        db.batch_execute(&format!(
+            "PRAGMA page_size = {};",
+            page_size(options.expected_games)
+        ))?;
+        db.transaction::<_, Error, _>(|db| {
+            core::init_db(db, &title, &options.description)?;
+            if options.extras.indexes {
+                db.batch_execute(INDEXES_SQL)?;
+            }
+            if options.extras.player_metadata {
+                player_metadata::ensure_player_metadata_table(db)?;
+            }
+            if options.extras.analysis_attributions {
+                attribution::ensure_attributions_table(db)?;
+            }
+            set_start_fen(db, options.start_fen.as_deref())?;
+            update_info_counts(db)?;
+            Ok(())
+        })?;
+        if options.extras.metadata || !options.tags.is_empty() {
+            metadata::write_metadata(
+                db,
+                metadata::DatabaseMetadataPatch {
+                    tags: options
+                        .tags
+                        .iter()
+                        .map(|(name, value)| (name.clone(), Some(value.clone())))
+                        .collect(),
+                    ..Default::default()
+                },
+            )?;
+        }
+        Ok(())
+    })();
+
+    // Don't leave a half-created database behind.
+    if created.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    created
+}
+
+/// Create an empty database, ready for games to be imported into it.
+#[tauri::command]
+#[specta::specta]
+pub async fn create_database(
+    path: PathBuf,
+    options: NewDatabaseOptions,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<()> {
+    let path_str = path.to_string_lossy().to_string();
+    let lock = write_lock(&state, &path_str);
+    let _guard = lock.lock().await;
+
+    if options.overwrite && path.exists() {
+        state.connection_pool.remove(&path_str);
+        trash::move_to_trash(&app, path.clone()).await?;
+    }
+    create_database_file(&path, &options)?;
+
+    // Lists the database in the sidebar right away.
+    state.db_watcher.touch(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        check_index_exists, get_start_fen, import_test_games, metadata::read_metadata,
+        schema::games,
+    };
+    use diesel::{dsl::sql, sql_types::Integer};
+
+    const PGN: &str = "[White \"Carlsen, Magnus\"]\n[Black \"Nepomniachtchi, Ian\"]\n\
+                       [Result \"1-0\"]\n[ECO \"C88\"]\n\n1. e4 e5 2. Nf3 Nc6 1-0\n\n\
+                       [White \"Ding, Liren\"]\n[Black \"Carlsen, Magnus\"]\n[Result \"1/2-1/2\"]\n\n\
+                       1. d4 Nf6 1/2-1/2\n";
+
+    fn pragma(db: &mut SqliteConnection, name: &str) -> i32 {
+        diesel::select(sql::<Integer>(&format!("(SELECT * FROM pragma_{})", name)))
+            .get_result(db)
+            .unwrap()
+    }
+
+    #[test]
+    fn created_databases_take_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("themed.db3");
+        let options = NewDatabaseOptions {
+            description: "World championship games".to_string(),
+            tags: BTreeMap::from([("event".to_string(), "world championship".to_string())]),
+            expected_games: Some(2_000_000),
+            extras: DatabaseExtras {
+                player_metadata: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        create_database_file(&path, &options).unwrap();
+
+        let db = &mut SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        assert_eq!(pragma(db, "page_size"), 16384);
+        assert!(check_index_exists(db).unwrap());
+        assert_eq!(get_start_fen(db).unwrap(), None);
+        assert_eq!(
+            read_metadata(db).unwrap().tags["event"],
+            "world championship"
+        );
+        db.batch_execute("SELECT * FROM PlayerMetadata").unwrap();
+
+        // Games import the way they do into a database created by an import.
+        import_test_games(db, PGN);
+        update_info_counts(db).unwrap();
+        let ecos: Vec<Option<String>> = games::table.select(games::eco).load(db).unwrap();
+        assert_eq!(ecos.len(), 2);
+        assert_eq!(ecos[0].as_deref(), Some("C88"));
+    }
+
+    #[test]
+    fn existing_or_misnamed_paths_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("games.db3");
+        create_database_file(&path, &NewDatabaseOptions::default()).unwrap();
+        assert!(matches!(
+            create_database_file(&path, &NewDatabaseOptions::default()),
+            Err(Error::DatabaseExists(_))
+        ));
+        assert!(matches!(
+            create_database_file(
+                &dir.path().join("games.pgn"),
+                &NewDatabaseOptions::default()
+            ),
+            Err(Error::InvalidDatabasePath(_))
+        ));
+        assert!(matches!(
+            create_database_file(
+                &dir.path().join("missing/games.db3"),
+                &NewDatabaseOptions::default()
+            ),
+            Err(Error::InvalidDatabasePath(_))
+        ));
+
+        // An invalid start position leaves nothing behind.
+        let themed = dir.path().join("themed.db3");
+        let options = NewDatabaseOptions {
+            start_fen: Some("not a fen".to_string()),
+            ..Default::default()
+        };
+        assert!(create_database_file(&themed, &options).is_err());
+        assert!(!themed.exists());
+        let options = NewDatabaseOptions {
+            start_fen: Some("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1".to_string()),
+            ..Default::default()
+        };
+        create_database_file(&themed, &options).unwrap();
+        let db = &mut SqliteConnection::establish(themed.to_str().unwrap()).unwrap();
+        assert_eq!(
+            get_start_fen(db).unwrap().as_deref(),
+            Some("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1")
+        );
+
+        // Without extras asked for, the optional tables are left for first use.
+        let db = &mut SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        assert_eq!(pragma(db, "page_size"), 4096);
+        assert!(db.batch_execute("SELECT * FROM PlayerMetadata").is_err());
+        let title: String = crate::db::schema::info::table
+            .filter(crate::db::schema::info::name.eq("Title"))
+            .select(crate::db::schema::info::value)
+            .first::<Option<String>>(db)
+            .unwrap()
+            .unwrap();
+        assert_eq!(title, "games");
+    }
+}
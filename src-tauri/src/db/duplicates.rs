@@ -0,0 +1,478 @@
+//! Duplicate games
+//!
+//! Exact duplicates share their headers and move data. Prefix duplicates are the same game
+//! imported at different stages, as with TWIC publishing an unfinished game one week and the
+//! finished game the next: same players, event, round, date and start position, with the
+//! main line of one a strict prefix of the other's. Main lines are compared without their
+//! comments, NAGs and variations, so an annotated partial game still matches the clean
+//! finished one.
+//!
+//! Of exact duplicates, the copy kept is the one whose source comes first in a precedence
+//! order (`DEFAULT_SOURCE_PRECEDENCE` unless given), then the oldest one.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Binary, Integer},
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    db::{
+        core,
+        encoding::main_line_bytes,
+        get_db_or_create, maintenance,
+        pgn::TempGame,
+        provenance::{self, ProvenanceKind, DEFAULT_SOURCE_PRECEDENCE},
+        schema::{events, games, players, sites},
+        watcher::record_game_count,
+        ConnectionOptions,
+    },
+    error::{Error, Result},
+    AppState,
+};
+
+const GAMES_DELETE_DUPLICATES: &str =
+    include_str!("../../../database/queries/games/delete_duplicates.sql");
+const GAMES_FIND_DUPLICATES: &str =
+    include_str!("../../../database/queries/games/find_duplicates.sql");
+const GAMES_PREFIX_CANDIDATES: &str =
+    include_str!("../../../database/queries/games/prefix_candidates.sql");
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicateKind {
+    /// Same headers and moves.
+    Exact,
+    /// Same game, stopped earlier.
+    Prefix,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct DuplicateGame {
+    pub id: i32,
+    /// Game kept in its place: the preferred exact copy, or the longest game extending it.
+    pub original: i32,
+    pub kind: DuplicateKind,
+}
+
+/// What to do with prefix duplicates. Exact duplicates are always deleted.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicatePolicy {
+    /// Delete the shorter game.
+    KeepLonger,
+    /// Keep both games.
+    #[default]
+    KeepBoth,
+    /// Keep both games and report them, so the user can decide.
+    Ask,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq, Type)]
+pub struct DuplicateReport {
+    pub deleted: u32,
+    /// Prefix duplicates left for the user to resolve, with `DuplicatePolicy::Ask`.
+    pub unresolved: Vec<DuplicateGame>,
+}
+
+#[derive(QueryableByName)]
+struct ExactDuplicate {
+    #[diesel(sql_type = Integer, column_name = "ID")]
+    id: i32,
+    #[diesel(sql_type = Integer, column_name = "Original")]
+    original: i32,
+}
+
+#[derive(QueryableByName)]
+struct PrefixCandidate {
+    #[diesel(sql_type = Integer, column_name = "ID")]
+    id: i32,
+    #[diesel(sql_type = BigInt, column_name = "GroupID")]
+    group: i64,
+    #[diesel(sql_type = Binary, column_name = "Moves")]
+    moves: Vec<u8>,
+}
+
+/// Fill in the order of sources in a duplicates query.
+fn with_precedence(query: &str, precedence: &[ProvenanceKind]) -> String {
+    query.replace("{precedence}", &provenance::precedence_order(precedence))
+}
+
+fn exact_duplicates(
+    db: &mut SqliteConnection,
+    precedence: &[ProvenanceKind],
+) -> Result<Vec<DuplicateGame>> {
+    provenance::ensure_sources_table(db)?;
+    let rows: Vec<ExactDuplicate> =
+        sql_query(with_precedence(GAMES_FIND_DUPLICATES, precedence)).load(db)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| DuplicateGame {
+            id: row.id,
+            original: row.original,
+            kind: DuplicateKind::Exact,
+        })
+        .collect())
+}
+
+/// Games whose main line is a strict prefix of another game of their group, along with the
+/// longest such game.
+fn prefix_duplicates(db: &mut SqliteConnection) -> Result<Vec<DuplicateGame>> {
+    let candidates: Vec<PrefixCandidate> = sql_query(GAMES_PREFIX_CANDIDATES).load(db)?;
+
+    let mut groups: BTreeMap<i64, Vec<(i32, Vec<u8>)>> = BTreeMap::new();
+    for candidate in candidates {
+        match main_line_bytes(&candidate.moves) {
+            Ok(main_line) => groups
+                .entry(candidate.group)
+                .or_default()
+                .push((candidate.id, main_line)),
+            Err(e) => log::warn!("Skipping game {} in duplicate search: {}", candidate.id, e),
+        }
+    }
+
+    let mut duplicates = Vec::new();
+    for group in groups.values() {
+        for (id, main_line) in group {
+            // Games are in ID order, so ties go to the oldest game.
+            let longest = group
+                .iter()
+                .filter(|(_, other)| other.len() > main_line.len() && other.starts_with(main_line))
+                .fold(None::<&(i32, Vec<u8>)>, |longest, game| match longest {
+                    Some(longest) if longest.1.len() >= game.1.len() => Some(longest),
+                    _ => Some(game),
+                });
+            if let Some((original, _)) = longest {
+                duplicates.push(DuplicateGame {
+                    id: *id,
+                    original: *original,
+                    kind: DuplicateKind::Prefix,
+                });
+            }
+        }
+    }
+    duplicates.sort_by_key(|duplicate| duplicate.id);
+    Ok(duplicates)
+}
+
+/// Exact and prefix duplicates, by ID. Games that are both are reported as exact duplicates.
+pub(crate) fn find_duplicates(
+    db: &mut SqliteConnection,
+    precedence: &[ProvenanceKind],
+) -> Result<Vec<DuplicateGame>> {
+    let mut duplicates = exact_duplicates(db, precedence)?;
+    let exact: HashSet<i32> = duplicates.iter().map(|duplicate| duplicate.id).collect();
+    duplicates.extend(
+        prefix_duplicates(db)?
+            .into_iter()
+            .filter(|duplicate| !exact.contains(&duplicate.id)),
+    );
+    duplicates.sort_by_key(|duplicate| duplicate.id);
+    Ok(duplicates)
+}
+
+/// ID of a game of `db` that `game` is an exact duplicate of, matched on the same headers and
+/// moves as `delete_duplicates`, so that imports can leave it out instead of deleting it after.
+pub(crate) fn exact_duplicate_of(
+    db: &mut SqliteConnection,
+    game: &TempGame,
+) -> Result<Option<i32>> {
+    // Games without a name are stored with ID 0, and a name missing from the database can't
+    // belong to a game of it.
+    let player = |db: &mut SqliteConnection, name: &Option<String>| match name {
+        Some(name) => players::table
+            .filter(players::name.eq(name))
+            .select(players::id)
+            .first::<i32>(db)
+            .optional(),
+        None => Ok(Some(0)),
+    };
+    let (Some(white_id), Some(black_id)) =
+        (player(db, &game.white_name)?, player(db, &game.black_name)?)
+    else {
+        return Ok(None);
+    };
+    let event_id = match &game.event_name {
+        Some(name) => events::table
+            .filter(events::name.eq(name))
+            .select(events::id)
+            .first::<i32>(db)
+            .optional()?,
+        None => Some(0),
+    };
+    let site_id = match &game.site_name {
+        Some(name) => sites::table
+            .filter(sites::name.eq(name))
+            .select(sites::id)
+            .first::<i32>(db)
+            .optional()?,
+        None => Some(0),
+    };
+    let (Some(event_id), Some(site_id)) = (event_id, site_id) else {
+        return Ok(None);
+    };
+
+    let candidates: Vec<(i32, Option<String>, Option<String>, Option<String>)> = games::table
+        .filter(games::white_id.eq(white_id))
+        .filter(games::black_id.eq(black_id))
+        .filter(games::event_id.eq(event_id))
+        .filter(games::site_id.eq(site_id))
+        .filter(games::moves.eq(&game.moves))
+        .select((games::id, games::date, games::time, games::round))
+        .order(games::id)
+        .load(db)?;
+    Ok(candidates
+        .into_iter()
+        .find(|(_, date, time, round)| {
+            *date == game.date && *time == game.time && *round == game.round
+        })
+        .map(|(id, ..)| id))
+}
+
+/// Delete exact duplicates, then handle prefix duplicates according to `policy`.
+pub(crate) fn delete_duplicates(
+    db: &mut SqliteConnection,
+    policy: DuplicatePolicy,
+    precedence: &[ProvenanceKind],
+) -> Result<DuplicateReport> {
+    provenance::ensure_sources_table(db)?;
+    db.transaction(|db| {
+        let total: i64 = games::table.count().get_result(db)?;
+        db.batch_execute(&with_precedence(GAMES_DELETE_DUPLICATES, precedence))?;
+
+        let mut unresolved = Vec::new();
+        match policy {
+            DuplicatePolicy::KeepLonger => {
+                for duplicate in prefix_duplicates(db)? {
+                    core::remove_game(db, duplicate.id)?;
+                }
+            }
+            DuplicatePolicy::KeepBoth => {}
+            DuplicatePolicy::Ask => unresolved = prefix_duplicates(db)?,
+        }
+
+        let remaining: i64 = games::table.count().get_result(db)?;
+        let deleted = (total - remaining) as usize;
+        maintenance::flag_if_needs_optimize(db, deleted, total)?;
+        Ok::<_, Error>(DuplicateReport {
+            deleted: deleted as u32,
+            unresolved,
+        })
+    })
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn find_duplicate_games(
+    file: PathBuf,
+    precedence: Option<Vec<ProvenanceKind>>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<DuplicateGame>> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    find_duplicates(
+        db,
+        precedence.as_deref().unwrap_or(&DEFAULT_SOURCE_PRECEDENCE),
+    )
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn delete_duplicated_games(
+    file: PathBuf,
+    policy: DuplicatePolicy,
+    precedence: Option<Vec<ProvenanceKind>>,
+    state: tauri::State<'_, AppState>,
+) -> Result<DuplicateReport> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let report = delete_duplicates(
+        db,
+        policy,
+        precedence.as_deref().unwrap_or(&DEFAULT_SOURCE_PRECEDENCE),
+    )?;
+    record_game_count(db)?;
+    state.game_cache.invalidate_file(&file.to_string_lossy());
+    state.db_watcher.touch(&file);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        pgn::Importer,
+        provenance::{GameProvenance, OnlineAccount},
+        test_db,
+    };
+    use pgn_reader::BufferedReader;
+
+    fn game(round: &str, movetext: &str) -> String {
+        format!(
+            "[Event \"Open\"]\n[Site \"Online\"]\n[Date \"2024.05.01\"]\n[Round \"{round}\"]\n\
+             [White \"Carlsen\"]\n[Black \"Nakamura\"]\n[Result \"*\"]\n\n{movetext}\n\n"
+        )
+    }
+
+    fn db_with(games: &[String]) -> SqliteConnection {
+        test_db(&games.concat())
+    }
+
+    fn prefix(id: i32, original: i32) -> DuplicateGame {
+        DuplicateGame {
+            id,
+            original,
+            kind: DuplicateKind::Prefix,
+        }
+    }
+
+    fn remaining_ids(db: &mut SqliteConnection) -> Vec<i32> {
+        games::table
+            .select(games::id)
+            .order(games::id)
+            .load(db)
+            .unwrap()
+    }
+
+    #[test]
+    fn annotated_partial_games_are_prefixes_of_finished_ones() {
+        let mut db = db_with(&[
+            game(
+                "1",
+                "1. e4 $1 { Best by test } e5 ( 1... c5 2. Nf3 ) 2. Nf3 *",
+            ),
+            game("1", "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 *"),
+            game("1", "1. e4 e5 2. Nf3 Nc6 *"),
+            // Other round, and a different game of the same round.
+            game("2", "1. e4 *"),
+            game("1", "1. d4 d5 2. c4 *"),
+        ]);
+
+        assert_eq!(
+            find_duplicates(&mut db, &DEFAULT_SOURCE_PRECEDENCE).unwrap(),
+            vec![prefix(1, 2), prefix(3, 2)]
+        );
+    }
+
+    #[test]
+    fn exact_copies_are_not_prefixes() {
+        let mut db = db_with(&[
+            game("1", "1. e4 e5 2. Nf3 *"),
+            game("1", "1. e4 e5 2. Nf3 *"),
+            game("1", "1. e4 e5 *"),
+        ]);
+
+        assert_eq!(
+            find_duplicates(&mut db, &DEFAULT_SOURCE_PRECEDENCE).unwrap(),
+            vec![
+                DuplicateGame {
+                    id: 2,
+                    original: 1,
+                    kind: DuplicateKind::Exact,
+                },
+                prefix(3, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn policies_decide_what_happens_to_prefixes() {
+        let games = [
+            game("1", "1. e4 { Unfinished } e5 *"),
+            game("1", "1. e4 e5 2. Nf3 Nc6 *"),
+            game("1", "1. e4 e5 2. Nf3 Nc6 *"),
+        ];
+
+        let mut db = db_with(&games);
+        let report = delete_duplicates(
+            &mut db,
+            DuplicatePolicy::KeepBoth,
+            &DEFAULT_SOURCE_PRECEDENCE,
+        )
+        .unwrap();
+        assert_eq!(
+            report,
+            DuplicateReport {
+                deleted: 1,
+                unresolved: vec![],
+            }
+        );
+        assert_eq!(remaining_ids(&mut db), vec![1, 2]);
+
+        let mut db = db_with(&games);
+        let report =
+            delete_duplicates(&mut db, DuplicatePolicy::Ask, &DEFAULT_SOURCE_PRECEDENCE).unwrap();
+        assert_eq!(report.deleted, 1);
+        assert_eq!(report.unresolved, vec![prefix(1, 2)]);
+        assert_eq!(remaining_ids(&mut db), vec![1, 2]);
+
+        let mut db = db_with(&games);
+        let report = delete_duplicates(
+            &mut db,
+            DuplicatePolicy::KeepLonger,
+            &DEFAULT_SOURCE_PRECEDENCE,
+        )
+        .unwrap();
+        assert_eq!(report.deleted, 2);
+        assert_eq!(remaining_ids(&mut db), vec![2]);
+    }
+
+    #[test]
+    fn imports_find_exact_duplicates_before_inserting() {
+        let mut db = db_with(&[game("1", "1. e4 e5 2. Nf3 *")]);
+        let pgn = [
+            game("1", "1. e4 e5 2. Nf3 *"),
+            game("2", "1. e4 e5 2. Nf3 *"),
+            game("1", "1. e4 e5 *"),
+            game("1", "1. e4 e5 2. Nf3 *").replace("Carlsen", "Caruana"),
+        ]
+        .concat();
+        let mut importer = Importer::new(None);
+        let found: Vec<Option<i32>> = BufferedReader::new_cursor(&pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .map(|game| exact_duplicate_of(&mut db, &game).unwrap())
+            .collect();
+        assert_eq!(found, [Some(1), None, None, None]);
+    }
+
+    #[test]
+    fn exact_duplicates_keep_the_preferred_source() {
+        let games = [
+            game("1", "1. e4 e5 2. Nf3 *"),
+            game("1", "1. e4 e5 2. Nf3 *"),
+            game("1", "1. e4 e5 2. Nf3 *"),
+        ];
+        let file = GameProvenance::file(std::path::Path::new("twic.pgn"));
+        let online = GameProvenance::online(&OnlineAccount {
+            platform: "lichess".to_string(),
+            username: "someone".to_string(),
+        });
+
+        let mut db = db_with(&games);
+        provenance::record_source(&mut db, 1, &GameProvenance::unknown()).unwrap();
+        provenance::record_source(&mut db, 2, &file).unwrap();
+        provenance::record_source(&mut db, 3, &online).unwrap();
+        let duplicates = find_duplicates(&mut db, &DEFAULT_SOURCE_PRECEDENCE).unwrap();
+        assert!(duplicates.iter().all(|duplicate| duplicate.original == 3));
+        delete_duplicates(
+            &mut db,
+            DuplicatePolicy::KeepBoth,
+            &DEFAULT_SOURCE_PRECEDENCE,
+        )
+        .unwrap();
+        assert_eq!(remaining_ids(&mut db), vec![3]);
+
+        // Files first, and unlisted sources after everything else.
+        let mut db = db_with(&games);
+        provenance::record_source(&mut db, 1, &online).unwrap();
+        provenance::record_source(&mut db, 3, &file).unwrap();
+        delete_duplicates(&mut db, DuplicatePolicy::KeepBoth, &[ProvenanceKind::File]).unwrap();
+        assert_eq!(remaining_ids(&mut db), vec![3]);
+    }
+}
@@ -1,6 +1,201 @@
+//! Binary move encoding shared by the game tree and the position search.
+//!
+//! Moves are stored as their index in the legal move list, interleaved with markers for
+//! NAGs (`251 nag`), comments (`252`, a big endian `u64` length, then UTF-8 text) and
+//! variations (`254 ... 253`). Blobs come straight from database files, so the reader
+//! below treats them as untrusted: every length and nesting level is bounded and any
+//! inconsistency is reported as a `DecodeError` with the offset of the offending byte.
+//!
+//! Blobs don't carry their encoding version; it is recorded per game in `GameEncodings`,
+//! and games without a row there are version 0, written before versions were tracked.
+
 use crate::db::pgn::GameTree;
 use shakmaty::{Chess, Move, Position};
 
+pub const START_VARIATION: u8 = 254;
+pub const END_VARIATION: u8 = 253;
+pub const COMMENT: u8 = 252;
+pub const NAG: u8 = 251;
+
+/// Version of the blobs written by `GameTree::encode`.
+pub const ENCODING_VERSION: i32 = 1;
+
+/// Largest move blob that will be decoded.
+pub const MAX_BLOB_LEN: usize = 16 * 1024 * 1024;
+/// Largest comment that will be decoded.
+pub const MAX_COMMENT_LEN: usize = 1024 * 1024;
+/// Deepest nesting of variations that will be decoded.
+pub const MAX_VARIATION_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("Move data of {length} bytes is too large to decode")]
+    TooLarge { length: usize },
+    #[error("Move data ends unexpectedly at byte {offset}")]
+    Truncated { offset: usize },
+    #[error("Comment of {length} bytes at byte {offset} is too long to decode")]
+    CommentTooLong { offset: usize, length: u64 },
+    #[error("Comment at byte {offset} is not valid UTF-8")]
+    InvalidComment { offset: usize },
+    #[error("Variations nested too deeply at byte {offset}")]
+    TooDeep { offset: usize },
+    #[error("Variation end without a matching start at byte {offset}")]
+    UnbalancedVariation { offset: usize },
+    #[error("Illegal move {byte} at byte {offset}")]
+    IllegalMove { offset: usize, byte: u8 },
+    #[error("Unsupported move encoding version {version}")]
+    UnsupportedVersion { version: i32 },
+}
+
+impl DecodeError {
+    /// Offset of the byte where decoding failed.
+    pub fn offset(&self) -> usize {
+        match self {
+            DecodeError::TooLarge { .. } | DecodeError::UnsupportedVersion { .. } => 0,
+            DecodeError::Truncated { offset }
+            | DecodeError::CommentTooLong { offset, .. }
+            | DecodeError::InvalidComment { offset }
+            | DecodeError::TooDeep { offset }
+            | DecodeError::UnbalancedVariation { offset }
+            | DecodeError::IllegalMove { offset, .. } => *offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// Index of the move in the legal move list of the current position.
+    Move(u8),
+    Nag(u8),
+    Comment(&'a [u8]),
+    StartVariation,
+    EndVariation,
+}
+
+/// Splits a move blob into tokens, enforcing the decoding limits.
+///
+/// Every token consumes at least one byte, so reading a blob always terminates.
+pub struct BlobReader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    depth: usize,
+}
+
+impl<'a> BlobReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        if bytes.len() > MAX_BLOB_LEN {
+            return Err(DecodeError::TooLarge {
+                length: bytes.len(),
+            });
+        }
+        Ok(Self {
+            bytes,
+            index: 0,
+            depth: 0,
+        })
+    }
+
+    /// Number of variations the reader is currently inside of.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Read the next token along with its offset, or `None` at the end of the blob.
+    pub fn next_token(&mut self) -> Result<Option<(usize, Token<'a>)>, DecodeError> {
+        let offset = self.index;
+        let Some(&byte) = self.bytes.get(offset) else {
+            if self.depth > 0 {
+                return Err(DecodeError::Truncated { offset });
+            }
+            return Ok(None);
+        };
+
+        let token = match byte {
+            NAG => {
+                let nag = *self
+                    .bytes
+                    .get(offset + 1)
+                    .ok_or(DecodeError::Truncated { offset })?;
+                self.index += 2;
+                Token::Nag(nag)
+            }
+            COMMENT => {
+                let length = self
+                    .bytes
+                    .get(offset + 1..offset + 9)
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u64::from_be_bytes)
+                    .ok_or(DecodeError::Truncated { offset })?;
+                if length > MAX_COMMENT_LEN as u64 {
+                    return Err(DecodeError::CommentTooLong { offset, length });
+                }
+                let end = offset + 9 + length as usize;
+                let text = self
+                    .bytes
+                    .get(offset + 9..end)
+                    .ok_or(DecodeError::Truncated { offset })?;
+                self.index = end;
+                Token::Comment(text)
+            }
+            START_VARIATION => {
+                if self.depth >= MAX_VARIATION_DEPTH {
+                    return Err(DecodeError::TooDeep { offset });
+                }
+                self.depth += 1;
+                self.index += 1;
+                Token::StartVariation
+            }
+            END_VARIATION => {
+                if self.depth == 0 {
+                    return Err(DecodeError::UnbalancedVariation { offset });
+                }
+                self.depth -= 1;
+                self.index += 1;
+                Token::EndVariation
+            }
+            byte => {
+                self.index += 1;
+                Token::Move(byte)
+            }
+        };
+        Ok(Some((offset, token)))
+    }
+}
+
+/// The move bytes of the main line, without comments, NAGs or variations.
+///
+/// Moves are indexes in the legal move list, so two games from the same start position
+/// share a main line exactly when these bytes are equal.
+pub fn main_line_bytes(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut reader = BlobReader::new(bytes)?;
+    let mut moves = Vec::new();
+    while let Some((_, token)) = reader.next_token()? {
+        match token {
+            Token::Move(index) if reader.depth() == 0 => moves.push(index),
+            _ => {}
+        }
+    }
+    Ok(moves)
+}
+
+/// Decode a blob written with encoding `version`.
+///
+/// Version 0 blobs predate version tracking but share the format of version 1, whose
+/// blobs are exactly what `GameTree::encode` writes.
+pub fn decode_versioned(
+    bytes: &[u8],
+    version: i32,
+    position: Option<Chess>,
+) -> Result<GameTree, DecodeError> {
+    match version {
+        0 | ENCODING_VERSION => match GameTree::from_bytes_partial(bytes, position) {
+            (tree, None) => Ok(tree),
+            (_, Some(err)) => Err(err),
+        },
+        version => Err(DecodeError::UnsupportedVersion { version }),
+    }
+}
+
 /// Extract only the main line moves from encoded game data, skipping annotations
 /// This function properly handles the extended format with comments and variations
 pub fn extract_main_line_moves(
@@ -22,3 +217,206 @@ pub fn extract_main_line_moves(
 
     Ok(moves)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::pgn::Importer;
+    use pgn_reader::BufferedReader;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const SAMPLE: &str =
+        "1. e4 e5 2. Nf3 $1 { Developing } ( 2. Bc4 Nf6 ( 2... Bc5 3. Qh5 ) 3. d3 ) \
+        2... Nc6 3. Bb5 { The Ruy Lopez } a6 $2 ( 3... Nf6 4. O-O ) 4. Ba4 *";
+    const MARKERS: [u8; 4] = [START_VARIATION, END_VARIATION, COMMENT, NAG];
+
+    fn encoded(pgn: &str) -> Vec<u8> {
+        let mut importer = Importer::new(None);
+        let game = BufferedReader::new_cursor(pgn)
+            .read_game(&mut importer)
+            .unwrap()
+            .flatten()
+            .unwrap();
+        let mut bytes = Vec::new();
+        game.tree.encode(&mut bytes, None);
+        bytes
+    }
+
+    /// Decode a blob every way it can be decoded, checking the reader's guarantees.
+    fn check(bytes: &[u8]) {
+        let mut reader = BlobReader::new(bytes).unwrap();
+        let mut tokens = 0;
+        loop {
+            match reader.next_token() {
+                Ok(Some((offset, token))) => {
+                    tokens += 1;
+                    // Every token consumes a byte, so a blob can't be read forever.
+                    assert!(tokens <= bytes.len());
+                    assert!(offset < bytes.len());
+                    assert!(reader.depth() <= MAX_VARIATION_DEPTH);
+                    if let Token::Comment(text) = token {
+                        assert!(text.len() <= MAX_COMMENT_LEN);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    assert!(err.offset() <= bytes.len());
+                    break;
+                }
+            }
+        }
+
+        let (_, err) = GameTree::from_bytes_partial(bytes, None);
+        assert_eq!(GameTree::from_bytes(bytes, None).is_ok(), err.is_none());
+        if let Some(err) = err {
+            assert!(err.offset() <= bytes.len());
+        }
+        let _ = extract_main_line_moves(bytes, None);
+    }
+
+    #[test]
+    fn random_blobs_never_panic() {
+        let mut rng = StdRng::seed_from_u64(932);
+        for _ in 0..2000 {
+            let len = rng.gen_range(0..256);
+            let bytes: Vec<u8> = (0..len)
+                .map(|_| {
+                    if rng.gen_bool(0.25) {
+                        MARKERS[rng.gen_range(0..MARKERS.len())]
+                    } else {
+                        rng.gen_range(0..40)
+                    }
+                })
+                .collect();
+            check(&bytes);
+        }
+    }
+
+    #[test]
+    fn mutated_blobs_never_panic() {
+        let sample = encoded(SAMPLE);
+        check(&sample);
+        assert!(GameTree::from_bytes(&sample, None).is_ok());
+
+        let mut rng = StdRng::seed_from_u64(1932);
+        for _ in 0..2000 {
+            let mut bytes = sample.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let at = rng.gen_range(0..bytes.len());
+                match rng.gen_range(0..5) {
+                    0 => bytes[at] = rng.gen(),
+                    1 => bytes.truncate(at),
+                    2 => bytes.insert(at, MARKERS[rng.gen_range(0..MARKERS.len())]),
+                    3 => {
+                        let end = rng.gen_range(at..bytes.len());
+                        let tail = bytes.split_off(at);
+                        bytes.extend_from_slice(&tail[..end - at]);
+                        bytes.extend(tail);
+                    }
+                    _ => {
+                        let length = rng.gen::<u64>() >> rng.gen_range(0..64);
+                        let tail = bytes.split_off(at);
+                        bytes.push(COMMENT);
+                        bytes.extend(length.to_be_bytes());
+                        bytes.extend(tail);
+                    }
+                }
+                if bytes.is_empty() {
+                    break;
+                }
+            }
+            check(&bytes);
+        }
+    }
+
+    #[test]
+    fn comment_lengths_are_bounded() {
+        let mut bytes = vec![12, COMMENT];
+        bytes.extend(u64::MAX.to_be_bytes());
+        bytes.extend(b"text");
+        assert_eq!(
+            GameTree::from_bytes_partial(&bytes, None).1,
+            Some(DecodeError::CommentTooLong {
+                offset: 1,
+                length: u64::MAX
+            })
+        );
+
+        let mut bytes = vec![12, COMMENT];
+        bytes.extend(100u64.to_be_bytes());
+        bytes.extend(b"text");
+        assert_eq!(
+            GameTree::from_bytes_partial(&bytes, None).1,
+            Some(DecodeError::Truncated { offset: 1 })
+        );
+
+        assert_eq!(
+            GameTree::from_bytes_partial(&[12, COMMENT, 0, 0], None).1,
+            Some(DecodeError::Truncated { offset: 1 })
+        );
+        assert_eq!(
+            GameTree::from_bytes_partial(&[12, NAG], None).1,
+            Some(DecodeError::Truncated { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn variation_nesting_is_bounded() {
+        let mut bytes = vec![12];
+        bytes.extend([START_VARIATION; 1000]);
+        assert_eq!(
+            GameTree::from_bytes_partial(&bytes, None).1,
+            Some(DecodeError::TooDeep {
+                offset: 1 + MAX_VARIATION_DEPTH
+            })
+        );
+
+        assert_eq!(
+            GameTree::from_bytes_partial(&[12, START_VARIATION, 12], None).1,
+            Some(DecodeError::Truncated { offset: 3 })
+        );
+        assert_eq!(
+            GameTree::from_bytes_partial(&[12, END_VARIATION], None).1,
+            Some(DecodeError::UnbalancedVariation { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn oversized_blobs_are_rejected() {
+        let bytes = vec![0; MAX_BLOB_LEN + 1];
+        assert_eq!(
+            BlobReader::new(&bytes).err(),
+            Some(DecodeError::TooLarge {
+                length: MAX_BLOB_LEN + 1
+            })
+        );
+        assert!(GameTree::from_bytes(&bytes, None).is_err());
+    }
+
+    #[test]
+    fn partial_decoding_keeps_the_moves_before_the_error() {
+        let mut bytes = encoded("1. e4 e5 2. Nf3 { Developing } *");
+        let end = bytes.len();
+        bytes.push(200);
+
+        let (tree, err) = GameTree::from_bytes_partial(&bytes, None);
+        assert_eq!(
+            err,
+            Some(DecodeError::IllegalMove {
+                offset: end,
+                byte: 200
+            })
+        );
+        assert_eq!(tree, GameTree::from_bytes(&bytes[..end], None).unwrap());
+        assert_eq!(tree.nodes().len(), 4);
+    }
+
+    #[test]
+    fn main_lines_skip_annotations() {
+        let annotated = main_line_bytes(&encoded(SAMPLE)).unwrap();
+        let clean = main_line_bytes(&encoded("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 *")).unwrap();
+        assert_eq!(annotated, clean);
+        assert_eq!(clean.len(), 7);
+        assert!(main_line_bytes(&[12, START_VARIATION, 12]).is_err());
+    }
+}
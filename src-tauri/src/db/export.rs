@@ -0,0 +1,797 @@
+//! Writing databases as PGN.
+//!
+//! Exports follow the database order unless they are canonical. Canonical exports are
+//! reproducible, so that databases shared through version control diff cleanly: exporting an
+//! unchanged database gives the same bytes, and changing a game only changes its own lines.
+//! Games are sorted by date, event, round, White and Black as written, so after normalizing
+//! them; ties are broken by the encoded moves, then by game ID, which only orders games written
+//! identically anyway. Header values are normalized, and a closing `%` line, the PGN escape for lines that belong to no game,
+//! records the number of games and a hash of everything written before it. The same hash
+//! identifies the games of a database without exporting them.
+//!
+//! A single game can also be exported with the results of its analysis, which the frontend
+//! holds rather than the database.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use diesel::{connection::DefaultLoadingMode, prelude::*};
+use pgn_reader::Nag;
+use serde::Deserialize;
+use shakmaty::{fen::Fen, CastlingMode, Chess, FromSetup};
+use specta::Type;
+
+use crate::chess::MoveClassification;
+use crate::error::Result;
+use crate::notation::{write_localized, SanStyle};
+use crate::AppState;
+
+use super::anonymize::{AnonymizeOptions, Anonymizer};
+use super::attribution::AnalysisAttribution;
+use super::core::StableHash;
+use super::models::{Event, Game, Player, Site};
+use super::pgn::GameTree;
+use super::schema::{events, games, players, sites};
+use super::{attribution, get_db_or_create, links, ConnectionOptions};
+
+/// Order of the games in a PGN export.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportSort {
+    /// Database order, by game ID.
+    #[default]
+    Id,
+    /// Reproducible order and formatting, followed by a manifest with the content hash.
+    Canonical,
+}
+
+struct PgnGame {
+    event: Option<String>,
+    site: Option<String>,
+    date: Option<String>,
+    round: Option<String>,
+    white: Option<String>,
+    black: Option<String>,
+    result: Option<String>,
+    time_control: Option<String>,
+    eco: Option<String>,
+    white_elo: Option<String>,
+    black_elo: Option<String>,
+    ply_count: Option<String>,
+    annotator: Option<String>,
+    fen: Option<String>,
+    moves: String,
+}
+
+impl PgnGame {
+    fn new(
+        game: Game,
+        white: Player,
+        black: Player,
+        event: Event,
+        site: Site,
+        annotator: Option<String>,
+        tree: &GameTree,
+    ) -> Self {
+        PgnGame {
+            event: event.name,
+            site: site.name,
+            date: game.date,
+            round: game.round,
+            white: white.name,
+            black: black.name,
+            result: game.result,
+            time_control: game.time_control,
+            eco: game.eco,
+            white_elo: game.white_elo.map(|e| e.to_string()),
+            black_elo: game.black_elo.map(|e| e.to_string()),
+            ply_count: game.ply_count.map(|e| e.to_string()),
+            annotator,
+            fen: game.fen,
+            moves: tree.to_string(),
+        }
+    }
+
+    /// Write header values the same way whatever way they were imported: trimmed, with quotes
+    /// escaped and dates as `YYYY.MM.DD`. Ratings and counts are stored as numbers, so they
+    /// never have leading zeros.
+    fn normalize(&mut self) {
+        for value in [
+            &mut self.event,
+            &mut self.site,
+            &mut self.date,
+            &mut self.round,
+            &mut self.white,
+            &mut self.black,
+            &mut self.time_control,
+            &mut self.eco,
+            &mut self.annotator,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *value = normalize_value(value);
+        }
+        self.date = Some(normalize_date_header(self.date.as_deref()));
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writeln!(
+            writer,
+            "[Event \"{}\"]",
+            self.event.as_deref().unwrap_or("")
+        )?;
+        writeln!(writer, "[Site \"{}\"]", self.site.as_deref().unwrap_or(""))?;
+        writeln!(writer, "[Date \"{}\"]", self.date.as_deref().unwrap_or(""))?;
+        writeln!(
+            writer,
+            "[Round \"{}\"]",
+            self.round.as_deref().unwrap_or("")
+        )?;
+        writeln!(
+            writer,
+            "[White \"{}\"]",
+            self.white.as_deref().unwrap_or("")
+        )?;
+        writeln!(
+            writer,
+            "[Black \"{}\"]",
+            self.black.as_deref().unwrap_or("")
+        )?;
+        writeln!(
+            writer,
+            "[Result \"{}\"]",
+            self.result.as_deref().unwrap_or("*")
+        )?;
+        if let Some(time_control) = self.time_control.as_deref() {
+            writeln!(writer, "[TimeControl \"{}\"]", time_control)?;
+        }
+        if let Some(eco) = self.eco.as_deref() {
+            writeln!(writer, "[ECO \"{}\"]", eco)?;
+        }
+        if let Some(white_elo) = self.white_elo.as_deref() {
+            writeln!(writer, "[WhiteElo \"{}\"]", white_elo)?;
+        }
+        if let Some(black_elo) = self.black_elo.as_deref() {
+            writeln!(writer, "[BlackElo \"{}\"]", black_elo)?;
+        }
+        if let Some(ply_count) = self.ply_count.as_deref() {
+            writeln!(writer, "[PlyCount \"{}\"]", ply_count)?;
+        }
+        if let Some(annotator) = self.annotator.as_deref() {
+            writeln!(writer, "[Annotator \"{}\"]", annotator)?;
+        }
+        if let Some(fen) = self.fen.as_deref() {
+            writeln!(writer, "[SetUp \"1\"]")?;
+            writeln!(writer, "[FEN \"{}\"]", fen)?;
+        }
+        writeln!(writer)?;
+        writer.write_all(self.moves.as_bytes())?;
+        if !self.moves.is_empty() {
+            write!(writer, " ")?;
+        }
+        match self.result.as_deref() {
+            Some("1-0") => writeln!(writer, "1-0"),
+            Some("0-1") => writeln!(writer, "0-1"),
+            Some("1/2-1/2") => writeln!(writer, "1/2-1/2"),
+            _ => writeln!(writer, "*"),
+        }?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+/// A header value trimmed and with quotes escaped.
+fn normalize_value(value: &str) -> String {
+    value.trim().replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The `Date` header of a game dated `date`, unknown dates as `????.??.??`.
+fn normalize_date_header(date: Option<&str>) -> String {
+    date.map_or("????.??.??".to_string(), normalize_date)
+}
+
+/// A game as loaded for export.
+type ExportRow = (Game, Player, Player, Event, Site);
+
+/// Position of a game in canonical exports: its date, event, round, White and Black as
+/// written, then its encoded moves and ID.
+fn canonical_key(
+    (game, white, black, event, _): &ExportRow,
+) -> (String, String, String, String, String, Vec<u8>, i32) {
+    let header = |value: &Option<String>| value.as_deref().map(normalize_value).unwrap_or_default();
+    (
+        normalize_date_header(game.date.as_deref()),
+        header(&event.name),
+        header(&game.round),
+        header(&white.name),
+        header(&black.name),
+        game.moves.clone(),
+        game.id,
+    )
+}
+
+/// Write a PGN date as `YYYY.MM.DD`, whatever separators and padding it was stored with,
+/// keeping `?` for unknown parts. Dates that can't be read are kept as they are.
+fn normalize_date(date: &str) -> String {
+    let date = date.trim();
+    let part = |value: &str, min: usize, width: usize| -> Option<String> {
+        if !value.is_empty() && value.chars().all(|c| c == '?') {
+            Some("?".repeat(width))
+        } else if (min..=width).contains(&value.len()) && value.chars().all(|c| c.is_ascii_digit())
+        {
+            Some(format!("{:0>width$}", value, width = width))
+        } else {
+            None
+        }
+    };
+    let parts: Vec<&str> = date.split(['.', '-', '/']).collect();
+    let [year, month, day] = parts[..] else {
+        return date.to_string();
+    };
+    match (part(year, 4, 4), part(month, 1, 2), part(day, 1, 2)) {
+        (Some(year), Some(month), Some(day)) => format!("{}.{}.{}", year, month, day),
+        _ => date.to_string(),
+    }
+}
+
+/// Moves of a stored game, with their comments and variations.
+fn game_tree(game: &Game) -> Result<GameTree> {
+    GameTree::from_bytes(
+        &game.moves,
+        game.fen
+            .as_ref()
+            .and_then(|fen| Fen::from_ascii(fen.as_bytes()).ok())
+            .and_then(|fen| Chess::from_setup(fen.into(), CastlingMode::Chess960).ok()),
+    )
+}
+
+/// Writer hashing everything that goes through it.
+struct HashingWriter<W> {
+    inner: W,
+    hash: StableHash,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hash.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write every game of a database as PGN, returning the hash of what was written.
+///
+/// Links between games are written as `[%link #<n> "label"]` commands, `<n>` being the
+/// position of the linked game in the output. Analysed games get the latest analysis as
+/// their `Annotator` header. With an anonymizer, players are written as their pseudonyms
+/// and without ratings.
+pub(crate) fn write_pgn(
+    db: &mut SqliteConnection,
+    writer: &mut impl Write,
+    sort: ExportSort,
+    mut anonymizer: Option<&mut Anonymizer>,
+) -> Result<String> {
+    let (white_players, black_players) = diesel::alias!(players as white, players as black);
+    let games_by_id = || {
+        games::table
+            .inner_join(white_players.on(games::white_id.eq(white_players.field(players::id))))
+            .inner_join(black_players.on(games::black_id.eq(black_players.field(players::id))))
+            .inner_join(events::table.on(games::event_id.eq(events::id)))
+            .inner_join(sites::table.on(games::site_id.eq(sites::id)))
+            .order(games::id.asc())
+    };
+
+    // Canonical exports are sorted on the values as written, which SQL can't sort on.
+    let mut canonical_rows = Vec::new();
+    let positions: HashMap<i32, usize> = match sort {
+        ExportSort::Id => games_by_id()
+            .select(games::id)
+            .load::<i32>(db)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (id, i + 1))
+            .collect(),
+        ExportSort::Canonical => {
+            canonical_rows = games_by_id().load::<ExportRow>(db)?;
+            canonical_rows.sort_by_cached_key(canonical_key);
+            canonical_rows
+                .iter()
+                .enumerate()
+                .map(|(i, (game, ..))| (game.id, i + 1))
+                .collect()
+        }
+    };
+    let mut links = links::all_links(db)?;
+    let mut annotators = attribution::all_annotators(db)?;
+
+    let mut writer = HashingWriter {
+        inner: writer,
+        hash: StableHash::default(),
+    };
+    let mut write_game = |(game, white, black, event, site): ExportRow| -> Result<()> {
+        let mut tree = game_tree(&game)?;
+        let link_commands: Vec<String> = links
+            .remove(&game.id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|link| {
+                let target = positions.get(&link.to_game?)?;
+                Some(links::link_command(*target, &link.label))
+            })
+            .collect();
+        if !link_commands.is_empty() {
+            tree.prepend_comment(&link_commands.join(" "));
+        }
+
+        let annotator = annotators.remove(&game.id);
+        let mut pgn = PgnGame::new(game, white, black, event, site, annotator, &tree);
+        if let Some(anonymizer) = anonymizer.as_deref_mut() {
+            for name in [&mut pgn.white, &mut pgn.black].into_iter().flatten() {
+                *name = anonymizer.name(name);
+            }
+            pgn.date = pgn.date.map(|date| anonymizer.date(&date));
+            pgn.white_elo = None;
+            pgn.black_elo = None;
+            pgn.moves = anonymizer.movetext(&pgn.moves);
+        }
+        if sort == ExportSort::Canonical {
+            pgn.normalize();
+        }
+
+        pgn.write(&mut writer)
+    };
+    match sort {
+        ExportSort::Id => {
+            for row in games_by_id()
+                .load_iter::<ExportRow, DefaultLoadingMode>(db)?
+                .flatten()
+            {
+                write_game(row)?;
+            }
+        }
+        ExportSort::Canonical => {
+            for row in canonical_rows {
+                write_game(row)?;
+            }
+        }
+    }
+
+    let hash = writer.hash.hex();
+    if sort == ExportSort::Canonical {
+        writeln!(
+            writer.inner,
+            "% Manifest: {} games, content hash fnv1a64:{}",
+            positions.len(),
+            hash
+        )?;
+    }
+    Ok(hash)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn export_to_pgn(
+    file: PathBuf,
+    dest_file: PathBuf,
+    sort: Option<ExportSort>,
+    localized: Option<SanStyle>,
+    anonymize: Option<AnonymizeOptions>,
+    state: tauri::State<'_, AppState>,
+) -> Result<()> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    state.path_scope.check(&dest_file)?;
+    if let Some(key_file) = anonymize
+        .as_ref()
+        .and_then(|options| options.key_file.as_ref())
+    {
+        state.path_scope.check(key_file)?;
+    }
+    // Comments may mention any player of the database, not only those of the game.
+    let mut anonymizer = match &anonymize {
+        Some(options) => {
+            let names = players::table
+                .select(players::name)
+                .load::<Option<String>>(db)?
+                .into_iter()
+                .flatten()
+                .collect();
+            Some(Anonymizer::new(options, names, rand::random()))
+        }
+        None => None,
+    };
+
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&dest_file)?;
+
+    let mut writer = BufWriter::new(file);
+    write_pgn(
+        db,
+        &mut writer,
+        sort.unwrap_or_default(),
+        anonymizer.as_mut(),
+    )?;
+    writer.flush()?;
+
+    if let (Some(anonymizer), Some(key_file)) =
+        (&anonymizer, anonymize.and_then(|options| options.key_file))
+    {
+        let mut key = BufWriter::new(File::create(key_file)?);
+        anonymizer.write_key(&mut key)?;
+        key.flush()?;
+    }
+
+    // The PGN file keeps English SAN; the localized moves go to a file of their own.
+    if let Some(style) = localized {
+        let localized_file = dest_file.with_extension(format!("{}.txt", style.file_suffix()));
+        state.path_scope.check(&localized_file)?;
+        let reader = BufReader::new(File::open(&dest_file)?);
+        write_localized(reader, BufWriter::new(File::create(localized_file)?), style)?;
+    }
+    Ok(())
+}
+
+/// Hash of the games of a database, as in the manifest of its canonical export, so that two
+/// databases can be checked for identical games without exporting them.
+#[tauri::command]
+#[specta::specta]
+pub async fn compute_db_content_hash(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<String> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    write_pgn(db, &mut std::io::sink(), ExportSort::Canonical, None)
+}
+
+/// What a game analysis found about a position of the main line, index 0 being the start
+/// position and index `n` the position after the `n`-th move.
+#[derive(Debug, Clone, Default, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionAnnotation {
+    /// Evaluation of the position in centipawns, from White's point of view.
+    pub cp: Option<i32>,
+    /// Moves to mate, positive when White mates. Takes precedence over `cp`.
+    pub mate: Option<i32>,
+    /// How the move leading here compares to the engine's choice.
+    pub classification: Option<MoveClassification>,
+}
+
+impl PositionAnnotation {
+    /// Value of an `[%eval ...]` command: pawns with two decimals, or `#N` for mates.
+    fn eval(&self) -> Option<String> {
+        match (self.mate, self.cp) {
+            (Some(mate), _) => Some(format!("#{}", mate)),
+            (None, Some(cp)) => Some(format!("{:.2}", f64::from(cp) / 100.0)),
+            (None, None) => None,
+        }
+    }
+
+    fn nag(&self) -> Option<Nag> {
+        match self.classification? {
+            MoveClassification::Inaccuracy => Some(Nag(6)), // ?!
+            MoveClassification::Mistake => Some(Nag(2)),    // ?
+            MoveClassification::Blunder => Some(Nag(4)),    // ??
+            MoveClassification::Best | MoveClassification::Good => None,
+        }
+    }
+}
+
+/// Add the evaluations and move judgements of `analysis` to the main line of `tree`.
+///
+/// Evaluations go into the first comment of each move as `[%eval ...]` commands, replacing
+/// those imported with the game. Judgements become NAGs, except on moves the annotator already
+/// judged with one of `!`, `?`, `!!`, `??`, `!?` or `?!`.
+fn annotate(tree: &mut GameTree, analysis: &[PositionAnnotation]) -> Result<()> {
+    let moves = tree.count_main_line_moves();
+    for (ply, position) in analysis.iter().enumerate().take(moves + 1).skip(1) {
+        if let Some(eval) = position.eval() {
+            tree.set_eval_command(ply, &eval)?;
+        }
+        if let Some(nag) = position.nag() {
+            let judged = tree
+                .move_nags(ply)?
+                .iter()
+                .any(|existing| (1..=6).contains(&existing.0));
+            if !judged {
+                tree.add_nag(ply, nag)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a game of a database as PGN, annotated with `analysis`.
+fn write_annotated_pgn(
+    db: &mut SqliteConnection,
+    game_id: i32,
+    analysis: &[PositionAnnotation],
+    writer: &mut impl Write,
+) -> Result<()> {
+    let (white_players, black_players) = diesel::alias!(players as white, players as black);
+    let (game, white, black, event, site): (Game, Player, Player, Event, Site) = games::table
+        .inner_join(white_players.on(games::white_id.eq(white_players.field(players::id))))
+        .inner_join(black_players.on(games::black_id.eq(black_players.field(players::id))))
+        .inner_join(events::table.on(games::event_id.eq(events::id)))
+        .inner_join(sites::table.on(games::site_id.eq(sites::id)))
+        .filter(games::id.eq(game_id))
+        .first(db)?;
+    let mut tree = game_tree(&game)?;
+    annotate(&mut tree, analysis)?;
+    let annotator = attribution::game_attributions(db, game_id)?
+        .first()
+        .map(AnalysisAttribution::annotator);
+    PgnGame::new(game, white, black, event, site, annotator, &tree).write(writer)
+}
+
+/// Export a game with the results of its analysis: evaluations as `[%eval ...]` commands and
+/// inaccuracies, mistakes and blunders as NAGs. Its own comments and variations are kept.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_annotated_pgn(
+    file: PathBuf,
+    game_id: i32,
+    analysis: Vec<PositionAnnotation>,
+    dest_file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<()> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    state.path_scope.check(&dest_file)?;
+
+    let mut writer = BufWriter::new(File::create(&dest_file)?);
+    write_annotated_pgn(db, game_id, &analysis, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::GoMode;
+    use crate::db::{
+        attribution::record_attribution, import_test_games, test_db, AnalysisAttribution,
+    };
+    use crate::lexer::Token;
+
+    const GAMES: [&str; 3] = [
+        "[Event \"Candidates\"]\n[Date \"2024.4.5\"]\n[Round \"2\"]\n[White \"Nepo\"]\n\
+         [Black \"Caruana\"]\n[WhiteElo \"2758\"]\n[Result \"1/2-1/2\"]\n\n1. e4 e5 1/2-1/2\n",
+        "[Event \"Candidates\"]\n[Date \"2024-04-04\"]\n[Round \"1\"]\n[White \"Gukesh\"]\n\
+         [Black \"Vidit\"]\n[Result \"1-0\"]\n\n1. d4 Nf6 {A \"quoted\" comment} 1-0\n",
+        "[Event \" Blitz \"]\n[Date \"????.??.??\"]\n[White \"Carlsen\"]\n\
+         [Black \"Nakamura\"]\n[Result \"*\"]\n\n1. c4 *\n",
+    ];
+
+    fn database(order: &[usize]) -> SqliteConnection {
+        let mut db = test_db("");
+        for i in order {
+            import_test_games(&mut db, GAMES[*i]);
+        }
+        db
+    }
+
+    fn export(db: &mut SqliteConnection) -> (String, String) {
+        let mut pgn = Vec::new();
+        let hash = write_pgn(db, &mut pgn, ExportSort::Canonical, None).unwrap();
+        (String::from_utf8(pgn).unwrap(), hash)
+    }
+
+    #[test]
+    fn dates_are_written_in_one_format() {
+        assert_eq!(normalize_date("2024.4.5"), "2024.04.05");
+        assert_eq!(normalize_date("2024-04-05"), "2024.04.05");
+        assert_eq!(normalize_date(" 2024/12/?? "), "2024.12.??");
+        assert_eq!(normalize_date("????.??.??"), "????.??.??");
+        assert_eq!(normalize_date("April 2024"), "April 2024");
+        assert_eq!(normalize_date("24.04.05"), "24.04.05");
+    }
+
+    #[test]
+    fn canonical_exports_are_reproducible() {
+        let mut first = database(&[0, 1, 2]);
+        let (pgn, hash) = export(&mut first);
+        assert_eq!(export(&mut first), (pgn.clone(), hash.clone()));
+
+        // The same games imported in another order, hence with other IDs, export the same.
+        let mut second = database(&[2, 1, 0]);
+        assert_eq!(export(&mut second), (pgn.clone(), hash.clone()));
+
+        assert!(pgn.starts_with("[Event \"Candidates\"]\n[Site \"\"]\n[Date \"2024.04.04\"]\n"));
+        assert!(pgn.contains("[Event \"Blitz\"]\n[Site \"\"]\n[Date \"????.??.??\"]\n"));
+        assert!(pgn.find("Gukesh").unwrap() < pgn.find("Nepo").unwrap());
+        assert!(pgn.find("Nepo").unwrap() < pgn.find("Carlsen").unwrap());
+        assert!(pgn.ends_with(&format!(
+            "% Manifest: 3 games, content hash fnv1a64:{}\n",
+            hash
+        )));
+        let mut hash_of_games = StableHash::default();
+        hash_of_games.update(pgn[..pgn.rfind("% Manifest").unwrap()].as_bytes());
+        assert_eq!(hash_of_games.hex(), hash);
+        assert_eq!(
+            write_pgn(
+                &mut second,
+                &mut std::io::sink(),
+                ExportSort::Canonical,
+                None
+            )
+            .unwrap(),
+            hash
+        );
+    }
+
+    #[test]
+    fn stored_formats_dont_change_the_canonical_order() {
+        // Stored as written by different importers: the order of the raw values differs.
+        let store = |db: &mut SqliteConnection, dates: [&str; 2], candidates: &str| {
+            for (white, date) in [
+                ("Nepo", dates[0]),
+                ("Carlsen", dates[0]),
+                ("Gukesh", dates[1]),
+            ] {
+                let white_ids = players::table
+                    .filter(players::name.eq(white))
+                    .select(players::id);
+                diesel::update(games::table.filter(games::white_id.eq_any(white_ids)))
+                    .set(games::date.eq(date))
+                    .execute(db)
+                    .unwrap();
+            }
+            for (name, stored) in [("%Candidates%", candidates), ("%Blitz%", "Blitz")] {
+                diesel::update(events::table.filter(events::name.like(name)))
+                    .set(events::name.eq(stored))
+                    .execute(db)
+                    .unwrap();
+            }
+        };
+        let mut tidy = database(&[0, 1, 2]);
+        store(&mut tidy, ["2024.04.05", "2024.04.10"], "Candidates");
+        let mut untidy = database(&[0, 1, 2]);
+        store(&mut untidy, ["2024.4.5", "2024.04.10"], " Candidates ");
+
+        let (pgn, hash) = export(&mut tidy);
+        assert_eq!(export(&mut untidy), (pgn.clone(), hash));
+        assert!(pgn.find("Carlsen").unwrap() < pgn.find("Nepo").unwrap());
+        assert!(pgn.find("Nepo").unwrap() < pgn.find("Gukesh").unwrap());
+    }
+
+    #[test]
+    fn changing_a_game_only_changes_its_lines() {
+        let mut db = database(&[0, 1, 2]);
+        let (before, hash) = export(&mut db);
+
+        diesel::update(games::table.filter(games::white_elo.eq(2758)))
+            .set(games::white_elo.eq(2761))
+            .execute(&mut db)
+            .unwrap();
+        let (after, new_hash) = export(&mut db);
+        assert_ne!(new_hash, hash);
+
+        let before: Vec<&str> = before.lines().collect();
+        let after: Vec<&str> = after.lines().collect();
+        assert_eq!(before.len(), after.len());
+        let changed: Vec<(&str, &str)> = before
+            .iter()
+            .zip(&after)
+            .filter(|(a, b)| a != b)
+            .map(|(a, b)| (*a, *b))
+            .collect();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0], ("[WhiteElo \"2758\"]", "[WhiteElo \"2761\"]"));
+        assert!(changed[1].1.starts_with("% Manifest"));
+    }
+
+    #[test]
+    fn analysed_games_name_their_annotator() {
+        let mut db = database(&[0]);
+        let id: i32 = games::table.select(games::id).first(&mut db).unwrap();
+        for (engine, depth) in [("Stockfish 16", 20), ("Stockfish 17 dev", 24)] {
+            let analysis = AnalysisAttribution {
+                engine: engine.to_string(),
+                go_mode: GoMode::Depth(depth),
+                analyzed_at: 0,
+            };
+            record_attribution(&mut db, id, &analysis).unwrap();
+        }
+        let (pgn, _) = export(&mut db);
+        assert!(pgn.contains("[Annotator \"Stockfish 17 dev, depth 24, Pawn Appétit\"]\n"));
+        assert!(!pgn.contains("Stockfish 16"));
+    }
+
+    #[test]
+    fn annotated_exports_keep_comments_and_variations() {
+        let pgn = "[White \"Anand\"]\n[Black \"Topalov\"]\n[Result \"*\"]\n\n\
+                   1. e4 {[%eval 0.3] King's pawn} e5 (1... c5) 2. Qh5 $2 Ke7 *\n";
+        let mut db = test_db(pgn);
+        let id: i32 = games::table.select(games::id).first(&mut db).unwrap();
+
+        let eval = |cp, classification| PositionAnnotation {
+            cp: Some(cp),
+            mate: None,
+            classification,
+        };
+        let analysis = [
+            eval(20, None),
+            eval(25, Some(MoveClassification::Best)),
+            eval(30, Some(MoveClassification::Good)),
+            eval(-50, Some(MoveClassification::Blunder)),
+            PositionAnnotation {
+                cp: Some(900),
+                mate: Some(7),
+                classification: Some(MoveClassification::Blunder),
+            },
+        ];
+        let mut pgn = Vec::new();
+        write_annotated_pgn(&mut db, id, &analysis, &mut pgn).unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+
+        let tokens = crate::lexer::lex(&pgn).unwrap();
+        let comments: Vec<&str> = tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Comment(comment) => Some(comment.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                "[%eval 0.25] King's pawn",
+                "[%eval 0.30]",
+                "[%eval -0.50]",
+                "[%eval #7]"
+            ]
+        );
+        // The annotator's own `?` is kept over the analysis' `??`.
+        let nags = tokens
+            .iter()
+            .filter(|token| matches!(token, Token::Nag(_)))
+            .count();
+        assert_eq!(nags, 2);
+        assert!(tokens.contains(&Token::San("c5".to_string())));
+        assert!(pgn.contains("$4"));
+    }
+
+    #[test]
+    fn anonymized_exports_hide_players() {
+        let mut db = database(&[0, 1, 2]);
+        let names = players::table
+            .select(players::name)
+            .load::<Option<String>>(&mut db)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect();
+        let options = AnonymizeOptions {
+            year_only: true,
+            ..Default::default()
+        };
+        let mut anonymizer = Anonymizer::new(&options, names, [0; 16]);
+        let mut pgn = Vec::new();
+        write_pgn(
+            &mut db,
+            &mut pgn,
+            ExportSort::Canonical,
+            Some(&mut anonymizer),
+        )
+        .unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+
+        assert!(pgn.starts_with(
+            "[Event \"Candidates\"]\n[Site \"\"]\n[Date \"2024.??.??\"]\n[Round \"1\"]\n\
+             [White \"Player A\"]\n[Black \"Player B\"]\n"
+        ));
+        assert!(pgn.contains("[White \"Player C\"]\n[Black \"Player D\"]\n"));
+        for hidden in ["Gukesh", "Nepo", "Carlsen", "Elo"] {
+            assert!(!pgn.contains(hidden), "{} in {}", hidden, pgn);
+        }
+    }
+}
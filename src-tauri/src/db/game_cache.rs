@@ -0,0 +1,320 @@
+//! Decoded games kept while stepping through a database.
+//!
+//! Opening a game decodes its moves and joins its players, event and site, which is slow
+//! enough to be felt when stepping through a games list. When a game is opened, the games
+//! next to it in the list are decoded in the background into an LRU cache, bounded by the
+//! size of the decoded games, so that the next step finds them ready. Commands changing a
+//! game drop it from the cache. Each change also moves the database to a new generation,
+//! so a game decoded before the change is never cached after it.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use serde::Serialize;
+use specta::Type;
+use tauri::Manager;
+
+use crate::error::Result;
+use crate::AppState;
+
+use super::models::NormalizedGame;
+use super::{core, get_db_or_create, ConnectionOptions};
+
+/// Bytes of decoded games kept at most.
+const DEFAULT_BUDGET: usize = 16 * 1024 * 1024;
+
+/// Neighbors decoded at most for one request.
+const PREFETCH_LIMIT: usize = 8;
+
+/// How often opened games were found already decoded.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct GameCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits over requests, or 0 before any request.
+    pub hit_rate: f64,
+    pub entries: u32,
+    /// Approximate size of the decoded games kept.
+    pub bytes: u64,
+}
+
+struct CachedGame {
+    game: NormalizedGame,
+    size: usize,
+}
+
+struct Inner {
+    games: LruCache<(String, i32), CachedGame>,
+    bytes: usize,
+    /// Bumped whenever a game of the database changes.
+    generations: HashMap<String, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+pub struct GameCache {
+    budget: usize,
+    inner: Mutex<Inner>,
+}
+
+impl Default for GameCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET)
+    }
+}
+
+/// Approximate size of a decoded game: its own size and that of its JSON, which is
+/// dominated by the same strings.
+fn decoded_size(game: &NormalizedGame) -> usize {
+    std::mem::size_of::<NormalizedGame>() + serde_json::to_vec(game).map_or(0, |json| json.len())
+}
+
+impl GameCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            inner: Mutex::new(Inner {
+                games: LruCache::unbounded(),
+                bytes: 0,
+                generations: HashMap::new(),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// A decoded game, counting the request as a hit or a miss.
+    pub fn get(&self, file: &str, id: i32) -> Option<NormalizedGame> {
+        let mut inner = self.inner.lock().unwrap();
+        let game = inner
+            .games
+            .get(&(file.to_string(), id))
+            .map(|cached| cached.game.clone());
+        match game {
+            Some(_) => inner.hits += 1,
+            None => inner.misses += 1,
+        }
+        game
+    }
+
+    fn contains(&self, file: &str, id: i32) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.games.contains(&(file.to_string(), id))
+    }
+
+    /// Current generation of a database, to pass to `insert` with games decoded after.
+    pub fn generation(&self, file: &str) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.generations.get(file).copied().unwrap_or_default()
+    }
+
+    /// Keep a game decoded at `generation`, unless the database changed since. Games
+    /// bigger than the whole budget aren't kept.
+    pub fn insert(&self, file: &str, game: NormalizedGame, generation: u64) {
+        let size = decoded_size(&game);
+        if size > self.budget {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generations.get(file).copied().unwrap_or_default() != generation {
+            return;
+        }
+        if let Some(old) = inner
+            .games
+            .put((file.to_string(), game.id), CachedGame { game, size })
+        {
+            inner.bytes -= old.size;
+        }
+        inner.bytes += size;
+        while inner.bytes > self.budget {
+            match inner.games.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.size,
+                None => break,
+            }
+        }
+    }
+
+    fn bump(inner: &mut Inner, file: &str) {
+        *inner.generations.entry(file.to_string()).or_default() += 1;
+    }
+
+    /// Drop a game that changed or was deleted.
+    pub fn invalidate_game(&self, file: &str, id: i32) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::bump(&mut inner, file);
+        if let Some(old) = inner.games.pop(&(file.to_string(), id)) {
+            inner.bytes -= old.size;
+        }
+    }
+
+    /// Drop every game of a database, after changes to many games or to their players.
+    pub fn invalidate_file(&self, file: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::bump(&mut inner, file);
+        let keys: Vec<(String, i32)> = inner
+            .games
+            .iter()
+            .filter(|((cached_file, _), _)| cached_file == file)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            if let Some(old) = inner.games.pop(&key) {
+                inner.bytes -= old.size;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> GameCacheStats {
+        let inner = self.inner.lock().unwrap();
+        let requests = inner.hits + inner.misses;
+        GameCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            hit_rate: if requests == 0 {
+                0.0
+            } else {
+                inner.hits as f64 / requests as f64
+            },
+            entries: inner.games.len() as u32,
+            bytes: inner.bytes as u64,
+        }
+    }
+}
+
+/// Get a game like `get_game`, then decode the games of `context` in the background, the
+/// ones the list shows next to it, so that opening them next is immediate.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_game_with_prefetch(
+    file: PathBuf,
+    game_id: i32,
+    context: Vec<i32>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<NormalizedGame> {
+    let key = file.to_string_lossy().to_string();
+    let game = match state.game_cache.get(&key, game_id) {
+        Some(game) => game,
+        None => {
+            let generation = state.game_cache.generation(&key);
+            let db = &mut get_db_or_create(&state, &key, ConnectionOptions::default())?;
+            let game = core::get_game(db, game_id)?;
+            state.game_cache.insert(&key, game.clone(), generation);
+            game
+        }
+    };
+
+    let neighbors: Vec<i32> = context
+        .into_iter()
+        .filter(|&id| id != game_id && !state.game_cache.contains(&key, id))
+        .take(PREFETCH_LIMIT)
+        .collect();
+    if !neighbors.is_empty() {
+        tauri::async_runtime::spawn_blocking(move || {
+            let state = app.state::<AppState>();
+            let generation = state.game_cache.generation(&key);
+            let Ok(mut db) = get_db_or_create(&state, &key, ConnectionOptions::default()) else {
+                return;
+            };
+            for id in neighbors {
+                // Games deleted meanwhile are simply not prefetched.
+                if let Ok(game) = core::get_game(&mut db, id) {
+                    state.game_cache.insert(&key, game, generation);
+                }
+            }
+        });
+    }
+    Ok(game)
+}
+
+/// How often `get_game_with_prefetch` found its game already decoded.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_game_cache_stats(state: tauri::State<'_, AppState>) -> Result<GameCacheStats> {
+    Ok(state.game_cache.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{schema::players, test_db};
+    use diesel::prelude::*;
+
+    const FILE: &str = "/games/club.db3";
+
+    fn database(games: usize) -> SqliteConnection {
+        let pgn: String = (0..games)
+            .map(|i| {
+                format!(
+                    "[White \"White {}\"]\n[Black \"Black {}\"]\n[Result \"*\"]\n\n1. e4 e5 2. Nf3 *\n\n",
+                    i, i
+                )
+            })
+            .collect();
+        test_db(&pgn)
+    }
+
+    #[test]
+    fn changed_games_are_decoded_again() {
+        let db = &mut database(2);
+        let cache = GameCache::default();
+        let generation = cache.generation(FILE);
+        cache.insert(FILE, core::get_game(db, 1).unwrap(), generation);
+        cache.insert(FILE, core::get_game(db, 2).unwrap(), generation);
+        assert_eq!(cache.get(FILE, 1).unwrap().white, "White 0");
+        assert!(cache.get("/games/other.db3", 1).is_none());
+
+        diesel::update(players::table.filter(players::name.eq("White 0")))
+            .set(players::name.eq("Renamed"))
+            .execute(db)
+            .unwrap();
+        cache.invalidate_game(FILE, 1);
+        assert!(cache.get(FILE, 1).is_none());
+        assert!(cache.get(FILE, 2).is_some());
+
+        // A game decoded before the change isn't kept after it.
+        let stale = core::get_game(db, 2).unwrap();
+        cache.invalidate_file(FILE);
+        cache.insert(FILE, stale, generation);
+        assert!(cache.get(FILE, 2).is_none());
+
+        cache.insert(FILE, core::get_game(db, 1).unwrap(), cache.generation(FILE));
+        assert_eq!(cache.get(FILE, 1).unwrap().white, "Renamed");
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn decoded_games_stay_within_the_budget() {
+        let db = &mut database(10);
+        let games: Vec<NormalizedGame> =
+            (1..=10).map(|id| core::get_game(db, id).unwrap()).collect();
+        let size = decoded_size(&games[0]);
+        let cache = GameCache::new(size * 4 + size / 2);
+        for game in &games {
+            cache.insert(FILE, game.clone(), 0);
+            assert!(cache.stats().bytes as usize <= size * 4 + size / 2);
+        }
+        assert_eq!(cache.stats().entries, 4);
+        // The least recently used go first.
+        assert!(cache.get(FILE, 6).is_none());
+        assert!(cache.get(FILE, 7).is_some());
+
+        // Touching a game keeps it over newer ones.
+        cache.get(FILE, 7);
+        cache.insert(FILE, games[0].clone(), 0);
+        assert!(cache.get(FILE, 7).is_some());
+        assert!(cache.get(FILE, 8).is_none());
+
+        cache.invalidate_file(FILE);
+        assert_eq!(cache.stats().bytes, 0);
+        let small = GameCache::new(size - 1);
+        small.insert(FILE, games[1].clone(), 0);
+        assert_eq!(small.stats().entries, 0);
+    }
+}
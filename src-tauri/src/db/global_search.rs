@@ -0,0 +1,583 @@
+//! One search box for the whole app.
+//!
+//! `global_search` looks a query up in several sources at once: players and events of every
+//! registered database, the openings table and the games opened recently. Each source has
+//! its own time limit, so a large or slow database leaves its results out with a warning
+//! instead of holding the others back. Results are ranked by how closely they match,
+//! weighted by the kind of result.
+//!
+//! Registered databases are the ones listed in the sidebar, remembered in
+//! `search_databases.json` so they can be searched before the watcher has scanned them.
+//! Puzzle databases only store positions and ratings, with no themes to search.
+
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Duration;
+
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Integer, Nullable, Text},
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::{
+    chess::{open_position_history, recent_visits, PositionHistoryFilter},
+    db::{
+        schema::{events, players},
+        watcher::read_only_uri,
+    },
+    error::{Error, Result},
+    opening::{matching_openings, name_similarity},
+    AppState,
+};
+
+const SEARCH_DATABASES_FILE: &str = "search_databases.json";
+
+/// How long a source is waited for before its results are left out.
+const SOURCE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+const MAX_SEARCH_RESULTS: u32 = 100;
+
+/// Distinct games from the position history looked through.
+const RECENT_GAMES: usize = 30;
+
+/// Similarity below which a recent game doesn't match.
+const MIN_RECENT_GAME_SIMILARITY: f64 = 0.8;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchHitKind {
+    RecentGame,
+    Player,
+    Opening,
+    Event,
+}
+
+impl SearchHitKind {
+    /// Weight of the kind's results, at equal similarity.
+    fn priority(self) -> f64 {
+        match self {
+            SearchHitKind::RecentGame => 1.0,
+            SearchHitKind::Player => 0.95,
+            SearchHitKind::Opening => 0.9,
+            SearchHitKind::Event => 0.85,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct GlobalSearchHit {
+    pub kind: SearchHitKind,
+    pub label: String,
+    /// Title of the database, or event of a recent game.
+    pub detail: Option<String>,
+    /// Database the player, event or game is in.
+    pub file: Option<String>,
+    /// Player, event or game ID.
+    pub id: Option<i32>,
+    /// Position of an opening.
+    pub fen: Option<String>,
+    pub score: f64,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct GlobalSearchResults {
+    pub hits: Vec<GlobalSearchHit>,
+    /// Sources that timed out or failed, and were left out.
+    pub warnings: Vec<String>,
+}
+
+/// How closely `text` matches `query`, from 0 to 1, favouring names starting with it.
+fn similarity(query: &str, text: &str) -> f64 {
+    let query = query.to_lowercase();
+    let text = text.to_lowercase();
+    if text == query {
+        1.0
+    } else if text.starts_with(&query) {
+        0.95
+    } else if text.contains(&query) {
+        0.9
+    } else {
+        name_similarity(&query, &text)
+    }
+}
+
+fn hit(kind: SearchHitKind, label: String, query: &str) -> GlobalSearchHit {
+    GlobalSearchHit {
+        score: kind.priority() * similarity(query, &label),
+        kind,
+        label,
+        detail: None,
+        file: None,
+        id: None,
+        fen: None,
+    }
+}
+
+/// Sort hits best first, breaking ties so that the order doesn't depend on which source
+/// answered first, and keep the `limit` best.
+fn rank_hits(mut hits: Vec<GlobalSearchHit>, limit: usize) -> Vec<GlobalSearchHit> {
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.kind.cmp(&b.kind))
+            .then_with(|| a.label.cmp(&b.label))
+            .then_with(|| a.file.cmp(&b.file))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    hits.truncate(limit);
+    hits
+}
+
+type SourceFuture = Pin<Box<dyn Future<Output = Result<Vec<GlobalSearchHit>>> + Send>>;
+
+/// A named source of results.
+struct Source {
+    name: String,
+    search: SourceFuture,
+}
+
+/// Run the sources concurrently, leaving out those that fail or take longer than `timeout`.
+async fn gather(sources: Vec<Source>, timeout: Duration) -> GlobalSearchResults {
+    let answers = join_all(sources.into_iter().map(|source| async move {
+        let answer = tokio::time::timeout(timeout, source.search).await;
+        (source.name, answer)
+    }))
+    .await;
+
+    let mut results = GlobalSearchResults::default();
+    for (name, answer) in answers {
+        match answer {
+            Ok(Ok(hits)) => results.hits.extend(hits),
+            Ok(Err(e)) => results.warnings.push(format!("{}: {}", name, e)),
+            Err(_) => results.warnings.push(format!("{}: timed out", name)),
+        }
+    }
+    results
+}
+
+/// Run blocking work off the async runtime.
+fn blocking<F>(search: F) -> SourceFuture
+where
+    F: FnOnce() -> Result<Vec<GlobalSearchHit>> + Send + 'static,
+{
+    Box::pin(async move {
+        tokio::task::spawn_blocking(search)
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?
+    })
+}
+
+fn like_pattern(query: &str) -> String {
+    format!(
+        "%{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// Players and events of the database at `path` whose names contain `query`.
+fn search_database(
+    path: &Path,
+    title: &str,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<GlobalSearchHit>> {
+    // Read-only, so that a database deleted meanwhile isn't created again.
+    let db = &mut SqliteConnection::establish(&read_only_uri(path))?;
+    let pattern = like_pattern(query);
+    let file = path.to_string_lossy().to_string();
+
+    let players: Vec<(i32, Option<String>)> = players::table
+        .filter(players::name.like(&pattern).escape('\\'))
+        .select((players::id, players::name))
+        .limit(limit)
+        .load(db)?;
+    let events: Vec<(i32, Option<String>)> = events::table
+        .filter(events::name.like(&pattern).escape('\\'))
+        .filter(events::name.ne("Unknown"))
+        .select((events::id, events::name))
+        .limit(limit)
+        .load(db)?;
+
+    let hits = players
+        .into_iter()
+        .map(|row| (SearchHitKind::Player, row))
+        .chain(events.into_iter().map(|row| (SearchHitKind::Event, row)))
+        .filter_map(|(kind, (id, name))| {
+            Some(GlobalSearchHit {
+                detail: Some(title.to_string()),
+                file: Some(file.clone()),
+                id: Some(id),
+                ..hit(kind, name?, query)
+            })
+        })
+        .collect();
+    Ok(hits)
+}
+
+#[derive(QueryableByName)]
+struct GameNames {
+    #[diesel(sql_type = Nullable<Text>, column_name = "White")]
+    white: Option<String>,
+    #[diesel(sql_type = Nullable<Text>, column_name = "Black")]
+    black: Option<String>,
+    #[diesel(sql_type = Nullable<Text>, column_name = "Event")]
+    event: Option<String>,
+}
+
+fn game_names(db: &mut SqliteConnection, id: i32) -> Result<Option<GameNames>> {
+    Ok(sql_query(
+        "SELECT w.Name AS White, b.Name AS Black, e.Name AS Event FROM Games g \
+         JOIN Players w ON w.ID = g.WhiteID JOIN Players b ON b.ID = g.BlackID \
+         JOIN Events e ON e.ID = g.EventID WHERE g.ID = ?",
+    )
+    .bind::<Integer, _>(id)
+    .get_result(db)
+    .optional()?)
+}
+
+/// Recently opened `games`, most recent first, whose players or event match `query`.
+fn search_recent_games(games: &[(String, i32)], query: &str) -> Result<Vec<GlobalSearchHit>> {
+    let mut connections: HashMap<&str, Option<SqliteConnection>> = HashMap::new();
+    let mut hits = Vec::new();
+    for (file, id) in games {
+        let db = connections
+            .entry(file)
+            .or_insert_with(|| SqliteConnection::establish(&read_only_uri(Path::new(file))).ok());
+        // Games of databases that are gone, or games deleted since, aren't listed.
+        let Some(db) = db else {
+            continue;
+        };
+        let Ok(Some(names)) = game_names(db, *id) else {
+            continue;
+        };
+        let label = format!(
+            "{} - {}",
+            names.white.as_deref().unwrap_or("?"),
+            names.black.as_deref().unwrap_or("?")
+        );
+        let matched = [
+            names.white.as_deref(),
+            names.black.as_deref(),
+            names.event.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|name| similarity(query, name))
+        .fold(0.0, f64::max);
+        if matched < MIN_RECENT_GAME_SIMILARITY {
+            continue;
+        }
+        hits.push(GlobalSearchHit {
+            score: SearchHitKind::RecentGame.priority() * matched,
+            detail: names.event,
+            file: Some(file.clone()),
+            id: Some(*id),
+            ..hit(SearchHitKind::RecentGame, label, query)
+        });
+    }
+    Ok(hits)
+}
+
+/// Distinct games of the position history, most recently opened first.
+fn recent_games(app: &AppHandle) -> Result<Vec<(String, i32)>> {
+    let mut db = open_position_history(app)?;
+    let visits = recent_visits(&mut db, 1000, &PositionHistoryFilter::default())?;
+    let mut seen = BTreeSet::new();
+    Ok(visits
+        .into_iter()
+        .filter_map(|visit| Some((visit.game_file?, visit.game_id?)))
+        .filter(|game| seen.insert(game.clone()))
+        .take(RECENT_GAMES)
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct SearchDatabases {
+    databases: Vec<PathBuf>,
+}
+
+impl SearchDatabases {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Add the databases of the sidebar and drop those that are gone. Returns whether the
+    /// registry changed.
+    fn refresh(&mut self, sidebar: impl IntoIterator<Item = PathBuf>) -> bool {
+        let databases: BTreeSet<PathBuf> = self
+            .databases
+            .drain(..)
+            .chain(sidebar)
+            .filter(|path| path.exists())
+            .collect();
+        let databases: Vec<PathBuf> = databases.into_iter().collect();
+        let changed = databases != self.databases;
+        self.databases = databases;
+        changed
+    }
+}
+
+fn search_databases_path(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app
+        .path()
+        .resolve(SEARCH_DATABASES_FILE, BaseDirectory::AppData)?)
+}
+
+/// Search players and events of every registered database, openings and recently opened
+/// games at once, and return the `limit` best results.
+#[tauri::command]
+#[specta::specta]
+pub async fn global_search(
+    query: String,
+    limit: u32,
+    state: tauri::State<'_, AppState>,
+    app: AppHandle,
+) -> Result<GlobalSearchResults> {
+    let query = query.trim().to_string();
+    if query.is_empty() {
+        return Ok(GlobalSearchResults::default());
+    }
+    let limit = limit.clamp(1, MAX_SEARCH_RESULTS);
+
+    let summaries = state.db_watcher.summaries();
+    let registry_path = search_databases_path(&app)?;
+    let mut registry = SearchDatabases::load(&registry_path)?;
+    let sidebar = summaries.iter().map(|summary| PathBuf::from(&summary.file));
+    if registry.refresh(sidebar) {
+        registry.save(&registry_path)?;
+    }
+    let titles: HashMap<&str, &str> = summaries
+        .iter()
+        .map(|summary| (summary.file.as_str(), summary.title.as_str()))
+        .collect();
+
+    let mut sources = Vec::new();
+    for path in registry.databases {
+        let file = path.to_string_lossy().to_string();
+        let title = titles
+            .get(file.as_str())
+            .map(|title| title.to_string())
+            .or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        let query = query.clone();
+        sources.push(Source {
+            name: title.clone(),
+            search: blocking(move || search_database(&path, &title, &query, limit as i64)),
+        });
+    }
+    let opening_query = query.clone();
+    sources.push(Source {
+        name: "Openings".to_string(),
+        search: blocking(move || {
+            Ok(matching_openings(&opening_query, limit as usize)
+                .into_iter()
+                .map(|(opening, matched)| GlobalSearchHit {
+                    score: SearchHitKind::Opening.priority() * matched,
+                    fen: Some(opening.fen),
+                    ..hit(SearchHitKind::Opening, opening.name, &opening_query)
+                })
+                .collect())
+        }),
+    });
+    let recent_query = query.clone();
+    sources.push(Source {
+        name: "Recent games".to_string(),
+        search: blocking(move || search_recent_games(&recent_games(&app)?, &recent_query)),
+    });
+
+    let mut results = gather(sources, SOURCE_TIMEOUT).await;
+    results.hits = rank_hits(results.hits, limit as usize);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{create::create_database_file, import_test_games, NewDatabaseOptions};
+
+    const PGN: &str = "[Event \"Tata Steel Masters\"]\n[White \"Carlsen, Magnus\"]\n\
+                       [Black \"Giri, Anish\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0\n\n\
+                       [Event \"Casual\"]\n[White \"Carlos, Juan\"]\n[Black \"Giri, Anish\"]\n\
+                       [Result \"0-1\"]\n\n1. d4 d5 0-1\n";
+
+    fn fixture(dir: &Path) -> PathBuf {
+        let path = dir.join("games.db3");
+        create_database_file(&path, &NewDatabaseOptions::default()).unwrap();
+        let db = &mut SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        import_test_games(db, PGN);
+        path
+    }
+
+    fn labels(hits: &[GlobalSearchHit]) -> Vec<(SearchHitKind, &str)> {
+        hits.iter()
+            .map(|hit| (hit.kind, hit.label.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn databases_and_recent_games_are_searched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(dir.path());
+
+        let hits = rank_hits(search_database(&path, "Games", "carl", 10).unwrap(), 10);
+        assert_eq!(
+            labels(&hits),
+            vec![
+                (SearchHitKind::Player, "Carlos, Juan"),
+                (SearchHitKind::Player, "Carlsen, Magnus"),
+            ]
+        );
+        assert_eq!(hits[0].detail.as_deref(), Some("Games"));
+        let hits = search_database(&path, "Games", "tata", 10).unwrap();
+        assert_eq!(
+            labels(&hits),
+            vec![(SearchHitKind::Event, "Tata Steel Masters")]
+        );
+        // Wildcards are matched literally.
+        assert!(search_database(&path, "Games", "%", 10).unwrap().is_empty());
+
+        let file = path.to_string_lossy().to_string();
+        let missing = dir.path().join("gone.db3").to_string_lossy().to_string();
+        let games = [
+            (missing.clone(), 1),
+            (file.clone(), 1),
+            (file.clone(), 2),
+            (file, 9),
+        ];
+        let hits = search_recent_games(&games, "Kasparov").unwrap();
+        assert!(hits.is_empty());
+        let hits = search_recent_games(&games, "Giri").unwrap();
+        assert_eq!(
+            labels(&hits),
+            vec![
+                (SearchHitKind::RecentGame, "Carlsen, Magnus - Giri, Anish"),
+                (SearchHitKind::RecentGame, "Carlos, Juan - Giri, Anish"),
+            ]
+        );
+        assert!(!Path::new(&missing).exists());
+    }
+
+    #[test]
+    fn ranking_is_stable() {
+        let hits = vec![
+            hit(
+                SearchHitKind::Event,
+                "Carlsen Invitational".to_string(),
+                "carlsen",
+            ),
+            hit(
+                SearchHitKind::Player,
+                "Carlsen, Magnus".to_string(),
+                "carlsen",
+            ),
+            GlobalSearchHit {
+                file: Some("b.db3".to_string()),
+                ..hit(SearchHitKind::Player, "Carlsen".to_string(), "carlsen")
+            },
+            GlobalSearchHit {
+                file: Some("a.db3".to_string()),
+                ..hit(SearchHitKind::Player, "Carlsen".to_string(), "carlsen")
+            },
+            hit(
+                SearchHitKind::RecentGame,
+                "Caruana - Carlsen".to_string(),
+                "carlsen",
+            ),
+        ];
+        let ranked = rank_hits(hits.clone(), 10);
+        assert_eq!(
+            labels(&ranked),
+            vec![
+                (SearchHitKind::Player, "Carlsen"),
+                (SearchHitKind::Player, "Carlsen"),
+                (SearchHitKind::Player, "Carlsen, Magnus"),
+                (SearchHitKind::RecentGame, "Caruana - Carlsen"),
+                (SearchHitKind::Event, "Carlsen Invitational"),
+            ]
+        );
+        assert_eq!(ranked[0].file.as_deref(), Some("a.db3"));
+        for shift in 1..hits.len() {
+            let mut shuffled = hits.clone();
+            shuffled.rotate_left(shift);
+            assert_eq!(rank_hits(shuffled, 10), ranked);
+        }
+        assert_eq!(rank_hits(hits, 2), ranked[..2]);
+    }
+
+    #[tokio::test]
+    async fn slow_sources_are_left_out() {
+        let source = |name: &str, delay: u64, label: &str| {
+            let label = label.to_string();
+            Source {
+                name: name.to_string(),
+                search: Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Ok(vec![hit(SearchHitKind::Player, label, "carlsen")])
+                }),
+            }
+        };
+        let failing = Source {
+            name: "Broken".to_string(),
+            search: Box::pin(async { Err(Error::NoMovesFound) }),
+        };
+        let results = gather(
+            vec![
+                source("Slow", 10_000, "Carlsen, Henrik"),
+                source("Fast", 0, "Carlsen, Magnus"),
+                failing,
+            ],
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(
+            labels(&results.hits),
+            vec![(SearchHitKind::Player, "Carlsen, Magnus")]
+        );
+        assert_eq!(results.warnings.len(), 2);
+        assert_eq!(results.warnings[0], "Slow: timed out");
+        assert!(results.warnings[1].starts_with("Broken: "));
+    }
+
+    #[test]
+    fn registry_follows_the_sidebar() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = fixture(dir.path());
+        let mut registry = SearchDatabases {
+            databases: vec![dir.path().join("deleted.db3")],
+        };
+        assert!(registry.refresh([kept.clone(), kept.clone()]));
+        assert_eq!(registry.databases, vec![kept.clone()]);
+        // Databases stay registered while the sidebar hasn't listed them yet.
+        assert!(!registry.refresh(Vec::new()));
+
+        let file = dir.path().join(SEARCH_DATABASES_FILE);
+        registry.save(&file).unwrap();
+        assert_eq!(SearchDatabases::load(&file).unwrap(), registry);
+        assert_eq!(
+            SearchDatabases::load(&dir.path().join("missing.json")).unwrap(),
+            SearchDatabases::default()
+        );
+    }
+}
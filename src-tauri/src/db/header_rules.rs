@@ -0,0 +1,640 @@
+//! Rules rewriting PGN headers on import.
+//!
+//! Each source abuses headers its own way: the round is written in the event, the player's
+//! team in the site, the time control in a custom tag. Rule sets, read from
+//! `import_header_rules.json` in the app data directory, move such values where the
+//! importer expects them before the game is stored. Rules of a set apply in order; by
+//! default only the first one matching a header is applied, while cumulative sets apply
+//! every matching rule, each seeing what the previous ones did. A set can be restricted to
+//! the games of one source by a header they all share.
+//!
+//! A rule with a malformed pattern is left out with a warning, and an unreadable rules file
+//! falls back to the default rule sets, so that a bad edit never stops imports.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use pgn_reader::{BufferedReader, RawHeader, Skip, Visitor};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::error::Result;
+
+const HEADER_RULES_FILE: &str = "import_header_rules.json";
+
+/// Games of a sample shown by `test_header_rules`.
+const SAMPLE_GAMES: usize = 5;
+
+/// A header, and the pattern its value must match, or any value without one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct HeaderMatch {
+    pub header: String,
+    #[serde(default)]
+    #[specta(optional)]
+    pub pattern: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum HeaderAction {
+    /// Set `to` to the value of the matched header.
+    Copy {
+        to: String,
+    },
+    /// Set `to` to the value of the matched header, and remove the header.
+    Move {
+        to: String,
+    },
+    /// Set `to`, which may be the matched header itself, to its value with the pattern
+    /// replaced by `replacement`, where `$1` is the first group of the pattern.
+    Transform {
+        to: String,
+        replacement: String,
+    },
+    Remove,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct HeaderRule {
+    #[serde(rename = "match")]
+    pub matches: HeaderMatch,
+    pub action: HeaderAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct HeaderRuleSet {
+    pub name: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Games the set applies to, all of them when unset.
+    #[serde(default)]
+    #[specta(optional)]
+    pub when: Option<HeaderMatch>,
+    /// Apply every rule matching a header instead of only the first one.
+    #[serde(default)]
+    pub cumulative: bool,
+    pub rules: Vec<HeaderRule>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct HeaderRulesConfig {
+    pub rule_sets: Vec<HeaderRuleSet>,
+}
+
+fn rule(header: &str, pattern: &str, action: HeaderAction) -> HeaderRule {
+    HeaderRule {
+        matches: HeaderMatch {
+            header: header.to_string(),
+            pattern: Some(pattern.to_string()),
+        },
+        action,
+    }
+}
+
+fn site(pattern: &str) -> Option<HeaderMatch> {
+    Some(HeaderMatch {
+        header: "Site".to_string(),
+        pattern: Some(pattern.to_string()),
+    })
+}
+
+impl Default for HeaderRulesConfig {
+    fn default() -> Self {
+        // "Tata Steel Masters (Rd 5)", "Ch World, Round 5.1"
+        const EVENT_WITH_ROUND: &str = r"^(.+?),?\s*\(?(?:Rd|Round)\.?\s*(\d+(?:\.\d+)*)\)?$";
+        Self {
+            rule_sets: vec![
+                HeaderRuleSet {
+                    name: "Lichess".to_string(),
+                    enabled: true,
+                    when: site(r"^https://lichess\.org/"),
+                    cumulative: false,
+                    rules: vec![
+                        // Games outside of tournaments have no round.
+                        rule("Round", r"^-$", HeaderAction::Remove),
+                    ],
+                },
+                HeaderRuleSet {
+                    name: "Chess.com".to_string(),
+                    enabled: true,
+                    when: site(r"^(?i)chess\.com$"),
+                    cumulative: false,
+                    rules: vec![
+                        rule("Round", r"^-$", HeaderAction::Remove),
+                        // Casual daily games.
+                        rule(
+                            "Event",
+                            r"^Let's Play!?$",
+                            HeaderAction::Transform {
+                                to: "Event".to_string(),
+                                replacement: "Daily Chess".to_string(),
+                            },
+                        ),
+                    ],
+                },
+                HeaderRuleSet {
+                    name: "TWIC".to_string(),
+                    enabled: true,
+                    when: None,
+                    // Both rules read the event before it is rewritten.
+                    cumulative: true,
+                    rules: vec![
+                        rule(
+                            "Event",
+                            EVENT_WITH_ROUND,
+                            HeaderAction::Transform {
+                                to: "Round".to_string(),
+                                replacement: "$2".to_string(),
+                            },
+                        ),
+                        rule(
+                            "Event",
+                            EVENT_WITH_ROUND,
+                            HeaderAction::Transform {
+                                to: "Event".to_string(),
+                                replacement: "$1".to_string(),
+                            },
+                        ),
+                    ],
+                },
+            ],
+        }
+    }
+}
+
+/// A header name and value, in the order of the PGN.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct PgnHeader {
+    pub name: String,
+    pub value: String,
+}
+
+fn header_value<'a>(headers: &'a [PgnHeader], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name == name)
+        .map(|header| header.value.as_str())
+}
+
+fn set_header(headers: &mut Vec<PgnHeader>, name: &str, value: String) {
+    match headers.iter_mut().find(|header| header.name == name) {
+        Some(header) => header.value = value,
+        None => headers.push(PgnHeader {
+            name: name.to_string(),
+            value,
+        }),
+    }
+}
+
+fn remove_header(headers: &mut Vec<PgnHeader>, name: &str) {
+    headers.retain(|header| header.name != name);
+}
+
+struct CompiledMatch {
+    header: String,
+    pattern: Option<Regex>,
+}
+
+impl CompiledMatch {
+    fn compile(matches: &HeaderMatch) -> std::result::Result<Self, regex::Error> {
+        Ok(Self {
+            header: matches.header.clone(),
+            pattern: matches.pattern.as_deref().map(Regex::new).transpose()?,
+        })
+    }
+
+    /// The value of the header, if the game has it and it matches.
+    fn find<'a>(&self, headers: &'a [PgnHeader]) -> Option<&'a str> {
+        header_value(headers, &self.header)
+            .filter(|value| self.pattern.as_ref().is_none_or(|p| p.is_match(value)))
+    }
+}
+
+struct CompiledRule {
+    matches: CompiledMatch,
+    action: HeaderAction,
+}
+
+impl CompiledRule {
+    fn apply(&self, headers: &mut Vec<PgnHeader>, value: String) {
+        match &self.action {
+            HeaderAction::Copy { to } => set_header(headers, to, value),
+            HeaderAction::Move { to } => {
+                remove_header(headers, &self.matches.header);
+                set_header(headers, to, value);
+            }
+            HeaderAction::Transform { to, replacement } => {
+                // Transforms are only compiled with a pattern.
+                if let Some(pattern) = &self.matches.pattern {
+                    let value = pattern.replace(&value, replacement.as_str()).into_owned();
+                    set_header(headers, to, value);
+                }
+            }
+            HeaderAction::Remove => remove_header(headers, &self.matches.header),
+        }
+    }
+}
+
+struct CompiledSet {
+    when: Option<CompiledMatch>,
+    cumulative: bool,
+    rules: Vec<CompiledRule>,
+}
+
+/// Enabled rule sets, ready to be applied to games.
+#[derive(Default)]
+pub struct HeaderRules {
+    sets: Vec<CompiledSet>,
+}
+
+impl HeaderRules {
+    /// Compile the enabled rule sets, leaving out the rules that can't be, with a warning for
+    /// each.
+    pub fn compile(config: &HeaderRulesConfig) -> (Self, Vec<String>) {
+        let mut warnings = Vec::new();
+        let mut sets = Vec::new();
+        for set in config.rule_sets.iter().filter(|set| set.enabled) {
+            let when = match set.when.as_ref().map(CompiledMatch::compile).transpose() {
+                Ok(when) => when,
+                Err(e) => {
+                    warnings.push(format!(
+                        "{}: invalid condition, set ignored: {}",
+                        set.name, e
+                    ));
+                    continue;
+                }
+            };
+            let mut rules = Vec::new();
+            for (i, rule) in set.rules.iter().enumerate() {
+                let compiled = match CompiledMatch::compile(&rule.matches) {
+                    Ok(matches) => matches,
+                    Err(e) => {
+                        warnings.push(format!("{}, rule {}: ignored: {}", set.name, i + 1, e));
+                        continue;
+                    }
+                };
+                if matches!(rule.action, HeaderAction::Transform { .. })
+                    && compiled.pattern.is_none()
+                {
+                    warnings.push(format!(
+                        "{}, rule {}: ignored: a transform needs a pattern",
+                        set.name,
+                        i + 1
+                    ));
+                    continue;
+                }
+                rules.push(CompiledRule {
+                    matches: compiled,
+                    action: rule.action.clone(),
+                });
+            }
+            sets.push(CompiledSet {
+                when,
+                cumulative: set.cumulative,
+                rules,
+            });
+        }
+        (Self { sets }, warnings)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.iter().all(|set| set.rules.is_empty())
+    }
+
+    /// Rewrite the headers of a game.
+    pub fn apply(&self, headers: &mut Vec<PgnHeader>) {
+        for set in &self.sets {
+            if set
+                .when
+                .as_ref()
+                .is_some_and(|when| when.find(headers).is_none())
+            {
+                continue;
+            }
+            let mut matched = HashSet::new();
+            for rule in &set.rules {
+                if !set.cumulative && matched.contains(&rule.matches.header) {
+                    continue;
+                }
+                let Some(value) = rule.matches.find(headers).map(str::to_string) else {
+                    continue;
+                };
+                matched.insert(rule.matches.header.clone());
+                rule.apply(headers, value);
+            }
+        }
+    }
+}
+
+/// The rule sets in `path`, or the default ones with a warning if it can't be read.
+pub fn load_rules_config(path: &Path) -> (HeaderRulesConfig, Vec<String>) {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return (HeaderRulesConfig::default(), Vec::new())
+        }
+        Err(e) => {
+            let warning = format!("Header rules unreadable, using the defaults: {}", e);
+            warn!("{}", warning);
+            return (HeaderRulesConfig::default(), vec![warning]);
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(config) => (config, Vec::new()),
+        Err(e) => {
+            let warning = format!("Header rules invalid, using the defaults: {}", e);
+            warn!("{}", warning);
+            (HeaderRulesConfig::default(), vec![warning])
+        }
+    }
+}
+
+/// Compiled header rules of the rules file, for imports.
+pub fn load_header_rules(app: &AppHandle) -> Result<HeaderRules> {
+    let (config, _) = load_rules_config(&header_rules_path(app)?);
+    let (rules, warnings) = HeaderRules::compile(&config);
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    Ok(rules)
+}
+
+fn header_rules_path(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app
+        .path()
+        .resolve(HEADER_RULES_FILE, BaseDirectory::AppData)?)
+}
+
+/// Collects the headers of games, skipping their moves.
+#[derive(Default)]
+struct HeaderCollector {
+    headers: Vec<PgnHeader>,
+}
+
+impl Visitor for HeaderCollector {
+    type Result = Vec<PgnHeader>;
+
+    fn begin_game(&mut self) {
+        self.headers.clear();
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        self.headers.push(PgnHeader {
+            name: String::from_utf8_lossy(key).into_owned(),
+            value: value.decode_utf8_lossy().into_owned(),
+        });
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        Skip(true)
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        std::mem::take(&mut self.headers)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct HeaderMapping {
+    pub before: Vec<PgnHeader>,
+    pub after: Vec<PgnHeader>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct HeaderRulesTest {
+    pub games: Vec<HeaderMapping>,
+    pub warnings: Vec<String>,
+}
+
+fn map_sample(sample_pgn: &str, rules: &HeaderRules) -> Vec<HeaderMapping> {
+    let mut collector = HeaderCollector::default();
+    BufferedReader::new_cursor(sample_pgn.as_bytes())
+        .into_iter(&mut collector)
+        .flatten()
+        .take(SAMPLE_GAMES)
+        .map(|before| {
+            let mut after = before.clone();
+            rules.apply(&mut after);
+            HeaderMapping { before, after }
+        })
+        .collect()
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_header_rules(app: AppHandle) -> Result<HeaderRulesConfig> {
+    Ok(load_rules_config(&header_rules_path(&app)?).0)
+}
+
+/// Save the rule sets. Returns the warnings of the rules that will be left out.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_header_rules(config: HeaderRulesConfig, app: AppHandle) -> Result<Vec<String>> {
+    let path = header_rules_path(&app)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&config)?)?;
+    Ok(HeaderRules::compile(&config).1)
+}
+
+/// Headers of the first games of `sample_pgn` before and after the rules, those of
+/// `config` or else the saved ones, so rules can be tried before importing with them.
+#[tauri::command]
+#[specta::specta]
+pub async fn test_header_rules(
+    sample_pgn: String,
+    config: Option<HeaderRulesConfig>,
+    app: AppHandle,
+) -> Result<HeaderRulesTest> {
+    let (config, mut warnings) = match config {
+        Some(config) => (config, Vec::new()),
+        None => load_rules_config(&header_rules_path(&app)?),
+    };
+    let (rules, compile_warnings) = HeaderRules::compile(&config);
+    warnings.extend(compile_warnings);
+    Ok(HeaderRulesTest {
+        games: map_sample(&sample_pgn, &rules),
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<PgnHeader> {
+        pairs
+            .iter()
+            .map(|(name, value)| PgnHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn single_set(cumulative: bool, rules: Vec<HeaderRule>) -> HeaderRules {
+        let config = HeaderRulesConfig {
+            rule_sets: vec![HeaderRuleSet {
+                name: "Test".to_string(),
+                enabled: true,
+                when: None,
+                cumulative,
+                rules,
+            }],
+        };
+        let (rules, warnings) = HeaderRules::compile(&config);
+        assert!(warnings.is_empty());
+        rules
+    }
+
+    fn transform(header: &str, pattern: &str, to: &str, replacement: &str) -> HeaderRule {
+        rule(
+            header,
+            pattern,
+            HeaderAction::Transform {
+                to: to.to_string(),
+                replacement: replacement.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn first_match_wins_unless_cumulative() {
+        let rules = vec![
+            transform("Event", "^(.*) Open$", "Event", "$1"),
+            transform("Event", "^(.*)$", "Event", "[$1]"),
+        ];
+        let mut game = headers(&[("Event", "Reykjavik Open")]);
+        single_set(false, rules.clone()).apply(&mut game);
+        assert_eq!(game, headers(&[("Event", "Reykjavik")]));
+
+        // Each rule sees the previous one's result.
+        let mut game = headers(&[("Event", "Reykjavik Open")]);
+        single_set(true, rules).apply(&mut game);
+        assert_eq!(game, headers(&[("Event", "[Reykjavik]")]));
+
+        // Rules apply in order: the move happens before the copy.
+        let ordered = vec![
+            rule(
+                "Board",
+                ".",
+                HeaderAction::Move {
+                    to: "Round".to_string(),
+                },
+            ),
+            HeaderRule {
+                matches: HeaderMatch {
+                    header: "Round".to_string(),
+                    pattern: None,
+                },
+                action: HeaderAction::Copy {
+                    to: "Annotator".to_string(),
+                },
+            },
+        ];
+        let mut game = headers(&[("Board", "3"), ("Round", "?")]);
+        single_set(true, ordered).apply(&mut game);
+        assert_eq!(game, headers(&[("Round", "3"), ("Annotator", "3")]));
+    }
+
+    #[test]
+    fn default_rules_fix_known_quirks() {
+        let (rules, warnings) = HeaderRules::compile(&HeaderRulesConfig::default());
+        assert!(warnings.is_empty());
+
+        let mut twic = headers(&[("Event", "Tata Steel Masters (Rd 5)"), ("Round", "?")]);
+        rules.apply(&mut twic);
+        assert_eq!(
+            twic,
+            headers(&[("Event", "Tata Steel Masters"), ("Round", "5")])
+        );
+        let mut twic = headers(&[("Event", "Ch World, Round 5.1")]);
+        rules.apply(&mut twic);
+        assert_eq!(twic, headers(&[("Event", "Ch World"), ("Round", "5.1")]));
+
+        let mut lichess = headers(&[("Site", "https://lichess.org/abcdefgh"), ("Round", "-")]);
+        rules.apply(&mut lichess);
+        assert_eq!(
+            lichess,
+            headers(&[("Site", "https://lichess.org/abcdefgh")])
+        );
+        // Only lichess games lose such rounds.
+        let mut other = headers(&[("Site", "Wijk aan Zee"), ("Round", "-")]);
+        rules.apply(&mut other);
+        assert_eq!(other, headers(&[("Site", "Wijk aan Zee"), ("Round", "-")]));
+
+        let pgn = "[Event \"Let's Play!\"]\n[Site \"Chess.com\"]\n[Round \"-\"]\n\n1. e4 *\n\n\
+                   [Event \"Hoogovens (Round 2)\"]\n\n1. d4 *\n";
+        let games = map_sample(pgn, &rules);
+        assert_eq!(games.len(), 2);
+        assert_eq!(
+            games[0].after,
+            headers(&[("Event", "Daily Chess"), ("Site", "Chess.com")])
+        );
+        assert_eq!(
+            games[1].before,
+            headers(&[("Event", "Hoogovens (Round 2)")])
+        );
+        assert_eq!(
+            games[1].after,
+            headers(&[("Event", "Hoogovens"), ("Round", "2")])
+        );
+    }
+
+    #[test]
+    fn malformed_rules_are_left_out() {
+        let mut config = HeaderRulesConfig::default();
+        config.rule_sets[0]
+            .rules
+            .push(rule("Event", "(unclosed", HeaderAction::Remove));
+        config.rule_sets[1].when = site("[");
+        config.rule_sets[2].rules.push(HeaderRule {
+            matches: HeaderMatch {
+                header: "Event".to_string(),
+                pattern: None,
+            },
+            action: HeaderAction::Transform {
+                to: "Event".to_string(),
+                replacement: "x".to_string(),
+            },
+        });
+        let (rules, warnings) = HeaderRules::compile(&config);
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].starts_with("Lichess, rule 2"));
+        assert!(warnings[1].starts_with("Chess.com: invalid condition"));
+        // The valid rules still apply.
+        let mut game = headers(&[("Event", "Linares (Rd 3)")]);
+        rules.apply(&mut game);
+        assert_eq!(game, headers(&[("Event", "Linares"), ("Round", "3")]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HEADER_RULES_FILE);
+        assert_eq!(
+            load_rules_config(&path),
+            (HeaderRulesConfig::default(), vec![])
+        );
+        std::fs::write(&path, "{\"ruleSets\": [{\"name\": 1}]}").unwrap();
+        let (config, warnings) = load_rules_config(&path);
+        assert_eq!(config, HeaderRulesConfig::default());
+        assert_eq!(warnings.len(), 1);
+
+        let mut disabled = HeaderRulesConfig::default();
+        disabled
+            .rule_sets
+            .iter_mut()
+            .for_each(|set| set.enabled = false);
+        std::fs::write(&path, serde_json::to_string(&disabled).unwrap()).unwrap();
+        let (config, warnings) = load_rules_config(&path);
+        assert!(warnings.is_empty());
+        assert!(HeaderRules::compile(&config).0.is_empty());
+    }
+}
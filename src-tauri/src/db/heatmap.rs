@@ -0,0 +1,338 @@
+//! Move heatmaps
+//!
+//! Counts, over a set of games, how often each piece moved from and to each square, for
+//! piece activity heatmaps. Moves are decoded from the stored games rather than read from
+//! their SAN, so the square a piece left is known even when the SAN leaves it out. Games
+//! are read in batches, each counted in parallel with one accumulator per thread.
+
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, CastlingMode, Chess, Color, Move, Position, Role};
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{
+        encoding::extract_main_line_moves, get_db_or_create, get_start_position, query_games,
+        schema::games, ConnectionOptions, DatabaseProgress, GameQueryJs,
+    },
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+/// Games read from the database at once.
+const HEATMAP_BATCH_SIZE: usize = 5_000;
+
+/// Pieces in the order of `MoveHeatmap::pieces`.
+const PIECES: [char; 12] = ['P', 'N', 'B', 'R', 'Q', 'K', 'p', 'n', 'b', 'r', 'q', 'k'];
+
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct HeatmapOptions {
+    /// First ply counted, 1 being the first move of the game.
+    #[serde(default)]
+    #[specta(optional)]
+    pub from_ply: Option<u32>,
+    /// Last ply counted.
+    #[serde(default)]
+    #[specta(optional)]
+    pub to_ply: Option<u32>,
+    #[serde(default)]
+    pub only_captures: bool,
+    /// Count the moves of each game as fractions of its counted moves, so that every game
+    /// weighs the same however long it is.
+    #[serde(default)]
+    pub normalize_per_game: bool,
+}
+
+/// Moves of one piece, by square from a1 = 0 to h8 = 63.
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct PieceHeatmap {
+    /// As in FEN, uppercase for White.
+    pub piece: String,
+    pub from: Vec<f64>,
+    pub to: Vec<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct MoveHeatmap {
+    pub games: u32,
+    pub moves: u32,
+    /// White pawn, knight, bishop, rook, queen and king, then the same for Black.
+    pub pieces: Vec<PieceHeatmap>,
+}
+
+#[derive(Clone)]
+struct Counts {
+    from: [[f64; 64]; 12],
+    to: [[f64; 64]; 12],
+    games: u32,
+    moves: u32,
+}
+
+fn piece_index(color: Color, role: Role) -> usize {
+    color.fold_wb(0, 6) + role as usize - 1
+}
+
+impl Counts {
+    fn new() -> Self {
+        Self {
+            from: [[0.0; 64]; 12],
+            to: [[0.0; 64]; 12],
+            games: 0,
+            moves: 0,
+        }
+    }
+
+    /// Count the moves of a game, skipping it if its moves can't be decoded.
+    fn add_game(&mut self, mut position: Chess, moves: &[u8], options: &HeatmapOptions) {
+        let Ok(moves) = extract_main_line_moves(moves, Some(position.clone())) else {
+            return;
+        };
+        let from_ply = options.from_ply.unwrap_or(1) as usize;
+        let to_ply = options.to_ply.map_or(usize::MAX, |ply| ply as usize);
+
+        let mut counted: Vec<(usize, usize, usize)> = Vec::new();
+        for (i, m) in moves.iter().enumerate() {
+            let ply = i + 1;
+            if ply > to_ply {
+                break;
+            }
+            if ply >= from_ply && (!options.only_captures || m.is_capture()) {
+                if let Some(from) = m.from() {
+                    // Castling is counted as the move of the king to its square.
+                    let to = match m {
+                        Move::Castle { .. } => m
+                            .castling_side()
+                            .map_or(m.to(), |side| side.king_to(position.turn())),
+                        _ => m.to(),
+                    };
+                    counted.push((
+                        piece_index(position.turn(), m.role()),
+                        from as usize,
+                        to as usize,
+                    ));
+                }
+            }
+            position.play_unchecked(m);
+        }
+
+        let weight = if options.normalize_per_game && !counted.is_empty() {
+            1.0 / counted.len() as f64
+        } else {
+            1.0
+        };
+        for &(piece, from, to) in &counted {
+            self.from[piece][from] += weight;
+            self.to[piece][to] += weight;
+        }
+        self.games += 1;
+        self.moves += counted.len() as u32;
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        let squares =
+            (self.from.iter_mut().zip(&other.from)).chain(self.to.iter_mut().zip(&other.to));
+        for (mine, theirs) in squares {
+            for (count, added) in mine.iter_mut().zip(theirs) {
+                *count += added;
+            }
+        }
+        self.games += other.games;
+        self.moves += other.moves;
+        self
+    }
+
+    fn into_heatmap(self) -> MoveHeatmap {
+        MoveHeatmap {
+            games: self.games,
+            moves: self.moves,
+            pieces: PIECES
+                .iter()
+                .enumerate()
+                .map(|(i, piece)| PieceHeatmap {
+                    piece: piece.to_string(),
+                    from: self.from[i].to_vec(),
+                    to: self.to[i].to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Heatmap of the games `ids`, calling `on_progress` with the games read after each batch
+/// and stopping with `TaskCancelled` once `is_cancelled` is set.
+pub(crate) fn move_heatmap(
+    db: &mut SqliteConnection,
+    ids: &[i32],
+    options: &HeatmapOptions,
+    mut on_progress: impl FnMut(usize),
+    is_cancelled: impl Fn() -> bool,
+) -> Result<MoveHeatmap> {
+    let start = get_start_position(db)?;
+    let mut counts = Counts::new();
+    let mut done = 0;
+    for batch_ids in ids.chunks(HEATMAP_BATCH_SIZE) {
+        if is_cancelled() {
+            return Err(Error::TaskCancelled);
+        }
+        let batch: Vec<(Option<String>, Vec<u8>)> = games::table
+            .select((games::fen, games::moves))
+            .filter(games::id.eq_any(batch_ids))
+            .load(db)?;
+        let batch_counts = batch
+            .par_iter()
+            .fold(Counts::new, |mut acc, (fen, moves)| {
+                let position = match fen {
+                    Some(fen) => match Fen::from_ascii(fen.as_bytes())
+                        .ok()
+                        .and_then(|fen| fen.into_position(CastlingMode::Chess960).ok())
+                    {
+                        Some(position) => position,
+                        None => return acc,
+                    },
+                    None => start.clone(),
+                };
+                acc.add_game(position, moves, options);
+                acc
+            })
+            .reduce(Counts::new, Counts::merge);
+        counts = counts.merge(batch_counts);
+        done += batch_ids.len();
+        on_progress(done);
+    }
+    Ok(counts.into_heatmap())
+}
+
+/// Count the moves of each piece from and to each square over the games matching `query`.
+///
+/// Progress is reported through `DatabaseProgress` events whose id is the database path,
+/// and the scan can be cancelled as a task of that id.
+#[tauri::command]
+#[specta::specta]
+pub async fn compute_move_heatmap(
+    file: PathBuf,
+    query: GameQueryJs,
+    options: HeatmapOptions,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<MoveHeatmap> {
+    let id = file.to_string_lossy().to_string();
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    let mut ids = Vec::new();
+    query_games(db, query, |game| {
+        ids.push(game.id);
+        Ok(())
+    })?;
+
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, true);
+    let heatmap = move_heatmap(
+        db,
+        &ids,
+        &options,
+        |done| {
+            let progress = (done as f64 / ids.len().max(1) as f64) * 100_f64;
+            let _ = DatabaseProgress {
+                id: id.clone(),
+                progress,
+                stage: Some("heatmap".to_string()),
+            }
+            .emit(&app);
+            task.report(progress, Some("heatmap".to_string()));
+        },
+        || task.is_cancelled(),
+    )?;
+    task.finish();
+    Ok(heatmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use shakmaty::Square;
+
+    const GAMES: &str = "[Result \"*\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Bxc6 dxc6 5. O-O *\n\n\
+                         [Result \"*\"]\n\n1. d4 d5 2. c4 dxc4 *\n";
+
+    fn heatmap(options: HeatmapOptions) -> MoveHeatmap {
+        let mut db = test_db(GAMES);
+        move_heatmap(&mut db, &[1, 2], &options, |_| {}, || false).unwrap()
+    }
+
+    fn count(heatmap: &MoveHeatmap, piece: char, from: Square, to: Square) -> (f64, f64) {
+        let piece = heatmap
+            .pieces
+            .iter()
+            .find(|p| p.piece == piece.to_string())
+            .unwrap();
+        (piece.from[from as usize], piece.to[to as usize])
+    }
+
+    fn total(heatmap: &MoveHeatmap) -> f64 {
+        heatmap.pieces.iter().flat_map(|p| p.from.iter()).sum()
+    }
+
+    #[test]
+    fn counts_every_move() {
+        let heatmap = heatmap(HeatmapOptions::default());
+        assert_eq!((heatmap.games, heatmap.moves), (2, 13));
+        assert_eq!(total(&heatmap), 13.0);
+        assert_eq!(count(&heatmap, 'P', Square::E2, Square::E4), (1.0, 1.0));
+        assert_eq!(count(&heatmap, 'P', Square::C2, Square::C4), (1.0, 1.0));
+        assert_eq!(count(&heatmap, 'B', Square::F1, Square::C6), (1.0, 1.0));
+        // Castling moves the king to g1.
+        assert_eq!(count(&heatmap, 'K', Square::E1, Square::G1), (1.0, 1.0));
+        assert_eq!(count(&heatmap, 'R', Square::H1, Square::F1), (0.0, 0.0));
+        // Two black pawns captured on c6 and c4, from d7 and d5.
+        assert_eq!(count(&heatmap, 'p', Square::D7, Square::C6), (2.0, 1.0));
+        assert_eq!(count(&heatmap, 'p', Square::D5, Square::C4), (1.0, 1.0));
+    }
+
+    #[test]
+    fn filters_by_phase_and_captures() {
+        let opening = heatmap(HeatmapOptions {
+            to_ply: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(opening.moves, 4);
+        assert_eq!(count(&opening, 'P', Square::D2, Square::E4), (1.0, 1.0));
+        assert_eq!(count(&opening, 'p', Square::E7, Square::D5), (1.0, 1.0));
+
+        let later = heatmap(HeatmapOptions {
+            from_ply: Some(5),
+            to_ply: Some(6),
+            ..Default::default()
+        });
+        assert_eq!(later.moves, 2);
+        assert_eq!(count(&later, 'B', Square::F1, Square::B5), (1.0, 1.0));
+        assert_eq!(count(&later, 'p', Square::A7, Square::A6), (1.0, 1.0));
+
+        let captures = heatmap(HeatmapOptions {
+            only_captures: true,
+            ..Default::default()
+        });
+        assert_eq!(captures.moves, 3);
+        assert_eq!(count(&captures, 'B', Square::B5, Square::C6), (1.0, 1.0));
+        assert_eq!(count(&captures, 'p', Square::D7, Square::C6), (1.0, 1.0));
+        assert_eq!(count(&captures, 'p', Square::D5, Square::C4), (1.0, 1.0));
+    }
+
+    #[test]
+    fn normalizes_per_game() {
+        let heatmap = heatmap(HeatmapOptions {
+            only_captures: true,
+            normalize_per_game: true,
+            ..Default::default()
+        });
+        // Both captures of the first game weigh a half, the only one of the second a whole.
+        assert_eq!(total(&heatmap), 2.0);
+        assert_eq!(count(&heatmap, 'B', Square::B5, Square::C6), (0.5, 0.5));
+        assert_eq!(count(&heatmap, 'p', Square::D7, Square::C6), (0.5, 0.5));
+        assert_eq!(count(&heatmap, 'p', Square::D5, Square::C4), (1.0, 1.0));
+    }
+}
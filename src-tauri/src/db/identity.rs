@@ -0,0 +1,522 @@
+//! One player across several databases.
+//!
+//! The same person is usually spelled differently in each database: a Lichess username, a
+//! Chess.com one and the full name in over-the-board games. Identities link these players
+//! together in `player_identities.json`, and `get_identity_report` runs the player report
+//! on each linked database and merges the results, each game tagged with the database and
+//! site it comes from. Databases that are gone or can't be read are left out of the report
+//! with a warning instead of failing it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::{
+    db::{get_db_or_create, player_site_stats, ConnectionOptions, GameOutcome, SiteStatsData},
+    error::{Error, Result},
+    AppState,
+};
+
+const IDENTITIES_FILE: &str = "player_identities.json";
+
+/// A player of one database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityLink {
+    pub db_path: PathBuf,
+    pub player_id: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct PlayerIdentity {
+    pub id: String,
+    pub links: Vec<IdentityLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct PlayerIdentities {
+    identities: Vec<PlayerIdentity>,
+}
+
+impl PlayerIdentities {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn get(&self, identity_id: &str) -> Option<&PlayerIdentity> {
+        self.identities.iter().find(|i| i.id == identity_id)
+    }
+
+    /// Link a player to an identity, moving it from the one it was linked to before.
+    fn link(&mut self, link: IdentityLink, identity_id: &str) -> PlayerIdentity {
+        self.unlink(&link);
+        let index = match self.identities.iter().position(|i| i.id == identity_id) {
+            Some(index) => index,
+            None => {
+                self.identities.push(PlayerIdentity {
+                    id: identity_id.to_string(),
+                    links: Vec::new(),
+                });
+                self.identities.len() - 1
+            }
+        };
+        self.identities[index].links.push(link);
+        self.identities[index].clone()
+    }
+
+    /// Unlink a player, dropping identities left without any.
+    fn unlink(&mut self, link: &IdentityLink) {
+        for identity in &mut self.identities {
+            identity.links.retain(|l| l != link);
+        }
+        self.identities.retain(|i| !i.links.is_empty());
+    }
+}
+
+fn identities_path(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app
+        .path()
+        .resolve(IDENTITIES_FILE, BaseDirectory::AppData)?)
+}
+
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityReportQuery {
+    /// First date included, as in the PGN `Date` header.
+    #[specta(optional)]
+    pub start_date: Option<String>,
+    /// Last date included.
+    #[specta(optional)]
+    pub end_date: Option<String>,
+}
+
+impl IdentityReportQuery {
+    fn includes(&self, date: &str) -> bool {
+        self.start_date
+            .as_deref()
+            .map_or(true, |start| date >= start)
+            && self.end_date.as_deref().map_or(true, |end| date <= end)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+pub struct ResultCounts {
+    pub won: u32,
+    pub drawn: u32,
+    pub lost: u32,
+}
+
+impl ResultCounts {
+    fn add(&mut self, result: &GameOutcome) {
+        match result {
+            GameOutcome::Won => self.won += 1,
+            GameOutcome::Drawn => self.drawn += 1,
+            GameOutcome::Lost => self.lost += 1,
+        }
+    }
+
+    pub fn games(&self) -> u32 {
+        self.won + self.drawn + self.lost
+    }
+}
+
+/// Games of one linked player on one site.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentitySource {
+    /// Tag of the source in the rest of the report, the database and the site.
+    pub source: String,
+    pub db_path: PathBuf,
+    /// Name of the player in that database.
+    pub player: String,
+    pub site: String,
+    pub results: ResultCounts,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct IdentityOpening {
+    pub opening: String,
+    pub results: ResultCounts,
+    /// Sources the opening was played in.
+    pub sources: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct RatingPoint {
+    pub date: String,
+    pub rating: i32,
+    pub source: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct Streaks {
+    /// Most wins in a row.
+    pub longest_winning: u32,
+    /// Most games in a row without a loss.
+    pub longest_unbeaten: u32,
+    /// Results in a row like the latest game, wins counting up and losses down, 0 after a
+    /// draw.
+    pub current: i32,
+}
+
+/// A linked database left out of a report.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityWarning {
+    pub db_path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityReport {
+    pub sources: Vec<IdentitySource>,
+    pub results: ResultCounts,
+    /// Openings, the most played first.
+    pub openings: Vec<IdentityOpening>,
+    /// Rating after each game, in date order.
+    pub ratings: Vec<RatingPoint>,
+    /// Streaks over the games of every source, in date order.
+    pub streaks: Streaks,
+    pub warnings: Vec<IdentityWarning>,
+}
+
+/// Player report of one linked database.
+pub struct LinkedStats {
+    pub db_path: PathBuf,
+    pub stats: Vec<SiteStatsData>,
+}
+
+fn source_tag(db_path: &Path, site: &str) -> String {
+    let db = db_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{} / {}", db, site)
+}
+
+fn streaks<'a>(results: impl Iterator<Item = &'a GameOutcome>) -> Streaks {
+    let mut streaks = Streaks::default();
+    let (mut winning, mut unbeaten) = (0, 0);
+    for result in results {
+        match result {
+            GameOutcome::Won => {
+                winning += 1;
+                unbeaten += 1;
+                streaks.current = streaks.current.max(0) + 1;
+            }
+            GameOutcome::Drawn => {
+                winning = 0;
+                unbeaten += 1;
+                streaks.current = 0;
+            }
+            GameOutcome::Lost => {
+                winning = 0;
+                unbeaten = 0;
+                streaks.current = streaks.current.min(0) - 1;
+            }
+        }
+        streaks.longest_winning = streaks.longest_winning.max(winning);
+        streaks.longest_unbeaten = streaks.longest_unbeaten.max(unbeaten);
+    }
+    streaks
+}
+
+/// Merge the player reports of the databases linked to an identity.
+pub fn merge_identity_stats(linked: &[LinkedStats], query: &IdentityReportQuery) -> IdentityReport {
+    let mut report = IdentityReport::default();
+    let mut openings: HashMap<&str, IdentityOpening> = HashMap::new();
+    // Games of every source, to be put in date order.
+    let mut games = Vec::new();
+
+    for linked in linked {
+        for site in &linked.stats {
+            let tag = source_tag(&linked.db_path, &site.site);
+            let mut source = IdentitySource {
+                source: tag.clone(),
+                db_path: linked.db_path.clone(),
+                player: site.player.clone(),
+                site: site.site.clone(),
+                results: ResultCounts::default(),
+            };
+            for game in site.data.iter().filter(|game| query.includes(&game.date)) {
+                source.results.add(&game.result);
+                report.results.add(&game.result);
+
+                let opening = openings
+                    .entry(&game.opening)
+                    .or_insert_with(|| IdentityOpening {
+                        opening: game.opening.clone(),
+                        results: ResultCounts::default(),
+                        sources: Vec::new(),
+                    });
+                opening.results.add(&game.result);
+                if !opening.sources.contains(&tag) {
+                    opening.sources.push(tag.clone());
+                }
+                games.push((game, tag.clone()));
+            }
+            if source.results.games() > 0 {
+                report.sources.push(source);
+            }
+        }
+    }
+
+    // Games of a day keep the order of their source, sources the order of their links.
+    games.sort_by(|(a, _), (b, _)| a.date.cmp(&b.date));
+    report.streaks = streaks(games.iter().map(|(game, _)| &game.result));
+    report.ratings = games
+        .into_iter()
+        .map(|(game, source)| RatingPoint {
+            date: game.date.clone(),
+            rating: game.player_elo,
+            source,
+        })
+        .collect();
+
+    report.openings = openings.into_values().collect();
+    report.openings.sort_by(|a, b| {
+        b.results
+            .games()
+            .cmp(&a.results.games())
+            .then_with(|| a.opening.cmp(&b.opening))
+    });
+    report
+}
+
+/// Identities and the players linked to them.
+#[tauri::command]
+#[specta::specta]
+pub async fn list_player_identities(app: AppHandle) -> Result<Vec<PlayerIdentity>> {
+    Ok(PlayerIdentities::load(&identities_path(&app)?)?.identities)
+}
+
+/// Link a player of a database to an identity, created if needed. A player belongs to one
+/// identity at most, so it leaves the one it was linked to before.
+#[tauri::command]
+#[specta::specta]
+pub async fn link_player_identity(
+    db_path: PathBuf,
+    player_id: i32,
+    identity_id: String,
+    app: AppHandle,
+) -> Result<PlayerIdentity> {
+    let path = identities_path(&app)?;
+    let mut identities = PlayerIdentities::load(&path)?;
+    let identity = identities.link(IdentityLink { db_path, player_id }, &identity_id);
+    identities.save(&path)?;
+    Ok(identity)
+}
+
+/// Unlink a player of a database from its identity.
+#[tauri::command]
+#[specta::specta]
+pub async fn unlink_player_identity(
+    db_path: PathBuf,
+    player_id: i32,
+    app: AppHandle,
+) -> Result<()> {
+    let path = identities_path(&app)?;
+    let mut identities = PlayerIdentities::load(&path)?;
+    identities.unlink(&IdentityLink { db_path, player_id });
+    identities.save(&path)
+}
+
+/// Results, openings, ratings and streaks of an identity over every linked database.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_identity_report(
+    identity_id: String,
+    query: IdentityReportQuery,
+    state: tauri::State<'_, AppState>,
+    app: AppHandle,
+) -> Result<IdentityReport> {
+    let identities = PlayerIdentities::load(&identities_path(&app)?)?;
+    let identity = identities
+        .get(&identity_id)
+        .ok_or_else(|| Error::IdentityNotFound(identity_id.clone()))?;
+
+    let mut linked = Vec::new();
+    let mut warnings = Vec::new();
+    for link in &identity.links {
+        let warn = |message: String| IdentityWarning {
+            db_path: link.db_path.clone(),
+            message,
+        };
+        // Opening a missing database would create an empty one.
+        if !link.db_path.exists() {
+            warnings.push(warn("Database not found".to_string()));
+            continue;
+        }
+        let Some(path) = link.db_path.to_str() else {
+            warnings.push(warn("Invalid database path".to_string()));
+            continue;
+        };
+        let stats = get_db_or_create(&state, path, ConnectionOptions::default())
+            .and_then(|mut db| player_site_stats(&mut db, link.player_id, |_, _| {}));
+        match stats {
+            Ok(stats) => linked.push(LinkedStats {
+                db_path: link.db_path.clone(),
+                stats,
+            }),
+            Err(e) => warnings.push(warn(e.to_string())),
+        }
+    }
+
+    let mut report = merge_identity_stats(&linked, &query);
+    report.warnings = warnings;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::StatsData;
+
+    fn game(date: &str, elo: i32, result: GameOutcome, opening: &str) -> StatsData {
+        StatsData {
+            date: date.to_string(),
+            is_player_white: true,
+            player_elo: elo,
+            result,
+            time_control: String::new(),
+            opening: opening.to_string(),
+        }
+    }
+
+    fn linked(db: &str, site: &str, player: &str, data: Vec<StatsData>) -> LinkedStats {
+        LinkedStats {
+            db_path: PathBuf::from(db),
+            stats: vec![SiteStatsData {
+                site: site.to_string(),
+                player: player.to_string(),
+                data,
+            }],
+        }
+    }
+
+    #[test]
+    fn players_move_between_identities() {
+        let mut identities = PlayerIdentities::default();
+        let lichess = IdentityLink {
+            db_path: PathBuf::from("lichess.db3"),
+            player_id: 4,
+        };
+        let otb = IdentityLink {
+            db_path: PathBuf::from("otb.db3"),
+            player_id: 12,
+        };
+        identities.link(lichess.clone(), "me");
+        let me = identities.link(otb.clone(), "me");
+        assert_eq!(me.links, vec![lichess.clone(), otb.clone()]);
+
+        // Linking again moves the player, and the emptied identity goes away.
+        identities.link(lichess.clone(), "other");
+        identities.link(lichess.clone(), "me");
+        assert_eq!(identities.identities.len(), 1);
+        assert_eq!(identities.get("me").unwrap().links, vec![otb, lichess]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(IDENTITIES_FILE);
+        assert_eq!(
+            PlayerIdentities::load(&path).unwrap(),
+            PlayerIdentities::default()
+        );
+        identities.save(&path).unwrap();
+        assert_eq!(PlayerIdentities::load(&path).unwrap(), identities);
+    }
+
+    #[test]
+    fn reports_merge_sources_by_date() {
+        use GameOutcome::*;
+        let linked = vec![
+            linked(
+                "/dbs/mylichess_lichess.db3",
+                "Lichess",
+                "me_on_lichess",
+                vec![
+                    game("2024.01.01", 1800, Won, "Sicilian Defense"),
+                    game("2024.01.03", 1810, Won, "French Defense"),
+                    game("2024.01.05", 1790, Lost, "Sicilian Defense"),
+                ],
+            ),
+            linked(
+                "/dbs/otb.db3",
+                "Club",
+                "Doe, John",
+                vec![
+                    game("2024.01.02", 1650, Won, "Sicilian Defense"),
+                    game("2024.01.04", 1655, Drawn, "Italian Game"),
+                    game("2023.12.01", 1640, Lost, "Italian Game"),
+                ],
+            ),
+        ];
+
+        let report = merge_identity_stats(&linked, &IdentityReportQuery::default());
+        assert_eq!(
+            report.results,
+            ResultCounts {
+                won: 3,
+                drawn: 1,
+                lost: 2
+            }
+        );
+        assert_eq!(report.sources.len(), 2);
+        assert_eq!(report.sources[1].source, "otb / Club");
+        assert_eq!(report.sources[1].player, "Doe, John");
+
+        let sicilian = &report.openings[0];
+        assert_eq!(sicilian.opening, "Sicilian Defense");
+        assert_eq!(sicilian.results.games(), 3);
+        assert_eq!(
+            sicilian.sources,
+            vec!["mylichess_lichess / Lichess", "otb / Club"]
+        );
+        assert_eq!(report.openings[1].opening, "Italian Game");
+
+        let dates: Vec<_> = report.ratings.iter().map(|r| r.date.as_str()).collect();
+        assert_eq!(
+            dates,
+            vec![
+                "2023.12.01",
+                "2024.01.01",
+                "2024.01.02",
+                "2024.01.03",
+                "2024.01.04",
+                "2024.01.05"
+            ]
+        );
+        assert_eq!(report.ratings[2].source, "otb / Club");
+
+        // Three wins in a row only once both sources are interleaved.
+        assert_eq!(
+            report.streaks,
+            Streaks {
+                longest_winning: 3,
+                longest_unbeaten: 4,
+                current: -1
+            }
+        );
+
+        let january = IdentityReportQuery {
+            start_date: Some("2024.01.02".to_string()),
+            end_date: Some("2024.01.04".to_string()),
+        };
+        let report = merge_identity_stats(&linked, &january);
+        assert_eq!(report.results.games(), 3);
+        assert_eq!(report.streaks.current, 0);
+    }
+}
@@ -0,0 +1,440 @@
+//! King safety of a player
+//!
+//! Summarizes how a player handles their king over their games: when and to which side
+//! they castle, how they score depending on it, how often they lose the right to castle
+//! without having castled, and how many enemy pieces bear on their king by move 20. All of
+//! it comes from the stored moves, without engine calls. Games are read in batches, each
+//! scanned in parallel with one accumulator per thread.
+
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use rayon::prelude::*;
+use serde::Serialize;
+use shakmaty::{
+    attacks, fen::Fen, Bitboard, Board, CastlingMode, CastlingSide, Chess, Color, Move, Position,
+};
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{
+        encoding::extract_main_line_moves, get_db_or_create, get_start_position, schema::games,
+        ConnectionOptions, DatabaseProgress,
+    },
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+/// Games read from the database at once.
+const KING_SAFETY_BATCH_SIZE: i64 = 5_000;
+
+/// Last move at which castling counts as early.
+const EARLY_CASTLING_MOVE: u32 = 10;
+
+/// Ply after which the pieces aimed at the king are counted, once both sides played move 20.
+const ATTACK_PLY: usize = 40;
+
+/// What a game tells about the king safety of one side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GameFeatures {
+    /// Ply of the castling move, 1 being the first move of the game, and the side castled to.
+    pub castled: Option<(u32, CastlingSide)>,
+    /// Whether every castling right was lost without castling.
+    pub lost_castling_rights: bool,
+    /// Enemy pieces aimed at the king zone after `ATTACK_PLY`, for games lasting that long.
+    pub attackers: Option<u32>,
+}
+
+/// Enemy pieces attacking the king of `color` or a square next to it.
+pub(crate) fn king_zone_attackers(board: &Board, color: Color) -> u32 {
+    let Some(king) = board.king_of(color) else {
+        return 0;
+    };
+    let zone = attacks::king_attacks(king) | Bitboard::from_square(king);
+    let enemies = board.by_color(!color) & !board.kings();
+    enemies
+        .into_iter()
+        .filter(|&square| {
+            board.piece_at(square).is_some_and(|piece| {
+                (attacks::attacks(square, piece, board.occupied()) & zone).any()
+            })
+        })
+        .count() as u32
+}
+
+/// King safety features of `color` in a game from `position` with the main line `moves`.
+pub(crate) fn game_features(mut position: Chess, moves: &[Move], color: Color) -> GameFeatures {
+    let had_castling_rights = position.castles().has_color(color);
+    let mut castled = None;
+    let mut attackers = None;
+    for (i, m) in moves.iter().enumerate() {
+        if castled.is_none() && position.turn() == color {
+            if let Some(side) = m.castling_side() {
+                castled = Some((i as u32 + 1, side));
+            }
+        }
+        position.play_unchecked(m);
+        if i + 1 == ATTACK_PLY {
+            attackers = Some(king_zone_attackers(position.board(), color));
+        }
+    }
+    GameFeatures {
+        castled,
+        lost_castling_rights: had_castling_rights
+            && castled.is_none()
+            && !position.castles().has_color(color),
+        attackers,
+    }
+}
+
+/// Results of a player, from their side.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Type)]
+pub struct KingSafetyResults {
+    pub games: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl KingSafetyResults {
+    fn add(&mut self, result: Option<&str>, color: Color) {
+        self.games += 1;
+        match (result, color) {
+            (Some("1-0"), Color::White) | (Some("0-1"), Color::Black) => self.wins += 1,
+            (Some("0-1"), Color::White) | (Some("1-0"), Color::Black) => self.losses += 1,
+            (Some("1/2-1/2"), _) => self.draws += 1,
+            _ => {}
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.games += other.games;
+        self.wins += other.wins;
+        self.draws += other.draws;
+        self.losses += other.losses;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Type)]
+pub struct KingSafetyReport {
+    /// Games of the player whose moves could be read.
+    pub games: u32,
+    /// Average ply of the castling move over the games the player castled in.
+    pub average_castling_ply: Option<f64>,
+    pub kingside: KingSafetyResults,
+    pub queenside: KingSafetyResults,
+    pub never_castled: KingSafetyResults,
+    /// Games castled by move 10.
+    pub early_castling: KingSafetyResults,
+    /// Games castled after move 10.
+    pub late_castling: KingSafetyResults,
+    /// Games in which the player lost every castling right without castling.
+    pub lost_castling_rights: u32,
+    /// Games lasting until both sides played move 20.
+    pub games_at_move_20: u32,
+    /// Average number of enemy pieces aimed at the player's king or the squares next to it
+    /// after move 20.
+    pub average_attackers_at_move_20: Option<f64>,
+}
+
+#[derive(Clone, Default)]
+struct Totals {
+    report: KingSafetyReport,
+    castling_plies: u64,
+    attackers: u64,
+}
+
+impl Totals {
+    fn add_game(&mut self, features: &GameFeatures, result: Option<&str>, color: Color) {
+        let report = &mut self.report;
+        report.games += 1;
+        match features.castled {
+            Some((ply, side)) => {
+                self.castling_plies += ply as u64;
+                match side {
+                    CastlingSide::KingSide => report.kingside.add(result, color),
+                    CastlingSide::QueenSide => report.queenside.add(result, color),
+                }
+                if ply.div_ceil(2) <= EARLY_CASTLING_MOVE {
+                    report.early_castling.add(result, color);
+                } else {
+                    report.late_castling.add(result, color);
+                }
+            }
+            None => report.never_castled.add(result, color),
+        }
+        if features.lost_castling_rights {
+            report.lost_castling_rights += 1;
+        }
+        if let Some(attackers) = features.attackers {
+            report.games_at_move_20 += 1;
+            self.attackers += attackers as u64;
+        }
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        let (report, added) = (&mut self.report, &other.report);
+        report.games += added.games;
+        report.kingside.merge(&added.kingside);
+        report.queenside.merge(&added.queenside);
+        report.never_castled.merge(&added.never_castled);
+        report.early_castling.merge(&added.early_castling);
+        report.late_castling.merge(&added.late_castling);
+        report.lost_castling_rights += added.lost_castling_rights;
+        report.games_at_move_20 += added.games_at_move_20;
+        self.castling_plies += other.castling_plies;
+        self.attackers += other.attackers;
+        self
+    }
+
+    fn into_report(self) -> KingSafetyReport {
+        let mut report = self.report;
+        let castled = report.kingside.games + report.queenside.games;
+        report.average_castling_ply =
+            (castled > 0).then(|| self.castling_plies as f64 / castled as f64);
+        report.average_attackers_at_move_20 = (report.games_at_move_20 > 0)
+            .then(|| self.attackers as f64 / report.games_at_move_20 as f64);
+        report
+    }
+}
+
+/// King safety report of the player `player_id`, calling `on_progress` with the games read
+/// and the total after each batch and stopping with `TaskCancelled` once `is_cancelled` is
+/// set.
+pub(crate) fn king_safety_report(
+    db: &mut SqliteConnection,
+    player_id: i32,
+    mut on_progress: impl FnMut(usize, i64),
+    is_cancelled: impl Fn() -> bool,
+) -> Result<KingSafetyReport> {
+    let start = get_start_position(db)?;
+    let player_games = || {
+        games::table.filter(
+            games::white_id
+                .eq(player_id)
+                .or(games::black_id.eq(player_id)),
+        )
+    };
+    let total: i64 = player_games().count().get_result(db)?;
+
+    let mut totals = Totals::default();
+    let mut done = 0;
+    let mut last_id = 0;
+    loop {
+        if is_cancelled() {
+            return Err(Error::TaskCancelled);
+        }
+        let batch: Vec<(i32, i32, Option<String>, Option<String>, Vec<u8>)> = player_games()
+            .select((
+                games::id,
+                games::white_id,
+                games::fen,
+                games::result,
+                games::moves,
+            ))
+            .filter(games::id.gt(last_id))
+            .order(games::id)
+            .limit(KING_SAFETY_BATCH_SIZE)
+            .load(db)?;
+        let Some((batch_last, ..)) = batch.last() else {
+            break;
+        };
+        last_id = *batch_last;
+
+        let batch_totals = batch
+            .par_iter()
+            .fold(
+                Totals::default,
+                |mut acc, (_, white_id, fen, result, moves)| {
+                    let position = match fen {
+                        Some(fen) => match Fen::from_ascii(fen.as_bytes())
+                            .ok()
+                            .and_then(|fen| fen.into_position(CastlingMode::Chess960).ok())
+                        {
+                            Some(position) => position,
+                            None => return acc,
+                        },
+                        None => start.clone(),
+                    };
+                    let Ok(moves) = extract_main_line_moves(moves, Some(position.clone())) else {
+                        return acc;
+                    };
+                    let color = if *white_id == player_id {
+                        Color::White
+                    } else {
+                        Color::Black
+                    };
+                    let features = game_features(position, &moves, color);
+                    acc.add_game(&features, result.as_deref(), color);
+                    acc
+                },
+            )
+            .reduce(Totals::default, Totals::merge);
+        totals = totals.merge(batch_totals);
+        done += batch.len();
+        on_progress(done, total);
+    }
+    Ok(totals.into_report())
+}
+
+/// Castling habits and king exposure of a player over their games.
+///
+/// Progress is reported through `DatabaseProgress` events whose id is the database path,
+/// and the scan can be cancelled as a task of that id.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_king_safety_report(
+    file: PathBuf,
+    player_id: i32,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<KingSafetyReport> {
+    let id = file.to_string_lossy().to_string();
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, true);
+    let report = king_safety_report(
+        db,
+        player_id,
+        |done, total| {
+            let progress = (done as f64 / total.max(1) as f64) * 100_f64;
+            let _ = DatabaseProgress {
+                id: id.clone(),
+                progress,
+                stage: Some("king_safety".to_string()),
+            }
+            .emit(&app);
+            task.report(progress, Some("king_safety".to_string()));
+        },
+        || task.is_cancelled(),
+    )?;
+    task.finish();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{schema::players, test_db};
+    use shakmaty::san::San;
+
+    const CASTLE_AT_5: &str = "e4 e5 Nf3 Nc6 Bc4 Bc5 d3 d6 O-O";
+
+    fn play(sans: &[String]) -> Vec<Move> {
+        let mut position = Chess::default();
+        sans.iter()
+            .map(|san| {
+                let m = san.parse::<San>().unwrap().to_move(&position).unwrap();
+                position.play_unchecked(&m);
+                m
+            })
+            .collect()
+    }
+
+    fn words(sans: &str) -> Vec<String> {
+        sans.split_whitespace().map(str::to_string).collect()
+    }
+
+    /// White castles at move 25 after shuffling knights, with the game going on to move 27.
+    fn castle_at_25() -> Vec<String> {
+        let mut sans = words("e4 e5 Nf3 Nc6 Bc4 Bc5");
+        for i in 4..25 {
+            let (white, black) = if i % 2 == 0 {
+                ("Nc3", "Nb8")
+            } else {
+                ("Nb1", "Nc6")
+            };
+            sans.extend([white.to_string(), black.to_string()]);
+        }
+        sans.extend(words("O-O Nf6 d3 d6 h3 h6"));
+        sans
+    }
+
+    #[test]
+    fn castling_early() {
+        let features = game_features(Chess::default(), &play(&words(CASTLE_AT_5)), Color::White);
+        assert_eq!(features.castled, Some((9, CastlingSide::KingSide)));
+        assert!(!features.lost_castling_rights);
+        assert_eq!(features.attackers, None);
+
+        // Black kept their rights without castling.
+        let features = game_features(Chess::default(), &play(&words(CASTLE_AT_5)), Color::Black);
+        assert_eq!(features.castled, None);
+        assert!(!features.lost_castling_rights);
+    }
+
+    #[test]
+    fn castling_late() {
+        let features = game_features(Chess::default(), &play(&castle_at_25()), Color::White);
+        assert_eq!(features.castled, Some((49, CastlingSide::KingSide)));
+        assert!(!features.lost_castling_rights);
+        assert!(features.attackers.is_some());
+    }
+
+    #[test]
+    fn never_castling() {
+        let moves = play(&words("e4 e5 Ke2 Ke7 Ke1 Ke8 Qh5"));
+        for color in [Color::White, Color::Black] {
+            let features = game_features(Chess::default(), &moves, color);
+            assert_eq!(features.castled, None);
+            assert!(features.lost_castling_rights);
+        }
+    }
+
+    #[test]
+    fn pieces_aimed_at_the_king_zone() {
+        // Queen and knight hit h2, the bishop hits f2, the rook is elsewhere.
+        let board: Board = "r5k1/b7/8/8/6nq/8/5PPP/6K1".parse().unwrap();
+        assert_eq!(king_zone_attackers(&board, Color::White), 3);
+        assert_eq!(king_zone_attackers(&board, Color::Black), 0);
+    }
+
+    #[test]
+    fn report_over_a_players_games() {
+        let pgn = format!(
+            "[White \"Student\"]\n[Black \"Coach\"]\n[Result \"1-0\"]\n\n{} 1-0\n\n\
+             [White \"Coach\"]\n[Black \"Student\"]\n[Result \"1-0\"]\n\n{} 1-0\n\n\
+             [White \"Student\"]\n[Black \"Coach\"]\n[Result \"1/2-1/2\"]\n\n{} 1/2-1/2\n",
+            CASTLE_AT_5,
+            CASTLE_AT_5,
+            castle_at_25().join(" ")
+        );
+        let mut db = test_db(&pgn);
+        let student: i32 = players::table
+            .filter(players::name.eq("Student"))
+            .select(players::id)
+            .first(&mut db)
+            .unwrap();
+
+        let report = king_safety_report(&mut db, student, |_, _| {}, || false).unwrap();
+        assert_eq!(report.games, 3);
+        assert_eq!(report.average_castling_ply, Some(29.0));
+        assert_eq!(
+            report.kingside,
+            KingSafetyResults {
+                games: 2,
+                wins: 1,
+                draws: 1,
+                losses: 0
+            }
+        );
+        assert_eq!(
+            report.never_castled,
+            KingSafetyResults {
+                games: 1,
+                wins: 0,
+                draws: 0,
+                losses: 1
+            }
+        );
+        assert_eq!(report.early_castling.games, 1);
+        assert_eq!(report.late_castling.games, 1);
+        assert_eq!(report.lost_castling_rights, 0);
+        assert_eq!(report.games_at_move_20, 1);
+
+        let cancelled = king_safety_report(&mut db, student, |_, _| {}, || true);
+        assert!(matches!(cancelled, Err(Error::TaskCancelled)));
+    }
+}
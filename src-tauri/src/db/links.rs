@@ -0,0 +1,475 @@
+//! Links between games
+//!
+//! Annotators refer from one game to another ("compare with Kasparov–Karpov 1985"). Links
+//! are stored per database in `GameLinks`. When a game is deleted, its own links go with it
+//! while links pointing to it are kept without a target, so the reference doesn't silently
+//! disappear from the annotated game.
+//!
+//! In PGN, links are written as `[%link #<n> "label"]` commands in the game's first
+//! comment, where `<n>` is the position of the target game in the exported file. Importing
+//! that file back resolves the positions to the new game IDs.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    db::{get_db_or_create, pgn::TempGame, schema::*, ConnectionOptions},
+    error::{Error, Result},
+    AppState,
+};
+
+const GAME_LINKS_SQL: &str = include_str!("../../../database/schema/game_links.sql");
+
+/// Command marking a link in a PGN comment.
+const LINK_COMMAND: &str = "[%link";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Queryable, Type)]
+pub struct GameLink {
+    pub id: i32,
+    pub from_game: i32,
+    /// `None` once the linked game was deleted.
+    pub to_game: Option<i32>,
+    pub label: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+pub struct LinkedGames {
+    /// Links from the game to others.
+    pub outgoing: Vec<GameLink>,
+    /// Links from other games to this one.
+    pub incoming: Vec<GameLink>,
+}
+
+/// Link found in a PGN comment, pointing to the `target`-th game of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgnLink {
+    pub target: usize,
+    pub label: String,
+}
+
+/// Databases created before games could be linked don't have the table yet.
+pub(crate) fn ensure_links_table(db: &mut SqliteConnection) -> Result<()> {
+    db.batch_execute(GAME_LINKS_SQL)?;
+    Ok(())
+}
+
+/// Link `from_game` to `to_game`, replacing the label of an existing link between them.
+pub fn add_link(
+    db: &mut SqliteConnection,
+    from_game: i32,
+    to_game: i32,
+    label: &str,
+) -> Result<GameLink> {
+    if from_game == to_game {
+        return Err(Error::InvalidGameLink(
+            "A game can't link to itself".to_string(),
+        ));
+    }
+    ensure_links_table(db)?;
+    let found: i64 = games::table
+        .filter(games::id.eq_any([from_game, to_game]))
+        .count()
+        .get_result(db)?;
+    if found != 2 {
+        return Err(Error::InvalidGameLink(format!(
+            "Game {} or {} doesn't exist",
+            from_game, to_game
+        )));
+    }
+
+    Ok(diesel::insert_into(game_links::table)
+        .values((
+            game_links::from_game.eq(from_game),
+            game_links::to_game.eq(to_game),
+            game_links::label.eq(label),
+        ))
+        .on_conflict((game_links::from_game, game_links::to_game))
+        .do_update()
+        .set(game_links::label.eq(label))
+        .get_result(db)?)
+}
+
+/// Remove the link from `from_game` to `to_game`, returning whether there was one.
+pub fn remove_link(db: &mut SqliteConnection, from_game: i32, to_game: i32) -> Result<bool> {
+    ensure_links_table(db)?;
+    let deleted = diesel::delete(
+        game_links::table
+            .filter(game_links::from_game.eq(from_game))
+            .filter(game_links::to_game.eq(to_game)),
+    )
+    .execute(db)?;
+    Ok(deleted > 0)
+}
+
+/// Links from and to a game.
+pub fn linked_games(db: &mut SqliteConnection, game_id: i32) -> Result<LinkedGames> {
+    ensure_links_table(db)?;
+    Ok(LinkedGames {
+        outgoing: game_links::table
+            .filter(game_links::from_game.eq(game_id))
+            .order(game_links::id)
+            .load(db)?,
+        incoming: game_links::table
+            .filter(game_links::to_game.eq(game_id))
+            .order(game_links::id)
+            .load(db)?,
+    })
+}
+
+/// Drop the links of a game about to be deleted, and mark links to it as orphaned.
+///
+/// Foreign keys do the same, but aren't enabled on every connection.
+pub(crate) fn detach_game(db: &mut SqliteConnection, game_id: i32) -> Result<()> {
+    ensure_links_table(db)?;
+    diesel::delete(game_links::table.filter(game_links::from_game.eq(game_id))).execute(db)?;
+    diesel::update(game_links::table.filter(game_links::to_game.eq(game_id)))
+        .set(game_links::to_game.eq(None::<i32>))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Links with a target of every game, by the linking game.
+pub(crate) fn all_links(db: &mut SqliteConnection) -> Result<HashMap<i32, Vec<GameLink>>> {
+    ensure_links_table(db)?;
+    let links: Vec<GameLink> = game_links::table
+        .filter(game_links::to_game.is_not_null())
+        .order(game_links::id)
+        .load(db)?;
+    let mut by_game: HashMap<i32, Vec<GameLink>> = HashMap::new();
+    for link in links {
+        by_game.entry(link.from_game).or_default().push(link);
+    }
+    Ok(by_game)
+}
+
+/// Format a link to the `target`-th game of a PGN file as a comment command.
+pub fn link_command(target: usize, label: &str) -> String {
+    // Comments end at the first closing brace.
+    let label = label
+        .replace('}', "")
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    format!("{} #{} \"{}\"]", LINK_COMMAND, target, label)
+}
+
+/// Take the link commands out of a comment, returning the links and the remaining text.
+pub fn extract_link_commands(comment: &str) -> (Vec<PgnLink>, String) {
+    let mut links = Vec::new();
+    let mut rest = String::new();
+    let mut text = comment;
+    while let Some(start) = text.find(LINK_COMMAND) {
+        rest.push_str(&text[..start]);
+        match parse_link_command(&text[start + LINK_COMMAND.len()..]) {
+            Some((link, remaining)) => {
+                links.extend(link);
+                text = remaining;
+            }
+            None => {
+                // Not a well-formed command, keep it as text.
+                rest.push_str(LINK_COMMAND);
+                text = &text[start + LINK_COMMAND.len()..];
+            }
+        }
+    }
+    rest.push_str(text);
+    (links, rest.trim().to_string())
+}
+
+/// Parse the arguments of a link command up to its closing bracket, returning the link
+/// (if its reference is understood) and the text after the command.
+fn parse_link_command(text: &str) -> Option<(Option<PgnLink>, &str)> {
+    let text = text.trim_start();
+    let reference_end = text.find(|c: char| c.is_whitespace() || c == ']')?;
+    let reference = &text[..reference_end];
+    let mut text = text[reference_end..].trim_start();
+
+    let mut label = String::new();
+    if let Some(quoted) = text.strip_prefix('"') {
+        let mut chars = quoted.char_indices();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => label.push(chars.next()?.1),
+                '"' => {
+                    end = Some(i + 1);
+                    break;
+                }
+                c => label.push(c),
+            }
+        }
+        text = quoted[end?..].trim_start();
+    }
+    let remaining = text.strip_prefix(']')?;
+
+    let link = reference
+        .strip_prefix('#')
+        .and_then(|n| n.parse().ok())
+        .filter(|&target| target > 0)
+        .map(|target| PgnLink { target, label });
+    Some((link, remaining))
+}
+
+/// Links found while importing a PGN file, resolved once all its games are in the database.
+#[derive(Default)]
+pub struct LinkImport {
+    /// ID of each imported game, in file order.
+    ids: Vec<i32>,
+    pending: Vec<(i32, PgnLink)>,
+}
+
+impl LinkImport {
+    /// Record the next game of the file, stored as `id`.
+    pub fn record(&mut self, id: i32, game: &TempGame) {
+        self.ids.push(id);
+        self.pending
+            .extend(game.links.iter().map(|link| (id, link.clone())));
+    }
+
+    /// Store the links whose target was imported, returning how many were stored.
+    pub fn finish(self, db: &mut SqliteConnection) -> Result<usize> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        ensure_links_table(db)?;
+        let mut stored = 0;
+        for (from_game, link) in self.pending {
+            let Some(&to_game) = self.ids.get(link.target - 1) else {
+                continue;
+            };
+            if to_game != from_game {
+                diesel::insert_or_ignore_into(game_links::table)
+                    .values((
+                        game_links::from_game.eq(from_game),
+                        game_links::to_game.eq(to_game),
+                        game_links::label.eq(&link.label),
+                    ))
+                    .execute(db)?;
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+}
+
+/// Drop both ends of a link from the game cache, since games are cached with their links.
+fn forget_linked(state: &AppState, file: &Path, from_game: i32, to_game: i32) {
+    let file = file.to_string_lossy();
+    state.game_cache.invalidate_game(&file, from_game);
+    state.game_cache.invalidate_game(&file, to_game);
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn link_games(
+    file: PathBuf,
+    from_game: i32,
+    to_game: i32,
+    label: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<GameLink> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let link = add_link(db, from_game, to_game, &label)?;
+    forget_linked(&state, &file, from_game, to_game);
+    Ok(link)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_linked_games(
+    file: PathBuf,
+    game_id: i32,
+    state: tauri::State<'_, AppState>,
+) -> Result<LinkedGames> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    linked_games(db, game_id)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn unlink_games(
+    file: PathBuf,
+    from_game: i32,
+    to_game: i32,
+    state: tauri::State<'_, AppState>,
+) -> Result<bool> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let removed = remove_link(db, from_game, to_game)?;
+    forget_linked(&state, &file, from_game, to_game);
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        core::remove_game, export::write_pgn, insert_to_db, pgn::Importer, test_db, ExportSort,
+    };
+    use pgn_reader::BufferedReader;
+
+    const GAMES: &str = r#"[White "Karpov"]
+[Black "Kasparov"]
+[Result "0-1"]
+
+1. e4 c5 0-1
+
+[White "Kasparov"]
+[Black "Karpov"]
+[Result "1-0"]
+
+{ [%link #1 "Compare with the \"first\" game"] Same opening } 1. e4 c5 2. Nf3 1-0
+
+[White "Anand"]
+[Black "Carlsen"]
+[Result "1/2-1/2"]
+
+1. d4 { [%link #2 "Model game"] } d5 1/2-1/2
+"#;
+
+    fn import(db: &mut SqliteConnection, pgn: &str) -> Vec<i32> {
+        let mut importer = Importer::new(None);
+        let mut links = LinkImport::default();
+        let mut ids = Vec::new();
+        for game in BufferedReader::new_cursor(pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+        {
+            let id = insert_to_db(db, &game).unwrap();
+            links.record(id, &game);
+            ids.push(id);
+        }
+        links.finish(db).unwrap();
+        ids
+    }
+
+    fn targets(links: &[GameLink]) -> Vec<(i32, Option<i32>, &str)> {
+        links
+            .iter()
+            .map(|link| (link.from_game, link.to_game, link.label.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn links_are_added_relabelled_and_removed() {
+        let mut db = test_db("");
+        let ids = import(&mut db, &GAMES.replace("[%link", "[%nolink"));
+        let (a, b, c) = (ids[0], ids[1], ids[2]);
+        assert_eq!(linked_games(&mut db, a).unwrap(), LinkedGames::default());
+
+        let link = add_link(&mut db, a, b, "Rematch").unwrap();
+        assert_eq!((link.from_game, link.to_game), (a, Some(b)));
+        let relabelled = add_link(&mut db, a, b, "The rematch").unwrap();
+        assert_eq!(relabelled.id, link.id);
+        add_link(&mut db, c, b, "").unwrap();
+
+        let linked = linked_games(&mut db, b).unwrap();
+        assert!(linked.outgoing.is_empty());
+        assert_eq!(
+            targets(&linked.incoming),
+            [(a, Some(b), "The rematch"), (c, Some(b), "")]
+        );
+        assert_eq!(
+            targets(&linked_games(&mut db, a).unwrap().outgoing),
+            [(a, Some(b), "The rematch")]
+        );
+
+        assert!(add_link(&mut db, a, a, "").is_err());
+        assert!(add_link(&mut db, a, 1000, "").is_err());
+
+        assert!(remove_link(&mut db, a, b).unwrap());
+        assert!(!remove_link(&mut db, a, b).unwrap());
+        assert_eq!(
+            targets(&linked_games(&mut db, b).unwrap().incoming),
+            [(c, Some(b), "")]
+        );
+    }
+
+    #[test]
+    fn deleting_games_drops_or_orphans_their_links() {
+        let mut db = test_db("");
+        let ids = import(&mut db, &GAMES.replace("[%link", "[%nolink"));
+        let (a, b, c) = (ids[0], ids[1], ids[2]);
+        add_link(&mut db, a, b, "to b").unwrap();
+        add_link(&mut db, b, c, "to c").unwrap();
+        add_link(&mut db, c, b, "back to b").unwrap();
+
+        remove_game(&mut db, b).unwrap();
+        let orphaned = linked_games(&mut db, a).unwrap().outgoing;
+        assert_eq!(targets(&orphaned), [(a, None, "to b")]);
+        assert_eq!(
+            targets(&linked_games(&mut db, c).unwrap().outgoing),
+            [(c, None, "back to b")]
+        );
+        assert!(linked_games(&mut db, c).unwrap().incoming.is_empty());
+
+        // Orphaned links aren't exported.
+        assert!(all_links(&mut db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn links_survive_a_pgn_round_trip() {
+        let mut db = test_db("");
+        let ids = import(&mut db, GAMES);
+        let (a, b, c) = (ids[0], ids[1], ids[2]);
+        assert_eq!(
+            targets(&linked_games(&mut db, a).unwrap().incoming),
+            [(b, Some(a), "Compare with the \"first\" game")]
+        );
+        assert_eq!(
+            targets(&linked_games(&mut db, c).unwrap().outgoing),
+            [(c, Some(b), "Model game")]
+        );
+        // The commands are taken out of the comments, keeping the rest of the text.
+        let game = crate::db::core::get_game(&mut db, b).unwrap();
+        assert!(game.moves.contains("Same opening"));
+        assert!(!game.moves.contains("%link"));
+        assert!(game.links.is_some());
+
+        let mut pgn = Vec::new();
+        write_pgn(&mut db, &mut pgn, ExportSort::Id, None).unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+        assert!(pgn.contains(r#"[%link #1 "Compare with the \"first\" game"]"#));
+
+        // Importing into a database that already has games maps positions to the new IDs.
+        let mut copy = test_db("");
+        import(&mut copy, "[White \"Someone\"]\n\n1. c4 *\n");
+        let ids = import(&mut copy, &pgn);
+        let (a, b, c) = (ids[0], ids[1], ids[2]);
+        assert_eq!(
+            targets(&linked_games(&mut copy, b).unwrap().outgoing),
+            [(b, Some(a), "Compare with the \"first\" game")]
+        );
+        assert_eq!(
+            targets(&linked_games(&mut copy, b).unwrap().incoming),
+            [(c, Some(b), "Model game")]
+        );
+    }
+
+    #[test]
+    fn link_commands_are_parsed_out_of_comments() {
+        let comment = format!("Before {} after", link_command(12, r#"Say "hi" \ {now}"#));
+        let (links, rest) = extract_link_commands(&comment);
+        assert_eq!(
+            links,
+            [PgnLink {
+                target: 12,
+                label: r#"Say "hi" \ {now"#.to_string()
+            }]
+        );
+        assert_eq!(rest, "Before  after");
+
+        let (links, rest) = extract_link_commands("[%link #3] [%link #4 \"x\"]");
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].label, "");
+        assert!(rest.is_empty());
+
+        // Unknown references are dropped, malformed commands kept as text.
+        let (links, rest) = extract_link_commands("[%link game:5 \"x\"] [%link #2 \"open");
+        assert!(links.is_empty());
+        assert_eq!(rest, "[%link #2 \"open");
+    }
+}
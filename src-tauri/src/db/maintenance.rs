@@ -0,0 +1,206 @@
+//! Database maintenance
+//!
+//! SQLite files never shrink on their own and query plans go stale as the data changes.
+//! `optimize_database` refreshes the planner statistics and can rewrite the file to reclaim
+//! the space left by deleted games. Operations deleting a large share of the games flag the
+//! database so the UI can suggest optimizing it.
+
+use std::path::{Path, PathBuf};
+
+use diesel::{connection::SimpleConnection, insert_into, prelude::*};
+use log::info;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sysinfo::{DiskExt, System, SystemExt};
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{
+        get_db_or_create, models::Info, schema::info, write_lock, ConnectionOptions,
+        DatabaseProgress,
+    },
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+const NEEDS_OPTIMIZE_KEY: &str = "NeedsOptimize";
+
+/// Share of the games a single operation has to delete for the database to need optimizing.
+const NEEDS_OPTIMIZE_THRESHOLD: f64 = 0.1;
+
+#[derive(Deserialize, Debug, Default, Type)]
+pub struct OptimizeOptions {
+    /// Rewrite the file to reclaim free pages. Needs as much free disk space as the database.
+    pub vacuum: bool,
+}
+
+#[derive(Serialize, Debug, Type)]
+pub struct OptimizeReport {
+    pub size_before: i64,
+    pub size_after: i64,
+    pub vacuumed: bool,
+}
+
+/// Flag the database as needing optimization if `deleted` is a large share of `total` games.
+pub(crate) fn flag_if_needs_optimize(
+    db: &mut SqliteConnection,
+    deleted: usize,
+    total: i64,
+) -> Result<()> {
+    if total > 0 && deleted as f64 / total as f64 > NEEDS_OPTIMIZE_THRESHOLD {
+        insert_into(info::table)
+            .values((info::name.eq(NEEDS_OPTIMIZE_KEY), info::value.eq("1")))
+            .on_conflict(info::name)
+            .do_update()
+            .set(info::value.eq("1"))
+            .execute(db)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn needs_optimize(db: &mut SqliteConnection) -> Result<bool> {
+    Ok(info::table
+        .filter(info::name.eq(NEEDS_OPTIMIZE_KEY))
+        .first::<Info>(db)
+        .optional()?
+        .is_some())
+}
+
+/// Size of the database file, including its write-ahead log.
+fn database_size(path: &Path) -> i64 {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    [path, Path::new(&wal)]
+        .iter()
+        .filter_map(|p| p.metadata().ok())
+        .map(|m| m.len() as i64)
+        .sum()
+}
+
+/// Free space on the disk holding `path`, given the mount points and free space of all disks.
+fn available_space(path: &Path, disks: &[(PathBuf, u64)]) -> Option<u64> {
+    disks
+        .iter()
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, available)| *available)
+}
+
+fn disks() -> Vec<(PathBuf, u64)> {
+    let mut sys = System::new();
+    sys.refresh_disks_list();
+    sys.disks()
+        .iter()
+        .map(|disk| (disk.mount_point().to_path_buf(), disk.available_space()))
+        .collect()
+}
+
+/// Refresh the query planner statistics of a database and optionally `VACUUM` it.
+///
+/// Waits for imports into the database to finish first. Progress is reported through
+/// `DatabaseProgress` events whose id is the database path, one stage per step.
+#[tauri::command]
+#[specta::specta]
+pub async fn optimize_database(
+    file: PathBuf,
+    options: OptimizeOptions,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<OptimizeReport> {
+    let id = file.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let size_before = database_size(&file);
+    if options.vacuum {
+        let path = file.canonicalize()?;
+        if let Some(available) = available_space(&path, &disks()) {
+            if available < size_before as u64 {
+                return Err(Error::InsufficientDiskSpace {
+                    needed: size_before as u64,
+                    available,
+                });
+            }
+        }
+    }
+
+    let mut steps = vec![("optimize", "PRAGMA optimize;"), ("analyze", "ANALYZE;")];
+    if options.vacuum {
+        steps.push(("vacuum", "PRAGMA wal_checkpoint(TRUNCATE); VACUUM;"));
+    }
+
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, false);
+    for (i, (stage, sql)) in steps.iter().enumerate() {
+        let progress = (i as f64 / steps.len() as f64) * 100_f64;
+        DatabaseProgress {
+            id: id.clone(),
+            progress,
+            stage: Some(stage.to_string()),
+        }
+        .emit(&app)?;
+        task.report(progress, Some(stage.to_string()));
+        db.batch_execute(sql)?;
+        tokio::task::yield_now().await;
+    }
+    diesel::delete(info::table.filter(info::name.eq(NEEDS_OPTIMIZE_KEY))).execute(db)?;
+
+    DatabaseProgress {
+        id,
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(&app)?;
+    task.finish();
+    state.db_watcher.touch(&file);
+
+    let size_after = database_size(&file);
+    info!(
+        "Optimized {}: {} -> {} bytes",
+        file.display(),
+        size_before,
+        size_after
+    );
+    Ok(OptimizeReport {
+        size_before,
+        size_after,
+        vacuumed: options.vacuum,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    #[test]
+    fn available_space_uses_the_innermost_mount_point() {
+        let disks = vec![
+            (PathBuf::from("/"), 100),
+            (PathBuf::from("/home"), 50),
+            (PathBuf::from("/home/user/data"), 10),
+        ];
+        let space = |p: &str| available_space(Path::new(p), &disks);
+        assert_eq!(space("/home/user/db/games.db3"), Some(50));
+        assert_eq!(space("/home/user/data/games.db3"), Some(10));
+        assert_eq!(space("/tmp/games.db3"), Some(100));
+        // Mount points match whole components only.
+        assert_eq!(space("/homework/games.db3"), Some(100));
+        assert_eq!(available_space(Path::new("/tmp"), &[]), None);
+    }
+
+    #[test]
+    fn large_deletions_flag_the_database() {
+        let mut db = test_db("");
+
+        flag_if_needs_optimize(&mut db, 5, 100).unwrap();
+        assert!(!needs_optimize(&mut db).unwrap());
+        flag_if_needs_optimize(&mut db, 0, 0).unwrap();
+        assert!(!needs_optimize(&mut db).unwrap());
+        flag_if_needs_optimize(&mut db, 20, 100).unwrap();
+        assert!(needs_optimize(&mut db).unwrap());
+        flag_if_needs_optimize(&mut db, 50, 100).unwrap();
+        assert!(needs_optimize(&mut db).unwrap());
+    }
+}
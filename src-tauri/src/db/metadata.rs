@@ -0,0 +1,330 @@
+//! Database metadata
+//!
+//! Besides the title and description kept in `Info`, a database can carry long-form notes in
+//! markdown, where its games come from and under which license, free-form tags and a cover
+//! for the databases page. They are stored in the single-row `DatabaseMetadata` table, which
+//! databases created before it existed only get on their first edit; until then they read as
+//! empty metadata.
+//!
+//! Every write bumps the metadata version. Edits made against an older version are rejected,
+//! so two windows editing the same database don't silently overwrite each other.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use diesel::{connection::SimpleConnection, dsl::sql, prelude::*, sql_types::Bool};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    db::{get_db_or_create, schema::database_metadata, ConnectionOptions},
+    error::{Error, Result},
+    AppState,
+};
+
+const DATABASE_METADATA_SQL: &str = include_str!("../../../database/schema/database_metadata.sql");
+
+/// ID of the only row of the table.
+const METADATA_ROW: i32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+pub struct DatabaseMetadata {
+    /// Number of times the metadata was written, 0 if it never was.
+    pub version: u32,
+    /// Markdown.
+    pub notes: String,
+    pub source_url: Option<String>,
+    pub license: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    pub cover_color: Option<String>,
+    /// Identifier of the icon shown on the cover.
+    pub cover_icon: Option<String>,
+}
+
+/// Changes to the metadata of a database. Fields left out are kept as they are.
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+pub struct DatabaseMetadataPatch {
+    /// Version the changes were made against. When set, the patch fails with
+    /// `MetadataConflict` if the metadata was written since.
+    pub expected_version: Option<u32>,
+    pub notes: Option<String>,
+    /// For the following fields, an empty string clears the field.
+    pub source_url: Option<String>,
+    pub license: Option<String>,
+    pub cover_color: Option<String>,
+    pub cover_icon: Option<String>,
+    /// Tags to set, or to remove when the value is `None`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, Option<String>>,
+}
+
+type MetadataRow = (
+    i32,
+    String,
+    Option<String>,
+    Option<String>,
+    String,
+    Option<String>,
+    Option<String>,
+);
+
+fn metadata_table_exists(db: &mut SqliteConnection) -> Result<bool> {
+    Ok(diesel::select(sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'DatabaseMetadata')",
+    ))
+    .get_result(db)?)
+}
+
+/// Metadata of a database, empty if it was never edited. Doesn't modify the database.
+pub(crate) fn read_metadata(db: &mut SqliteConnection) -> Result<DatabaseMetadata> {
+    if !metadata_table_exists(db)? {
+        return Ok(DatabaseMetadata::default());
+    }
+    let row: Option<MetadataRow> = database_metadata::table
+        .find(METADATA_ROW)
+        .select((
+            database_metadata::version,
+            database_metadata::notes,
+            database_metadata::source_url,
+            database_metadata::license,
+            database_metadata::tags,
+            database_metadata::cover_color,
+            database_metadata::cover_icon,
+        ))
+        .first(db)
+        .optional()?;
+    let Some((version, notes, source_url, license, tags, cover_color, cover_icon)) = row else {
+        return Ok(DatabaseMetadata::default());
+    };
+    Ok(DatabaseMetadata {
+        version: version as u32,
+        notes,
+        source_url,
+        license,
+        tags: serde_json::from_str(&tags)?,
+        cover_color,
+        cover_icon,
+    })
+}
+
+/// `None` for an empty or blank value.
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl DatabaseMetadataPatch {
+    fn apply(self, metadata: &mut DatabaseMetadata) -> Result<()> {
+        if let Some(notes) = self.notes {
+            metadata.notes = notes;
+        }
+        if let Some(source_url) = self.source_url {
+            metadata.source_url = non_empty(source_url);
+        }
+        if let Some(license) = self.license {
+            metadata.license = non_empty(license);
+        }
+        if let Some(cover_color) = self.cover_color {
+            metadata.cover_color = non_empty(cover_color);
+        }
+        if let Some(cover_icon) = self.cover_icon {
+            metadata.cover_icon = non_empty(cover_icon);
+        }
+        for (name, value) in self.tags {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(Error::InvalidMetadata(
+                    "Tag names can't be empty".to_string(),
+                ));
+            }
+            match value {
+                Some(value) => metadata.tags.insert(name.to_string(), value),
+                None => metadata.tags.remove(name),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Apply `patch` to the metadata of a database, creating the table on first use.
+///
+/// Nothing is written if the patch fails. Returns the new metadata.
+pub(crate) fn write_metadata(
+    db: &mut SqliteConnection,
+    patch: DatabaseMetadataPatch,
+) -> Result<DatabaseMetadata> {
+    db.immediate_transaction(|db| {
+        db.batch_execute(DATABASE_METADATA_SQL)?;
+        let mut metadata = read_metadata(db)?;
+        if patch
+            .expected_version
+            .is_some_and(|expected| expected != metadata.version)
+        {
+            return Err(Error::MetadataConflict(metadata.version));
+        }
+        patch.apply(&mut metadata)?;
+        metadata.version += 1;
+
+        diesel::replace_into(database_metadata::table)
+            .values((
+                database_metadata::id.eq(METADATA_ROW),
+                database_metadata::version.eq(metadata.version as i32),
+                database_metadata::notes.eq(&metadata.notes),
+                database_metadata::source_url.eq(&metadata.source_url),
+                database_metadata::license.eq(&metadata.license),
+                database_metadata::tags.eq(serde_json::to_string(&metadata.tags)?),
+                database_metadata::cover_color.eq(&metadata.cover_color),
+                database_metadata::cover_icon.eq(&metadata.cover_icon),
+            ))
+            .execute(db)?;
+        Ok(metadata)
+    })
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_db_metadata(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<DatabaseMetadata> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    read_metadata(db)
+}
+
+/// Edit the metadata of a database, returning it as saved.
+#[tauri::command]
+#[specta::specta]
+pub async fn set_db_metadata(
+    file: PathBuf,
+    patch: DatabaseMetadataPatch,
+    state: tauri::State<'_, AppState>,
+) -> Result<DatabaseMetadata> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    write_metadata(db, patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    /// A database as created before the metadata table existed.
+    fn legacy_db() -> SqliteConnection {
+        let mut db = test_db("");
+        assert!(!metadata_table_exists(&mut db).unwrap());
+        db
+    }
+
+    fn tags(tags: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        tags.iter()
+            .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn legacy_databases_read_as_empty_until_edited() {
+        let mut db = legacy_db();
+        assert_eq!(read_metadata(&mut db).unwrap(), DatabaseMetadata::default());
+        // Reading doesn't migrate.
+        assert!(!metadata_table_exists(&mut db).unwrap());
+
+        let saved = write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                expected_version: Some(0),
+                notes: Some("# Sicilian\nModel games.".to_string()),
+                source_url: Some("https://example.org/games.pgn".to_string()),
+                license: Some("CC0".to_string()),
+                cover_color: Some("teal".to_string()),
+                tags: tags(&[("opening", Some("B90")), ("level", Some("master"))]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(metadata_table_exists(&mut db).unwrap());
+        assert_eq!(saved.version, 1);
+        assert_eq!(saved.cover_icon, None);
+        assert_eq!(saved.tags["opening"], "B90");
+        assert_eq!(read_metadata(&mut db).unwrap(), saved);
+    }
+
+    #[test]
+    fn patches_keep_fields_they_leave_out() {
+        let mut db = legacy_db();
+        write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                notes: Some("Notes".to_string()),
+                license: Some("CC-BY-4.0".to_string()),
+                tags: tags(&[("opening", Some("B90")), ("level", Some("master"))]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let saved = write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                license: Some(" ".to_string()),
+                cover_icon: Some("trophy".to_string()),
+                tags: tags(&[("level", None), ("year", Some("2024"))]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(saved.version, 2);
+        assert_eq!(saved.notes, "Notes");
+        assert_eq!(saved.license, None);
+        assert_eq!(saved.cover_icon.as_deref(), Some("trophy"));
+        assert_eq!(
+            saved.tags.keys().collect::<Vec<_>>(),
+            vec!["opening", "year"]
+        );
+    }
+
+    #[test]
+    fn stale_or_invalid_patches_write_nothing() {
+        let mut db = legacy_db();
+        let first = write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                notes: Some("First".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let stale = write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                expected_version: Some(0),
+                notes: Some("Stale".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(stale, Err(Error::MetadataConflict(1))));
+
+        let invalid = write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                notes: Some("Invalid".to_string()),
+                tags: tags(&[("", Some("value"))]),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(invalid, Err(Error::InvalidMetadata(_))));
+        assert_eq!(read_metadata(&mut db).unwrap(), first);
+
+        // A failed first edit doesn't leave the table behind either.
+        let mut db = legacy_db();
+        assert!(write_metadata(
+            &mut db,
+            DatabaseMetadataPatch {
+                expected_version: Some(3),
+                ..Default::default()
+            },
+        )
+        .is_err());
+        assert!(!metadata_table_exists(&mut db).unwrap());
+    }
+}
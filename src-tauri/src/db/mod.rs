@@ -1,37 +1,69 @@
+mod anonymize;
+mod attribution;
+mod batch_import;
+mod clone;
+mod compact;
 mod core;
+mod create;
+mod duplicates;
 mod encoding;
+mod export;
+mod game_cache;
+mod global_search;
+mod header_rules;
+mod heatmap;
+mod identity;
+mod king_safety;
+mod links;
+mod maintenance;
+mod metadata;
 mod models;
 mod ops;
 mod pgn;
+mod pgn_update;
+mod player_metadata;
+mod provenance;
+mod rarity;
+mod reevaluate;
+mod repertoire;
+mod result_handles;
 mod schema;
 mod search;
+mod structure;
+mod transform;
+mod trash;
+mod tree;
+mod watcher;
 
 use crate::{
     db::{encoding::extract_main_line_moves, models::*, ops::*, schema::*},
     error::{Error, Result},
     opening::get_opening_from_setup,
+    tasks::{TaskHandle, TaskKind},
     AppState,
 };
 use dashmap::DashMap;
 use diesel::{
-    connection::{DefaultLoadingMode, SimpleConnection},
+    connection::SimpleConnection,
     insert_into,
     prelude::*,
     r2d2::{ConnectionManager, Pool},
     sql_query,
     sql_types::Text,
 };
-use pgn::{GameTree, Importer, TempGame};
+use pgn::{GameTree, GameTreeNode, Importer, TempGame};
 use pgn_reader::BufferedReader;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use shakmaty::{fen::Fen, Board, CastlingMode, Chess, EnPassantMode, FromSetup, Piece, Position};
 use specta::Type;
-use std::io::{BufWriter, Write};
 use std::{
-    fs::{remove_file, File, OpenOptions},
+    fs::{remove_file, File},
     path::PathBuf,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tauri::{path::BaseDirectory, Manager};
@@ -40,12 +72,72 @@
 use log::info;
 use tauri_specta::Event as _;
 
+pub use self::attribution::{get_analysis_attribution, AnalysisAttribution};
+pub use self::batch_import::{convert_pgn_batch, BatchImportFailure, BatchImportReport};
+pub use self::clone::{clone_games_to_database, CloneReport, GameSelection};
+pub use self::compact::{compact_database, CompactFailure, CompactReport};
+pub use self::create::{create_database, DatabaseExtras, NewDatabaseOptions};
+pub use self::duplicates::{
+    delete_duplicated_games, find_duplicate_games, DuplicateGame, DuplicateKind, DuplicatePolicy,
+    DuplicateReport,
+};
+pub use self::encoding::DecodeError;
+pub use self::export::{
+    compute_db_content_hash, export_annotated_pgn, export_to_pgn, ExportSort, PositionAnnotation,
+};
+pub use self::game_cache::{get_game_cache_stats, get_game_with_prefetch, GameCache};
+pub use self::global_search::{global_search, GlobalSearchHit, GlobalSearchResults, SearchHitKind};
+pub use self::header_rules::{
+    get_header_rules, set_header_rules, test_header_rules, HeaderAction, HeaderMapping,
+    HeaderMatch, HeaderRule, HeaderRuleSet, HeaderRulesConfig, HeaderRulesTest, PgnHeader,
+};
+pub use self::heatmap::{compute_move_heatmap, HeatmapOptions, MoveHeatmap, PieceHeatmap};
+pub use self::identity::{
+    get_identity_report, link_player_identity, list_player_identities, unlink_player_identity,
+    IdentityReport, IdentityReportQuery, PlayerIdentity,
+};
+pub use self::king_safety::{get_king_safety_report, KingSafetyReport, KingSafetyResults};
+pub use self::links::{get_linked_games, link_games, unlink_games, GameLink, LinkedGames};
+pub use self::maintenance::{optimize_database, OptimizeOptions, OptimizeReport};
+pub use self::metadata::{
+    get_db_metadata, set_db_metadata, DatabaseMetadata, DatabaseMetadataPatch,
+};
 pub use self::models::NormalizedGame;
+pub use self::models::PlayerMetadata;
 pub use self::models::Puzzle;
+pub use self::pgn_update::{update_database_from_pgn, PgnUpdateReport};
+pub use self::player_metadata::{fetch_player_metadata, get_player_metadata_bulk};
+pub use self::provenance::{
+    GameProvenance, OnlineAccount, ProvenanceCount, ProvenanceKind, ProvenanceQuery,
+};
+pub use self::rarity::compute_opening_frequencies;
+pub use self::reevaluate::{reevaluate_variations, ReevaluationReport};
+pub use self::repertoire::{
+    export_repertoire, RepertoireColor, RepertoireFormat, RepertoireNode, RepertoireSource,
+};
+pub use self::result_handles::{
+    fetch_result_chunk, query_games_handle, release_result_handle, search_position_handle,
+    ResultHandle, ResultHandles,
+};
 pub use self::schema::puzzles;
 pub use self::search::{
     is_position_in_db, search_position, PositionQuery, PositionQueryJs, PositionStats,
+    PositionVariant, SearchPartialResult, SideToMove,
+};
+pub use self::structure::{
+    classify_pawn_structures, get_pawn_structure_counts, PawnStructure, PawnStructureCount,
+};
+pub use self::transform::{transform_game, transform_position, PositionTransform, TransformedGame};
+pub use self::trash::{
+    list_trashed_databases, purge_trash_on_startup, restore_trashed_database, TrashedDatabase,
 };
+pub use self::tree::{
+    get_game_tree, get_node_details, FlatGameTree, GameSource, NodeDetails, TreeNode,
+};
+pub use self::watcher::{watch_databases, DatabaseInfoChanged, DatabaseSummary, DatabaseWatcher};
+
+/// Info entry holding the FEN that games stored without one start from.
+const START_FEN_KEY: &str = "StartFen";
 
 const INDEXES_SQL: &str = include_str!("../../../database/queries/indexes/create_indexes.sql");
 const DELETE_INDEXES_SQL: &str =
@@ -61,8 +153,6 @@
 
 // Games queries
 const GAMES_CHECK_INDEXES: &str = include_str!("../../../database/queries/games/check_indexes.sql");
-const GAMES_DELETE_DUPLICATES: &str =
-    include_str!("../../../database/queries/games/delete_duplicates.sql");
 
 const WHITE_PAWN: Piece = Piece {
     color: shakmaty::Color::White,
@@ -84,6 +174,21 @@ fn get_pawn_home(board: &Board) -> u16 {
     (second_rank_pawns as u16) | ((seventh_rank_pawns as u16) << 8)
 }
 
+/// Returns the bit of `square` in the `get_pawn_home` representation for pawns of `color`,
+/// or 0 when the square is not on that color's home rank.
+fn pawn_home_bit(color: shakmaty::Color, square: shakmaty::Square) -> u16 {
+    let (first, shift) = match color {
+        shakmaty::Color::White => (8, 0),
+        shakmaty::Color::Black => (48, 8),
+    };
+    let index = u32::from(square);
+    if (first..first + 8).contains(&index) {
+        1 << (index - first + shift)
+    } else {
+        0
+    }
+}
+
 #[derive(Debug)]
 pub enum JournalMode {
     Delete,
@@ -142,6 +247,8 @@ fn get_db_or_create(
     let pool = match state.connection_pool.get(db_path) {
         Some(pool) => pool.clone(),
         None => {
+            // Cached pools were checked when they were created.
+            state.path_scope.check(db_path)?;
             let pool = Pool::builder()
                 .max_size(16)
                 .connection_customizer(Box::new(options))
@@ -153,9 +260,20 @@ fn get_db_or_create(
         }
     };
 
+    state.db_watcher.note_use(db_path);
     Ok(pool.get()?)
 }
 
+/// Lock serializing the operations that write a whole database, such as imports and
+/// `optimize_database`.
+fn write_lock(state: &State<AppState>, db_path: &str) -> Arc<tokio::sync::Mutex<()>> {
+    state
+        .db_write_locks
+        .entry(db_path.to_string())
+        .or_default()
+        .clone()
+}
+
 #[derive(Default, Debug, Serialize)]
 pub struct TempPlayer {
     id: usize,
@@ -163,8 +281,10 @@ pub struct TempPlayer {
     rating: Option<i32>,
 }
 
-pub fn insert_to_db(db: &mut SqliteConnection, game: &TempGame) -> Result<()> {
-    let pawn_home = get_pawn_home(game.position.board());
+/// Insert an imported game, returning its ID.
+pub fn insert_to_db(db: &mut SqliteConnection, game: &TempGame) -> Result<i32> {
+    // The final pawn structure bounds which positions the game can contain.
+    let pawn_home = get_pawn_home(&game.final_board);
 
     let white_id = if let Some(name) = &game.white_name {
         create_player(db, name)?.id
@@ -217,11 +337,54 @@ pub fn insert_to_db(db: &mut SqliteConnection, game: &TempGame) -> Result<()> {
         pawn_home: pawn_home as i32,
     };
 
-    core::add_game(db, new_game)?;
+    let inserted = core::add_game(db, new_game)?;
+    structure::store_structure(db, inserted.id, game.structure)?;
 
+    Ok(inserted.id)
+}
+
+/// Store the game, player, event and site counts in the info table.
+fn update_info_counts(db: &mut SqliteConnection) -> Result<()> {
+    let game_count: i64 = games::table.count().get_result(db)?;
+    let player_count: i64 = players::table.count().get_result(db)?;
+    let event_count: i64 = events::table.count().get_result(db)?;
+    let site_count: i64 = sites::table.count().get_result(db)?;
+
+    let counts = [
+        ("GameCount", game_count),
+        ("PlayerCount", player_count),
+        ("EventCount", event_count),
+        ("SiteCount", site_count),
+    ];
+
+    for c in counts.iter() {
+        insert_into(info::table)
+            .values((info::name.eq(c.0), info::value.eq(c.1.to_string())))
+            .on_conflict(info::name)
+            .do_update()
+            .set(info::value.eq(c.1.to_string()))
+            .execute(db)?;
+    }
     Ok(())
 }
 
+/// Read a PGN file, decompressing it by its extension.
+fn decompress(
+    file: impl std::io::Read + Send + 'static,
+    extension: Option<&std::ffi::OsStr>,
+) -> Result<Box<dyn std::io::Read + Send>> {
+    Ok(if extension == Some("bz2".as_ref()) {
+        Box::new(bzip2::read::MultiBzDecoder::new(file))
+    } else if extension == Some("zst".as_ref()) {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Import the games of a PGN file into a database, creating it if needed.
+///
+/// Games are recorded as coming from the file, or from `account` for downloaded games.
 #[tauri::command]
 #[specta::specta]
 pub async fn convert_pgn(
@@ -231,11 +394,17 @@ pub async fn convert_pgn(
     app: tauri::AppHandle,
     title: String,
     description: Option<String>,
+    study: Option<bool>,
+    account: Option<OnlineAccount>,
     state: tauri::State<'_, AppState>,
 ) -> Result<()> {
+    state.path_scope.check(&file)?;
     let description = description.unwrap_or_default();
     let extension = file.extension();
 
+    let lock = write_lock(&state, db_path.to_str().unwrap());
+    let _guard = lock.lock().await;
+
     let db_exists = db_path.exists();
 
     // create the database file
@@ -244,29 +413,32 @@ pub async fn convert_pgn(
         db_path.to_str().unwrap(),
         ConnectionOptions {
             enable_foreign_keys: false,
-            busy_timeout: None,
             journal_mode: JournalMode::Off,
+            ..Default::default()
         },
     )?;
 
     if !db_exists {
         core::init_db(db, &title, &description)?;
     }
-
-    let file = File::open(&file)?;
-
-    let uncompressed: Box<dyn std::io::Read + Send> = if extension == Some("bz2".as_ref()) {
-        Box::new(bzip2::read::MultiBzDecoder::new(file))
-    } else if extension == Some("zst".as_ref()) {
-        Box::new(zstd::Decoder::new(file)?)
-    } else {
-        Box::new(file)
+    structure::ensure_structure_table(db)?;
+    provenance::ensure_sources_table(db)?;
+    rarity::ensure_frequency_table(db)?;
+    let source = match &account {
+        Some(account) => GameProvenance::online(account),
+        None => GameProvenance::file(&file),
     };
 
+    let uncompressed = decompress(File::open(&file)?, extension)?;
+
     // start counting time
     let start = Instant::now();
 
-    let mut importer = Importer::new(timestamp.map(|t| t as i64));
+    // Lichess studies are detected per game unless `study` says otherwise.
+    let mut importer = Importer::new(timestamp.map(|t| t as i64))
+        .study_mode(study)
+        .header_rules(header_rules::load_header_rules(&app)?);
+    let mut links = links::LinkImport::default();
     db.transaction::<_, Error, _>(|db| {
         for (i, game) in BufferedReader::new(uncompressed)
             .into_iter(&mut importer)
@@ -278,8 +450,11 @@ pub async fn convert_pgn(
                 let elapsed = start.elapsed().as_millis() as u32;
                 app.emit("convert_progress", (i, elapsed)).unwrap();
             }
-            insert_to_db(db, &game)?;
+            let id = insert_to_db(db, &game)?;
+            provenance::record_source(db, id, &source)?;
+            links.record(id, &game);
         }
+        links.finish(db)?;
         Ok(())
     })?;
 
@@ -288,31 +463,70 @@ pub async fn convert_pgn(
         db.batch_execute(INDEXES_SQL)?;
     }
 
-    // get game, player, event and site counts and to the info table
-    let game_count: i64 = games::table.count().get_result(db)?;
-    let player_count: i64 = players::table.count().get_result(db)?;
-    let event_count: i64 = events::table.count().get_result(db)?;
-    let site_count: i64 = sites::table.count().get_result(db)?;
-
-    let counts = [
-        ("GameCount", game_count),
-        ("PlayerCount", player_count),
-        ("EventCount", event_count),
-        ("SiteCount", site_count),
-    ];
+    update_info_counts(db)?;
 
-    for c in counts.iter() {
-        insert_into(info::table)
-            .values((info::name.eq(c.0), info::value.eq(c.1.to_string())))
-            .on_conflict(info::name)
-            .do_update()
-            .set(info::value.eq(c.1.to_string()))
-            .execute(db)?;
+    if !db_exists {
+        if let Some(fen) = common_start_fen(db)? {
+            info!("All imported games start from {}", fen);
+            set_start_fen(db, Some(&fen))?;
+        }
     }
 
+    // Pools are cached per path, so drop the unjournaled import pool to make later
+    // commands connect with the default options again.
+    state.connection_pool.remove(db_path.to_str().unwrap());
+    state.db_watcher.touch(&db_path);
+
     Ok(())
 }
 
+/// Import the games of `pgn` into the database at `db_path`, creating it with `title` if
+/// it doesn't exist. Returns the number of games imported.
+pub(crate) async fn import_pgn_games(
+    state: &State<'_, AppState>,
+    db_path: &std::path::Path,
+    title: &str,
+    pgn: &str,
+) -> Result<usize> {
+    let lock = write_lock(state, db_path.to_str().unwrap());
+    let _guard = lock.lock().await;
+
+    let db_exists = db_path.exists();
+    let db = &mut get_db_or_create(
+        state,
+        db_path.to_str().unwrap(),
+        ConnectionOptions::default(),
+    )?;
+    if !db_exists {
+        core::init_db(db, title, "")?;
+    }
+    structure::ensure_structure_table(db)?;
+    provenance::ensure_sources_table(db)?;
+    rarity::ensure_frequency_table(db)?;
+
+    let mut importer = Importer::new(None);
+    let imported = db.transaction::<_, Error, _>(|db| {
+        let mut imported = 0;
+        for game in BufferedReader::new(pgn.as_bytes())
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+        {
+            insert_to_db(db, &game)?;
+            imported += 1;
+        }
+        Ok(imported)
+    })?;
+
+    if !db_exists {
+        db.batch_execute(INDEXES_SQL)?;
+    }
+    update_info_counts(db)?;
+    state.db_watcher.touch(db_path);
+
+    Ok(imported)
+}
+
 #[derive(Serialize, Type)]
 pub struct DatabaseInfo {
     title: String,
@@ -323,12 +537,18 @@ pub struct DatabaseInfo {
     storage_size: i64,
     filename: String,
     indexed: bool,
+    start_fen: Option<String>,
+    /// Set when enough games were deleted for `optimize_database` to be worthwhile.
+    needs_optimize: bool,
+    metadata: DatabaseMetadata,
+    /// Number of games from each source.
+    sources: Vec<ProvenanceCount>,
 }
 
 #[derive(QueryableByName, Debug, Serialize)]
 struct IndexInfo {
     #[diesel(sql_type = Text, column_name = "name")]
-    _name: String,
+    name: String,
 }
 
 fn check_index_exists(conn: &mut SqliteConnection) -> Result<bool> {
@@ -378,6 +598,10 @@ pub async fn get_db_info(
     let filename = path.file_name().expect("get filename").to_string_lossy();
 
     let is_indexed = check_index_exists(db)?;
+    let start_fen = get_start_fen(db)?;
+    let needs_optimize = maintenance::needs_optimize(db)?;
+    let metadata = metadata::read_metadata(db)?;
+    let sources = provenance::source_counts(db)?;
     Ok(DatabaseInfo {
         title,
         description,
@@ -387,19 +611,150 @@ pub async fn get_db_info(
         storage_size,
         filename: filename.to_string(),
         indexed: is_indexed,
+        start_fen,
+        needs_optimize,
+        metadata,
+        sources,
     })
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn create_indexes(file: PathBuf, state: tauri::State<'_, AppState>) -> Result<()> {
-    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+pub async fn create_indexes(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<()> {
+    let names = expected_indexes()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    build_indexes(&file, names, &state, &app).await
+}
+
+/// Create a single expected index, e.g. one reported missing by `get_index_status`.
+#[tauri::command]
+#[specta::specta]
+pub async fn create_index(
+    file: PathBuf,
+    name: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<()> {
+    check_index_name(&name)?;
+    build_indexes(&file, vec![name], &state, &app).await
+}
+
+/// Fail unless `name` is one of the expected indexes.
+fn check_index_name(name: &str) -> Result<()> {
+    if expected_indexes()
+        .iter()
+        .any(|(expected, _)| expected == name)
+    {
+        Ok(())
+    } else {
+        Err(Error::UnknownIndex(name.to_string()))
+    }
+}
+
+/// Name and `CREATE INDEX` statement of every index the games queries rely on.
+fn expected_indexes() -> Vec<(String, String)> {
+    INDEXES_SQL
+        .split(';')
+        .filter_map(|stmt| {
+            let stmt = stmt
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("--"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let name = stmt
+                .split_whitespace()
+                .skip_while(|word| !word.eq_ignore_ascii_case("EXISTS"))
+                .nth(1)?
+                .to_string();
+            Some((name, stmt.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Build the given indexes one at a time.
+///
+/// Each index is created in its own transaction and the connection is released in
+/// between, so other commands only wait for the index being built rather than the
+/// whole batch. An interrupted build leaves every finished index in place and rolls
+/// back the one in progress. Progress is reported through `DatabaseProgress` events
+/// whose id is the database path.
+async fn build_indexes(
+    file: &PathBuf,
+    names: Vec<String>,
+    state: &State<'_, AppState>,
+    app: &tauri::AppHandle,
+) -> Result<()> {
+    let id = file.to_string_lossy().to_string();
+    let indexes: Vec<_> = expected_indexes()
+        .into_iter()
+        .filter(|(name, _)| names.contains(name))
+        .collect();
+
+    let task = TaskHandle::start(app, TaskKind::Database, &id, false);
+    for (i, (name, stmt)) in indexes.iter().enumerate() {
+        let progress = (i as f64 / indexes.len() as f64) * 100_f64;
+        DatabaseProgress {
+            id: id.clone(),
+            progress,
+            stage: Some(name.clone()),
+        }
+        .emit(app)?;
+        task.report(progress, Some(name.clone()));
+
+        {
+            let db = &mut get_db_or_create(state, &id, ConnectionOptions::default())?;
+            db.immediate_transaction::<_, Error, _>(|db| {
+                db.batch_execute(stmt)?;
+                Ok(())
+            })?;
+        }
+        info!("Created index {} on {}", name, id);
 
-    db.batch_execute(INDEXES_SQL)?;
+        tokio::task::yield_now().await;
+    }
+
+    DatabaseProgress {
+        id,
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(app)?;
+    task.finish();
 
     Ok(())
 }
 
+#[derive(Serialize, Type)]
+pub struct IndexStatus {
+    pub name: String,
+    pub present: bool,
+}
+
+/// List the expected indexes of a database and whether each one exists.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_index_status(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<IndexStatus>> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let existing: Vec<IndexInfo> = sql_query(GAMES_CHECK_INDEXES).load(db)?;
+
+    Ok(expected_indexes()
+        .into_iter()
+        .map(|(name, _)| IndexStatus {
+            present: existing.iter().any(|index| index.name == name),
+            name,
+        })
+        .collect())
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn delete_indexes(file: PathBuf, state: tauri::State<'_, AppState>) -> Result<()> {
@@ -416,6 +771,7 @@ pub async fn edit_db_info(
     file: PathBuf,
     title: Option<String>,
     description: Option<String>,
+    start_fen: Option<String>,
     state: tauri::State<'_, AppState>,
 ) -> Result<()> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
@@ -441,9 +797,73 @@ pub async fn edit_db_info(
             .execute(db)?;
     }
 
+    // An empty FEN clears the start position, falling back to the standard one.
+    if let Some(start_fen) = start_fen {
+        let start_fen = start_fen.trim();
+        if start_fen.is_empty() {
+            set_start_fen(db, None)?;
+        } else {
+            Fen::from_ascii(start_fen.as_bytes())?
+                .into_position::<Chess>(CastlingMode::Chess960)?;
+            set_start_fen(db, Some(start_fen))?;
+        }
+        // Cached searches were computed against the previous start position.
+        state.line_cache.lock().unwrap().clear();
+        state.db_cache.lock().unwrap().clear();
+    }
+
+    state.db_watcher.touch(&file);
+    Ok(())
+}
+
+/// The FEN games stored without one start from, if it isn't the standard position.
+pub(crate) fn get_start_fen(db: &mut SqliteConnection) -> Result<Option<String>> {
+    Ok(info::table
+        .filter(info::name.eq(START_FEN_KEY))
+        .select(info::value)
+        .first::<Option<String>>(db)
+        .optional()?
+        .flatten())
+}
+
+/// The position games stored without a FEN start from.
+pub(crate) fn get_start_position(db: &mut SqliteConnection) -> Result<Chess> {
+    match get_start_fen(db)? {
+        Some(fen) => Ok(Fen::from_ascii(fen.as_bytes())?.into_position(CastlingMode::Chess960)?),
+        None => Ok(Chess::default()),
+    }
+}
+
+fn set_start_fen(db: &mut SqliteConnection, fen: Option<&str>) -> Result<()> {
+    match fen {
+        Some(fen) => {
+            insert_into(info::table)
+                .values((info::name.eq(START_FEN_KEY), info::value.eq(fen)))
+                .on_conflict(info::name)
+                .do_update()
+                .set(info::value.eq(fen))
+                .execute(db)?;
+        }
+        None => {
+            diesel::delete(info::table.filter(info::name.eq(START_FEN_KEY))).execute(db)?;
+        }
+    }
     Ok(())
 }
 
+/// The custom FEN shared by every game of the database, if there is one.
+fn common_start_fen(db: &mut SqliteConnection) -> Result<Option<String>> {
+    let fens: Vec<Option<String>> = games::table
+        .select(games::fen)
+        .distinct()
+        .limit(2)
+        .load(db)?;
+    Ok(match fens.as_slice() {
+        [Some(fen)] => Some(fen.clone()),
+        _ => None,
+    })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Type)]
 pub enum Sides {
     BlackWhite,
@@ -466,6 +886,10 @@ pub enum GameSort {
     AverageElo,
     #[serde(rename = "ply_count")]
     PlyCount,
+    /// By how few games of the database share the game's opening, rarest first when
+    /// descending. Games without an ECO code come last.
+    #[serde(rename = "openingRarity")]
+    OpeningRarity,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Type)]
@@ -530,6 +954,27 @@ pub struct GameQueryJs {
     pub position: Option<PositionQueryJs>,
     #[specta(optional)]
     pub wanted_result: Option<String>,
+    #[specta(optional)]
+    pub pawn_structure: Option<PawnStructure>,
+    /// Position searches only: minimum rating of the player to move in the searched
+    /// position. Games where that player has no rating are left out.
+    #[specta(optional)]
+    pub min_elo_side_to_move: Option<i32>,
+    /// Position searches only: minimum average rating of both players. Games where either
+    /// player has no rating are left out.
+    #[specta(optional)]
+    pub min_avg_elo: Option<i32>,
+    /// Position searches only: the player who must be the one to move in the searched
+    /// position. Games where they had the other color there, or didn't play, are left out.
+    #[specta(optional)]
+    pub matched_side_player: Option<i32>,
+    /// Only games imported from this source.
+    #[specta(optional)]
+    pub source: Option<ProvenanceQuery>,
+    /// Only games whose opening is among the rarest of the database, taking in this
+    /// percentage of its games (or slightly more, as openings aren't split).
+    #[specta(optional)]
+    pub rarest_percent: Option<u32>,
 }
 
 impl GameQueryJs {
@@ -557,6 +1002,32 @@ pub async fn get_games(
 ) -> Result<QueryResponse<Vec<NormalizedGame>>> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
+    let mut normalized_games = Vec::new();
+    let count = query_games(db, query, |game| {
+        normalized_games.push(game);
+        Ok(())
+    })?;
+    provenance::attach_sources(db, &mut normalized_games)?;
+
+    Ok(QueryResponse {
+        data: normalized_games,
+        count: count.map(|c| c as i32),
+    })
+}
+
+/// SQL of the average rating of a game, or of its only rating, or 0 without any.
+const AVERAGE_ELO_SQL: &str =
+    "CASE WHEN Games.WhiteElo IS NOT NULL AND Games.BlackElo IS NOT NULL \
+     THEN (Games.WhiteElo + Games.BlackElo + 1) / 2 \
+     ELSE COALESCE(Games.WhiteElo, Games.BlackElo, 0) END";
+
+/// Run a games query, handing each game to `each` as it is read rather than loading them
+/// all, and returning the count of matching games unless skipped. Sources aren't attached.
+pub(crate) fn query_games(
+    db: &mut SqliteConnection,
+    query: GameQueryJs,
+    mut each: impl FnMut(NormalizedGame) -> Result<()>,
+) -> Result<Option<i64>> {
     let mut count: Option<i64> = None;
     let query_options = query.options.unwrap_or_default();
 
@@ -594,6 +1065,32 @@ pub async fn get_games(
         count_query = count_query.filter(games::event_id.eq(tournament_id));
     }
 
+    if let Some(pawn_structure) = query.pawn_structure {
+        structure::ensure_structure_table(db)?;
+        let with_structure = || {
+            game_structures::table
+                .filter(game_structures::structure.eq(pawn_structure.as_str()))
+                .select(game_structures::game_id)
+        };
+        sql_query = sql_query.filter(games::id.eq_any(with_structure()));
+        count_query = count_query.filter(games::id.eq_any(with_structure()));
+    }
+
+    if let Some(source) = &query.source {
+        provenance::ensure_sources_table(db)?;
+        sql_query = sql_query.filter(games::id.eq_any(provenance::games_from(source)));
+        count_query = count_query.filter(games::id.eq_any(provenance::games_from(source)));
+    }
+
+    if query.rarest_percent.is_some() || query_options.sort == GameSort::OpeningRarity {
+        rarity::ensure_frequency_table(db)?;
+    }
+    if let Some(percent) = query.rarest_percent {
+        let threshold = rarity::rarity_threshold(db, percent)?;
+        sql_query = sql_query.filter(games::eco.eq_any(rarity::openings_up_to(threshold)));
+        count_query = count_query.filter(games::eco.eq_any(rarity::openings_up_to(threshold)));
+    }
+
     if let Some(limit) = query_options.page_size {
         sql_query = sql_query.limit(limit as i64);
     }
@@ -723,13 +1220,17 @@ pub async fn get_games(
             SortDirection::Desc => sql_query.order(games::black_elo.desc()),
         },
         GameSort::AverageElo => {
-            // AverageElo will be sorted in Rust after calculating
-            sql_query
+            let average = diesel::dsl::sql::<diesel::sql_types::Integer>(AVERAGE_ELO_SQL);
+            match query_options.direction {
+                SortDirection::Asc => sql_query.order(average.asc()),
+                SortDirection::Desc => sql_query.order(average.desc()),
+            }
         }
         GameSort::PlyCount => match query_options.direction {
             SortDirection::Asc => sql_query.order(games::ply_count.asc()),
             SortDirection::Desc => sql_query.order(games::ply_count.desc()),
         },
+        GameSort::OpeningRarity => sql_query.order(rarity::rarity_order(&query_options.direction)),
     };
 
     if !query_options.skip_count {
@@ -740,48 +1241,15 @@ pub async fn get_games(
         );
     }
 
-    let games: Vec<(Game, Player, Player, Event, Site)> = sql_query.load(db)?;
-    let mut normalized_games = normalize_games(games)?;
-
-    // Sort by average ELO if needed (calculated in Rust)
-    if matches!(query_options.sort, GameSort::AverageElo) {
-        normalized_games.sort_by(|a, b| {
-            // Calculate average ELO: (white_elo + black_elo) / 2, rounded
-            // If only one ELO is available, use that one
-            // If neither is available, treat as 0 for sorting purposes
-            let a_avg = match (a.white_elo, a.black_elo) {
-                (Some(white), Some(black)) => {
-                    // Round the average (same as Math.round in TypeScript)
-                    let sum = white + black;
-                    Some((sum + 1) / 2) // This is equivalent to rounding for integers
-                }
-                (Some(elo), None) | (None, Some(elo)) => Some(elo),
-                (None, None) => None,
-            };
-            let b_avg = match (b.white_elo, b.black_elo) {
-                (Some(white), Some(black)) => {
-                    let sum = white + black;
-                    Some((sum + 1) / 2)
-                }
-                (Some(elo), None) | (None, Some(elo)) => Some(elo),
-                (None, None) => None,
-            };
-
-            // For sorting, treat None as 0 (lowest priority)
-            let a_val = a_avg.unwrap_or(0);
-            let b_val = b_avg.unwrap_or(0);
-
-            match query_options.direction {
-                SortDirection::Asc => a_val.cmp(&b_val),
-                SortDirection::Desc => b_val.cmp(&a_val), // Descending: higher ELO first
-            }
-        });
+    let rows = sql_query
+        .load_iter::<(Game, Player, Player, Event, Site), diesel::connection::DefaultLoadingMode>(
+            db,
+        )?;
+    for row in rows {
+        let (game, white, black, event, site) = row?;
+        each(core::normalize_game(game, white, black, event, site)?)?;
     }
-
-    Ok(QueryResponse {
-        data: normalized_games,
-        count: count.map(|c| c as i32),
-    })
+    Ok(count)
 }
 
 fn normalize_games(games: Vec<(Game, Player, Player, Event, Site)>) -> Result<Vec<NormalizedGame>> {
@@ -1006,6 +1474,8 @@ pub struct StatsData {
 pub struct DatabaseProgress {
     pub id: String,
     pub progress: f64,
+    /// What is currently being processed, e.g. the name of the index being built.
+    pub stage: Option<String>,
 }
 
 #[tauri::command]
@@ -1019,6 +1489,29 @@ pub async fn get_players_game_info(
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
     let timer = Instant::now();
 
+    let game_info = PlayerGameInfo {
+        site_stats_data: player_site_stats(db, id, |done, total| {
+            let _ = DatabaseProgress {
+                id: id.to_string(),
+                progress: (done as f64 / total as f64) * 100_f64,
+                stage: None,
+            }
+            .emit(&app);
+        })?,
+    };
+
+    println!("get_players_game_info {:?}: {:?}", file, timer.elapsed());
+
+    Ok(game_info)
+}
+
+/// Rated games of a player from the start position, by site, calling `on_progress` with the
+/// games done and the total every thousand games.
+pub(crate) fn player_site_stats(
+    db: &mut SqliteConnection,
+    id: i32,
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<Vec<SiteStatsData>> {
     let sql_query = games::table
         .inner_join(sites::table.on(games::site_id.eq(sites::id)))
         .inner_join(players::table.on(players::id.eq(id)))
@@ -1051,9 +1544,8 @@ pub async fn get_players_game_info(
     );
     let info: Vec<GameInfo> = sql_query.load(db)?;
 
-    let mut game_info = PlayerGameInfo::default();
     let progress = AtomicUsize::new(0);
-    game_info.site_stats_data = info
+    let site_stats_data = info
         .par_iter()
         .filter_map(
             |(
@@ -1120,11 +1612,7 @@ pub async fn get_players_game_info(
 
                 let p = progress.fetch_add(1, Ordering::Relaxed);
                 if p % 1000 == 0 || p == info.len() - 1 {
-                    let _ = DatabaseProgress {
-                        id: id.to_string(),
-                        progress: (p as f64 / info.len() as f64) * 100_f64,
-                    }
-                    .emit(&app);
+                    on_progress(p, info.len());
                 }
 
                 Some(SiteStatsData {
@@ -1169,222 +1657,221 @@ pub async fn get_players_game_info(
         .map(|((site, player), data)| SiteStatsData { site, player, data })
         .collect();
 
-    println!("get_players_game_info {:?}: {:?}", file, timer.elapsed());
-
-    Ok(game_info)
+    Ok(site_stats_data)
 }
 
+/// Delete a database, moving it to the trash unless `permanently_delete` is set.
 #[tauri::command]
 #[specta::specta]
-pub async fn delete_database(file: PathBuf, state: tauri::State<'_, AppState>) -> Result<()> {
-    let pool = &state.connection_pool;
+pub async fn delete_database(
+    file: PathBuf,
+    permanently_delete: bool,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<()> {
     let path_str = file.to_str().unwrap();
+    let lock = write_lock(&state, path_str);
+    let _guard = lock.lock().await;
+    state.game_cache.invalidate_file(path_str);
+
+    if !permanently_delete {
+        state.connection_pool.remove(path_str);
+        trash::move_to_trash(&app, file.clone()).await?;
+        state.db_watcher.forget(&file);
+        return Ok(());
+    }
+
+    let photos = player_metadata::stored_photo_paths(&file);
+
+    let pool = &state.connection_pool;
     pool.remove(path_str);
 
     // delete file
     remove_file(path_str)?;
+    state.db_watcher.forget(&file);
+
+    player_metadata::remove_orphaned_photos(&file, photos);
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn delete_empty_games(file: PathBuf, state: tauri::State<'_, AppState>) -> Result<()> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    let total: i64 = games::table.count().get_result(db)?;
+    let deleted = diesel::delete(games::table.filter(games::ply_count.eq(0))).execute(db)?;
+    maintenance::flag_if_needs_optimize(db, deleted, total)?;
+    watcher::record_game_count(db)?;
+    state.game_cache.invalidate_file(&file.to_string_lossy());
+
+    state.db_watcher.touch(&file);
     Ok(())
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn delete_duplicated_games(
+pub async fn delete_db_game(
     file: PathBuf,
+    game_id: i32,
     state: tauri::State<'_, AppState>,
 ) -> Result<()> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    db.batch_execute(GAMES_DELETE_DUPLICATES)?;
+    core::remove_game(db, game_id)?;
+    watcher::record_game_count(db)?;
+    state
+        .game_cache
+        .invalidate_game(&file.to_string_lossy(), game_id);
 
+    state.db_watcher.touch(&file);
     Ok(())
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn delete_empty_games(file: PathBuf, state: tauri::State<'_, AppState>) -> Result<()> {
+pub async fn get_game(
+    file: PathBuf,
+    game_id: i32,
+    state: tauri::State<'_, AppState>,
+) -> Result<NormalizedGame> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    diesel::delete(games::table.filter(games::ply_count.eq(0))).execute(db)?;
+    Ok(core::get_game(db, game_id)?)
+}
 
-    Ok(())
+/// Main line of a stored game, with the comments that follow each move.
+pub struct GameMainLine {
+    pub start_position: Chess,
+    pub time_control: Option<String>,
+    pub moves: Vec<String>,
+    pub comments: Vec<String>,
 }
 
-struct PgnGame {
-    event: Option<String>,
-    site: Option<String>,
-    date: Option<String>,
-    round: Option<String>,
-    white: Option<String>,
-    black: Option<String>,
-    result: Option<String>,
-    time_control: Option<String>,
-    eco: Option<String>,
-    white_elo: Option<String>,
-    black_elo: Option<String>,
-    ply_count: Option<String>,
-    fen: Option<String>,
-    moves: String,
-}
-
-impl PgnGame {
-    fn write(&self, writer: &mut impl Write) -> Result<()> {
-        writeln!(
-            writer,
-            "[Event \"{}\"]",
-            self.event.as_deref().unwrap_or("")
-        )?;
-        writeln!(writer, "[Site \"{}\"]", self.site.as_deref().unwrap_or(""))?;
-        writeln!(writer, "[Date \"{}\"]", self.date.as_deref().unwrap_or(""))?;
-        writeln!(
-            writer,
-            "[Round \"{}\"]",
-            self.round.as_deref().unwrap_or("")
-        )?;
-        writeln!(
-            writer,
-            "[White \"{}\"]",
-            self.white.as_deref().unwrap_or("")
-        )?;
-        writeln!(
-            writer,
-            "[Black \"{}\"]",
-            self.black.as_deref().unwrap_or("")
-        )?;
-        writeln!(
-            writer,
-            "[Result \"{}\"]",
-            self.result.as_deref().unwrap_or("*")
-        )?;
-        if let Some(time_control) = self.time_control.as_deref() {
-            writeln!(writer, "[TimeControl \"{}\"]", time_control)?;
-        }
-        if let Some(eco) = self.eco.as_deref() {
-            writeln!(writer, "[ECO \"{}\"]", eco)?;
-        }
-        if let Some(white_elo) = self.white_elo.as_deref() {
-            writeln!(writer, "[WhiteElo \"{}\"]", white_elo)?;
-        }
-        if let Some(black_elo) = self.black_elo.as_deref() {
-            writeln!(writer, "[BlackElo \"{}\"]", black_elo)?;
-        }
-        if let Some(ply_count) = self.ply_count.as_deref() {
-            writeln!(writer, "[PlyCount \"{}\"]", ply_count)?;
-        }
-        if let Some(fen) = self.fen.as_deref() {
-            writeln!(writer, "[SetUp \"1\"]")?;
-            writeln!(writer, "[FEN \"{}\"]", fen)?;
+/// Load the main line of a game, keeping the comments attached to each move
+/// so that embedded commands such as `[%clk ...]` or `[%eval ...]` can be read.
+pub fn get_game_main_line(
+    state: &State<AppState>,
+    file: &PathBuf,
+    game_id: i32,
+) -> Result<GameMainLine> {
+    let db = &mut get_db_or_create(state, file.to_str().unwrap(), ConnectionOptions::default())?;
+
+    let (moves, fen, time_control): (Vec<u8>, Option<String>, Option<String>) = games::table
+        .filter(games::id.eq(game_id))
+        .select((games::moves, games::fen, games::time_control))
+        .first(db)?;
+
+    let start_position = match fen {
+        Some(fen) => Chess::from_setup(
+            Fen::from_ascii(fen.as_bytes())?.into(),
+            CastlingMode::Chess960,
+        )?,
+        None => Chess::default(),
+    };
+
+    let tree = GameTree::from_bytes(&moves, Some(start_position.clone()))?;
+    let mut main_line = GameMainLine {
+        start_position,
+        time_control,
+        moves: Vec::new(),
+        comments: Vec::new(),
+    };
+
+    for node in tree.nodes() {
+        match node {
+            GameTreeNode::Move(san) => {
+                main_line.moves.push(san.to_string());
+                main_line.comments.push(String::new());
+            }
+            GameTreeNode::Comment(comment) => {
+                if let Some(last) = main_line.comments.last_mut() {
+                    if !last.is_empty() {
+                        last.push(' ');
+                    }
+                    last.push_str(comment);
+                }
+            }
+            _ => {}
         }
-        writeln!(writer)?;
-        writer.write(self.moves.as_bytes())?;
-        match self.result.as_deref() {
-            Some("1-0") => writeln!(writer, "1-0"),
-            Some("0-1") => writeln!(writer, "0-1"),
-            Some("1/2-1/2") => writeln!(writer, "1/2-1/2"),
-            _ => writeln!(writer, "*"),
-        }?;
-        writeln!(writer)?;
-        Ok(())
     }
+
+    Ok(main_line)
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn export_to_pgn(
+pub async fn update_game(
     file: PathBuf,
-    dest_file: PathBuf,
+    game_id: i32,
+    update: UpdateGame,
+    tab: Option<String>,
     state: tauri::State<'_, AppState>,
 ) -> Result<()> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(dest_file)?;
-
-    let mut writer = BufWriter::new(file);
-
-    let (white_players, black_players) = diesel::alias!(players as white, players as black);
-    games::table
-        .inner_join(white_players.on(games::white_id.eq(white_players.field(players::id))))
-        .inner_join(black_players.on(games::black_id.eq(black_players.field(players::id))))
-        .inner_join(events::table.on(games::event_id.eq(events::id)))
-        .inner_join(sites::table.on(games::site_id.eq(sites::id)))
-        .load_iter::<(Game, Player, Player, Event, Site), DefaultLoadingMode>(db)?
-        .flatten()
-        .map(|(game, white, black, event, site)| {
-            let pgn = PgnGame {
-                event: event.name,
-                site: site.name,
-                date: game.date,
-                round: game.round,
-                white: white.name,
-                black: black.name,
-                result: game.result,
-                time_control: game.time_control,
-                eco: game.eco,
-                white_elo: game.white_elo.map(|e| e.to_string()),
-                black_elo: game.black_elo.map(|e| e.to_string()),
-                ply_count: game.ply_count.map(|e| e.to_string()),
-                fen: game.fen.clone(),
-                moves: GameTree::from_bytes(
-                    &game.moves,
-                    game.fen
-                        .map(|fen| Fen::from_ascii(fen.as_bytes()).ok())
-                        .flatten()
-                        .map(|fen| Chess::from_setup(fen.into(), CastlingMode::Chess960).ok())
-                        .flatten(),
-                )?
-                .to_string(),
-            };
-
-            pgn.write(&mut writer)?;
+    core::update_game(db, game_id, &update)?;
+    state
+        .game_cache
+        .invalidate_game(&file.to_string_lossy(), game_id);
 
-            Ok(())
-        })
-        .collect::<Result<Vec<_>>>()?;
+    state.dirty_tabs.record_write(tab.as_deref(), &file);
+    state.db_watcher.touch(&file);
     Ok(())
 }
 
+/// Get the revision of a game's moves, to be passed to `patch_game`.
 #[tauri::command]
 #[specta::specta]
-pub async fn delete_db_game(
+pub async fn get_game_revision(
     file: PathBuf,
     game_id: i32,
     state: tauri::State<'_, AppState>,
-) -> Result<()> {
+) -> Result<String> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    core::remove_game(db, game_id)?;
-
-    Ok(())
+    let moves: Vec<u8> = games::table.find(game_id).select(games::moves).first(db)?;
+    Ok(core::game_revision(&moves))
 }
 
+/// Apply fine-grained changes to a game instead of rewriting it.
+///
+/// Fails with a conflict if the game's moves changed since `expected_revision` was read.
+/// Returns the new revision.
+/// `tab` is the tab the changes come from, if any, for tracking unsaved games.
 #[tauri::command]
 #[specta::specta]
-pub async fn get_game(
+pub async fn patch_game(
     file: PathBuf,
     game_id: i32,
+    expected_revision: String,
+    ops: Vec<GamePatchOp>,
+    tab: Option<String>,
     state: tauri::State<'_, AppState>,
-) -> Result<NormalizedGame> {
+) -> Result<String> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    Ok(core::get_game(db, game_id)?)
+    let revision = core::patch_game(db, game_id, &expected_revision, &ops)?;
+    state
+        .game_cache
+        .invalidate_game(&file.to_string_lossy(), game_id);
+    state.dirty_tabs.record_write(tab.as_deref(), &file);
+    state.db_watcher.touch(&file);
+    Ok(revision)
 }
 
+/// Comment the move where a game leaves opening theory with the name of its opening,
+/// returning where and which opening, if any.
 #[tauri::command]
 #[specta::specta]
-pub async fn update_game(
+pub async fn annotate_opening(
     file: PathBuf,
     game_id: i32,
-    update: UpdateGame,
     state: tauri::State<'_, AppState>,
-) -> Result<()> {
+) -> Result<Option<crate::opening::OpeningAnnotation>> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
-    core::update_game(db, game_id, &update)?;
-
-    Ok(())
+    core::annotate_opening(db, game_id)
 }
 
 #[tauri::command]
@@ -1417,6 +1904,7 @@ pub async fn merge_players(
         .execute(db)?;
 
     diesel::delete(players::table.filter(players::id.eq(player1))).execute(db)?;
+    state.game_cache.invalidate_file(&file.to_string_lossy());
 
     let player_count: i64 = players::table.count().get_result(db)?;
     diesel::insert_into(info::table)
@@ -1429,6 +1917,7 @@ pub async fn merge_players(
         .set(info::value.eq(player_count.to_string()))
         .execute(db)?;
 
+    state.db_watcher.touch(&file);
     Ok(())
 }
 
@@ -1439,6 +1928,36 @@ pub fn clear_games(state: tauri::State<'_, AppState>) {
     state.clear();
 }
 
+/// A database in memory set up by the statements of `schema`, for the tests of the modules
+/// keeping their own tables.
+#[cfg(test)]
+pub(crate) fn memory_db(schema: &str) -> SqliteConnection {
+    let mut db = SqliteConnection::establish(":memory:").unwrap();
+    db.batch_execute(schema).unwrap();
+    db
+}
+
+/// A database in memory holding the games of `pgn`, for the tests of the database modules.
+#[cfg(test)]
+pub(crate) fn test_db(pgn: &str) -> SqliteConnection {
+    let mut db = memory_db("");
+    core::init_db(&mut db, "Test", "").unwrap();
+    import_test_games(&mut db, pgn);
+    db
+}
+
+/// Import the games of `pgn` into a test database, returning their ids.
+#[cfg(test)]
+fn import_test_games(db: &mut SqliteConnection, pgn: &str) -> Vec<i32> {
+    let mut importer = Importer::new(None);
+    BufferedReader::new_cursor(pgn)
+        .into_iter(&mut importer)
+        .flatten()
+        .flatten()
+        .map(|game| insert_to_db(db, &game).unwrap())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1458,4 +1977,23 @@ fn home_row() {
         let pawn_home = get_pawn_home(&Board::from_ascii_board_fen(b"8/8/8/8/8/8/8/8").unwrap());
         assert_eq!(pawn_home, 0b0000000000000000);
     }
+
+    #[test]
+    fn expected_indexes_are_parsed_from_sql() {
+        let indexes = expected_indexes();
+        assert_eq!(indexes.len(), 7);
+        assert_eq!(indexes[0].0, "games_date_idx");
+        assert!(indexes
+            .iter()
+            .all(|(name, stmt)| stmt.starts_with("CREATE INDEX") && stmt.contains(name.as_str())));
+    }
+
+    #[test]
+    fn only_expected_indexes_can_be_created() {
+        assert!(check_index_name("games_date_idx").is_ok());
+        assert!(matches!(
+            check_index_name("games_typo_idx"),
+            Err(Error::UnknownIndex(name)) if name == "games_typo_idx"
+        ));
+    }
 }
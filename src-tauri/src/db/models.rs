@@ -4,7 +4,7 @@
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-use crate::db::schema::*;
+use crate::db::{links::LinkedGames, provenance::GameProvenance, schema::*};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, Identifiable, Type)]
 #[diesel(table_name = puzzles)]
@@ -33,6 +33,19 @@ pub struct NewPlayer<'a> {
     pub elo: Option<i32>,
 }
 
+#[derive(
+    Default, Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize, Clone, Type,
+)]
+#[diesel(table_name = player_metadata)]
+pub struct PlayerMetadata {
+    pub player_id: i32,
+    pub federation: Option<String>,
+    pub title: Option<String>,
+    /// Absolute path of the cached photo inside the app data directory.
+    pub photo_path: Option<String>,
+    pub fide_id: Option<i32>,
+}
+
 /// Marker struct for Diesel associations representing the white player in a game.
 /// Used to establish the relationship between games and white players.
 struct White();
@@ -193,6 +206,14 @@ pub struct NormalizedGame {
     #[specta(optional)]
     pub ply_count: Option<i32>,
     pub moves: String,
+    /// Decoding problems in the stored moves; `moves` only holds what precedes them.
+    pub decode_warnings: u32,
+    /// Links from and to the game, only loaded for a single game.
+    #[specta(optional)]
+    pub links: Option<LinkedGames>,
+    /// Where the game came from, loaded by games lists and for a single game.
+    #[specta(optional)]
+    pub source: Option<GameProvenance>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Type)]
@@ -221,3 +242,34 @@ pub struct UpdateGame {
     pub ply_count: Option<i32>,
     pub moves: String,
 }
+
+/// A single change to a stored game, applied by `patch_game`.
+///
+/// Plies count main line moves from 1; ply 0 refers to the start of the game.
+#[derive(Serialize, Deserialize, Clone, Debug, Type)]
+#[serde(tag = "op")]
+pub enum GamePatchOp {
+    /// Replace the comment of a move, removing it when empty.
+    SetCommentAtPly {
+        ply: u32,
+        comment: String,
+    },
+    AddNagAtPly {
+        ply: u32,
+        nag: u8,
+    },
+    /// Remove the `index`-th variation branching off at a move.
+    RemoveVariation {
+        ply: u32,
+        index: u32,
+    },
+    /// Set a header, clearing it when `value` is `None`.
+    SetHeader {
+        name: String,
+        value: Option<String>,
+    },
+    /// Append moves in SAN notation to the main line.
+    AppendMovesToMainline {
+        moves: Vec<String>,
+    },
+}
@@ -1,10 +1,23 @@
+use crate::db::encoding::{self, BlobReader, DecodeError, Token};
+use crate::db::header_rules::{HeaderRules, PgnHeader};
+use crate::db::links::{extract_link_commands, PgnLink};
+use crate::db::structure::{board_pawns, classify_game, PawnStructure, DEFAULT_STRUCTURE_PLY};
 use crate::error::{Error, Result};
 use chrono::{NaiveDate, NaiveTime};
 use pgn_reader::{Nag, RawComment, RawHeader, SanPlus, Skip, Visitor};
-use shakmaty::{fen::Fen, Board, ByColor, Chess, FromSetup, Position, PositionError};
+use shakmaty::{
+    fen::Fen, Board, ByColor, CastlingMode, Chess, FromSetup, Position, PositionError, Role,
+};
 
 pub type MaterialCount = ByColor<u8>;
 
+/// Sites of games exported from lichess studies start with this.
+const LICHESS_STUDY_URL: &str = "https://lichess.org/study/";
+
+/// Comment marking a mainline that was promoted from a variation on import.
+pub const PROMOTED_MAINLINE_MARKER: &str =
+    "[%study] Mainline promoted from the first variation of the chapter";
+
 pub fn get_material_count(board: &Board) -> MaterialCount {
     board.material().map(|material| {
         material.pawn
@@ -15,6 +28,79 @@ pub fn get_material_count(board: &Board) -> MaterialCount {
     })
 }
 
+/// Returns the value a piece adds to the material count.
+pub fn piece_value(role: Role) -> u8 {
+    match role {
+        Role::Pawn => 1,
+        Role::Knight | Role::Bishop => 3,
+        Role::Rook => 5,
+        Role::Queen => 9,
+        Role::King => 0,
+    }
+}
+
+fn invalid_ply(ply: usize) -> Error {
+    Error::InvalidPatch(format!("No move at ply {}", ply))
+}
+
+/// Whether a comment line is an evaluation such as `+0.45/20 Stockfish 17` or `#-3/24 Stockfish 17`.
+fn is_eval_line(line: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let token = line.split_whitespace().next().unwrap_or_default();
+    let Some((score, depth)) = token.split_once('/') else {
+        return false;
+    };
+    let score_ok = match score.strip_prefix('#') {
+        Some(mate) => digits(mate.strip_prefix('-').unwrap_or(mate)),
+        None => score
+            .strip_prefix('+')
+            .or_else(|| score.strip_prefix('-'))
+            .and_then(|pawns| pawns.split_once('.'))
+            .is_some_and(|(int, frac)| digits(int) && frac.len() == 2 && digits(frac)),
+    };
+    score_ok && digits(depth)
+}
+
+/// Put `eval` on the first line of `comment`, replacing a previous evaluation and keeping
+/// the rest of the text.
+pub fn with_eval(comment: &str, eval: &str) -> String {
+    let text = match comment.split_once('\n') {
+        Some((first, rest)) if is_eval_line(first) => rest,
+        None if is_eval_line(comment) => "",
+        _ => comment,
+    };
+    if text.trim().is_empty() {
+        eval.to_string()
+    } else {
+        format!("{}\n{}", eval, text)
+    }
+}
+
+/// Put the `[%eval ...]` command `command` in `comment`, in place of the one it may already
+/// have, or else in front of the text.
+fn with_eval_command(comment: &str, command: &str) -> String {
+    let existing = comment
+        .find("[%eval ")
+        .and_then(|start| Some((start, start + comment[start..].find(']')? + 1)));
+    match existing {
+        Some((start, end)) => format!("{}{}{}", &comment[..start], command, &comment[end..]),
+        None if comment.trim().is_empty() => command.to_string(),
+        None => format!("{} {}", command, comment),
+    }
+}
+
+/// A move of a game tree to annotate with an evaluation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalTarget {
+    /// Indices of the enclosing variations and of the move, from the root of the tree.
+    pub path: Vec<usize>,
+    /// Moves from the start of the game to the position to evaluate, in UCI notation.
+    pub moves: Vec<String>,
+    /// Whether the position is the end of the variation starting with the move, rather
+    /// than the position right after it.
+    pub variation_end: bool,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum GameTreeNode {
     Move(SanPlus),
@@ -27,10 +113,10 @@ pub enum GameTreeNode {
 pub struct GameTree(Vec<GameTreeNode>);
 
 impl GameTree {
-    const START_VARIATION: u8 = 254;
-    const END_VARIATION: u8 = 253;
-    const COMMENT: u8 = 252;
-    const NAG: u8 = 251;
+    const START_VARIATION: u8 = encoding::START_VARIATION;
+    const END_VARIATION: u8 = encoding::END_VARIATION;
+    const COMMENT: u8 = encoding::COMMENT;
+    const NAG: u8 = encoding::NAG;
 
     pub fn new() -> Self {
         GameTree::default()
@@ -40,6 +126,11 @@ pub fn push(&mut self, node: GameTreeNode) {
         self.0.push(node);
     }
 
+    /// Add a comment in front of the whole game.
+    pub fn prepend_comment(&mut self, comment: &str) {
+        self.0.insert(0, GameTreeNode::Comment(comment.to_string()));
+    }
+
     pub fn count_main_line_moves(&self) -> usize {
         self.0
             .iter()
@@ -55,6 +146,315 @@ pub fn nodes(&self) -> &Vec<GameTreeNode> {
         &self.0
     }
 
+    /// Make the first variation of a tree without main line moves its main line.
+    ///
+    /// The other top-level variations become alternatives to the first move of the promoted
+    /// line, and `marker` is added as a comment in front of it. Returns whether anything was
+    /// promoted.
+    pub fn promote_first_variation(&mut self, marker: &str) -> bool {
+        if self.count_main_line_moves() > 0 {
+            return false;
+        }
+        let Some(first) = self
+            .0
+            .iter()
+            .position(|node| matches!(node, GameTreeNode::Variation(_)))
+        else {
+            return false;
+        };
+
+        let mut nodes = std::mem::take(&mut self.0);
+        let GameTreeNode::Variation(promoted) = nodes.remove(first) else {
+            unreachable!()
+        };
+        let (alternatives, leading): (Vec<_>, Vec<_>) = nodes
+            .into_iter()
+            .partition(|node| matches!(node, GameTreeNode::Variation(_)));
+
+        self.0 = leading;
+        self.0.push(GameTreeNode::Comment(marker.to_string()));
+        let mut promoted = promoted.0.into_iter();
+        // Keep anything preceding the first move (e.g. a comment) in front of it.
+        for node in promoted.by_ref() {
+            let is_move = matches!(node, GameTreeNode::Move(_));
+            self.0.push(node);
+            if is_move {
+                break;
+            }
+        }
+        self.0.extend(alternatives);
+        self.0.extend(promoted);
+        true
+    }
+
+    /// Index of the node of the `ply`-th main line move, counting from 1.
+    fn move_index(&self, ply: usize) -> Result<usize> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node, GameTreeNode::Move(_)))
+            .nth(ply.checked_sub(1).ok_or_else(|| invalid_ply(ply))?)
+            .map(|(i, _)| i)
+            .ok_or_else(|| invalid_ply(ply))
+    }
+
+    /// Nodes following the move at `ply` (or starting the game for ply 0) up to the next move
+    /// or variation, i.e. its comments and NAGs.
+    fn annotation_range(&self, ply: usize) -> Result<std::ops::Range<usize>> {
+        let start = match ply {
+            0 => 0,
+            ply => self.move_index(ply)? + 1,
+        };
+        let end = self.0[start..]
+            .iter()
+            .position(|node| matches!(node, GameTreeNode::Move(_) | GameTreeNode::Variation(_)))
+            .map_or(self.0.len(), |i| start + i);
+        Ok(start..end)
+    }
+
+    /// Replace the comments of the move at `ply` (0 for the game comment), removing them if
+    /// `comment` is empty.
+    pub fn set_comment(&mut self, ply: usize, comment: &str) -> Result<()> {
+        let range = self.annotation_range(ply)?;
+        let mut annotations: Vec<_> = self
+            .0
+            .splice(range.clone(), [])
+            .filter(|node| !matches!(node, GameTreeNode::Comment(_)))
+            .collect();
+        if !comment.is_empty() {
+            annotations.push(GameTreeNode::Comment(comment.to_string()));
+        }
+        self.0.splice(range.start..range.start, annotations);
+        Ok(())
+    }
+
+    /// Add a comment after the other comments of the move at `ply` (0 for the game comment),
+    /// unless it already has it.
+    pub fn add_comment(&mut self, ply: usize, comment: &str) -> Result<()> {
+        let range = self.annotation_range(ply)?;
+        let comment = GameTreeNode::Comment(comment.to_string());
+        if !self.0[range.clone()].contains(&comment) {
+            self.0.insert(range.end, comment);
+        }
+        Ok(())
+    }
+
+    /// Add a NAG to the move at `ply`, unless it already has it.
+    pub fn add_nag(&mut self, ply: usize, nag: Nag) -> Result<()> {
+        if ply == 0 {
+            return Err(invalid_ply(ply));
+        }
+        let range = self.annotation_range(ply)?;
+        if self.0[range.clone()].contains(&GameTreeNode::Nag(nag)) {
+            return Ok(());
+        }
+        // NAGs go right after the move and its other NAGs, before any comment.
+        let at = self.0[range.clone()]
+            .iter()
+            .position(|node| !matches!(node, GameTreeNode::Nag(_)))
+            .map_or(range.end, |i| range.start + i);
+        self.0.insert(at, GameTreeNode::Nag(nag));
+        Ok(())
+    }
+
+    /// Remove the `index`-th variation branching off at the move at `ply`.
+    pub fn remove_variation(&mut self, ply: usize, index: usize) -> Result<()> {
+        let start = self.move_index(ply)? + 1;
+        let variation = self.0[start..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, node)| !matches!(node, GameTreeNode::Move(_)))
+            .filter(|(_, node)| matches!(node, GameTreeNode::Variation(_)))
+            .nth(index)
+            .map(|(i, _)| start + i)
+            .ok_or_else(|| Error::InvalidPatch(format!("No variation {} at ply {}", index, ply)))?;
+        self.0.remove(variation);
+        Ok(())
+    }
+
+    /// Position at the end of the main line.
+    pub fn main_line_end(&self, position: Option<Chess>) -> Result<Chess> {
+        let mut position = position.unwrap_or_default();
+        for node in &self.0 {
+            if let GameTreeNode::Move(m) = node {
+                let mv = m.san.to_move(&position)?;
+                position.play_unchecked(&mv);
+            }
+        }
+        Ok(position)
+    }
+
+    /// Positions along the main line, starting with `position`.
+    pub fn main_line_positions(&self, position: Option<Chess>) -> Result<Vec<Chess>> {
+        let mut position = position.unwrap_or_default();
+        let mut positions = vec![position.clone()];
+        for node in &self.0 {
+            if let GameTreeNode::Move(m) = node {
+                let mv = m.san.to_move(&position)?;
+                position.play_unchecked(&mv);
+                positions.push(position.clone());
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Append moves in SAN notation to the main line, checking that they are legal.
+    pub fn append_moves(&mut self, moves: &[String], position: Option<Chess>) -> Result<()> {
+        let mut position = self.main_line_end(position)?;
+        let mut appended = Vec::with_capacity(moves.len());
+        for m in moves {
+            let mv = SanPlus::from_ascii(m.as_bytes())?.san.to_move(&position)?;
+            appended.push(GameTreeNode::Move(SanPlus::from_move_and_play_unchecked(
+                &mut position,
+                &mv,
+            )));
+        }
+        self.0.extend(appended);
+        Ok(())
+    }
+
+    /// Moves to annotate with an evaluation: the first move of every variation, for the end of
+    /// the variation, and with `every_node` every other move too.
+    ///
+    /// Targets come in reverse order, so annotating them one after the other never shifts the
+    /// path of those remaining.
+    pub fn eval_targets(&self, position: Chess, every_node: bool) -> Vec<EvalTarget> {
+        let mut targets = Vec::new();
+        self.collect_eval_targets(
+            &mut Vec::new(),
+            position,
+            Vec::new(),
+            false,
+            every_node,
+            &mut targets,
+        );
+        targets.reverse();
+        targets
+    }
+
+    fn collect_eval_targets(
+        &self,
+        path: &mut Vec<usize>,
+        mut position: Chess,
+        mut moves: Vec<String>,
+        is_variation: bool,
+        every_node: bool,
+        targets: &mut Vec<EvalTarget>,
+    ) {
+        let mut previous = (position.clone(), moves.clone());
+        // Index in `targets` of the variation start, filled in once its end is known.
+        let mut start = None;
+        for (i, node) in self.0.iter().enumerate() {
+            match node {
+                GameTreeNode::Move(m) => {
+                    let Ok(mv) = m.san.to_move(&position) else {
+                        break;
+                    };
+                    previous = (position.clone(), moves.clone());
+                    position.play_unchecked(&mv);
+                    moves.push(mv.to_uci(CastlingMode::Standard).to_string());
+
+                    path.push(i);
+                    if is_variation && start.is_none() {
+                        start = Some(targets.len());
+                        targets.push(EvalTarget {
+                            path: path.clone(),
+                            moves: Vec::new(),
+                            variation_end: true,
+                        });
+                    } else if every_node {
+                        targets.push(EvalTarget {
+                            path: path.clone(),
+                            moves: moves.clone(),
+                            variation_end: false,
+                        });
+                    }
+                    path.pop();
+                }
+                GameTreeNode::Variation(branch) => {
+                    path.push(i);
+                    branch.collect_eval_targets(
+                        path,
+                        previous.0.clone(),
+                        previous.1.clone(),
+                        true,
+                        every_node,
+                        targets,
+                    );
+                    path.pop();
+                }
+                GameTreeNode::Comment(_) | GameTreeNode::Nag(_) => {}
+            }
+        }
+        if let Some(start) = start {
+            targets[start].moves = moves;
+        }
+    }
+
+    /// Write `eval` into the comment of the move at `path`, keeping any other text in it.
+    pub fn set_eval_comment(&mut self, path: &[usize], eval: &str) -> Result<()> {
+        let no_move = || Error::InvalidPatch(format!("No move at {:?}", path));
+        let (&index, parents) = path.split_last().ok_or_else(no_move)?;
+        let mut tree = self;
+        for &i in parents {
+            match tree.0.get_mut(i) {
+                Some(GameTreeNode::Variation(branch)) => tree = branch,
+                _ => return Err(no_move()),
+            }
+        }
+        if !matches!(tree.0.get(index), Some(GameTreeNode::Move(_))) {
+            return Err(no_move());
+        }
+
+        let start = index + 1;
+        let end = tree.0[start..]
+            .iter()
+            .position(|node| matches!(node, GameTreeNode::Move(_) | GameTreeNode::Variation(_)))
+            .map_or(tree.0.len(), |i| start + i);
+        match tree.0[start..end].iter_mut().find_map(|node| match node {
+            GameTreeNode::Comment(comment) => Some(comment),
+            _ => None,
+        }) {
+            Some(comment) => *comment = with_eval(comment, eval),
+            None => tree.0.insert(end, GameTreeNode::Comment(eval.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Write `[%eval value]` into the first comment of the move at `ply`, replacing the
+    /// evaluation command it may already have and keeping the rest of the text.
+    pub fn set_eval_command(&mut self, ply: usize, value: &str) -> Result<()> {
+        if ply == 0 {
+            return Err(invalid_ply(ply));
+        }
+        let range = self.annotation_range(ply)?;
+        let command = format!("[%eval {}]", value);
+        match self.0[range.clone()]
+            .iter_mut()
+            .find_map(|node| match node {
+                GameTreeNode::Comment(comment) => Some(comment),
+                _ => None,
+            }) {
+            Some(comment) => *comment = with_eval_command(comment, &command),
+            None => self.0.insert(range.end, GameTreeNode::Comment(command)),
+        }
+        Ok(())
+    }
+
+    /// NAGs of the move at `ply`.
+    pub fn move_nags(&self, ply: usize) -> Result<Vec<Nag>> {
+        if ply == 0 {
+            return Err(invalid_ply(ply));
+        }
+        Ok(self.0[self.annotation_range(ply)?]
+            .iter()
+            .filter_map(|node| match node {
+                GameTreeNode::Nag(nag) => Some(*nag),
+                _ => None,
+            })
+            .collect())
+    }
+
     pub fn encode(&self, bytes: &mut Vec<u8>, position: Option<Chess>) {
         let mut cur_position = position.unwrap_or_default();
         let mut prev_position = cur_position.clone();
@@ -89,61 +489,64 @@ pub fn encode(&self, bytes: &mut Vec<u8>, position: Option<Chess>) {
         }
     }
 
-    fn from_bytes_impl(mut bytes: &[u8], position: Chess) -> Result<(Vec<GameTreeNode>, &[u8])> {
+    fn from_bytes_impl(
+        reader: &mut BlobReader,
+        position: Chess,
+        tree: &mut Vec<GameTreeNode>,
+    ) -> std::result::Result<(), DecodeError> {
         let mut prev_position: Chess = position.clone();
         let mut cur_position: Chess = position;
-        let mut tree: Vec<GameTreeNode> = Vec::new();
 
-        loop {
-            match bytes.first().copied() {
-                Some(Self::NAG) => {
-                    tree.push(GameTreeNode::Nag(Nag(bytes[1])));
-                    bytes = &bytes[2..];
-                }
-                Some(Self::COMMENT) => {
-                    let length = u64::from_be_bytes(
-                        bytes[1..]
-                            .first_chunk::<8>()
-                            .ok_or(Error::InvalidBinaryData)?
-                            .to_owned(),
-                    ) as usize;
-                    tree.push(GameTreeNode::Comment(String::from_utf8(
-                        bytes[9..9 + length].to_owned(),
-                    )?));
-                    bytes = &bytes[9 + length..];
+        while let Some((offset, token)) = reader.next_token()? {
+            match token {
+                Token::Nag(nag) => tree.push(GameTreeNode::Nag(Nag(nag))),
+                Token::Comment(text) => {
+                    let text = std::str::from_utf8(text)
+                        .map_err(|_| DecodeError::InvalidComment { offset })?;
+                    tree.push(GameTreeNode::Comment(text.to_string()));
                 }
-                Some(Self::END_VARIATION) => {
-                    bytes = &bytes[1..];
-                    break;
-                }
-                Some(Self::START_VARIATION) => {
-                    let (branch, rest) = Self::from_bytes_impl(&bytes[1..], prev_position.clone())?;
+                Token::EndVariation => break,
+                Token::StartVariation => {
+                    // Keep the partially decoded branch even if it turns out to be corrupted.
+                    let mut branch = Vec::new();
+                    let result = Self::from_bytes_impl(reader, prev_position.clone(), &mut branch);
                     tree.push(GameTreeNode::Variation(GameTree(branch)));
-                    bytes = rest;
-                }
-                Some(byte) => {
-                    if let Some(m) = cur_position.legal_moves().get(byte as usize) {
-                        prev_position = cur_position.clone();
-                        let san = SanPlus::from_move_and_play_unchecked(&mut cur_position, m);
-                        tree.push(GameTreeNode::Move(san));
-                        bytes = &bytes[1..];
-                    } else {
-                        return Err(Error::InvalidBinaryData);
-                    }
+                    result?;
                 }
-                None => {
-                    break;
+                Token::Move(byte) => {
+                    let m = cur_position
+                        .legal_moves()
+                        .get(byte as usize)
+                        .cloned()
+                        .ok_or(DecodeError::IllegalMove { offset, byte })?;
+                    prev_position = cur_position.clone();
+                    let san = SanPlus::from_move_and_play_unchecked(&mut cur_position, &m);
+                    tree.push(GameTreeNode::Move(san));
                 }
             }
         }
 
-        Ok((tree, bytes))
+        Ok(())
     }
 
     pub fn from_bytes(bytes: &[u8], position: Option<Chess>) -> Result<Self> {
-        Ok(Self(
-            Self::from_bytes_impl(bytes, position.unwrap_or_default())?.0,
-        ))
+        match Self::from_bytes_partial(bytes, position) {
+            (tree, None) => Ok(tree),
+            (_, Some(err)) => Err(err.into()),
+        }
+    }
+
+    /// Decode as much of a possibly corrupted move blob as possible, returning the moves read
+    /// up to the first error along with that error.
+    pub fn from_bytes_partial(
+        bytes: &[u8],
+        position: Option<Chess>,
+    ) -> (Self, Option<DecodeError>) {
+        let mut tree = Vec::new();
+        let result = BlobReader::new(bytes).and_then(|mut reader| {
+            Self::from_bytes_impl(&mut reader, position.unwrap_or_default(), &mut tree)
+        });
+        (Self(tree), result.err())
     }
 
     pub fn pretty_print(
@@ -228,7 +631,18 @@ pub struct TempGame {
     pub moves: Vec<u8>,
     pub position: Chess,
     pub material_count: ByColor<u8>,
+    pub final_board: Board,
     pub tree: GameTree,
+    pub structure: PawnStructure,
+    /// Links to other games of the file, taken out of the comments.
+    pub links: Vec<PgnLink>,
+}
+
+/// Headers of a lichess study chapter, used to group chapters by study.
+#[derive(Default)]
+struct StudyHeaders {
+    study_name: Option<String>,
+    chapter_name: Option<String>,
 }
 
 pub struct Importer {
@@ -236,6 +650,14 @@ pub struct Importer {
     variants: Vec<GameTree>,
     timestamp: Option<i64>,
     skip: bool,
+    /// Forces study imports on or off; detected from the site of each game when `None`.
+    study_mode: Option<bool>,
+    study_headers: StudyHeaders,
+    is_study: bool,
+    /// Rewrite the headers of each game before they are read.
+    header_rules: Option<HeaderRules>,
+    /// Headers of the current game, kept until the rules apply to them.
+    pending_headers: Vec<PgnHeader>,
 }
 
 impl Importer {
@@ -245,57 +667,91 @@ pub fn new(timestamp: Option<i64>) -> Self {
             variants: Vec::new(),
             timestamp,
             skip: false,
+            study_mode: None,
+            study_headers: StudyHeaders::default(),
+            is_study: false,
+            header_rules: None,
+            pending_headers: Vec::new(),
         }
     }
 
-    #[inline]
-    #[must_use]
-    fn active_branch(&mut self) -> &mut GameTree {
-        self.variants.last_mut().unwrap_or(&mut self.game.tree)
+    /// Apply `rules` to the headers of each game.
+    pub fn header_rules(mut self, rules: HeaderRules) -> Self {
+        self.header_rules = Some(rules).filter(|rules| !rules.is_empty());
+        self
     }
-}
 
-impl Visitor for Importer {
-    type Result = Option<TempGame>;
+    /// Import games as study chapters (`Some(true)`), as regular games (`Some(false)`), or
+    /// decide per game from its site (`None`).
+    pub fn study_mode(mut self, study_mode: Option<bool>) -> Self {
+        self.study_mode = study_mode;
+        self
+    }
 
-    fn begin_game(&mut self) {
-        self.skip = false;
+    /// Store the study name as the event and the chapter name as the round.
+    ///
+    /// Lichess exports chapters with a `StudyName: ChapterName` event, and newer exports also
+    /// carry dedicated headers, which take precedence.
+    fn group_study_chapter(&mut self) {
+        let headers = std::mem::take(&mut self.study_headers);
+        let (event_study, event_chapter) = match self.game.event_name.as_deref() {
+            Some(event) => match event.split_once(": ") {
+                Some((study, chapter)) => (Some(study.to_string()), Some(chapter.to_string())),
+                None => (Some(event.to_string()), None),
+            },
+            None => (None, None),
+        };
+
+        if let Some(study) = headers.study_name.or(event_study) {
+            self.game.event_name = Some(study);
+        }
+        let chapter = headers.chapter_name.or(event_chapter);
+        if chapter.is_some() && matches!(self.game.round.as_deref(), None | Some("?")) {
+            self.game.round = chapter;
+        }
     }
 
-    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+    /// Read a header of the current game.
+    fn set_header(&mut self, key: &[u8], value: &str) {
         if key == b"White" {
-            self.game.white_name = Some(value.decode_utf8_lossy().into_owned());
+            self.game.white_name = Some(value.to_string());
         } else if key == b"Black" {
-            self.game.black_name = Some(value.decode_utf8_lossy().into_owned());
+            self.game.black_name = Some(value.to_string());
         } else if key == b"WhiteElo" {
             self.game.white_elo = btoi::btoi(value.as_bytes()).ok();
         } else if key == b"BlackElo" {
             self.game.black_elo = btoi::btoi(value.as_bytes()).ok();
         } else if key == b"TimeControl" {
-            self.game.time_control = Some(value.decode_utf8_lossy().into_owned());
+            self.game.time_control = Some(value.to_string());
         } else if key == b"ECO" {
-            self.game.eco = Some(value.decode_utf8_lossy().into_owned());
+            self.game.eco = Some(value.to_string());
         } else if key == b"Round" {
-            self.game.round = Some(value.decode_utf8_lossy().into_owned());
+            self.game.round = Some(value.to_string());
         } else if key == b"Date" || key == b"UTCDate" {
-            self.game.date = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
+            self.game.date = Some(value.to_string());
         } else if key == b"UTCTime" {
-            self.game.time = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
+            self.game.time = Some(value.to_string());
         } else if key == b"Site" {
-            self.game.site_name = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
+            self.game.site_name = Some(value.to_string());
         } else if key == b"Event" {
-            self.game.event_name = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
+            self.game.event_name = Some(value.to_string());
+        } else if key == b"StudyName" {
+            self.study_headers.study_name = Some(value.to_string());
+        } else if key == b"ChapterName" {
+            self.study_headers.chapter_name = Some(value.to_string());
         } else if key == b"Result" {
-            self.game.result = Some(String::from_utf8_lossy(value.as_bytes()).to_string());
+            self.game.result = Some(value.to_string());
         } else if key == b"FEN" {
-            if value.as_bytes() == b"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1" {
+            if value == "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1" {
                 self.game.fen = None;
             } else {
                 let fen = Fen::from_ascii(value.as_bytes());
                 if let Ok(fen) = fen {
-                    self.game.fen = Some(value.decode_utf8_lossy().into_owned());
+                    self.game.fen = Some(value.to_string());
+                    // Chess960 reads standard castling rights the same way, and keeps
+                    // games from other setups with their rights.
                     if let Ok(setup) =
-                        Chess::from_setup(fen.into_setup(), shakmaty::CastlingMode::Standard)
+                        Chess::from_setup(fen.into_setup(), shakmaty::CastlingMode::Chess960)
                             .or_else(PositionError::ignore_too_much_material)
                     {
                         self.game.position = setup;
@@ -309,7 +765,44 @@ fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
         }
     }
 
+    #[inline]
+    #[must_use]
+    fn active_branch(&mut self) -> &mut GameTree {
+        self.variants.last_mut().unwrap_or(&mut self.game.tree)
+    }
+}
+
+impl Visitor for Importer {
+    type Result = Option<TempGame>;
+
+    fn begin_game(&mut self) {
+        self.skip = false;
+        self.is_study = false;
+        self.study_headers = StudyHeaders::default();
+        self.pending_headers.clear();
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let value = value.decode_utf8_lossy();
+        if self.header_rules.is_some() {
+            self.pending_headers.push(PgnHeader {
+                name: String::from_utf8_lossy(key).into_owned(),
+                value: value.into_owned(),
+            });
+        } else {
+            self.set_header(key, &value);
+        }
+    }
+
     fn end_headers(&mut self) -> Skip {
+        if let Some(rules) = &self.header_rules {
+            let mut headers = std::mem::take(&mut self.pending_headers);
+            rules.apply(&mut headers);
+            for header in headers {
+                self.set_header(header.name.as_bytes(), &header.value);
+            }
+        }
+
         // Skip games with timestamp before
         let cur_timestamp = self.game.date.as_ref().and_then(|date| {
             let date = NaiveDate::parse_from_str(date, "%Y.%m.%d").ok()?;
@@ -327,6 +820,16 @@ fn end_headers(&mut self) -> Skip {
             }
         }
 
+        self.is_study = self.study_mode.unwrap_or_else(|| {
+            self.game
+                .site_name
+                .as_deref()
+                .is_some_and(|site| site.starts_with(LICHESS_STUDY_URL))
+        });
+        if self.is_study {
+            self.group_study_chapter();
+        }
+
         // Skip games without ELO
         // self.skip |= self.current.white_elo.is_none() || self.current.black_elo.is_none();
         Skip(self.skip)
@@ -338,7 +841,11 @@ fn san(&mut self, san: SanPlus) {
 
     fn comment(&mut self, comment: RawComment<'_>) {
         if let Ok(comment) = String::from_utf8(comment.as_bytes().to_owned()) {
-            self.active_branch().push(GameTreeNode::Comment(comment));
+            let (links, comment) = extract_link_commands(&comment);
+            self.game.links.extend(links);
+            if !comment.is_empty() {
+                self.active_branch().push(GameTreeNode::Comment(comment));
+            }
         }
     }
 
@@ -365,17 +872,26 @@ fn end_game(&mut self) -> Self::Result {
             self.game = TempGame::default();
             None
         } else {
+            // Chapters made only of variations would otherwise be imported without moves.
+            if self.is_study {
+                self.game
+                    .tree
+                    .promote_first_variation(PROMOTED_MAINLINE_MARKER);
+            }
+
             // encode game tree
             self.game
                 .tree
                 .encode(&mut self.game.moves, Some(self.game.position.clone()));
 
-            // calc material
+            // calc material and pawn structure
             let mut cur_position = self.game.position.clone();
+            let mut pawns = vec![board_pawns(cur_position.board())];
             for item in &self.game.tree.0 {
                 if let GameTreeNode::Move(san) = item {
                     if let Ok(m) = san.san.to_move(&cur_position) {
                         cur_position.play_unchecked(&m);
+                        pawns.push(board_pawns(cur_position.board()));
                     } else {
                         // Invalid game
                         self.game = TempGame::default();
@@ -384,6 +900,8 @@ fn end_game(&mut self) -> Self::Result {
                 }
             }
             self.game.material_count = get_material_count(cur_position.board());
+            self.game.final_board = cur_position.board().clone();
+            self.game.structure = classify_game(&pawns, DEFAULT_STRUCTURE_PLY);
 
             Some(std::mem::take(&mut self.game))
         }
@@ -572,4 +1090,280 @@ fn test_pgn_with_many_variations() {
         assert_eq!(game.tree, GameTree::from_bytes(&bytes, None).unwrap());
         assert_eq!(trim(&game.tree.to_string()), trim(pgn));
     }
+
+    const STUDY_PGN: &str = r#"[Event "Caro-Kann Repertoire: Advance Variation"]
+[Site "https://lichess.org/study/AbCdEfGh/IjKlMnOp"]
+[Result "*"]
+[Variant "Standard"]
+[ECO "B12"]
+[Annotator "https://lichess.org/@/someone"]
+[UTCDate "2024.03.02"]
+[UTCTime "10:15:00"]
+
+1. e4 c6 2. d4 d5 3. e5 Bf5 { The main move. } *
+
+[Event "Caro-Kann Repertoire: Sidelines"]
+[Site "https://lichess.org/study/AbCdEfGh/QrStUvWx"]
+[Result "*"]
+[Variant "Standard"]
+[UTCDate "2024.03.02"]
+[UTCTime "10:20:00"]
+
+{ Sidelines worth knowing } ( 1. e4 c6 2. Nc3 d5 3. Nf3 ) ( 1. e4 c6 2. c4 d5 ) *
+
+[Event "Caro-Kann Repertoire: Two Knights"]
+[Site "https://lichess.org/study/AbCdEfGh/YzAbCdEf"]
+[Result "*"]
+[StudyName "Caro-Kann Repertoire"]
+[ChapterName "Two Knights Attack"]
+
+1. e4 c6 2. Nc3 d5 3. Nf3 *
+"#;
+
+    fn read_all(pgn: &str, study_mode: Option<bool>) -> Vec<TempGame> {
+        let mut importer = Importer::new(None).study_mode(study_mode);
+        BufferedReader::new_cursor(pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .collect()
+    }
+
+    fn tree(movetext: &str) -> GameTree {
+        let pgn = format!("[Result \"*\"]\n\n{} *\n", movetext);
+        read_all(&pgn, Some(false)).remove(0).tree
+    }
+
+    #[test]
+    fn comments_are_replaced_or_removed() {
+        let mut game = tree("{intro} 1. e4 $1 {old} {older} e5 2. Nf3");
+        game.set_comment(1, "new").unwrap();
+        assert_eq!(game, tree("{intro} 1. e4 $1 {new} e5 2. Nf3"));
+
+        game.set_comment(0, "").unwrap();
+        game.set_comment(3, "developing").unwrap();
+        assert_eq!(game, tree("1. e4 $1 {new} e5 2. Nf3 {developing}"));
+
+        game.set_comment(1, "").unwrap();
+        assert_eq!(game, tree("1. e4 $1 e5 2. Nf3 {developing}"));
+        assert!(game.set_comment(4, "too far").is_err());
+    }
+
+    #[test]
+    fn nags_are_added_before_comments_once() {
+        let mut game = tree("1. e4 {best by test} e5 (1... c5) 2. Nf3");
+        game.add_nag(1, Nag(1)).unwrap();
+        game.add_nag(1, Nag(1)).unwrap();
+        game.add_nag(2, Nag(6)).unwrap();
+        assert_eq!(game, tree("1. e4 $1 {best by test} e5 $6 (1... c5) 2. Nf3"));
+        assert!(game.add_nag(0, Nag(1)).is_err());
+    }
+
+    #[test]
+    fn eval_commands_replace_older_ones() {
+        let mut game = tree("1. e4 {[%clk 0:03:00] [%eval 0.3]} e5 {solid} (1... c5) 2. Nf3");
+        game.set_eval_command(1, "0.25").unwrap();
+        game.set_eval_command(2, "#-3").unwrap();
+        game.set_eval_command(3, "0.40").unwrap();
+        assert_eq!(
+            game,
+            tree(
+                "1. e4 {[%clk 0:03:00] [%eval 0.25]} e5 {[%eval #-3] solid} (1... c5) \
+                 2. Nf3 {[%eval 0.40]}"
+            )
+        );
+        assert!(game.set_eval_command(0, "0.00").is_err());
+        assert_eq!(game.move_nags(1).unwrap(), []);
+    }
+
+    #[test]
+    fn variations_are_removed_by_index() {
+        let mut game = tree("1. e4 e5 (1... c5) {Sicilian} (1... e6) 2. Nf3");
+        game.remove_variation(2, 1).unwrap();
+        assert_eq!(game, tree("1. e4 e5 (1... c5) {Sicilian} 2. Nf3"));
+        assert!(game.remove_variation(2, 1).is_err());
+        assert!(game.remove_variation(1, 0).is_err());
+        game.remove_variation(2, 0).unwrap();
+        assert_eq!(game, tree("1. e4 e5 {Sicilian} 2. Nf3"));
+    }
+
+    #[test]
+    fn moves_are_appended_to_the_main_line() {
+        let mut game = tree("1. e4 e5 (1... c5 2. Nf3) 2. Nf3");
+        game.append_moves(&["Nc6".to_string(), "Bb5".to_string()], None)
+            .unwrap();
+        assert_eq!(game, tree("1. e4 e5 (1... c5 2. Nf3) 2. Nf3 Nc6 3. Bb5"));
+
+        // Nothing is appended if any move is illegal.
+        assert!(game
+            .append_moves(&["a6".to_string(), "Bb5".to_string()], None)
+            .is_err());
+        assert_eq!(game.count_main_line_moves(), 5);
+    }
+
+    #[test]
+    fn study_chapters_are_grouped_by_study() {
+        let games = read_all(STUDY_PGN, None);
+        assert_eq!(games.len(), 3);
+        assert!(games
+            .iter()
+            .all(|g| g.event_name.as_deref() == Some("Caro-Kann Repertoire")));
+
+        let chapters: Vec<_> = games.iter().map(|g| g.round.as_deref()).collect();
+        assert_eq!(
+            chapters,
+            vec![
+                Some("Advance Variation"),
+                Some("Sidelines"),
+                Some("Two Knights Attack")
+            ]
+        );
+    }
+
+    #[test]
+    fn variations_only_chapter_is_promoted() {
+        let games = read_all(STUDY_PGN, None);
+        let sidelines = &games[1];
+
+        // 1. e4 c6 2. Nc3 d5 3. Nf3 becomes the main line, the other line an alternative.
+        assert_eq!(sidelines.tree.count_main_line_moves(), 5);
+        assert!(matches!(
+            &sidelines.tree.nodes()[0],
+            GameTreeNode::Comment(c) if c.trim() == "Sidelines worth knowing"
+        ));
+        assert_eq!(
+            sidelines.tree.nodes()[1],
+            GameTreeNode::Comment(PROMOTED_MAINLINE_MARKER.to_string())
+        );
+        assert!(matches!(sidelines.tree.nodes()[2], GameTreeNode::Move(_)));
+        assert!(matches!(
+            sidelines.tree.nodes()[3],
+            GameTreeNode::Variation(_)
+        ));
+
+        let mut bytes = Vec::new();
+        sidelines.tree.encode(&mut bytes, None);
+        assert_eq!(sidelines.tree, GameTree::from_bytes(&bytes, None).unwrap());
+        assert_eq!(
+            sidelines.final_board.to_string(),
+            "rnbqkbnr/pp2pppp/2p5/3p4/4P3/2N2N2/PPPP1PPP/R1BQKB1R"
+        );
+    }
+
+    #[test]
+    fn study_mode_can_be_disabled() {
+        let games = read_all(STUDY_PGN, Some(false));
+        assert_eq!(
+            games[0].event_name.as_deref(),
+            Some("Caro-Kann Repertoire: Advance Variation")
+        );
+        assert_eq!(games[1].tree.count_main_line_moves(), 0);
+    }
+
+    #[test]
+    fn regular_games_are_not_studies() {
+        let pgn =
+            "[Event \"Casual: Blitz\"]\n[Site \"https://lichess.org/abcdefgh\"]\n\n( 1. e4 e5 ) *";
+        let games = read_all(pgn, None);
+        assert_eq!(games[0].event_name.as_deref(), Some("Casual: Blitz"));
+        assert_eq!(games[0].tree.count_main_line_moves(), 0);
+    }
+
+    #[test]
+    fn evaluations_replace_only_the_eval_line() {
+        let eval = "+0.45/20 Stockfish 17";
+        assert_eq!(with_eval("", eval), eval);
+        assert_eq!(
+            with_eval("Sicilian!", eval),
+            "+0.45/20 Stockfish 17\nSicilian!"
+        );
+        assert_eq!(with_eval("-1.20/18 Stockfish 15", eval), eval);
+        assert_eq!(
+            with_eval("+0.30/18 Stockfish 15\nSicilian!", eval),
+            "+0.45/20 Stockfish 17\nSicilian!"
+        );
+        assert_eq!(
+            with_eval("#-3/24 Komodo Dragon 3\nmate", eval),
+            "+0.45/20 Stockfish 17\nmate"
+        );
+        assert_eq!(
+            with_eval("+1 for creativity", eval),
+            "+0.45/20 Stockfish 17\n+1 for creativity"
+        );
+    }
+
+    const NESTED: &str = "1. e4 {Best by test} e5 (1... c5 {+0.30/18 Stockfish 15\nSicilian!} \
+                          2. Nf3 (2. c3 d5) 2... d6) (1... e6 2. d4) 2. Nf3 Nc6";
+
+    #[test]
+    fn eval_targets_cover_nested_variations() {
+        let targets = tree(NESTED).eval_targets(Chess::default(), false);
+        let found: Vec<_> = targets
+            .iter()
+            .map(|t| (t.path.clone(), t.moves.join(" "), t.variation_end))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (vec![4, 0], "e2e4 e7e6 d2d4".to_string(), true),
+                (vec![3, 3, 0], "e2e4 c7c5 c2c3 d7d5".to_string(), true),
+                (vec![3, 0], "e2e4 c7c5 g1f3 d7d6".to_string(), true),
+            ]
+        );
+
+        let every_node =
+            tree("1. e4 e5 (1... c5 2. Nf3) 2. Nf3").eval_targets(Chess::default(), true);
+        assert_eq!(every_node.len(), 5);
+        assert_eq!(every_node.iter().filter(|t| t.variation_end).count(), 1);
+        assert_eq!(every_node.last().unwrap().moves, vec!["e2e4"]);
+    }
+
+    #[test]
+    fn eval_comments_keep_human_text() {
+        let mut game = tree(NESTED);
+        for (i, target) in game
+            .eval_targets(Chess::default(), false)
+            .iter()
+            .enumerate()
+        {
+            game.set_eval_comment(&target.path, &format!("+0.{}0/20 Stockfish 17", i))
+                .unwrap();
+        }
+        assert_eq!(
+            game,
+            tree(
+                "1. e4 {Best by test} e5 (1... c5 {+0.20/20 Stockfish 17\nSicilian!} \
+                 2. Nf3 (2. c3 {+0.10/20 Stockfish 17} d5) 2... d6) \
+                 (1... e6 {+0.00/20 Stockfish 17} 2. d4) 2. Nf3 Nc6"
+            )
+        );
+        assert!(game.set_eval_comment(&[1], "+0.00/1 x").is_err());
+        assert!(game.set_eval_comment(&[0, 0], "+0.00/1 x").is_err());
+    }
+
+    #[test]
+    fn header_rules_apply_before_headers_are_read() {
+        use crate::db::header_rules::HeaderRulesConfig;
+
+        let pgn =
+            "[Event \"Tata Steel Masters (Rd 5)\"]\n[Round \"?\"]\n[White \"Giri, Anish\"]\n\n\
+                   1. e4 *\n";
+        let (rules, _) = HeaderRules::compile(&HeaderRulesConfig::default());
+        let mut importer = Importer::new(None).header_rules(rules);
+        let games: Vec<TempGame> = BufferedReader::new_cursor(pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .collect();
+        assert_eq!(games[0].event_name.as_deref(), Some("Tata Steel Masters"));
+        assert_eq!(games[0].round.as_deref(), Some("5"));
+        assert_eq!(games[0].white_name.as_deref(), Some("Giri, Anish"));
+
+        // Without rules, headers are read as they are.
+        let games = read_all(pgn, None);
+        assert_eq!(
+            games[0].event_name.as_deref(),
+            Some("Tata Steel Masters (Rd 5)")
+        );
+    }
 }
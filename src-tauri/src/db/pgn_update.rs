@@ -0,0 +1,390 @@
+//! Keeping a database up to date with a growing PGN file.
+//!
+//! A file that only ever gets games appended to it, like an export of one's online games, is
+//! imported once and then only from where the last import ended. For each file, the database
+//! remembers in its `Info` table how far the file was imported, how many games were read from
+//! it, and the start and hash of the last of them. Before importing from that point, the last
+//! game is read again. If the file is now shorter, or that game changed, the file was truncated
+//! or rewritten and is imported again from the start, leaving out the games the database already
+//! holds.
+//!
+//! Compressed files can't be read from the middle, so they are always imported whole, also
+//! leaving out what is already there.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    error::{Error, Result},
+    AppState,
+};
+
+use super::batch_import::{import_games, FileImport};
+use super::core::{self, StableHash};
+use super::pgn::Importer;
+use super::provenance::{self, GameProvenance};
+use super::schema::info;
+use super::{
+    decompress, get_db_or_create, header_rules, rarity, structure, update_info_counts, write_lock,
+    ConnectionOptions, INDEXES_SQL,
+};
+
+/// Prefix of the `Info` entries holding how far each file was imported.
+const IMPORT_MARK_PREFIX: &str = "PgnImport:";
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PgnUpdateReport {
+    pub imported: u32,
+    /// Games left out because the database already had them.
+    pub duplicates: u32,
+    /// Set when the file no longer matched what was imported from it before, having been
+    /// truncated or rewritten, and was imported again from the start.
+    pub full_reimport: bool,
+}
+
+/// How far a file was imported into a database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ImportMark {
+    /// Bytes of the file imported.
+    offset: u64,
+    /// Games read from the file.
+    games: u32,
+    /// Start of the last game read.
+    last_game: u64,
+    /// Hash of the last game read, from its start to `offset`.
+    last_game_hash: String,
+}
+
+fn mark_key(file: &Path) -> String {
+    format!("{}{}", IMPORT_MARK_PREFIX, file.to_string_lossy())
+}
+
+fn read_mark(db: &mut SqliteConnection, file: &Path) -> Result<Option<ImportMark>> {
+    let value: Option<String> = info::table
+        .filter(info::name.eq(mark_key(file)))
+        .select(info::value)
+        .first::<Option<String>>(db)
+        .optional()?
+        .flatten();
+    Ok(value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()?)
+}
+
+fn write_mark(db: &mut SqliteConnection, file: &Path, mark: &ImportMark) -> Result<()> {
+    let value = serde_json::to_string(mark)?;
+    diesel::insert_into(info::table)
+        .values((info::name.eq(mark_key(file)), info::value.eq(&value)))
+        .on_conflict(info::name)
+        .do_update()
+        .set(info::value.eq(&value))
+        .execute(db)?;
+    Ok(())
+}
+
+fn hash(bytes: &[u8]) -> String {
+    let mut hash = StableHash::default();
+    hash.update(bytes);
+    hash.hex()
+}
+
+/// Whether `file` still holds what was imported from it, as checked on its last game.
+fn mark_holds(file: &mut File, mark: &ImportMark) -> io::Result<bool> {
+    if mark.last_game > mark.offset || file.metadata()?.len() < mark.offset {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(mark.last_game))?;
+    let mut last_game = Vec::new();
+    file.take(mark.offset - mark.last_game)
+        .read_to_end(&mut last_game)?;
+    Ok(hash(&last_game) == mark.last_game_hash)
+}
+
+/// Games of a part of a PGN file, found by their first header line.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ScannedGames {
+    /// Number of games.
+    count: u32,
+    /// Offset and content of the last game.
+    last: Option<(u64, Vec<u8>)>,
+    /// Offset the part ends at.
+    end: u64,
+}
+
+/// Find the games of `reader`, which holds a file from `offset` on.
+fn scan_games(mut reader: impl BufRead, offset: u64) -> io::Result<ScannedGames> {
+    let mut scanned = ScannedGames {
+        end: offset,
+        ..Default::default()
+    };
+    let mut in_headers = false;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        let start = scanned.end;
+        scanned.end += read as u64;
+
+        let text = match line.strip_prefix(UTF8_BOM) {
+            Some(text) if start == 0 => text,
+            _ => &line[..],
+        };
+        if text.starts_with(b"[") {
+            if !in_headers {
+                in_headers = true;
+                scanned.count += 1;
+                scanned.last = Some((start, Vec::new()));
+            }
+        } else if !text.iter().all(u8::is_ascii_whitespace) {
+            in_headers = false;
+        }
+        if let Some((_, game)) = &mut scanned.last {
+            game.extend_from_slice(&line);
+        }
+    }
+    Ok(scanned)
+}
+
+/// Import the games `file` got since it was last imported into `db`, or all of them the first
+/// time and when it doesn't hold what was imported from it anymore.
+fn update_from_pgn(
+    db: &mut SqliteConnection,
+    file: &Path,
+    mut importer: Importer,
+) -> Result<PgnUpdateReport> {
+    let source = GameProvenance::file(file);
+    let mut opened = File::open(file)?;
+    if matches!(
+        file.extension().and_then(|extension| extension.to_str()),
+        Some("bz2" | "zst")
+    ) {
+        let import = import_games(
+            db,
+            decompress(opened, file.extension())?,
+            &mut importer,
+            &source,
+            true,
+            || {},
+        )?;
+        return Ok(PgnUpdateReport {
+            imported: import.imported,
+            duplicates: import.duplicates,
+            full_reimport: false,
+        });
+    }
+
+    let mark = read_mark(db, file)?;
+    let holds = match &mark {
+        Some(mark) => mark_holds(&mut opened, mark)?,
+        None => false,
+    };
+    let full_reimport = mark.is_some() && !holds;
+    let (offset, games) = match mark.as_ref().filter(|_| holds) {
+        Some(mark) => (mark.offset, mark.games),
+        None => (0, 0),
+    };
+    if full_reimport {
+        warn!(
+            "{} changed since it was last imported, importing it again",
+            file.display()
+        );
+    }
+
+    // Games appended while importing are left for the next time.
+    let length = opened.metadata()?.len();
+    opened.seek(SeekFrom::Start(offset))?;
+    let scanned = scan_games(BufReader::new((&opened).take(length - offset)), offset)?;
+    opened.seek(SeekFrom::Start(offset))?;
+
+    db.transaction::<_, Error, _>(|db| {
+        // Only the games of the file that are new are read, so only a first or full import
+        // can meet games the database already has.
+        let import: FileImport = import_games(
+            db,
+            (&opened).take(scanned.end - offset),
+            &mut importer,
+            &source,
+            offset == 0,
+            || {},
+        )?;
+        match &scanned.last {
+            Some((last_game, content)) => {
+                let mark = ImportMark {
+                    offset: scanned.end,
+                    games: games + scanned.count,
+                    last_game: *last_game,
+                    last_game_hash: hash(content),
+                };
+                write_mark(db, file, &mark)?;
+            }
+            // A file emptied of its games starts over.
+            None if offset == 0 => {
+                diesel::delete(info::table.filter(info::name.eq(mark_key(file)))).execute(db)?;
+            }
+            None => {}
+        }
+        Ok(PgnUpdateReport {
+            imported: import.imported,
+            duplicates: import.duplicates,
+            full_reimport,
+        })
+    })
+}
+
+/// Import the games added to the PGN file `file` since it was last imported into the database
+/// at `db`, creating the database if needed.
+///
+/// The file is imported again from the start, without the games already in the database, when
+/// it was truncated or rewritten since, which the report tells.
+#[tauri::command]
+#[specta::specta]
+pub async fn update_database_from_pgn(
+    file: PathBuf,
+    db: PathBuf,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<PgnUpdateReport> {
+    state.path_scope.check(&file)?;
+    let id = db.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let db_exists = db.exists();
+    let conn = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    if !db_exists {
+        let title = db
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        core::init_db(conn, &title, "")?;
+    }
+    structure::ensure_structure_table(conn)?;
+    provenance::ensure_sources_table(conn)?;
+    rarity::ensure_frequency_table(conn)?;
+    // Games already in the database are looked up by player.
+    conn.batch_execute(INDEXES_SQL)?;
+
+    let importer = Importer::new(None).header_rules(header_rules::load_header_rules(&app)?);
+    let report = update_from_pgn(conn, &file, importer)?;
+    update_info_counts(conn)?;
+    state.game_cache.invalidate_file(&id);
+    state.db_watcher.touch(&db);
+
+    info!(
+        "Imported {} new games from {} into {}",
+        report.imported,
+        file.display(),
+        db.display()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{schema::games, test_db};
+    use std::io::Write;
+
+    fn game(white: &str, moves: &str) -> String {
+        format!(
+            "[Event \"Online\"]\n[White \"{}\"]\n[Black \"Guest\"]\n[Result \"*\"]\n\n{} *\n\n",
+            white, moves
+        )
+    }
+
+    fn update(db: &mut SqliteConnection, file: &Path) -> PgnUpdateReport {
+        update_from_pgn(db, file, Importer::new(None)).unwrap()
+    }
+
+    fn game_count(db: &mut SqliteConnection) -> i64 {
+        games::table.count().get_result(db).unwrap()
+    }
+
+    #[test]
+    fn games_are_found_by_their_headers() {
+        let pgn = format!(
+            "\u{feff}{}{}",
+            game("Ann", "1. e4 e5"),
+            game("Bob", "1. d4")
+        );
+        let scanned = scan_games(pgn.as_bytes(), 0).unwrap();
+        assert_eq!(scanned.count, 2);
+        assert_eq!(scanned.end, pgn.len() as u64);
+        let (start, content) = scanned.last.unwrap();
+        assert_eq!(&pgn.as_bytes()[start as usize..], content.as_slice());
+        assert!(content.starts_with(b"[Event \"Online\"]\n[White \"Bob\"]"));
+
+        assert_eq!(scan_games("\n\n".as_bytes(), 10).unwrap().count, 0);
+    }
+
+    #[test]
+    fn only_appended_games_are_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("online.pgn");
+        std::fs::write(&path, game("Ann", "1. e4 e5") + &game("Bob", "1. d4")).unwrap();
+        let mut db = test_db("");
+        assert_eq!(update(&mut db, &path).imported, 2);
+        assert_eq!(update(&mut db, &path), PgnUpdateReport::default());
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(game("Cid", "1. c4").as_bytes()).unwrap();
+        assert_eq!(
+            update(&mut db, &path),
+            PgnUpdateReport {
+                imported: 1,
+                duplicates: 0,
+                full_reimport: false,
+            }
+        );
+        assert_eq!(game_count(&mut db), 3);
+        let mark = read_mark(&mut db, &path).unwrap().unwrap();
+        assert_eq!(mark.games, 3);
+        assert_eq!(mark.offset, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn rewritten_files_are_imported_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("online.pgn");
+        std::fs::write(&path, game("Ann", "1. e4 e5") + &game("Bob", "1. d4")).unwrap();
+        let mut db = test_db("");
+        update(&mut db, &path);
+
+        // The last game changed, with a new one after it.
+        std::fs::write(
+            &path,
+            game("Ann", "1. e4 e5") + &game("Bob", "1. d4 d5") + &game("Cid", "1. c4"),
+        )
+        .unwrap();
+        assert_eq!(
+            update(&mut db, &path),
+            PgnUpdateReport {
+                imported: 2,
+                duplicates: 1,
+                full_reimport: true,
+            }
+        );
+
+        // Truncated.
+        std::fs::write(&path, game("Ann", "1. e4 e5")).unwrap();
+        let report = update(&mut db, &path);
+        assert!(report.full_reimport);
+        assert_eq!((report.imported, report.duplicates), (0, 1));
+        assert_eq!(game_count(&mut db), 4);
+    }
+}
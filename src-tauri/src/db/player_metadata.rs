@@ -0,0 +1,371 @@
+//! Player metadata
+//!
+//! Resolves database players against the FIDE list for their federation and title,
+//! and optionally caches a photo of them in the app data directory. Results are
+//! stored per database in the `PlayerMetadata` table, so lookups keep working offline.
+
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+};
+
+use diesel::{connection::SimpleConnection, prelude::*};
+use log::{info, warn};
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::{
+    db::{
+        get_db_or_create,
+        models::{Player, PlayerMetadata},
+        schema::{player_metadata, players},
+        watcher::read_only_uri,
+        ConnectionOptions,
+    },
+    error::Result,
+    fide::{find_fide_player, FidePlayer},
+    fs::download_small_file,
+    AppState,
+};
+
+const PLAYER_METADATA_SQL: &str = include_str!("../../../database/schema/player_metadata.sql");
+
+/// Directory inside the app data directory where player photos are cached.
+const PHOTOS_DIR: &str = "player_photos";
+
+/// Photos larger than this are rejected.
+const MAX_PHOTO_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Placeholder replaced by the player's FIDE ID in photo URL templates.
+const FIDE_ID_PLACEHOLDER: &str = "{fideid}";
+
+/// Databases created before player metadata existed don't have the table yet.
+pub(super) fn ensure_player_metadata_table(db: &mut SqliteConnection) -> Result<()> {
+    db.batch_execute(PLAYER_METADATA_SQL)?;
+    Ok(())
+}
+
+fn photo_exists(metadata: &PlayerMetadata) -> bool {
+    metadata
+        .photo_path
+        .as_ref()
+        .is_some_and(|p| Path::new(p).is_file())
+}
+
+/// Whether cached metadata answers a lookup without going to the network: it only lacks the
+/// photo when one is wanted and the player could be matched to a FIDE ID.
+fn is_cached_enough(cached: &PlayerMetadata, wants_photo: bool) -> bool {
+    !wants_photo || cached.fide_id.is_none() || photo_exists(cached)
+}
+
+/// Fill in the FIDE details of `name` found by a lookup, keeping what was known when it failed.
+fn apply_fide_player(metadata: &mut PlayerMetadata, name: &str, found: Result<Option<FidePlayer>>) {
+    match found {
+        Ok(Some(fide_player)) => {
+            metadata.federation = Some(fide_player.country);
+            metadata.title = fide_player.title.or(fide_player.w_title);
+            metadata.fide_id = Some(fide_player.fideid as i32);
+        }
+        Ok(None) => {}
+        Err(e) => info!("No FIDE match for {}: {}", name, e),
+    }
+}
+
+/// Record the photo of `name` downloaded to `path`, keeping what was known when it failed.
+fn apply_photo(metadata: &mut PlayerMetadata, name: &str, path: &Path, downloaded: Result<()>) {
+    match downloaded {
+        Ok(()) => metadata.photo_path = Some(path.to_string_lossy().to_string()),
+        Err(e) => warn!("Failed to download photo for {}: {}", name, e),
+    }
+}
+
+/// Insert or replace the cached metadata of a player.
+fn store_metadata(db: &mut SqliteConnection, metadata: &PlayerMetadata) -> Result<()> {
+    diesel::insert_into(player_metadata::table)
+        .values(metadata)
+        .on_conflict(player_metadata::player_id)
+        .do_update()
+        .set(metadata)
+        .execute(db)?;
+    Ok(())
+}
+
+/// Resolve federation, title and photo for a player, caching the result in the database.
+///
+/// Cached metadata is returned as-is unless a photo template is given and the photo
+/// is still missing. Failures to reach the network are logged and the cached data
+/// is returned instead.
+#[tauri::command]
+#[specta::specta]
+pub async fn fetch_player_metadata(
+    file: PathBuf,
+    player_id: i32,
+    photo_url_template: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<Option<PlayerMetadata>> {
+    let (cached, player) = {
+        let db =
+            &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+        ensure_player_metadata_table(db)?;
+
+        let cached = player_metadata::table
+            .find(player_id)
+            .first::<PlayerMetadata>(db)
+            .optional()?;
+        let player = players::table
+            .find(player_id)
+            .first::<Player>(db)
+            .optional()?;
+        (cached, player)
+    };
+
+    let Some(name) = player.and_then(|p| p.name) else {
+        return Ok(cached);
+    };
+
+    let wants_photo = photo_url_template.is_some();
+    if let Some(cached) = cached.as_ref().filter(|c| is_cached_enough(c, wants_photo)) {
+        return Ok(Some(cached.clone()));
+    }
+
+    let mut metadata = cached.unwrap_or(PlayerMetadata {
+        player_id,
+        ..Default::default()
+    });
+
+    if metadata.fide_id.is_none() {
+        let found = find_fide_player(name.clone(), state.clone(), app.clone()).await;
+        apply_fide_player(&mut metadata, &name, found);
+    }
+
+    if let (Some(template), Some(fide_id)) = (&photo_url_template, metadata.fide_id) {
+        if !photo_exists(&metadata) {
+            let path = app.path().resolve(
+                format!("{}/{}.jpg", PHOTOS_DIR, fide_id),
+                BaseDirectory::AppData,
+            )?;
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+
+            let url = template.replace(FIDE_ID_PLACEHOLDER, &fide_id.to_string());
+            let downloaded = download_small_file(&url, &path, MAX_PHOTO_SIZE).await;
+            apply_photo(&mut metadata, &name, &path, downloaded);
+        }
+    }
+
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    store_metadata(db, &metadata)?;
+
+    Ok(Some(metadata))
+}
+
+/// Get the cached metadata of several players at once. Never touches the network.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_player_metadata_bulk(
+    file: PathBuf,
+    ids: Vec<i32>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<PlayerMetadata>> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    ensure_player_metadata_table(db)?;
+
+    Ok(player_metadata::table
+        .filter(player_metadata::player_id.eq_any(ids))
+        .load::<PlayerMetadata>(db)?)
+}
+
+/// Photo paths referenced by a database. Databases without the table reference none.
+fn photo_paths(db: &mut SqliteConnection) -> Vec<String> {
+    player_metadata::table
+        .select(player_metadata::photo_path)
+        .filter(player_metadata::photo_path.is_not_null())
+        .load::<Option<String>>(db)
+        .map(|paths| paths.into_iter().flatten().collect())
+        .unwrap_or_default()
+}
+
+/// Photo paths referenced by the database file at `path`, opened read-only so that a
+/// missing database isn't created. Missing databases reference none.
+pub(super) fn stored_photo_paths(path: &Path) -> Vec<String> {
+    SqliteConnection::establish(&read_only_uri(path))
+        .map(|mut db| photo_paths(&mut db))
+        .unwrap_or_default()
+}
+
+/// Remove the photos of a deleted database that no sibling database still references.
+pub(super) fn remove_orphaned_photos(deleted: &Path, photos: Vec<String>) {
+    if photos.is_empty() {
+        return;
+    }
+
+    let mut referenced = HashSet::new();
+    if let Some(Ok(entries)) = deleted.parent().map(read_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_db = matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("db") | Some("db3")
+            );
+            if !is_db || path == deleted {
+                continue;
+            }
+            referenced.extend(stored_photo_paths(&path));
+        }
+    }
+
+    for photo in photos {
+        let path = Path::new(&photo);
+        // Only ever delete files we put in the photo cache ourselves.
+        let in_cache = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .is_some_and(|n| n == PHOTOS_DIR);
+        if !in_cache || referenced.contains(&photo) {
+            continue;
+        }
+        match remove_file(path) {
+            Ok(()) => info!("Removed orphaned player photo {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove player photo {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::error::Error;
+
+    fn with_photo(fide_id: Option<i32>, photo: Option<&Path>) -> PlayerMetadata {
+        PlayerMetadata {
+            player_id: 1,
+            fide_id,
+            photo_path: photo.map(|p| p.to_string_lossy().to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cached_metadata_only_goes_stale_for_a_missing_photo() {
+        let dir = tempfile::tempdir().unwrap();
+        let photo = dir.path().join("1503014.jpg");
+        std::fs::write(&photo, b"jpeg").unwrap();
+        let missing = dir.path().join("missing.jpg");
+
+        assert!(is_cached_enough(&with_photo(Some(1503014), None), false));
+        // Players without a FIDE ID have no photo to look for.
+        assert!(is_cached_enough(&with_photo(None, None), true));
+        assert!(is_cached_enough(
+            &with_photo(Some(1503014), Some(&photo)),
+            true
+        ));
+        assert!(!is_cached_enough(&with_photo(Some(1503014), None), true));
+        assert!(!is_cached_enough(
+            &with_photo(Some(1503014), Some(&missing)),
+            true
+        ));
+    }
+
+    #[test]
+    fn failed_lookups_keep_the_cached_metadata() {
+        let cached = PlayerMetadata {
+            player_id: 1,
+            federation: Some("NOR".to_string()),
+            title: Some("GM".to_string()),
+            photo_path: Some("/photos/1503014.jpg".to_string()),
+            fide_id: Some(1503014),
+        };
+        let mut metadata = cached.clone();
+        let offline = || Error::PackageManager("offline".to_string());
+        apply_fide_player(&mut metadata, "Carlsen, Magnus", Err(offline()));
+        apply_fide_player(&mut metadata, "Carlsen, Magnus", Ok(None));
+        apply_photo(
+            &mut metadata,
+            "Carlsen, Magnus",
+            Path::new("/photos/new.jpg"),
+            Err(offline()),
+        );
+        assert_eq!(metadata.federation, cached.federation);
+        assert_eq!(metadata.title, cached.title);
+        assert_eq!(metadata.photo_path, cached.photo_path);
+        assert_eq!(metadata.fide_id, cached.fide_id);
+
+        apply_photo(
+            &mut metadata,
+            "Carlsen, Magnus",
+            Path::new("/photos/new.jpg"),
+            Ok(()),
+        );
+        assert_eq!(metadata.photo_path.as_deref(), Some("/photos/new.jpg"));
+    }
+
+    #[test]
+    fn metadata_is_cached_per_player() {
+        let db = &mut test_db("");
+        ensure_player_metadata_table(db).unwrap();
+        let mut metadata = with_photo(None, None);
+        store_metadata(db, &metadata).unwrap();
+        metadata.fide_id = Some(1503014);
+        metadata.federation = Some("NOR".to_string());
+        store_metadata(db, &metadata).unwrap();
+
+        let stored = player_metadata::table.load::<PlayerMetadata>(db).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].fide_id, Some(1503014));
+        assert_eq!(stored[0].federation.as_deref(), Some("NOR"));
+    }
+
+    #[test]
+    fn only_unreferenced_cached_photos_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let photos = dir.path().join(PHOTOS_DIR);
+        create_dir_all(&photos).unwrap();
+        let orphan = photos.join("1.jpg");
+        let shared = photos.join("2.jpg");
+        let outside = dir.path().join("3.jpg");
+        for photo in [&orphan, &shared, &outside] {
+            std::fs::write(photo, b"jpeg").unwrap();
+        }
+
+        let dbs = dir.path().join("db");
+        create_dir_all(&dbs).unwrap();
+        let sibling = dbs.join("sibling.db3");
+        let mut db = SqliteConnection::establish(&sibling.to_string_lossy()).unwrap();
+        ensure_player_metadata_table(&mut db).unwrap();
+        store_metadata(&mut db, &with_photo(Some(2), Some(&shared))).unwrap();
+        drop(db);
+
+        let paths = [&orphan, &shared, &outside]
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        remove_orphaned_photos(&dbs.join("deleted.db3"), paths);
+        assert!(!orphan.exists());
+        assert!(shared.exists());
+        // Files outside the photo cache are never the app's to delete.
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn missing_databases_are_not_created_for_their_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.db3");
+        assert!(stored_photo_paths(&missing).is_empty());
+        assert!(!missing.exists());
+
+        let photo = dir.path().join(PHOTOS_DIR).join("1.jpg");
+        let stored = dir.path().join("stored.db3");
+        let mut db = SqliteConnection::establish(&stored.to_string_lossy()).unwrap();
+        ensure_player_metadata_table(&mut db).unwrap();
+        store_metadata(&mut db, &with_photo(Some(1), Some(&photo))).unwrap();
+        drop(db);
+        assert_eq!(
+            stored_photo_paths(&stored),
+            vec![photo.to_string_lossy().to_string()]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Game provenance
+//!
+//! Each game records where it came from in `GameSources`: the PGN file it was imported
+//! from, or the online account it was downloaded from, along with when. Games copied to
+//! another database keep their original source. Databases created before sources were
+//! tracked get the table the first time it's needed, with their games marked as unknown.
+//!
+//! Duplicate removal keeps the copy whose source comes first in a precedence order, so a
+//! game downloaded from its platform can win over the same game found in a PGN file.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use diesel::{
+    connection::SimpleConnection,
+    dsl::sql,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
+    sqlite::Sqlite,
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    db::{models::NormalizedGame, schema::game_sources},
+    error::{Error, Result},
+};
+
+const GAME_SOURCES_SQL: &str = include_str!("../../../database/schema/game_sources.sql");
+
+/// Games looked up per query when loading sources.
+const LOAD_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ProvenanceKind {
+    /// Imported from a PGN file.
+    File,
+    /// Downloaded from an online account.
+    Online,
+    /// Imported before sources were tracked.
+    Unknown,
+}
+
+impl ProvenanceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProvenanceKind::File => "file",
+            ProvenanceKind::Online => "online",
+            ProvenanceKind::Unknown => "unknown",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Self {
+        match label {
+            "file" => ProvenanceKind::File,
+            "online" => ProvenanceKind::Online,
+            _ => ProvenanceKind::Unknown,
+        }
+    }
+}
+
+/// Sources kept first when deleting duplicates, unless configured otherwise.
+pub const DEFAULT_SOURCE_PRECEDENCE: [ProvenanceKind; 3] = [
+    ProvenanceKind::Online,
+    ProvenanceKind::File,
+    ProvenanceKind::Unknown,
+];
+
+/// Where a game came from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct GameProvenance {
+    pub kind: ProvenanceKind,
+    /// File name for files, platform for online accounts.
+    pub name: String,
+    /// Username of online accounts.
+    pub account: Option<String>,
+    /// Unix timestamp in seconds of the import.
+    pub imported_at: Option<i64>,
+}
+
+impl GameProvenance {
+    /// Games imported now from `file`.
+    pub fn file(file: &Path) -> Self {
+        Self {
+            kind: ProvenanceKind::File,
+            name: file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            account: None,
+            imported_at: Some(chrono::Utc::now().timestamp()),
+        }
+    }
+
+    /// Games downloaded now from `account`.
+    pub fn online(account: &OnlineAccount) -> Self {
+        Self {
+            kind: ProvenanceKind::Online,
+            name: account.platform.clone(),
+            account: Some(account.username.clone()),
+            imported_at: Some(chrono::Utc::now().timestamp()),
+        }
+    }
+
+    pub fn unknown() -> Self {
+        Self {
+            kind: ProvenanceKind::Unknown,
+            name: String::new(),
+            account: None,
+            imported_at: None,
+        }
+    }
+}
+
+/// Account games are downloaded from, e.g. `lichess` and a username.
+#[derive(Deserialize, Debug, Clone, Type)]
+pub struct OnlineAccount {
+    pub platform: String,
+    pub username: String,
+}
+
+/// Games from a source, as filtered by `GameQueryJs::source`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash, Type)]
+pub struct ProvenanceQuery {
+    pub kind: ProvenanceKind,
+    /// Only games from this file or platform.
+    #[specta(optional)]
+    pub name: Option<String>,
+    /// Only games from this online account.
+    #[specta(optional)]
+    pub account: Option<String>,
+}
+
+/// Number of games of a source, as shown in `DatabaseInfo`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct ProvenanceCount {
+    pub kind: ProvenanceKind,
+    pub name: String,
+    pub account: Option<String>,
+    pub count: i64,
+}
+
+#[derive(QueryableByName)]
+struct ProvenanceCountRow {
+    #[diesel(sql_type = Text, column_name = "Kind")]
+    kind: String,
+    #[diesel(sql_type = Text, column_name = "Name")]
+    name: String,
+    #[diesel(sql_type = Nullable<Text>, column_name = "Account")]
+    account: Option<String>,
+    #[diesel(sql_type = BigInt, column_name = "Count")]
+    count: i64,
+}
+
+/// Databases created before sources were tracked don't have the table yet; their games are
+/// marked as unknown when it's created.
+pub(crate) fn ensure_sources_table(db: &mut SqliteConnection) -> Result<()> {
+    let exists: bool = diesel::select(sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'GameSources')",
+    ))
+    .get_result(db)?;
+    if exists {
+        return Ok(());
+    }
+    db.transaction::<_, Error, _>(|db| {
+        db.batch_execute(GAME_SOURCES_SQL)?;
+        db.batch_execute(
+            "INSERT OR IGNORE INTO GameSources (GameID, Kind) SELECT ID, 'unknown' FROM Games",
+        )?;
+        Ok(())
+    })
+}
+
+pub(crate) fn record_source(
+    db: &mut SqliteConnection,
+    game_id: i32,
+    source: &GameProvenance,
+) -> Result<()> {
+    diesel::replace_into(game_sources::table)
+        .values((
+            game_sources::game_id.eq(game_id),
+            game_sources::kind.eq(source.kind.as_str()),
+            game_sources::name.eq(&source.name),
+            game_sources::account.eq(&source.account),
+            game_sources::imported_at.eq(source.imported_at),
+        ))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Sources of the games `ids`. Games without one are left out.
+pub(crate) fn load_sources(
+    db: &mut SqliteConnection,
+    ids: &[i32],
+) -> Result<HashMap<i32, GameProvenance>> {
+    let mut sources = HashMap::with_capacity(ids.len());
+    for batch in ids.chunks(LOAD_BATCH_SIZE) {
+        let rows: Vec<(i32, String, String, Option<String>, Option<i64>)> = game_sources::table
+            .filter(game_sources::game_id.eq_any(batch))
+            .load(db)?;
+        sources.extend(
+            rows.into_iter()
+                .map(|(id, kind, name, account, imported_at)| {
+                    let source = GameProvenance {
+                        kind: ProvenanceKind::from_label(&kind),
+                        name,
+                        account,
+                        imported_at,
+                    };
+                    (id, source)
+                }),
+        );
+    }
+    Ok(sources)
+}
+
+/// Fill in the source of listed games, as unknown for games without one.
+pub(crate) fn attach_sources(
+    db: &mut SqliteConnection,
+    games: &mut [NormalizedGame],
+) -> Result<()> {
+    ensure_sources_table(db)?;
+    let ids: Vec<i32> = games.iter().map(|game| game.id).collect();
+    let mut sources = load_sources(db, &ids)?;
+    for game in games {
+        game.source = Some(
+            sources
+                .remove(&game.id)
+                .unwrap_or_else(GameProvenance::unknown),
+        );
+    }
+    Ok(())
+}
+
+/// IDs of the games matching `query`, for use as a subquery.
+pub(crate) fn games_from(
+    query: &ProvenanceQuery,
+) -> game_sources::BoxedQuery<'static, Sqlite, Integer> {
+    let mut ids = game_sources::table
+        .filter(game_sources::kind.eq(query.kind.as_str()))
+        .select(game_sources::game_id)
+        .into_boxed();
+    if let Some(name) = &query.name {
+        ids = ids.filter(game_sources::name.eq(name.clone()));
+    }
+    if let Some(account) = &query.account {
+        ids = ids.filter(game_sources::account.eq(account.clone()));
+    }
+    ids
+}
+
+/// Number of games of each source, largest first. Games without one count as unknown.
+pub(crate) fn source_counts(db: &mut SqliteConnection) -> Result<Vec<ProvenanceCount>> {
+    ensure_sources_table(db)?;
+    let rows: Vec<ProvenanceCountRow> = sql_query(
+        "SELECT COALESCE(s.Kind, 'unknown') AS Kind, COALESCE(s.Name, '') AS Name, \
+         s.Account AS Account, COUNT(*) AS Count FROM Games g \
+         LEFT JOIN GameSources s ON s.GameID = g.ID \
+         GROUP BY 1, 2, 3 ORDER BY Count DESC, Kind, Name",
+    )
+    .load(db)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ProvenanceCount {
+            kind: ProvenanceKind::from_label(&row.kind),
+            name: row.name,
+            account: row.account,
+            count: row.count,
+        })
+        .collect())
+}
+
+/// SQL ordering games of `GameSources s` by the position of their source in `precedence`.
+/// Sources left out of it, and games without one, come last.
+pub(crate) fn precedence_order(precedence: &[ProvenanceKind]) -> String {
+    let mut order = String::from("CASE COALESCE(s.Kind, 'unknown')");
+    for (rank, kind) in precedence.iter().enumerate() {
+        order.push_str(&format!(" WHEN '{}' THEN {}", kind.as_str(), rank));
+    }
+    order.push_str(&format!(" ELSE {} END", precedence.len()));
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{import_test_games, test_db};
+
+    fn db_with(pgn: &str, source: &GameProvenance) -> SqliteConnection {
+        let mut db = test_db("");
+        for id in import_test_games(&mut db, pgn) {
+            record_source(&mut db, id, source).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn games_imported_before_sources_are_unknown() {
+        let mut db = db_with(
+            "[White \"A\"]\n[Black \"B\"]\n\n1. e4 *\n\n[White \"C\"]\n[Black \"D\"]\n\n1. d4 *\n\n",
+            &GameProvenance::file(Path::new("/games/old.pgn")),
+        );
+        // A database from before sources were tracked.
+        db.batch_execute("DROP TABLE GameSources").unwrap();
+        ensure_sources_table(&mut db).unwrap();
+
+        let counts = source_counts(&mut db).unwrap();
+        assert_eq!(
+            counts,
+            vec![ProvenanceCount {
+                kind: ProvenanceKind::Unknown,
+                name: String::new(),
+                account: None,
+                count: 2,
+            }]
+        );
+        // Creating the table again keeps the sources recorded since.
+        let online = GameProvenance::online(&OnlineAccount {
+            platform: "lichess".to_string(),
+            username: "someone".to_string(),
+        });
+        record_source(&mut db, 1, &online).unwrap();
+        ensure_sources_table(&mut db).unwrap();
+        assert_eq!(load_sources(&mut db, &[1]).unwrap()[&1], online);
+    }
+
+    #[test]
+    fn precedence_ranks_unlisted_sources_last() {
+        assert_eq!(
+            precedence_order(&[ProvenanceKind::File]),
+            "CASE COALESCE(s.Kind, 'unknown') WHEN 'file' THEN 0 ELSE 1 END"
+        );
+    }
+}
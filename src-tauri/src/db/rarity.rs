@@ -0,0 +1,240 @@
+//! Opening rarity
+//!
+//! `OpeningFrequencies` counts the games of each opening, keyed by ECO code, so games can be
+//! sorted by how rare their opening is in the database and narrowed down to the rarest ones.
+//! Triggers on `Games` adjust the counts as games are added, deleted or given another ECO
+//! code, so imports and deletions never need a full recount. Databases created before the
+//! table existed are counted once, the first time it's needed. Games without an ECO code have
+//! no rarity and come last whichever way games are sorted.
+
+use std::path::PathBuf;
+
+use diesel::{
+    connection::SimpleConnection,
+    dsl::{count_star, sql},
+    expression::SqlLiteral,
+    prelude::*,
+    sql_types::{Bool, Integer, Nullable, Text},
+    sqlite::Sqlite,
+};
+
+use crate::{
+    db::{
+        get_db_or_create, schema::opening_frequencies, write_lock, ConnectionOptions, SortDirection,
+    },
+    error::{Error, Result},
+    AppState,
+};
+
+const OPENING_FREQUENCIES_SQL: &str =
+    include_str!("../../../database/schema/opening_frequencies.sql");
+
+const RECOUNT_SQL: &str = "DELETE FROM OpeningFrequencies; \
+     INSERT INTO OpeningFrequencies (OpeningKey, GameCount) \
+     SELECT ECO, COUNT(*) FROM Games WHERE ECO IS NOT NULL AND ECO <> '' GROUP BY ECO;";
+
+/// Number of games sharing the opening of the game in the current row of `Games`.
+const FREQUENCY_SQL: &str =
+    "(SELECT GameCount FROM OpeningFrequencies WHERE OpeningKey = Games.ECO)";
+
+/// Databases created before openings were counted don't have the table yet; their games
+/// are counted when it's created.
+pub(crate) fn ensure_frequency_table(db: &mut SqliteConnection) -> Result<()> {
+    let exists: bool = diesel::select(sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'OpeningFrequencies')",
+    ))
+    .get_result(db)?;
+    if exists {
+        return Ok(());
+    }
+    db.transaction::<_, Error, _>(|db| {
+        db.batch_execute(OPENING_FREQUENCIES_SQL)?;
+        db.batch_execute(RECOUNT_SQL)?;
+        Ok(())
+    })
+}
+
+/// Ordering of a games query by opening rarity, rarest first when descending.
+pub(crate) fn rarity_order(direction: &SortDirection) -> SqlLiteral<Integer> {
+    let frequency_order = match direction {
+        SortDirection::Asc => "DESC",
+        SortDirection::Desc => "ASC",
+    };
+    sql(&format!(
+        "{FREQUENCY_SQL} IS NULL, {FREQUENCY_SQL} {frequency_order}"
+    ))
+}
+
+/// Largest number of games an opening may have to be among the rarest `percent` of games.
+///
+/// Openings aren't split, so games of the opening crossing the line all count as rare and
+/// slightly more than `percent` of the games may be. Zero when no game is rare enough.
+pub(crate) fn rarity_threshold(db: &mut SqliteConnection, percent: u32) -> Result<i32> {
+    let frequencies: Vec<(i32, i64)> = opening_frequencies::table
+        .group_by(opening_frequencies::game_count)
+        .select((opening_frequencies::game_count, count_star()))
+        .order(opening_frequencies::game_count.asc())
+        .load(db)?;
+    let games_of = |(games, openings): &(i32, i64)| *games as u64 * *openings as u64;
+    let total: u64 = frequencies.iter().map(games_of).sum();
+    let wanted = (total * percent.min(100) as u64).div_ceil(100);
+    if wanted == 0 {
+        return Ok(0);
+    }
+    let mut counted = 0;
+    for frequency in &frequencies {
+        counted += games_of(frequency);
+        if counted >= wanted {
+            return Ok(frequency.0);
+        }
+    }
+    Ok(frequencies.last().map_or(0, |(games, _)| *games))
+}
+
+/// ECO codes of the openings with at most `threshold` games.
+pub(crate) fn openings_up_to(
+    threshold: i32,
+) -> opening_frequencies::BoxedQuery<'static, Sqlite, Nullable<Text>> {
+    opening_frequencies::table
+        .filter(opening_frequencies::game_count.le(threshold))
+        .select(opening_frequencies::opening_key.nullable())
+        .into_boxed()
+}
+
+/// Count the games of every opening of a database again, returning the number of openings.
+///
+/// Counts are kept up to date as games change, so this is only needed to repair them, such
+/// as after the database was edited by another program.
+#[tauri::command]
+#[specta::specta]
+pub async fn compute_opening_frequencies(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<u32> {
+    let id = file.to_string_lossy().to_string();
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    ensure_frequency_table(db)?;
+    db.immediate_transaction::<_, Error, _>(|db| {
+        db.batch_execute(RECOUNT_SQL)?;
+        Ok(())
+    })?;
+    let openings: i64 = opening_frequencies::table.count().get_result(db)?;
+    Ok(openings as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{schema::games, test_db};
+
+    fn db_with(ecos: &[Option<&str>]) -> SqliteConnection {
+        let pgn: String = ecos
+            .iter()
+            .map(|eco| match eco {
+                Some(eco) => format!("[ECO \"{eco}\"]\n\n1. e4 *\n\n"),
+                None => "[White \"A\"]\n\n1. e4 *\n\n".to_string(),
+            })
+            .collect();
+        test_db(&pgn)
+    }
+
+    fn frequencies(db: &mut SqliteConnection) -> Vec<(String, i32)> {
+        opening_frequencies::table
+            .select((
+                opening_frequencies::opening_key,
+                opening_frequencies::game_count,
+            ))
+            .order(opening_frequencies::opening_key)
+            .load(db)
+            .unwrap()
+    }
+
+    fn ids_by_rarity(db: &mut SqliteConnection, direction: SortDirection) -> Vec<i32> {
+        games::table
+            .select(games::id)
+            .order(rarity_order(&direction))
+            .then_order_by(games::id)
+            .load(db)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_follow_inserted_and_deleted_games() {
+        let mut db = db_with(&[Some("B01"), Some("C20"), Some("B01"), None]);
+        assert_eq!(
+            frequencies(&mut db),
+            vec![("B01".to_string(), 2), ("C20".to_string(), 1)]
+        );
+
+        diesel::delete(games::table.filter(games::id.eq(2)))
+            .execute(&mut db)
+            .unwrap();
+        diesel::delete(games::table.filter(games::id.eq(4)))
+            .execute(&mut db)
+            .unwrap();
+        assert_eq!(frequencies(&mut db), vec![("B01".to_string(), 2)]);
+
+        diesel::update(games::table.filter(games::id.eq(1)))
+            .set(games::eco.eq("A00"))
+            .execute(&mut db)
+            .unwrap();
+        assert_eq!(
+            frequencies(&mut db),
+            vec![("A00".to_string(), 1), ("B01".to_string(), 1)]
+        );
+
+        // A database from before openings were counted.
+        db.batch_execute(
+            "DROP TABLE OpeningFrequencies; \
+             DROP TRIGGER opening_frequencies_insert; \
+             DROP TRIGGER opening_frequencies_delete; \
+             DROP TRIGGER opening_frequencies_update;",
+        )
+        .unwrap();
+        ensure_frequency_table(&mut db).unwrap();
+        assert_eq!(
+            frequencies(&mut db),
+            vec![("A00".to_string(), 1), ("B01".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn games_sorted_and_filtered_by_rarity() {
+        let mut db = db_with(&[
+            Some("A00"),
+            Some("C20"),
+            None,
+            Some("A00"),
+            Some("B01"),
+            Some("C20"),
+            Some("A00"),
+        ]);
+
+        assert_eq!(
+            ids_by_rarity(&mut db, SortDirection::Desc),
+            vec![5, 2, 6, 1, 4, 7, 3]
+        );
+        assert_eq!(
+            ids_by_rarity(&mut db, SortDirection::Asc),
+            vec![1, 4, 7, 2, 6, 5, 3]
+        );
+
+        // Six games have an opening: the rarest 10% is the B01 game, and the rarest 20% takes
+        // in both C20 games.
+        assert_eq!(rarity_threshold(&mut db, 10).unwrap(), 1);
+        assert_eq!(rarity_threshold(&mut db, 20).unwrap(), 2);
+        assert_eq!(rarity_threshold(&mut db, 100).unwrap(), 3);
+        assert_eq!(rarity_threshold(&mut db, 0).unwrap(), 0);
+
+        let rare: Vec<i32> = games::table
+            .select(games::id)
+            .filter(games::eco.eq_any(openings_up_to(2)))
+            .order(games::id)
+            .load(&mut db)
+            .unwrap();
+        assert_eq!(rare, vec![2, 5, 6]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Re-evaluation of a game's variations
+//!
+//! Annotated games often carry variations whose evaluations were never checked, or were
+//! checked with a much weaker engine. `reevaluate_variations` runs an engine on the final
+//! position of every variation (and optionally on every move at a fixed depth) and writes
+//! the result into the move comments as `+0.45/20 Stockfish 17`, keeping the human text.
+//! The engine and search mode are recorded as the game's latest analysis.
+
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use log::info;
+use serde::Serialize;
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, EnPassantMode, FromSetup, Position};
+use specta::Type;
+use vampirc_uci::{
+    parse_one,
+    uci::{Score, ScoreValue},
+    UciMessage,
+};
+
+use crate::{
+    chess::{parse_uci_attrs, EngineOptions, EngineProcess, GoMode},
+    db::{
+        attribution::{record_attribution, AnalysisAttribution},
+        core::{game_revision, replace_moves},
+        get_db_or_create, get_start_position,
+        models::Game,
+        pgn::GameTree,
+        schema::games,
+        ConnectionOptions,
+    },
+    error::Result,
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+/// Move time used instead of an infinite search, which would never finish.
+const INFINITE_FALLBACK_MOVETIME: u32 = 1000;
+
+#[derive(Serialize, Debug, Type)]
+pub struct ReevaluationReport {
+    /// Moves whose comment received an evaluation.
+    pub evaluated: u32,
+    /// Moves that were to be evaluated.
+    pub total: u32,
+    pub cancelled: bool,
+    /// Revision of the game after the update.
+    pub revision: String,
+}
+
+/// Format an evaluation from White's point of view, e.g. `+0.45/20 Stockfish 17` or `#-3/18 Stockfish 17`.
+pub(crate) fn format_eval(score: &Score, depth: u32, engine: &str) -> String {
+    let value = match score.value {
+        ScoreValue::Cp(cp) => format!("{:+.2}", cp as f64 / 100.0),
+        ScoreValue::Mate(mate) => format!("#{}", mate),
+    };
+    format!("{}/{} {}", value, depth, engine)
+}
+
+/// Evaluate the end of every variation of a game with a single engine and store the results
+/// in the move comments.
+///
+/// With `every_node_depth`, every other move is evaluated too, searching to that depth.
+/// Progress is reported as a cancellable analysis task whose id is `<db_path>#<game_id>`;
+/// cancelling keeps the evaluations written so far.
+#[tauri::command]
+#[specta::specta]
+pub async fn reevaluate_variations(
+    db_path: PathBuf,
+    game_id: i32,
+    engine: String,
+    go_mode: GoMode,
+    every_node_depth: Option<u32>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ReevaluationReport> {
+    let go_mode = match go_mode {
+        GoMode::Infinite => GoMode::Time(INFINITE_FALLBACK_MOVETIME),
+        go_mode => go_mode,
+    };
+    let path = db_path.to_string_lossy().to_string();
+
+    let (game, start) = {
+        let db = &mut get_db_or_create(&state, &path, ConnectionOptions::default())?;
+        let game: Game = games::table.find(game_id).first(db)?;
+        let start = match game.fen.as_deref() {
+            Some(fen) => {
+                let fen = Fen::from_ascii(fen.as_bytes())?;
+                Chess::from_setup(fen.into(), CastlingMode::Chess960)?
+            }
+            None => get_start_position(db)?,
+        };
+        (game, start)
+    };
+    let revision = game_revision(&game.moves);
+    let start_fen = Fen::from_position(start.clone(), EnPassantMode::Legal).to_string();
+
+    let mut tree = GameTree::from_bytes(&game.moves, Some(start.clone()))?;
+    let targets = tree.eval_targets(start.clone(), every_node_depth.is_some());
+    let total = targets.len() as u32;
+
+    let engine_path = PathBuf::from(&engine);
+    let (mut proc, mut reader) = EngineProcess::new(engine_path.clone(), &state.path_scope).await?;
+    let engine_name = proc.engine_name().unwrap_or_else(|| {
+        engine_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or(engine)
+    });
+
+    let task = TaskHandle::start(
+        &app,
+        TaskKind::Analysis,
+        &format!("{}#{}", db_path.display(), game_id),
+        true,
+    );
+    let mut evaluated = 0;
+    let mut cancelled = false;
+    for (i, target) in targets.iter().enumerate() {
+        if task.is_cancelled() {
+            cancelled = true;
+            break;
+        }
+        task.report(
+            i as f64 / total as f64 * 100.0,
+            Some(format!("{}/{}", i, total)),
+        );
+
+        let mut position = start.clone();
+        for m in &target.moves {
+            let mv = UciMove::from_ascii(m.as_bytes())?.to_move(&position)?;
+            position.play_unchecked(&mv);
+        }
+        if position.legal_moves().is_empty() {
+            continue;
+        }
+
+        proc.set_options(EngineOptions {
+            fen: start_fen.clone(),
+            moves: target.moves.clone(),
+            extra_options: Vec::new(),
+        })
+        .await?;
+        let mode = match every_node_depth {
+            Some(depth) if !target.variation_end => GoMode::Depth(depth),
+            _ => go_mode.clone(),
+        };
+        proc.go(&mode).await?;
+
+        let fen: Fen = start_fen.parse()?;
+        let mut best = None;
+        while let Ok(Some(line)) = reader.next_line().await {
+            match parse_one(&line) {
+                UciMessage::Info(attrs) => {
+                    if let Ok(best_moves) = parse_uci_attrs(attrs, &fen, &target.moves) {
+                        if best_moves.multipv == 1 {
+                            best = Some(best_moves);
+                        }
+                    }
+                }
+                UciMessage::BestMove { .. } => break,
+                _ => {}
+            }
+        }
+
+        if let Some(best) = best {
+            tree.set_eval_comment(
+                &target.path,
+                &format_eval(&best.score, best.depth, &engine_name),
+            )?;
+            evaluated += 1;
+        }
+    }
+    proc.kill().await?;
+
+    let revision = if evaluated > 0 {
+        let mut moves = Vec::new();
+        tree.encode(&mut moves, Some(start));
+        let db = &mut get_db_or_create(&state, &path, ConnectionOptions::default())?;
+        let revision = replace_moves(db, game_id, &revision, &moves)?;
+        record_attribution(
+            db,
+            game_id,
+            &AnalysisAttribution::now(&engine_name, go_mode),
+        )?;
+        revision
+    } else {
+        revision
+    };
+    task.finish();
+
+    info!(
+        "Re-evaluated {}/{} moves of game {} in {}",
+        evaluated,
+        total,
+        game_id,
+        db_path.display()
+    );
+    Ok(ReevaluationReport {
+        evaluated,
+        total,
+        cancelled,
+        revision,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evals_are_formatted_as_pawns_or_mates() {
+        let score = |value| Score {
+            value,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_eval(&score(ScoreValue::Cp(45)), 20, "Stockfish 17"),
+            "+0.45/20 Stockfish 17"
+        );
+        assert_eq!(
+            format_eval(&score(ScoreValue::Cp(-130)), 18, "Stockfish 17"),
+            "-1.30/18 Stockfish 17"
+        );
+        assert_eq!(
+            format_eval(&score(ScoreValue::Cp(0)), 1, "Komodo"),
+            "+0.00/1 Komodo"
+        );
+        assert_eq!(
+            format_eval(&score(ScoreValue::Mate(-3)), 30, "Komodo"),
+            "#-3/30 Komodo"
+        );
+    }
+}
@@ -0,0 +1,789 @@
+//! Opening repertoire export
+//!
+//! A repertoire is a tree of moves with the results of the games that reached each of them,
+//! either built by the frontend or collected from the games of a player in a database. It is
+//! exported as a multi-chapter PGN ready to be imported as a Lichess study, or as a single
+//! HTML file with a collapsible move tree that needs no other assets.
+//!
+//! Study chapters are cut at the first position where the repertoire branches, one chapter
+//! per reply, and chapters longer than the maximum size are cut again at their next branching
+//! position. A position reached again through a transposition keeps only a pointer to the
+//! line it was first reached by, so its moves appear in a single chapter.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use pgn_reader::SanPlus;
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, CastlingMode, Chess, EnPassantMode, FromSetup, Position};
+use specta::Type;
+
+use crate::{
+    db::{
+        get_db_or_create, get_start_position,
+        pgn::{GameTree, GameTreeNode},
+        schema::{games, players},
+        ConnectionOptions,
+    },
+    error::Result,
+    AppState,
+};
+
+const HTML_TEMPLATE: &str = include_str!("../../data/repertoire.html");
+
+/// Moves a study chapter holds before it is cut at its next branching position.
+const DEFAULT_MAX_CHAPTER_MOVES: usize = 200;
+
+/// A move of a repertoire and the results of the games that reached it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+pub struct RepertoireNode {
+    /// Move leading to the node in SAN, empty for the root.
+    pub san: String,
+    pub white: i32,
+    pub draw: i32,
+    pub black: i32,
+    pub children: Vec<RepertoireNode>,
+}
+
+impl RepertoireNode {
+    fn games(&self) -> i32 {
+        self.white + self.draw + self.black
+    }
+
+    /// Add the moves of a game with the given result.
+    fn add_game(&mut self, moves: &[String], result: &str) {
+        let count = |node: &mut RepertoireNode| match result {
+            "1-0" => node.white += 1,
+            "0-1" => node.black += 1,
+            _ => node.draw += 1,
+        };
+        count(self);
+        let mut node = self;
+        for san in moves {
+            let index = match node.children.iter().position(|c| &c.san == san) {
+                Some(index) => index,
+                None => {
+                    node.children.push(RepertoireNode {
+                        san: san.clone(),
+                        ..Default::default()
+                    });
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[index];
+            count(node);
+        }
+    }
+
+    /// Remove the moves played in fewer than `min_games` games.
+    fn prune(&mut self, min_games: i32) {
+        self.children.retain(|child| child.games() >= min_games);
+        for child in &mut self.children {
+            child.prune(min_games);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum RepertoireColor {
+    White,
+    Black,
+}
+
+/// Where the exported repertoire comes from.
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RepertoireSource {
+    /// A tree built by the frontend, from `fen` or the standard starting position.
+    Tree {
+        fen: Option<String>,
+        root: RepertoireNode,
+    },
+    /// The games of a player with one color in a database.
+    #[serde(rename_all = "camelCase")]
+    Query {
+        file: PathBuf,
+        player: i32,
+        color: RepertoireColor,
+        max_ply: u32,
+        min_games: u32,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum RepertoireFormat {
+    LichessStudy,
+    Html,
+}
+
+/// A repertoire move ready for export.
+struct Node {
+    san: String,
+    /// Move number, and whether White played the move.
+    number: u32,
+    white_move: bool,
+    /// Moves from the root up to this one, e.g. `1. d4 Nf6 2. c4`.
+    line: String,
+    white: i32,
+    draw: i32,
+    black: i32,
+    /// Line first reaching the position after this move, if it was reached before.
+    transposes_to: Option<String>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn size(&self) -> usize {
+        1 + self.children.iter().map(Node::size).sum::<usize>()
+    }
+}
+
+fn move_text(number: u32, white_move: bool, san: &str, numbered: bool) -> String {
+    if white_move {
+        format!("{}. {}", number, san)
+    } else if numbered {
+        format!("{}... {}", number, san)
+    } else {
+        san.to_string()
+    }
+}
+
+/// Key of a position, ignoring the move counters.
+fn position_key(position: &Chess) -> String {
+    let fen = Fen::from_position(position.clone(), EnPassantMode::Legal).to_string();
+    fen.split(' ').take(4).collect::<Vec<_>>().join(" ")
+}
+
+/// Validate the moves of the repertoire, ordering them by popularity and cutting the lines
+/// after transpositions.
+///
+/// `lines` maps the positions already reached to the line first reaching them; as moves are
+/// visited depth-first, that is the most popular one.
+fn prepare(
+    node: &RepertoireNode,
+    position: &Chess,
+    line: &str,
+    lines: &mut HashMap<String, String>,
+) -> Result<Vec<Node>> {
+    let mut children: Vec<&RepertoireNode> = node.children.iter().collect();
+    children.sort_by_key(|child| Reverse(child.games()));
+    children
+        .into_iter()
+        .map(|child| {
+            let mv = SanPlus::from_ascii(child.san.as_bytes())?
+                .san
+                .to_move(position)?;
+            let number = position.fullmoves().get();
+            let white_move = position.turn().is_white();
+            let mut after = position.clone();
+            let san = SanPlus::from_move_and_play_unchecked(&mut after, &mv).to_string();
+            let text = move_text(number, white_move, &san, line.is_empty());
+            let line = if line.is_empty() {
+                text
+            } else {
+                format!("{} {}", line, text)
+            };
+
+            let key = position_key(&after);
+            let transposes_to = lines.get(&key).cloned();
+            let children = if transposes_to.is_none() {
+                lines.insert(key, line.clone());
+                prepare(child, &after, &line, lines)?
+            } else {
+                Vec::new()
+            };
+            Ok(Node {
+                san,
+                number,
+                white_move,
+                line,
+                white: child.white,
+                draw: child.draw,
+                black: child.black,
+                transposes_to,
+                children,
+            })
+        })
+        .collect()
+}
+
+/// The repertoire moves of `root`, ready for export.
+fn prepare_tree(root: &RepertoireNode, position: &Chess) -> Result<Vec<Node>> {
+    prepare(root, position, "", &mut HashMap::new())
+}
+
+/// A study chapter: the moves from the root, each with the alternatives shown next to it.
+/// The last move is followed by all the moves after it.
+struct Chapter<'a> {
+    /// Line of the move the chapter is about.
+    title: &'a str,
+    line: Vec<(&'a Node, Vec<&'a Node>)>,
+}
+
+/// Cut the chapters of a branching position, keeping the transpositions, which have no
+/// moves of their own, next to the first of the other moves.
+fn split_branches<'a>(
+    line: Vec<(&'a Node, Vec<&'a Node>)>,
+    children: &'a [Node],
+    max_moves: usize,
+    chapters: &mut Vec<Chapter<'a>>,
+) {
+    let (mut transpositions, own): (Vec<&Node>, Vec<&Node>) = children
+        .iter()
+        .partition(|child| child.transposes_to.is_some());
+    if own.is_empty() {
+        if !transpositions.is_empty() {
+            let first = transpositions.remove(0);
+            let mut line = line;
+            line.push((first, transpositions));
+            chapters.push(Chapter {
+                title: &first.line,
+                line,
+            });
+        }
+        return;
+    }
+    for child in own {
+        split_chapter(
+            line.clone(),
+            child,
+            std::mem::take(&mut transpositions),
+            max_moves,
+            chapters,
+        );
+    }
+}
+
+fn split_chapter<'a>(
+    mut line: Vec<(&'a Node, Vec<&'a Node>)>,
+    node: &'a Node,
+    alternatives: Vec<&'a Node>,
+    max_moves: usize,
+    chapters: &mut Vec<Chapter<'a>>,
+) {
+    line.push((node, alternatives));
+    let title = &node.line;
+    if node.size() <= max_moves {
+        chapters.push(Chapter { title, line });
+        return;
+    }
+    let mut branch = node;
+    while let [only] = branch.children.as_slice() {
+        branch = only;
+        line.push((only, Vec::new()));
+    }
+    if branch.children.is_empty() {
+        chapters.push(Chapter { title, line });
+    } else {
+        split_branches(line, &branch.children, max_moves, chapters);
+    }
+}
+
+/// Cut a repertoire into chapters: one per move at the first branching position, cut again
+/// while longer than `max_moves`.
+fn split_chapters(nodes: &[Node], max_moves: usize) -> Vec<Chapter<'_>> {
+    let mut chapters = Vec::new();
+    let mut line = Vec::new();
+    let mut children = nodes;
+    while let [only] = children {
+        line.push((only, Vec::new()));
+        children = &only.children;
+    }
+    if children.is_empty() {
+        if let Some((last, _)) = line.last() {
+            chapters.push(Chapter {
+                title: &last.line,
+                line,
+            });
+        }
+    } else {
+        split_branches(line, children, max_moves, &mut chapters);
+    }
+    chapters
+}
+
+fn push_move(out: &mut String, node: &Node, numbered: bool) {
+    if !out.is_empty() && !out.ends_with('(') {
+        out.push(' ');
+    }
+    out.push_str(&move_text(
+        node.number,
+        node.white_move,
+        &node.san,
+        numbered,
+    ));
+    if let Some(line) = &node.transposes_to {
+        write!(out, " {{Transposes to {}}}", line).unwrap();
+    }
+}
+
+fn push_alternative(out: &mut String, node: &Node) {
+    out.push_str(" (");
+    push_move(out, node, true);
+    push_moves(out, &node.children, node.transposes_to.is_some());
+    out.push(')');
+}
+
+/// Write the moves after a position as PGN movetext, the first move as the main line.
+fn push_moves(out: &mut String, nodes: &[Node], numbered: bool) {
+    let Some((main, alternatives)) = nodes.split_first() else {
+        return;
+    };
+    push_move(out, main, numbered);
+    for alternative in alternatives {
+        push_alternative(out, alternative);
+    }
+    push_moves(
+        out,
+        &main.children,
+        !alternatives.is_empty() || main.transposes_to.is_some(),
+    );
+}
+
+fn chapter_movetext(chapter: &Chapter) -> String {
+    let mut out = String::new();
+    let mut numbered = true;
+    for (node, alternatives) in &chapter.line {
+        push_move(&mut out, node, numbered);
+        for alternative in alternatives {
+            push_alternative(&mut out, alternative);
+        }
+        numbered = !alternatives.is_empty() || node.transposes_to.is_some();
+    }
+    if let Some((last, _)) = chapter.line.last() {
+        push_moves(&mut out, &last.children, numbered);
+    }
+    out
+}
+
+fn header_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Write a repertoire as a multi-chapter PGN for import as a Lichess study.
+fn study_pgn(
+    nodes: &[Node],
+    title: &str,
+    fen: Option<&str>,
+    color: Option<RepertoireColor>,
+    max_moves: usize,
+) -> String {
+    let mut out = String::new();
+    for chapter in split_chapters(nodes, max_moves) {
+        let name = chapter.title;
+        writeln!(
+            out,
+            "[Event \"{}: {}\"]",
+            header_value(title),
+            header_value(name)
+        )
+        .unwrap();
+        writeln!(out, "[Site \"?\"]").unwrap();
+        writeln!(out, "[Result \"*\"]").unwrap();
+        writeln!(out, "[StudyName \"{}\"]", header_value(title)).unwrap();
+        writeln!(out, "[ChapterName \"{}\"]", header_value(name)).unwrap();
+        if let Some(color) = color {
+            let color = match color {
+                RepertoireColor::White => "white",
+                RepertoireColor::Black => "black",
+            };
+            writeln!(out, "[Orientation \"{}\"]", color).unwrap();
+        }
+        if let Some(fen) = fen {
+            writeln!(out, "[SetUp \"1\"]").unwrap();
+            writeln!(out, "[FEN \"{}\"]", header_value(fen)).unwrap();
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "{} *", chapter_movetext(&chapter)).unwrap();
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent(count: i32, total: i32) -> i32 {
+    (count as f64 * 100.0 / total as f64).round() as i32
+}
+
+fn html_node(out: &mut String, node: &Node, depth: usize) {
+    let mut label = format!(
+        "<span class=\"move\">{}</span>",
+        escape_html(&move_text(node.number, node.white_move, &node.san, true))
+    );
+    let total = node.white + node.draw + node.black;
+    if total > 0 {
+        let (white, draw, black) = (
+            percent(node.white, total),
+            percent(node.draw, total),
+            percent(node.black, total),
+        );
+        write!(
+            label,
+            "<span class=\"stats\">{} games · {}% / {}% / {}%</span>\
+             <span class=\"bar\"><i class=\"white\" style=\"width:{}%\"></i>\
+             <i class=\"draw\" style=\"width:{}%\"></i>\
+             <i class=\"black\" style=\"width:{}%\"></i></span>",
+            total, white, draw, black, white, draw, black
+        )
+        .unwrap();
+    }
+    if let Some(line) = &node.transposes_to {
+        write!(
+            label,
+            "<span class=\"transposition\">Transposes to {}</span>",
+            escape_html(line)
+        )
+        .unwrap();
+    }
+
+    let indent = "  ".repeat(depth);
+    if node.children.is_empty() {
+        writeln!(out, "{}<li class=\"leaf\">{}</li>", indent, label).unwrap();
+    } else {
+        // The first moves start expanded.
+        let open = if depth < 2 { " open" } else { "" };
+        writeln!(
+            out,
+            "{}<li><details{}><summary>{}</summary><ul>",
+            indent, open, label
+        )
+        .unwrap();
+        for child in &node.children {
+            html_node(out, child, depth + 1);
+        }
+        writeln!(out, "{}</ul></details></li>", indent).unwrap();
+    }
+}
+
+/// Write a repertoire as a standalone HTML page.
+fn repertoire_html(nodes: &[Node], title: &str, games: i32) -> String {
+    let mut tree = String::new();
+    for node in nodes {
+        html_node(&mut tree, node, 0);
+    }
+    let summary = format!(
+        "{} games · results shown as White wins / draws / Black wins",
+        games
+    );
+    HTML_TEMPLATE
+        .replace("{{title}}", &escape_html(title))
+        .replace("{{summary}}", &escape_html(&summary))
+        .replace("{{tree}}", tree.trim_end())
+}
+
+/// Collect the openings of a player with one color, up to `max_ply` moves.
+fn query_repertoire(
+    db: &mut SqliteConnection,
+    player: i32,
+    color: RepertoireColor,
+    max_ply: usize,
+) -> Result<RepertoireNode> {
+    let start = get_start_position(db)?;
+    let query = games::table
+        .filter(games::fen.is_null())
+        .select((games::moves, games::result))
+        .into_boxed();
+    let query = match color {
+        RepertoireColor::White => query.filter(games::white_id.eq(player)),
+        RepertoireColor::Black => query.filter(games::black_id.eq(player)),
+    };
+    let mut root = RepertoireNode::default();
+    for (moves, result) in query.load::<(Vec<u8>, Option<String>)>(db)? {
+        let Some(result @ ("1-0" | "0-1" | "1/2-1/2")) = result.as_deref() else {
+            continue;
+        };
+        let Ok(tree) = GameTree::from_bytes(&moves, Some(start.clone())) else {
+            continue;
+        };
+        let moves: Vec<String> = tree
+            .nodes()
+            .iter()
+            .filter_map(|node| match node {
+                GameTreeNode::Move(san) => Some(san.to_string()),
+                _ => None,
+            })
+            .take(max_ply)
+            .collect();
+        root.add_game(&moves, result);
+    }
+    Ok(root)
+}
+
+/// Export a repertoire to `out_path` as a Lichess study PGN or a standalone HTML page.
+///
+/// `max_chapter_moves` limits the size of study chapters.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_repertoire(
+    source: RepertoireSource,
+    format: RepertoireFormat,
+    title: Option<String>,
+    out_path: PathBuf,
+    max_chapter_moves: Option<u32>,
+    state: tauri::State<'_, AppState>,
+) -> Result<()> {
+    let (root, position, color, default_title) = match source {
+        RepertoireSource::Tree { fen, root } => {
+            let position = match fen {
+                Some(fen) => {
+                    let fen = Fen::from_ascii(fen.as_bytes())?;
+                    Chess::from_setup(fen.into(), CastlingMode::Chess960)?
+                }
+                None => Chess::default(),
+            };
+            (root, position, None, "Repertoire".to_string())
+        }
+        RepertoireSource::Query {
+            file,
+            player,
+            color,
+            max_ply,
+            min_games,
+        } => {
+            let db = &mut get_db_or_create(
+                &state,
+                file.to_str().unwrap(),
+                ConnectionOptions::default(),
+            )?;
+            let mut root = query_repertoire(db, player, color, max_ply as usize)?;
+            root.prune(min_games as i32);
+            let name: String = players::table
+                .find(player)
+                .select(players::name)
+                .first::<Option<String>>(db)?
+                .unwrap_or_default();
+            let side = match color {
+                RepertoireColor::White => "White",
+                RepertoireColor::Black => "Black",
+            };
+            let position = get_start_position(db)?;
+            (root, position, Some(color), format!("{} as {}", name, side))
+        }
+    };
+    let title = title.unwrap_or(default_title);
+    let nodes = prepare_tree(&root, &position)?;
+
+    let content = match format {
+        RepertoireFormat::LichessStudy => {
+            let fen = (position_key(&position) != position_key(&Chess::default()))
+                .then(|| Fen::from_position(position.clone(), EnPassantMode::Legal).to_string());
+            study_pgn(
+                &nodes,
+                &title,
+                fen.as_deref(),
+                color,
+                max_chapter_moves.map_or(DEFAULT_MAX_CHAPTER_MOVES, |max| max as usize),
+            )
+        }
+        RepertoireFormat::Html => repertoire_html(&nodes, &title, root.games()),
+    };
+    File::create(out_path)?.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(san: &str, games: i32, children: Vec<RepertoireNode>) -> RepertoireNode {
+        RepertoireNode {
+            san: san.to_string(),
+            white: games / 2,
+            draw: games - games / 2 - games / 3,
+            black: games / 3,
+            children,
+        }
+    }
+
+    /// 1. d4 and 1. c4 systems meeting after 1. d4 Nf6 2. c4 e6 and 1. d4 d5 2. c4.
+    fn synthetic_tree() -> RepertoireNode {
+        node(
+            "",
+            30,
+            vec![
+                node(
+                    "c4",
+                    10,
+                    vec![
+                        node(
+                            "e6",
+                            6,
+                            vec![node(
+                                "d4",
+                                6,
+                                vec![node("Nf6", 6, vec![node("Nc3", 6, vec![])])],
+                            )],
+                        ),
+                        node(
+                            "d5",
+                            4,
+                            vec![
+                                node("d4", 3, vec![node("e6", 3, vec![])]),
+                                node("Nf3", 1, vec![node("Nf6", 1, vec![])]),
+                            ],
+                        ),
+                    ],
+                ),
+                node(
+                    "d4",
+                    20,
+                    vec![
+                        node(
+                            "Nf6",
+                            12,
+                            vec![node(
+                                "c4",
+                                12,
+                                vec![node("e6", 12, vec![node("Nc3", 12, vec![])])],
+                            )],
+                        ),
+                        node(
+                            "d5",
+                            8,
+                            vec![node(
+                                "c4",
+                                8,
+                                vec![node("e6", 5, vec![]), node("c6", 3, vec![])],
+                            )],
+                        ),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    fn chapters(max_moves: usize) -> Vec<(String, String)> {
+        let nodes = prepare_tree(&synthetic_tree(), &Chess::default()).unwrap();
+        split_chapters(&nodes, max_moves)
+            .iter()
+            .map(|chapter| (chapter.title.to_string(), chapter_movetext(chapter)))
+            .collect()
+    }
+
+    #[test]
+    fn chapters_are_cut_at_the_first_branch() {
+        assert_eq!(
+            chapters(DEFAULT_MAX_CHAPTER_MOVES),
+            vec![
+                (
+                    "1. d4".to_string(),
+                    "1. d4 Nf6 (1... d5 2. c4 e6 (2... c6)) 2. c4 e6 3. Nc3".to_string()
+                ),
+                (
+                    "1. c4".to_string(),
+                    "1. c4 e6 (1... d5 2. d4 {Transposes to 1. d4 d5 2. c4} (2. Nf3 Nf6)) \
+                     2. d4 Nf6 {Transposes to 1. d4 Nf6 2. c4 e6}"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn transpositions_do_not_duplicate_chapters() {
+        let chapters = chapters(3);
+        let titles: Vec<&str> = chapters.iter().map(|(title, _)| title.as_str()).collect();
+        assert_eq!(
+            titles,
+            vec![
+                "1. d4 Nf6",
+                "1. d4 d5 2. c4 e6",
+                "1. d4 d5 2. c4 c6",
+                "1. c4 e6",
+                "1. c4 d5 2. Nf3"
+            ]
+        );
+        assert_eq!(chapters[0].1, "1. d4 Nf6 2. c4 e6 3. Nc3");
+        // 1. c4 e6 2. d4 Nf6 reaches 1. d4 Nf6 2. c4 e6: its moves are only in that chapter.
+        assert_eq!(
+            chapters[3].1,
+            "1. c4 e6 2. d4 Nf6 {Transposes to 1. d4 Nf6 2. c4 e6}"
+        );
+        // The transposing 2. d4 has no chapter of its own.
+        assert_eq!(
+            chapters[4].1,
+            "1. c4 d5 2. Nf3 (2. d4 {Transposes to 1. d4 d5 2. c4}) 2... Nf6"
+        );
+        assert_eq!(
+            chapters
+                .iter()
+                .filter(|(_, moves)| moves.contains("Nc3"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn study_pgn_has_one_game_per_chapter() {
+        let nodes = prepare_tree(&synthetic_tree(), &Chess::default()).unwrap();
+        let pgn = study_pgn(
+            &nodes,
+            "My \"QGD\"",
+            None,
+            Some(RepertoireColor::White),
+            DEFAULT_MAX_CHAPTER_MOVES,
+        );
+        assert_eq!(pgn.matches("[Event ").count(), 2);
+        assert!(pgn.contains("[Event \"My \\\"QGD\\\": 1. d4\"]"));
+        assert!(pgn.contains("[ChapterName \"1. c4\"]"));
+        assert!(pgn.contains("[Orientation \"white\"]"));
+        assert!(!pgn.contains("[FEN"));
+        assert_eq!(pgn.matches(" *\n").count(), 2);
+    }
+
+    #[test]
+    fn html_tree_is_standalone_with_win_rates() {
+        let root = synthetic_tree();
+        let nodes = prepare_tree(&root, &Chess::default()).unwrap();
+        let html = repertoire_html(&nodes, "Queen's <Gambit>", root.games());
+
+        assert!(html.contains("<title>Queen's &lt;Gambit&gt;</title>"));
+        assert!(!html.contains("{{"));
+        assert!(!html.contains("src=") && !html.contains("href="));
+        // 1. d4 comes first, as it was played more often, and starts expanded.
+        let d4 = html.find("<span class=\"move\">1. d4</span>").unwrap();
+        let c4 = html.find("<span class=\"move\">1. c4</span>").unwrap();
+        assert!(d4 < c4);
+        assert!(html.contains("<details open><summary><span class=\"move\">1. d4</span>"));
+        assert!(html.contains("20 games · 50% / 20% / 30%"));
+        assert!(html.contains("Transposes to 1. d4 Nf6 2. c4 e6"));
+        // Every opened element is closed.
+        assert_eq!(
+            html.matches("<details").count(),
+            html.matches("</details>").count()
+        );
+    }
+
+    #[test]
+    fn games_are_merged_into_the_tree() {
+        let moves = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let mut root = RepertoireNode::default();
+        root.add_game(&moves("e4 e5 Nf3"), "1-0");
+        root.add_game(&moves("e4 c5"), "0-1");
+        root.add_game(&moves("e4 e5 Bc4"), "1/2-1/2");
+        root.add_game(&moves("d4"), "1-0");
+
+        assert_eq!((root.white, root.draw, root.black), (2, 1, 1));
+        let e4 = &root.children[0];
+        assert_eq!((e4.san.as_str(), e4.games()), ("e4", 3));
+        assert_eq!(e4.children[0].children.len(), 2);
+
+        root.prune(2);
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(root.children[0].children.len(), 1);
+        assert!(root.children[0].children[0].children.is_empty());
+    }
+}
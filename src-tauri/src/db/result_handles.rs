@@ -0,0 +1,433 @@
+//! Results of broad queries, kept in the backend and fetched in chunks.
+//!
+//! Sending tens of thousands of games over IPC at once freezes the webview while it parses
+//! them. Instead, a query can leave its games here behind a handle, and the games table
+//! fetches the rows it shows as it scrolls. A result keeps a bounded number of games in
+//! memory; bigger ones are spilled to a temporary SQLite database as they are read.
+//! Handles are released explicitly, or expire once unused for a while.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Text},
+    sqlite::Sqlite,
+};
+use serde::Serialize;
+use specta::Type;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::AppState;
+
+use super::models::NormalizedGame;
+use super::search::{search_position, PositionStats};
+use super::{get_db_or_create, provenance, query_games, ConnectionOptions, GameQueryJs};
+
+/// Games of a result kept in memory at most, before it is spilled to disk.
+const MEMORY_LIMIT: usize = 2_000;
+
+/// How long a result is kept without being fetched from.
+const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Games written to disk per statement.
+const SPILL_BATCH: usize = 200;
+
+/// A result left in the backend.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultHandle {
+    pub id: String,
+    /// Games in the result.
+    pub total: u32,
+    /// Games matching the query, when it was paged and counted.
+    pub count: Option<i32>,
+}
+
+#[derive(QueryableByName)]
+struct SpilledGame {
+    #[diesel(sql_type = Text)]
+    game: String,
+}
+
+struct Spill {
+    db: SqliteConnection,
+    /// Removed with the result.
+    _file: NamedTempFile,
+}
+
+impl Spill {
+    fn create() -> Result<Self> {
+        let file = NamedTempFile::new()?;
+        let mut db = SqliteConnection::establish(&file.path().to_string_lossy())?;
+        db.batch_execute(
+            "PRAGMA journal_mode = OFF;
+             PRAGMA synchronous = OFF;
+             CREATE TABLE Results (Pos INTEGER PRIMARY KEY, Game TEXT NOT NULL);",
+        )?;
+        Ok(Self { db, _file: file })
+    }
+
+    fn write(&mut self, first: usize, games: &[NormalizedGame]) -> Result<()> {
+        self.db.transaction(|db| {
+            for (i, batch) in games.chunks(SPILL_BATCH).enumerate() {
+                let values = vec!["(?, ?)"; batch.len()].join(", ");
+                let mut insert =
+                    sql_query(format!("INSERT INTO Results (Pos, Game) VALUES {}", values))
+                        .into_boxed::<Sqlite>();
+                for (j, game) in batch.iter().enumerate() {
+                    insert = insert
+                        .bind::<BigInt, _>((first + i * SPILL_BATCH + j) as i64)
+                        .bind::<Text, _>(serde_json::to_string(game)?);
+                }
+                insert.execute(db)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&mut self, offset: usize, limit: usize) -> Result<Vec<NormalizedGame>> {
+        sql_query("SELECT Game AS game FROM Results WHERE Pos >= ? ORDER BY Pos LIMIT ?")
+            .bind::<BigInt, _>(offset as i64)
+            .bind::<BigInt, _>(limit as i64)
+            .load::<SpilledGame>(&mut self.db)?
+            .into_iter()
+            .map(|row| Ok(serde_json::from_str(&row.game)?))
+            .collect()
+    }
+}
+
+/// Games of a result, in memory until there are more than the limit.
+pub struct ResultBuffer {
+    limit: usize,
+    memory: Vec<NormalizedGame>,
+    spill: Option<Spill>,
+    len: usize,
+}
+
+impl ResultBuffer {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            memory: Vec::new(),
+            spill: None,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, game: NormalizedGame) -> Result<()> {
+        self.memory.push(game);
+        self.len += 1;
+        if self.memory.len() >= self.limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the games still in memory to disk, once the result was spilled.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.spill.is_some() && !self.memory.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let first = self.len - self.memory.len();
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create()?);
+        }
+        if let Some(spill) = &mut self.spill {
+            spill.write(first, &self.memory)?;
+        }
+        self.memory.clear();
+        Ok(())
+    }
+
+    /// Games in the result.
+    pub fn total(&self) -> usize {
+        self.len
+    }
+
+    /// Games held in memory.
+    pub fn resident(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Up to `limit` games from `offset` on; none past the end.
+    pub fn chunk(&mut self, offset: usize, limit: usize) -> Result<Vec<NormalizedGame>> {
+        match &mut self.spill {
+            Some(spill) => spill.read(offset, limit),
+            None => Ok(self
+                .memory
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect()),
+        }
+    }
+}
+
+struct StoredResult {
+    file: PathBuf,
+    buffer: ResultBuffer,
+    last_used: Instant,
+}
+
+/// Results left in the backend, by handle.
+pub struct ResultHandles {
+    ttl: Duration,
+    results: Mutex<HashMap<String, StoredResult>>,
+}
+
+impl Default for ResultHandles {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+impl ResultHandles {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn expire(&self, results: &mut HashMap<String, StoredResult>) {
+        let now = Instant::now();
+        results.retain(|_, result| now.duration_since(result.last_used) <= self.ttl);
+    }
+
+    /// Keep the games of a query on `file`, returning the handle to fetch them with.
+    pub fn insert(&self, file: PathBuf, mut buffer: ResultBuffer) -> Result<String> {
+        buffer.finish()?;
+        let id = Uuid::new_v4().to_string();
+        let mut results = self.results.lock().unwrap();
+        self.expire(&mut results);
+        results.insert(
+            id.clone(),
+            StoredResult {
+                file,
+                buffer,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Games of a result, with the database they came from.
+    pub fn fetch(
+        &self,
+        id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(PathBuf, Vec<NormalizedGame>)> {
+        let mut results = self.results.lock().unwrap();
+        self.expire(&mut results);
+        let result = results
+            .get_mut(id)
+            .ok_or_else(|| Error::ResultHandleNotFound(id.to_string()))?;
+        result.last_used = Instant::now();
+        Ok((result.file.clone(), result.buffer.chunk(offset, limit)?))
+    }
+
+    pub fn release(&self, id: &str) {
+        self.results.lock().unwrap().remove(id);
+    }
+}
+
+fn keep(
+    state: &tauri::State<'_, AppState>,
+    file: PathBuf,
+    buffer: ResultBuffer,
+    count: Option<i32>,
+) -> Result<ResultHandle> {
+    let total = buffer.total() as u32;
+    let id = state.result_handles.insert(file, buffer)?;
+    Ok(ResultHandle { id, total, count })
+}
+
+/// Run a games query like `get_games`, leaving its games behind a handle to fetch them
+/// in chunks with `fetch_result_chunk`.
+#[tauri::command]
+#[specta::specta]
+pub async fn query_games_handle(
+    file: PathBuf,
+    query: GameQueryJs,
+    state: tauri::State<'_, AppState>,
+) -> Result<ResultHandle> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let mut buffer = ResultBuffer::new(MEMORY_LIMIT);
+    let count = query_games(db, query, |game| buffer.push(game))?;
+    keep(&state, file, buffer, count.map(|c| c as i32))
+}
+
+/// Search a position like `search_position`, leaving the matching games behind a handle.
+#[tauri::command]
+#[specta::specta]
+pub async fn search_position_handle(
+    file: PathBuf,
+    query: GameQueryJs,
+    app: tauri::AppHandle,
+    tab_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(Vec<PositionStats>, ResultHandle)> {
+    let (stats, games) = search_position(file.clone(), query, app, tab_id, state.clone()).await?;
+    let mut buffer = ResultBuffer::new(MEMORY_LIMIT);
+    for game in games {
+        buffer.push(game)?;
+    }
+    Ok((stats, keep(&state, file, buffer, None)?))
+}
+
+/// Up to `limit` games of a result from `offset` on.
+#[tauri::command]
+#[specta::specta]
+pub async fn fetch_result_chunk(
+    handle: String,
+    offset: u32,
+    limit: u32,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<NormalizedGame>> {
+    let (file, mut games) = state
+        .result_handles
+        .fetch(&handle, offset as usize, limit as usize)?;
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    provenance::attach_sources(db, &mut games)?;
+    Ok(games)
+}
+
+/// Drop a result once its table is closed.
+#[tauri::command]
+#[specta::specta]
+pub async fn release_result_handle(
+    handle: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<()> {
+    state.result_handles.release(&handle);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(id: i32) -> NormalizedGame {
+        NormalizedGame {
+            id,
+            fen: String::new(),
+            event: "Club".to_string(),
+            event_id: 1,
+            site: "Club".to_string(),
+            site_id: 1,
+            date: None,
+            time: None,
+            round: None,
+            white: format!("White {}", id),
+            white_id: 1,
+            white_elo: None,
+            black: "Black".to_string(),
+            black_id: 2,
+            black_elo: None,
+            result: Default::default(),
+            time_control: None,
+            eco: None,
+            ply_count: None,
+            moves: String::new(),
+            decode_warnings: 0,
+            links: None,
+            source: None,
+        }
+    }
+
+    fn ids(games: &[NormalizedGame]) -> Vec<i32> {
+        games.iter().map(|game| game.id).collect()
+    }
+
+    fn buffer(games: i32, limit: usize) -> ResultBuffer {
+        let mut buffer = ResultBuffer::new(limit);
+        for id in 0..games {
+            buffer.push(game(id)).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn chunks_cover_the_result_exactly() {
+        // Kept in memory, spilled exactly at the limit, and spilled with a partial batch.
+        for (games, limit) in [(7, 10), (10, 10), (25, 10)] {
+            let mut buffer = buffer(games, limit);
+            buffer.finish().unwrap();
+            assert_eq!(buffer.total(), games as usize);
+
+            let mut fetched = Vec::new();
+            let mut offset = 0;
+            loop {
+                let chunk = buffer.chunk(offset, 4).unwrap();
+                if chunk.is_empty() {
+                    break;
+                }
+                offset += chunk.len();
+                fetched.extend(ids(&chunk));
+            }
+            assert_eq!(fetched, (0..games).collect::<Vec<_>>(), "{} games", games);
+            assert_eq!(
+                ids(&buffer.chunk(games as usize - 1, 4).unwrap()),
+                [games - 1]
+            );
+            assert!(buffer.chunk(games as usize, 4).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn large_results_stay_bounded_in_memory() {
+        let mut buffer = ResultBuffer::new(MEMORY_LIMIT);
+        for id in 0..100_000 {
+            buffer.push(game(id)).unwrap();
+            assert!(buffer.resident() < MEMORY_LIMIT);
+        }
+        buffer.finish().unwrap();
+        assert_eq!(buffer.resident(), 0);
+        assert_eq!(buffer.total(), 100_000);
+        assert_eq!(
+            ids(&buffer.chunk(49_999, 3).unwrap()),
+            [49_999, 50_000, 50_001]
+        );
+        assert_eq!(ids(&buffer.chunk(99_998, 10).unwrap()), [99_998, 99_999]);
+    }
+
+    #[test]
+    fn handles_expire_unless_used() {
+        let handles = ResultHandles::new(Duration::from_millis(50));
+        let id = handles
+            .insert(PathBuf::from("a.db3"), buffer(5, 10))
+            .unwrap();
+        let other = handles
+            .insert(PathBuf::from("b.db3"), buffer(5, 10))
+            .unwrap();
+        let (file, games) = handles.fetch(&id, 3, 10).unwrap();
+        assert_eq!(file, PathBuf::from("a.db3"));
+        assert_eq!(ids(&games), [3, 4]);
+
+        handles.release(&other);
+        assert!(matches!(
+            handles.fetch(&other, 0, 1),
+            Err(Error::ResultHandleNotFound(_))
+        ));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            handles.fetch(&id, 0, 1),
+            Err(Error::ResultHandleNotFound(_))
+        ));
+        assert!(handles.results.lock().unwrap().is_empty());
+    }
+}
@@ -110,7 +110,136 @@
     }
 }
 
+diesel::table! {
+    #[sql_name = "PlayerMetadata"]
+    player_metadata (player_id) {
+        #[sql_name = "PlayerID"]
+        player_id -> Integer,
+        #[sql_name = "Federation"]
+        federation -> Nullable<Text>,
+        #[sql_name = "Title"]
+        title -> Nullable<Text>,
+        #[sql_name = "PhotoPath"]
+        photo_path -> Nullable<Text>,
+        #[sql_name = "FideID"]
+        fide_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "GameEncodings"]
+    game_encodings (game_id) {
+        #[sql_name = "GameID"]
+        game_id -> Integer,
+        #[sql_name = "Version"]
+        version -> Integer,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "GameStructures"]
+    game_structures (game_id) {
+        #[sql_name = "GameID"]
+        game_id -> Integer,
+        #[sql_name = "Structure"]
+        structure -> Text,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "GameSources"]
+    game_sources (game_id) {
+        #[sql_name = "GameID"]
+        game_id -> Integer,
+        #[sql_name = "Kind"]
+        kind -> Text,
+        #[sql_name = "Name"]
+        name -> Text,
+        #[sql_name = "Account"]
+        account -> Nullable<Text>,
+        #[sql_name = "ImportedAt"]
+        imported_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "GameLinks"]
+    game_links (id) {
+        #[sql_name = "ID"]
+        id -> Integer,
+        #[sql_name = "FromGame"]
+        from_game -> Integer,
+        #[sql_name = "ToGame"]
+        to_game -> Nullable<Integer>,
+        #[sql_name = "Label"]
+        label -> Text,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "AnalysisAttributions"]
+    analysis_attributions (id) {
+        #[sql_name = "ID"]
+        id -> Integer,
+        #[sql_name = "GameID"]
+        game_id -> Integer,
+        #[sql_name = "Engine"]
+        engine -> Text,
+        #[sql_name = "GoMode"]
+        go_mode -> Text,
+        #[sql_name = "AnalyzedAt"]
+        analyzed_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "DatabaseMetadata"]
+    database_metadata (id) {
+        #[sql_name = "ID"]
+        id -> Integer,
+        #[sql_name = "Version"]
+        version -> Integer,
+        #[sql_name = "Notes"]
+        notes -> Text,
+        #[sql_name = "SourceUrl"]
+        source_url -> Nullable<Text>,
+        #[sql_name = "License"]
+        license -> Nullable<Text>,
+        #[sql_name = "Tags"]
+        tags -> Text,
+        #[sql_name = "CoverColor"]
+        cover_color -> Nullable<Text>,
+        #[sql_name = "CoverIcon"]
+        cover_icon -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    #[sql_name = "OpeningFrequencies"]
+    opening_frequencies (opening_key) {
+        #[sql_name = "OpeningKey"]
+        opening_key -> Text,
+        #[sql_name = "GameCount"]
+        game_count -> Integer,
+    }
+}
+
 diesel::joinable!(games -> events (event_id));
 diesel::joinable!(games -> sites (site_id));
 
-diesel::allow_tables_to_appear_in_same_query!(comments, events, games, info, players, sites,);
+diesel::allow_tables_to_appear_in_same_query!(
+    analysis_attributions,
+    comments,
+    database_metadata,
+    events,
+    game_encodings,
+    game_links,
+    game_sources,
+    game_structures,
+    games,
+    info,
+    opening_frequencies,
+    player_metadata,
+    players,
+    sites,
+);
@@ -4,33 +4,37 @@
 //! It supports both exact position matching and partial position matching.
 
 use diesel::prelude::*;
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use shakmaty::{fen::Fen, san::SanPlus, Bitboard, ByColor, Chess, FromSetup, Position, Setup};
+use shakmaty::{
+    fen::Fen, san::SanPlus, Bitboard, ByColor, CastlingMode, Chess, Color, FromSetup, Move,
+    Position, PositionError, Role, Setup,
+};
 use specta::Type;
 use std::{
     collections::HashMap,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time::Instant,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 use tauri::Emitter;
+use tauri_specta::Event as _;
 
 use crate::{
     db::{
-        get_db_or_create, get_pawn_home,
+        encoding::{BlobReader, DecodeError, Token},
+        get_db_or_create, get_pawn_home, get_start_position,
         models::*,
-        normalize_games,
-        pgn::{get_material_count, MaterialCount},
+        normalize_games, pawn_home_bit,
+        pgn::{get_material_count, piece_value, MaterialCount},
+        rarity,
         schema::*,
         ConnectionOptions, GameSort, SortDirection,
     },
     error::Error,
-    AppState,
+    tasks::{TaskHandle, TaskKind},
+    AppState, GameData,
 };
 
 use super::GameQueryJs;
@@ -42,6 +46,9 @@ pub struct ExactData {
     pawn_home: u16,
     material: MaterialCount,
     position: Chess,
+    /// Castling rooks the position must keep. Only compared in Chess960, where the same
+    /// board can come with different rights.
+    castling: Option<Bitboard>,
 }
 
 /// Data for partial position matching
@@ -50,6 +57,45 @@ pub struct ExactData {
 pub struct PartialData {
     piece_positions: Setup,
     material: MaterialCount,
+    /// Side that must be to move, if any
+    side_to_move: Option<Color>,
+}
+
+/// Side to move a partial position query requires
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SideToMove {
+    White,
+    Black,
+}
+
+impl From<SideToMove> for Color {
+    fn from(side: SideToMove) -> Self {
+        match side {
+            SideToMove::White => Color::White,
+            SideToMove::Black => Color::Black,
+        }
+    }
+}
+
+/// Variant a position is searched in, which decides how the castling rights of the query are
+/// read and whether they are compared. Games are replayed with the castling rules their own
+/// start position needs.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionVariant {
+    #[default]
+    Standard,
+    Chess960,
+}
+
+impl From<CastlingMode> for PositionVariant {
+    fn from(mode: CastlingMode) -> Self {
+        match mode {
+            CastlingMode::Standard => PositionVariant::Standard,
+            CastlingMode::Chess960 => PositionVariant::Chess960,
+        }
+    }
 }
 
 /// Query type for searching positions
@@ -63,24 +109,48 @@ pub enum PositionQuery {
 
 impl PositionQuery {
     pub fn exact_from_fen(fen: &str) -> Result<PositionQuery, Error> {
-        let position: Chess =
-            Fen::from_ascii(fen.as_bytes())?.into_position(shakmaty::CastlingMode::Chess960)?;
+        Self::exact_from_fen_in(fen, PositionVariant::Standard)
+    }
+
+    pub fn exact_from_fen_in(fen: &str, variant: PositionVariant) -> Result<PositionQuery, Error> {
+        let fen = Fen::from_ascii(fen.as_bytes())?;
+        let position: Chess = match variant {
+            // Castling rights aren't compared, so those only Chess960 allows are dropped
+            // rather than refused.
+            PositionVariant::Standard => fen
+                .into_position(CastlingMode::Standard)
+                .or_else(PositionError::ignore_invalid_castling_rights)?,
+            PositionVariant::Chess960 => fen.into_position(CastlingMode::Chess960)?,
+        };
         let pawn_home = get_pawn_home(position.board());
         let material = get_material_count(position.board());
+        let castling = match variant {
+            PositionVariant::Standard => None,
+            PositionVariant::Chess960 => Some(position.castles().castling_rights()),
+        };
         Ok(PositionQuery::Exact(ExactData {
             pawn_home,
             material,
             position,
+            castling,
         }))
     }
 
     pub fn partial_from_fen(fen: &str) -> Result<PositionQuery, Error> {
+        Self::partial_from_fen_to_move(fen, None)
+    }
+
+    pub fn partial_from_fen_to_move(
+        fen: &str,
+        side_to_move: Option<Color>,
+    ) -> Result<PositionQuery, Error> {
         let fen = Fen::from_ascii(fen.as_bytes())?;
         let setup = fen.into_setup();
         let material = get_material_count(&setup.board);
         Ok(PositionQuery::Partial(PartialData {
             piece_positions: setup,
             material,
+            side_to_move,
         }))
     }
 }
@@ -89,14 +159,23 @@ pub fn partial_from_fen(fen: &str) -> Result<PositionQuery, Error> {
 pub struct PositionQueryJs {
     pub fen: String,
     pub type_: String,
+    #[serde(default)]
+    #[specta(optional)]
+    pub variant: PositionVariant,
+    /// Partial queries only: side that must be to move. Exact queries always compare it.
+    #[serde(default)]
+    #[specta(optional)]
+    pub side_to_move: Option<SideToMove>,
 }
 
 /// Convert JavaScript position query to internal format
 #[inline(always)]
 fn convert_position_query(query: PositionQueryJs) -> Result<PositionQuery, Error> {
     match query.type_.as_str() {
-        "exact" => PositionQuery::exact_from_fen(&query.fen),
-        "partial" => PositionQuery::partial_from_fen(&query.fen),
+        "exact" => PositionQuery::exact_from_fen_in(&query.fen, query.variant),
+        "partial" => {
+            PositionQuery::partial_from_fen_to_move(&query.fen, query.side_to_move.map(Color::from))
+        }
         _ => unreachable!(),
     }
 }
@@ -108,9 +187,21 @@ fn matches(&self, position: &Chess) -> bool {
         match self {
             PositionQuery::Exact(ref data) => {
                 // Check turn and board position exactly
-                data.position.turn() == position.turn() && data.position.board() == position.board()
+                data.position.turn() == position.turn()
+                    && data.position.board() == position.board()
+                    && match data.castling {
+                        Some(rooks) => position.castles().castling_rights() == rooks,
+                        None => true,
+                    }
             }
             PositionQuery::Partial(ref data) => {
+                if data
+                    .side_to_move
+                    .is_some_and(|side| side != position.turn())
+                {
+                    return false;
+                }
+
                 let query_board = &data.piece_positions.board;
                 let tested_board = position.board();
 
@@ -187,132 +278,160 @@ pub struct PositionStats {
 
 /// Parses chess moves from binary format one at a time
 /// Avoids loading entire game tree into memory
+///
+/// Material and pawn home squares are updated from each move played rather than recounted
+/// from the board, since searches check them after every move.
 struct MoveStream<'a> {
-    bytes: &'a [u8],
+    reader: BlobReader<'a>,
     position: Chess,
-    index: usize,
+    material: MaterialCount,
+    pawn_home: u16,
 }
 
 impl<'a> MoveStream<'a> {
-    // Binary format markers
-    const START_VARIATION: u8 = 254;
-    const END_VARIATION: u8 = 253;
-    const COMMENT: u8 = 252;
-    const NAG: u8 = 251;
-
-    fn new(bytes: &'a [u8], start_position: Chess) -> Self {
-        Self {
-            bytes,
+    fn new(bytes: &'a [u8], start_position: Chess) -> Result<Self, DecodeError> {
+        Ok(Self {
+            reader: BlobReader::new(bytes)?,
+            material: get_material_count(start_position.board()),
+            pawn_home: get_pawn_home(start_position.board()),
             position: start_position,
-            index: 0,
-        }
+        })
     }
 
-    fn next_move(&mut self) -> Option<(Chess, String)> {
-        while self.index < self.bytes.len() {
-            let byte = self.bytes[self.index];
+    /// Material count of the current position.
+    fn material(&self) -> &MaterialCount {
+        &self.material
+    }
 
-            match byte {
-                // Skip comments, annotations, and variations
-                Self::COMMENT => {
-                    if self.index + 8 >= self.bytes.len() {
-                        break;
-                    }
-                    let length_bytes = &self.bytes[self.index + 1..self.index + 9];
-                    if let Ok(length_array) = <[u8; 8]>::try_from(length_bytes) {
-                        let length = u64::from_be_bytes(length_array) as usize;
-                        self.index += 9 + length;
-                    } else {
-                        break;
-                    }
-                }
-                Self::NAG => {
-                    self.index += 2;
-                }
-                Self::START_VARIATION => {
-                    // Skip entire variation
-                    let mut depth = 1;
-                    self.index += 1;
-                    while self.index < self.bytes.len() && depth > 0 {
-                        match self.bytes[self.index] {
-                            Self::START_VARIATION => depth += 1,
-                            Self::END_VARIATION => depth -= 1,
-                            _ => {}
-                        }
-                        self.index += 1;
-                    }
-                }
-                Self::END_VARIATION => {
-                    break;
-                }
-                move_byte => {
-                    // Parse actual chess move
-                    // Get legal moves once instead of on every iteration
-                    let legal_moves = self.position.legal_moves();
-                    if let Some(chess_move) = legal_moves.get(move_byte as usize) {
-                        // Only clone position when we're returning it
-                        // This avoids cloning on every move in the game
-                        let san =
-                            SanPlus::from_move_and_play_unchecked(&mut self.position, chess_move);
-                        let move_string = san.to_string();
-                        self.index += 1;
-                        return Some((self.position.clone(), move_string));
-                    } else {
-                        break; // Invalid move
-                    }
-                }
+    /// Pawns of the current position still on their home squares, as in `get_pawn_home`.
+    fn pawn_home(&self) -> u16 {
+        self.pawn_home
+    }
+
+    fn next_move(&mut self) -> Result<Option<(Chess, String)>, DecodeError> {
+        while let Some((offset, token)) = self.reader.next_token()? {
+            // Skip comments, annotations, and everything inside variations
+            let Token::Move(move_byte) = token else {
+                continue;
+            };
+            if self.reader.depth() > 0 {
+                continue;
+            }
+
+            // Get legal moves once instead of on every iteration
+            let legal_moves = self.position.legal_moves();
+            let chess_move =
+                legal_moves
+                    .get(move_byte as usize)
+                    .ok_or(DecodeError::IllegalMove {
+                        offset,
+                        byte: move_byte,
+                    })?;
+            self.track(chess_move);
+            // Only clone position when we're returning it
+            // This avoids cloning on every move in the game
+            let san = SanPlus::from_move_and_play_unchecked(&mut self.position, chess_move);
+            return Ok(Some((self.position.clone(), san.to_string())));
+        }
+        Ok(None)
+    }
+
+    /// Update material and pawn home squares for a move about to be played.
+    fn track(&mut self, chess_move: &Move) {
+        let us = self.position.turn();
+        if let Some(captured) = chess_move.capture() {
+            *self.material.get_mut(!us) -= piece_value(captured);
+            if captured == Role::Pawn {
+                self.pawn_home &= !pawn_home_bit(!us, chess_move.to());
+            }
+        }
+        if let Some(promoted) = chess_move.promotion() {
+            *self.material.get_mut(us) += piece_value(promoted) - piece_value(Role::Pawn);
+        }
+        if chess_move.role() == Role::Pawn {
+            if let Some(from) = chess_move.from() {
+                self.pawn_home &= !pawn_home_bit(us, from);
             }
         }
-        None
     }
 }
 
+/// Position of a game matching a query.
+#[derive(Debug, PartialEq, Eq)]
+struct PositionMatch {
+    /// Side to move in the matched position.
+    turn: Color,
+    /// Move played from it, or "*" if the game ended there.
+    next_move: String,
+}
+
 /// Find the next move played after a position matches the query
+///
+/// Games without a FEN of their own start from `default_start`, the database's start position.
+#[cfg(test)]
 fn get_move_after_match(
     move_blob: &[u8],
     fen: &Option<String>,
+    default_start: &Chess,
     query: &PositionQuery,
 ) -> Result<Option<String>, Error> {
+    Ok(find_position_match(move_blob, fen, default_start, query)?.map(|m| m.next_move))
+}
+
+/// Find the first position of a game matching the query, with the move played after it
+fn find_position_match(
+    move_blob: &[u8],
+    fen: &Option<String>,
+    default_start: &Chess,
+    query: &PositionQuery,
+) -> Result<Option<PositionMatch>, Error> {
     let start_position = if let Some(fen) = fen {
-        let fen = Fen::from_ascii(fen.as_bytes())?;
-        Chess::from_setup(fen.into_setup(), shakmaty::CastlingMode::Chess960)?
+        // Each game is replayed with the castling rules its own start position needs,
+        // whatever the variant of the query.
+        let setup = Fen::from_ascii(fen.as_bytes())?.into_setup();
+        let mode = CastlingMode::detect(&setup);
+        Chess::from_setup(setup, mode)?
     } else {
-        Chess::default()
+        default_start.clone()
     };
 
     // Check if starting position already matches
     if query.matches(&start_position) {
-        let mut stream = MoveStream::new(move_blob, start_position);
-        if let Some((_, first_move)) = stream.next_move() {
-            return Ok(Some(first_move));
-        }
-        return Ok(Some("*".to_string()));
+        let turn = start_position.turn();
+        let mut stream = MoveStream::new(move_blob, start_position)?;
+        let next_move = match stream.next_move()? {
+            Some((_, first_move)) => first_move,
+            None => "*".to_string(),
+        };
+        return Ok(Some(PositionMatch { turn, next_move }));
     }
 
     // Check each position in the game
-    let mut stream = MoveStream::new(move_blob, start_position);
+    let mut stream = MoveStream::new(move_blob, start_position)?;
 
-    while let Some((current_position, _current_move)) = stream.next_move() {
+    while let Some((current_position, _current_move)) = stream.next_move()? {
         // Quick material check first
-        let board = current_position.board();
-        let material = get_material_count(board);
+        let material = stream.material();
 
-        if !query.has_sufficient_material(&material) {
+        if !query.has_sufficient_material(material) {
             continue;
         }
 
-        let pawn_home = get_pawn_home(board);
-        if !query.is_reachable_by(&material, pawn_home) {
+        if !query.is_reachable_by(material, stream.pawn_home()) {
             return Ok(None); // Position is unreachable
         }
 
         // Check for position match
         if query.matches(&current_position) {
             // Return the next move after the match
-            if let Some((_, next_move)) = stream.next_move() {
-                return Ok(Some(next_move));
-            }
-            return Ok(Some("*".to_string())); // End of game
+            let next_move = match stream.next_move()? {
+                Some((_, next_move)) => next_move,
+                None => "*".to_string(), // End of game
+            };
+            return Ok(Some(PositionMatch {
+                turn: current_position.turn(),
+                next_move,
+            }));
         }
     }
 
@@ -342,21 +461,7 @@ fn load_games_batch(
     file: &PathBuf,
     offset: i64,
     limit: i64,
-) -> Result<
-    Vec<(
-        i32,
-        i32,
-        i32,
-        Option<String>,
-        Option<String>,
-        Vec<u8>,
-        Option<String>,
-        i32,
-        i32,
-        i32,
-    )>,
-    Error,
-> {
+) -> Result<Vec<GameData>, Error> {
     let db = &mut get_db_or_create(state, file.to_str().unwrap(), ConnectionOptions::default())?;
 
     let games = games::table
@@ -371,6 +476,8 @@ fn load_games_batch(
             games::pawn_home,
             games::white_material,
             games::black_material,
+            games::white_elo,
+            games::black_elo,
         ))
         .offset(offset)
         .limit(limit)
@@ -379,11 +486,13 @@ fn load_games_batch(
     Ok(games)
 }
 
-/// Check if game matches basic filters (player, date, result)
+/// Check if game matches basic filters (player, rating, date, result)
 #[inline(always)]
 fn matches_basic_filters(
     white_id: i32,
     black_id: i32,
+    white_elo: Option<i32>,
+    black_elo: Option<i32>,
     date: &Option<String>,
     result: &Option<String>,
     query: &GameQueryJs,
@@ -401,6 +510,22 @@ fn matches_basic_filters(
         }
     }
 
+    // The side to move is only known once the position is found, but the player must at
+    // least have played the game
+    if let Some(player) = query.matched_side_player {
+        if player != white_id && player != black_id {
+            return false;
+        }
+    }
+
+    // Check average rating filter
+    if let Some(min_avg_elo) = query.min_avg_elo {
+        match (white_elo, black_elo) {
+            (Some(white), Some(black)) if white + black >= 2 * min_avg_elo => {}
+            _ => return false,
+        }
+    }
+
     // Check result filter
     if let Some(wanted_result) = &query.wanted_result {
         if let Some(game_result) = result {
@@ -436,12 +561,251 @@ fn matches_basic_filters(
     true
 }
 
+/// Check the rating of the player to move in the matched position
+#[inline(always)]
+fn matches_side_to_move_elo(
+    turn: Color,
+    white_elo: Option<i32>,
+    black_elo: Option<i32>,
+    query: &GameQueryJs,
+) -> bool {
+    let Some(min_elo) = query.min_elo_side_to_move else {
+        return true;
+    };
+    let elo = match turn {
+        Color::White => white_elo,
+        Color::Black => black_elo,
+    };
+    elo.is_some_and(|elo| elo >= min_elo)
+}
+
+/// Check that the player to move in the matched position is the wanted player
+#[inline(always)]
+fn matches_side_to_move_player(
+    turn: Color,
+    white_id: i32,
+    black_id: i32,
+    query: &GameQueryJs,
+) -> bool {
+    let Some(player) = query.matched_side_player else {
+        return true;
+    };
+    let to_move = match turn {
+        Color::White => white_id,
+        Color::Black => black_id,
+    };
+    to_move == player
+}
+
 /// Calculate search progress as percentage
 #[inline(always)]
 fn calculate_batch_progress(processed: usize, total: usize) -> f64 {
     (processed as f64 / total as f64 * 100.0).min(100.0)
 }
 
+/// Games searched between two partial results, at least.
+const PARTIAL_CHUNK_SIZE: usize = 5000;
+
+/// Time between two partial results, at least.
+const PARTIAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Explorer stats of the games searched so far, emitted while a position search runs so the
+/// explorer can show provisional numbers. The last one, with `is_final` set, has the same
+/// stats as the search result. Searches answered from the cache emit none.
+#[derive(Serialize, Debug, Clone, Type, tauri_specta::Event)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchPartialResult {
+    pub tab: String,
+    pub openings: Vec<PositionStats>,
+    pub processed: u32,
+    pub total: u32,
+    pub is_final: bool,
+}
+
+/// Move statistics and matching games found so far.
+#[derive(Default)]
+struct SearchResults {
+    position_stats: HashMap<String, PositionStats>,
+    matched_ids: Vec<i32>,
+}
+
+impl SearchResults {
+    fn add(&mut self, next_move: String, result: Option<&str>) {
+        let stats = self
+            .position_stats
+            .entry(next_move.clone())
+            .or_insert_with(|| PositionStats {
+                move_: next_move,
+                white: 0,
+                black: 0,
+                draw: 0,
+            });
+
+        // Count results by game outcome
+        match result {
+            Some("1-0") => stats.white += 1,
+            Some("0-1") => stats.black += 1,
+            Some("1/2-1/2") => stats.draw += 1,
+            _ => (), // Unknown results don't count
+        }
+    }
+
+    /// Add the results of other games, keeping at most `max_ids` matching games.
+    fn merge(&mut self, other: SearchResults, max_ids: usize) {
+        for (key, other_stats) in other.position_stats {
+            let stats = self
+                .position_stats
+                .entry(key)
+                .or_insert_with(|| PositionStats {
+                    move_: other_stats.move_.clone(),
+                    white: 0,
+                    black: 0,
+                    draw: 0,
+                });
+            stats.white += other_stats.white;
+            stats.black += other_stats.black;
+            stats.draw += other_stats.draw;
+        }
+
+        let room = max_ids.saturating_sub(self.matched_ids.len());
+        self.matched_ids
+            .extend(other.matched_ids.into_iter().take(room));
+    }
+}
+
+/// What a position search looks for, and its lock-free counters.
+struct SearchContext<'a> {
+    query: &'a GameQueryJs,
+    position_query: &'a PositionQuery,
+    default_start: &'a Chess,
+    /// Matching games kept for the games list.
+    max_ids: usize,
+    processed: AtomicUsize,
+    filter_matched: AtomicUsize,
+    /// Games whose move data could not be decoded, reported once the search is done.
+    decode_errors: AtomicUsize,
+}
+
+impl SearchContext<'_> {
+    fn processed(&self) -> usize {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Search `games` in parallel with thread-local accumulators, skipping the remaining games
+    /// once `stopped` returns true.
+    fn search(&self, games: &[GameData], stopped: &(impl Fn() -> bool + Sync)) -> SearchResults {
+        games
+            .par_iter()
+            .fold(
+                SearchResults::default,
+                |mut acc, (id, white_id, black_id, date, result, moves, fen, .., white_elo, black_elo)| {
+                    if stopped() {
+                        return acc;
+                    }
+                    self.processed.fetch_add(1, Ordering::Relaxed);
+
+                    // Check basic filters first (player, rating, date, result)
+                    if !matches_basic_filters(
+                        *white_id, *black_id, *white_elo, *black_elo, date, result, self.query,
+                    ) {
+                        return acc;
+                    }
+                    self.filter_matched.fetch_add(1, Ordering::Relaxed);
+
+                    // Check if game contains the target position
+                    let found =
+                        find_position_match(moves, fen, self.default_start, self.position_query);
+                    if let Err(Error::Decode(_)) = found {
+                        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Ok(Some(found)) = found {
+                        // Only known once the position is found
+                        if !matches_side_to_move_elo(found.turn, *white_elo, *black_elo, self.query)
+                            || !matches_side_to_move_player(
+                                found.turn, *white_id, *black_id, self.query,
+                            )
+                        {
+                            return acc;
+                        }
+                        if acc.matched_ids.len() < self.max_ids {
+                            acc.matched_ids.push(*id);
+                        }
+                        acc.add(found.next_move, result.as_deref());
+                    }
+                    acc
+                },
+            )
+            .reduce(SearchResults::default, |mut acc1, acc2| {
+                acc1.merge(acc2, self.max_ids);
+                acc1
+            })
+    }
+
+    /// Search `games` `chunk_size` at a time, adding to `results` and offering them as partial
+    /// results after each chunk.
+    fn search_chunks<F: FnMut(SearchPartialResult)>(
+        &self,
+        games: &[GameData],
+        chunk_size: usize,
+        results: &mut SearchResults,
+        partials: &mut PartialResults<F>,
+        stopped: &(impl Fn() -> bool + Sync),
+    ) {
+        for chunk in games.chunks(chunk_size) {
+            if stopped() {
+                return;
+            }
+            results.merge(self.search(chunk, stopped), self.max_ids);
+            partials.update(results, self.processed());
+        }
+    }
+}
+
+/// Throttled emission of partial results.
+struct PartialResults<F> {
+    tab: String,
+    total: usize,
+    interval: Duration,
+    last: Instant,
+    emit: F,
+}
+
+impl<F: FnMut(SearchPartialResult)> PartialResults<F> {
+    fn new(tab: String, total: usize, interval: Duration, emit: F) -> Self {
+        Self {
+            tab,
+            total,
+            interval,
+            last: Instant::now(),
+            emit,
+        }
+    }
+
+    /// Emit `results` unless the last partial result is too recent.
+    fn update(&mut self, results: &SearchResults, processed: usize) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+        self.send(results, processed, false);
+    }
+
+    fn finish(&mut self, results: &SearchResults, processed: usize) {
+        self.send(results, processed, true);
+    }
+
+    fn send(&mut self, results: &SearchResults, processed: usize, is_final: bool) {
+        // Only the small stats map is copied, never the games.
+        (self.emit)(SearchPartialResult {
+            tab: self.tab.clone(),
+            openings: results.position_stats.values().cloned().collect(),
+            processed: processed as u32,
+            total: self.total as u32,
+            is_final,
+        });
+    }
+}
+
 /// Search for chess positions in the database
 /// Returns position statistics and matching games
 #[tauri::command]
@@ -482,6 +846,12 @@ pub async fn search_position(
 
     let position_query = position_query.unwrap();
 
+    let default_start = {
+        let db =
+            &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+        get_start_position(db)?
+    };
+
     // Cache management with LRU
     const DISABLE_CACHE: bool = false;
 
@@ -509,6 +879,7 @@ pub async fn search_position(
         drop(permit);
         return Err(Error::SearchStopped);
     }
+    let task = TaskHandle::start(&app, TaskKind::Search, &tab_id, true);
 
     // Decide between cached data or batch processing
     let (use_cached_data, total_games, cached_games) = {
@@ -530,132 +901,34 @@ pub async fn search_position(
         total_games
     );
 
-    // Data structures for collecting results from parallel processing
-    let position_stats: HashMap<String, PositionStats>;
-    let matched_game_ids: Vec<i32>;
-    let processed_count: usize;
-    let games_with_basic_filter_match: usize;
+    let context = SearchContext {
+        query: &query,
+        position_query: &position_query,
+        default_start: &default_start,
+        // Collect at least 100 games from the cache, but allow more
+        max_ids: if use_cached_data { 1000 } else { 50 },
+        processed: AtomicUsize::new(0),
+        filter_matched: AtomicUsize::new(0),
+        decode_errors: AtomicUsize::new(0),
+    };
+    let stopped = || state.new_request.available_permits() == 0 || task.is_cancelled();
+    let mut partials = PartialResults::new(tab_id.clone(), total_games, PARTIAL_INTERVAL, |p| {
+        let _ = p.emit(&app);
+    });
+    let mut results = SearchResults::default();
 
     if use_cached_data {
-        // Use cached data with thread-local accumulator pattern (eliminates mutex contention)
         let games = cached_games.unwrap();
-
-        // Atomic counters for lock-free progress tracking
-        let processed_count_atomic = Arc::new(AtomicUsize::new(0));
-        let filter_match_count_atomic = Arc::new(AtomicUsize::new(0));
-
-        // Structure for collecting results in parallel threads
-        #[derive(Default)]
-        struct ThreadLocalResults {
-            position_stats: HashMap<String, PositionStats>,
-            matched_ids: Vec<i32>,
-        }
-
-        // Process games in parallel
-        let final_results = games
-            .par_iter()
-            .fold(
-                || ThreadLocalResults::default(),
-                |mut acc,
-                 (
-                    id,
-                    white_id,
-                    black_id,
-                    date,
-                    result,
-                    moves,
-                    fen,
-                    _pawn_home,
-                    _white_material,
-                    _black_material,
-                )| {
-                    // Check for cancellation (lock-free)
-                    if state.new_request.available_permits() == 0 {
-                        return acc;
-                    }
-
-                    // Lock-free increment of processed count
-                    let _current_processed =
-                        processed_count_atomic.fetch_add(1, Ordering::Relaxed) + 1;
-
-                    // Progress updates only from main thread after batch completion
-
-                    // Check basic filters first (player, date, result)
-                    if !matches_basic_filters(*white_id, *black_id, date, result, &query) {
-                        return acc;
-                    }
-
-                    // Count games that pass basic filters
-                    filter_match_count_atomic.fetch_add(1, Ordering::Relaxed);
-
-                    // Check if game contains the target position
-                    if let Ok(Some(next_move)) = get_move_after_match(moves, fen, &position_query) {
-                        // Save matching game ID (collect at least 100 games, but allow more)
-                        if acc.matched_ids.len() < 1000 {
-                            acc.matched_ids.push(*id);
-                        }
-
-                        // Update move statistics
-                        let stats =
-                            acc.position_stats
-                                .entry(next_move.clone())
-                                .or_insert_with(|| PositionStats {
-                                    move_: next_move,
-                                    white: 0,
-                                    black: 0,
-                                    draw: 0,
-                                });
-
-                        // Count results by game outcome
-                        match result.as_deref() {
-                            Some("1-0") => stats.white += 1,
-                            Some("0-1") => stats.black += 1,
-                            Some("1/2-1/2") => stats.draw += 1,
-                            _ => (), // Skip unknown results
-                        }
-                    }
-
-                    acc
-                },
-            )
-            .reduce(
-                || ThreadLocalResults::default(),
-                |mut acc1, acc2| {
-                    // Merge thread-local results (no contention here!)
-                    for (key, stats2) in acc2.position_stats {
-                        let stats1 =
-                            acc1.position_stats
-                                .entry(key)
-                                .or_insert_with(|| PositionStats {
-                                    move_: stats2.move_.clone(),
-                                    white: 0,
-                                    black: 0,
-                                    draw: 0,
-                                });
-                        stats1.white += stats2.white;
-                        stats1.black += stats2.black;
-                        stats1.draw += stats2.draw;
-                    }
-
-                    // Merge matched IDs (keep within limit)
-                    for id in acc2.matched_ids {
-                        if acc1.matched_ids.len() < 1000 {
-                            acc1.matched_ids.push(id);
-                        }
-                    }
-
-                    acc1
-                },
-            );
-
-        // Extract final results (no Arc unwrapping needed)
-        position_stats = final_results.position_stats;
-        matched_game_ids = final_results.matched_ids;
-        processed_count = processed_count_atomic.load(Ordering::Relaxed);
-        games_with_basic_filter_match = filter_match_count_atomic.load(Ordering::Relaxed);
+        context.search_chunks(
+            &games,
+            PARTIAL_CHUNK_SIZE,
+            &mut results,
+            &mut partials,
+            &stopped,
+        );
 
         info!("Cached data processing complete: {} games processed, {} passed basic filters, {} matches found", 
-              processed_count, games_with_basic_filter_match, matched_game_ids.len());
+              context.processed(), context.filter_matched.load(Ordering::Relaxed), results.matched_ids.len());
 
         // Emit progress update after batch completion (main thread, no mutex overhead)
         let _ = app.emit(
@@ -666,22 +939,15 @@ struct ThreadLocalResults {
                 finished: false,
             },
         );
+        task.report(100.0, None);
     } else {
         // Process large datasets in batches to manage memory
         const BATCH_SIZE: i64 = 30000;
         let mut offset = 0;
 
-        // Track progress across all threads
-        let global_processed_count = Arc::new(AtomicUsize::new(0));
-        let global_filter_match_count = Arc::new(AtomicUsize::new(0));
-
-        // Collect results from all batches
-        let mut global_position_stats = HashMap::<String, PositionStats>::new();
-        let mut global_matched_ids = Vec::<i32>::new();
-
         loop {
             // Check for cancellation
-            if state.new_request.available_permits() == 0 {
+            if stopped() {
                 drop(permit);
                 return Err(Error::SearchStopped);
             }
@@ -698,132 +964,13 @@ struct ThreadLocalResults {
                 offset
             );
 
-            // Thread-local accumulator structure
-            #[derive(Default)]
-            struct ThreadLocalResults {
-                position_stats: HashMap<String, PositionStats>,
-                matched_ids: Vec<i32>,
-            }
-
-            // Process batch using parallel fold pattern with thread-local accumulators
-            let batch_results = batch
-                .par_iter()
-                .fold(
-                    || ThreadLocalResults::default(),
-                    |mut acc,
-                     (
-                        id,
-                        white_id,
-                        black_id,
-                        date,
-                        result,
-                        moves,
-                        fen,
-                        _pawn_home,
-                        _white_material,
-                        _black_material,
-                    )| {
-                        // Check for cancellation (lock-free)
-                        if state.new_request.available_permits() == 0 {
-                            return acc;
-                        }
-
-                        // Lock-free increment of processed count
-                        let _current_processed =
-                            global_processed_count.fetch_add(1, Ordering::Relaxed) + 1;
-
-                        // Progress updates only from main thread after batch completion
-
-                        // Apply basic filters first (fast elimination)
-                        if !matches_basic_filters(*white_id, *black_id, date, result, &query) {
-                            return acc;
-                        }
-
-                        // Lock-free increment of filter match count
-                        global_filter_match_count.fetch_add(1, Ordering::Relaxed);
-
-                        // Process game for position matching
-                        if let Ok(Some(next_move)) =
-                            get_move_after_match(moves, fen, &position_query)
-                        {
-                            // Thread-local update (no locks needed!)
-                            if acc.matched_ids.len() < 50 {
-                                acc.matched_ids.push(*id);
-                            }
-
-                            let stats =
-                                acc.position_stats
-                                    .entry(next_move.clone())
-                                    .or_insert_with(|| PositionStats {
-                                        move_: next_move,
-                                        white: 0,
-                                        black: 0,
-                                        draw: 0,
-                                    });
-
-                            match result.as_deref() {
-                                Some("1-0") => stats.white += 1,
-                                Some("0-1") => stats.black += 1,
-                                Some("1/2-1/2") => stats.draw += 1,
-                                _ => (), // Unknown results don't count
-                            }
-                        }
-
-                        acc
-                    },
-                )
-                .reduce(
-                    || ThreadLocalResults::default(),
-                    |mut acc1, acc2| {
-                        // Merge thread-local results (no contention here!)
-                        for (key, stats2) in acc2.position_stats {
-                            let stats1 =
-                                acc1.position_stats
-                                    .entry(key)
-                                    .or_insert_with(|| PositionStats {
-                                        move_: stats2.move_.clone(),
-                                        white: 0,
-                                        black: 0,
-                                        draw: 0,
-                                    });
-                            stats1.white += stats2.white;
-                            stats1.black += stats2.black;
-                            stats1.draw += stats2.draw;
-                        }
-
-                        // Merge matched IDs (keep within limit)
-                        for id in acc2.matched_ids {
-                            if acc1.matched_ids.len() < 50 {
-                                acc1.matched_ids.push(id);
-                            }
-                        }
-
-                        acc1
-                    },
-                );
-
-            // Merge batch results into global accumulator (single-threaded, no contention)
-            for (key, batch_stat) in batch_results.position_stats {
-                let global_stat =
-                    global_position_stats
-                        .entry(key)
-                        .or_insert_with(|| PositionStats {
-                            move_: batch_stat.move_.clone(),
-                            white: 0,
-                            black: 0,
-                            draw: 0,
-                        });
-                global_stat.white += batch_stat.white;
-                global_stat.black += batch_stat.black;
-                global_stat.draw += batch_stat.draw;
-            }
-
-            // Merge matched IDs (keep within limit)
-            for id in batch_results.matched_ids {
-                if global_matched_ids.len() < 50 {
-                    global_matched_ids.push(id);
-                }
-            }
+            context.search_chunks(
+                &batch,
+                PARTIAL_CHUNK_SIZE,
+                &mut results,
+                &mut partials,
+                &stopped,
+            );
 
             offset += BATCH_SIZE;
 
@@ -837,6 +984,7 @@ struct ThreadLocalResults {
                     finished: false,
                 },
             );
+            task.report(progress, None);
 
             // For first batch, populate cache if it's reasonable size
             if offset == BATCH_SIZE && batch.len() < 50000 {
@@ -853,30 +1001,38 @@ struct ThreadLocalResults {
             }
         }
 
-        // Extract final results from global accumulators (no Arc unwrapping needed)
-        position_stats = global_position_stats;
-        matched_game_ids = global_matched_ids;
-        processed_count = global_processed_count.load(Ordering::Relaxed);
-        games_with_basic_filter_match = global_filter_match_count.load(Ordering::Relaxed);
-
         info!("Batch processing complete: {} games processed, {} passed basic filters, {} matches found", 
-              processed_count, games_with_basic_filter_match, matched_game_ids.len());
+              context.processed(), context.filter_matched.load(Ordering::Relaxed), results.matched_ids.len());
+    }
+
+    let decode_errors = context.decode_errors.load(Ordering::Relaxed);
+    if decode_errors > 0 {
+        warn!(
+            "Skipped {} games with corrupted move data in {}",
+            decode_errors,
+            file.display()
+        );
     }
 
     info!(
         "Position search completed in {:?}. Found {} unique moves from {} games.",
         start.elapsed(),
-        position_stats.len(),
-        matched_game_ids.len()
+        results.position_stats.len(),
+        results.matched_ids.len()
     );
 
     // Final cancellation check
-    if state.new_request.available_permits() == 0 {
+    if stopped() {
         drop(permit);
         return Err(Error::SearchStopped);
     }
+    partials.finish(&results, context.processed());
 
     // Convert results
+    let SearchResults {
+        position_stats,
+        matched_ids: matched_game_ids,
+    } = results;
     let openings: Vec<PositionStats> = position_stats.into_values().collect();
 
     // Load full game details for matched games
@@ -925,6 +1081,10 @@ struct ThreadLocalResults {
                     // AverageElo will be sorted in Rust after calculating
                     query_builder
                 }
+                GameSort::OpeningRarity => {
+                    rarity::ensure_frequency_table(db)?;
+                    query_builder.order(rarity::rarity_order(&options.direction))
+                }
             };
         }
 
@@ -1016,6 +1176,7 @@ struct ThreadLocalResults {
             finished: true,
         },
     );
+    task.finish();
 
     drop(permit);
 
@@ -1040,6 +1201,10 @@ pub async fn is_position_in_db(
     state: tauri::State<'_, AppState>,
 ) -> Result<bool, Error> {
     let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    let default_start = get_start_position(db)?;
+    let has_custom_start = default_start != Chess::default();
+    let start_material = get_material_count(default_start.board());
+    let start_pawn_home = get_pawn_home(default_start.board());
 
     // Log the position query for debugging
     if let Some(pos_query) = &query.position {
@@ -1082,6 +1247,8 @@ pub async fn is_position_in_db(
                 games::pawn_home,
                 games::white_material,
                 games::black_material,
+                games::white_elo,
+                games::black_elo,
             ))
             .load(db)?;
 
@@ -1091,8 +1258,8 @@ pub async fn is_position_in_db(
     let exists = games.par_iter().any(
         |(
             _id,
-            _white_id,
-            _black_id,
+            white_id,
+            black_id,
             _date,
             _result,
             game,
@@ -1100,21 +1267,34 @@ pub async fn is_position_in_db(
             end_pawn_home,
             white_material,
             black_material,
+            ..,
         )| {
             if state.new_request.available_permits() == 0 {
                 return false;
             }
-            let end_material: MaterialCount = ByColor {
+            let mut end_material: MaterialCount = ByColor {
                 white: *white_material as u8,
                 black: *black_material as u8,
             };
+            let mut end_pawn_home = *end_pawn_home as u16;
+            // Games without a FEN may have been stored against the standard position, so
+            // only trust what is also true of the database's start position.
+            if fen.is_none() && has_custom_start {
+                end_material = ByColor {
+                    white: end_material.white.min(start_material.white),
+                    black: end_material.black.min(start_material.black),
+                };
+                end_pawn_home &= start_pawn_home;
+            }
             if let Some(position_query) = &query.position {
                 let position_query =
                     convert_position_query(position_query.clone()).expect("Invalid position query");
-                position_query.can_reach(&end_material, *end_pawn_home as u16)
-                    && get_move_after_match(game, fen, &position_query)
+                position_query.can_reach(&end_material, end_pawn_home)
+                    && find_position_match(game, fen, &default_start, &position_query)
                         .unwrap_or(None)
-                        .is_some()
+                        .is_some_and(|found| {
+                            matches_side_to_move_player(found.turn, *white_id, *black_id, &query)
+                        })
             } else {
                 false
             }
@@ -1144,6 +1324,36 @@ pub async fn is_position_in_db(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chess::build_analysis_positions;
+    use crate::db::{common_start_fen, import_test_games, set_start_fen, test_db};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use shakmaty::{fen::Epd, CastlingMode, EnPassantMode};
+    use std::collections::HashSet;
+
+    const KINGS_GAMBIT: &str = "rnbqkbnr/pppp1ppp/8/4p3/4PP2/8/PPPP2PP/RNBQKBNR b KQkq - 0 2";
+
+    const THEMED_PGN: &str = r#"[Result "1-0"]
+[SetUp "1"]
+[FEN "rnbqkbnr/pppp1ppp/8/4p3/4PP2/8/PPPP2PP/RNBQKBNR b KQkq - 0 2"]
+
+2... exf4 3. Nf3 g5 1-0
+
+[Result "1/2-1/2"]
+[SetUp "1"]
+[FEN "rnbqkbnr/pppp1ppp/8/4p3/4PP2/8/PPPP2PP/RNBQKBNR b KQkq - 0 2"]
+
+2... exf4 3. Bc4 1/2-1/2
+
+[Result "0-1"]
+[SetUp "1"]
+[FEN "rnbqkbnr/pppp1ppp/8/4p3/4PP2/8/PPPP2PP/RNBQKBNR b KQkq - 0 2"]
+
+2... d5 3. exd5 0-1
+"#;
+
+    fn themed_db() -> SqliteConnection {
+        test_db(THEMED_PGN)
+    }
 
     fn assert_partial_match(fen1: &str, fen2: &str) {
         let query = PositionQuery::partial_from_fen(fen1).unwrap();
@@ -1231,30 +1441,587 @@ fn get_move_after_exact_match_test() {
             "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
         )
         .unwrap();
-        let result = get_move_after_match(&game[..], &None, &query).unwrap();
+        let result = get_move_after_match(&game[..], &None, &Chess::default(), &query).unwrap();
         assert_eq!(result, Some("e4".to_string()));
 
         let query = PositionQuery::exact_from_fen(
             "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
         )
         .unwrap();
-        let result = get_move_after_match(&game[..], &None, &query).unwrap();
+        let result = get_move_after_match(&game[..], &None, &Chess::default(), &query).unwrap();
         assert_eq!(result, Some("e5".to_string()));
 
         let query = PositionQuery::exact_from_fen(
             "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2",
         )
         .unwrap();
-        let result = get_move_after_match(&game[..], &None, &query).unwrap();
+        let result = get_move_after_match(&game[..], &None, &Chess::default(), &query).unwrap();
         assert_eq!(result, Some("*".to_string()));
     }
 
+    #[test]
+    fn get_move_after_match_reports_corrupted_games() {
+        let query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+        )
+        .unwrap();
+
+        // A comment claiming to be longer than the game used to overflow the stream's index.
+        let mut game = vec![12, 252];
+        game.extend(u64::MAX.to_be_bytes());
+        game.push(12);
+        assert!(matches!(
+            get_move_after_match(&game, &None, &Chess::default(), &query),
+            Err(Error::Decode(DecodeError::CommentTooLong { offset: 1, .. }))
+        ));
+
+        let game = vec![12, 200];
+        assert!(matches!(
+            get_move_after_match(&game, &None, &Chess::default(), &query),
+            Err(Error::Decode(DecodeError::IllegalMove {
+                offset: 1,
+                byte: 200
+            }))
+        ));
+
+        // Variations are skipped without losing track of the main line.
+        let game = vec![12, 254, 0, 254, 1, 253, 253, 12];
+        assert_eq!(
+            get_move_after_match(&game, &None, &Chess::default(), &query).unwrap(),
+            Some("e5".to_string())
+        );
+    }
+
+    #[test]
+    fn partial_matches_honor_side_to_move() {
+        // A white rook on the seventh rank, with either side to move.
+        let white_to_move = "6k1/R7/8/8/8/8/8/6K1 w - - 0 1";
+        let black_to_move = "6k1/R7/8/8/8/8/8/6K1 b - - 0 1";
+        let position = |fen: &str| {
+            Fen::from_ascii(fen.as_bytes())
+                .unwrap()
+                .into_position::<Chess>(CastlingMode::Standard)
+                .unwrap()
+        };
+        let any_side = PositionQuery::partial_from_fen("8/R7/8/8/8/8/8/8").unwrap();
+        let white_only =
+            PositionQuery::partial_from_fen_to_move("8/R7/8/8/8/8/8/8", Some(Color::White))
+                .unwrap();
+
+        assert!(any_side.matches(&position(white_to_move)));
+        assert!(any_side.matches(&position(black_to_move)));
+        assert!(white_only.matches(&position(white_to_move)));
+        assert!(!white_only.matches(&position(black_to_move)));
+    }
+
+    #[test]
+    fn side_to_move_is_part_of_the_cache_key() {
+        let query = |side_to_move| PositionQueryJs {
+            fen: "8/R7/8/8/8/8/8/8".to_string(),
+            type_: "partial".to_string(),
+            variant: PositionVariant::Standard,
+            side_to_move,
+        };
+        let keys: HashSet<GameQueryJs> = [None, Some(SideToMove::White), Some(SideToMove::Black)]
+            .into_iter()
+            .map(|side| GameQueryJs::new().position(query(side)))
+            .collect();
+        assert_eq!(keys.len(), 3);
+    }
+
     #[test]
     fn get_move_after_partial_match_test() {
         let game = vec![12, 12]; // 1. e4 e5
 
         let query = PositionQuery::partial_from_fen("8/pppppppp/8/8/8/8/PPPPPPPP/8").unwrap();
-        let result = get_move_after_match(&game[..], &None, &query).unwrap();
+        let result = get_move_after_match(&game[..], &None, &Chess::default(), &query).unwrap();
         assert_eq!(result, Some("e4".to_string()));
     }
+
+    #[test]
+    fn themed_database_root_stats_use_start_fen() {
+        let mut db = themed_db();
+        assert_eq!(
+            common_start_fen(&mut db).unwrap(),
+            Some(KINGS_GAMBIT.to_string())
+        );
+
+        // Games stored without a FEN of their own rely on the database's start position.
+        diesel::update(games::table)
+            .set(games::fen.eq(None::<String>))
+            .execute(&mut db)
+            .unwrap();
+        set_start_fen(&mut db, Some(KINGS_GAMBIT)).unwrap();
+        let start = get_start_position(&mut db).unwrap();
+
+        let query = PositionQuery::exact_from_fen(KINGS_GAMBIT).unwrap();
+        let rows: Vec<(Vec<u8>, Option<String>, Option<String>, i32, i32, i32)> = games::table
+            .select((
+                games::moves,
+                games::fen,
+                games::result,
+                games::pawn_home,
+                games::white_material,
+                games::black_material,
+            ))
+            .load(&mut db)
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+
+        let mut stats: HashMap<String, (i32, i32, i32)> = HashMap::new();
+        for (moves, fen, result, pawn_home, white_material, black_material) in &rows {
+            let material = ByColor {
+                white: *white_material as u8,
+                black: *black_material as u8,
+            };
+            assert!(query.can_reach(&material, *pawn_home as u16));
+
+            let next_move = get_move_after_match(moves, fen, &start, &query)
+                .unwrap()
+                .unwrap();
+            let entry = stats.entry(next_move).or_default();
+            match result.as_deref() {
+                Some("1-0") => entry.0 += 1,
+                Some("1/2-1/2") => entry.1 += 1,
+                Some("0-1") => entry.2 += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["exf4"], (1, 1, 0));
+        assert_eq!(stats["d5"], (0, 0, 1));
+
+        // Replaying from the standard position never reaches the gambit.
+        for (moves, fen, ..) in &rows {
+            assert_eq!(
+                get_move_after_match(moves, fen, &Chess::default(), &query).unwrap_or(None),
+                None
+            );
+        }
+    }
+
+    const CHESS960_PGN: &str = r#"[Variant "Chess960"]
+[SetUp "1"]
+[FEN "qnbbrknr/pppppppp/8/8/8/8/PPPPPPPP/QNBBRKNR w KQkq - 0 1"]
+
+1. Nf3 Nf6 2. O-O O-O *
+"#;
+
+    /// Both sides castled short here, leaving Black the e8 and h8 rooks to castle with.
+    const CHESS960_CASTLED: &str = "qnbbrk1r/pppppppp/5n2/8/8/5N2/PPPPPPPP/QNBBRRK1 b kq - 3 2";
+
+    fn chess960_game() -> (Vec<u8>, Option<String>) {
+        let mut db = test_db(CHESS960_PGN);
+        games::table
+            .select((games::moves, games::fen))
+            .first(&mut db)
+            .unwrap()
+    }
+
+    #[test]
+    fn chess960_positions_are_found_after_castling() {
+        let (moves, fen) = chess960_game();
+        assert!(fen.is_some());
+
+        // Queries from the frontend leave the variant out.
+        let query = convert_position_query(PositionQueryJs {
+            fen: CHESS960_CASTLED.to_string(),
+            type_: "exact".to_string(),
+            variant: PositionVariant::default(),
+            side_to_move: None,
+        })
+        .unwrap();
+        assert_eq!(
+            get_move_after_match(&moves, &fen, &Chess::default(), &query).unwrap(),
+            Some("O-O".to_string())
+        );
+
+        let query =
+            PositionQuery::exact_from_fen_in(CHESS960_CASTLED, PositionVariant::Chess960).unwrap();
+        assert_eq!(
+            get_move_after_match(&moves, &fen, &Chess::default(), &query).unwrap(),
+            Some("O-O".to_string())
+        );
+
+        // Same board, but Black could no longer castle, which only Chess960 queries compare.
+        let without_rights = CHESS960_CASTLED.replace(" kq ", " - ");
+        let query =
+            PositionQuery::exact_from_fen_in(&without_rights, PositionVariant::Chess960).unwrap();
+        assert_eq!(
+            get_move_after_match(&moves, &fen, &Chess::default(), &query).unwrap(),
+            None
+        );
+        let query = PositionQuery::exact_from_fen(&without_rights).unwrap();
+        assert_eq!(
+            get_move_after_match(&moves, &fen, &Chess::default(), &query).unwrap(),
+            Some("O-O".to_string())
+        );
+
+        let query = PositionQuery::partial_from_fen("8/8/8/8/8/8/8/5RK1").unwrap();
+        assert_eq!(
+            get_move_after_match(&moves, &fen, &Chess::default(), &query).unwrap(),
+            Some("O-O".to_string())
+        );
+    }
+
+    #[test]
+    fn position_queries_default_to_standard() {
+        let query: PositionQueryJs =
+            serde_json::from_str(r#"{ "fen": "8/8/8/8/8/8/8/8", "type_": "partial" }"#).unwrap();
+        assert_eq!(query.variant, PositionVariant::Standard);
+        let query: PositionQueryJs = serde_json::from_str(
+            r#"{ "fen": "8/8/8/8/8/8/8/8", "type_": "partial", "variant": "chess960" }"#,
+        )
+        .unwrap();
+        assert_eq!(query.variant, PositionVariant::Chess960);
+    }
+
+    /// Plays random legal moves that never end the game early: moves ending it or repeating
+    /// a position are avoided, and pawns move well before the fifty-move rule applies.
+    fn long_game(plies: usize) -> (Vec<String>, Vec<u8>) {
+        let mut rng = StdRng::seed_from_u64(935);
+        let mut position = Chess::default();
+        let key = |p: &Chess| Epd::from_position(p.clone(), EnPassantMode::Legal).to_string();
+        let mut seen = HashSet::from([key(&position)]);
+        let (mut moves, mut blob) = (Vec::new(), Vec::new());
+
+        while moves.len() < plies {
+            let legal = position.legal_moves();
+            let mut candidates: Vec<(usize, Chess)> = Vec::new();
+            for (i, m) in legal.iter().enumerate() {
+                let mut next = position.clone();
+                next.play_unchecked(m);
+                if !next.is_game_over() && next.halfmoves() < 100 && !seen.contains(&key(&next)) {
+                    candidates.push((i, next));
+                }
+            }
+            let preferred: Vec<&(usize, Chess)> = candidates
+                .iter()
+                .filter(|(i, _)| {
+                    let m = &legal[*i];
+                    if position.halfmoves() >= 40 {
+                        m.role() == Role::Pawn
+                    } else {
+                        m.role() != Role::Pawn && !m.is_capture()
+                    }
+                })
+                .collect();
+            let pool: Vec<&(usize, Chess)> = if preferred.is_empty() {
+                candidates.iter().collect()
+            } else {
+                preferred
+            };
+            assert!(!pool.is_empty(), "ran out of moves at ply {}", moves.len());
+            let (i, next) = pool[rng.gen_range(0..pool.len())].clone();
+
+            moves.push(legal[i].to_uci(CastlingMode::Chess960).to_string());
+            blob.push(i as u8);
+            seen.insert(key(&next));
+            position = next;
+        }
+        (moves, blob)
+    }
+
+    #[test]
+    fn long_games_are_analysed_and_searched_in_linear_time() {
+        let (moves, blob) = long_game(600);
+        let start = Instant::now();
+
+        let fen = Fen::from_position(Chess::default(), EnPassantMode::Legal);
+        let (positions, truncated) = build_analysis_positions(&fen, &moves, None).unwrap();
+        assert!(!truncated);
+        assert_eq!(positions.len(), 601);
+        // Positions point into the shared move list and were allocated once.
+        assert!(positions.iter().enumerate().all(|(i, p)| p.ply == i));
+        assert_eq!(positions.capacity(), 601);
+        assert!(positions.iter().all(|p| p.termination.is_none()));
+
+        let mut stream = MoveStream::new(&blob, Chess::default()).unwrap();
+        let mut last = Chess::default();
+        while let Some((position, _)) = stream.next_move().unwrap() {
+            assert_eq!(*stream.material(), get_material_count(position.board()));
+            assert_eq!(stream.pawn_home(), get_pawn_home(position.board()));
+            last = position;
+        }
+        assert_eq!(
+            Fen::from_position(last.clone(), EnPassantMode::Legal),
+            positions[600].fen
+        );
+
+        let query = PositionQuery::exact_from_fen(
+            &Fen::from_position(last, EnPassantMode::Legal).to_string(),
+        )
+        .unwrap();
+        let result = get_move_after_match(&blob, &None, &Chess::default(), &query).unwrap();
+        assert_eq!(result, Some("*".to_string()));
+
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(10),
+            "took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn partial_results_converge_to_the_final_stats() {
+        let mut db = themed_db();
+        let rows: Vec<GameData> = games::table
+            .select((
+                games::id,
+                games::white_id,
+                games::black_id,
+                games::date,
+                games::result,
+                games::moves,
+                games::fen,
+                games::pawn_home,
+                games::white_material,
+                games::black_material,
+                games::white_elo,
+                games::black_elo,
+            ))
+            .load(&mut db)
+            .unwrap();
+        let games: Vec<GameData> = rows.iter().cycle().take(30).cloned().collect();
+
+        let query = GameQueryJs::default();
+        let position_query = PositionQuery::exact_from_fen(KINGS_GAMBIT).unwrap();
+        let start = get_start_position(&mut db).unwrap();
+        let context = || SearchContext {
+            query: &query,
+            position_query: &position_query,
+            default_start: &start,
+            max_ids: 1000,
+            processed: AtomicUsize::new(0),
+            filter_matched: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+        };
+        let sorted = |openings: &[PositionStats]| {
+            let mut stats: Vec<_> = openings
+                .iter()
+                .map(|s| (s.move_.clone(), s.white, s.draw, s.black))
+                .collect();
+            stats.sort();
+            stats
+        };
+
+        let mut sent = Vec::new();
+        let mut partials =
+            PartialResults::new("tab".to_string(), games.len(), Duration::ZERO, |p| {
+                sent.push(p)
+            });
+        let chunked = context();
+        let mut results = SearchResults::default();
+        chunked.search_chunks(&games, 7, &mut results, &mut partials, &|| false);
+        partials.finish(&results, chunked.processed());
+
+        // One per chunk, then the final one.
+        assert_eq!(sent.len(), 6);
+        for pair in sent[..5].windows(2) {
+            assert!(pair[0].processed < pair[1].processed);
+        }
+        assert!(sent[..5].iter().all(|p| !p.is_final && p.total == 30));
+        let last = sent.last().unwrap();
+        assert!(last.is_final);
+        assert_eq!(last.processed, 30);
+
+        let whole = context().search(&games, &|| false);
+        let expected: Vec<_> = whole.position_stats.into_values().collect();
+        assert_eq!(sorted(&last.openings), sorted(&expected));
+        assert_eq!(
+            sorted(&last.openings),
+            vec![
+                ("d5".to_string(), 0, 0, 10),
+                ("exf4".to_string(), 10, 10, 0)
+            ]
+        );
+        assert_eq!(results.matched_ids.len(), 30);
+
+        // A stopped search offers nothing more.
+        let stopped_context = context();
+        let mut sent = Vec::new();
+        let mut partials =
+            PartialResults::new("tab".to_string(), games.len(), Duration::ZERO, |p| {
+                sent.push(p)
+            });
+        let mut results = SearchResults::default();
+        stopped_context.search_chunks(&games, 7, &mut results, &mut partials, &|| true);
+        assert!(sent.is_empty());
+        assert_eq!(stopped_context.processed(), 0);
+    }
+
+    #[test]
+    fn rating_filters_apply_to_stats_and_games() {
+        let mut db = themed_db();
+        // Black is to move in the gambit. Only the first game, 2... exf4 1-0, has a weaker
+        // player to move.
+        diesel::update(games::table)
+            .set((games::white_elo.eq(2700), games::black_elo.eq(2600)))
+            .execute(&mut db)
+            .unwrap();
+        let first: i32 = games::table
+            .select(games::id)
+            .order(games::id.asc())
+            .first(&mut db)
+            .unwrap();
+        diesel::update(games::table.find(first))
+            .set(games::black_elo.eq(2300))
+            .execute(&mut db)
+            .unwrap();
+        let games: Vec<GameData> = games::table
+            .select((
+                games::id,
+                games::white_id,
+                games::black_id,
+                games::date,
+                games::result,
+                games::moves,
+                games::fen,
+                games::pawn_home,
+                games::white_material,
+                games::black_material,
+                games::white_elo,
+                games::black_elo,
+            ))
+            .load(&mut db)
+            .unwrap();
+
+        let position_query = PositionQuery::exact_from_fen(KINGS_GAMBIT).unwrap();
+        let start = get_start_position(&mut db).unwrap();
+        let search = |query: &GameQueryJs| {
+            let results = SearchContext {
+                query,
+                position_query: &position_query,
+                default_start: &start,
+                max_ids: 1000,
+                processed: AtomicUsize::new(0),
+                filter_matched: AtomicUsize::new(0),
+                decode_errors: AtomicUsize::new(0),
+            }
+            .search(&games, &|| false);
+            let mut stats: Vec<_> = results
+                .position_stats
+                .into_values()
+                .map(|s| (s.move_, s.white, s.draw, s.black))
+                .collect();
+            stats.sort();
+            let mut ids = results.matched_ids;
+            ids.sort();
+            (stats, ids)
+        };
+
+        let strong = GameQueryJs {
+            min_elo_side_to_move: Some(2500),
+            ..Default::default()
+        };
+        let (stats, ids) = search(&strong);
+        assert_eq!(
+            stats,
+            vec![("d5".to_string(), 0, 0, 1), ("exf4".to_string(), 0, 1, 0)]
+        );
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&first));
+
+        let (stats, ids) = search(&GameQueryJs {
+            min_elo_side_to_move: Some(2200),
+            ..Default::default()
+        });
+        assert_eq!(
+            stats,
+            vec![("d5".to_string(), 0, 0, 1), ("exf4".to_string(), 1, 1, 0)]
+        );
+        assert!(ids.contains(&first));
+
+        // (2700 + 2300) / 2 = 2500
+        let (_, ids) = search(&GameQueryJs {
+            min_avg_elo: Some(2500),
+            ..Default::default()
+        });
+        assert_eq!(ids.len(), 3);
+        let (_, ids) = search(&GameQueryJs {
+            min_avg_elo: Some(2600),
+            ..Default::default()
+        });
+        assert_eq!(ids.len(), 2);
+
+        // Searches differing only by the filter are cached apart.
+        assert_ne!(strong, GameQueryJs::default());
+    }
+
+    #[test]
+    fn matched_side_player_must_be_to_move() {
+        let mut db = test_db("");
+        // White is to move after 1. e4 e5 in every game, but only the first has Me as White.
+        let pgn = "[White \"Me\"]\n[Black \"A\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 1-0\n\n\
+                   [White \"B\"]\n[Black \"Me\"]\n[Result \"0-1\"]\n\n1. e4 e5 2. Bc4 0-1\n\n\
+                   [White \"B\"]\n[Black \"A\"]\n[Result \"1/2-1/2\"]\n\n1. e4 e5 2. d4 1/2-1/2\n\n";
+        let ids = import_test_games(&mut db, pgn);
+        let me: i32 = players::table
+            .filter(players::name.eq("Me"))
+            .select(players::id)
+            .first(&mut db)
+            .unwrap();
+        let games: Vec<GameData> = games::table
+            .select((
+                games::id,
+                games::white_id,
+                games::black_id,
+                games::date,
+                games::result,
+                games::moves,
+                games::fen,
+                games::pawn_home,
+                games::white_material,
+                games::black_material,
+                games::white_elo,
+                games::black_elo,
+            ))
+            .load(&mut db)
+            .unwrap();
+
+        let position_query = PositionQuery::exact_from_fen(
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2",
+        )
+        .unwrap();
+        let start = get_start_position(&mut db).unwrap();
+        let query = GameQueryJs {
+            matched_side_player: Some(me),
+            ..Default::default()
+        };
+        let results = SearchContext {
+            query: &query,
+            position_query: &position_query,
+            default_start: &start,
+            max_ids: 1000,
+            processed: AtomicUsize::new(0),
+            filter_matched: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+        }
+        .search(&games, &|| false);
+        assert_eq!(results.matched_ids, vec![ids[0]]);
+        let stats: Vec<_> = results
+            .position_stats
+            .into_values()
+            .map(|s| (s.move_, s.white, s.draw, s.black))
+            .collect();
+        assert_eq!(stats, vec![("Nf3".to_string(), 1, 0, 0)]);
+
+        // Me is to move with Black after 1. e4, in the second game only.
+        let (_, white_id, black_id, _, _, moves, fen, ..) = &games[1];
+        let found = find_position_match(
+            moves,
+            fen,
+            &start,
+            &PositionQuery::exact_from_fen(
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            )
+            .unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(matches_side_to_move_player(
+            found.turn, *white_id, *black_id, &query
+        ));
+        assert_ne!(query, GameQueryJs::default());
+    }
 }
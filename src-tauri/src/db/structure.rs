@@ -0,0 +1,439 @@
+//! Pawn structure families
+//!
+//! Games are classified by the pawn structure reached after the opening, so they can be
+//! filtered for structure-based study (Carlsbad, IQP, hedgehog, stonewall). The structure
+//! is read once the pawns stop moving for a while, or at a fixed ply otherwise, and
+//! matched against a table of rules over the pawns' files and ranks. Positions matching
+//! no family, or several of them, are classified as `Other`.
+
+use std::path::PathBuf;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Text},
+};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, Bitboard, Board, ByColor, CastlingMode, Chess, File, Position, Square};
+use specta::Type;
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{
+        encoding::extract_main_line_moves, get_db_or_create, get_start_position, schema::*,
+        write_lock, ConnectionOptions, DatabaseProgress,
+    },
+    error::{Error, Result},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+const PAWN_STRUCTURES_SQL: &str = include_str!("../../../database/schema/pawn_structures.sql");
+
+/// Ply at which the structure is read when the pawns never settle, i.e. after move 15.
+pub const DEFAULT_STRUCTURE_PLY: usize = 30;
+
+/// Earliest ply at which a settled structure is read.
+const MIN_STRUCTURE_PLY: usize = 16;
+
+/// Plies without a pawn move after which the structure is considered settled.
+const STABLE_PLIES: usize = 8;
+
+const BACKFILL_BATCH_SIZE: i64 = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum PawnStructure {
+    Carlsbad,
+    /// Isolated queen pawn.
+    Iqp,
+    Hedgehog,
+    Stonewall,
+    #[default]
+    Other,
+}
+
+impl PawnStructure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PawnStructure::Carlsbad => "carlsbad",
+            PawnStructure::Iqp => "iqp",
+            PawnStructure::Hedgehog => "hedgehog",
+            PawnStructure::Stonewall => "stonewall",
+            PawnStructure::Other => "other",
+        }
+    }
+
+    fn from_label(s: &str) -> Self {
+        match s {
+            "carlsbad" => PawnStructure::Carlsbad,
+            "iqp" => PawnStructure::Iqp,
+            "hedgehog" => PawnStructure::Hedgehog,
+            "stonewall" => PawnStructure::Stonewall,
+            _ => PawnStructure::Other,
+        }
+    }
+}
+
+/// Pawns of the side the rule is written for (`us`, moving up the board) and of its opponent.
+struct Pawns {
+    us: Bitboard,
+    them: Bitboard,
+}
+
+impl Pawns {
+    fn us_on(&self, squares: &[Square]) -> bool {
+        squares.iter().all(|sq| self.us.contains(*sq))
+    }
+
+    fn them_on(&self, squares: &[Square]) -> bool {
+        squares.iter().all(|sq| self.them.contains(*sq))
+    }
+
+    fn us_without(&self, files: &[File]) -> bool {
+        files
+            .iter()
+            .all(|file| (self.us & Bitboard::from_file(*file)).is_empty())
+    }
+
+    fn them_without(&self, files: &[File]) -> bool {
+        files
+            .iter()
+            .all(|file| (self.them & Bitboard::from_file(*file)).is_empty())
+    }
+}
+
+/// d4 and e3 against c6 and d5, with the c- and e-pawns exchanged.
+fn is_carlsbad(p: &Pawns) -> bool {
+    p.us_on(&[Square::D4])
+        && p.us_without(&[File::C])
+        && !p.us_without(&[File::E])
+        && p.them_on(&[Square::C6, Square::D5])
+        && p.them_without(&[File::E])
+}
+
+/// An isolated d4 pawn against no c- or d-pawn.
+fn is_iqp(p: &Pawns) -> bool {
+    p.us_on(&[Square::D4])
+        && p.us_without(&[File::C, File::E])
+        && p.them_without(&[File::C, File::D])
+}
+
+/// c4 and e4 against b6, d6 and e6, with the c- and d-pawns exchanged.
+fn is_hedgehog(p: &Pawns) -> bool {
+    p.us_on(&[Square::C4, Square::E4])
+        && p.us_without(&[File::D])
+        && p.them_on(&[Square::B6, Square::D6, Square::E6])
+        && p.them_without(&[File::C])
+}
+
+/// Pawns on c3, d4, e3 and f4.
+fn is_stonewall(p: &Pawns) -> bool {
+    p.us_on(&[Square::C3, Square::D4, Square::E3, Square::F4])
+}
+
+const RULES: &[(PawnStructure, fn(&Pawns) -> bool)] = &[
+    (PawnStructure::Carlsbad, is_carlsbad),
+    (PawnStructure::Iqp, is_iqp),
+    (PawnStructure::Hedgehog, is_hedgehog),
+    (PawnStructure::Stonewall, is_stonewall),
+];
+
+/// Classify pawns, trying every rule from both sides' point of view.
+pub fn classify_pawns(pawns: ByColor<Bitboard>) -> PawnStructure {
+    let sides = [
+        Pawns {
+            us: pawns.white,
+            them: pawns.black,
+        },
+        Pawns {
+            us: pawns.black.flip_vertical(),
+            them: pawns.white.flip_vertical(),
+        },
+    ];
+    let mut families = RULES
+        .iter()
+        .filter(|(_, rule)| sides.iter().any(rule))
+        .map(|(family, _)| *family);
+    match (families.next(), families.next()) {
+        (Some(family), None) => family,
+        _ => PawnStructure::Other,
+    }
+}
+
+pub fn board_pawns(board: &Board) -> ByColor<Bitboard> {
+    ByColor {
+        white: board.pawns() & board.white(),
+        black: board.pawns() & board.black(),
+    }
+}
+
+/// Ply at which to read the structure of a game, given its pawns after each ply.
+///
+/// This is the first ply from `MIN_STRUCTURE_PLY` on where the pawns haven't moved for
+/// `STABLE_PLIES`, and `ply` (or the end of the game, if shorter) when they never settle.
+pub fn structure_ply(pawns: &[ByColor<Bitboard>], ply: usize) -> usize {
+    let target = ply.min(pawns.len().saturating_sub(1));
+    let mut last_change = 0;
+    for i in 1..=target {
+        if pawns[i] != pawns[i - 1] {
+            last_change = i;
+        }
+        if i >= MIN_STRUCTURE_PLY && i - last_change >= STABLE_PLIES {
+            return i;
+        }
+    }
+    target
+}
+
+/// Classify a game from its pawns after each ply, starting with the initial position.
+pub fn classify_game(pawns: &[ByColor<Bitboard>], ply: usize) -> PawnStructure {
+    match pawns.get(structure_ply(pawns, ply)) {
+        Some(pawns) => classify_pawns(*pawns),
+        None => PawnStructure::Other,
+    }
+}
+
+/// Databases created before structures were classified don't have the table yet.
+pub(crate) fn ensure_structure_table(db: &mut SqliteConnection) -> Result<()> {
+    db.batch_execute(PAWN_STRUCTURES_SQL)?;
+    Ok(())
+}
+
+pub(crate) fn store_structure(
+    db: &mut SqliteConnection,
+    game_id: i32,
+    structure: PawnStructure,
+) -> Result<()> {
+    diesel::replace_into(game_structures::table)
+        .values((
+            game_structures::game_id.eq(game_id),
+            game_structures::structure.eq(structure.as_str()),
+        ))
+        .execute(db)?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Type)]
+pub struct PawnStructureCount {
+    pub structure: PawnStructure,
+    pub count: i64,
+}
+
+#[derive(QueryableByName)]
+struct StructureCountRow {
+    #[diesel(sql_type = Text, column_name = "Structure")]
+    structure: String,
+    #[diesel(sql_type = BigInt, column_name = "Count")]
+    count: i64,
+}
+
+/// Number of games of each pawn structure family, most common first.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_pawn_structure_counts(
+    file: PathBuf,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<PawnStructureCount>> {
+    let db = &mut get_db_or_create(&state, file.to_str().unwrap(), ConnectionOptions::default())?;
+    ensure_structure_table(db)?;
+
+    let rows: Vec<StructureCountRow> = sql_query(
+        "SELECT s.Structure AS Structure, COUNT(*) AS Count FROM GameStructures s \
+         JOIN Games g ON g.ID = s.GameID GROUP BY s.Structure ORDER BY Count DESC",
+    )
+    .load(db)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| PawnStructureCount {
+            structure: PawnStructure::from_label(&row.structure),
+            count: row.count,
+        })
+        .collect())
+}
+
+/// Classify the pawn structure of every game of a database, returning how many were classified.
+///
+/// Databases imported before structures existed need this once; it can also be rerun with
+/// a different `ply` (`DEFAULT_STRUCTURE_PLY` when omitted). Progress is reported through
+/// `DatabaseProgress` events whose id is the database path.
+#[tauri::command]
+#[specta::specta]
+pub async fn classify_pawn_structures(
+    file: PathBuf,
+    ply: Option<u32>,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<u32> {
+    let id = file.to_string_lossy().to_string();
+    let ply = ply.map(|p| p as usize).unwrap_or(DEFAULT_STRUCTURE_PLY);
+    let lock = write_lock(&state, &id);
+    let _guard = lock.lock().await;
+
+    let db = &mut get_db_or_create(&state, &id, ConnectionOptions::default())?;
+    ensure_structure_table(db)?;
+    let start = get_start_position(db)?;
+    let total: i64 = games::table.count().get_result(db)?;
+
+    let task = TaskHandle::start(&app, TaskKind::Database, &id, false);
+    let mut classified = 0;
+    let mut last_id = 0;
+    loop {
+        let batch: Vec<(i32, Option<String>, Vec<u8>)> = games::table
+            .select((games::id, games::fen, games::moves))
+            .filter(games::id.gt(last_id))
+            .order(games::id)
+            .limit(BACKFILL_BATCH_SIZE)
+            .load(db)?;
+        let Some((batch_last, _, _)) = batch.last() else {
+            break;
+        };
+        last_id = *batch_last;
+
+        db.immediate_transaction::<_, Error, _>(|db| {
+            for (game_id, fen, moves) in &batch {
+                let position = match fen {
+                    Some(fen) => Fen::from_ascii(fen.as_bytes())?
+                        .into_position::<Chess>(CastlingMode::Chess960)?,
+                    None => start.clone(),
+                };
+                store_structure(db, *game_id, classify_moves(position, moves, ply)?)?;
+            }
+            Ok(())
+        })?;
+        classified += batch.len();
+
+        let progress = (classified as f64 / total.max(1) as f64) * 100_f64;
+        DatabaseProgress {
+            id: id.clone(),
+            progress,
+            stage: Some("pawn_structures".to_string()),
+        }
+        .emit(&app)?;
+        task.report(progress, Some("pawn_structures".to_string()));
+        tokio::task::yield_now().await;
+    }
+
+    DatabaseProgress {
+        id,
+        progress: 100_f64,
+        stage: None,
+    }
+    .emit(&app)?;
+    task.finish();
+
+    Ok(classified as u32)
+}
+
+/// Classify a game stored in the database from its encoded moves.
+fn classify_moves(mut position: Chess, moves: &[u8], ply: usize) -> Result<PawnStructure> {
+    let mut pawns = vec![board_pawns(position.board())];
+    for m in extract_main_line_moves(moves, Some(position.clone()))? {
+        position.play_unchecked(&m);
+        pawns.push(board_pawns(position.board()));
+    }
+    Ok(classify_game(&pawns, ply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(board_fen: &str) -> PawnStructure {
+        let board = Board::from_ascii_board_fen(board_fen.as_bytes()).unwrap();
+        classify_pawns(board_pawns(&board))
+    }
+
+    #[test]
+    fn carlsbad_from_either_side() {
+        // Queen's Gambit exchange.
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/RNBQKBNR"),
+            PawnStructure::Carlsbad
+        );
+        // Caro-Kann exchange, the reversed Carlsbad.
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/4p3/3p4/3P4/2P5/PP3PPP/RNBQKBNR"),
+            PawnStructure::Carlsbad
+        );
+    }
+
+    #[test]
+    fn isolated_queen_pawn_of_either_side() {
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/4p3/8/3P4/8/PP3PPP/RNBQKBNR"),
+            PawnStructure::Iqp
+        );
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/8/3p4/8/4P3/PP3PPP/RNBQKBNR"),
+            PawnStructure::Iqp
+        );
+    }
+
+    #[test]
+    fn hedgehog() {
+        assert_eq!(
+            classify("rnbqkbnr/5ppp/pp1pp3/8/2P1P3/8/PP3PPP/RNBQKBNR"),
+            PawnStructure::Hedgehog
+        );
+    }
+
+    #[test]
+    fn stonewall() {
+        // Dutch stonewall.
+        assert_eq!(
+            classify("rnbqkbnr/pp4pp/2p1p3/3p1p2/2PP4/4P3/PP3PPP/RNBQKBNR"),
+            PawnStructure::Stonewall
+        );
+    }
+
+    #[test]
+    fn ambiguous_structures_are_other() {
+        assert_eq!(
+            classify("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR"),
+            PawnStructure::Other
+        );
+        // Both sides have an isolated d-pawn.
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/8/3p4/3P4/8/PP3PPP/RNBQKBNR"),
+            PawnStructure::Other
+        );
+        // Carlsbad without the c6 pawn: not a Carlsbad, and d5 isn't isolated against d4.
+        assert_eq!(
+            classify("rnbqkbnr/pp3ppp/8/3p4/3P4/4P3/PP3PPP/RNBQKBNR"),
+            PawnStructure::Other
+        );
+    }
+
+    #[test]
+    fn structure_is_read_once_pawns_settle() {
+        let start = board_pawns(&Board::default());
+        let iqp = board_pawns(
+            &Board::from_ascii_board_fen(b"rnbqkbnr/pp3ppp/4p3/8/3P4/8/PP3PPP/RNBQKBNR").unwrap(),
+        );
+
+        // Pawns settle at ply 10 and are still unchanged at ply 18.
+        let mut pawns = vec![start; 10];
+        pawns.extend(vec![iqp; 40]);
+        assert_eq!(structure_ply(&pawns, DEFAULT_STRUCTURE_PLY), 18);
+        assert_eq!(
+            classify_game(&pawns, DEFAULT_STRUCTURE_PLY),
+            PawnStructure::Iqp
+        );
+
+        // Pawns keep moving: the structure is read at the configured ply.
+        let moving: Vec<_> = (0..50)
+            .map(|i| if i % 2 == 0 { start } else { iqp })
+            .collect();
+        assert_eq!(structure_ply(&moving, DEFAULT_STRUCTURE_PLY), 30);
+        assert_eq!(structure_ply(&moving, 12), 12);
+
+        // Short games are read at their end.
+        assert_eq!(structure_ply(&pawns[..12], DEFAULT_STRUCTURE_PLY), 11);
+        assert_eq!(
+            classify_game(&[], DEFAULT_STRUCTURE_PLY),
+            PawnStructure::Other
+        );
+    }
+}
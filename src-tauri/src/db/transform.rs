@@ -0,0 +1,348 @@
+//! Mirrored and color-swapped versions of positions and games, for training content.
+//!
+//! Positions are transformed square by square, pieces, castling rights and the en passant
+//! square alike, and are then set up again in Chess960 mode, since a mirrored king no
+//! longer starts on the e-file. Games are replayed move by move on both boards, and every
+//! transformed position is checked against the transform of the original one. Castling is
+//! where they part ways: the king and rook of a mirrored castle land on other squares than
+//! the mirror of the original ones, and such games are refused at that ply.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, san::SanPlus, Bitboard, Board, CastlingMode, Chess, EnPassantMode, FromSetup, Move,
+    Piece, Position, Setup, Square,
+};
+use specta::Type;
+
+use crate::{
+    db::{
+        pgn::{GameTree, GameTreeNode},
+        tree::{load_game, GameSource},
+    },
+    error::{Error, Result},
+    AppState,
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionTransform {
+    /// Swap the a- and h-files, keeping colors.
+    MirrorHorizontal,
+    /// Swap the first and eighth ranks along with the colors, so the other side plays the
+    /// same moves.
+    ColorSwap,
+    /// Both of the above, as seen from the other side of the board.
+    Rotate180,
+}
+
+impl PositionTransform {
+    fn square(self, square: Square) -> Square {
+        match self {
+            PositionTransform::MirrorHorizontal => square.flip_horizontal(),
+            PositionTransform::ColorSwap => square.flip_vertical(),
+            PositionTransform::Rotate180 => square.rotate_180(),
+        }
+    }
+
+    fn bitboard(self, bitboard: Bitboard) -> Bitboard {
+        match self {
+            PositionTransform::MirrorHorizontal => bitboard.flip_horizontal(),
+            PositionTransform::ColorSwap => bitboard.flip_vertical(),
+            PositionTransform::Rotate180 => bitboard.rotate_180(),
+        }
+    }
+
+    fn swaps_colors(self) -> bool {
+        self != PositionTransform::MirrorHorizontal
+    }
+
+    /// Transform a setup, which may not be a legal position anymore.
+    pub fn setup(self, setup: Setup) -> Setup {
+        let mut board = Board::empty();
+        for square in Square::ALL {
+            if let Some(piece) = setup.board.piece_at(square) {
+                let piece = if self.swaps_colors() {
+                    Piece {
+                        color: !piece.color,
+                        role: piece.role,
+                    }
+                } else {
+                    piece
+                };
+                board.set_piece_at(self.square(square), piece);
+            }
+        }
+        Setup {
+            board,
+            promoted: self.bitboard(setup.promoted),
+            turn: if self.swaps_colors() {
+                !setup.turn
+            } else {
+                setup.turn
+            },
+            castling_rights: self.bitboard(setup.castling_rights),
+            ep_square: setup.ep_square.map(|square| self.square(square)),
+            ..setup
+        }
+    }
+
+    /// Transform a position, failing if the result can't be set up.
+    pub fn position(self, position: &Chess) -> Result<Chess> {
+        let setup = self.setup(position.clone().into_setup(EnPassantMode::Legal));
+        Ok(Chess::from_setup(setup, CastlingMode::Chess960)?)
+    }
+
+    fn map_move(self, m: &Move) -> Move {
+        match *m {
+            Move::Normal {
+                role,
+                from,
+                capture,
+                to,
+                promotion,
+            } => Move::Normal {
+                role,
+                from: self.square(from),
+                capture,
+                to: self.square(to),
+                promotion,
+            },
+            Move::EnPassant { from, to } => Move::EnPassant {
+                from: self.square(from),
+                to: self.square(to),
+            },
+            Move::Castle { king, rook } => Move::Castle {
+                king: self.square(king),
+                rook: self.square(rook),
+            },
+            Move::Put { role, to } => Move::Put {
+                role,
+                to: self.square(to),
+            },
+        }
+    }
+}
+
+fn fen_of(setup: Setup) -> String {
+    Fen::from_setup(setup).to_string()
+}
+
+/// Transform the moves of `tree`, the first one played from `original` whose transform is
+/// `transformed`. `ply` is the number of moves leading to `original`.
+fn transform_line(
+    tree: &GameTree,
+    mut original: Chess,
+    mut transformed: Chess,
+    mut ply: u32,
+    transform: PositionTransform,
+) -> Result<GameTree> {
+    let mut line = GameTree::new();
+    let mut previous = (original.clone(), transformed.clone(), ply);
+    for node in tree.nodes() {
+        match node {
+            GameTreeNode::Move(san) => {
+                let m = san.san.to_move(&original)?;
+                let illegal = || Error::IllegalTransform {
+                    ply: ply + 1,
+                    san: san.to_string(),
+                };
+                let mapped = transform.map_move(&m);
+                if !transformed.is_legal(&mapped) {
+                    return Err(illegal());
+                }
+                previous = (original.clone(), transformed.clone(), ply);
+                original.play_unchecked(&m);
+                let san = SanPlus::from_move_and_play_unchecked(&mut transformed, &mapped);
+                ply += 1;
+                let expected = transform.setup(original.clone().into_setup(EnPassantMode::Legal));
+                if fen_of(expected) != fen_of(transformed.clone().into_setup(EnPassantMode::Legal))
+                {
+                    return Err(illegal());
+                }
+                line.push(GameTreeNode::Move(san));
+            }
+            GameTreeNode::Comment(comment) => line.push(GameTreeNode::Comment(comment.clone())),
+            GameTreeNode::Nag(nag) => line.push(GameTreeNode::Nag(*nag)),
+            GameTreeNode::Variation(branch) => {
+                // An alternative to the last move, played from the same position.
+                let (original, transformed, ply) = previous.clone();
+                line.push(GameTreeNode::Variation(transform_line(
+                    branch,
+                    original,
+                    transformed,
+                    ply,
+                    transform,
+                )?));
+            }
+        }
+    }
+    Ok(line)
+}
+
+/// Transform a game and its start position.
+pub fn transform_tree(
+    tree: &GameTree,
+    start: &Chess,
+    transform: PositionTransform,
+) -> Result<(GameTree, Chess)> {
+    let transformed = transform.position(start)?;
+    let tree = transform_line(tree, start.clone(), transformed.clone(), 0, transform)?;
+    Ok((tree, transformed))
+}
+
+struct Movetext<'a> {
+    tree: &'a GameTree,
+    start: &'a Chess,
+}
+
+impl fmt::Display for Movetext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tree
+            .pretty_print(f, Some(self.start.clone()))
+            .map_err(|_| fmt::Error)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct TransformedGame {
+    /// Start position of the transformed game.
+    pub fen: String,
+    /// Movetext of the transformed game, with its comments, NAGs and variations.
+    pub pgn: String,
+}
+
+/// Transform a position given as FEN.
+#[tauri::command]
+#[specta::specta]
+pub fn transform_position(fen: String, transform: PositionTransform) -> Result<String> {
+    let setup = Fen::from_ascii(fen.as_bytes())?.into_setup();
+    let position = Chess::from_setup(transform.setup(setup), CastlingMode::Chess960)?;
+    Ok(Fen::from_position(position, EnPassantMode::Legal).to_string())
+}
+
+/// Transform a game move by move, failing at the first move that has no legal counterpart.
+#[tauri::command]
+#[specta::specta]
+pub async fn transform_game(
+    game: GameSource,
+    transform: PositionTransform,
+    state: tauri::State<'_, AppState>,
+) -> Result<TransformedGame> {
+    let (tree, start) = load_game(game, &state)?;
+    let (tree, start) = transform_tree(&tree, &start, transform)?;
+    Ok(TransformedGame {
+        fen: Fen::from_position(start.clone(), EnPassantMode::Legal).to_string(),
+        pgn: Movetext {
+            tree: &tree,
+            start: &start,
+        }
+        .to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::pgn::Importer;
+    use pgn_reader::BufferedReader;
+    use PositionTransform::*;
+
+    fn transform(fen: &str, transform: PositionTransform) -> String {
+        transform_position(fen.to_string(), transform).unwrap()
+    }
+
+    fn game(pgn: &str) -> (GameTree, Chess) {
+        let mut importer = Importer::new(None);
+        let game = BufferedReader::new_cursor(pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .next()
+            .unwrap();
+        (game.tree, game.position)
+    }
+
+    fn movetext(pgn: &str, transform: PositionTransform) -> Result<String> {
+        let (tree, start) = game(pgn);
+        let (tree, start) = transform_tree(&tree, &start, transform)?;
+        Ok(Movetext {
+            tree: &tree,
+            start: &start,
+        }
+        .to_string())
+    }
+
+    #[test]
+    fn castling_rights_follow_the_rooks() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w Kq - 0 1";
+        assert_eq!(
+            transform(fen, ColorSwap),
+            "r3k2r/8/8/8/8/8/8/R3K2R b Qk - 0 1"
+        );
+        // The kings leave the e-file, making a Chess960 position.
+        assert_eq!(
+            transform(fen, MirrorHorizontal),
+            "r2k3r/8/8/8/8/8/8/R2K3R w Qk - 0 1"
+        );
+        assert_eq!(
+            transform(fen, Rotate180),
+            "r2k3r/8/8/8/8/8/8/R2K3R b Kq - 0 1"
+        );
+    }
+
+    #[test]
+    fn en_passant_squares_are_transformed() {
+        let fen = "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3";
+        assert_eq!(
+            transform(fen, ColorSwap),
+            "rnbqkbnr/pppp1ppp/8/3PpP2/8/8/PPP1P1PP/RNBQKBNR b KQkq f3 0 3"
+        );
+        assert_eq!(
+            transform(fen, MirrorHorizontal),
+            "rnbkqbnr/pp1p1ppp/8/2pPp3/8/8/PPP1PPPP/RNBKQBNR w KQkq c6 0 3"
+        );
+        // Transforms undo themselves.
+        for t in [MirrorHorizontal, ColorSwap, Rotate180] {
+            assert_eq!(transform(&transform(fen, t), t), fen);
+        }
+    }
+
+    #[test]
+    fn promotions_keep_their_piece() {
+        let pgn = "[FEN \"8/P6k/8/8/8/8/8/K7 w - - 0 1\"]\n\n1. a8=N Kg6 *";
+        assert_eq!(movetext(pgn, ColorSwap).unwrap(), "1...a1=N 2.Kg3");
+        assert_eq!(movetext(pgn, MirrorHorizontal).unwrap(), "1.h8=N Kb6");
+        assert_eq!(movetext(pgn, Rotate180).unwrap(), "1...h1=N 2.Kb3");
+    }
+
+    #[test]
+    fn games_keep_en_passant_and_annotations() {
+        let pgn = "1. e4 (1. d4 {Queen's pawn} d5) 1... a6 2. e5 d5 3. exd6 $1 *";
+        let swapped = movetext(pgn, ColorSwap).unwrap();
+        assert!(swapped.starts_with("1...e5 ( 1...d5 {Queen's pawn} "));
+        assert!(swapped.ends_with("2.a3 e4 3.d4 exd3 $1"));
+
+        // Transforming twice gives the game back.
+        let (tree, start) = game(pgn);
+        let (once, start_once) = transform_tree(&tree, &start, ColorSwap).unwrap();
+        let (twice, _) = transform_tree(&once, &start_once, ColorSwap).unwrap();
+        assert_eq!(twice, tree);
+    }
+
+    #[test]
+    fn castles_that_cant_be_mirrored_are_refused() {
+        let pgn = "1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. O-O Nf6 *";
+        assert!(movetext(pgn, ColorSwap).unwrap().contains("4.Bc4 O-O"));
+        for t in [MirrorHorizontal, Rotate180] {
+            match movetext(pgn, t) {
+                Err(Error::IllegalTransform { ply, san }) => {
+                    assert_eq!(ply, 7);
+                    assert_eq!(san, "O-O");
+                }
+                other => panic!("expected a refused castle, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Deleted databases
+//!
+//! Deleting a database moves it, with its journal files, into a folder of its own in the
+//! app's `trash` directory, named after when it was deleted, from where it can be restored.
+//! Moving to another file system copies the files, which takes a while for big databases, so
+//! copies report their progress through `DatabaseProgress` events whose id is the database
+//! path, and are verified before the originals are removed.
+//!
+//! Trashed databases are purged on startup once they're older than `RETENTION_DAYS`, and the
+//! oldest ones first while the trash is larger than `MAX_TRASH_SIZE`.
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+use tauri_specta::Event as _;
+
+use crate::{
+    db::{player_metadata, DatabaseProgress},
+    error::{Error, Result},
+    fs::{copy_file, unique_path},
+    tasks::{TaskHandle, TaskKind},
+    AppState,
+};
+
+const TRASH_DIR: &str = "trash";
+
+/// File describing a trashed database, next to it in its folder.
+const ENTRY_FILE: &str = "entry.json";
+
+/// Files SQLite keeps next to a database.
+const SIDECAR_SUFFIXES: [&str; 3] = ["-journal", "-wal", "-shm"];
+
+/// Days trashed databases are kept.
+pub const RETENTION_DAYS: i64 = 30;
+
+/// Size in bytes above which the oldest trashed databases are purged.
+pub const MAX_TRASH_SIZE: u64 = 20 * 1024 * 1024 * 1024;
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct TrashedDatabase {
+    /// Name of the database's folder in the trash, to pass to `restore_trashed_database`.
+    pub entry: String,
+    /// Where the database was deleted from.
+    pub original_path: String,
+    pub filename: String,
+    /// Unix timestamp in milliseconds of the deletion.
+    pub deleted_at: i64,
+    /// Size in bytes of the database and its journal files.
+    pub size: u64,
+}
+
+fn trash_dir(app: &AppHandle) -> Result<PathBuf> {
+    Ok(app.path().resolve(TRASH_DIR, BaseDirectory::AppData)?)
+}
+
+/// Journal files of `file` that exist, with their suffix.
+fn sidecars(file: &Path) -> Vec<(&'static str, PathBuf)> {
+    SIDECAR_SUFFIXES
+        .iter()
+        .map(|suffix| {
+            let mut path = file.as_os_str().to_owned();
+            path.push(suffix);
+            (*suffix, PathBuf::from(path))
+        })
+        .filter(|(_, path)| path.exists())
+        .collect()
+}
+
+/// `file` followed by its journal files, each with where it goes when `file` moves to `to`.
+fn database_moves(file: &Path, to: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let mut moves = vec![(file.to_path_buf(), to.to_path_buf())];
+    moves.extend(sidecars(file).into_iter().map(|(suffix, path)| {
+        let mut target = to.as_os_str().to_owned();
+        target.push(suffix);
+        (path, PathBuf::from(target))
+    }));
+    moves
+}
+
+/// Move files, copying them when they can't be renamed, e.g. across file systems.
+///
+/// Reports the share of the bytes moved, from 0 to 1, while copying.
+fn move_files(moves: &[(PathBuf, PathBuf)], progress: &mut dyn FnMut(f64)) -> Result<()> {
+    let sizes: Vec<u64> = moves
+        .iter()
+        .map(|(from, _)| from.metadata().map(|m| m.len()))
+        .collect::<std::io::Result<_>>()?;
+    let total: u64 = sizes.iter().sum();
+    let mut done = 0;
+    for ((from, to), size) in moves.iter().zip(&sizes) {
+        if rename(from, to).is_err() {
+            copy_file(from, to, true, &mut |copied| {
+                progress((done + copied) as f64 / total.max(1) as f64)
+            })?;
+            remove_file(from)?;
+        }
+        done += size;
+    }
+    Ok(())
+}
+
+/// Move `file` into a new folder of `trash`.
+fn trash_database(
+    file: &Path,
+    trash: &Path,
+    deleted_at: i64,
+    progress: &mut dyn FnMut(f64),
+) -> Result<TrashedDatabase> {
+    let filename = file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| Error::DatabaseNotFound(file.to_string_lossy().to_string()))?;
+    let (dir, _) = unique_path(trash, &format!("{}-{}", deleted_at, filename));
+    create_dir_all(&dir)?;
+
+    let moves = database_moves(file, &dir.join(&filename));
+    let size = moves
+        .iter()
+        .map(|(from, _)| from.metadata().map_or(0, |m| m.len()))
+        .sum();
+    let trashed = TrashedDatabase {
+        entry: dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        original_path: file.to_string_lossy().to_string(),
+        filename,
+        deleted_at,
+        size,
+    };
+    std::fs::write(dir.join(ENTRY_FILE), serde_json::to_vec(&trashed)?)?;
+
+    if let Err(e) = move_files(&moves, progress) {
+        // Once the database itself moved it stays listed, even if its journal files didn't.
+        if file.exists() {
+            let _ = remove_dir_all(&dir);
+        } else {
+            warn!("Partly moved {} to the trash: {}", file.display(), e);
+        }
+        return Err(e);
+    }
+    Ok(trashed)
+}
+
+/// Trashed databases, newest first. Folders without a readable entry are left out.
+fn list_trash(trash: &Path) -> Vec<TrashedDatabase> {
+    let Ok(entries) = read_dir(trash) else {
+        return Vec::new();
+    };
+    let mut trashed: Vec<TrashedDatabase> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let contents = std::fs::read(entry.path().join(ENTRY_FILE)).ok()?;
+            let mut trashed: TrashedDatabase = serde_json::from_slice(&contents).ok()?;
+            // The folder name is what identifies the entry, even if it was renamed.
+            trashed.entry = entry.file_name().to_string_lossy().to_string();
+            Some(trashed)
+        })
+        .collect();
+    trashed.sort_by(|a, b| {
+        b.deleted_at
+            .cmp(&a.deleted_at)
+            .then_with(|| a.entry.cmp(&b.entry))
+    });
+    trashed
+}
+
+fn find_entry(trash: &Path, entry: &str) -> Result<TrashedDatabase> {
+    list_trash(trash)
+        .into_iter()
+        .find(|trashed| trashed.entry == entry)
+        .ok_or_else(|| Error::TrashEntryNotFound(entry.to_string()))
+}
+
+/// Move a trashed database back where it was deleted from, under a new name if the old one
+/// was taken since. Returns where it was restored.
+fn restore_database(trash: &Path, entry: &str, progress: &mut dyn FnMut(f64)) -> Result<PathBuf> {
+    let trashed = find_entry(trash, entry)?;
+    let dir = trash.join(&trashed.entry);
+    let original = PathBuf::from(&trashed.original_path);
+    let parent = original
+        .parent()
+        .ok_or_else(|| Error::DatabaseNotFound(trashed.original_path.clone()))?;
+    create_dir_all(parent)?;
+    let (target, renamed) = unique_path(parent, &trashed.filename);
+    if renamed {
+        info!(
+            "{} was taken, restoring to {}",
+            original.display(),
+            target.display()
+        );
+    }
+
+    move_files(
+        &database_moves(&dir.join(&trashed.filename), &target),
+        progress,
+    )?;
+    remove_dir_all(&dir)?;
+    Ok(target)
+}
+
+/// Entries of `trashed`, sorted newest first, that are older than the retention period, or
+/// that are as old as or older than the first one going over the size cap.
+fn entries_to_purge(
+    trashed: &[TrashedDatabase],
+    now: i64,
+    retention_days: i64,
+    max_size: u64,
+) -> Vec<TrashedDatabase> {
+    let mut kept_size = 0;
+    let mut full = false;
+    trashed
+        .iter()
+        .filter(|trashed| {
+            let expired = now - trashed.deleted_at > retention_days * DAY_MILLIS;
+            full = full || kept_size + trashed.size > max_size;
+            if !expired && !full {
+                kept_size += trashed.size;
+            }
+            expired || full
+        })
+        .cloned()
+        .collect()
+}
+
+/// Permanently delete trashed databases past the retention policy, with the player photos
+/// only they used.
+fn purge_trash(trash: &Path, now: i64) -> Vec<TrashedDatabase> {
+    let purged = entries_to_purge(&list_trash(trash), now, RETENTION_DAYS, MAX_TRASH_SIZE);
+    for trashed in &purged {
+        let dir = trash.join(&trashed.entry);
+        let photos = player_metadata::stored_photo_paths(&dir.join(&trashed.filename));
+        if let Err(e) = remove_dir_all(&dir) {
+            warn!("Failed to purge {} from the trash: {}", trashed.entry, e);
+            continue;
+        }
+        player_metadata::remove_orphaned_photos(Path::new(&trashed.original_path), photos);
+    }
+    purged
+}
+
+/// Purge the trash in the background, as part of the startup maintenance.
+pub fn purge_trash_on_startup(app: &AppHandle) -> Result<()> {
+    let trash = trash_dir(app)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let purged = purge_trash(&trash, chrono::Utc::now().timestamp_millis());
+        if !purged.is_empty() {
+            info!("Purged {} databases from the trash", purged.len());
+        }
+    });
+    Ok(())
+}
+
+/// Run a move of database files off the async runtime, reporting the progress of copies
+/// with `DatabaseProgress` events and as a task, both with `id`.
+async fn run_move<T: Send + 'static>(
+    app: &AppHandle,
+    id: String,
+    stage: &'static str,
+    f: impl FnOnce(&mut dyn FnMut(f64)) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let task = TaskHandle::start(app, TaskKind::Database, &id, false);
+    let app = app.clone();
+    let moved = tokio::task::spawn_blocking(move || {
+        let result = f(&mut |progress| {
+            let progress = progress * 100_f64;
+            DatabaseProgress {
+                id: id.clone(),
+                progress,
+                stage: Some(stage.to_string()),
+            }
+            .emit(&app)
+            .ok();
+            task.report(progress, Some(stage.to_string()));
+        });
+        result.map(|value| (value, task))
+    })
+    .await
+    .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+    let (value, task) = moved?;
+    task.finish();
+    Ok(value)
+}
+
+/// Move a database to the trash.
+pub(super) async fn move_to_trash(app: &AppHandle, file: PathBuf) -> Result<TrashedDatabase> {
+    let trash = trash_dir(app)?;
+    let id = file.to_string_lossy().to_string();
+    let deleted_at = chrono::Utc::now().timestamp_millis();
+    let trashed = run_move(app, id, "trash", move |progress| {
+        trash_database(&file, &trash, deleted_at, progress)
+    })
+    .await?;
+    info!(
+        "Moved {} to the trash as {}",
+        trashed.original_path, trashed.entry
+    );
+    Ok(trashed)
+}
+
+/// Databases in the trash, newest first.
+#[tauri::command]
+#[specta::specta]
+pub async fn list_trashed_databases(app: AppHandle) -> Result<Vec<TrashedDatabase>> {
+    Ok(list_trash(&trash_dir(&app)?))
+}
+
+/// Move a database out of the trash, returning the path it was restored to.
+#[tauri::command]
+#[specta::specta]
+pub async fn restore_trashed_database(
+    entry: String,
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<String> {
+    let trash = trash_dir(&app)?;
+    let id = trash.join(&entry).to_string_lossy().to_string();
+    let restored = run_move(&app, id, "restore", move |progress| {
+        restore_database(&trash, &entry, progress)
+    })
+    .await?;
+    state.db_watcher.touch(&restored);
+    Ok(restored.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn trashed_databases_are_restored_with_their_journals() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_dir = dir.path().join("db");
+        let trash = dir.path().join("trash");
+        create_dir_all(&db_dir).unwrap();
+        let file = db_dir.join("games.db3");
+        write(&file, "database");
+        write(&db_dir.join("games.db3-wal"), "wal");
+
+        let trashed = trash_database(&file, &trash, 1_000, &mut |_| {}).unwrap();
+        assert_eq!(trashed.entry, "1000-games.db3");
+        assert_eq!(trashed.size, 11);
+        assert!(!file.exists());
+        assert!(!db_dir.join("games.db3-wal").exists());
+        assert_eq!(list_trash(&trash), vec![trashed.clone()]);
+
+        // A new database took the name in the meantime.
+        write(&file, "new");
+        let restored = restore_database(&trash, &trashed.entry, &mut |_| {}).unwrap();
+        assert_eq!(restored, db_dir.join("games (1).db3"));
+        assert_eq!(std::fs::read_to_string(&restored).unwrap(), "database");
+        assert_eq!(
+            std::fs::read_to_string(db_dir.join("games (1).db3-wal")).unwrap(),
+            "wal"
+        );
+        assert!(list_trash(&trash).is_empty());
+        assert!(matches!(
+            restore_database(&trash, &trashed.entry, &mut |_| {}),
+            Err(Error::TrashEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn purging_drops_old_entries_then_the_oldest_over_the_cap() {
+        let entry = |name: &str, days_ago: i64, size: u64| TrashedDatabase {
+            entry: name.to_string(),
+            original_path: format!("/db/{name}"),
+            filename: name.to_string(),
+            deleted_at: 100 * DAY_MILLIS - days_ago * DAY_MILLIS,
+            size,
+        };
+        let trashed = vec![
+            entry("new", 1, 60),
+            entry("big", 2, 50),
+            entry("small", 3, 30),
+            entry("old", 40, 1),
+        ];
+        let purged: Vec<String> = entries_to_purge(&trashed, 100 * DAY_MILLIS, 30, 100)
+            .into_iter()
+            .map(|trashed| trashed.entry)
+            .collect();
+        assert_eq!(purged, vec!["big", "small", "old"]);
+    }
+}
@@ -0,0 +1,361 @@
+//! Flat variation trees for navigation.
+//!
+//! The frontend used to rebuild the variation tree from PGN on every render. Instead, a game
+//! is decoded once with `GameTree`, the same representation `patch_game` and the PGN writer
+//! use, and flattened into an array of nodes linked by index: each node knows its parent and
+//! its children, the main line continuation first, so moving through the tree with the arrow
+//! keys is a lookup. Comments are only flagged there; `get_node_details` returns them for one
+//! node at a time.
+//!
+//! Node 0 is the starting position and holds the comments in front of the game. Comments
+//! in front of the first move of a variation belong to that move.
+
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use pgn_reader::{BufferedReader, Nag};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, CastlingMode, Chess, EnPassantMode, Position};
+use specta::Type;
+
+use crate::{
+    db::{
+        get_db_or_create, get_start_position,
+        pgn::{GameTree, GameTreeNode, Importer},
+        schema::games,
+        ConnectionOptions,
+    },
+    error::{Error, Result},
+    AppState,
+};
+
+/// Where to read a game from.
+#[derive(Deserialize, Debug, Clone, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameSource {
+    Database {
+        file: PathBuf,
+        id: i32,
+    },
+    /// The first game of a PGN text.
+    Pgn {
+        pgn: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeNode {
+    pub id: u32,
+    /// `None` for the starting position.
+    pub parent: Option<u32>,
+    /// Moves played since the starting position.
+    pub ply: u32,
+    /// Move leading to the node, empty for the starting position.
+    pub san: String,
+    pub uci: String,
+    pub nags: Vec<u8>,
+    pub has_comment: bool,
+    /// The main line continuation first, then the variations in order.
+    pub children: Vec<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct FlatGameTree {
+    pub start_fen: String,
+    /// Indexed by node ID.
+    pub nodes: Vec<TreeNode>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeDetails {
+    pub id: u32,
+    /// Position after the move.
+    pub fen: String,
+    pub comments: Vec<String>,
+    pub nags: Vec<u8>,
+}
+
+/// A flattened game along with what the nodes don't carry.
+struct Flattened {
+    tree: FlatGameTree,
+    positions: Vec<Chess>,
+    comments: Vec<Vec<String>>,
+}
+
+impl Flattened {
+    fn new(start: Chess) -> Self {
+        Self {
+            tree: FlatGameTree {
+                start_fen: Fen::from_position(start.clone(), EnPassantMode::Legal).to_string(),
+                nodes: vec![TreeNode::default()],
+            },
+            positions: vec![start],
+            comments: vec![Vec::new()],
+        }
+    }
+
+    /// Add the moves of `tree`, the first one played from `node`.
+    fn add_line(&mut self, tree: &GameTree, node: u32, is_variation: bool) -> Result<()> {
+        let mut current = node;
+        // Comments in front of the first move of a variation go to that move.
+        let mut pending = Vec::new();
+        for item in tree.nodes() {
+            match item {
+                GameTreeNode::Move(san) => {
+                    let parent = current as usize;
+                    let mut position = self.positions[parent].clone();
+                    let m = san.san.to_move(&position)?;
+                    let uci = m.to_uci(CastlingMode::Standard).to_string();
+                    position.play_unchecked(&m);
+
+                    let id = self.tree.nodes.len() as u32;
+                    let ply = self.tree.nodes[parent].ply + 1;
+                    self.tree.nodes[parent].children.push(id);
+                    self.tree.nodes.push(TreeNode {
+                        id,
+                        parent: Some(current),
+                        ply,
+                        san: san.to_string(),
+                        uci,
+                        has_comment: !pending.is_empty(),
+                        ..Default::default()
+                    });
+                    self.positions.push(position);
+                    self.comments.push(std::mem::take(&mut pending));
+                    current = id;
+                }
+                GameTreeNode::Comment(comment) if is_variation && current == node => {
+                    pending.push(comment.clone());
+                }
+                GameTreeNode::Comment(comment) => {
+                    self.comments[current as usize].push(comment.clone());
+                    self.tree.nodes[current as usize].has_comment = true;
+                }
+                GameTreeNode::Nag(Nag(nag)) => self.tree.nodes[current as usize].nags.push(*nag),
+                GameTreeNode::Variation(branch) => {
+                    // An alternative to the last move, played from the same position.
+                    let parent = self.tree.nodes[current as usize].parent.unwrap_or(0);
+                    self.add_line(branch, parent, true)?;
+                }
+            }
+        }
+        if !pending.is_empty() {
+            // A variation made only of comments, kept where it branches off.
+            self.comments[node as usize].extend(pending);
+            self.tree.nodes[node as usize].has_comment = true;
+        }
+        Ok(())
+    }
+
+    fn details(mut self, id: u32) -> Result<NodeDetails> {
+        let node = self
+            .tree
+            .nodes
+            .get(id as usize)
+            .ok_or(Error::NodeNotFound(id))?;
+        Ok(NodeDetails {
+            id,
+            fen: Fen::from_position(self.positions[id as usize].clone(), EnPassantMode::Legal)
+                .to_string(),
+            nags: node.nags.clone(),
+            comments: std::mem::take(&mut self.comments[id as usize]),
+        })
+    }
+}
+
+fn flatten(tree: &GameTree, start: Chess) -> Result<Flattened> {
+    let mut flattened = Flattened::new(start);
+    flattened.add_line(tree, 0, false)?;
+    Ok(flattened)
+}
+
+/// Decode a game and its starting position.
+pub(super) fn load_game(
+    source: GameSource,
+    state: &tauri::State<'_, AppState>,
+) -> Result<(GameTree, Chess)> {
+    match source {
+        GameSource::Database { file, id } => {
+            let db =
+                &mut get_db_or_create(state, file.to_str().unwrap(), ConnectionOptions::default())?;
+            let (moves, fen): (Vec<u8>, Option<String>) = games::table
+                .filter(games::id.eq(id))
+                .select((games::moves, games::fen))
+                .first(db)?;
+            // Games without a FEN of their own start from the database's start position.
+            let start = match fen {
+                Some(fen) => {
+                    Fen::from_ascii(fen.as_bytes())?.into_position(CastlingMode::Chess960)?
+                }
+                None => get_start_position(db)?,
+            };
+            Ok((GameTree::from_bytes(&moves, Some(start.clone()))?, start))
+        }
+        GameSource::Pgn { pgn } => {
+            let mut importer = Importer::new(None);
+            let game = BufferedReader::new_cursor(&pgn)
+                .into_iter(&mut importer)
+                .flatten()
+                .flatten()
+                .next()
+                .ok_or(Error::NoGameInPgn)?;
+            Ok((game.tree, game.position))
+        }
+    }
+}
+
+/// Decode a game into a flat tree of its moves and variations.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_game_tree(
+    game: GameSource,
+    state: tauri::State<'_, AppState>,
+) -> Result<FlatGameTree> {
+    let (tree, start) = load_game(game, &state)?;
+    Ok(flatten(&tree, start)?.tree)
+}
+
+/// Comments, NAGs and position of a node of the tree returned by `get_game_tree`.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_node_details(
+    game: GameSource,
+    node_id: u32,
+    state: tauri::State<'_, AppState>,
+) -> Result<NodeDetails> {
+    let (tree, start) = load_game(game, &state)?;
+    flatten(&tree, start)?.details(node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(pgn: &str) -> Flattened {
+        let mut importer = Importer::new(None);
+        let game = BufferedReader::new_cursor(pgn)
+            .into_iter(&mut importer)
+            .flatten()
+            .flatten()
+            .next()
+            .unwrap();
+        // Decoded from the stored blob, as the commands do.
+        let tree = GameTree::from_bytes(&game.moves, Some(game.position.clone())).unwrap();
+        flatten(&tree, game.position).unwrap()
+    }
+
+    fn san_of(tree: &FlatGameTree, ids: &[u32]) -> Vec<String> {
+        ids.iter()
+            .map(|&id| tree.nodes[id as usize].san.clone())
+            .collect()
+    }
+
+    /// Follows the first child from the root.
+    fn main_line(tree: &FlatGameTree) -> Vec<u32> {
+        let mut line = Vec::new();
+        let mut node = &tree.nodes[0];
+        while let Some(&child) = node.children.first() {
+            line.push(child);
+            node = &tree.nodes[child as usize];
+        }
+        line
+    }
+
+    const NESTED: &str = "{Game comment} 1. e4 $1 {Best by test} e5 \
+        (1... c5 2. Nf3 (2. c3 d5 (2... Nf6 3. e5 {Alapin}) 3. exd5) 2... d6) \
+        (1... {French} e6 2. d4) 2. Nf3 Nc6 (2... d6 3. d4 (3. Bc4)) 3. Bb5 *";
+
+    #[test]
+    fn links_are_consistent() {
+        let flattened = flat(NESTED);
+        let tree = &flattened.tree;
+        assert_eq!(tree.nodes.len(), 19);
+        assert_eq!(tree.nodes[0].parent, None);
+        for (i, node) in tree.nodes.iter().enumerate() {
+            assert_eq!(node.id, i as u32);
+            for &child in &node.children {
+                let child = &tree.nodes[child as usize];
+                assert_eq!(child.parent, Some(node.id));
+                assert_eq!(child.ply, node.ply + 1);
+            }
+            if let Some(parent) = node.parent {
+                assert_eq!(
+                    tree.nodes[parent as usize]
+                        .children
+                        .iter()
+                        .filter(|&&c| c == node.id)
+                        .count(),
+                    1
+                );
+            }
+        }
+        assert_eq!(flattened.positions.len(), tree.nodes.len());
+    }
+
+    #[test]
+    fn main_line_comes_first() {
+        let tree = flat(NESTED).tree;
+        assert_eq!(
+            san_of(&tree, &main_line(&tree)),
+            vec!["e4", "e5", "Nf3", "Nc6", "Bb5"]
+        );
+
+        let e4 = &tree.nodes[main_line(&tree)[0] as usize];
+        assert_eq!(san_of(&tree, &e4.children), vec!["e5", "c5", "e6"]);
+        assert_eq!(e4.uci, "e2e4");
+        assert_eq!(e4.nags, vec![1]);
+
+        let c5 = e4.children[1] as usize;
+        assert_eq!(san_of(&tree, &tree.nodes[c5].children), vec!["Nf3", "c3"]);
+        let c3 = tree.nodes[c5].children[1] as usize;
+        assert_eq!(san_of(&tree, &tree.nodes[c3].children), vec!["d5", "Nf6"]);
+        let nf6 = tree.nodes[c3].children[1] as usize;
+        assert_eq!(tree.nodes[nf6].ply, 4);
+        assert_eq!(san_of(&tree, &tree.nodes[nf6].children), vec!["e5"]);
+
+        let nf3 = main_line(&tree)[2] as usize;
+        assert_eq!(san_of(&tree, &tree.nodes[nf3].children), vec!["Nc6", "d6"]);
+    }
+
+    #[test]
+    fn comments_are_fetched_per_node() {
+        let flattened = flat(NESTED);
+        let tree = &flattened.tree;
+        let e4 = main_line(tree)[0];
+        let e6 = tree.nodes[e4 as usize].children[2];
+        assert!(tree.nodes[0].has_comment);
+        assert!(tree.nodes[e4 as usize].has_comment);
+        assert!(tree.nodes[e6 as usize].has_comment);
+        assert!(!tree.nodes[main_line(tree)[1] as usize].has_comment);
+
+        let details = flat(NESTED).details(0).unwrap();
+        assert_eq!(details.comments, vec!["Game comment"]);
+        assert_eq!(details.fen, tree.start_fen);
+
+        let details = flat(NESTED).details(e4).unwrap();
+        assert_eq!(details.comments, vec!["Best by test"]);
+        assert_eq!(
+            details.fen,
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+        );
+        assert_eq!(flat(NESTED).details(e6).unwrap().comments, vec!["French"]);
+        assert!(matches!(
+            flat(NESTED).details(100),
+            Err(Error::NodeNotFound(100))
+        ));
+    }
+
+    #[test]
+    fn games_from_a_position_keep_it() {
+        let tree = flat(
+            "[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 w - - 0 1\"]\n\n1. e4 Kd7 (1... Ke7) *",
+        )
+        .tree;
+        assert_eq!(tree.start_fen, "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1");
+        assert_eq!(san_of(&tree, &tree.nodes[1].children), vec!["Kd7", "Ke7"]);
+        assert_eq!(tree.nodes[3].uci, "e8e7");
+    }
+}
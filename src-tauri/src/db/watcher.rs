@@ -0,0 +1,387 @@
+//! Database summaries for the sidebar.
+//!
+//! The databases in the app's `db` directory are summarized with what's cheap to read:
+//! the game count stored in their `Info` table, their size and when they were last
+//! modified. Summaries are sent with `DatabaseInfoChanged` when the app starts, after
+//! commands changing a database, and when a file changes on disk.
+//!
+//! Files are only looked at with `stat` every few seconds and read through short-lived
+//! read-only connections, so the watcher never keeps a database open and doesn't get in
+//! the way of deleting it on Windows. A file that changes while the app wasn't using it
+//! was modified by something else, and its summary is flagged so the frontend can offer
+//! to reload it.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use diesel::{insert_into, prelude::*};
+use log::{info, warn};
+use serde::Serialize;
+use specta::Type;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+use tauri_specta::Event as _;
+
+use crate::{
+    db::schema::{games, info},
+    error::{Error, Result},
+    AppState,
+};
+
+/// Time between two looks at the database files.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changes this soon after the app used a database are taken as its own.
+const OWN_CHANGE_GRACE: Duration = Duration::from_secs(5);
+
+const DB_EXTENSION: &str = "db3";
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+pub struct DatabaseSummary {
+    pub file: String,
+    pub filename: String,
+    pub title: String,
+    pub game_count: i64,
+    pub storage_size: i64,
+    /// Unix timestamp in milliseconds of the last modification.
+    pub modified: i64,
+    /// Whether the file was last changed by something other than the app.
+    pub externally_modified: bool,
+}
+
+/// Summaries of databases that changed, and paths of databases that are gone.
+#[derive(Serialize, Debug, Clone, Type, tauri_specta::Event)]
+pub struct DatabaseInfoChanged {
+    pub databases: Vec<DatabaseSummary>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: SystemTime,
+    size: u64,
+}
+
+impl FileStamp {
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = path.metadata()?;
+        Ok(Self {
+            modified: metadata.modified()?,
+            size: metadata.len(),
+        })
+    }
+
+    fn modified_millis(&self) -> i64 {
+        self.modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    }
+}
+
+struct Watched {
+    stamp: FileStamp,
+    summary: DatabaseSummary,
+    /// Whether the app was seen using the database since it was last summarized.
+    used_by_app: bool,
+}
+
+/// Databases of the app's `db` directory, keyed by path.
+#[derive(Default)]
+pub struct DatabaseWatcher {
+    app: OnceLock<AppHandle>,
+    dir: OnceLock<PathBuf>,
+    watched: DashMap<String, Watched>,
+    /// When the app last got a connection to each database.
+    last_used: DashMap<String, Instant>,
+}
+
+impl DatabaseWatcher {
+    /// Summarize the databases and keep watching them. Does nothing if already started.
+    pub fn start(&self, app: &AppHandle) -> Result<()> {
+        let dir = app.path().resolve("db", BaseDirectory::AppData)?;
+        if self.app.set(app.clone()).is_err() {
+            return Ok(());
+        }
+        let _ = self.dir.set(dir);
+        info!("Watching databases");
+
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            loop {
+                let state = app.state::<AppState>();
+                state.db_watcher.poll(&state);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Current summaries, sorted by file.
+    pub fn summaries(&self) -> Vec<DatabaseSummary> {
+        let mut summaries: Vec<DatabaseSummary> =
+            self.watched.iter().map(|w| w.summary.clone()).collect();
+        summaries.sort_by(|a, b| a.file.cmp(&b.file));
+        summaries
+    }
+
+    /// Record that the app is using `file`, so its next change isn't taken as external.
+    pub fn note_use(&self, file: &str) {
+        self.last_used.insert(file.to_string(), Instant::now());
+    }
+
+    /// Summarize `file` again after the app changed it.
+    pub fn touch(&self, file: &Path) {
+        let key = file.to_string_lossy().to_string();
+        self.note_use(&key);
+        if !self.is_watched_file(file) {
+            return;
+        }
+        let summary = FileStamp::read(file)
+            .map_err(Error::from)
+            .and_then(|stamp| summarize(file, stamp, false).map(|summary| (stamp, summary)));
+        match summary {
+            Ok((stamp, summary)) => {
+                self.watched.insert(
+                    key,
+                    Watched {
+                        stamp,
+                        summary: summary.clone(),
+                        used_by_app: false,
+                    },
+                );
+                self.send(vec![summary], Vec::new());
+            }
+            Err(e) => warn!("Failed to summarize {}: {}", file.display(), e),
+        }
+    }
+
+    /// Stop watching a deleted database.
+    pub fn forget(&self, file: &Path) {
+        let key = file.to_string_lossy().to_string();
+        self.last_used.remove(&key);
+        if self.watched.remove(&key).is_some() {
+            self.send(Vec::new(), vec![key]);
+        }
+    }
+
+    fn is_watched_file(&self, file: &Path) -> bool {
+        self.dir
+            .get()
+            .is_some_and(|dir| file.parent() == Some(dir.as_path()))
+            && file.extension() == Some(DB_EXTENSION.as_ref())
+    }
+
+    /// Whether the app is writing `file` or used it recently.
+    fn used_by_app(&self, state: &AppState, file: &str) -> bool {
+        let busy = state.connection_pool.get(file).is_some_and(|pool| {
+            let pool_state = pool.state();
+            pool_state.connections > pool_state.idle_connections
+        });
+        let locked = state
+            .db_write_locks
+            .get(file)
+            .is_some_and(|lock| lock.try_lock().is_err());
+        let recent = self
+            .last_used
+            .get(file)
+            .is_some_and(|used| used.elapsed() < OWN_CHANGE_GRACE);
+        busy || locked || recent
+    }
+
+    /// Summarize the databases that appeared or changed since the last poll.
+    fn poll(&self, state: &AppState) {
+        let Some(dir) = self.dir.get() else {
+            return;
+        };
+        let files: Vec<PathBuf> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| self.is_watched_file(path))
+                .collect(),
+            Err(e) => {
+                warn!("Failed to list databases: {}", e);
+                return;
+            }
+        };
+
+        let mut changed = Vec::new();
+        for path in &files {
+            let key = path.to_string_lossy().to_string();
+            let Ok(stamp) = FileStamp::read(path) else {
+                continue;
+            };
+            let used_by_app = self.used_by_app(state, &key);
+            let used_since = match self.watched.get_mut(&key) {
+                Some(mut watched) if watched.stamp == stamp => {
+                    watched.used_by_app = used_by_app;
+                    continue;
+                }
+                Some(mut watched) => {
+                    watched.used_by_app |= used_by_app;
+                    Some(watched.used_by_app)
+                }
+                None => None,
+            };
+            // Wait for the app to be done before reading what it wrote.
+            if used_by_app {
+                continue;
+            }
+            // New files aren't flagged, there's nothing to reload.
+            let externally_modified = used_since == Some(false);
+            match summarize(path, stamp, externally_modified) {
+                Ok(summary) => {
+                    self.watched.insert(
+                        key,
+                        Watched {
+                            stamp,
+                            summary: summary.clone(),
+                            used_by_app: false,
+                        },
+                    );
+                    changed.push(summary);
+                }
+                Err(e) => warn!("Failed to summarize {}: {}", path.display(), e),
+            }
+        }
+
+        let removed: Vec<String> = self
+            .watched
+            .iter()
+            .map(|watched| watched.key().clone())
+            .filter(|key| !files.iter().any(|path| path.to_string_lossy() == **key))
+            .collect();
+        for key in &removed {
+            self.watched.remove(key);
+        }
+
+        self.send(changed, removed);
+    }
+
+    fn send(&self, databases: Vec<DatabaseSummary>, removed: Vec<String>) {
+        if databases.is_empty() && removed.is_empty() {
+            return;
+        }
+        let Some(app) = self.app.get() else {
+            return;
+        };
+        if let Err(e) = (DatabaseInfoChanged { databases, removed }).emit(app) {
+            warn!("Failed to send database summaries: {}", e);
+        }
+    }
+}
+
+/// URI opening `path` read-only, without creating it if it's gone.
+pub(super) fn read_only_uri(path: &Path) -> String {
+    let path = path
+        .to_string_lossy()
+        .replace('\\', "/")
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    format!("file:{}?mode=ro", path)
+}
+
+fn info_value(db: &mut SqliteConnection, name: &str) -> Option<String> {
+    info::table
+        .filter(info::name.eq(name))
+        .select(info::value)
+        .first::<Option<String>>(db)
+        .ok()
+        .flatten()
+}
+
+fn summarize(path: &Path, stamp: FileStamp, externally_modified: bool) -> Result<DatabaseSummary> {
+    // The connection is dropped before returning, releasing the file.
+    let db = &mut SqliteConnection::establish(&read_only_uri(path))?;
+    let game_count = match info_value(db, "GameCount").and_then(|count| count.parse().ok()) {
+        Some(count) => count,
+        None => games::table.count().get_result(db)?,
+    };
+    Ok(DatabaseSummary {
+        file: path.to_string_lossy().to_string(),
+        filename: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        title: info_value(db, "Title").unwrap_or_else(|| "Untitled".to_string()),
+        game_count,
+        storage_size: stamp.size as i64,
+        modified: stamp.modified_millis(),
+        externally_modified,
+    })
+}
+
+/// Store the number of games, read by summaries, after games were added or removed.
+pub(crate) fn record_game_count(db: &mut SqliteConnection) -> Result<()> {
+    let game_count: i64 = games::table.count().get_result(db)?;
+    insert_into(info::table)
+        .values((
+            info::name.eq("GameCount"),
+            info::value.eq(game_count.to_string()),
+        ))
+        .on_conflict(info::name)
+        .do_update()
+        .set(info::value.eq(game_count.to_string()))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Start watching the databases if needed and return their current summaries.
+/// Changes are then sent with `DatabaseInfoChanged`.
+#[tauri::command]
+#[specta::specta]
+pub async fn watch_databases(
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<DatabaseSummary>> {
+    state.db_watcher.start(&app)?;
+    Ok(state.db_watcher.summaries())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::core::init_db;
+    use diesel::connection::SimpleConnection;
+
+    #[test]
+    fn summaries_prefer_the_stored_game_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("my games #1.db3");
+        let db = &mut SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        init_db(db, "My games", "").unwrap();
+        db.batch_execute("DELETE FROM Info WHERE Name = 'GameCount'")
+            .unwrap();
+
+        let stamp = FileStamp::read(&path).unwrap();
+        let summary = summarize(&path, stamp, false).unwrap();
+        assert_eq!(summary.title, "My games");
+        assert_eq!(summary.filename, "my games #1.db3");
+        assert_eq!(summary.game_count, 0);
+        assert_eq!(summary.storage_size, stamp.size as i64);
+
+        // A stale stored count is what's shown until the app records it again.
+        db.batch_execute("INSERT INTO Info (Name, Value) VALUES ('GameCount', '12')")
+            .unwrap();
+        assert_eq!(summarize(&path, stamp, true).unwrap().game_count, 12);
+        record_game_count(db).unwrap();
+        assert_eq!(summarize(&path, stamp, true).unwrap().game_count, 0);
+    }
+
+    #[test]
+    fn summaries_never_create_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gone.db3");
+        let stamp = FileStamp {
+            modified: UNIX_EPOCH,
+            size: 0,
+        };
+        assert!(summarize(&path, stamp, false).is_err());
+        assert!(!path.exists());
+    }
+}
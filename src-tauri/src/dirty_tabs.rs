@@ -0,0 +1,264 @@
+//! Unsaved changes in tabs.
+//!
+//! Games opened from a PGN are imported into a temporary file, and the frontend keeps
+//! writing a tab's game there until the user saves it somewhere. Commands writing games
+//! take the id of the tab they write for: a write to a temporary import marks the tab as
+//! dirty, a write anywhere else marks it as saved. The frontend can also flag a tab itself
+//! with `mark_tab_dirty`, e.g. for edits it hasn't written anywhere yet.
+//!
+//! Quitting while tabs are dirty emits `ConfirmExit` instead of exiting, so the user can
+//! save them first. `force_exit` quits anyway.
+
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::Manager;
+use tauri_specta::Event as _;
+
+use crate::{error::Error, AppState};
+
+/// Prefix of the temporary files PGN imports are opened from.
+const TEMP_IMPORT_PREFIX: &str = "temp_import_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum DirtyReason {
+    /// The game only exists in a temporary import file.
+    UnsavedImport,
+    /// The game has edits that weren't written anywhere.
+    UnsavedEdits,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+pub struct DirtyTab {
+    pub tab: String,
+    pub reason: DirtyReason,
+    /// File the tab's game was last written to, if any.
+    pub file: Option<String>,
+    /// Unix timestamp in milliseconds of when the tab became dirty.
+    pub since: i64,
+}
+
+/// Sent instead of exiting when tabs have unsaved changes.
+#[derive(Serialize, Debug, Clone, Type, tauri_specta::Event)]
+pub struct ConfirmExit {
+    pub tabs: Vec<DirtyTab>,
+}
+
+/// Whether `file` is a temporary import, whose games are lost on exit.
+pub fn is_temp_import(file: &Path) -> bool {
+    file.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(TEMP_IMPORT_PREFIX))
+}
+
+/// Tabs with unsaved changes, keyed by tab id.
+#[derive(Default)]
+pub struct DirtyTabs {
+    tabs: DashMap<String, DirtyTab>,
+    force_exit: AtomicBool,
+}
+
+impl DirtyTabs {
+    /// Mark a tab as dirty, keeping the time it first became dirty.
+    pub fn mark_dirty(&self, tab: &str, reason: DirtyReason, file: Option<&Path>) {
+        let file = file.map(|file| file.to_string_lossy().to_string());
+        self.tabs
+            .entry(tab.to_string())
+            .and_modify(|dirty| {
+                dirty.reason = reason;
+                if file.is_some() {
+                    dirty.file = file.clone();
+                }
+            })
+            .or_insert_with(|| DirtyTab {
+                tab: tab.to_string(),
+                reason,
+                file: file.clone(),
+                since: chrono::Utc::now().timestamp_millis(),
+            });
+    }
+
+    /// Mark a tab as saved, returning whether it was dirty.
+    pub fn mark_clean(&self, tab: &str) -> bool {
+        self.tabs.remove(tab).is_some()
+    }
+
+    /// Move the unsaved changes of tab `old` to tab `new`.
+    pub fn rename(&self, old: &str, new: &str) {
+        if let Some((_, mut dirty)) = self.tabs.remove(old) {
+            dirty.tab = new.to_string();
+            self.tabs.insert(new.to_string(), dirty);
+        }
+    }
+
+    /// Record that a tab's game was written to `file`.
+    pub fn record_write(&self, tab: Option<&str>, file: &Path) {
+        let Some(tab) = tab else {
+            return;
+        };
+        if is_temp_import(file) {
+            self.mark_dirty(tab, DirtyReason::UnsavedImport, Some(file));
+        } else {
+            self.mark_clean(tab);
+        }
+    }
+
+    /// Dirty tabs, oldest first.
+    pub fn list(&self) -> Vec<DirtyTab> {
+        let mut tabs: Vec<DirtyTab> = self.tabs.iter().map(|e| e.value().clone()).collect();
+        tabs.sort_by(|a, b| a.since.cmp(&b.since).then_with(|| a.tab.cmp(&b.tab)));
+        tabs
+    }
+
+    /// Tabs keeping the app from exiting, unless the user chose to exit anyway.
+    pub fn blocking_exit(&self) -> Option<Vec<DirtyTab>> {
+        if self.force_exit.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(self.list()).filter(|tabs| !tabs.is_empty())
+    }
+
+    /// Let the app exit regardless of dirty tabs.
+    pub fn allow_exit(&self) {
+        self.force_exit.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Handle a request to quit, returning whether it must be prevented.
+pub fn intercept_exit(app: &tauri::AppHandle) -> bool {
+    match app.state::<AppState>().dirty_tabs.blocking_exit() {
+        Some(tabs) => {
+            log::info!("Exit postponed: {} tab(s) with unsaved changes", tabs.len());
+            let _ = ConfirmExit { tabs }.emit(app);
+            true
+        }
+        None => false,
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn mark_tab_dirty(
+    tab: String,
+    reason: DirtyReason,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    state.dirty_tabs.mark_dirty(&tab, reason, None);
+    Ok(())
+}
+
+/// Mark a tab as saved, e.g. once it's closed or its changes were discarded.
+#[tauri::command]
+#[specta::specta]
+pub async fn mark_tab_clean(tab: String, state: tauri::State<'_, AppState>) -> Result<bool, Error> {
+    Ok(state.dirty_tabs.mark_clean(&tab))
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_dirty_tabs(state: tauri::State<'_, AppState>) -> Result<Vec<DirtyTab>, Error> {
+    Ok(state.dirty_tabs.list())
+}
+
+/// Quit, discarding unsaved changes.
+#[tauri::command]
+#[specta::specta]
+pub async fn force_exit(app: tauri::AppHandle) -> Result<(), Error> {
+    app.state::<AppState>().dirty_tabs.allow_exit();
+    app.exit(0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_import() -> PathBuf {
+        std::env::temp_dir()
+            .join("pawn-appetit")
+            .join("temp_import_1718000000000.pgn")
+    }
+
+    #[test]
+    fn only_temp_imports_are_unsaved() {
+        assert!(is_temp_import(&temp_import()));
+        assert!(!is_temp_import(Path::new("/home/user/games/temp.pgn")));
+        assert!(!is_temp_import(Path::new(
+            "/home/user/temp_import_/game.pgn"
+        )));
+    }
+
+    #[test]
+    fn editing_an_import_blocks_exit_until_saved() {
+        let dirty = DirtyTabs::default();
+        assert_eq!(dirty.blocking_exit(), None);
+
+        // Autosave of an edited game to its temporary import.
+        dirty.record_write(Some("tab-1"), &temp_import());
+        // Writes not attributed to a tab don't count.
+        dirty.record_write(None, &temp_import());
+        let tabs = dirty.blocking_exit().unwrap();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0].tab, "tab-1");
+        assert_eq!(tabs[0].reason, DirtyReason::UnsavedImport);
+        assert_eq!(
+            tabs[0].file.as_deref(),
+            Some(temp_import().to_string_lossy().as_ref())
+        );
+
+        // Saving it to a real file.
+        dirty.record_write(Some("tab-1"), Path::new("/home/user/games/saved.pgn"));
+        assert_eq!(dirty.blocking_exit(), None);
+        assert!(dirty.list().is_empty());
+    }
+
+    #[test]
+    fn marking_again_keeps_the_original_time() {
+        let dirty = DirtyTabs::default();
+        dirty.record_write(Some("tab-1"), &temp_import());
+        let since = dirty.list()[0].since;
+
+        dirty.mark_dirty("tab-1", DirtyReason::UnsavedEdits, None);
+        let tabs = dirty.list();
+        assert_eq!(tabs[0].since, since);
+        assert_eq!(tabs[0].reason, DirtyReason::UnsavedEdits);
+        assert!(tabs[0].file.is_some());
+
+        assert!(dirty.mark_clean("tab-1"));
+        assert!(!dirty.mark_clean("tab-1"));
+    }
+
+    #[test]
+    fn renamed_tabs_stay_dirty() {
+        let dirty = DirtyTabs::default();
+        dirty.record_write(Some("tab-1"), &temp_import());
+        let since = dirty.list()[0].since;
+
+        dirty.rename("tab-1", "tab-2");
+        let tabs = dirty.list();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0].tab, "tab-2");
+        assert_eq!(tabs[0].since, since);
+        assert!(!dirty.mark_clean("tab-1"));
+        assert!(dirty.mark_clean("tab-2"));
+    }
+
+    #[test]
+    fn forcing_exit_ignores_dirty_tabs() {
+        let dirty = DirtyTabs::default();
+        dirty.mark_dirty("tab-1", DirtyReason::UnsavedEdits, None);
+        dirty.mark_dirty("tab-2", DirtyReason::UnsavedEdits, None);
+        assert_eq!(dirty.blocking_exit().map(|tabs| tabs.len()), Some(2));
+
+        dirty.allow_exit();
+        assert_eq!(dirty.blocking_exit(), None);
+        assert_eq!(dirty.list().len(), 2);
+    }
+}
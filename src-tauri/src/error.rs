@@ -75,6 +75,9 @@ pub enum Error {
     #[error(transparent)]
     FormatError(#[from] std::fmt::Error),
 
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
     #[error("No stdin")]
     NoStdin,
 
@@ -87,6 +90,9 @@ pub enum Error {
     #[error("Search stopped")]
     SearchStopped,
 
+    #[error("Task cancelled")]
+    TaskCancelled,
+
     #[error("Missing reference database")]
     MissingReferenceDatabase,
 
@@ -102,8 +108,146 @@ pub enum Error {
     #[error("Cannot merge players: they are distinct players who have played against each other")]
     NotDistinctPlayers,
 
-    #[error("Invalid binary data")]
-    InvalidBinaryData,
+    #[error(transparent)]
+    Decode(#[from] crate::db::DecodeError),
+
+    #[error("Not enough disk space: {needed} bytes needed, {available} available")]
+    InsufficientDiskSpace { needed: u64, available: u64 },
+
+    #[error("Invalid game patch: {0}")]
+    InvalidPatch(String),
+
+    #[error("The game was modified elsewhere (current revision {0})")]
+    GameConflict(String),
+
+    #[error("The database metadata was modified elsewhere (current version {0})")]
+    MetadataConflict(u32),
+
+    #[error("Invalid database metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Access to {0} was not authorized")]
+    PathNotAuthorized(String),
+
+    #[error("Games can't be copied into the database they come from")]
+    SameDatabase,
+
+    #[error("Database not found: {0}")]
+    DatabaseNotFound(String),
+
+    #[error("A database already exists at {0}")]
+    DatabaseExists(String),
+
+    #[error("Unknown index: {0}")]
+    UnknownIndex(String),
+
+    #[error("Invalid database path: {0}")]
+    InvalidDatabasePath(String),
+
+    #[error("No database {0} in the trash")]
+    TrashEntryNotFound(String),
+
+    #[error("No game found in the PGN")]
+    NoGameInPgn,
+
+    #[error("No node {0} in the game tree")]
+    NodeNotFound(u32),
+
+    #[error("Variation path of {0} plies for {1} moves")]
+    VariationPathMismatch(usize, usize),
+
+    #[error("{san} at ply {ply} has no legal counterpart once transformed")]
+    IllegalTransform { ply: u32, san: String },
+
+    #[error("Too many positions to evaluate: {0}, at most {1}")]
+    BatchTooLarge(usize, usize),
+
+    #[error("Player identity not found: {0}")]
+    IdentityNotFound(String),
+
+    #[error("Invalid board shape: {0}")]
+    InvalidShape(String),
+
+    #[error("Invalid game link: {0}")]
+    InvalidGameLink(String),
+
+    #[error("Invalid conditional moves: {0}")]
+    InvalidConditional(String),
+
+    #[error("Play session not found: {0}")]
+    PlaySessionNotFound(String),
+
+    #[error("It is not the player's turn")]
+    NotPlayerTurn,
+
+    #[error("Line drill not found: {0}")]
+    DrillNotFound(String),
+
+    #[error("Blindfold session not found: {0}")]
+    BlindfoldSessionNotFound(String),
+
+    #[error("{0} is already being recorded")]
+    RecordingInProgress(String),
+
+    #[error("{0} isn't being recorded")]
+    RecordingNotFound(String),
+
+    #[error("Invalid UCI recording: {0}")]
+    InvalidRecording(String),
+
+    #[error("Pairing event not found: {0}")]
+    PairingEventNotFound(i32),
+
+    #[error("Cannot pair: {0}")]
+    InvalidPairing(String),
+
+    #[error("Invalid odds: {0}")]
+    InvalidOdds(String),
+
+    #[error("Query result not found or expired: {0}")]
+    ResultHandleNotFound(String),
+
+    #[error("A consensus analysis takes at most {0} engines")]
+    TooManyConsensusEngines(usize),
+
+    #[error("Tab {0} already exists")]
+    TabInUse(String),
+
+    #[error("Invalid vision drill: {0}")]
+    InvalidVisionDrill(String),
+
+    #[error("Invalid time control: {0}")]
+    InvalidTimeControl(String),
+
+    #[error("Time control preset not found: {0}")]
+    TimeControlPresetNotFound(String),
+
+    #[error("Time is up")]
+    TimeForfeit,
+
+    #[error("Not in the tablebases: {0}")]
+    NotInTablebase(String),
+
+    #[error("{0} can't probe Syzygy tablebases")]
+    NoTablebaseSupport(String),
+
+    #[error("No free port for the OAuth callback between {0} and {1}")]
+    NoCallbackPort(u16, u16),
+
+    #[error("No authentication in progress")]
+    NoPendingAuth,
+
+    #[error("Authentication timed out, please try again")]
+    AuthTimeout,
+
+    #[error("No authorization code in the pasted URL")]
+    MissingAuthCode,
+
+    #[error("OAuth error: {0}")]
+    OAuth(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
 
     #[error("Failed to acquire mutex lock: {0}")]
     MutexLockFailed(String),
@@ -111,6 +255,16 @@ pub enum Error {
     #[error("Package manager error: {0}")]
     PackageManager(String),
 
+    #[error("Checksum mismatch for {file}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("No download available for {0}")]
+    NoAssetDownload(String),
+
     #[allow(dead_code)]
     #[error("Engine timeout: {0}")]
     EngineTimeout(String),
@@ -1,11 +1,13 @@
 use std::{
-    fs::create_dir_all,
-    io::{Cursor, Write},
+    fs::{create_dir_all, File, OpenOptions},
+    hash::Hasher,
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
 };
 
 use log::{info, warn};
-use reqwest::{Client, Url};
+use reqwest::{header::RANGE, Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
 use specta::Type;
 use tauri_specta::Event;
 
@@ -15,9 +17,13 @@
 use futures_util::StreamExt;
 
 use crate::error::Error;
+use crate::tasks::{TaskHandle, TaskKind};
 
 const MAX_DOWNLOAD_SIZE: u64 = 10 * 1024 * 1024 * 1024;
 
+/// Bytes copied between two progress reports.
+pub(crate) const COPY_CHUNK: usize = 4 * 1024 * 1024;
+
 #[derive(Clone, Type, serde::Serialize, Event)]
 pub struct DownloadProgress {
     pub progress: f32,
@@ -47,24 +53,7 @@ pub async fn download_file(
         }
     });
 
-    let parsed_url =
-        Url::parse(&url).map_err(|e| Error::PackageManager(format!("Invalid URL: {}", e)))?;
-
-    if parsed_url.scheme() != "https" && parsed_url.scheme() != "http" {
-        return Err(Error::PackageManager(format!(
-            "Only HTTP/HTTPS allowed, got: {}",
-            parsed_url.scheme()
-        )));
-    }
-
-    if let Some(host) = parsed_url.host_str() {
-        if is_private_or_localhost(host) {
-            return Err(Error::PackageManager(format!(
-                "Cannot access private/local addresses: {}",
-                host
-            )));
-        }
-    }
+    validate_download_url(&url)?;
 
     info!("Downloading file from {} to {}", url, path.display());
 
@@ -102,11 +91,13 @@ pub async fn download_file(
 
     let is_archive = url.ends_with(".zip") || url.ends_with(".tar") || url.ends_with(".tar.gz");
 
+    let task = TaskHandle::start(&app, TaskKind::Download, &id, false);
     if is_archive {
-        download_and_extract(res, content_length, &path, &url, &id, &app, finalize).await?;
+        download_and_extract(res, content_length, &path, &url, &task, &app, finalize).await?;
     } else {
-        download_to_file(res, content_length, &path, &id, &app, finalize).await?;
+        download_to_file(res, content_length, &path, &task, &app, finalize).await?;
     }
+    task.finish();
 
     Ok(())
 }
@@ -115,7 +106,7 @@ async fn download_to_file(
     res: reqwest::Response,
     content_length: Option<u64>,
     path: &Path,
-    id: &str,
+    task: &TaskHandle,
     app: &tauri::AppHandle,
     finalize: bool,
 ) -> Result<(), Error> {
@@ -145,10 +136,11 @@ async fn download_to_file(
 
         DownloadProgress {
             progress,
-            id: id.to_string(),
+            id: task.id().to_string(),
             finished: false,
         }
         .emit(app)?;
+        task.report(progress as f64, None);
     }
 
     file.sync_all()?;
@@ -158,7 +150,7 @@ async fn download_to_file(
     if finalize {
         DownloadProgress {
             progress: 100.0,
-            id: id.to_string(),
+            id: task.id().to_string(),
             finished: true,
         }
         .emit(app)?;
@@ -172,7 +164,7 @@ async fn download_and_extract(
     content_length: Option<u64>,
     path: &Path,
     url: &str,
-    id: &str,
+    task: &TaskHandle,
     app: &tauri::AppHandle,
     finalize: bool,
 ) -> Result<(), Error> {
@@ -204,10 +196,11 @@ async fn download_and_extract(
 
         DownloadProgress {
             progress,
-            id: id.to_string(),
+            id: task.id().to_string(),
             finished: false,
         }
         .emit(app)?;
+        task.report(progress as f64, None);
     }
 
     info!(
@@ -218,10 +211,11 @@ async fn download_and_extract(
 
     DownloadProgress {
         progress: 50.0,
-        id: id.to_string(),
+        id: task.id().to_string(),
         finished: false,
     }
     .emit(app)?;
+    task.report(50.0, Some("extract".to_string()));
 
     if url.ends_with(".zip") {
         unzip_file(path, file_data)?;
@@ -236,7 +230,7 @@ async fn download_and_extract(
     if finalize {
         DownloadProgress {
             progress: 100.0,
-            id: id.to_string(),
+            id: task.id().to_string(),
             finished: true,
         }
         .emit(app)?;
@@ -245,6 +239,285 @@ async fn download_and_extract(
     Ok(())
 }
 
+/// Downloads a small file to `path`, rejecting responses larger than `max_size` bytes.
+///
+/// Unlike [`download_file`] this emits no progress events and is meant for
+/// auxiliary assets such as player photos.
+pub(crate) async fn download_small_file(
+    url: &str,
+    path: &Path,
+    max_size: u64,
+) -> Result<(), Error> {
+    validate_download_url(url)?;
+    validate_destination_path(path)?;
+
+    let client = Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+
+    let res = client.get(url).send().await?;
+
+    if !res.status().is_success() {
+        return Err(Error::PackageManager(format!(
+            "Download failed: {}",
+            res.status()
+        )));
+    }
+
+    if let Some(size) = res.content_length() {
+        if size > max_size {
+            return Err(Error::PackageManager(format!(
+                "File too large: {} bytes (max {})",
+                size, max_size
+            )));
+        }
+    }
+
+    let data = read_limited(res.bytes_stream(), max_size).await?;
+    std::fs::write(path, data)?;
+
+    info!("Downloaded file to {}", path.display());
+
+    Ok(())
+}
+
+/// Collects the chunks of a download, failing as soon as they add up to more than `max_size`
+/// bytes, whatever size the server announced.
+async fn read_limited<S, B, E>(mut stream: S, max_size: u64) -> Result<Vec<u8>, Error>
+where
+    S: futures_util::Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    Error: From<E>,
+{
+    let mut data = Vec::new();
+    while let Some(item) = stream.next().await {
+        let chunk = item?;
+        let chunk = chunk.as_ref();
+        if (data.len() + chunk.len()) as u64 > max_size {
+            return Err(Error::PackageManager(
+                "Download size limit exceeded".to_string(),
+            ));
+        }
+        data.extend_from_slice(chunk);
+    }
+    Ok(data)
+}
+
+/// Downloads `url` to `path` through `<path>.part`, resuming an interrupted download with a
+/// range request when the server supports it.
+///
+/// With `sha256_prefix`, the file's SHA-256 digest in lowercase hex must start with it, or
+/// the file is deleted and the download fails.
+pub(crate) async fn download_resumable(
+    url: &str,
+    path: &Path,
+    sha256_prefix: Option<&str>,
+    task: &TaskHandle,
+    app: &tauri::AppHandle,
+) -> Result<(), Error> {
+    validate_download_url(url)?;
+    validate_destination_path(path)?;
+
+    let part = partial_path(path);
+    let resume_from = std::fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+
+    let client = Client::builder()
+        .timeout(std::time::Duration::from_secs(300))
+        .build()?;
+    let mut req = client.get(url);
+    if resume_from > 0 {
+        info!("Resuming download of {} at {} bytes", url, resume_from);
+        req = req.header(RANGE, format!("bytes={}-", resume_from));
+    }
+    let res = req.send().await?;
+
+    // A range starting at the end of the file means the previous download had finished.
+    let complete = resume_from > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE;
+    if !complete {
+        if !res.status().is_success() {
+            return Err(Error::PackageManager(format!(
+                "Download failed: {}",
+                res.status()
+            )));
+        }
+        // Servers ignoring the range send the whole file again.
+        let resumed = res.status() == StatusCode::PARTIAL_CONTENT;
+        let mut downloaded = if resumed { resume_from } else { 0 };
+        let content_length = res.content_length().map(|len| len + downloaded);
+        if content_length.is_some_and(|size| size > MAX_DOWNLOAD_SIZE) {
+            return Err(Error::PackageManager(format!(
+                "File too large: {} bytes (max {})",
+                content_length.unwrap_or_default(),
+                MAX_DOWNLOAD_SIZE
+            )));
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(&part)?;
+        let mut stream = res.bytes_stream();
+        while let Some(item) = stream.next().await {
+            let chunk = item?;
+            downloaded = downloaded.saturating_add(chunk.len() as u64);
+            if downloaded > MAX_DOWNLOAD_SIZE {
+                return Err(Error::PackageManager(
+                    "Download size limit exceeded".to_string(),
+                ));
+            }
+            file.write_all(&chunk)?;
+
+            let progress = content_length
+                .map(|total| ((downloaded as f64 / total as f64) * 100.0).min(100.0) as f32)
+                .unwrap_or(-1.0);
+            DownloadProgress {
+                progress,
+                id: task.id().to_string(),
+                finished: false,
+            }
+            .emit(app)?;
+            task.report(progress as f64, None);
+        }
+        file.sync_all()?;
+    }
+
+    if let Some(expected) = sha256_prefix {
+        let actual = sha256_hex(&part)?;
+        if !actual.starts_with(&expected.to_ascii_lowercase()) {
+            std::fs::remove_file(&part)?;
+            return Err(Error::ChecksumMismatch {
+                file: path.display().to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    std::fs::rename(&part, path)?;
+    info!("Downloaded file to {}", path.display());
+
+    DownloadProgress {
+        progress: 100.0,
+        id: task.id().to_string(),
+        finished: true,
+    }
+    .emit(app)?;
+    Ok(())
+}
+
+/// Where a download to `path` is written until it's complete.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// SHA-256 digest of a file, in lowercase hex.
+pub(crate) fn sha256_hex(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A path for `name` in `dir` that isn't taken, adding " (n)" before the extension if needed.
+///
+/// Returns whether the name had to change.
+pub(crate) fn unique_path(dir: &Path, name: &str) -> (PathBuf, bool) {
+    let path = dir.join(name);
+    if !path.exists() {
+        return (path, false);
+    }
+    let (stem, ext) = match name.split_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let path = dir.join(format!("{} ({}){}", stem, n, ext));
+        if !path.exists() {
+            return (path, true);
+        }
+        n += 1;
+    }
+}
+
+/// Copy `from` to `to`, reporting the bytes copied after each chunk.
+///
+/// With `verify`, the copy is read back and compared with the source by checksum. A failed
+/// copy is removed.
+pub(crate) fn copy_file(
+    from: &Path,
+    to: &Path,
+    verify: bool,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(), Error> {
+    let mut copy = || -> Result<(), Error> {
+        let mut source = File::open(from)?;
+        let mut target = File::create(to)?;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let mut buf = vec![0; COPY_CHUNK];
+        let mut copied = 0;
+        loop {
+            let n = source.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            target.write_all(&buf[..n])?;
+            hasher.write(&buf[..n]);
+            copied += n as u64;
+            progress(copied);
+        }
+        target.sync_all()?;
+        if verify && checksum(to)? != hasher.finish() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("checksum mismatch after copying {}", from.display()),
+            )));
+        }
+        Ok(())
+    };
+    copy().inspect_err(|_| {
+        let _ = std::fs::remove_file(to);
+    })
+}
+
+pub(crate) fn checksum(path: &Path) -> std::io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let mut buf = vec![0; COPY_CHUNK];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+fn validate_download_url(url: &str) -> Result<Url, Error> {
+    let parsed_url =
+        Url::parse(url).map_err(|e| Error::PackageManager(format!("Invalid URL: {}", e)))?;
+
+    if parsed_url.scheme() != "https" && parsed_url.scheme() != "http" {
+        return Err(Error::PackageManager(format!(
+            "Only HTTP/HTTPS allowed, got: {}",
+            parsed_url.scheme()
+        )));
+    }
+
+    if let Some(host) = parsed_url.host_str() {
+        if is_private_or_localhost(host) {
+            return Err(Error::PackageManager(format!(
+                "Cannot access private/local addresses: {}",
+                host
+            )));
+        }
+    }
+
+    Ok(parsed_url)
+}
+
 fn validate_destination_path(path: &Path) -> Result<(), Error> {
     let canonical = path.canonicalize().or_else(|_| {
         if let Some(parent) = path.parent() {
@@ -472,3 +745,26 @@ pub async fn get_file_metadata(path: String) -> Result<FileMetadata, Error> {
         is_readonly: metadata.permissions().readonly(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(sizes: &[usize]) -> impl futures_util::Stream<Item = Result<Vec<u8>, Error>> {
+        futures_util::stream::iter(
+            sizes
+                .iter()
+                .map(|&size| Ok(vec![0; size]))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn small_downloads_are_limited_in_size() {
+        let data = read_limited(chunks(&[3, 4, 3]), 10).await.unwrap();
+        assert_eq!(data.len(), 10);
+        assert!(read_limited(chunks(&[3, 4, 4]), 10).await.is_err());
+        // Servers announcing no size are cut off all the same.
+        assert!(read_limited(chunks(&[11]), 10).await.is_err());
+    }
+}
@@ -8,7 +8,7 @@ struct Lexer {
     tokens: Vec<Token>,
 }
 
-#[derive(Serialize, Clone, Type)]
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
 #[serde(tag = "type", content = "value")]
 pub enum Token {
     ParenOpen,
@@ -63,9 +63,8 @@ fn outcome(&mut self, outcome: Option<shakmaty::Outcome>) {
     }
 }
 
-#[tauri::command]
-#[specta::specta]
-pub async fn lex_pgn(pgn: String) -> Result<Vec<Token>, Error> {
+/// Tokens of the first game of a PGN.
+pub fn lex(pgn: &str) -> Result<Vec<Token>, Error> {
     let mut reader = BufferedReader::new(pgn.as_bytes());
 
     let mut lexer = Lexer { tokens: Vec::new() };
@@ -74,3 +73,9 @@ pub async fn lex_pgn(pgn: String) -> Result<Vec<Token>, Error> {
 
     Ok(lexer.tokens)
 }
+
+#[tauri::command]
+#[specta::specta]
+pub async fn lex_pgn(pgn: String) -> Result<Vec<Token>, Error> {
+    lex(&pgn)
+}
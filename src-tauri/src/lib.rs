@@ -5,59 +5,120 @@
 
 mod app;
 mod chess;
+mod clipboard;
+mod cloud_eval;
 mod db;
+mod dirty_tabs;
 mod error;
 mod fide;
 mod fs;
 mod lexer;
+mod notation;
 mod oauth;
+mod online_stats;
 mod opening;
 mod package_manager;
+mod pairings;
 mod pgn;
 mod puzzle;
+mod puzzle_export;
+mod scope;
 mod sound;
+mod tasks;
 mod telemetry;
 
 use std::sync::{Arc, Mutex};
 
-use chess::{BestMovesPayload, EngineProcess, ReportProgress};
+use chess::{
+    BatchEvaluationResult, BestMovesPayload, BlindfoldSession, DrillSession,
+    EngineCapabilityWarning, EngineMovePlayed, EnginePool, EngineProcess, EvalBarEngine,
+    EvalBarUpdate, PinnedLine, PlayAccuracyUpdated, PlaySessionHandle, Refutation,
+    RefutationEngine, RefutationKey, ReportProgress, TabEngineState,
+};
 use dashmap::DashMap;
-use db::{DatabaseProgress, GameQueryJs, NormalizedGame, PositionStats};
+use db::{
+    DatabaseInfoChanged, DatabaseProgress, DatabaseWatcher, GameCache, GameQueryJs, NormalizedGame,
+    PositionStats, ResultHandles, SearchPartialResult,
+};
 use derivative::Derivative;
 use fide::FidePlayer;
 use oauth::AuthState;
+use online_stats::OnlineStatsCache;
+use scope::{authorize_path, PathScope};
 #[cfg(all(debug_assertions, not(target_os = "android")))]
 use specta_typescript::{BigIntExportBehavior, Typescript};
 use sysinfo::SystemExt;
 use tauri::AppHandle;
 
 use crate::chess::{
-    analyze_game, get_best_moves, get_engine_config, get_engine_logs, kill_engine, kill_engines,
-    stop_engine,
+    analyze_game, analyze_play_session, apply_option_to_all_engines, blindfold_move,
+    blindfold_peek, check_capture_vision, check_conditionals, check_engine_assets,
+    check_knight_path, classify_move, clear_analysis_cache, clear_conditional_moves,
+    clear_evalbar_engine, compare_perft, compute_position_timeline, create_odds_position,
+    delete_time_control_preset, download_engine_asset, duplicate_tab, end_play_session,
+    estimate_engine_strength, evaluate_positions_batch, export_conditional_moves,
+    export_position_notes, finish_blindfold_session, generate_capture_vision_drill,
+    generate_coordinate_drill, generate_knight_path_drill, get_best_moves,
+    get_correspondence_rules, get_engine_concurrency_status, get_engine_config, get_engine_logs,
+    get_play_session_pgn, get_position_history, get_position_history_enabled, get_position_note,
+    get_position_notes_bulk, get_refutation, get_time_usage_report, import_conditional_moves,
+    kill_engine, kill_engines, list_conditional_moves, list_position_notes,
+    list_time_control_presets, parse_time_control_header, perft, pin_line, probe_tablebase,
+    record_position_visit, rename_tab, replay_uci_recording, save_time_control_preset,
+    search_position_history, set_conditional_moves, set_correspondence_rules,
+    set_engine_concurrency_limit, set_evalbar_engine, set_evalbar_position,
+    set_position_history_enabled, set_position_note, set_tab_engine_policy,
+    start_blindfold_session, start_line_drill, start_play_session, start_uci_recording,
+    stop_analysis, stop_engine, stop_uci_recording, submit_coordinate_drill, submit_drill_move,
+    submit_player_move, tab_hidden, tab_ready, takeback, unpin_line, validate_timeline,
+    SharedRecorder,
 };
+use crate::clipboard::parse_clipboard_content;
+use crate::cloud_eval::{get_cloud_eval, CloudEvalCache};
 use crate::db::{
-    clear_games, convert_pgn, create_indexes, delete_database, delete_db_game, delete_empty_games,
-    delete_indexes, export_to_pgn, get_player, get_players_game_info, get_tournaments,
-    search_position,
+    classify_pawn_structures, clear_games, clone_games_to_database, compact_database,
+    compute_db_content_hash, compute_move_heatmap, compute_opening_frequencies, convert_pgn,
+    convert_pgn_batch, create_database, create_index, create_indexes, delete_database,
+    delete_db_game, delete_empty_games, delete_indexes, export_annotated_pgn, export_repertoire,
+    export_to_pgn, fetch_player_metadata, fetch_result_chunk, find_duplicate_games,
+    get_analysis_attribution, get_game_cache_stats, get_game_tree, get_game_with_prefetch,
+    get_identity_report, get_index_status, get_king_safety_report, get_linked_games,
+    get_node_details, get_pawn_structure_counts, get_player, get_player_metadata_bulk,
+    get_players_game_info, get_tournaments, global_search, link_games, link_player_identity,
+    list_player_identities, list_trashed_databases, optimize_database, query_games_handle,
+    reevaluate_variations, release_result_handle, restore_trashed_database, search_position,
+    search_position_handle, transform_game, transform_position, unlink_games,
+    unlink_player_identity, update_database_from_pgn, watch_databases,
+};
+use crate::dirty_tabs::{
+    force_exit, get_dirty_tabs, mark_tab_clean, mark_tab_dirty, ConfirmExit, DirtyTabs,
 };
 use crate::fide::{download_fide_db, find_fide_player};
 use crate::fs::{set_file_as_executable, DownloadProgress};
 use crate::lexer::lex_pgn;
-use crate::oauth::authenticate;
+use crate::oauth::{authenticate, authenticate_manual, complete_manual_auth};
+use crate::online_stats::{get_online_profile, get_rating_history};
 use crate::package_manager::{
     check_package_installed, check_package_manager_available, find_executable_path, install_package,
 };
+use crate::pairings::{
+    create_event, export_event_games, generate_next_round, get_pairing_event, get_standings,
+    record_result,
+};
 use crate::pgn::{count_pgn_games, delete_game, read_games, write_game};
 use crate::puzzle::{get_puzzle, get_puzzle_db_info, get_puzzle_rating_range, import_puzzle_file};
+use crate::puzzle_export::export_puzzles;
 use crate::sound::get_sound_server_port;
+use crate::tasks::{cancel_task, list_active_tasks, TaskProgress, TaskRegistry};
 use crate::telemetry::{
     get_platform_info_command, get_telemetry_config, get_telemetry_enabled, get_user_country_api,
     get_user_country_locale, get_user_id_command, set_telemetry_enabled,
 };
 use crate::{
     db::{
-        delete_duplicated_games, edit_db_info, get_db_info, get_game, get_games, get_players,
-        merge_players, update_game,
+        annotate_opening, delete_duplicated_games, edit_db_info, get_db_info, get_db_metadata,
+        get_game, get_game_revision, get_games, get_players, merge_players, patch_game,
+        set_db_metadata, update_game,
     },
     fs::{download_file, file_exists, get_file_metadata},
     opening::{get_opening_from_fen, get_opening_from_name, search_opening_name},
@@ -75,6 +136,8 @@
     i32,
     i32,
     i32,
+    Option<i32>,
+    Option<i32>,
 );
 
 #[derive(Derivative)]
@@ -96,7 +159,45 @@ pub struct AppState {
     pgn_offsets: DashMap<String, Vec<u64>>,
     fide_players: RwLock<Vec<FidePlayer>>,
     engine_processes: DashMap<(String, String), Arc<tokio::sync::Mutex<EngineProcess>>>,
+    play_sessions: DashMap<String, PlaySessionHandle>,
+    line_drills: DashMap<String, Arc<tokio::sync::Mutex<DrillSession>>>,
+    blindfold_sessions: DashMap<String, BlindfoldSession>,
+    refutation_engines: DashMap<(String, String), Arc<tokio::sync::Mutex<RefutationEngine>>>,
+    #[derivative(Default(
+        value = "Mutex::new(lru::LruCache::new(std::num::NonZeroUsize::new(256).unwrap()))"
+    ))]
+    refutation_cache: Mutex<lru::LruCache<RefutationKey, Refutation>>,
+    /// Lichess cloud evaluations, by FEN and number of lines.
+    #[derivative(Default(
+        value = "Mutex::new(lru::LruCache::new(std::num::NonZeroUsize::new(256).unwrap()))"
+    ))]
+    cloud_evals: CloudEvalCache,
+    /// Pinned engine lines, by tab and engine.
+    pinned_lines: DashMap<(String, String), PinnedLine>,
     auth: AuthState,
+    online_stats: OnlineStatsCache,
+    db_write_locks: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    tasks: TaskRegistry,
+    /// Tabs with unsaved changes.
+    dirty_tabs: DirtyTabs,
+    /// MultiPV limit of engines seen ignoring the option, by engine path.
+    engine_multipv_limits: DashMap<String, u16>,
+    /// Running UCI recordings, by tab and engine.
+    uci_recordings: DashMap<(String, String), SharedRecorder>,
+    /// Engine policy and visibility, by tab.
+    tab_engines: DashMap<String, TabEngineState>,
+    /// Limit on the engines searching at once, and the searches waiting for it.
+    engine_pool: EnginePool,
+    /// Eval bar engine, by tab.
+    evalbar_engines: DashMap<String, Arc<tokio::sync::Mutex<EvalBarEngine>>>,
+    /// Paths commands are allowed to use.
+    path_scope: PathScope,
+    /// Summaries of the databases shown in the sidebar.
+    db_watcher: DatabaseWatcher,
+    /// Decoded games, prefetched while browsing databases.
+    game_cache: GameCache,
+    /// Results of games queries, fetched in chunks by the games table.
+    result_handles: ResultHandles,
 }
 
 // ============================================================================
@@ -109,8 +210,35 @@ pub async fn run() {
     let specta_builder = tauri_specta::Builder::new()
         .commands(tauri_specta::collect_commands!(
             app::platform::screen_capture,
+            app::platform::desktop::migration::detect_legacy_data,
+            app::platform::desktop::migration::run_migration,
             find_fide_player,
             get_best_moves,
+            get_refutation,
+            pin_line,
+            unpin_line,
+            set_tab_engine_policy,
+            tab_ready,
+            tab_hidden,
+            set_evalbar_engine,
+            set_evalbar_position,
+            clear_evalbar_engine,
+            set_conditional_moves,
+            list_conditional_moves,
+            clear_conditional_moves,
+            check_conditionals,
+            export_conditional_moves,
+            import_conditional_moves,
+            get_correspondence_rules,
+            set_correspondence_rules,
+            get_engine_book_moves,
+            prune_engine_book,
+            record_position_visit,
+            get_position_history,
+            search_position_history,
+            get_position_history_enabled,
+            set_position_history_enabled,
+            apply_option_to_all_engines,
             analyze_game,
             stop_engine,
             kill_engine,
@@ -123,7 +251,10 @@ pub async fn run() {
             get_opening_from_name,
             get_players_game_info,
             get_engine_config,
+            check_engine_assets,
+            download_engine_asset,
             file_exists,
+            authorize_path,
             get_file_metadata,
             merge_players,
             convert_pgn,
@@ -131,32 +262,64 @@ pub async fn run() {
             count_pgn_games,
             read_games,
             lex_pgn,
+            parse_clipboard_content,
             is_bmi2_compatible,
             delete_game,
             delete_duplicated_games,
+            find_duplicate_games,
             delete_empty_games,
+            optimize_database,
+            classify_pawn_structures,
+            get_pawn_structure_counts,
+            link_games,
+            get_linked_games,
+            get_analysis_attribution,
+            unlink_games,
+            reevaluate_variations,
+            export_repertoire,
             clear_games,
             set_file_as_executable,
             delete_indexes,
             create_indexes,
+            create_index,
+            get_index_status,
             edit_db_info,
+            get_game_tree,
+            get_node_details,
+            transform_position,
+            transform_game,
+            get_db_metadata,
+            set_db_metadata,
             delete_db_game,
             delete_database,
+            list_trashed_databases,
+            restore_trashed_database,
+            watch_databases,
             export_to_pgn,
+            compute_db_content_hash,
             authenticate,
+            authenticate_manual,
+            complete_manual_auth,
+            get_online_profile,
+            get_rating_history,
             write_game,
             download_fide_db,
             download_file,
             get_tournaments,
             get_db_info,
             get_games,
+            clone_games_to_database,
             get_game,
             update_game,
+            get_game_revision,
+            patch_game,
+            annotate_opening,
             search_position,
             get_players,
             get_puzzle_db_info,
             get_puzzle_rating_range,
             import_puzzle_file,
+            export_puzzles,
             get_telemetry_enabled,
             set_telemetry_enabled,
             get_telemetry_config,
@@ -169,13 +332,107 @@ pub async fn run() {
             check_package_installed,
             find_executable_path,
             open_external_link,
-            get_sound_server_port
+            get_sound_server_port,
+            get_time_usage_report,
+            fetch_player_metadata,
+            get_player_metadata_bulk,
+            start_play_session,
+            submit_player_move,
+            takeback,
+            end_play_session,
+            start_line_drill,
+            submit_drill_move,
+            classify_move,
+            compute_position_timeline,
+            validate_timeline,
+            evaluate_positions_batch,
+            link_player_identity,
+            unlink_player_identity,
+            list_player_identities,
+            get_identity_report,
+            create_database,
+            perft,
+            compare_perft,
+            global_search,
+            set_position_note,
+            get_position_note,
+            get_position_notes_bulk,
+            list_position_notes,
+            export_position_notes,
+            get_header_rules,
+            set_header_rules,
+            test_header_rules,
+            analyze_play_session,
+            compute_opening_frequencies,
+            start_uci_recording,
+            stop_uci_recording,
+            replay_uci_recording,
+            create_event,
+            get_pairing_event,
+            generate_next_round,
+            record_result,
+            get_standings,
+            export_event_games,
+            get_game_with_prefetch,
+            get_game_cache_stats,
+            create_odds_position,
+            get_play_session_pgn,
+            query_games_handle,
+            search_position_handle,
+            fetch_result_chunk,
+            release_result_handle,
+            compute_move_heatmap,
+            compact_database,
+            get_king_safety_report,
+            rename_tab,
+            duplicate_tab,
+            generate_coordinate_drill,
+            submit_coordinate_drill,
+            generate_knight_path_drill,
+            check_knight_path,
+            generate_capture_vision_drill,
+            check_capture_vision,
+            save_time_control_preset,
+            list_time_control_presets,
+            delete_time_control_preset,
+            parse_time_control_header,
+            estimate_engine_strength,
+            clear_analysis_cache,
+            stop_analysis,
+            set_engine_concurrency_limit,
+            get_engine_concurrency_status,
+            probe_tablebase,
+            get_cloud_eval,
+            export_annotated_pgn,
+            convert_pgn_batch,
+            update_database_from_pgn,
+            start_blindfold_session,
+            blindfold_move,
+            blindfold_peek,
+            finish_blindfold_session,
+            list_active_tasks,
+            cancel_task,
+            mark_tab_dirty,
+            mark_tab_clean,
+            get_dirty_tabs,
+            force_exit
         ))
         .events(tauri_specta::collect_events!(
+            BatchEvaluationResult,
             BestMovesPayload,
+            ConfirmExit,
+            DatabaseInfoChanged,
             DatabaseProgress,
             DownloadProgress,
-            ReportProgress
+            EngineCapabilityWarning,
+            EngineMovePlayed,
+            EvalBarUpdate,
+            app::platform::desktop::migration::LegacyDataAvailable,
+            app::platform::desktop::migration::MigrationProgress,
+            PlayAccuracyUpdated,
+            ReportProgress,
+            SearchPartialResult,
+            TaskProgress
         ));
 
     #[cfg(all(debug_assertions, not(target_os = "android")))]
@@ -191,8 +448,9 @@ pub async fn run() {
 
     builder
         .setup(move |app| app::setup::setup_tauri_app(app, &specta_builder))
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(app::setup::handle_run_event);
 }
 
 // ============================================================================
@@ -0,0 +1,208 @@
+//! Localized SAN for printed and displayed moves.
+//!
+//! PGN move text is always written in English SAN, since that's what every program reads.
+//! Players used to other piece letters, or to figurines, get a separate rendering of the
+//! moves instead. Only piece letters change: squares, castling and annotations are kept as
+//! they are. Renderings are for reading, and are never written back as PGN move text.
+
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// Piece letters of English SAN, in the order of `SanStyle::pieces`.
+const ENGLISH_PIECES: &str = "KQRBN";
+
+/// How pieces are written in a rendering of the moves.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum SanStyle {
+    #[default]
+    English,
+    German,
+    Spanish,
+    French,
+    Italian,
+    Dutch,
+    /// Unicode chess glyphs, for HTML and print.
+    Figurine,
+}
+
+impl SanStyle {
+    /// King, queen, rook, bishop and knight.
+    fn pieces(self) -> [&'static str; 5] {
+        match self {
+            SanStyle::English => ["K", "Q", "R", "B", "N"],
+            SanStyle::German => ["K", "D", "T", "L", "S"],
+            SanStyle::Spanish => ["R", "D", "T", "A", "C"],
+            SanStyle::French => ["R", "D", "T", "F", "C"],
+            SanStyle::Italian => ["R", "D", "T", "A", "C"],
+            SanStyle::Dutch => ["K", "D", "T", "L", "P"],
+            SanStyle::Figurine => ["\u{2654}", "\u{2655}", "\u{2656}", "\u{2657}", "\u{2658}"],
+        }
+    }
+
+    /// Added to the name of the file a rendering is written to, next to the PGN file.
+    pub fn file_suffix(self) -> &'static str {
+        match self {
+            SanStyle::English => "en",
+            SanStyle::German => "de",
+            SanStyle::Spanish => "es",
+            SanStyle::French => "fr",
+            SanStyle::Italian => "it",
+            SanStyle::Dutch => "nl",
+            SanStyle::Figurine => "figurine",
+        }
+    }
+
+    fn push_char(self, out: &mut String, c: char) {
+        match ENGLISH_PIECES.find(c) {
+            Some(piece) => out.push_str(self.pieces()[piece]),
+            None => out.push(c),
+        }
+    }
+}
+
+/// Write a SAN move in `style`.
+///
+/// Uppercase letters of SAN are only ever pieces, whether moving or promoted to: squares are
+/// lowercase and castling is written with `O`, which is left alone.
+pub fn localize_san(san: &str, style: SanStyle) -> String {
+    let mut out = String::with_capacity(san.len());
+    for c in san.chars() {
+        style.push_char(&mut out, c);
+    }
+    out
+}
+
+/// Renders PGN text line by line, writing the moves in a style while keeping headers,
+/// comments and escaped lines as they are.
+pub struct MovetextLocalizer {
+    style: SanStyle,
+    /// Whether the previous line ended inside a `{}` comment.
+    in_comment: bool,
+}
+
+impl MovetextLocalizer {
+    pub fn new(style: SanStyle) -> Self {
+        Self {
+            style,
+            in_comment: false,
+        }
+    }
+
+    pub fn line(&mut self, line: &str) -> String {
+        if !self.in_comment && (line.starts_with('[') || line.starts_with('%')) {
+            return line.to_string();
+        }
+        let mut out = String::with_capacity(line.len());
+        for (i, c) in line.char_indices() {
+            if self.in_comment {
+                out.push(c);
+                self.in_comment = c != '}';
+                continue;
+            }
+            match c {
+                '{' => {
+                    out.push(c);
+                    self.in_comment = true;
+                }
+                // The rest of the line is a comment.
+                ';' => {
+                    out.push_str(&line[i..]);
+                    break;
+                }
+                _ => self.style.push_char(&mut out, c),
+            }
+        }
+        out
+    }
+}
+
+/// Render PGN text with the moves in `style`.
+pub fn localize_movetext(text: &str, style: SanStyle) -> String {
+    let mut localizer = MovetextLocalizer::new(style);
+    text.split_inclusive('\n')
+        .map(|line| localizer.line(line))
+        .collect()
+}
+
+/// Write a rendering of the PGN read from `reader` with the moves in `style`.
+pub fn write_localized(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    style: SanStyle,
+) -> io::Result<()> {
+    let mut localizer = MovetextLocalizer::new(style);
+    for line in reader.lines() {
+        writeln!(writer, "{}", localizer.line(&line?))?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVES: [&str; 7] = ["Nf3", "Bxb7", "Qd1+", "Rae1", "Kxh8", "e8=Q#", "O-O-O"];
+
+    fn localize_all(style: SanStyle) -> Vec<String> {
+        MOVES.iter().map(|san| localize_san(san, style)).collect()
+    }
+
+    #[test]
+    fn piece_letters_of_each_language() {
+        assert_eq!(localize_all(SanStyle::English), MOVES);
+        assert_eq!(
+            localize_all(SanStyle::German),
+            ["Sf3", "Lxb7", "Dd1+", "Tae1", "Kxh8", "e8=D#", "O-O-O"]
+        );
+        assert_eq!(
+            localize_all(SanStyle::Spanish),
+            ["Cf3", "Axb7", "Dd1+", "Tae1", "Rxh8", "e8=D#", "O-O-O"]
+        );
+        assert_eq!(
+            localize_all(SanStyle::French),
+            ["Cf3", "Fxb7", "Dd1+", "Tae1", "Rxh8", "e8=D#", "O-O-O"]
+        );
+        assert_eq!(
+            localize_all(SanStyle::Italian),
+            ["Cf3", "Axb7", "Dd1+", "Tae1", "Rxh8", "e8=D#", "O-O-O"]
+        );
+        assert_eq!(
+            localize_all(SanStyle::Dutch),
+            ["Pf3", "Lxb7", "Dd1+", "Tae1", "Kxh8", "e8=D#", "O-O-O"]
+        );
+    }
+
+    #[test]
+    fn figurines() {
+        assert_eq!(
+            localize_all(SanStyle::Figurine),
+            ["♘f3", "♗xb7", "♕d1+", "♖ae1", "♔xh8", "e8=♕#", "O-O-O"]
+        );
+        // Underpromotions, also when written without `=`.
+        assert_eq!(localize_san("bxa1=N+", SanStyle::Figurine), "bxa1=♘+");
+        assert_eq!(localize_san("g1R", SanStyle::German), "g1T");
+    }
+
+    #[test]
+    fn only_move_text_is_localized() {
+        let pgn = "[Event \"Bundesliga\"]\n[Black \"Nepomniachtchi, Ian\"]\n\n\
+                   1. e4 e5 2. Nf3 {Best by test: Nf3\nor Bc4} Nc6 $1 (2... Qf6?) ; Nc6 or Nf6\n\
+                   3. Bb5 1/2-1/2\n% Manifest: Bb5\n";
+        assert_eq!(
+            localize_movetext(pgn, SanStyle::German),
+            "[Event \"Bundesliga\"]\n[Black \"Nepomniachtchi, Ian\"]\n\n\
+             1. e4 e5 2. Sf3 {Best by test: Nf3\nor Bc4} Sc6 $1 (2... Df6?) ; Nc6 or Nf6\n\
+             3. Lb5 1/2-1/2\n% Manifest: Bb5\n"
+        );
+
+        let mut rendering = Vec::new();
+        write_localized(pgn.as_bytes(), &mut rendering, SanStyle::German).unwrap();
+        assert_eq!(
+            String::from_utf8(rendering).unwrap(),
+            localize_movetext(pgn, SanStyle::German)
+        );
+    }
+}
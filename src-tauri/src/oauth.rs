@@ -4,16 +4,29 @@
     basic::BasicClient, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId,
     CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
 };
+use reqwest::Url;
 use serde::Deserialize;
 use std::{
-    net::{SocketAddr, TcpListener},
-    sync::Arc,
+    net::TcpListener,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
 };
 use tauri::{Emitter, Manager};
 use tauri_plugin_opener::OpenerExt;
+use tokio::sync::{oneshot, Mutex};
 
 use crate::{error::Error, AppState};
 
+/// Ports tried, in order, for the loopback callback listener.
+const CALLBACK_PORTS: RangeInclusive<u16> = 48620..=48639;
+
+/// Redirect used by the manual flow. Nothing listens there; the user copies the code
+/// from the address bar of the failed redirect instead.
+const MANUAL_REDIRECT_URL: &str = "http://127.0.0.1/callback";
+
+/// Pending authentications are cancelled after this long.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 fn create_client(redirect_url: RedirectUrl) -> BasicClient {
     let client_id = ClientId::new("com.pawnappetit".to_string());
     let auth_url = AuthUrl::new("https://lichess.org/oauth".to_string());
@@ -23,35 +36,138 @@ fn create_client(redirect_url: RedirectUrl) -> BasicClient {
         .set_redirect_uri(redirect_url)
 }
 
-fn get_available_addr() -> SocketAddr {
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    let addr = listener.local_addr().unwrap();
-    drop(listener);
+/// Bind the first free port of `ports`.
+///
+/// The listener is handed to the callback server as is, so the port can't be taken
+/// between picking it and serving on it.
+fn bind_callback_listener(ports: RangeInclusive<u16>) -> Result<TcpListener, Error> {
+    ports
+        .clone()
+        .find_map(|port| TcpListener::bind(("127.0.0.1", port)).ok())
+        .ok_or_else(|| Error::NoCallbackPort(*ports.start(), *ports.end()))
+}
+
+/// Split what the user pasted into the authorization code and, when a whole redirected
+/// URL was pasted, its CSRF token.
+fn parse_manual_code(input: &str) -> Result<(String, Option<String>), Error> {
+    let input = input.trim();
+    match Url::parse(input) {
+        Ok(url) => {
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string())
+            };
+            Ok((param("code").ok_or(Error::MissingAuthCode)?, param("state")))
+        }
+        Err(_) => Ok((input.to_string(), None)),
+    }
+}
+
+/// An authorization request waiting for its code.
+struct PendingAuth {
+    csrf_token: CsrfToken,
+    pkce_verifier: String,
+    client: BasicClient,
+    started: Instant,
+    /// Stops the callback server of a loopback authentication.
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl PendingAuth {
+    /// Create a new request with a fresh CSRF token and PKCE pair, returning it
+    /// together with the URL the user has to open.
+    fn new(redirect_url: String, username: String) -> Result<(Self, Url), Error> {
+        let redirect_url =
+            RedirectUrl::new(redirect_url).map_err(|e| Error::OAuth(e.to_string()))?;
+        let client = create_client(redirect_url);
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (auth_url, csrf_token) = client
+            .authorize_url(CsrfToken::new_random)
+            .add_scope(Scope::new("preference:read".to_string()))
+            .add_extra_param("username", username)
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        Ok((
+            Self {
+                csrf_token,
+                pkce_verifier: pkce_verifier.secret().to_string(),
+                client,
+                started: Instant::now(),
+                shutdown: None,
+            },
+            auth_url,
+        ))
+    }
+
+    fn cancel(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
 
-    addr
+    /// Exchange the authorization code for an access token.
+    async fn exchange(mut self, code: AuthorizationCode) -> Result<String, Error> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let token = self
+            .client
+            .exchange_code(code)
+            .set_pkce_verifier(PkceCodeVerifier::new(self.pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| Error::OAuth(e.to_string()))?;
+        Ok(token.access_token().secret().to_string())
+    }
 }
 
-#[derive(Clone)]
+/// State of the authentication currently in progress, if any.
+#[derive(Default)]
 pub struct AuthState {
-    pub csrf_token: CsrfToken,
-    pub pkce: Arc<(PkceCodeChallenge, String)>,
-    pub client: Arc<BasicClient>,
-    pub socket_addr: SocketAddr,
+    pending: Mutex<Option<PendingAuth>>,
 }
 
-impl Default for AuthState {
-    fn default() -> Self {
-        let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-        let socket_addr = get_available_addr();
-        let redirect_url = format!("http://{socket_addr}/callback");
-        AuthState {
-            csrf_token: CsrfToken::new_random(),
-            pkce: Arc::new((
-                pkce_code_challenge,
-                PkceCodeVerifier::secret(&pkce_code_verifier).to_string(),
-            )),
-            client: Arc::new(create_client(RedirectUrl::new(redirect_url).unwrap())),
-            socket_addr,
+impl AuthState {
+    /// Start tracking a new authentication, cancelling the previous one.
+    async fn begin(&self, auth: PendingAuth) {
+        let mut pending = self.pending.lock().await;
+        if let Some(previous) = pending.replace(auth) {
+            previous.cancel();
+        }
+    }
+
+    /// Take the pending authentication if it matches `csrf_token` (when given) and
+    /// hasn't expired.
+    async fn take(&self, csrf_token: Option<&str>) -> Result<PendingAuth, Error> {
+        let mut pending = self.pending.lock().await;
+        let auth = pending.as_ref().ok_or(Error::NoPendingAuth)?;
+
+        if csrf_token.is_some_and(|token| token != auth.csrf_token.secret()) {
+            log::warn!("CSRF token mismatch in OAuth callback");
+            return Err(Error::NoPendingAuth);
+        }
+
+        let auth = pending.take().unwrap();
+        if auth.started.elapsed() > AUTH_TIMEOUT {
+            auth.cancel();
+            return Err(Error::AuthTimeout);
+        }
+        Ok(auth)
+    }
+
+    /// Drop the pending authentication if it is still the one identified by `csrf_token`.
+    async fn clear(&self, csrf_token: &str) {
+        let mut pending = self.pending.lock().await;
+        if pending
+            .as_ref()
+            .is_some_and(|auth| auth.csrf_token.secret() == csrf_token)
+        {
+            if let Some(auth) = pending.take() {
+                auth.cancel();
+            }
         }
     }
 }
@@ -64,16 +180,53 @@ pub async fn authenticate(
     app: tauri::AppHandle,
 ) -> Result<(), Error> {
     info!("Authenticating user {}", username);
-    let (auth_url, _) = state
-        .auth
-        .client
-        .authorize_url(|| state.auth.csrf_token.clone())
-        .add_scope(Scope::new("preference:read".to_string()))
-        .add_extra_param("username", username)
-        .set_pkce_challenge(state.auth.pkce.0.clone())
-        .url();
+    let listener = bind_callback_listener(CALLBACK_PORTS)?;
+    let redirect_url = format!("http://{}/callback", listener.local_addr()?);
+
+    let (mut auth, auth_url) = PendingAuth::new(redirect_url, username)?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    auth.shutdown = Some(shutdown_tx);
+    let csrf_token = auth.csrf_token.secret().to_string();
+    state.auth.begin(auth).await;
+
     app.opener().open_url(auth_url, None::<String>)?;
-    let _server_handle = tauri::async_runtime::spawn(async move { run_server(app).await });
+    let _server_handle = tauri::async_runtime::spawn(async move {
+        if let Err(e) = run_server(app.clone(), listener, shutdown_rx).await {
+            log::error!("OAuth callback server failed: {}", e);
+        }
+        app.state::<AppState>().auth.clear(&csrf_token).await;
+    });
+    Ok(())
+}
+
+/// Start an authentication where the user pastes the code back into the app, for when
+/// the browser can't reach the loopback callback (e.g. remote or headless setups).
+///
+/// Returns the URL to open. The code is then submitted with `complete_manual_auth`.
+#[tauri::command]
+#[specta::specta]
+pub async fn authenticate_manual(
+    username: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    info!("Authenticating user {} manually", username);
+    let (auth, auth_url) = PendingAuth::new(MANUAL_REDIRECT_URL.to_string(), username)?;
+    state.auth.begin(auth).await;
+    Ok(auth_url.to_string())
+}
+
+/// Finish a manual authentication with the pasted code, or the whole redirected URL.
+#[tauri::command]
+#[specta::specta]
+pub async fn complete_manual_auth(
+    code: String,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), Error> {
+    let (code, csrf_token) = parse_manual_code(&code)?;
+    let auth = state.auth.take(csrf_token.as_deref()).await?;
+    let access_token = auth.exchange(AuthorizationCode::new(code)).await?;
+    app.emit("access_token", access_token)?;
     Ok(())
 }
 
@@ -87,22 +240,21 @@ async fn authorize(
     app: Extension<tauri::AppHandle>,
     query: Query<CallbackQuery>,
 ) -> impl IntoResponse {
-    let auth = &app.state::<AppState>().auth;
-
-    if query.state.secret() != auth.csrf_token.secret() {
-        log::warn!("CSRF token mismatch in OAuth callback");
-        return "authorized".to_string(); // Return generic response for security
-    }
-
-    match auth
-        .client
-        .exchange_code(query.code.clone())
-        .set_pkce_verifier(PkceCodeVerifier::new(auth.pkce.1.clone()))
-        .request_async(async_http_client)
+    let auth = match app
+        .state::<AppState>()
+        .auth
+        .take(Some(query.state.secret()))
         .await
     {
-        Ok(token) => {
-            let access_token = token.access_token().secret();
+        Ok(auth) => auth,
+        Err(e) => {
+            log::warn!("Ignoring OAuth callback: {}", e);
+            return "authorized".to_string(); // Return generic response for security
+        }
+    };
+
+    match auth.exchange(query.code.clone()).await {
+        Ok(access_token) => {
             if let Err(e) = app.emit("access_token", access_token) {
                 log::error!("Failed to emit access token: {}", e);
             }
@@ -115,14 +267,137 @@ async fn authorize(
     "authorized".to_string()
 }
 
-async fn run_server(handle: tauri::AppHandle) -> Result<(), axum::Error> {
+/// Serve the callback until it is used, cancelled, or `AUTH_TIMEOUT` elapses.
+async fn run_server(
+    handle: tauri::AppHandle,
+    listener: TcpListener,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<(), Error> {
     let app = Router::new()
         .route("/callback", get(authorize))
-        .layer(Extension(handle.clone()));
+        .layer(Extension(handle));
 
-    let _ = axum::Server::bind(&handle.state::<AppState>().auth.socket_addr.clone())
+    axum::Server::from_tcp(listener)
+        .map_err(|e| Error::OAuth(e.to_string()))?
         .serve(app.into_make_service())
-        .await;
+        .with_graceful_shutdown(async {
+            tokio::select! {
+                _ = shutdown => {}
+                _ = tokio::time::sleep(AUTH_TIMEOUT) => info!("OAuth callback timed out"),
+            }
+        })
+        .await
+        .map_err(|e| Error::OAuth(e.to_string()))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_auth() -> (PendingAuth, String) {
+        let (auth, _) =
+            PendingAuth::new(MANUAL_REDIRECT_URL.to_string(), "player".to_string()).unwrap();
+        let csrf_token = auth.csrf_token.secret().to_string();
+        (auth, csrf_token)
+    }
+
+    #[test]
+    fn busy_callback_ports_are_skipped() {
+        // Find a taken port followed by a free one.
+        let (taken, port) = loop {
+            let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let port = taken.local_addr().unwrap().port();
+            if port < u16::MAX && TcpListener::bind(("127.0.0.1", port + 1)).is_ok() {
+                break (taken, port);
+            }
+        };
+
+        let listener = bind_callback_listener(port..=port + 1).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port + 1);
+
+        assert!(matches!(
+            bind_callback_listener(port..=port + 1),
+            Err(Error::NoCallbackPort(start, end)) if start == port && end == port + 1
+        ));
+        drop(taken);
+    }
+
+    #[test]
+    fn pasted_codes_and_urls_are_parsed() {
+        assert_eq!(
+            parse_manual_code("  abc123\n").unwrap(),
+            ("abc123".to_string(), None)
+        );
+        assert_eq!(
+            parse_manual_code("http://127.0.0.1/callback?code=abc123&state=xyz").unwrap(),
+            ("abc123".to_string(), Some("xyz".to_string()))
+        );
+        assert_eq!(
+            parse_manual_code("http://127.0.0.1/callback?code=abc123").unwrap(),
+            ("abc123".to_string(), None)
+        );
+        assert!(matches!(
+            parse_manual_code("http://127.0.0.1/callback?error=access_denied"),
+            Err(Error::MissingAuthCode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn mismatched_csrf_tokens_are_rejected() {
+        let state = AuthState::default();
+        let (auth, csrf_token) = pending_auth();
+        state.begin(auth).await;
+
+        assert!(matches!(
+            state.take(Some("forged")).await,
+            Err(Error::NoPendingAuth)
+        ));
+        // The rejected callback leaves the real authentication pending.
+        assert!(state.take(Some(&csrf_token)).await.is_ok());
+        assert!(matches!(state.take(None).await, Err(Error::NoPendingAuth)));
+    }
+
+    #[tokio::test]
+    async fn pasted_codes_need_no_csrf_token() {
+        let state = AuthState::default();
+        let (auth, _) = pending_auth();
+        state.begin(auth).await;
+
+        assert!(state.take(None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_authentications_are_cancelled() {
+        let state = AuthState::default();
+        let (mut auth, csrf_token) = pending_auth();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        auth.shutdown = Some(shutdown_tx);
+        auth.started = Instant::now() - AUTH_TIMEOUT - Duration::from_secs(1);
+        state.begin(auth).await;
+
+        assert!(matches!(
+            state.take(Some(&csrf_token)).await,
+            Err(Error::AuthTimeout)
+        ));
+        assert!(shutdown_rx.try_recv().is_ok());
+        assert!(matches!(state.take(None).await, Err(Error::NoPendingAuth)));
+    }
+
+    #[tokio::test]
+    async fn a_new_authentication_cancels_the_previous_one() {
+        let state = AuthState::default();
+        let (mut first, first_token) = pending_auth();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        first.shutdown = Some(shutdown_tx);
+        state.begin(first).await;
+        let (second, second_token) = pending_auth();
+        state.begin(second).await;
+
+        assert!(shutdown_rx.try_recv().is_ok());
+        assert!(matches!(
+            state.take(Some(&first_token)).await,
+            Err(Error::NoPendingAuth)
+        ));
+        assert!(state.take(Some(&second_token)).await.is_ok());
+    }
 }
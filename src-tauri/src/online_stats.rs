@@ -0,0 +1,658 @@
+//! Online ratings from Lichess and Chess.com.
+//!
+//! Both sites' public APIs are queried and their answers normalized into the same structs.
+//! Responses are cached for a while; when a site rate limits us or can't be reached, the
+//! last cached answer is served instead, flagged as stale.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use log::warn;
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use specta::Type;
+
+use crate::{error::Error, AppState};
+
+/// How long responses are served from the cache before being refreshed.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Chess.com monthly game archives looked at for the rating history.
+const CHESSCOM_HISTORY_MONTHS: usize = 3;
+
+pub(crate) const USER_AGENT: &str = concat!("Pawn-Appetit/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum OnlinePlatform {
+    Lichess,
+    Chesscom,
+}
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Type, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum TimeControl {
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+    Correspondence,
+}
+
+impl TimeControl {
+    /// Parse a Lichess perf name, e.g. `blitz` or `Blitz`.
+    fn from_lichess(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "bullet" => Some(Self::Bullet),
+            "blitz" => Some(Self::Blitz),
+            "rapid" => Some(Self::Rapid),
+            "classical" => Some(Self::Classical),
+            "correspondence" => Some(Self::Correspondence),
+            _ => None,
+        }
+    }
+
+    /// Parse a Chess.com time class, e.g. `blitz` or `daily`.
+    fn from_chesscom(name: &str) -> Option<Self> {
+        match name {
+            "bullet" => Some(Self::Bullet),
+            "blitz" => Some(Self::Blitz),
+            "rapid" => Some(Self::Rapid),
+            "daily" => Some(Self::Correspondence),
+            _ => None,
+        }
+    }
+}
+
+/// Current rating of a player in one time control.
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeControlRating {
+    pub time_control: TimeControl,
+    pub rating: u32,
+    pub games: u32,
+    pub best_rating: Option<u32>,
+    /// Rating change over the last games, when the site reports it.
+    pub trend: Option<i32>,
+    pub provisional: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OnlineProfile {
+    pub platform: OnlinePlatform,
+    pub username: String,
+    pub ratings: Vec<TimeControlRating>,
+    /// Set when the site couldn't be queried and cached data is returned instead.
+    pub stale: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingPoint {
+    /// Day of the rating, as `YYYY-MM-DD`.
+    pub date: String,
+    pub rating: u32,
+}
+
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingSeries {
+    pub time_control: TimeControl,
+    pub points: Vec<RatingPoint>,
+}
+
+#[derive(Serialize, Debug, Clone, Type, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingHistory {
+    pub platform: OnlinePlatform,
+    pub username: String,
+    pub series: Vec<RatingSeries>,
+    /// Set when the site couldn't be queried and cached data is returned instead.
+    pub stale: bool,
+}
+
+type CacheKey = (OnlinePlatform, String);
+
+/// Cached responses, keyed by platform and lowercase username.
+#[derive(Default)]
+pub struct OnlineStatsCache {
+    profiles: DashMap<CacheKey, (Instant, OnlineProfile)>,
+    histories: DashMap<CacheKey, (Instant, RatingHistory)>,
+}
+
+/// Serve `key` from `cache` while fresh, otherwise fetch it, falling back to the stale entry
+/// when fetching fails. Returns the value and whether it is stale.
+async fn cached<T, F>(
+    cache: &DashMap<CacheKey, (Instant, T)>,
+    key: CacheKey,
+    fetch: F,
+) -> Result<(T, bool), Error>
+where
+    T: Clone,
+    F: Future<Output = Result<T, Error>>,
+{
+    if let Some(entry) = cache.get(&key) {
+        if entry.0.elapsed() < CACHE_TTL {
+            return Ok((entry.1.clone(), false));
+        }
+    }
+
+    match fetch.await {
+        Ok(value) => {
+            cache.insert(key, (Instant::now(), value.clone()));
+            Ok((value, false))
+        }
+        Err(e) => match cache.get(&key) {
+            Some(entry) => {
+                warn!("Serving stale online stats for {}: {}", key.1, e);
+                Ok((entry.1.clone(), true))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+pub(crate) async fn fetch_json<T: DeserializeOwned>(
+    url: &str,
+    token: Option<&str>,
+) -> Result<T, Error> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(15))
+        .user_agent(USER_AGENT)
+        .build()?;
+    let mut req = client.get(url);
+    if let Some(token) = token {
+        req = req.header("Authorization", format!("Bearer {}", token));
+    }
+
+    let res = req.send().await?;
+    if res.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::RateLimited(url.to_string()));
+    }
+    Ok(res.error_for_status()?.json().await?)
+}
+
+// Lichess
+
+#[derive(Deserialize)]
+struct LichessUser {
+    username: String,
+    #[serde(default)]
+    perfs: BTreeMap<String, LichessPerf>,
+}
+
+#[derive(Deserialize)]
+struct LichessPerf {
+    // Puzzle modes like storm share the map but have neither field.
+    #[serde(default)]
+    games: u32,
+    #[serde(default)]
+    rating: u32,
+    prog: Option<i32>,
+    #[serde(default)]
+    prov: bool,
+}
+
+#[derive(Deserialize)]
+struct LichessHistorySeries {
+    name: String,
+    /// `[year, month (0-based), day, rating]`
+    points: Vec<[u32; 4]>,
+}
+
+fn lichess_profile(user: LichessUser, history: Option<&RatingHistory>) -> OnlineProfile {
+    let mut ratings: Vec<_> = user
+        .perfs
+        .into_iter()
+        .filter_map(|(name, perf)| {
+            let time_control = TimeControl::from_lichess(&name)?;
+            // The user endpoint has no peak rating, but the history does.
+            let best_rating = history
+                .and_then(|h| h.series.iter().find(|s| s.time_control == time_control))
+                .and_then(|s| s.points.iter().map(|p| p.rating).max());
+            Some(TimeControlRating {
+                time_control,
+                rating: perf.rating,
+                games: perf.games,
+                best_rating,
+                trend: perf.prog,
+                provisional: perf.prov,
+            })
+        })
+        .filter(|rating| rating.games > 0)
+        .collect();
+    ratings.sort_by_key(|r| r.time_control);
+
+    OnlineProfile {
+        platform: OnlinePlatform::Lichess,
+        username: user.username,
+        ratings,
+        stale: false,
+    }
+}
+
+fn lichess_history(username: &str, series: Vec<LichessHistorySeries>) -> RatingHistory {
+    let mut series: Vec<_> = series
+        .into_iter()
+        .filter_map(|s| {
+            let time_control = TimeControl::from_lichess(&s.name)?;
+            let points = s
+                .points
+                .into_iter()
+                .map(|[year, month, day, rating]| RatingPoint {
+                    date: format!("{:04}-{:02}-{:02}", year, month + 1, day),
+                    rating,
+                })
+                .collect();
+            Some(RatingSeries {
+                time_control,
+                points,
+            })
+        })
+        .filter(|s| !s.points.is_empty())
+        .collect();
+    series.sort_by_key(|s| s.time_control);
+
+    RatingHistory {
+        platform: OnlinePlatform::Lichess,
+        username: username.to_string(),
+        series,
+        stale: false,
+    }
+}
+
+// Chess.com
+
+#[derive(Deserialize)]
+struct ChesscomStats {
+    chess_bullet: Option<ChesscomPerf>,
+    chess_blitz: Option<ChesscomPerf>,
+    chess_rapid: Option<ChesscomPerf>,
+    chess_daily: Option<ChesscomPerf>,
+}
+
+#[derive(Deserialize)]
+struct ChesscomPerf {
+    last: ChesscomRating,
+    best: Option<ChesscomRating>,
+    record: ChesscomRecord,
+}
+
+#[derive(Deserialize)]
+struct ChesscomRating {
+    rating: u32,
+}
+
+#[derive(Deserialize)]
+struct ChesscomRecord {
+    win: u32,
+    loss: u32,
+    draw: u32,
+}
+
+#[derive(Deserialize)]
+struct ChesscomArchives {
+    archives: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChesscomGames {
+    games: Vec<ChesscomGame>,
+}
+
+#[derive(Deserialize)]
+struct ChesscomGame {
+    end_time: i64,
+    time_class: String,
+    #[serde(default)]
+    rules: String,
+    white: ChesscomGamePlayer,
+    black: ChesscomGamePlayer,
+}
+
+#[derive(Deserialize)]
+struct ChesscomGamePlayer {
+    username: String,
+    rating: u32,
+}
+
+fn chesscom_profile(username: &str, stats: ChesscomStats) -> OnlineProfile {
+    let perfs = [
+        (TimeControl::Bullet, stats.chess_bullet),
+        (TimeControl::Blitz, stats.chess_blitz),
+        (TimeControl::Rapid, stats.chess_rapid),
+        (TimeControl::Correspondence, stats.chess_daily),
+    ];
+    let ratings = perfs
+        .into_iter()
+        .filter_map(|(time_control, perf)| {
+            let perf = perf?;
+            Some(TimeControlRating {
+                time_control,
+                rating: perf.last.rating,
+                games: perf.record.win + perf.record.loss + perf.record.draw,
+                best_rating: perf.best.map(|b| b.rating),
+                trend: None,
+                provisional: false,
+            })
+        })
+        .collect();
+
+    OnlineProfile {
+        platform: OnlinePlatform::Chesscom,
+        username: username.to_string(),
+        ratings,
+        stale: false,
+    }
+}
+
+fn chesscom_history(username: &str, games: Vec<ChesscomGame>) -> RatingHistory {
+    let mut series: BTreeMap<TimeControl, Vec<(i64, u32)>> = BTreeMap::new();
+    for game in games {
+        if game.rules != "chess" {
+            continue;
+        }
+        let Some(time_control) = TimeControl::from_chesscom(&game.time_class) else {
+            continue;
+        };
+        let rating = if game.white.username.eq_ignore_ascii_case(username) {
+            game.white.rating
+        } else if game.black.username.eq_ignore_ascii_case(username) {
+            game.black.rating
+        } else {
+            continue;
+        };
+        series
+            .entry(time_control)
+            .or_default()
+            .push((game.end_time, rating));
+    }
+
+    let series = series
+        .into_iter()
+        .map(|(time_control, mut games)| {
+            games.sort_by_key(|(end_time, _)| *end_time);
+            // One point per day, with the rating after the day's last game.
+            let mut points: Vec<RatingPoint> = Vec::new();
+            for (end_time, rating) in games {
+                let Some(date) = chrono::DateTime::from_timestamp(end_time, 0) else {
+                    continue;
+                };
+                let date = date.format("%Y-%m-%d").to_string();
+                match points.last_mut() {
+                    Some(last) if last.date == date => last.rating = rating,
+                    _ => points.push(RatingPoint { date, rating }),
+                }
+            }
+            RatingSeries {
+                time_control,
+                points,
+            }
+        })
+        .collect();
+
+    RatingHistory {
+        platform: OnlinePlatform::Chesscom,
+        username: username.to_string(),
+        series,
+        stale: false,
+    }
+}
+
+async fn fetch_history(
+    platform: OnlinePlatform,
+    username: &str,
+    token: Option<&str>,
+) -> Result<RatingHistory, Error> {
+    match platform {
+        OnlinePlatform::Lichess => {
+            let url = format!("https://lichess.org/api/user/{}/rating-history", username);
+            let series: Vec<LichessHistorySeries> = fetch_json(&url, token).await?;
+            Ok(lichess_history(username, series))
+        }
+        OnlinePlatform::Chesscom => {
+            let url = format!(
+                "https://api.chess.com/pub/player/{}/games/archives",
+                username.to_lowercase()
+            );
+            let archives: ChesscomArchives = fetch_json(&url, None).await?;
+            let mut games = Vec::new();
+            let start = archives
+                .archives
+                .len()
+                .saturating_sub(CHESSCOM_HISTORY_MONTHS);
+            for archive in &archives.archives[start..] {
+                let month: ChesscomGames = fetch_json(archive, None).await?;
+                games.extend(month.games);
+            }
+            Ok(chesscom_history(username, games))
+        }
+    }
+}
+
+async fn history(
+    platform: OnlinePlatform,
+    username: &str,
+    token: Option<&str>,
+    cache: &OnlineStatsCache,
+) -> Result<RatingHistory, Error> {
+    let key = (platform, username.to_lowercase());
+    let (mut history, stale) = cached(
+        &cache.histories,
+        key,
+        fetch_history(platform, username, token),
+    )
+    .await?;
+    history.stale = stale;
+    Ok(history)
+}
+
+/// Get the current ratings of a player on Lichess or Chess.com.
+///
+/// The Lichess OAuth token, when given, is sent along so private accounts can be read.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_online_profile(
+    platform: OnlinePlatform,
+    username: String,
+    token: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<OnlineProfile, Error> {
+    let cache = &state.online_stats;
+    let token = token.as_deref();
+    let fetch = async {
+        match platform {
+            OnlinePlatform::Lichess => {
+                let url = format!("https://lichess.org/api/user/{}", username);
+                let user: LichessUser = fetch_json(&url, token).await?;
+                let history = history(platform, &username, token, cache).await.ok();
+                Ok(lichess_profile(user, history.as_ref()))
+            }
+            OnlinePlatform::Chesscom => {
+                let url = format!(
+                    "https://api.chess.com/pub/player/{}/stats",
+                    username.to_lowercase()
+                );
+                let stats: ChesscomStats = fetch_json(&url, None).await?;
+                Ok(chesscom_profile(&username, stats))
+            }
+        }
+    };
+
+    let key = (platform, username.to_lowercase());
+    let (mut profile, stale) = cached(&cache.profiles, key, fetch).await?;
+    profile.stale = stale;
+    Ok(profile)
+}
+
+/// Get the rating history of a player on Lichess or Chess.com.
+///
+/// Chess.com has no history endpoint, so it is rebuilt from the games of the last months.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_rating_history(
+    platform: OnlinePlatform,
+    username: String,
+    token: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<RatingHistory, Error> {
+    history(platform, &username, token.as_deref(), &state.online_stats).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LICHESS_USER: &str = r#"{
+        "id": "thibault",
+        "username": "thibault",
+        "perfs": {
+            "bullet": { "games": 3120, "rating": 1642, "rd": 62, "prog": -14 },
+            "blitz": { "games": 8211, "rating": 1791, "rd": 45, "prog": 23 },
+            "rapid": { "games": 4, "rating": 1500, "rd": 290, "prog": 0, "prov": true },
+            "classical": { "games": 0, "rating": 1500, "rd": 500, "prog": 0, "prov": true },
+            "puzzle": { "games": 900, "rating": 2020, "rd": 80, "prog": 5 },
+            "storm": { "runs": 12, "score": 30 }
+        },
+        "createdAt": 1290415680000
+    }"#;
+
+    const LICHESS_HISTORY: &str = r#"[
+        { "name": "Bullet", "points": [[2024, 0, 5, 1610], [2024, 1, 12, 1671], [2024, 2, 1, 1642]] },
+        { "name": "Blitz", "points": [[2023, 11, 31, 1802], [2024, 2, 3, 1791]] },
+        { "name": "Puzzles", "points": [[2024, 2, 3, 2020]] },
+        { "name": "Classical", "points": [] }
+    ]"#;
+
+    const CHESSCOM_STATS: &str = r#"{
+        "chess_daily": {
+            "last": { "rating": 1402, "date": 1709251200, "rd": 120 },
+            "best": { "rating": 1455, "date": 1690000000, "game": "https://www.chess.com/game/daily/1" },
+            "record": { "win": 20, "loss": 14, "draw": 2, "time_per_move": 6000, "timeout_percent": 0 }
+        },
+        "chess_blitz": {
+            "last": { "rating": 1588, "date": 1709337600, "rd": 40 },
+            "best": { "rating": 1650, "date": 1700000000, "game": "https://www.chess.com/game/live/2" },
+            "record": { "win": 500, "loss": 480, "draw": 40 }
+        },
+        "chess_rapid": {
+            "last": { "rating": 1701, "date": 1709337600, "rd": 60 },
+            "record": { "win": 10, "loss": 5, "draw": 1 }
+        },
+        "fide": 0,
+        "tactics": { "highest": { "rating": 2100, "date": 1700000000 } }
+    }"#;
+
+    const CHESSCOM_GAMES: &str = r#"{
+        "games": [
+            {
+                "url": "https://www.chess.com/game/live/3",
+                "end_time": 1709337600, "rated": true, "time_class": "blitz", "rules": "chess",
+                "white": { "rating": 1580, "result": "win", "username": "Hikaru_Fan" },
+                "black": { "rating": 1575, "result": "resigned", "username": "someone" }
+            },
+            {
+                "url": "https://www.chess.com/game/live/4",
+                "end_time": 1709341200, "rated": true, "time_class": "blitz", "rules": "chess",
+                "white": { "rating": 1601, "result": "checkmated", "username": "other" },
+                "black": { "rating": 1588, "result": "win", "username": "hikaru_fan" }
+            },
+            {
+                "url": "https://www.chess.com/game/live/5",
+                "end_time": 1709424000, "rated": true, "time_class": "blitz", "rules": "chess960",
+                "white": { "rating": 1500, "result": "win", "username": "hikaru_fan" },
+                "black": { "rating": 1500, "result": "resigned", "username": "x" }
+            },
+            {
+                "url": "https://www.chess.com/game/daily/6",
+                "end_time": 1709424000, "rated": true, "time_class": "daily", "rules": "chess",
+                "white": { "rating": 1400, "result": "win", "username": "hikaru_fan" },
+                "black": { "rating": 1390, "result": "resigned", "username": "y" }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn lichess_profile_is_normalized() {
+        let user: LichessUser = serde_json::from_str(LICHESS_USER).unwrap();
+        let series: Vec<LichessHistorySeries> = serde_json::from_str(LICHESS_HISTORY).unwrap();
+        let history = lichess_history("thibault", series);
+        let profile = lichess_profile(user, Some(&history));
+
+        assert_eq!(profile.platform, OnlinePlatform::Lichess);
+        let controls: Vec<_> = profile.ratings.iter().map(|r| r.time_control).collect();
+        assert_eq!(
+            controls,
+            vec![TimeControl::Bullet, TimeControl::Blitz, TimeControl::Rapid]
+        );
+
+        let bullet = &profile.ratings[0];
+        assert_eq!(bullet.rating, 1642);
+        assert_eq!(bullet.games, 3120);
+        assert_eq!(bullet.best_rating, Some(1671));
+        assert_eq!(bullet.trend, Some(-14));
+        assert!(!bullet.provisional);
+        assert!(profile.ratings[2].provisional);
+        assert_eq!(profile.ratings[2].best_rating, None);
+    }
+
+    #[test]
+    fn lichess_history_is_normalized() {
+        let series: Vec<LichessHistorySeries> = serde_json::from_str(LICHESS_HISTORY).unwrap();
+        let history = lichess_history("thibault", series);
+
+        assert_eq!(history.series.len(), 2);
+        assert_eq!(history.series[0].time_control, TimeControl::Bullet);
+        assert_eq!(
+            history.series[1].points[0],
+            RatingPoint {
+                date: "2023-12-31".to_string(),
+                rating: 1802
+            }
+        );
+    }
+
+    #[test]
+    fn chesscom_profile_is_normalized() {
+        let stats: ChesscomStats = serde_json::from_str(CHESSCOM_STATS).unwrap();
+        let profile = chesscom_profile("Hikaru_Fan", stats);
+
+        let controls: Vec<_> = profile.ratings.iter().map(|r| r.time_control).collect();
+        assert_eq!(
+            controls,
+            vec![
+                TimeControl::Blitz,
+                TimeControl::Rapid,
+                TimeControl::Correspondence
+            ]
+        );
+        assert_eq!(profile.ratings[0].games, 1020);
+        assert_eq!(profile.ratings[0].best_rating, Some(1650));
+        assert_eq!(profile.ratings[1].best_rating, None);
+        assert_eq!(profile.ratings[2].rating, 1402);
+    }
+
+    #[test]
+    fn chesscom_history_is_rebuilt_from_games() {
+        let games: ChesscomGames = serde_json::from_str(CHESSCOM_GAMES).unwrap();
+        let history = chesscom_history("hikaru_fan", games.games);
+
+        assert_eq!(history.series.len(), 2);
+        let blitz = &history.series[0];
+        assert_eq!(blitz.time_control, TimeControl::Blitz);
+        // Both blitz games were played on the same day; the variant game is left out.
+        assert_eq!(
+            blitz.points,
+            vec![RatingPoint {
+                date: "2024-03-02".to_string(),
+                rating: 1588
+            }]
+        );
+        assert_eq!(history.series[1].time_control, TimeControl::Correspondence);
+    }
+}
@@ -19,8 +19,8 @@ struct Opening {
 
 #[derive(Debug, Clone, Type, Serialize)]
 pub struct OutOpening {
-    name: String,
-    fen: String,
+    pub(crate) name: String,
+    pub(crate) fen: String,
 }
 
 #[derive(Deserialize)]
@@ -63,55 +63,127 @@ pub fn get_opening_from_name(name: &str) -> Result<String, Error> {
         .ok_or_else(|| Error::NoOpeningFound)
 }
 
+/// Name of the opening reaching `setup`. Move counters are ignored, so positions reached by
+/// transposition are named too.
 pub fn get_opening_from_setup(setup: Setup) -> Result<String, Error> {
     OPENINGS
         .iter()
-        .find(|o| o.setup == setup)
+        .find(|o| same_position(&o.setup, &setup))
         .map(|o| o.name.clone())
         .ok_or_else(|| Error::NoOpeningFound)
 }
 
-#[tauri::command]
-#[specta::specta]
-pub async fn search_opening_name(query: String) -> Result<Vec<OutOpening>, Error> {
+/// Whether two setups describe the same position, regardless of their move counters.
+fn same_position(a: &Setup, b: &Setup) -> bool {
+    a.board == b.board
+        && a.turn == b.turn
+        && a.castling_rights == b.castling_rights
+        && a.ep_square == b.ep_square
+}
+
+/// The last book position of a game, with the name of its opening.
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize)]
+pub struct OpeningAnnotation {
+    /// Moves played to reach the position.
+    pub ply: u32,
+    pub name: String,
+}
+
+impl OpeningAnnotation {
+    /// Comment marking the move where the game leaves theory.
+    pub fn comment(&self) -> String {
+        format!("Theory ends here: {}", self.name)
+    }
+}
+
+/// Find the deepest book position of a game, given the position after each of its moves
+/// with the start position first.
+///
+/// Positions are looked up one by one, so a game that leaves the book and transposes back
+/// into it is labelled at the later position. Games from a custom start have no opening.
+pub fn find_opening_annotation(positions: &[Setup]) -> Option<OpeningAnnotation> {
+    if !same_position(positions.first()?, &Setup::default()) {
+        return None;
+    }
+    positions
+        .iter()
+        .enumerate()
+        .skip(1)
+        .take(*MAX_BOOK_PLY)
+        .filter_map(|(ply, setup)| {
+            let name = get_opening_from_setup(setup.clone()).ok()?;
+            if name == STARTING_POSITION {
+                return None;
+            }
+            Some(OpeningAnnotation {
+                ply: ply as u32,
+                name,
+            })
+        })
+        .last()
+}
+
+/// How closely `name` matches `query`, both lowercase, from 0 to 1.
+pub fn name_similarity(query: &str, name: &str) -> f64 {
+    sorensen_dice(query, name).max(jaro_winkler(query, name))
+}
+
+/// The `limit` openings whose names best match `query`, with their similarity, best first.
+pub fn matching_openings(query: &str, limit: usize) -> Vec<(OutOpening, f64)> {
     let lower_query = query.to_lowercase();
-    let scores = OPENINGS
+    let mut best_matches = OPENINGS
         .iter()
         .map(|opening| {
-            let lower_name = opening.name.to_lowercase();
-            let sorenson_score = sorensen_dice(&lower_query, &lower_name);
-            let jaro_score = jaro_winkler(&lower_query, &lower_name);
-            let score = sorenson_score.max(jaro_score);
-            (opening.clone(), score)
+            let score = name_similarity(&lower_query, &opening.name.to_lowercase());
+            (opening, score)
         })
-        .collect::<Vec<_>>();
-    let mut best_matches = scores
-        .into_iter()
         .filter(|(_, score)| *score > 0.8)
         .collect::<Vec<_>>();
 
     best_matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
 
-    let best_matches_names = best_matches
-        .iter()
-        .map(|(o, _)| o.clone())
-        .take(15)
-        .map(|o| OutOpening {
-            name: o.name,
-            fen: Fen::from_setup(o.setup.clone()).to_string(),
+    best_matches
+        .into_iter()
+        .take(limit)
+        .map(|(o, score)| {
+            (
+                OutOpening {
+                    name: o.name.clone(),
+                    fen: Fen::from_setup(o.setup.clone()).to_string(),
+                },
+                score,
+            )
         })
-        .collect();
-    Ok(best_matches_names)
+        .collect()
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn search_opening_name(query: String) -> Result<Vec<OutOpening>, Error> {
+    Ok(matching_openings(&query, 15)
+        .into_iter()
+        .map(|(opening, _)| opening)
+        .collect())
 }
 
+const STARTING_POSITION: &str = "Starting Position";
+
 lazy_static! {
+    /// Moves in the longest line of the openings table, past which games are out of book.
+    static ref MAX_BOOK_PLY: usize = OPENINGS
+        .iter()
+        .filter(|o| o.pgn.is_some())
+        .map(|o| (o.setup.fullmoves.get() as usize - 1) * 2 + usize::from(o.setup.turn.is_black()))
+        .max()
+        .unwrap_or(0);
+
     static ref OPENINGS: Vec<Opening> = {
         info!("Initializing openings table...");
 
         let mut positions = vec![
             Opening {
                 eco: "Extra".to_string(),
-                name: "Starting Position".to_string(),
+                name: STARTING_POSITION.to_string(),
                 setup: Setup::default(),
                 pgn: None,
             },
@@ -197,4 +269,54 @@ fn test_get_opening() {
                 .unwrap();
         assert_eq!(opening, "Bongcloud Attack");
     }
+
+    fn positions(moves: &str) -> Vec<Setup> {
+        let mut position = Chess::default();
+        let mut positions = vec![position.clone().into_setup(EnPassantMode::Legal)];
+        for san in moves.split_whitespace().filter(|t| !t.ends_with('.')) {
+            let m = san.parse::<San>().unwrap().to_move(&position).unwrap();
+            position.play_unchecked(&m);
+            positions.push(position.clone().into_setup(EnPassantMode::Legal));
+        }
+        positions
+    }
+
+    #[test]
+    fn najdorf_main_line_ends_at_the_deepest_book_move() {
+        let game = positions(
+            "1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Bg5 e6 7. f4 Be7 \
+             8. Qf3 Qc7 9. O-O-O Nbd7 10. g4 b5 11. Bxf6 Nxf6",
+        );
+        let annotation = find_opening_annotation(&game).unwrap();
+        assert_eq!(annotation.ply, 18);
+        assert_eq!(
+            annotation.comment(),
+            "Theory ends here: Sicilian Defense: Najdorf Variation, Main Line"
+        );
+    }
+
+    #[test]
+    fn transpositions_are_found_past_gaps_in_the_book() {
+        // 2... d5 is not in the book, but 3. d4 transposes into the Queen's Gambit Declined.
+        let game = positions("1. c4 e6 2. Nc3 d5 3. d4 Nf6 4. h4 h5");
+        assert!(get_opening_from_setup(game[4].clone()).is_err());
+        assert_eq!(
+            find_opening_annotation(&game),
+            Some(OpeningAnnotation {
+                ply: 6,
+                name: "Queen's Gambit Declined: Normal Defense".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn custom_starts_and_returns_to_the_start_have_no_opening() {
+        let mut game = positions("1. e4 e5");
+        game.remove(0);
+        assert_eq!(find_opening_annotation(&game), None);
+
+        let game = positions("1. Nf3 Nf6 2. Ng1 Ng8");
+        // Back at the start is not an opening; the Zukertort Opening after 1... Nf6 is.
+        assert_eq!(find_opening_annotation(&game).map(|a| a.ply), Some(2));
+    }
 }
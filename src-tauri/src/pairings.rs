@@ -0,0 +1,1070 @@
+//! Pairings for club events, with the Swiss or round robin system.
+//!
+//! Events, their players and every board paired so far live in a SQLite database in the app
+//! data directory. Players are numbered from 0 in pairing order: by rating in Swiss events,
+//! and drawn by lot from the event's seed in round robins. Pairing a round only depends on
+//! the boards before it and on the seed, so the same results always give the same pairings.
+//! Once every round is played, the games can be added to a database as skeletons with their
+//! headers and results, for the moves to be entered later.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use specta::Type;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+use crate::AppState;
+
+const PAIRINGS_SQL: &str = include_str!("../../database/schema/pairings.sql");
+
+/// Pairings database, relative to the app data directory.
+const PAIRINGS_FILE: &str = "pairings.db3";
+
+/// Strength of a colour preference. Absolute preferences are only broken when no pairing
+/// keeps them all.
+const MILD: u8 = 1;
+const STRONG: u8 = 2;
+const ABSOLUTE: u8 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerRef {
+    pub name: String,
+    pub rating: Option<i32>,
+    /// The player in a database, when picked from one.
+    pub player_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum PairingSystem {
+    Swiss,
+    RoundRobin,
+}
+
+impl PairingSystem {
+    fn as_str(self) -> &'static str {
+        match self {
+            PairingSystem::Swiss => "swiss",
+            PairingSystem::RoundRobin => "roundRobin",
+        }
+    }
+
+    fn parse(system: &str) -> Option<Self> {
+        match system {
+            "swiss" => Some(PairingSystem::Swiss),
+            "roundRobin" => Some(PairingSystem::RoundRobin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum BoardResult {
+    WhiteWins,
+    Draw,
+    BlackWins,
+}
+
+impl BoardResult {
+    /// As written in the Result header of PGN.
+    pub fn as_pgn(self) -> &'static str {
+        match self {
+            BoardResult::WhiteWins => "1-0",
+            BoardResult::Draw => "1/2-1/2",
+            BoardResult::BlackWins => "0-1",
+        }
+    }
+
+    fn from_pgn(result: &str) -> Option<Self> {
+        match result {
+            "1-0" => Some(BoardResult::WhiteWins),
+            "1/2-1/2" => Some(BoardResult::Draw),
+            "0-1" => Some(BoardResult::BlackWins),
+            _ => None,
+        }
+    }
+
+    /// Half points scored by the player of `color`.
+    fn half_points(self, color: Color) -> u32 {
+        match (self, color) {
+            (BoardResult::Draw, _) => 1,
+            (BoardResult::WhiteWins, Color::White) | (BoardResult::BlackWins, Color::Black) => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// A board of a round, or a bye when there is no black player.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct Pairing {
+    pub round: u32,
+    /// From 1, with the bye last.
+    pub board: u32,
+    /// Pairing number of the white player, or of the player with the bye.
+    pub white: u32,
+    pub black: Option<u32>,
+    /// Unset until the game is played, and always for byes, which are worth a point.
+    pub result: Option<BoardResult>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingEvent {
+    pub id: i32,
+    pub name: String,
+    pub system: PairingSystem,
+    pub rounds: u32,
+    /// By pairing number.
+    pub players: Vec<PlayerRef>,
+    /// Every board paired so far, by round and board.
+    pub pairings: Vec<Pairing>,
+    #[serde(skip)]
+    seed: u64,
+}
+
+impl PairingEvent {
+    /// The last round paired, or 0 before the first.
+    pub fn current_round(&self) -> u32 {
+        self.pairings.last().map_or(0, |pairing| pairing.round)
+    }
+
+    fn round_complete(&self) -> bool {
+        self.pairings
+            .iter()
+            .filter(|pairing| pairing.round == self.current_round())
+            .all(|pairing| pairing.black.is_none() || pairing.result.is_some())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.current_round() == self.rounds && self.round_complete()
+    }
+
+    /// Pairings of the next round.
+    pub fn next_round(&self) -> Result<Vec<Pairing>, Error> {
+        if !self.round_complete() {
+            return Err(Error::InvalidPairing(format!(
+                "round {} has games without a result",
+                self.current_round()
+            )));
+        }
+        if self.current_round() >= self.rounds {
+            return Err(Error::InvalidPairing(format!(
+                "all {} rounds are paired",
+                self.rounds
+            )));
+        }
+        match self.system {
+            PairingSystem::Swiss => swiss_round(self.players.len(), &self.pairings, self.seed),
+            PairingSystem::RoundRobin => Ok(round_robin_round(
+                self.players.len(),
+                self.current_round() + 1,
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct Standing {
+    /// From 1.
+    pub rank: u32,
+    /// Pairing number of the player.
+    pub player: u32,
+    pub points: f32,
+    /// Games played with a result, not counting byes.
+    pub games: u32,
+    pub wins: u32,
+    /// Sum of the points of the opponents played.
+    pub buchholz: f32,
+    /// Sum of the points of the opponents beaten, and half those of the opponents drawn.
+    pub sonneborn_berger: f32,
+}
+
+/// What the pairings so far tell about a player.
+#[derive(Debug, Clone, Default)]
+struct Record {
+    half_points: u32,
+    /// Colours of the games played, in order.
+    colors: Vec<Color>,
+    opponents: HashSet<usize>,
+    byes: u32,
+}
+
+fn records(players: usize, pairings: &[Pairing]) -> Vec<Record> {
+    let mut records = vec![Record::default(); players];
+    for pairing in pairings {
+        let white = pairing.white as usize;
+        let Some(black) = pairing.black.map(|black| black as usize) else {
+            records[white].byes += 1;
+            records[white].half_points += 2;
+            continue;
+        };
+        records[white].colors.push(Color::White);
+        records[black].colors.push(Color::Black);
+        records[white].opponents.insert(black);
+        records[black].opponents.insert(white);
+        if let Some(result) = pairing.result {
+            records[white].half_points += result.half_points(Color::White);
+            records[black].half_points += result.half_points(Color::Black);
+        }
+    }
+    records
+}
+
+/// The colour a player should have next, and how much.
+///
+/// Nobody gets a colour three times in a row, or twice more than the other.
+fn color_preference(colors: &[Color]) -> Option<(Color, u8)> {
+    let last = *colors.last()?;
+    let whites = colors
+        .iter()
+        .filter(|color| **color == Color::White)
+        .count() as i32;
+    let difference = 2 * whites - colors.len() as i32;
+    let repeated = colors.len() >= 2 && colors[colors.len() - 2] == last;
+    Some(match difference {
+        d if d >= 2 => (Color::Black, ABSOLUTE),
+        d if d <= -2 => (Color::White, ABSOLUTE),
+        _ if repeated => (!last, ABSOLUTE),
+        1 => (Color::Black, STRONG),
+        -1 => (Color::White, STRONG),
+        _ => (!last, MILD),
+    })
+}
+
+struct SwissPairer<'a> {
+    records: &'a [Record],
+    preferences: Vec<Option<(Color, u8)>>,
+    /// Whether absolute colour preferences must be kept.
+    strict: bool,
+}
+
+impl SwissPairer<'_> {
+    fn can_meet(&self, a: usize, b: usize) -> bool {
+        if self.records[a].opponents.contains(&b) {
+            return false;
+        }
+        match (self.preferences[a], self.preferences[b]) {
+            (Some((color_a, ABSOLUTE)), Some((color_b, ABSOLUTE))) => {
+                !self.strict || color_a != color_b
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether `a` and `b` would like the same colour.
+    fn clash(&self, a: usize, b: usize) -> bool {
+        matches!(
+            (self.preferences[a], self.preferences[b]),
+            (Some((color_a, _)), Some((color_b, _))) if color_a == color_b
+        )
+    }
+
+    /// Pair the players of `order`, ranked best first. The higher ranked player of each pair
+    /// comes first, and pairs are in the order of their higher ranked player.
+    fn pair(&self, order: &[usize]) -> Option<Vec<(usize, usize)>> {
+        let Some((&top, rest)) = order.split_first() else {
+            return Some(Vec::new());
+        };
+        let points = |player: usize| self.records[player].half_points;
+        let group: Vec<usize> = rest
+            .iter()
+            .copied()
+            .filter(|&player| points(player) == points(top))
+            .collect();
+        // The top half of a score group meets its bottom half, counting `top` in the group.
+        let middle = (group.len() + 1) / 2;
+        let mut candidates: Vec<(usize, usize)> = rest
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, player)| self.can_meet(top, player))
+            .collect();
+        candidates.sort_by_key(|&(i, player)| {
+            let distance = match group.iter().position(|&other| other == player) {
+                Some(position) => (position + 1).abs_diff(middle),
+                None => i,
+            };
+            (
+                points(top).abs_diff(points(player)),
+                self.clash(top, player),
+                distance,
+            )
+        });
+        candidates.into_iter().find_map(|(i, opponent)| {
+            let mut remaining = rest.to_vec();
+            remaining.remove(i);
+            let mut pairs = self.pair(&remaining)?;
+            pairs.insert(0, (top, opponent));
+            Some(pairs)
+        })
+    }
+
+    /// Pair a whole round, giving a bye with an odd number of players.
+    fn pair_round(&self, order: &[usize]) -> Option<(Vec<(usize, usize)>, Option<usize>)> {
+        if order.len() % 2 == 0 {
+            return self.pair(order).map(|pairs| (pairs, None));
+        }
+        // The lowest ranked player without a bye, or with the fewest.
+        let mut candidates: Vec<usize> = order.iter().rev().copied().collect();
+        candidates.sort_by_key(|&player| self.records[player].byes);
+        candidates.into_iter().find_map(|bye| {
+            let rest: Vec<usize> = order.iter().copied().filter(|&p| p != bye).collect();
+            self.pair(&rest).map(|pairs| (pairs, Some(bye)))
+        })
+    }
+
+    /// The players `higher` and `lower` as white and black. The stronger preference wins,
+    /// and the higher ranked player's on equal strength. `toss` is for players without one.
+    fn colors(&self, higher: usize, lower: usize, toss: Color) -> (usize, usize) {
+        let color = match (self.preferences[higher], self.preferences[lower]) {
+            (Some((color_a, strength_a)), Some((color_b, strength_b)))
+                if color_a == color_b && strength_b > strength_a =>
+            {
+                !color_b
+            }
+            (Some((color_a, _)), _) => color_a,
+            (None, Some((color_b, _))) => !color_b,
+            (None, None) => toss,
+        };
+        match color {
+            Color::White => (higher, lower),
+            Color::Black => (lower, higher),
+        }
+    }
+}
+
+/// Colour of the higher ranked player on the first board of `round`, when neither player
+/// has one to ask for.
+fn toss(seed: u64, round: u32) -> Color {
+    Color::from_white(StdRng::seed_from_u64(seed ^ u64::from(round)).gen())
+}
+
+/// Pairings of the next round of a Swiss event, from the boards paired so far.
+///
+/// Players are ranked by score and then by pairing number, and the top half of each score
+/// group meets its bottom half. Nobody meets the same opponent twice, or plays against
+/// their absolute colour preference unless no pairing avoids it. The bye goes to the
+/// lowest ranked player who hasn't had one.
+pub fn swiss_round(players: usize, pairings: &[Pairing], seed: u64) -> Result<Vec<Pairing>, Error> {
+    let round = pairings.last().map_or(0, |pairing| pairing.round) + 1;
+    let records = records(players, pairings);
+    let mut order: Vec<usize> = (0..players).collect();
+    order.sort_by_key(|&player| Reverse(records[player].half_points));
+
+    let mut pairer = SwissPairer {
+        records: &records,
+        preferences: records
+            .iter()
+            .map(|record| color_preference(&record.colors))
+            .collect(),
+        strict: true,
+    };
+    let mut paired = pairer.pair_round(&order);
+    if paired.is_none() {
+        pairer.strict = false;
+        paired = pairer.pair_round(&order);
+    }
+    let (pairs, bye) = paired.ok_or_else(|| {
+        Error::InvalidPairing(format!("every pairing of round {} has a rematch", round))
+    })?;
+
+    let toss = toss(seed, round);
+    let mut boards: Vec<Pairing> = pairs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (higher, lower))| {
+            let toss = if i % 2 == 0 { toss } else { !toss };
+            let (white, black) = pairer.colors(higher, lower, toss);
+            Pairing {
+                round,
+                board: i as u32 + 1,
+                white: white as u32,
+                black: Some(black as u32),
+                result: None,
+            }
+        })
+        .collect();
+    if let Some(bye) = bye {
+        boards.push(Pairing {
+            round,
+            board: boards.len() as u32 + 1,
+            white: bye as u32,
+            black: None,
+            result: None,
+        });
+    }
+    Ok(boards)
+}
+
+/// Pairings of `round` of a round robin event, from the Berger tables.
+///
+/// With an odd number of players, whoever would meet the missing last player has a bye.
+/// Rounds after everyone has met start another cycle, with colours reversed.
+pub fn round_robin_round(players: usize, round: u32) -> Vec<Pairing> {
+    let n = players + players % 2;
+    let last = n - 1;
+    let cycle_round = (round as usize - 1) % last;
+    let reversed = (round as usize - 1) / last % 2 == 1;
+
+    let first = cycle_round * (n / 2) % last;
+    // The last player stays put, and alternates colours.
+    let mut pairs = vec![if cycle_round % 2 == 0 {
+        (first, last)
+    } else {
+        (last, first)
+    }];
+    for i in 1..n / 2 {
+        pairs.push(((first + i) % last, (first + last - i) % last));
+    }
+
+    let mut boards = Vec::with_capacity(n / 2);
+    let mut bye = None;
+    for (white, black) in pairs {
+        let (white, black) = if reversed {
+            (black, white)
+        } else {
+            (white, black)
+        };
+        if white == players || black == players {
+            bye = Some(white.min(black));
+            continue;
+        }
+        boards.push(Pairing {
+            round,
+            board: boards.len() as u32 + 1,
+            white: white as u32,
+            black: Some(black as u32),
+            result: None,
+        });
+    }
+    if let Some(bye) = bye {
+        boards.push(Pairing {
+            round,
+            board: boards.len() as u32 + 1,
+            white: bye as u32,
+            black: None,
+            result: None,
+        });
+    }
+    boards
+}
+
+/// Standings after the results so far, best first.
+///
+/// Ties on points are broken by Buchholz and then Sonneborn-Berger in Swiss events, by
+/// Sonneborn-Berger alone in round robins where everyone meets the same opponents, then by
+/// wins and pairing number.
+pub fn standings(event: &PairingEvent) -> Vec<Standing> {
+    let records = records(event.players.len(), &event.pairings);
+    // Tiebreaks in quarter points, so that they stay exact.
+    let mut buchholz = vec![0; records.len()];
+    let mut sonneborn_berger = vec![0; records.len()];
+    let mut games = vec![0; records.len()];
+    let mut wins = vec![0; records.len()];
+    for pairing in &event.pairings {
+        let (Some(black), Some(result)) = (pairing.black, pairing.result) else {
+            continue;
+        };
+        for (player, opponent, color) in [
+            (pairing.white as usize, black as usize, Color::White),
+            (black as usize, pairing.white as usize, Color::Black),
+        ] {
+            let opponent_points = records[opponent].half_points;
+            let scored = result.half_points(color);
+            games[player] += 1;
+            buchholz[player] += 2 * opponent_points;
+            sonneborn_berger[player] += scored * opponent_points;
+            if scored == 2 {
+                wins[player] += 1;
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..records.len()).collect();
+    order.sort_by_key(|&player| {
+        let points = records[player].half_points;
+        let tiebreaks = match event.system {
+            PairingSystem::Swiss => (buchholz[player], sonneborn_berger[player]),
+            PairingSystem::RoundRobin => (sonneborn_berger[player], 0),
+        };
+        Reverse((points, tiebreaks, wins[player]))
+    });
+    order
+        .into_iter()
+        .enumerate()
+        .map(|(i, player)| Standing {
+            rank: i as u32 + 1,
+            player: player as u32,
+            points: records[player].half_points as f32 / 2.0,
+            games: games[player],
+            wins: wins[player],
+            buchholz: buchholz[player] as f32 / 4.0,
+            sonneborn_berger: sonneborn_berger[player] as f32 / 4.0,
+        })
+        .collect()
+}
+
+fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The games of `event` as PGN without moves, with their headers and results.
+pub fn game_skeletons(event: &PairingEvent) -> String {
+    let mut pgn = String::new();
+    for pairing in &event.pairings {
+        let Some(black) = pairing.black else {
+            continue;
+        };
+        let players = [
+            ("White", &event.players[pairing.white as usize]),
+            ("Black", &event.players[black as usize]),
+        ];
+        let result = pairing.result.map_or("*", BoardResult::as_pgn);
+        pgn.push_str(&format!("[Event \"{}\"]\n", escape_header(&event.name)));
+        pgn.push_str(&format!(
+            "[Round \"{}.{}\"]\n",
+            pairing.round, pairing.board
+        ));
+        for (color, player) in players {
+            pgn.push_str(&format!(
+                "[{} \"{}\"]\n",
+                color,
+                escape_header(&player.name)
+            ));
+        }
+        for (color, player) in players {
+            if let Some(rating) = player.rating {
+                pgn.push_str(&format!("[{}Elo \"{}\"]\n", color, rating));
+            }
+        }
+        pgn.push_str(&format!("[Result \"{}\"]\n\n{}\n\n", result, result));
+    }
+    pgn
+}
+
+#[derive(QueryableByName)]
+struct StoredEvent {
+    #[diesel(sql_type = Integer, column_name = "ID")]
+    id: i32,
+    #[diesel(sql_type = Text, column_name = "Name")]
+    name: String,
+    #[diesel(sql_type = Text, column_name = "System")]
+    system: String,
+    #[diesel(sql_type = Integer, column_name = "Rounds")]
+    rounds: i32,
+    #[diesel(sql_type = BigInt, column_name = "Seed")]
+    seed: i64,
+}
+
+#[derive(QueryableByName)]
+struct StoredPlayer {
+    #[diesel(sql_type = Text, column_name = "Name")]
+    name: String,
+    #[diesel(sql_type = Nullable<Integer>, column_name = "Rating")]
+    rating: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>, column_name = "PlayerID")]
+    player_id: Option<i32>,
+}
+
+#[derive(QueryableByName)]
+struct StoredPairing {
+    #[diesel(sql_type = Integer, column_name = "Round")]
+    round: i32,
+    #[diesel(sql_type = Integer, column_name = "Board")]
+    board: i32,
+    #[diesel(sql_type = Integer, column_name = "White")]
+    white: i32,
+    #[diesel(sql_type = Nullable<Integer>, column_name = "Black")]
+    black: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>, column_name = "Result")]
+    result: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct InsertedId {
+    #[diesel(sql_type = Integer, column_name = "ID")]
+    id: i32,
+}
+
+/// Open the pairings database, creating it on first use.
+pub fn open_pairings(app: &tauri::AppHandle) -> Result<SqliteConnection, Error> {
+    let path = app.path().resolve(PAIRINGS_FILE, BaseDirectory::AppData)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut db = SqliteConnection::establish(&path.to_string_lossy())?;
+    db.batch_execute(PAIRINGS_SQL)?;
+    Ok(db)
+}
+
+/// Store a new event, numbering its players by rating in Swiss events and by lot in
+/// round robins.
+pub fn insert_event(
+    db: &mut SqliteConnection,
+    name: &str,
+    mut players: Vec<PlayerRef>,
+    system: PairingSystem,
+    rounds: u32,
+    seed: u64,
+    now: i64,
+) -> Result<PairingEvent, Error> {
+    if players.len() < 2 {
+        return Err(Error::InvalidPairing(
+            "an event needs at least two players".to_string(),
+        ));
+    }
+    if rounds == 0 || (system == PairingSystem::Swiss && rounds as usize >= players.len()) {
+        return Err(Error::InvalidPairing(format!(
+            "{} players can't play {} rounds",
+            players.len(),
+            rounds
+        )));
+    }
+    match system {
+        PairingSystem::Swiss => players.sort_by_key(|player| Reverse(player.rating)),
+        PairingSystem::RoundRobin => players.shuffle(&mut StdRng::seed_from_u64(seed)),
+    }
+
+    db.transaction::<_, Error, _>(|db| {
+        sql_query(
+            "INSERT INTO PairingEvents (Name, System, Rounds, Seed, CreatedAt) \
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind::<Text, _>(name)
+        .bind::<Text, _>(system.as_str())
+        .bind::<Integer, _>(rounds as i32)
+        .bind::<BigInt, _>(seed as i64)
+        .bind::<BigInt, _>(now)
+        .execute(db)?;
+        let id = sql_query("SELECT last_insert_rowid() AS ID")
+            .get_result::<InsertedId>(db)?
+            .id;
+        for (number, player) in players.iter().enumerate() {
+            sql_query(
+                "INSERT INTO PairingPlayers (EventID, Number, Name, Rating, PlayerID) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind::<Integer, _>(id)
+            .bind::<Integer, _>(number as i32)
+            .bind::<Text, _>(&player.name)
+            .bind::<Nullable<Integer>, _>(player.rating)
+            .bind::<Nullable<Integer>, _>(player.player_id)
+            .execute(db)?;
+        }
+        Ok(PairingEvent {
+            id,
+            name: name.to_string(),
+            system,
+            rounds,
+            players,
+            pairings: Vec::new(),
+            seed,
+        })
+    })
+}
+
+pub fn load_event(db: &mut SqliteConnection, id: i32) -> Result<PairingEvent, Error> {
+    let event = sql_query("SELECT ID, Name, System, Rounds, Seed FROM PairingEvents WHERE ID = ?")
+        .bind::<Integer, _>(id)
+        .get_result::<StoredEvent>(db)
+        .optional()?
+        .ok_or(Error::PairingEventNotFound(id))?;
+    let players = sql_query(
+        "SELECT Name, Rating, PlayerID FROM PairingPlayers WHERE EventID = ? ORDER BY Number",
+    )
+    .bind::<Integer, _>(id)
+    .load::<StoredPlayer>(db)?
+    .into_iter()
+    .map(|player| PlayerRef {
+        name: player.name,
+        rating: player.rating,
+        player_id: player.player_id,
+    })
+    .collect();
+    let pairings = sql_query(
+        "SELECT Round, Board, White, Black, Result FROM PairingGames \
+         WHERE EventID = ? ORDER BY Round, Board",
+    )
+    .bind::<Integer, _>(id)
+    .load::<StoredPairing>(db)?
+    .into_iter()
+    .map(|pairing| Pairing {
+        round: pairing.round as u32,
+        board: pairing.board as u32,
+        white: pairing.white as u32,
+        black: pairing.black.map(|black| black as u32),
+        result: pairing.result.as_deref().and_then(BoardResult::from_pgn),
+    })
+    .collect();
+    Ok(PairingEvent {
+        id: event.id,
+        name: event.name,
+        system: PairingSystem::parse(&event.system).unwrap_or(PairingSystem::Swiss),
+        rounds: event.rounds as u32,
+        players,
+        pairings,
+        seed: event.seed as u64,
+    })
+}
+
+/// Pair and store the next round of event `id`.
+pub fn pair_next_round(db: &mut SqliteConnection, id: i32) -> Result<Vec<Pairing>, Error> {
+    db.transaction::<_, Error, _>(|db| {
+        let boards = load_event(db, id)?.next_round()?;
+        for pairing in &boards {
+            sql_query(
+                "INSERT INTO PairingGames (EventID, Round, Board, White, Black) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind::<Integer, _>(id)
+            .bind::<Integer, _>(pairing.round as i32)
+            .bind::<Integer, _>(pairing.board as i32)
+            .bind::<Integer, _>(pairing.white as i32)
+            .bind::<Nullable<Integer>, _>(pairing.black.map(|black| black as i32))
+            .execute(db)?;
+        }
+        Ok(boards)
+    })
+}
+
+/// Set the result of `board` in the current round of event `id`.
+pub fn set_result(
+    db: &mut SqliteConnection,
+    id: i32,
+    board: u32,
+    result: BoardResult,
+) -> Result<Pairing, Error> {
+    let event = load_event(db, id)?;
+    let round = event.current_round();
+    let mut pairing = event
+        .pairings
+        .into_iter()
+        .find(|pairing| pairing.round == round && pairing.board == board)
+        .ok_or_else(|| Error::InvalidPairing(format!("round {} has no board {}", round, board)))?;
+    if pairing.black.is_none() {
+        return Err(Error::InvalidPairing(format!("board {} is a bye", board)));
+    }
+    sql_query("UPDATE PairingGames SET Result = ? WHERE EventID = ? AND Round = ? AND Board = ?")
+        .bind::<Text, _>(result.as_pgn())
+        .bind::<Integer, _>(id)
+        .bind::<Integer, _>(round as i32)
+        .bind::<Integer, _>(board as i32)
+        .execute(db)?;
+    pairing.result = Some(result);
+    Ok(pairing)
+}
+
+/// Create an event, drawing the seed it is paired with.
+#[tauri::command]
+#[specta::specta]
+pub async fn create_event(
+    name: String,
+    players: Vec<PlayerRef>,
+    system: PairingSystem,
+    rounds: u32,
+    app: tauri::AppHandle,
+) -> Result<PairingEvent, Error> {
+    let mut db = open_pairings(&app)?;
+    insert_event(
+        &mut db,
+        name.trim(),
+        players,
+        system,
+        rounds,
+        rand::random(),
+        chrono::Utc::now().timestamp(),
+    )
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_pairing_event(
+    event_id: i32,
+    app: tauri::AppHandle,
+) -> Result<PairingEvent, Error> {
+    let mut db = open_pairings(&app)?;
+    load_event(&mut db, event_id)
+}
+
+/// Pair the next round, once every game of the current one has a result.
+#[tauri::command]
+#[specta::specta]
+pub async fn generate_next_round(
+    event_id: i32,
+    app: tauri::AppHandle,
+) -> Result<Vec<Pairing>, Error> {
+    let mut db = open_pairings(&app)?;
+    pair_next_round(&mut db, event_id)
+}
+
+/// Set the result of a board of the current round.
+#[tauri::command]
+#[specta::specta]
+pub async fn record_result(
+    event_id: i32,
+    board: u32,
+    result: BoardResult,
+    app: tauri::AppHandle,
+) -> Result<Pairing, Error> {
+    let mut db = open_pairings(&app)?;
+    set_result(&mut db, event_id, board, result)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_standings(event_id: i32, app: tauri::AppHandle) -> Result<Vec<Standing>, Error> {
+    let mut db = open_pairings(&app)?;
+    Ok(standings(&load_event(&mut db, event_id)?))
+}
+
+/// Add the games of a finished event to a database, creating it if needed. Returns the
+/// number of games added.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_event_games(
+    event_id: i32,
+    db_path: PathBuf,
+    state: tauri::State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<usize, Error> {
+    state.path_scope.check(&db_path)?;
+    let event = load_event(&mut open_pairings(&app)?, event_id)?;
+    if !event.is_finished() {
+        return Err(Error::InvalidPairing(format!(
+            "{} isn't finished",
+            event.name
+        )));
+    }
+    crate::db::import_pgn_games(&state, &db_path, &event.name, &game_skeletons(&event)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory_db;
+
+    fn players(count: usize) -> Vec<PlayerRef> {
+        (0..count)
+            .map(|i| PlayerRef {
+                name: format!("Player {}", i + 1),
+                rating: Some(2400 - 50 * i as i32),
+                player_id: None,
+            })
+            .collect()
+    }
+
+    fn event(system: PairingSystem, players: usize, rounds: u32, seed: u64) -> PairingEvent {
+        let mut db = memory_db(PAIRINGS_SQL);
+        insert_event(
+            &mut db,
+            "Club championship",
+            self::players(players),
+            system,
+            rounds,
+            seed,
+            0,
+        )
+        .unwrap()
+    }
+
+    /// Pair every round of `event`, with results drawn from `results`.
+    fn play(event: &mut PairingEvent, results: u64) {
+        let mut rng = StdRng::seed_from_u64(results);
+        while !event.is_finished() {
+            for mut pairing in event.next_round().unwrap() {
+                if pairing.black.is_some() {
+                    pairing.result = Some(match rng.gen_range(0..10) {
+                        0..=3 => BoardResult::WhiteWins,
+                        4..=6 => BoardResult::BlackWins,
+                        _ => BoardResult::Draw,
+                    });
+                }
+                event.pairings.push(pairing);
+            }
+        }
+    }
+
+    fn colors(event: &PairingEvent) -> Vec<Vec<Color>> {
+        records(event.players.len(), &event.pairings)
+            .into_iter()
+            .map(|record| record.colors)
+            .collect()
+    }
+
+    fn meetings(event: &PairingEvent) -> Vec<(u32, u32)> {
+        let mut meetings: Vec<(u32, u32)> = event
+            .pairings
+            .iter()
+            .filter_map(|pairing| {
+                let black = pairing.black?;
+                Some((pairing.white.min(black), pairing.white.max(black)))
+            })
+            .collect();
+        meetings.sort();
+        meetings
+    }
+
+    #[test]
+    fn round_robin_meets_everyone_once() {
+        for count in 2..=12 {
+            let mut event = event(
+                PairingSystem::RoundRobin,
+                count,
+                (count + count % 2) as u32 - 1,
+                7,
+            );
+            play(&mut event, 1);
+
+            let all: Vec<(u32, u32)> = (0..count as u32)
+                .flat_map(|a| (a + 1..count as u32).map(move |b| (a, b)))
+                .collect();
+            assert_eq!(meetings(&event), all, "{} players", count);
+            for colors in colors(&event) {
+                let whites = colors.iter().filter(|c| **c == Color::White).count();
+                assert!(
+                    whites.abs_diff(colors.len() - whites) <= 1,
+                    "{} players",
+                    count
+                );
+            }
+            let byes = event.pairings.iter().filter(|p| p.black.is_none()).count();
+            assert_eq!(byes, if count % 2 == 1 { count } else { 0 });
+        }
+
+        // The second cycle reverses the colours of the first.
+        let first = round_robin_round(6, 2);
+        let second = round_robin_round(6, 7);
+        for (a, b) in first.iter().zip(&second) {
+            assert_eq!((a.white, a.black), (b.black.unwrap(), Some(b.white)));
+        }
+    }
+
+    #[test]
+    fn swiss_has_no_rematches_and_balanced_colours() {
+        for (count, results) in [(10, 1), (10, 2), (9, 3), (9, 4), (16, 5)] {
+            let mut event = event(PairingSystem::Swiss, count, 5, results);
+            play(&mut event, results);
+
+            let meetings = meetings(&event);
+            let distinct: HashSet<_> = meetings.iter().collect();
+            assert_eq!(distinct.len(), meetings.len(), "{} players", count);
+            for colors in colors(&event) {
+                let whites = colors.iter().filter(|c| **c == Color::White).count();
+                assert!(whites.abs_diff(colors.len() - whites) <= 2);
+                assert!(!colors.windows(3).any(|w| w[0] == w[1] && w[1] == w[2]));
+            }
+            for player in 0..count as u32 {
+                let byes = event
+                    .pairings
+                    .iter()
+                    .filter(|p| p.white == player && p.black.is_none())
+                    .count();
+                assert!(byes <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn swiss_first_round_splits_the_field() {
+        let round = swiss_round(8, &[], 3).unwrap();
+        let pairs: Vec<(u32, u32)> = round
+            .iter()
+            .map(|p| (p.white.min(p.black.unwrap()), p.white.max(p.black.unwrap())))
+            .collect();
+        assert_eq!(pairs, [(0, 4), (1, 5), (2, 6), (3, 7)]);
+        // Colours alternate down the boards.
+        let top_white: Vec<bool> = round.iter().map(|p| p.white < p.black.unwrap()).collect();
+        assert_eq!(
+            top_white,
+            [top_white[0], !top_white[0], top_white[0], !top_white[0]]
+        );
+    }
+
+    #[test]
+    fn same_seed_same_pairings() {
+        let mut a = event(PairingSystem::Swiss, 11, 6, 42);
+        let mut b = event(PairingSystem::Swiss, 11, 6, 42);
+        play(&mut a, 9);
+        play(&mut b, 9);
+        assert_eq!(a.pairings, b.pairings);
+
+        let mut c = event(PairingSystem::RoundRobin, 8, 7, 42);
+        let mut d = event(PairingSystem::RoundRobin, 8, 7, 42);
+        play(&mut c, 9);
+        play(&mut d, 9);
+        assert_eq!(c.players, d.players);
+        assert_eq!(c.pairings, d.pairings);
+    }
+
+    #[test]
+    fn standings_break_ties() {
+        let mut event = event(PairingSystem::RoundRobin, 4, 3, 0);
+        let result = |white: u32, black: u32, result| Pairing {
+            round: 1,
+            board: 1,
+            white,
+            black: Some(black),
+            result: Some(result),
+        };
+        // 0 and 1 both score 2, but 0's wins came against stronger opponents.
+        event.pairings = vec![
+            result(0, 1, BoardResult::WhiteWins),
+            result(2, 3, BoardResult::Draw),
+            result(2, 0, BoardResult::WhiteWins),
+            result(1, 3, BoardResult::WhiteWins),
+            result(3, 0, BoardResult::BlackWins),
+            result(1, 2, BoardResult::WhiteWins),
+        ];
+        let standings = standings(&event);
+        let order: Vec<u32> = standings.iter().map(|s| s.player).collect();
+        assert_eq!(order, [0, 1, 2, 3]);
+        assert_eq!(standings[0].points, 2.0);
+        assert_eq!(standings[0].sonneborn_berger, 2.5);
+        assert_eq!(standings[1].sonneborn_berger, 2.0);
+        assert_eq!(standings[1].buchholz, 4.0);
+        assert_eq!(standings[3].wins, 0);
+    }
+
+    #[test]
+    fn events_are_stored() {
+        let mut db = memory_db(PAIRINGS_SQL);
+        let event =
+            insert_event(&mut db, "Rapid", players(5), PairingSystem::Swiss, 3, 1, 0).unwrap();
+        let round = pair_next_round(&mut db, event.id).unwrap();
+        assert_eq!(round.len(), 3);
+        assert!(pair_next_round(&mut db, event.id).is_err());
+        assert!(set_result(&mut db, event.id, 3, BoardResult::Draw).is_err());
+        set_result(&mut db, event.id, 1, BoardResult::WhiteWins).unwrap();
+        set_result(&mut db, event.id, 2, BoardResult::Draw).unwrap();
+
+        let stored = load_event(&mut db, event.id).unwrap();
+        assert_eq!(stored.players, event.players);
+        assert_eq!(stored.pairings[0].result, Some(BoardResult::WhiteWins));
+        assert_eq!(pair_next_round(&mut db, event.id).unwrap()[0].round, 2);
+        assert!(matches!(
+            load_event(&mut db, event.id + 1),
+            Err(Error::PairingEventNotFound(_))
+        ));
+
+        let skeletons = game_skeletons(&stored);
+        assert!(skeletons.contains("[Round \"1.1\"]"));
+        assert!(skeletons.contains("[Result \"1-0\"]\n\n1-0\n"));
+        assert_eq!(skeletons.matches("[Event \"Rapid\"]").count(), 2);
+    }
+}
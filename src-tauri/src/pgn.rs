@@ -4,7 +4,11 @@
     path::PathBuf,
 };
 
-use crate::{error::Error, AppState};
+use crate::{
+    error::Error,
+    notation::{localize_movetext, SanStyle},
+    AppState,
+};
 
 const GAME_OFFSET_FREQ: usize = 100;
 
@@ -137,6 +141,7 @@ pub async fn count_pgn_games(
     file: PathBuf,
     state: tauri::State<'_, AppState>,
 ) -> Result<i32, Error> {
+    state.path_scope.check(&file)?;
     let files_string = file.to_string_lossy().to_string();
 
     let file = File::open(&file)?;
@@ -170,6 +175,7 @@ pub async fn read_games(
     end: i32,
     state: tauri::State<'_, AppState>,
 ) -> Result<Vec<String>, Error> {
+    state.path_scope.check(&file)?;
     let file_r = File::open(&file)?;
     let file_str = file.to_string_lossy();
     let mut parser = PgnParser::new(file_r);
@@ -196,6 +202,7 @@ pub async fn delete_game(
     n: i32,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), Error> {
+    state.path_scope.check(&file)?;
     let file_r = File::open(&file)?;
 
     let mut parser = PgnParser::new(file_r.try_clone()?);
@@ -227,8 +234,11 @@ pub async fn write_game(
     file: PathBuf,
     n: i32,
     pgn: String,
+    tab: Option<String>,
+    localized: Option<SanStyle>,
     state: tauri::State<'_, AppState>,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
+    state.path_scope.check(&file)?;
     if !file.exists() {
         File::create(&file)?;
     }
@@ -254,5 +264,7 @@ pub async fn write_game(
 
     write_to_end(&mut tmpf, &mut file_w)?;
 
-    Ok(())
+    state.dirty_tabs.record_write(tab.as_deref(), &file);
+    // The file always gets English SAN; the localized game is only returned for display.
+    Ok(localized.map(|style| localize_movetext(&pgn, style)))
 }
@@ -570,7 +570,7 @@ fn ensure_puzzle_schema(db_path: &PathBuf) -> Result<(), Error> {
 }
 
 /// Parses puzzles from a PGN reader
-fn parse_puzzles_from_pgn<R: Read>(mut reader: R) -> Result<Vec<NewPuzzle>, Error> {
+pub(crate) fn parse_puzzles_from_pgn<R: Read>(mut reader: R) -> Result<Vec<NewPuzzle>, Error> {
     let mut puzzles = Vec::new();
     let mut current_puzzle = NewPuzzle::default();
     let mut in_puzzle = false;
@@ -662,13 +662,13 @@ fn parse_pgn_header(line: &str) -> Option<(String, String)> {
 /// Represents a new puzzle to be inserted into the database
 #[derive(diesel::Insertable, Default)]
 #[diesel(table_name = puzzles)]
-struct NewPuzzle {
-    fen: String,
-    moves: String,
-    rating: i32,
-    rating_deviation: i32,
-    popularity: i32,
-    nb_plays: i32,
+pub(crate) struct NewPuzzle {
+    pub(crate) fen: String,
+    pub(crate) moves: String,
+    pub(crate) rating: i32,
+    pub(crate) rating_deviation: i32,
+    pub(crate) popularity: i32,
+    pub(crate) nb_plays: i32,
 }
 
 impl NewPuzzle {
@@ -0,0 +1,662 @@
+//! Export of puzzles to PGN or to a printable worksheet.
+//!
+//! Puzzles are picked with the same filters as `get_puzzle`, sorted by rating and written as
+//! they are loaded, so large exports don't have to fit in memory.
+//!
+//! Following the Lichess puzzle format, a puzzle with an even number of moves starts with the
+//! opponent's move, which is played before the position is shown. Exported puzzles start from
+//! the position the solver sees.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use diesel::{
+    dsl::sql,
+    sql_query,
+    sql_types::{Bool, Integer, Nullable, Text},
+    Connection, QueryableByName, RunQueryDsl, SqliteConnection,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Deserialize;
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::UciMove, Board, CastlingMode, Chess, Color, EnPassantMode,
+    File as BoardFile, Position, Rank, Role, Square,
+};
+use specta::Type;
+use tauri::Emitter;
+
+use crate::{
+    error::Error,
+    notation::{localize_san, SanStyle},
+    AppState,
+};
+
+/// Puzzles loaded from the database at once.
+const EXPORT_CHUNK_SIZE: usize = 500;
+
+/// Size of a square in diagrams, in SVG units.
+const SQUARE_SIZE: u32 = 40;
+
+/// Which puzzles to export, as for `get_puzzle`.
+#[derive(Deserialize, Debug, Clone, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct PuzzleExportFilter {
+    pub min_rating: u16,
+    pub max_rating: u16,
+    /// Themes every exported puzzle must have. Only databases with a `themes` column, as in
+    /// the Lichess export, can be filtered by theme.
+    #[serde(default)]
+    pub themes: Vec<String>,
+    /// Number of puzzles to export, all of them if not set.
+    pub count: Option<u32>,
+    /// Pick the puzzles at random rather than in database order.
+    #[serde(default)]
+    pub random: bool,
+    /// Seed of the random pick, to export the same puzzles again.
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PuzzleExportFormat {
+    /// One game per puzzle, with the solution as mainline.
+    Pgn,
+    /// HTML pages of diagrams, with the solutions on the last page.
+    Worksheet {
+        diagrams_per_page: u32,
+        /// How pieces are written in the solutions, English SAN by default.
+        #[serde(default)]
+        notation: Option<SanStyle>,
+    },
+}
+
+#[derive(QueryableByName, Debug)]
+struct PuzzleRow {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Text)]
+    fen: String,
+    #[diesel(sql_type = Text)]
+    moves: String,
+    #[diesel(sql_type = Integer)]
+    rating: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    themes: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct PuzzleKey {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Integer)]
+    rating: i32,
+}
+
+/// A puzzle ready to be written, from the position the solver sees.
+struct ExportedPuzzle {
+    id: i32,
+    rating: i32,
+    themes: Option<String>,
+    position: Chess,
+    uci: Vec<String>,
+    san: Vec<String>,
+}
+
+impl ExportedPuzzle {
+    fn new(row: PuzzleRow) -> Result<Self, Error> {
+        let fen: Fen = row.fen.parse()?;
+        let mut position: Chess = fen.into_position(CastlingMode::Chess960)?;
+        let mut moves: Vec<&str> = row.moves.split_whitespace().collect();
+        if moves.len() % 2 == 0 && !moves.is_empty() {
+            let setup = moves.remove(0);
+            let m = UciMove::from_ascii(setup.as_bytes())?.to_move(&position)?;
+            position.play_unchecked(&m);
+        }
+
+        let mut san = Vec::with_capacity(moves.len());
+        let mut current = position.clone();
+        for uci in &moves {
+            let m = UciMove::from_ascii(uci.as_bytes())?.to_move(&current)?;
+            san.push(SanPlus::from_move_and_play_unchecked(&mut current, &m).to_string());
+        }
+        Ok(Self {
+            id: row.id,
+            rating: row.rating,
+            themes: row.themes.filter(|t| !t.trim().is_empty()),
+            position,
+            uci: moves.into_iter().map(str::to_string).collect(),
+            san,
+        })
+    }
+
+    fn fen(&self) -> String {
+        Fen::from_position(self.position.clone(), EnPassantMode::Legal).to_string()
+    }
+
+    /// The solution with move numbers, as in "12... Qxd5 13. Nf3".
+    fn movetext(&self) -> String {
+        let mut number = self.position.fullmoves().get();
+        let mut turn = self.position.turn();
+        let mut text = Vec::new();
+        for (i, san) in self.san.iter().enumerate() {
+            match turn {
+                Color::White => text.push(format!("{}.", number)),
+                Color::Black if i == 0 => text.push(format!("{}...", number)),
+                Color::Black => {}
+            }
+            text.push(san.clone());
+            if turn == Color::Black {
+                number += 1;
+            }
+            turn = !turn;
+        }
+        text.join(" ")
+    }
+}
+
+fn has_themes_column(db: &mut SqliteConnection) -> Result<bool, Error> {
+    Ok(diesel::select(sql::<Bool>(
+        "EXISTS (SELECT 1 FROM pragma_table_info('puzzles') WHERE name = 'themes')",
+    ))
+    .get_result(db)?)
+}
+
+/// IDs of the puzzles to export, sorted by rating.
+fn pick_puzzles(
+    db: &mut SqliteConnection,
+    filter: &PuzzleExportFilter,
+    has_themes: bool,
+) -> Result<Vec<i32>, Error> {
+    if !filter.themes.is_empty() && !has_themes {
+        log::warn!("Puzzle database has no themes to filter by");
+        return Err(Error::NoPuzzles);
+    }
+    let mut query = String::from("SELECT id, rating FROM puzzles WHERE rating BETWEEN ? AND ?");
+    for _ in &filter.themes {
+        query.push_str(" AND (' ' || themes || ' ') LIKE ?");
+    }
+    query.push_str(" ORDER BY id");
+    let mut query = sql_query(query)
+        .into_boxed()
+        .bind::<Integer, _>(filter.min_rating as i32)
+        .bind::<Integer, _>(filter.max_rating as i32);
+    for theme in &filter.themes {
+        query = query.bind::<Text, _>(format!("% {} %", theme.trim()));
+    }
+    let mut keys: Vec<PuzzleKey> = query.load(db)?;
+
+    if filter.random {
+        let seed = filter.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        keys.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+    if let Some(count) = filter.count {
+        keys.truncate(count as usize);
+    }
+    keys.sort_by_key(|key| (key.rating, key.id));
+    Ok(keys.into_iter().map(|key| key.id).collect())
+}
+
+/// Load puzzles by ID, in the order given.
+fn load_puzzles(
+    db: &mut SqliteConnection,
+    ids: &[i32],
+    has_themes: bool,
+) -> Result<Vec<PuzzleRow>, Error> {
+    let list = ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let themes = if has_themes { "themes" } else { "NULL" };
+    let mut rows: Vec<PuzzleRow> = sql_query(format!(
+        "SELECT id, fen, moves, rating, {} AS themes FROM puzzles WHERE id IN ({})",
+        themes, list
+    ))
+    .load(db)?;
+    rows.sort_by_key(|row| ids.iter().position(|id| *id == row.id));
+    Ok(rows)
+}
+
+fn escape_pgn(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// SVG diagram of `board`, seen from `orientation`.
+fn board_svg(board: &Board, orientation: Color) -> String {
+    let size = 8 * SQUARE_SIZE;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" class="board">"#
+    );
+    for row in 0..8u32 {
+        for col in 0..8u32 {
+            let (file, rank) = match orientation {
+                Color::White => (col, 7 - row),
+                Color::Black => (7 - col, row),
+            };
+            let (x, y) = (col * SQUARE_SIZE, row * SQUARE_SIZE);
+            let fill = if (file + rank) % 2 == 1 {
+                "#f0d9b5"
+            } else {
+                "#b58863"
+            };
+            svg.push_str(&format!(
+                r#"<rect x="{x}" y="{y}" width="{SQUARE_SIZE}" height="{SQUARE_SIZE}" fill="{fill}"/>"#
+            ));
+            let square = Square::from_coords(BoardFile::new(file), Rank::new(rank));
+            if let Some(piece) = board.piece_at(square) {
+                // Outlined glyphs for White, solid ones for Black, which print well in black
+                // and white.
+                let glyph = match (piece.color, piece.role) {
+                    (Color::White, Role::King) => '♔',
+                    (Color::White, Role::Queen) => '♕',
+                    (Color::White, Role::Rook) => '♖',
+                    (Color::White, Role::Bishop) => '♗',
+                    (Color::White, Role::Knight) => '♘',
+                    (Color::White, Role::Pawn) => '♙',
+                    (Color::Black, Role::King) => '♚',
+                    (Color::Black, Role::Queen) => '♛',
+                    (Color::Black, Role::Rook) => '♜',
+                    (Color::Black, Role::Bishop) => '♝',
+                    (Color::Black, Role::Knight) => '♞',
+                    (Color::Black, Role::Pawn) => '♟',
+                };
+                svg.push_str(&format!(
+                    r#"<text x="{}" y="{}" font-size="{}" text-anchor="middle" dominant-baseline="central">{}</text>"#,
+                    x + SQUARE_SIZE / 2,
+                    y + SQUARE_SIZE / 2,
+                    SQUARE_SIZE * 4 / 5,
+                    glyph
+                ));
+            }
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+const WORKSHEET_STYLE: &str = "body { font-family: sans-serif; margin: 0; }
+.page { page-break-after: always; padding: 1cm; }
+.page:last-child { page-break-after: auto; }
+.diagrams { display: grid; grid-template-columns: repeat(2, 1fr); gap: 0.8cm; }
+figure { margin: 0; }
+figcaption { margin-top: 0.2cm; font-size: 11pt; }
+.board { width: 100%; max-width: 8cm; border: 1px solid #333; }
+.solutions li { margin-bottom: 0.2cm; }";
+
+/// Writes exported puzzles one at a time.
+enum PuzzleWriter<'a, W: Write> {
+    Pgn(&'a mut W),
+    Worksheet {
+        out: &'a mut W,
+        per_page: usize,
+        notation: SanStyle,
+        /// Solution of each puzzle written, for the key on the last page.
+        solutions: Vec<String>,
+    },
+}
+
+impl<'a, W: Write> PuzzleWriter<'a, W> {
+    fn new(format: PuzzleExportFormat, out: &'a mut W) -> Result<Self, Error> {
+        Ok(match format {
+            PuzzleExportFormat::Pgn => PuzzleWriter::Pgn(out),
+            PuzzleExportFormat::Worksheet {
+                diagrams_per_page,
+                notation,
+            } => {
+                write!(
+                    out,
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <title>Puzzles</title>\n<style>\n{}\n</style>\n</head>\n<body>\n",
+                    WORKSHEET_STYLE
+                )?;
+                PuzzleWriter::Worksheet {
+                    out,
+                    per_page: diagrams_per_page.max(1) as usize,
+                    notation: notation.unwrap_or_default(),
+                    solutions: Vec::new(),
+                }
+            }
+        })
+    }
+
+    fn write(&mut self, puzzle: &ExportedPuzzle) -> Result<(), Error> {
+        match self {
+            PuzzleWriter::Pgn(out) => {
+                writeln!(out, "[Event \"Puzzle {}\"]", puzzle.id)?;
+                writeln!(out, "[Site \"?\"]")?;
+                writeln!(out, "[Date \"????.??.??\"]")?;
+                writeln!(out, "[White \"?\"]")?;
+                writeln!(out, "[Black \"?\"]")?;
+                writeln!(out, "[Result \"*\"]")?;
+                writeln!(out, "[SetUp \"1\"]")?;
+                writeln!(out, "[FEN \"{}\"]", puzzle.fen())?;
+                writeln!(out, "[PuzzleId \"{}\"]", puzzle.id)?;
+                writeln!(out, "[Rating \"{}\"]", puzzle.rating)?;
+                if let Some(themes) = &puzzle.themes {
+                    writeln!(out, "[Themes \"{}\"]", escape_pgn(themes))?;
+                }
+                writeln!(out, "[Solution \"{}\"]", puzzle.uci.join(" "))?;
+                writeln!(out)?;
+                writeln!(out, "{} *", puzzle.movetext())?;
+                writeln!(out)?;
+            }
+            PuzzleWriter::Worksheet {
+                out,
+                per_page,
+                notation,
+                solutions,
+            } => {
+                let number = solutions.len() + 1;
+                if solutions.len() % *per_page == 0 {
+                    if !solutions.is_empty() {
+                        writeln!(out, "</div>\n</section>")?;
+                    }
+                    writeln!(out, "<section class=\"page\">\n<div class=\"diagrams\">")?;
+                }
+                let to_move = match puzzle.position.turn() {
+                    Color::White => "White to move",
+                    Color::Black => "Black to move",
+                };
+                writeln!(
+                    out,
+                    "<figure>{}<figcaption>{}. {} ({})</figcaption></figure>",
+                    board_svg(puzzle.position.board(), puzzle.position.turn()),
+                    number,
+                    to_move,
+                    puzzle.rating
+                )?;
+                solutions.push(localize_san(&puzzle.movetext(), *notation));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            PuzzleWriter::Pgn(out) => out.flush()?,
+            PuzzleWriter::Worksheet { out, solutions, .. } => {
+                if !solutions.is_empty() {
+                    writeln!(out, "</div>\n</section>")?;
+                }
+                writeln!(
+                    out,
+                    "<section class=\"page solutions\">\n<h2>Solutions</h2>\n<ol>"
+                )?;
+                for solution in &solutions {
+                    writeln!(out, "<li>{}</li>", escape_html(solution))?;
+                }
+                writeln!(out, "</ol>\n</section>\n</body>\n</html>")?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the puzzles of `db` picked by `filter` to `out`, reporting the number written so
+/// far and the total after each chunk. Puzzles that can't be replayed are skipped.
+///
+/// Returns the number of puzzles written.
+fn write_puzzles<W: Write>(
+    db: &mut SqliteConnection,
+    filter: &PuzzleExportFilter,
+    format: PuzzleExportFormat,
+    out: &mut W,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<usize, Error> {
+    let has_themes = has_themes_column(db)?;
+    let ids = pick_puzzles(db, filter, has_themes)?;
+    if ids.is_empty() {
+        return Err(Error::NoPuzzles);
+    }
+
+    let mut writer = PuzzleWriter::new(format, out)?;
+    let mut written = 0;
+    for (i, chunk) in ids.chunks(EXPORT_CHUNK_SIZE).enumerate() {
+        for row in load_puzzles(db, chunk, has_themes)? {
+            let id = row.id;
+            match ExportedPuzzle::new(row) {
+                Ok(puzzle) => {
+                    writer.write(&puzzle)?;
+                    written += 1;
+                }
+                Err(e) => log::warn!("Skipping puzzle {}: {}", id, e),
+            }
+        }
+        progress(((i + 1) * EXPORT_CHUNK_SIZE).min(ids.len()), ids.len());
+    }
+    writer.finish()?;
+    Ok(written)
+}
+
+/// Exports puzzles to a PGN file or a printable HTML worksheet
+///
+/// Progress is reported with `export_puzzle_progress` events, as (exported, total).
+///
+/// # Arguments
+/// * `file` - Path to the puzzle database
+/// * `filter` - Which puzzles to export
+/// * `format` - PGN or worksheet
+/// * `out_path` - File to write
+///
+/// # Returns
+/// * `Ok(count)` with the number of puzzles exported
+/// * `Err(Error::NoPuzzles)` if no puzzles match the filter
+#[tauri::command]
+#[specta::specta]
+pub async fn export_puzzles(
+    file: String,
+    filter: PuzzleExportFilter,
+    format: PuzzleExportFormat,
+    out_path: PathBuf,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<u32, Error> {
+    state.path_scope.check(&out_path)?;
+    let mut db = SqliteConnection::establish(&file)?;
+    let mut out = BufWriter::new(File::create(&out_path)?);
+    let written = write_puzzles(&mut db, &filter, format, &mut out, |exported, total| {
+        let _ = app.emit("export_puzzle_progress", (exported, total));
+    })?;
+    Ok(written as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory_db;
+    use diesel::connection::SimpleConnection;
+
+    const PUZZLES_TABLES: &str = include_str!("../../database/schema/puzzles_tables.sql");
+
+    /// (fen, moves, rating, themes)
+    const PUZZLES: [(&str, &str, i32, &str); 4] = [
+        // Lichess style: Black plays first, then White mates.
+        (
+            "r1bqkbnr/pppp1ppp/2n5/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR b KQkq - 3 3",
+            "g8f6 h5f7",
+            1200,
+            "mateIn1 short",
+        ),
+        // Starts with the solver to move.
+        (
+            "6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1",
+            "a1a8",
+            900,
+            "mateIn1 backRankMate",
+        ),
+        (
+            "r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4",
+            "h5f7",
+            1500,
+            "mateIn1",
+        ),
+        // Illegal, skipped.
+        ("8/8/8/8/8/8/8/K6k w - - 0 1", "a1a8", 1000, "broken"),
+    ];
+
+    fn puzzle_db(themes: bool) -> SqliteConnection {
+        let mut db = memory_db(PUZZLES_TABLES);
+        if themes {
+            db.batch_execute("ALTER TABLE puzzles ADD COLUMN themes TEXT")
+                .unwrap();
+        }
+        for (fen, moves, rating, puzzle_themes) in PUZZLES {
+            sql_query("INSERT INTO puzzles (fen, moves, rating) VALUES (?, ?, ?)")
+                .bind::<Text, _>(fen)
+                .bind::<Text, _>(moves)
+                .bind::<Integer, _>(rating)
+                .execute(&mut db)
+                .unwrap();
+            if themes {
+                sql_query("UPDATE puzzles SET themes = ? WHERE id = last_insert_rowid()")
+                    .bind::<Text, _>(puzzle_themes)
+                    .execute(&mut db)
+                    .unwrap();
+            }
+        }
+        db
+    }
+
+    fn filter() -> PuzzleExportFilter {
+        PuzzleExportFilter {
+            min_rating: 0,
+            max_rating: 3000,
+            ..Default::default()
+        }
+    }
+
+    fn export(
+        db: &mut SqliteConnection,
+        filter: &PuzzleExportFilter,
+        format: PuzzleExportFormat,
+    ) -> String {
+        let mut out = Vec::new();
+        write_puzzles(db, filter, format, &mut out, |_, _| {}).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn pgn_exports_round_trip_through_the_puzzle_importer() {
+        let mut db = puzzle_db(true);
+        let pgn = export(&mut db, &filter(), PuzzleExportFormat::Pgn);
+
+        // Sorted by rating, starting from the position shown to the solver.
+        assert!(pgn.find("Puzzle 2").unwrap() < pgn.find("Puzzle 1").unwrap());
+        assert!(pgn.contains(
+            "[FEN \"r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4\"]"
+        ));
+        assert!(pgn.contains("[Themes \"mateIn1 backRankMate\"]"));
+        assert!(pgn.contains("4. Qxf7# *"));
+        assert!(!pgn.contains("Puzzle 4"));
+
+        let puzzles = crate::puzzle::parse_puzzles_from_pgn(pgn.as_bytes()).unwrap();
+        let reimported: Vec<_> = puzzles
+            .iter()
+            .map(|p| (p.moves.as_str(), p.rating))
+            .collect();
+        assert_eq!(
+            reimported,
+            vec![("a1a8", 900), ("h5f7", 1200), ("h5f7", 1500)]
+        );
+    }
+
+    #[test]
+    fn worksheets_have_a_diagram_per_puzzle_and_a_solution_key() {
+        let mut db = puzzle_db(false);
+        let html = export(
+            &mut db,
+            &filter(),
+            PuzzleExportFormat::Worksheet {
+                diagrams_per_page: 2,
+                notation: None,
+            },
+        );
+        assert_eq!(html.matches("<svg").count(), 3);
+        // Two pages of diagrams, then the solutions.
+        assert_eq!(html.matches("<section class=\"page\">").count(), 2);
+        let key = &html[html.find("Solutions").unwrap()..];
+        assert_eq!(key.matches("<li>").count(), 3);
+        assert!(key.contains("<li>1. Ra8#</li>"));
+        assert!(key.contains("<li>4. Qxf7#</li>"));
+        assert!(html.contains("1. White to move (900)"));
+    }
+
+    #[test]
+    fn worksheet_solutions_in_figurines() {
+        let mut db = puzzle_db(false);
+        let html = export(
+            &mut db,
+            &filter(),
+            PuzzleExportFormat::Worksheet {
+                diagrams_per_page: 2,
+                notation: Some(SanStyle::Figurine),
+            },
+        );
+        let key = &html[html.find("Solutions").unwrap()..];
+        assert!(key.contains("<li>1. ♖a8#</li>"));
+        assert!(key.contains("<li>4. ♕xf7#</li>"));
+    }
+
+    #[test]
+    fn filters_pick_the_same_puzzles_for_the_same_seed() {
+        let mut db = puzzle_db(true);
+        let has_themes = has_themes_column(&mut db).unwrap();
+
+        let ranged = PuzzleExportFilter {
+            min_rating: 1000,
+            max_rating: 1500,
+            ..Default::default()
+        };
+        assert_eq!(pick_puzzles(&mut db, &ranged, has_themes).unwrap().len(), 3);
+
+        let themed = PuzzleExportFilter {
+            themes: vec!["mateIn1".to_string()],
+            ..filter()
+        };
+        assert_eq!(
+            pick_puzzles(&mut db, &themed, has_themes).unwrap(),
+            vec![2, 1, 3]
+        );
+
+        let seeded = PuzzleExportFilter {
+            count: Some(2),
+            random: true,
+            seed: Some(7),
+            ..filter()
+        };
+        let picked = pick_puzzles(&mut db, &seeded, has_themes).unwrap();
+        assert_eq!(picked.len(), 2);
+        assert_eq!(pick_puzzles(&mut db, &seeded, has_themes).unwrap(), picked);
+
+        // Without a themes column, nothing matches a theme.
+        let mut db = puzzle_db(false);
+        assert!(matches!(
+            pick_puzzles(&mut db, &themed, false),
+            Err(Error::NoPuzzles)
+        ));
+    }
+
+    #[test]
+    fn diagrams_follow_the_side_to_move() {
+        let position = Chess::default();
+        let white = board_svg(position.board(), Color::White);
+        let black = board_svg(position.board(), Color::Black);
+        assert_eq!(white.matches("<rect").count(), 64);
+        assert_eq!(white.matches("<text").count(), 32);
+        // The first piece drawn is a8 for White, h1 for Black.
+        assert!(white.find('♜').unwrap() < white.find('♖').unwrap());
+        assert!(black.find('♖').unwrap() < black.find('♜').unwrap());
+    }
+}
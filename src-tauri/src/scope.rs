@@ -0,0 +1,248 @@
+//! Scoping of the paths commands may touch.
+//!
+//! Commands receiving a path (engines, databases, PGN files) only accept it below a root the
+//! user approved: the app data directory, which holds installed engines and databases, the
+//! documents directory, and files or folders authorized with `authorize_path`. The frontend
+//! authorizes the files picked in the native file dialog, and asks for confirmation before
+//! authorizing anything else, so a deep link or a compromised renderer can't make the backend
+//! read or run arbitrary files.
+//!
+//! Paths are compared once canonicalized, which resolves `..` and symbolic links, and matched
+//! by whole components. Approved roots are saved in the app config directory.
+
+use std::fs::create_dir_all;
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+
+use log::info;
+use tauri::{path::BaseDirectory, Manager};
+
+use crate::error::Error;
+use crate::AppState;
+
+/// Roots authorized by the user, relative to the app config directory.
+const ALLOWED_PATHS_FILE: &str = "allowed_paths.json";
+
+/// Canonical form of `path`, following links. Paths that don't exist yet, such as a database
+/// about to be created, are resolved through their parent directory.
+fn canonical(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Some(path);
+    }
+    let name = match path.components().next_back()? {
+        Component::Normal(name) => name,
+        _ => return None,
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(parent.canonicalize().ok()?.join(name))
+}
+
+/// Directories and files commands are allowed to use.
+#[derive(Default)]
+pub struct PathScope {
+    /// Canonical roots.
+    roots: RwLock<Vec<PathBuf>>,
+    /// Roots authorized by the user, as saved.
+    authorized: RwLock<Vec<PathBuf>>,
+}
+
+impl PathScope {
+    /// Allow everything below `root` for this session. Returns the canonical root.
+    fn allow(&self, root: &Path) -> Option<PathBuf> {
+        let root = canonical(root)?;
+        let mut roots = self.roots.write().unwrap();
+        if !roots.contains(&root) {
+            roots.push(root.clone());
+        }
+        Some(root)
+    }
+
+    /// A scope allowing only what is below `root`.
+    #[cfg(test)]
+    pub fn for_root(root: &Path) -> Self {
+        let scope = PathScope::default();
+        scope.allow(root).unwrap();
+        scope
+    }
+
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        let Some(path) = canonical(path) else {
+            return false;
+        };
+        self.roots
+            .read()
+            .unwrap()
+            .iter()
+            .any(|root| path.starts_with(root))
+    }
+
+    /// Fail with `PathNotAuthorized` unless `path` is below an allowed root.
+    pub fn check(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        if self.is_allowed(path) {
+            Ok(())
+        } else {
+            Err(Error::PathNotAuthorized(path.display().to_string()))
+        }
+    }
+
+    /// Like `check`, for an engine: the built-in engine has no path to check.
+    pub fn check_engine(&self, engine: impl AsRef<Path>) -> Result<(), Error> {
+        if engine.as_ref().as_os_str() == crate::chess::BUILTIN_ENGINE {
+            return Ok(());
+        }
+        self.check(engine)
+    }
+
+    /// Allow the app's own directories and the roots the user authorized before.
+    pub fn restore(&self, app: &tauri::AppHandle) -> Result<(), Error> {
+        for dir in [app.path().app_data_dir(), app.path().document_dir()]
+            .into_iter()
+            .flatten()
+        {
+            create_dir_all(&dir).ok();
+            self.allow(&dir);
+        }
+
+        let path = app
+            .path()
+            .resolve(ALLOWED_PATHS_FILE, BaseDirectory::AppConfig)?;
+        if path.exists() {
+            let saved: Vec<PathBuf> = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            for root in saved {
+                if let Some(root) = self.allow(&root) {
+                    self.authorized.write().unwrap().push(root);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Allow `path` from now on and save it with the other authorized roots.
+    fn authorize(&self, app: &tauri::AppHandle, path: &Path) -> Result<PathBuf, Error> {
+        let root = self
+            .allow(path)
+            .ok_or_else(|| Error::PathNotAuthorized(path.display().to_string()))?;
+        let saved = {
+            let mut authorized = self.authorized.write().unwrap();
+            if authorized.contains(&root) {
+                return Ok(root);
+            }
+            authorized.push(root.clone());
+            serde_json::to_string(&*authorized)?
+        };
+
+        let file = app
+            .path()
+            .resolve(ALLOWED_PATHS_FILE, BaseDirectory::AppConfig)?;
+        if let Some(parent) = file.parent() {
+            create_dir_all(parent)?;
+        }
+        std::fs::write(file, saved)?;
+        info!("Authorized path: {}", root.display());
+        Ok(root)
+    }
+}
+
+/// Allow commands to use `path`, a file or a directory with everything below it.
+///
+/// Only called once the user explicitly chose or confirmed the path. Returns the canonical
+/// path that was authorized.
+#[tauri::command]
+#[specta::specta]
+pub fn authorize_path(
+    path: PathBuf,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<PathBuf, Error> {
+    state.path_scope.authorize(&app, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scoped(root: &Path) -> PathScope {
+        PathScope::for_root(root)
+    }
+
+    #[test]
+    fn paths_below_a_root_are_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("databases");
+        create_dir_all(root.join("openings")).unwrap();
+        let scope = scoped(&root);
+
+        assert!(scope.is_allowed(&root));
+        assert!(scope.is_allowed(&root.join("openings")));
+        // Files about to be created are checked through their directory.
+        assert!(scope.is_allowed(&root.join("new.db3")));
+        assert!(scope.check(root.join("openings/new.db3")).is_ok());
+
+        assert!(!scope.is_allowed(dir.path()));
+        assert!(!scope.is_allowed(&dir.path().join("engine")));
+        // Prefixes only match whole components.
+        create_dir_all(dir.path().join("databases-old")).unwrap();
+        assert!(!scope.is_allowed(&dir.path().join("databases-old")));
+        assert!(matches!(
+            scope.check(dir.path().join("engine")),
+            Err(Error::PathNotAuthorized(_))
+        ));
+    }
+
+    #[test]
+    fn parent_components_cannot_escape_a_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("databases");
+        create_dir_all(&root).unwrap();
+        std::fs::write(dir.path().join("secret"), "").unwrap();
+        let scope = scoped(&root);
+
+        assert!(!scope.is_allowed(&root.join("../secret")));
+        assert!(!scope.is_allowed(&root.join("..")));
+        assert!(!scope.is_allowed(&root.join("missing/../../secret")));
+        assert!(scope.is_allowed(&root.join("../databases/games.db3")));
+        assert!(!scope.is_allowed(Path::new("")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_are_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("databases");
+        let outside = dir.path().join("outside");
+        create_dir_all(&root).unwrap();
+        create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("engine"), "").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("engine"), root.join("engine")).unwrap();
+        let scope = scoped(&root);
+
+        assert!(!scope.is_allowed(&root.join("link/engine")));
+        assert!(!scope.is_allowed(&root.join("engine")));
+        assert!(!scope.is_allowed(&root.join("link/new.db3")));
+
+        // A root reached through a link covers the real directory.
+        let linked_root = dir.path().join("linked");
+        std::os::unix::fs::symlink(&outside, &linked_root).unwrap();
+        let scope = scoped(&linked_root);
+        assert!(scope.is_allowed(&outside.join("engine")));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn unc_paths_do_not_alias_local_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let scope = scoped(dir.path());
+        let local = dir.path().canonicalize().unwrap();
+        let local = local.to_string_lossy();
+        // `\\?\C:\...` reached again through the administrative share.
+        let unc = format!(r"\\localhost\{}$\{}", &local[4..5], &local[7..]);
+        assert!(!scope.is_allowed(Path::new(&unc)));
+        assert!(!scope.is_allowed(Path::new(r"\\server\share\engine.exe")));
+        assert!(scope.is_allowed(&dir.path().join("games.db3")));
+    }
+}
@@ -0,0 +1,275 @@
+//! Background task tracking.
+//!
+//! Long-running commands (position searches, game analysis, downloads, database
+//! maintenance) register themselves in the `TaskRegistry` and report their progress
+//! through a single `TaskProgress` event, so the frontend can show everything currently
+//! running in one place. Tasks that support it can be cancelled with `cancel_task`.
+//!
+//! The older per-feature events (`DatabaseProgress`, `DownloadProgress`, `ReportProgress`
+//! and `search_progress`) are still emitted alongside `TaskProgress` for compatibility.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::Manager;
+use tauri_specta::Event as _;
+
+use crate::{error::Error, AppState};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskKind {
+    Search,
+    Analysis,
+    Download,
+    Database,
+}
+
+/// Progress of a background task.
+#[derive(Serialize, Debug, Clone, Type, tauri_specta::Event)]
+pub struct TaskProgress {
+    pub id: String,
+    pub kind: TaskKind,
+    /// Percentage, or a negative value when the total amount of work is unknown.
+    pub progress: f64,
+    /// What the task is currently doing, e.g. the name of the index being built.
+    pub phase: Option<String>,
+    pub finished: bool,
+    /// Set when the task finished without completing.
+    pub error: Option<String>,
+}
+
+/// A task currently running.
+#[derive(Serialize, Debug, Clone, Type)]
+pub struct ActiveTask {
+    pub id: String,
+    pub kind: TaskKind,
+    pub progress: f64,
+    pub phase: Option<String>,
+    pub cancellable: bool,
+    /// Unix timestamp in milliseconds.
+    pub started_at: i64,
+}
+
+struct TaskEntry {
+    task: ActiveTask,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Tasks currently running, keyed by kind and id.
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: DashMap<(TaskKind, String), TaskEntry>,
+}
+
+impl TaskRegistry {
+    /// Register a task, replacing any previous task of the same kind and id.
+    fn register(&self, kind: TaskKind, id: &str, cancellable: bool) -> Arc<AtomicBool> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.tasks.insert(
+            (kind, id.to_string()),
+            TaskEntry {
+                task: ActiveTask {
+                    id: id.to_string(),
+                    kind,
+                    progress: 0.0,
+                    phase: None,
+                    cancellable,
+                    started_at: chrono::Utc::now().timestamp_millis(),
+                },
+                cancelled: cancelled.clone(),
+            },
+        );
+        cancelled
+    }
+
+    fn update(&self, progress: &TaskProgress, token: &Arc<AtomicBool>) {
+        let key = (progress.kind, progress.id.clone());
+        if progress.finished {
+            self.tasks
+                .remove_if(&key, |_, entry| Arc::ptr_eq(&entry.cancelled, token));
+        } else if let Some(mut entry) = self.tasks.get_mut(&key) {
+            if Arc::ptr_eq(&entry.cancelled, token) {
+                entry.task.progress = progress.progress;
+                entry.task.phase = progress.phase.clone();
+            }
+        }
+    }
+
+    /// Running tasks, oldest first.
+    pub fn list(&self) -> Vec<ActiveTask> {
+        let mut tasks: Vec<ActiveTask> = self.tasks.iter().map(|e| e.task.clone()).collect();
+        tasks.sort_by_key(|task| task.started_at);
+        tasks
+    }
+
+    /// Request a task to stop, returning whether it was found and supports cancellation.
+    pub fn cancel(&self, kind: TaskKind, id: &str) -> bool {
+        match self.tasks.get(&(kind, id.to_string())) {
+            Some(entry) if entry.task.cancellable => {
+                entry.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Handle of a registered task, used to report its progress.
+///
+/// Dropping the handle without calling `finish` reports the task as failed, so tasks
+/// returning early with an error don't linger in the registry.
+pub struct TaskHandle {
+    app: tauri::AppHandle,
+    id: String,
+    kind: TaskKind,
+    cancelled: Arc<AtomicBool>,
+    finished: bool,
+}
+
+impl TaskHandle {
+    /// Register a new task in the app's `TaskRegistry`.
+    pub fn start(app: &tauri::AppHandle, kind: TaskKind, id: &str, cancellable: bool) -> Self {
+        let cancelled = app
+            .state::<AppState>()
+            .tasks
+            .register(kind, id, cancellable);
+        Self {
+            app: app.clone(),
+            id: id.to_string(),
+            kind,
+            cancelled,
+            finished: false,
+        }
+    }
+
+    fn emit(&self, progress: f64, phase: Option<String>, finished: bool, error: Option<String>) {
+        let event = TaskProgress {
+            id: self.id.clone(),
+            kind: self.kind,
+            progress,
+            phase,
+            finished,
+            error,
+        };
+        self.app
+            .state::<AppState>()
+            .tasks
+            .update(&event, &self.cancelled);
+        let _ = event.emit(&self.app);
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn report(&self, progress: f64, phase: Option<String>) {
+        self.emit(progress, phase, false, None);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+        self.emit(100.0, None, true, None);
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            let error = if self.is_cancelled() {
+                "Cancelled"
+            } else {
+                "Stopped before completing"
+            };
+            self.emit(-1.0, None, true, Some(error.to_string()));
+        }
+    }
+}
+
+/// List the background tasks currently running.
+#[tauri::command]
+#[specta::specta]
+pub async fn list_active_tasks(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<ActiveTask>, Error> {
+    Ok(state.tasks.list())
+}
+
+/// Request a running task to stop, returning whether it supports cancellation.
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_task(
+    kind: TaskKind,
+    id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, Error> {
+    Ok(state.tasks.cancel(kind, &id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(id: &str, value: f64, finished: bool) -> TaskProgress {
+        TaskProgress {
+            id: id.to_string(),
+            kind: TaskKind::Search,
+            progress: value,
+            phase: None,
+            finished,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn finished_tasks_leave_the_registry() {
+        let registry = TaskRegistry::default();
+        let token = registry.register(TaskKind::Search, "tab-1", true);
+        registry.register(TaskKind::Analysis, "tab-1", false);
+
+        registry.update(&progress("tab-1", 40.0, false), &token);
+        let tasks = registry.list();
+        assert_eq!(tasks.len(), 2);
+        let search = tasks.iter().find(|t| t.kind == TaskKind::Search).unwrap();
+        assert_eq!(search.progress, 40.0);
+
+        registry.update(&progress("tab-1", 100.0, true), &token);
+        let tasks = registry.list();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].kind, TaskKind::Analysis);
+    }
+
+    #[test]
+    fn replaced_tasks_do_not_remove_their_successor() {
+        let registry = TaskRegistry::default();
+        let old = registry.register(TaskKind::Search, "tab-1", true);
+        let new = registry.register(TaskKind::Search, "tab-1", true);
+
+        registry.update(&progress("tab-1", 100.0, true), &old);
+        assert_eq!(registry.list().len(), 1);
+        registry.update(&progress("tab-1", 100.0, true), &new);
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn only_cancellable_tasks_can_be_cancelled() {
+        let registry = TaskRegistry::default();
+        let search = registry.register(TaskKind::Search, "a", true);
+        let download = registry.register(TaskKind::Download, "b", false);
+
+        assert!(registry.cancel(TaskKind::Search, "a"));
+        assert!(search.load(Ordering::Relaxed));
+        assert!(!registry.cancel(TaskKind::Download, "b"));
+        assert!(!download.load(Ordering::Relaxed));
+        assert!(!registry.cancel(TaskKind::Search, "missing"));
+    }
+}
@@ -2,6 +2,7 @@
 use specta::Type;
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 use sysinfo::{System, SystemExt};
 use tauri::path::BaseDirectory;
 use tauri::{AppHandle, Manager};
@@ -132,6 +133,16 @@ fn get_platform_info() -> String {
     format!("{} {} ({})", os_name, os_version, arch)
 }
 
+/// Countries resolved through the API are kept this long, so it is hit at most once a month.
+const COUNTRY_CACHE_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// The geo-IP lookup never holds up more than this.
+const COUNTRY_API_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Locales that are the default on many systems regardless of where the user lives, so their
+/// region says little about the country.
+const AMBIGUOUS_LOCALES: &[&str] = &["en_US", "en_GB"];
+
 #[derive(Debug, Deserialize)]
 struct GeolocationResponse {
     country: Option<String>,
@@ -139,21 +150,90 @@ struct GeolocationResponse {
     country_code: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct CachedCountry {
+    country: String,
+    /// Unix timestamp of the API lookup.
+    resolved_at: i64,
+}
+
+impl CachedCountry {
+    fn get_cache_path(app: &AppHandle) -> Option<PathBuf> {
+        app.path()
+            .resolve("country_cache.json", BaseDirectory::AppConfig)
+            .ok()
+    }
+
+    fn load(app: &AppHandle) -> Option<Self> {
+        let content = fs::read_to_string(Self::get_cache_path(app)?).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save(&self, app: &AppHandle) {
+        let Some(path) = Self::get_cache_path(app) else {
+            return;
+        };
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        if let Ok(json) = serde_json::to_string(self) {
+            let _ = fs::write(path, json);
+        }
+    }
+
+    fn is_fresh(&self, now: i64) -> bool {
+        now - self.resolved_at < COUNTRY_CACHE_TTL_SECS
+    }
+}
+
+/// Where a resolved country comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CountrySource {
+    Locale,
+    Cache,
+    Api,
+    StaleCache,
+    AmbiguousLocale,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LocaleCountry {
+    country: String,
+    confident: bool,
+}
+
+fn is_country_code(code: &str) -> bool {
+    code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+/// Parse the country of a locale such as `de_DE.UTF-8`, `en-GB` or `pt_BR@euro`.
+fn locale_country(locale: &str) -> Option<LocaleCountry> {
+    let locale = locale.split(['.', '@']).next()?.replace('-', "_");
+    let (language, region) = locale.split_once('_')?;
+    let country = region.split('_').next()?.to_uppercase();
+    if !is_country_code(&country) {
+        return None;
+    }
+    let normalized = format!("{}_{}", language.to_lowercase(), country);
+    Some(LocaleCountry {
+        confident: !AMBIGUOUS_LOCALES.contains(&normalized.as_str()),
+        country,
+    })
+}
+
 async fn get_user_country_from_api() -> Option<String> {
     let api_url = "http://ip-api.com/json/?fields=countryCode";
 
     if let Ok(response) = reqwest::Client::new()
         .get(api_url)
-        .timeout(std::time::Duration::from_secs(5))
+        .timeout(COUNTRY_API_TIMEOUT)
         .send()
         .await
     {
         if let Ok(text) = response.text().await {
             if let Ok(geo) = serde_json::from_str::<GeolocationResponse>(&text) {
                 if let Some(country_code) = geo.country_code.or(geo.country) {
-                    if country_code.len() == 2
-                        && country_code.chars().all(|c| c.is_ascii_uppercase())
-                    {
+                    if is_country_code(&country_code) {
                         log::info!("Retrieved country from IP-API: {}", country_code);
                         return Some(country_code);
                     }
@@ -162,39 +242,25 @@ async fn get_user_country_from_api() -> Option<String> {
         }
     }
 
-    log::warn!("Failed to get country from IP-API, falling back to locale detection");
+    log::warn!("Failed to get country from IP-API");
     None
 }
 
-fn get_user_country_from_locale() -> Option<String> {
+fn get_system_locale() -> Option<String> {
     std::env::var("LC_ALL")
         .or_else(|_| std::env::var("LC_CTYPE"))
         .or_else(|_| std::env::var("LANG"))
         .ok()
-        .and_then(|locale| {
-            if let Some(country_part) = locale.split('.').next() {
-                if let Some(country) = country_part.split('_').nth(1) {
-                    if country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()) {
-                        return Some(country.to_string());
-                    }
-                }
-            }
-            None
-        })
+        .filter(|locale| locale_country(locale).is_some())
         .or_else(|| {
             #[cfg(target_os = "windows")]
             {
                 use std::process::Command;
                 if let Ok(output) = Command::new("powershell")
-                    .args(&["-Command", "(Get-Culture).TwoLetterISOLanguageName"])
+                    .args(&["-Command", "(Get-Culture).Name"])
                     .output()
                 {
-                    let country = String::from_utf8_lossy(&output.stdout)
-                        .trim()
-                        .to_uppercase();
-                    if country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()) {
-                        return Some(country);
-                    }
+                    return Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
                 }
             }
 
@@ -205,14 +271,7 @@ fn get_user_country_from_locale() -> Option<String> {
                     .args(&["read", "-g", "AppleLocale"])
                     .output()
                 {
-                    let locale = String::from_utf8_lossy(&output.stdout);
-                    let locale = locale.trim();
-                    if let Some(country) = locale.split('_').nth(1) {
-                        let country = country.to_uppercase();
-                        if country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()) {
-                            return Some(country);
-                        }
-                    }
+                    return Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
                 }
             }
 
@@ -220,25 +279,81 @@ fn get_user_country_from_locale() -> Option<String> {
         })
 }
 
-async fn get_user_country() -> Option<String> {
-    if let Some(country) = get_user_country_from_api().await {
-        return Some(country);
-    }
+fn get_user_country_from_locale() -> Option<String> {
+    get_system_locale()
+        .and_then(|locale| locale_country(&locale))
+        .map(|locale| locale.country)
+}
 
-    if let Some(country) = get_user_country_from_locale() {
-        log::info!("Retrieved country from locale: {}", country);
-        return Some(country);
+/// Resolve the country, local sources first.
+///
+/// A confident locale wins, then a fresh cached lookup. Only then, and only if allowed, is the
+/// API asked, with a short timeout. Failing that, a stale cached lookup or an ambiguous locale
+/// is used.
+async fn resolve_country<F, Fut>(
+    locale: Option<LocaleCountry>,
+    cached: Option<&CachedCountry>,
+    network_allowed: bool,
+    now: i64,
+    lookup: F,
+) -> Option<(String, CountrySource)>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Option<String>>,
+{
+    if let Some(locale) = locale.as_ref().filter(|l| l.confident) {
+        return Some((locale.country.clone(), CountrySource::Locale));
+    }
+    if let Some(cached) = cached.filter(|c| c.is_fresh(now)) {
+        return Some((cached.country.clone(), CountrySource::Cache));
+    }
+    if network_allowed {
+        if let Ok(Some(country)) = tokio::time::timeout(COUNTRY_API_TIMEOUT, lookup()).await {
+            return Some((country, CountrySource::Api));
+        }
+    }
+    if let Some(cached) = cached {
+        return Some((cached.country.clone(), CountrySource::StaleCache));
     }
+    locale.map(|l| (l.country, CountrySource::AmbiguousLocale))
+}
 
-    log::warn!("Could not determine user country from API or locale");
-    None
+async fn get_user_country(app: &AppHandle) -> Option<String> {
+    let network_allowed = TelemetryConfig::load(app).is_ok_and(|config| config.enabled);
+    let locale = get_system_locale().and_then(|locale| locale_country(&locale));
+    let cached = CachedCountry::load(app);
+    let now = chrono::Utc::now().timestamp();
+
+    let resolved = resolve_country(
+        locale,
+        cached.as_ref(),
+        network_allowed,
+        now,
+        get_user_country_from_api,
+    )
+    .await;
+
+    match &resolved {
+        Some((country, CountrySource::Api)) => {
+            CachedCountry {
+                country: country.clone(),
+                resolved_at: now,
+            }
+            .save(app);
+        }
+        Some((country, source)) => {
+            log::info!("Retrieved country {} from {:?}", country, source);
+        }
+        None => log::warn!("Could not determine user country"),
+    }
+    resolved.map(|(country, _)| country)
 }
 
 async fn track_event_to_supabase(event_name: &str, app: &AppHandle) -> Result<(), TelemetryError> {
     let supabase_url = "https://jklxpooswizrhfdghcog.supabase.co";
     let supabase_key = "sb_publishable_sLNbFdo6jEh5JYYiT9XgmQ_P8jx7z2V";
 
-    let country = get_user_country().await;
+    let country = get_user_country(app).await;
 
     let event = TelemetryEvent {
         id: Uuid::new_v4().to_string(),
@@ -342,8 +457,8 @@ pub fn get_telemetry_config(app: AppHandle) -> Result<TelemetryConfig, String> {
 
 #[tauri::command]
 #[specta::specta]
-pub async fn get_user_country_api() -> Result<Option<String>, String> {
-    Ok(get_user_country().await)
+pub async fn get_user_country_api(app: AppHandle) -> Result<Option<String>, String> {
+    Ok(get_user_country(&app).await)
 }
 
 #[tauri::command]
@@ -363,3 +478,115 @@ pub fn get_user_id_command(app: AppHandle) -> Result<String, String> {
 pub fn get_platform_info_command() -> Result<String, String> {
     Ok(get_platform_info())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn cached(country: &str, age: i64) -> CachedCountry {
+        CachedCountry {
+            country: country.to_string(),
+            resolved_at: NOW - age,
+        }
+    }
+
+    /// Resolve with a mocked API answering `answer`, returning the result and the API calls made.
+    async fn resolve(
+        locale: &str,
+        cache: Option<CachedCountry>,
+        network_allowed: bool,
+        answer: Option<&str>,
+    ) -> (Option<(String, CountrySource)>, u32) {
+        let calls = AtomicU32::new(0);
+        let resolved = resolve_country(
+            locale_country(locale),
+            cache.as_ref(),
+            network_allowed,
+            NOW,
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                answer.map(str::to_string)
+            },
+        )
+        .await;
+        (resolved, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn locales_are_parsed() {
+        let de = locale_country("de_DE.UTF-8").unwrap();
+        assert_eq!(de.country, "DE");
+        assert!(de.confident);
+        assert_eq!(locale_country("pt-br").unwrap().country, "BR");
+        assert!(!locale_country("en_US.UTF-8").unwrap().confident);
+        assert!(!locale_country("en-GB").unwrap().confident);
+        assert!(locale_country("C.UTF-8").is_none());
+        assert!(locale_country("fr").is_none());
+    }
+
+    #[tokio::test]
+    async fn confident_locale_skips_cache_and_api() {
+        let (resolved, calls) =
+            resolve("fr_FR.UTF-8", Some(cached("DE", 0)), true, Some("ES")).await;
+        assert_eq!(resolved, Some(("FR".to_string(), CountrySource::Locale)));
+        assert_eq!(calls, 0);
+    }
+
+    #[tokio::test]
+    async fn fresh_cache_is_used_before_api() {
+        let (resolved, calls) =
+            resolve("en_US.UTF-8", Some(cached("IE", 3600)), true, Some("ES")).await;
+        assert_eq!(resolved, Some(("IE".to_string(), CountrySource::Cache)));
+        assert_eq!(calls, 0);
+    }
+
+    #[tokio::test]
+    async fn api_is_asked_for_ambiguous_locales() {
+        let expired = COUNTRY_CACHE_TTL_SECS + 1;
+        let (resolved, calls) =
+            resolve("en_GB.UTF-8", Some(cached("IE", expired)), true, Some("NZ")).await;
+        assert_eq!(resolved, Some(("NZ".to_string(), CountrySource::Api)));
+        assert_eq!(calls, 1);
+
+        let (resolved, calls) =
+            resolve("en_GB.UTF-8", Some(cached("IE", expired)), true, None).await;
+        assert_eq!(
+            resolved,
+            Some(("IE".to_string(), CountrySource::StaleCache))
+        );
+        assert_eq!(calls, 1);
+
+        let (resolved, _) = resolve("en_GB.UTF-8", None, true, None).await;
+        assert_eq!(
+            resolved,
+            Some(("GB".to_string(), CountrySource::AmbiguousLocale))
+        );
+    }
+
+    #[tokio::test]
+    async fn api_is_not_asked_when_telemetry_is_disabled() {
+        let (resolved, calls) = resolve("en_US.UTF-8", None, false, Some("CA")).await;
+        assert_eq!(
+            resolved,
+            Some(("US".to_string(), CountrySource::AmbiguousLocale))
+        );
+        assert_eq!(calls, 0);
+        assert_eq!(resolve("C", None, false, Some("CA")).await, (None, 0));
+    }
+
+    #[tokio::test]
+    async fn slow_api_falls_back_silently() {
+        let resolved = resolve_country(locale_country("en_US"), None, true, NOW, || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Some("CA".to_string())
+        })
+        .await;
+        assert_eq!(
+            resolved,
+            Some(("US".to_string(), CountrySource::AmbiguousLocale))
+        );
+    }
+}
@@ -34,6 +34,7 @@ import { routeTree } from "./routeTree.gen";
 import type { VersionCheckResult } from "./services/version-checker";
 import { getDocumentDir } from "./utils/documentDir";
 import { openFile } from "./utils/files";
+import { authorizePaths, migrateAuthorizedPaths } from "./utils/pathAccess";
 
 export type Dirs = {
   documentDir: string;
@@ -220,6 +221,8 @@ function useAppInitialization() {
       const matches = await getMatches();
       if (matches.args.file.occurrences > 0 && typeof matches.args.file.value === "string") {
         info(`Opening file from command line: ${matches.args.file.value}`);
+        // Files the app is launched with were picked by the user, like in a dialog.
+        await authorizePaths([matches.args.file.value]);
         await openFile(matches.args.file.value, setTabs, setActiveTab);
       }
     } catch (e) {
@@ -238,6 +241,8 @@ function useAppInitialization() {
       detachConsole = detach;
       info("Console logging attached successfully");
 
+      await migrateAuthorizedPaths();
+
       await handleCommandLineFile();
       await commands.screenCapture();
 
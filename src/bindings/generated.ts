@@ -32,10 +32,25 @@ async getBestMoves(id: string, engine: string, tab: string, goMode: GoMode, opti
     else return { status: "error", error: e  as any };
 }
 },
+/**
+ * Set a UCI option on every configured engine and, live, on the running ones.
+ * 
+ * Running engines that are searching receive the option with their next configuration,
+ * or right away with their search restarted if `restart_running` is set. Stored profiles
+ * are left to the frontend: their results carry the setting to store.
+ */
+async applyOptionToAllEngines(optionName: string, value: string, onlyRunning: boolean, restartRunning: boolean | null) : Promise<Result<EngineOptionResult[], string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("apply_option_to_all_engines", { optionName, value, onlyRunning, restartRunning }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 /**
  * Analyze a game using the engine, returning move-by-move analysis.
  */
-async analyzeGame(id: string, engine: string, goMode: GoMode, options: AnalysisOptions, uciOptions: EngineOption[]) : Promise<Result<MoveAnalysis[], string>> {
+async analyzeGame(id: string, engine: string, goMode: GoMode, options: AnalysisOptions, uciOptions: EngineOption[]) : Promise<Result<GameAnalysis, string>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("analyze_game", { id, engine, goMode, options, uciOptions }) };
 } catch (e) {
@@ -167,6 +182,20 @@ async fileExists(path: string) : Promise<Result<boolean, string>> {
     else return { status: "error", error: e  as any };
 }
 },
+/**
+ * Allow commands to use `path`, a file or a directory with everything below it.
+ * 
+ * Only called once the user explicitly chose or confirmed the path. Returns the canonical
+ * path that was authorized.
+ */
+async authorizePath(path: string) : Promise<Result<string, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("authorize_path", { path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async getFileMetadata(path: string) : Promise<Result<FileMetadata, string>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("get_file_metadata", { path }) };
@@ -183,9 +212,9 @@ async mergePlayers(file: string, player1: number, player2: number) : Promise<Res
     else return { status: "error", error: e  as any };
 }
 },
-async convertPgn(file: string, dbPath: string, timestamp: number | null, title: string, description: string | null) : Promise<Result<null, string>> {
+async convertPgn(file: string, dbPath: string, timestamp: number | null, title: string, description: string | null, study: boolean | null, account: OnlineAccount | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("convert_pgn", { file, dbPath, timestamp, title, description }) };
+    return { status: "ok", data: await TAURI_INVOKE("convert_pgn", { file, dbPath, timestamp, title, description, study, account }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -234,9 +263,9 @@ async deleteGame(file: string, n: number) : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
-async deleteDuplicatedGames(file: string) : Promise<Result<null, string>> {
+async deleteDuplicatedGames(file: string, policy: DuplicatePolicy, precedence: ProvenanceKind[] | null) : Promise<Result<DuplicateReport, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("delete_duplicated_games", { file }) };
+    return { status: "ok", data: await TAURI_INVOKE("delete_duplicated_games", { file, policy, precedence }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -277,9 +306,9 @@ async createIndexes(file: string) : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
-async editDbInfo(file: string, title: string | null, description: string | null) : Promise<Result<null, string>> {
+async editDbInfo(file: string, title: string | null, description: string | null, startFen: string | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("edit_db_info", { file, title, description }) };
+    return { status: "ok", data: await TAURI_INVOKE("edit_db_info", { file, title, description, startFen }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -293,17 +322,20 @@ async deleteDbGame(file: string, gameId: number) : Promise<Result<null, string>>
     else return { status: "error", error: e  as any };
 }
 },
-async deleteDatabase(file: string) : Promise<Result<null, string>> {
+/**
+ * Delete a database, moving it to the trash unless `permanently_delete` is set.
+ */
+async deleteDatabase(file: string, permanentlyDelete: boolean) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("delete_database", { file }) };
+    return { status: "ok", data: await TAURI_INVOKE("delete_database", { file, permanentlyDelete }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
-async exportToPgn(file: string, destFile: string) : Promise<Result<null, string>> {
+async exportToPgn(file: string, destFile: string, sort: ExportSort | null, localized: SanStyle | null, anonymize: AnonymizeOptions | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("export_to_pgn", { file, destFile }) };
+    return { status: "ok", data: await TAURI_INVOKE("export_to_pgn", { file, destFile, sort, localized, anonymize }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -317,9 +349,9 @@ async authenticate(username: string) : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
-async writeGame(file: string, n: number, pgn: string) : Promise<Result<null, string>> {
+async writeGame(file: string, n: number, pgn: string, tab: string | null, localized: SanStyle | null) : Promise<Result<string | null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("write_game", { file, n, pgn }) };
+    return { status: "ok", data: await TAURI_INVOKE("write_game", { file, n, pgn, tab, localized }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -373,9 +405,9 @@ async getGame(file: string, gameId: number) : Promise<Result<NormalizedGame, str
     else return { status: "error", error: e  as any };
 }
 },
-async updateGame(file: string, gameId: number, update: UpdateGame) : Promise<Result<null, string>> {
+async updateGame(file: string, gameId: number, update: UpdateGame, tab: string | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("update_game", { file, gameId, update }) };
+    return { status: "ok", data: await TAURI_INVOKE("update_game", { file, gameId, update, tab }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -595,65 +627,654 @@ reportProgress: "report-progress"
 
 /** user-defined types **/
 
+/**
+ * Budget for analysing a game adaptively.
+ */
+export type AdaptiveConfig = { 
+/**
+ * Depth every position is searched to first.
+ */
+initialDepth: number; 
+/**
+ * Depth interesting positions are deepened to at most.
+ */
+maxDepth: number; 
+/**
+ * Time for analysing the whole game, in milliseconds.
+ */
+timeBudgetMs: number; 
+/**
+ * Depth added each time a position is deepened.
+ */
+depthStep?: number | null; 
+/**
+ * Evaluation change between depths, in centipawns, making a position unstable.
+ */
+instabilityCp?: number | null; 
+/**
+ * Evaluation change from the previous position, in centipawns, making it critical.
+ */
+swingCp?: number | null }
 /**
  * Options for full-game analysis (FEN, moves, novelty annotation, etc).
  */
-export type AnalysisOptions = { fen: string; moves: string[]; annotateNovelties: boolean; referenceDb: string | null; reversed: boolean }
+export type AnalysisOptions = { fen: string; moves: string[]; annotateNovelties: boolean; referenceDb: string | null; reversed: boolean; 
+/**
+ * Analyse at most this many moves of the game, which keeps reports of very long games
+ * affordable.
+ */
+maxPly?: number | null; 
+/**
+ * Name the opening on the last book position of the game.
+ */
+annotateOpening?: boolean; 
+/**
+ * Rating of White, which moves are judged at. `DEFAULT_RATING` if unknown.
+ */
+whiteRating?: number | null; 
+/**
+ * Rating of Black, which moves are judged at. `DEFAULT_RATING` if unknown.
+ */
+blackRating?: number | null; 
+/**
+ * Search every position to a small depth, then deepen the interesting ones for as long
+ * as the time budget allows. The search mode of the analysis is then ignored.
+ */
+adaptive?: AdaptiveConfig | null; 
+/**
+ * Evaluation gap between the two best moves, in centipawns, making the best one an
+ * only move. `DEFAULT_ONLY_MOVE_GAP_CP` if unset.
+ */
+onlyMoveGapCp?: number | null; 
+/**
+ * Evaluation the only move must keep, in centipawns from the player's point of view.
+ * `DEFAULT_SURVIVAL_CP` if unset.
+ */
+onlyMoveSurvivalCp?: number | null; 
+/**
+ * Other engines searching every position as well, for an evaluation agreed between up to
+ * `MAX_CONSENSUS_ENGINES` engines in all.
+ */
+consensusEngines?: string[]; 
+/**
+ * Spread of the engines' evaluations, in centipawns, above which they disagree.
+ * `DEFAULT_DISAGREEMENT_CP` if unset.
+ */
+disagreementCp?: number | null; 
+/**
+ * Measure the swindle chances of the moves of lost positions, searching the opponent's
+ * plausible replies after each. Adaptive analyses spend what is left of their time
+ * budget on it.
+ */
+swindleChances?: boolean; 
+/**
+ * Evaluation against the player, in centipawns, from which a position is lost.
+ * `DEFAULT_SWINDLE_LOSING_CP` if unset.
+ */
+swindleLosingCp?: number | null; 
+/**
+ * Centipawn loss from which a move is an inaccuracy. `DEFAULT_INACCURACY_CP` if unset.
+ */
+inaccuracyCp?: number | null; 
+/**
+ * Centipawn loss from which a move is a mistake. `DEFAULT_MISTAKE_CP` if unset.
+ */
+mistakeCp?: number | null; 
+/**
+ * Centipawn loss from which a move is a blunder. `DEFAULT_BLUNDER_CP` if unset.
+ */
+blunderCp?: number | null }
+/**
+ * Why an engine search ended.
+ */
+export type AnalysisCompletion = 
+/**
+ * The search reached the requested depth, or ended on its own without a limit.
+ */
+{ type: "targetReached" } | 
+/**
+ * The search was stopped or its engine killed on request.
+ */
+{ type: "userStopped" } | 
+/**
+ * The engine exited in the middle of the search.
+ */
+{ type: "engineCrashed" } | 
+/**
+ * The engine stopped early after finding a forced mate.
+ */
+{ type: "autoStoppedMate" } | 
+/**
+ * The search used up its time or nodes.
+ */
+{ type: "budgetExhausted" } | 
+/**
+ * The engine's output couldn't be read anymore.
+ */
+{ type: "error"; value: string }
+/**
+ * Totals of an engine search, reported when it ends.
+ */
+export type AnalysisStatistics = { nodes: number; 
+/**
+ * Wall time since the search started, in milliseconds.
+ */
+elapsedMs: number; maxDepth: number; 
+/**
+ * Lines the engine actually reported.
+ */
+multipv: number }
+export type AnonymizeOptions = { pseudonyms?: PseudonymStyle; 
+/**
+ * Keep only the year of dates.
+ */
+yearOnly?: boolean; 
+/**
+ * JSON file to write the pseudonyms to, with the names they stand for.
+ */
+keyFile: string | null }
 /**
  * Best-move line from engine output, including PV, score, and stats.
  */
-export type BestMoves = { nodes: number; depth: number; score: Score; uciMoves: string[]; sanMoves: string[]; multipv: number; nps: number }
+export type BestMoves = { nodes: number; depth: number; score: Score; uciMoves: string[]; sanMoves: string[]; multipv: number; nps: number; 
+/**
+ * Line of the move pinned in the tab, possibly searched outside the MultiPV window.
+ */
+pinned: boolean; 
+/**
+ * Score as displayed, from White's point of view.
+ */
+display: string }
 /**
  * Event payload for best-move updates (emitted to frontend).
  */
-export type BestMovesPayload = { bestLines: BestMoves[]; engine: string; tab: string; fen: string; moves: string[]; progress: number }
-export type DatabaseInfo = { title: string; description: string; player_count: number; event_count: number; game_count: number; storage_size: bigint; filename: string; indexed: boolean }
+export type BestMovesPayload = { bestLines: BestMoves[]; engine: string; tab: string; fen: string; moves: string[]; progress: number; 
+/**
+ * Why the search ended, on the last payload of a search.
+ */
+completion: AnalysisCompletion | null; 
+/**
+ * Totals of the whole search, on the last payload of a search.
+ */
+statistics: AnalysisStatistics | null; 
+/**
+ * Fewer lines than requested, the engine not having reported the others at this depth.
+ */
+partial: boolean; 
+/**
+ * Reply the engine expects to its best move, on the last payload of a search.
+ */
+ponder: string | null }
+export type DatabaseInfo = { title: string; description: string; player_count: number; event_count: number; game_count: number; storage_size: bigint; filename: string; indexed: boolean; start_fen: string | null; 
+/**
+ * Set when enough games were deleted for `optimize_database` to be worthwhile.
+ */
+needs_optimize: boolean; metadata: DatabaseMetadata; 
+/**
+ * Number of games from each source.
+ */
+sources: ProvenanceCount[] }
+export type DatabaseMetadata = { 
+/**
+ * Number of times the metadata was written, 0 if it never was.
+ */
+version: number; 
+/**
+ * Markdown.
+ */
+notes: string; source_url: string | null; license: string | null; tags: { [key in string]: string }; cover_color: string | null; 
+/**
+ * Identifier of the icon shown on the cover.
+ */
+cover_icon: string | null }
 export type DatabaseProgress = { id: string; progress: number }
 export type DownloadProgress = { progress: number; id: string; finished: boolean }
+export type DuplicateGame = { id: number; 
+/**
+ * Game kept in its place: the first exact copy, or the longest game extending it.
+ */
+original: number; kind: DuplicateKind }
+export type DuplicateKind = 
+/**
+ * Same headers and moves.
+ */
+"exact" | 
+/**
+ * Same game, stopped earlier.
+ */
+"prefix"
+/**
+ * What to do with prefix duplicates. Exact duplicates are always deleted.
+ */
+export type DuplicatePolicy = 
+/**
+ * Delete the shorter game.
+ */
+"keepLonger" | 
+/**
+ * Keep both games.
+ */
+"keepBoth" | 
+/**
+ * Keep both games and report them, so the user can decide.
+ */
+"ask"
+export type DuplicateReport = { deleted: number; 
+/**
+ * Prefix duplicates left for the user to resolve, with `DuplicatePolicy::Ask`.
+ */
+unresolved: DuplicateGame[] }
 /**
  * UCI engine configuration (name and available options).
  */
-export type EngineConfig = { name: string; options: UciOptionConfig[] }
+export type EngineConfig = { name: string; options: UciOptionConfig[]; 
+/**
+ * Lines the engine reports at most, if it was seen ignoring MultiPV.
+ */
+multipv_limit: number | null }
 /**
  * Log entry for engine GUI or engine output.
  */
+/**
+ * Lines one engine of a consensus analysis found in a position.
+ */
+export type EngineLines = { engine: string; best: BestMoves[] }
 export type EngineLog = { type: "gui"; value: string } | { type: "engine"; value: string }
 /**
  * UCI engine option (name-value pair).
  */
 export type EngineOption = { name: string; value: string }
+/**
+ * Result of applying an option to a stored engine profile or a running engine.
+ */
+export type EngineOptionResult = ({ 
+/**
+ * Name of the engine profile, or path of the running engine.
+ */
+engine: string; 
+/**
+ * Tab of the running engine, `None` for stored profiles.
+ */
+tab: string | null; 
+/**
+ * Setting to store in the profile, for stored profiles the option was applied to.
+ */
+setting: ProfileSetting | null }) & (
+/**
+ * The option was stored or sent to the engine.
+ */
+{ status: "applied" } | 
+/**
+ * The engine is searching; the option is sent with its next configuration.
+ */
+{ status: "deferred" } | 
+/**
+ * The engine doesn't support the option or value.
+ */
+{ status: "skipped"; reason: string } | { status: "failed"; error: string })
 /**
  * Options for configuring engine analysis (FEN, moves, extra UCI options).
  */
 export type EngineOptions = { fen: string; moves: string[]; extraOptions: EngineOption[] }
 export type Event = { id: number; name: string | null }
+/**
+ * Order of the games in a PGN export.
+ */
+export type ExportSort = 
+/**
+ * Database order, by game ID.
+ */
+"id" | 
+/**
+ * Reproducible order and formatting, followed by a manifest with the content hash.
+ */
+"canonical"
 export type FidePlayer = { fideid: number; name: string; country: string; sex: string; title: string | null; w_title: string | null; o_title: string | null; foa_title: string | null; rating: number | null; games: number | null; k: number | null; rapid_rating: number | null; rapid_games: number | null; rapid_k: number | null; blitz_rating: number | null; blitz_games: number | null; blitz_k: number | null; birthday: number | null; flag: string | null }
 export type FileMetadata = { last_modified: bigint; size: bigint; is_dir: boolean; is_readonly: boolean }
+export type GameAccuracy = { white: PlayerAccuracy; black: PlayerAccuracy; phases: GamePhases; performance: GamePerformance; 
+/**
+ * Swindle chances of each player, when the analysis measured them.
+ */
+swindles: GameSwindles | null; 
+/**
+ * Centipawn loss of each player's moves, in game analyses.
+ */
+cp_loss: GameCpLoss | null }
+/**
+ * Result of a game analysis.
+ */
+export type GameAnalysis = { 
+/**
+ * Analysis of each position of the game.
+ */
+analysis: MoveAnalysis[]; 
+/**
+ * Whether the analysis was stopped with `stop_analysis`. Positions it didn't get to are
+ * then left out, or left without lines when it went backwards, and the annotations of the
+ * whole game such as accuracy and novelties are left unset.
+ */
+cancelled: boolean }
+export type GameCpLoss = { white: PlayerCpLoss; black: PlayerCpLoss }
+export type GameLink = { id: number; from_game: number; 
+/**
+ * `None` once the linked game was deleted.
+ */
+to_game: number | null; label: string }
 export type GameOutcome = "Won" | "Drawn" | "Lost"
-export type GameQueryJs = { options?: QueryOptions<GameSort> | null; player1?: number | null; player2?: number | null; tournament_id?: number | null; start_date?: string | null; end_date?: string | null; range1?: [number, number] | null; range2?: [number, number] | null; sides?: Sides | null; outcome?: string | null; position?: PositionQueryJs | null; wanted_result?: string | null }
-export type GameSort = "id" | "date" | "whiteElo" | "blackElo" | "averageElo" | "ply_count"
+/**
+ * Phases are absent when the game has no moves in them, as short games skip the endgame.
+ */
+export type GamePerformance = { white: PlayerPerformance; black: PlayerPerformance }
+export type GamePhases = { opening: PhaseAccuracy | null; middlegame: PhaseAccuracy | null; endgame: PhaseAccuracy | null }
+export type GameSwindles = { white: PlayerSwindles; black: PlayerSwindles }
+/**
+ * Where a game came from.
+ */
+export type GameProvenance = { kind: ProvenanceKind; 
+/**
+ * File name for files, platform for online accounts.
+ */
+name: string; 
+/**
+ * Username of online accounts.
+ */
+account: string | null; 
+/**
+ * Unix timestamp in seconds of the import.
+ */
+imported_at: number | null }
+export type GameQueryJs = { options?: QueryOptions<GameSort> | null; player1?: number | null; player2?: number | null; tournament_id?: number | null; start_date?: string | null; end_date?: string | null; range1?: [number, number] | null; range2?: [number, number] | null; sides?: Sides | null; outcome?: string | null; position?: PositionQueryJs | null; wanted_result?: string | null; pawn_structure?: PawnStructure | null; 
+/**
+ * Position searches only: minimum rating of the player to move in the searched
+ * position. Games where that player has no rating are left out.
+ */
+min_elo_side_to_move?: number | null; 
+/**
+ * Position searches only: minimum average rating of both players. Games where either
+ * player has no rating are left out.
+ */
+min_avg_elo?: number | null; 
+/**
+ * Position searches only: the player who must be the one to move in the searched
+ * position. Games where they had the other color there, or didn't play, are left out.
+ */
+matched_side_player?: number | null; 
+/**
+ * Only games imported from this source.
+ */
+source?: ProvenanceQuery | null; 
+/**
+ * Only games whose opening is among the rarest of the database, taking in this
+ * percentage of its games (or slightly more, as openings aren't split).
+ */
+rarest_percent?: number | null }
+export type GameSort = "id" | "date" | "whiteElo" | "blackElo" | "averageElo" | "ply_count" | 
+/**
+ * By how few games of the database share the game's opening, rarest first when
+ * descending. Games without an ECO code come last.
+ */
+"openingRarity"
 /**
  * Engine search mode (depth, time, nodes, etc).
  */
-export type GoMode = { t: "PlayersTime"; c: PlayersTime } | { t: "Depth"; c: number } | { t: "Time"; c: number } | { t: "Nodes"; c: number } | { t: "Infinite" }
+export type GoMode = { t: "PlayersTime"; c: PlayersTime } | { t: "Depth"; c: number } | { t: "Time"; c: number } | { t: "Nodes"; c: number } | { t: "Mate"; c: number } | { t: "Infinite" }
 /**
  * Analysis result for a single move/position.
  */
-export type MoveAnalysis = { best: BestMoves[]; novelty: boolean; is_sacrifice: boolean }
-export type NormalizedGame = { id: number; fen: string; event: string; event_id: number; site: string; site_id: number; date?: string | null; time?: string | null; round?: string | null; white: string; white_id: number; white_elo?: number | null; black: string; black_id: number; black_elo?: number | null; result: Outcome; time_control?: string | null; eco?: string | null; ply_count?: number | null; moves: string }
+export type LinkedGames = { 
+/**
+ * Links from the game to others.
+ */
+outgoing: GameLink[]; 
+/**
+ * Links from other games to this one.
+ */
+incoming: GameLink[] }
+export type MoveAnalysis = { best: BestMoves[]; novelty: boolean; is_sacrifice: boolean; 
+/**
+ * Set on the last analysed position when the rest of the game was left out because of
+ * `AnalysisOptions::max_ply`.
+ */
+truncated: boolean; 
+/**
+ * Name of the opening, on the last book position of the game.
+ */
+opening: string | null; 
+/**
+ * Accuracy of the whole game, by player and phase, on the start position.
+ */
+accuracy: GameAccuracy | null; 
+/**
+ * Expected points lost by the move leading here, at the rating of the player who made it.
+ */
+expected_points_lost: number | null; 
+/**
+ * Centipawns lost by the move leading here, from the point of view of the player who
+ * made it.
+ */
+cp_loss: number | null; 
+/**
+ * How the move leading here compares to the engine's choice.
+ */
+classification: MoveClassification | null; 
+/**
+ * Depth the engine reached in this position.
+ */
+depth: number | null; 
+/**
+ * Whether a single move holds the evaluation here, all others losing much more.
+ */
+only_move: boolean; 
+/**
+ * Whether the game move from this position was the only move.
+ */
+found_only_move: boolean; 
+/**
+ * Whether the engines of a consensus analysis are far apart here.
+ */
+engines_disagree: boolean; 
+/**
+ * Lines of each engine of a consensus analysis, `best` holding the merged ones.
+ */
+engine_lines: EngineLines[]; 
+/**
+ * Practical chances left by the game move, when the player was lost here and
+ * `AnalysisOptions::swindle_chances` is set.
+ */
+swindle: SwindleChances | null }
+/**
+ * How a move compares to the engine's choice, by the centipawns it lost.
+ */
+export type MoveClassification = 
+/**
+ * The engine's first choice.
+ */
+"best" | "good" | "inaccuracy" | "mistake" | "blunder"
+export type NormalizedGame = { id: number; fen: string; event: string; event_id: number; site: string; site_id: number; date?: string | null; time?: string | null; round?: string | null; white: string; white_id: number; white_elo?: number | null; black: string; black_id: number; black_elo?: number | null; result: Outcome; time_control?: string | null; eco?: string | null; ply_count?: number | null; moves: string; 
+/**
+ * Decoding problems in the stored moves; `moves` only holds what precedes them.
+ */
+decode_warnings: number; 
+/**
+ * Links from and to the game, only loaded for a single game.
+ */
+links?: LinkedGames | null; 
+/**
+ * Where the game came from, loaded by games lists and for a single game.
+ */
+source?: GameProvenance | null }
+/**
+ * Account games are downloaded from, e.g. `lichess` and a username.
+ */
+export type OnlineAccount = { platform: string; username: string }
+/**
+ * What happened to the option for one engine.
+ */
+export type OptionApplyStatus = 
+/**
+ * The option was stored or sent to the engine.
+ */
+{ status: "applied" } | 
+/**
+ * The engine is searching; the option is sent with its next configuration.
+ */
+{ status: "deferred" } | 
+/**
+ * The engine doesn't support the option or value.
+ */
+{ status: "skipped"; reason: string } | { status: "failed"; error: string }
 export type OutOpening = { name: string; fen: string }
 export type Outcome = "1-0" | "0-1" | "1/2-1/2" | "*"
 export type PackageManagerResult = { success: boolean; stdout: string; stderr: string }
+export type PawnStructure = "carlsbad" | 
+/**
+ * Isolated queen pawn.
+ */
+"iqp" | "hedgehog" | "stonewall" | "other"
+/**
+ * Accuracy of both players during a phase of the game.
+ */
+export type PhaseAccuracy = { 
+/**
+ * First move of the phase, as the number of moves played before it.
+ */
+start_ply: number; 
+/**
+ * Moves played by the end of the phase.
+ */
+end_ply: number; white: PlayerAccuracy; black: PlayerAccuracy }
 export type Player = { id: number; name: string | null; elo: number | null }
+/**
+ * Accuracy of one player over part of a game.
+ */
+export type PlayerAccuracy = { 
+/**
+ * Average move accuracy, from 0 to 100.
+ */
+accuracy: number; 
+/**
+ * Average centipawn loss.
+ */
+acpl: number; blunders: number; 
+/**
+ * Total expected points lost, at the player's rating.
+ */
+expected_points_lost: number; 
+/**
+ * Moves with evaluations on both sides; the other fields are zero without any.
+ */
+moves: number }
+/**
+ * Centipawn loss of one player's moves over a game.
+ */
+export type PlayerCpLoss = { 
+/**
+ * Average centipawn loss, zero without any move.
+ */
+acpl: number; 
+/**
+ * Moves with evaluations on both sides.
+ */
+moves: number; best: number; good: number; inaccuracies: number; mistakes: number; blunders: number }
 export type PlayerGameInfo = { site_stats_data: SiteStatsData[] }
+/**
+ * Rating a player's moves were judged at, and the rating they played at.
+ */
+export type PlayerPerformance = { 
+/**
+ * The player's rating in the game, or `DEFAULT_RATING`.
+ */
+rating: number; 
+/**
+ * Performance against the opponent's rating, scoring what's left of an even game once
+ * both players lost their expected points.
+ */
+estimate: number }
 export type PlayerQuery = { options: QueryOptions<PlayerSort>; name?: string | null; range?: [number, number] | null }
 export type PlayerSort = "id" | "name" | "elo"
+/**
+ * Swindle chances of one player over a game.
+ */
+export type PlayerSwindles = { 
+/**
+ * Lost positions the player moved in.
+ */
+positions: number; 
+/**
+ * Average resourcefulness of their moves there, zero without any.
+ */
+resourcefulness: number; 
+/**
+ * Moves that set at least as many traps as the engine's suggestions.
+ */
+best_tries: number }
 /**
  * Player time controls for GoMode::PlayersTime.
  */
-export type PlayersTime = { white: number; black: number; winc: number; binc: number }
-export type PositionQueryJs = { fen: string; type_: string }
+export type PlayersTime = { white: number; black: number; winc: number; binc: number; 
+/**
+ * Search while the opponent thinks, on the move the engine expects, until the move is
+ * played (`ponderhit`) or another one is (`stop`).
+ */
+ponder?: boolean }
+export type PositionQueryJs = { fen: string; type_: string; variant?: PositionVariant; 
+/**
+ * Partial queries only: side that must be to move. Exact queries always compare it.
+ */
+side_to_move?: SideToMove | null }
 export type PositionStats = { move: string; white: number; draw: number; black: number }
+/**
+ * Variant a position is searched in, which decides how the castling rights of the query are
+ * read and whether they are compared. Games are replayed with the castling rules their own
+ * start position needs.
+ */
+export type PositionVariant = "standard" | "chess960"
+/**
+ * Setting for the frontend to store in an engine profile.
+ */
+export type ProfileSetting = { 
+/**
+ * Name of the option as the engine advertises it.
+ */
+name: string; value: SettingValue }
+/**
+ * Number of games of a source, as shown in `DatabaseInfo`.
+ */
+export type ProvenanceCount = { kind: ProvenanceKind; name: string; account: string | null; count: number }
+export type ProvenanceKind = 
+/**
+ * Imported from a PGN file.
+ */
+"file" | 
+/**
+ * Downloaded from an online account.
+ */
+"online" | 
+/**
+ * Imported before sources were tracked.
+ */
+"unknown"
+/**
+ * Games from a source, as filtered by `GameQueryJs::source`.
+ */
+export type ProvenanceQuery = { kind: ProvenanceKind; 
+/**
+ * Only games from this file or platform.
+ */
+name?: string | null; 
+/**
+ * Only games from this online account.
+ */
+account?: string | null }
+export type PseudonymStyle = 
+/**
+ * "Player A", "Player B" and so on, in order of appearance.
+ */
+"letters" | 
+/**
+ * "Player " and a hash of the name, salted for each export.
+ */
+"hashed"
 export type Puzzle = { id: number; fen: string; moves: string; rating: number; rating_deviation: number; popularity: number; nb_plays: number }
 /**
  * Information about a puzzle database
@@ -686,6 +1307,14 @@ export type QueryResponse<T> = { data: T; count: number | null }
  * Event payload for reporting analysis progress.
  */
 export type ReportProgress = { progress: number; id: string; finished: boolean }
+/**
+ * How pieces are written in a rendering of the moves.
+ */
+export type SanStyle = "english" | "german" | "spanish" | "french" | "italian" | "dutch" | 
+/**
+ * Unicode chess glyphs, for HTML and print.
+ */
+"figurine"
 export type Score = { value: ScoreValue; 
 /**
  * The probability of each result (win, draw, loss).
@@ -700,10 +1329,43 @@ export type ScoreValue =
  * Mate coming up in this many moves. Negative value means the engine is getting mated.
  */
 { type: "mate"; value: number }
+/**
+ * Value of an engine profile setting, kept typed as profiles store it.
+ */
+export type SettingValue = number | boolean | string
+/**
+ * Side to move a partial position query requires
+ */
+export type SideToMove = "white" | "black"
 export type Sides = "BlackWhite" | "WhiteBlack" | "Any"
 export type SiteStatsData = { site: string; player: string; data: StatsData[] }
 export type SortDirection = "asc" | "desc"
 export type StatsData = { date: string; is_player_white: boolean; player_elo: number; result: GameOutcome; time_control: string; opening: string }
+/**
+ * Practical chances a move of a lost position left.
+ */
+export type SwindleChances = { 
+/**
+ * Share of the opponent's plausible replies throwing the win away, weighted by how
+ * plausible they are, from 0 to 1.
+ */
+resourcefulness: number; 
+/**
+ * Replies searched.
+ */
+replies: number; 
+/**
+ * Replies throwing the win away.
+ */
+throwaways: number; 
+/**
+ * Legal replies stalemating the player.
+ */
+stalemates: number; 
+/**
+ * Whether no move the engine suggested set more traps than the game move.
+ */
+best_try: boolean }
 export type TelemetryConfig = { enabled: boolean; initial_run_completed: boolean }
 export type Token = { type: "ParenOpen" } | { type: "ParenClose" } | { type: "Comment"; value: string } | { type: "San"; value: string } | { type: "Header"; value: { tag: string; value: string } } | { type: "Nag"; value: string } | { type: "Outcome"; value: string }
 export type TournamentQuery = { options: QueryOptions<TournamentSort>; name: string | null }
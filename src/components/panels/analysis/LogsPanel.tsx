@@ -11,7 +11,6 @@ import {
 import { IconFileExport, IconRefresh } from "@tabler/icons-react";
 import { useQuery } from "@tanstack/react-query";
 import { useVirtualizer } from "@tanstack/react-virtual";
-import { save } from "@tauri-apps/plugin-dialog";
 import { writeTextFile } from "@tauri-apps/plugin-fs";
 import { useAtomValue } from "jotai";
 import { useEffect, useMemo, useRef, useState } from "react";
@@ -19,6 +18,7 @@ import { useTranslation } from "react-i18next";
 import { commands } from "@/bindings";
 import { activeTabAtom, enginesAtom, fontSizeAtom } from "@/state/atoms";
 import type { LocalEngine } from "@/utils/engines";
+import { save } from "@/utils/pathAccess";
 import { unwrap } from "@/utils/unwrap";
 
 export default function LogsPanel() {
@@ -44,6 +44,7 @@ function ReportModal({
   const localEngines = engines.filter((e): e is LocalEngine => e.type === "local");
   const store = useContext(TreeStateContext)!;
   const addAnalysis = useStore(store, (s) => s.addAnalysis);
+  const headers = useStore(store, (s) => s.headers);
 
   const [reportSettings, setReportSettings] = useAtom(reportSettingsAtom);
   const analysisEngineRef = useRef<{ engine: string; tab: string } | null>(null);
@@ -111,13 +112,15 @@ function ReportModal({
           referenceDb,
           reversed: form.values.reversed,
           moves,
+          whiteRating: headers.white_elo ?? null,
+          blackRating: headers.black_elo ?? null,
         },
         engineSettings,
       )
       .then((analysis) => {
         if (analysisEngineRef.current) {
           const analysisData = unwrap(analysis);
-          addAnalysis(analysisData);
+          addAnalysis(analysisData.analysis);
         }
       })
       .catch((error) => {
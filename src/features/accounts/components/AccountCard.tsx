@@ -153,6 +153,8 @@ export function AccountCard({
           timestamp ? timestamp / 1000 : null,
           filename,
           null,
+          null,
+          { platform: type, username: title },
         ),
       );
       info(`Conversion complete, database saved to: ${dbPath}`);
@@ -254,7 +254,7 @@ function LichessOrChessCom({
           try {
             // Delete database file if it exists
             try {
-              await commands.deleteDatabase(dbPath);
+              await commands.deleteDatabase(dbPath, true);
             } catch {
               // Database file might not exist, ignore
             }
@@ -357,7 +357,7 @@ function LichessOrChessCom({
           try {
             // Delete database file if it exists
             try {
-              await commands.deleteDatabase(dbPath);
+              await commands.deleteDatabase(dbPath, true);
             } catch {
               // Database file might not exist, ignore
             }
@@ -266,7 +266,7 @@ function AccountsTableView({
       try {
         // Delete database file if it exists
         try {
-          await commands.deleteDatabase(dbPath);
+          await commands.deleteDatabase(dbPath, true);
         } catch {
           // Database file might not exist, ignore
         }
@@ -304,7 +304,7 @@ function AccountsTableView({
       try {
         // Delete database file if it exists
         try {
-          await commands.deleteDatabase(dbPath);
+          await commands.deleteDatabase(dbPath, true);
         } catch {
           // Database file might not exist, ignore
         }
@@ -3,7 +3,6 @@ import type { Piece } from "@lichess-org/chessground/types";
 import { Box, Group, Text, useMantineTheme } from "@mantine/core";
 import { useHotkeys } from "@mantine/hooks";
 import { notifications } from "@mantine/notifications";
-import { save } from "@tauri-apps/plugin-dialog";
 import { writeFile } from "@tauri-apps/plugin-fs";
 import { makeSquare, type NormalMove, parseSquare, parseUci, type SquareName } from "chessops";
 import { chessgroundDests, chessgroundMove } from "chessops/compat";
@@ -47,6 +46,7 @@ import { annotationColors, isBasicAnnotation } from "@/utils/annotation";
 import { getMaterialDiff, getVariationLine } from "@/utils/chess";
 import { chessopsError, forceEnPassant, positionFromFen } from "@/utils/chessops";
 import { getDocumentDir } from "@/utils/documentDir";
+import { save } from "@/utils/pathAccess";
 import AnnotationHint from "./AnnotationHint";
 import EvalBar from "./EvalBar";
 import MoveInput from "./MoveInput";
@@ -32,7 +32,6 @@ import {
   IconZoomCheck,
 } from "@tabler/icons-react";
 import { useNavigate } from "@tanstack/react-router";
-import { save } from "@tauri-apps/plugin-dialog";
 import { parseUci } from "chessops";
 import { INITIAL_FEN } from "chessops/fen";
 import { makeSan, parseSan } from "chessops/san";
@@ -80,6 +79,7 @@ import type { TimeControlField } from "@/utils/clock";
 import { getDocumentDir } from "@/utils/documentDir";
 import type { LocalEngine } from "@/utils/engines";
 import { createFile } from "@/utils/files";
+import { save } from "@/utils/pathAccess";
 import { deserializeStorageValue } from "@/utils/tabStateStorage";
 import { formatDateToPGN } from "@/utils/format";
 import { type GameRecord, saveGameRecord } from "@/utils/gameRecords";
@@ -4,7 +4,6 @@ import { useHotkeys, useToggle } from "@mantine/hooks";
 import { notifications } from "@mantine/notifications";
 import { IconPuzzle } from "@tabler/icons-react";
 import { useLoaderData } from "@tanstack/react-router";
-import { save } from "@tauri-apps/plugin-dialog";
 import { writeTextFile } from "@tauri-apps/plugin-fs";
 import { useAtom, useAtomValue } from "jotai";
 import { useCallback, useContext, useEffect, useRef, useState } from "react";
@@ -26,6 +25,7 @@ import { defaultPGN, getMoveText, getPGN } from "@/utils/chess";
 import { positionFromFen } from "@/utils/chessops";
 import { createFile, isTempImportFile } from "@/utils/files";
 import { formatDateToPGN } from "@/utils/format";
+import { save } from "@/utils/pathAccess";
 import { reloadTab, saveTab, saveToFile, type Tab } from "@/utils/tabs";
 import { getNodeAtPath, type TreeNode } from "@/utils/treeReducer";
 import EditingCard from "./EditingCard";
@@ -19,7 +19,6 @@ import { notifications } from "@mantine/notifications";
 import { IconAlertCircle, IconX } from "@tabler/icons-react";
 import { useQuery } from "@tanstack/react-query";
 import { appDataDir, resolve } from "@tauri-apps/api/path";
-import { open } from "@tauri-apps/plugin-dialog";
 import { type Dispatch, type SetStateAction, useState } from "react";
 import { useTranslation } from "react-i18next";
 import { commands, events, type PuzzleDatabaseInfo } from "@/bindings";
@@ -27,6 +26,7 @@ import FileInput from "@/components/FileInput";
 import ProgressButton from "@/components/ProgressButton";
 import { getDefaultPuzzleDatabases } from "@/utils/db";
 import { capitalize } from "@/utils/format";
+import { open } from "@/utils/pathAccess";
 import { getPuzzleDatabases } from "@/utils/puzzles";
 import { unwrap } from "@/utils/unwrap";
 
@@ -838,6 +838,8 @@ export default function DashboardPage() {
                   referenceDb: null,
                   reversed: false,
                   moves,
+                  whiteRating: gameHeaders.white_elo ?? null,
+                  blackRating: gameHeaders.black_elo ?? null,
                 },
                 engineSettings,
               );
@@ -854,7 +856,7 @@ export default function DashboardPage() {
                 break;
               }
 
-              const analysis = unwrap(analysisResult);
+              const { analysis } = unwrap(analysisResult);
 
               // Use the same addAnalysis function from the store to ensure consistency
               // This ensures the same logic is used for both individual and batch analysis
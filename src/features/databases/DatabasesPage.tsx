@@ -31,7 +31,6 @@ import {
 import { useQuery } from "@tanstack/react-query";
 import { Link, useNavigate, useSearch } from "@tanstack/react-router";
 import { listen } from "@tauri-apps/api/event";
-import { open as openDialog, save } from "@tauri-apps/plugin-dialog";
 import { readDir } from "@tauri-apps/plugin-fs";
 import { useAtom } from "jotai";
 import { DataTable } from "mantine-datatable";
@@ -47,6 +46,7 @@ import { useResponsiveLayout } from "@/hooks/useResponsiveLayout";
 import { referenceDbAtom } from "@/state/atoms";
 import { useActiveDatabaseViewStore } from "@/state/store/database";
 import { getDatabases, type SuccessDatabaseInfo } from "@/utils/db";
+import { open as openDialog, save } from "@/utils/pathAccess";
 import { getPuzzleDatabases } from "@/utils/puzzles";
 import { unwrap } from "@/utils/unwrap";
 import AddDatabase from "./components/modals/AddDatabase";
@@ -930,7 +930,7 @@ function DatabaseActions({
 
     setConvertLoading(true);
     try {
-      await commands.convertPgn(file, database.file, null, "", null);
+      await commands.convertPgn(file, database.file, null, "", null, null, null);
       mutate();
     } finally {
       setConvertLoading(false);
@@ -945,7 +945,7 @@ function DatabaseActions({
 
     setExportLoading(true);
     try {
-      await commands.exportToPgn(database.file, destFile);
+      await commands.exportToPgn(database.file, destFile, null);
     } finally {
       setExportLoading(false);
     }
@@ -964,7 +964,7 @@ function DatabaseActions({
       labels: { confirm: t("common.remove"), cancel: t("common.cancel") },
       confirmProps: { color: "red" },
       onConfirm: async () => {
-        await commands.deleteDatabase(database.file);
+        await commands.deleteDatabase(database.file, false);
         mutate();
         onSelect(null);
         if (isPuzzleDatabase(database)) {
@@ -1026,7 +1026,7 @@ function GeneralSettings({
 
   useEffect(() => {
     commands
-      .editDbInfo(selectedDatabase.file, debouncedTitle ?? null, debouncedDescription ?? null)
+      .editDbInfo(selectedDatabase.file, debouncedTitle ?? null, debouncedDescription ?? null, null)
       .then(() => mutate());
   }, [debouncedTitle, debouncedDescription, selectedDatabase.file, mutate]);
 
@@ -1114,7 +1114,7 @@ function DuplicateRemover({
   const handleRemoveDuplicates = useCallback(async () => {
     setLoading(true);
     try {
-      await commands.deleteDuplicatedGames(selectedDatabase.file);
+      await commands.deleteDuplicatedGames(selectedDatabase.file, "keepBoth", null);
     } finally {
       setLoading(false);
       reload();
@@ -19,7 +19,6 @@ import { IconAlertCircle } from "@tabler/icons-react";
 import { useQuery } from "@tanstack/react-query";
 import { useNavigate } from "@tanstack/react-router";
 import { appDataDir, resolve } from "@tauri-apps/api/path";
-import { open } from "@tauri-apps/plugin-dialog";
 import { type Dispatch, type SetStateAction, useCallback, useMemo, useState } from "react";
 import { useTranslation } from "react-i18next";
 import { commands, type DatabaseInfo, events, type PuzzleDatabaseInfo } from "@/bindings";
@@ -32,6 +31,7 @@ import {
   useDefaultDatabases,
 } from "@/utils/db";
 import { capitalize } from "@/utils/format";
+import { open } from "@/utils/pathAccess";
 import { getPuzzleDatabases } from "@/utils/puzzles";
 import { unwrap } from "@/utils/unwrap";
 
@@ -133,7 +133,7 @@ const useDatabaseOperations = (
       try {
         setLoading(true);
         const dbPath = await resolve(await appDataDir(), "db", `${title}.db3`);
-        unwrap(await commands.convertPgn(path, dbPath, null, title, description ?? null));
+        unwrap(await commands.convertPgn(path, dbPath, null, title, description ?? null, null, null));
         setDatabases();
       } catch (error) {
         console.error("Failed to convert database:", error);
@@ -1,6 +1,5 @@
 import { Button, Input, NumberInput, Text, TextInput } from "@mantine/core";
 import type { UseFormReturnType } from "@mantine/form";
-import { open } from "@tauri-apps/plugin-dialog";
 import { useRef } from "react";
 import { useTranslation } from "react-i18next";
 import { match } from "ts-pattern";
@@ -8,6 +7,7 @@ import { commands, type UciOptionConfig } from "@/bindings";
 import FileInput from "@/components/FileInput";
 import { type LocalEngine, requiredEngineSettings } from "@/utils/engines";
 import { usePlatform } from "@/utils/files";
+import { open } from "@/utils/pathAccess";
 
 export default function EngineForm({
   onSubmit,
@@ -21,7 +21,6 @@ import {
 import { useToggle } from "@mantine/hooks";
 import { modals } from "@mantine/modals";
 import { IconCloud, IconPhotoPlus } from "@tabler/icons-react";
-import { open } from "@tauri-apps/plugin-dialog";
 import { useAtom } from "jotai";
 import { useCallback, useEffect, useRef, useState } from "react";
 import { useTranslation } from "react-i18next";
@@ -30,6 +29,7 @@ import GoModeInput from "@/components/GoModeInput";
 import LocalImage from "@/components/LocalImage";
 import { enginesAtom } from "@/state/atoms";
 import { type LocalEngine, requiredEngineSettings } from "@/utils/engines";
+import { open } from "@/utils/pathAccess";
 import { JSONModal } from "../modals/JSONModal";
 
 interface EngineSettingsProps {
@@ -1,10 +1,10 @@
 import { Divider, FileInput, Textarea } from "@mantine/core";
-import { open } from "@tauri-apps/plugin-dialog";
 import { useState } from "react";
 import { useTranslation } from "react-i18next";
 import { commands } from "@/bindings/generated";
 import type { FileMetadata } from "@/features/files/utils/file";
 import { createTempImportFile, getFileNameWithoutExtension } from "@/utils/files";
+import { open } from "@/utils/pathAccess";
 import { unwrap } from "@/utils/unwrap";
 
 export type PgnTarget = {
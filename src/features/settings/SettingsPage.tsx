@@ -11,6 +11,7 @@ import {
   Title,
   useDirection,
 } from "@mantine/core";
+import { open } from "@/utils/pathAccess";
 
 import {
   IconBook,
@@ -22,7 +23,6 @@ import {
   IconVolume,
 } from "@tabler/icons-react";
 import { useLoaderData } from "@tanstack/react-router";
-import { open } from "@tauri-apps/plugin-dialog";
 import { useAtom } from "jotai";
 import { useCallback, useMemo, useState } from "react";
 import { useTranslation } from "react-i18next";
@@ -8,7 +8,7 @@ import { createRootRouteWithContext, Outlet, useNavigate } from "@tanstack/react
 import { Menu, MenuItem, PredefinedMenuItem, Submenu } from "@tauri-apps/api/menu";
 import { appLogDir, resolve } from "@tauri-apps/api/path";
 import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
-import { ask, message, open } from "@tauri-apps/plugin-dialog";
+import { ask, message } from "@tauri-apps/plugin-dialog";
 import { openPath } from "@tauri-apps/plugin-opener";
 import { exit, relaunch } from "@tauri-apps/plugin-process";
 import { check } from "@tauri-apps/plugin-updater";
@@ -26,6 +26,7 @@ import { activeTabAtom, tabsAtom } from "@/state/atoms";
 import { keyMapAtom } from "@/state/keybindings";
 import { openFile } from "@/utils/files";
 import { formatHotkeyDisplay } from "@/utils/formatHotkey";
+import { open } from "@/utils/pathAccess";
 import { createTab } from "@/utils/tabs";
 
 type MenuGroup = {
@@ -12,6 +12,7 @@ import {
     type QueryResponse,
 } from "@/bindings";
 import type { LocalOptions } from "@/components/panels/database/DatabasePanel";
+import { withPathAccess } from "./pathAccess";
 import { unwrap } from "./unwrap";
 
 export type SuccessDatabaseInfo = Extract<DatabaseInfo, { type: "success" }>;
@@ -232,31 +233,33 @@ export async function getTournamentGames(file: string, id: number) {
 }
 
 export async function searchPosition(options: LocalOptions, tab: string) {
-    const res = await commands.searchPosition(
-        options.path!,
-        {
-            player1: options.color === "white" ? options.player : undefined,
-            player2: options.color === "black" ? options.player : undefined,
-            position: {
-                fen: options.fen,
-                type_: options.type,
+    const res = await withPathAccess(() =>
+        commands.searchPosition(
+            options.path!,
+            {
+                player1: options.color === "white" ? options.player : undefined,
+                player2: options.color === "black" ? options.player : undefined,
+                position: {
+                    fen: options.fen,
+                    type_: options.type,
+                },
+                start_date: options.start_date,
+                end_date: options.end_date,
+                wanted_result: options.result,
+                options: {
+                    skipCount: true,
+                    sort: (options.sort || "averageElo") as
+                        | "id"
+                        | "date"
+                        | "whiteElo"
+                        | "blackElo"
+                        | "averageElo"
+                        | "ply_count",
+                    direction: (options.direction || "desc") as "asc" | "desc",
+                },
             },
-            start_date: options.start_date,
-            end_date: options.end_date,
-            wanted_result: options.result,
-            options: {
-                skipCount: true,
-                sort: (options.sort || "averageElo") as
-                    | "id"
-                    | "date"
-                    | "whiteElo"
-                    | "blackElo"
-                    | "averageElo"
-                    | "ply_count",
-                direction: (options.direction || "desc") as "asc" | "desc",
-            },
-        },
-        tab,
+            tab,
+        ),
     );
     if (res.status === "error") {
         if (res.error !== "Search stopped") {
@@ -0,0 +1,43 @@
+// Setting a UCI option on every engine at once. The backend checks the option against each
+// engine and sends it to the running ones; the stored profiles belong to `enginesAtom`, so
+// the settings it returns for them are stored here.
+import { getDefaultStore } from "jotai";
+import { commands, type EngineOptionResult, type ProfileSetting } from "@/bindings";
+import { enginesAtom } from "@/state/engineAtoms";
+import type { EngineSettings } from "@/utils/engines";
+import { unwrap } from "@/utils/unwrap";
+
+/** `settings` with `setting` replacing the one of the same name, or added. */
+function withSetting(settings: EngineSettings, setting: ProfileSetting): EngineSettings {
+    return settings.some((s) => s.name === setting.name)
+        ? settings.map((s) => (s.name === setting.name ? setting : s))
+        : [...settings, setting];
+}
+
+/** Set `name` to `value` on every engine, storing it in the profiles of those supporting it. */
+export async function applyOptionToAllEngines(
+    name: string,
+    value: string,
+    onlyRunning: boolean,
+    restartRunning?: boolean,
+): Promise<EngineOptionResult[]> {
+    const results = unwrap(
+        await commands.applyOptionToAllEngines(name, value, onlyRunning, restartRunning ?? null),
+    );
+    const settings = new Map<string, ProfileSetting>();
+    for (const result of results) {
+        if (result.tab === null && result.setting) {
+            settings.set(result.engine, result.setting);
+        }
+    }
+    if (settings.size > 0) {
+        await getDefaultStore().set(enginesAtom, async (prev) =>
+            (await prev).map((engine) => {
+                const setting = settings.get(engine.name);
+                if (!setting) return engine;
+                return { ...engine, settings: withSetting(engine.settings ?? [], setting) };
+            }),
+        );
+    }
+    return results;
+}
@@ -3,6 +3,7 @@ import type { Platform } from "@tauri-apps/plugin-os";
 import { z } from "zod";
 import { type BestMoves, commands, type EngineOptions, type GoMode } from "@/bindings";
 import { isInstallMethodSupported } from "./packageManager";
+import { withPathAccess } from "./pathAccess";
 import { unwrap } from "./unwrap";
 
 export const requiredEngineSettings = ["MultiPV", "Threads", "Hash"];
@@ -418,9 +419,9 @@ export function getBestMoves(
     goMode: GoMode,
     options: EngineOptions,
 ): Promise<[number, BestMoves[]] | null> {
-    return commands
-        .getBestMoves(engine.name, engine.path, tab, goMode, options)
-        .then((r) => unwrap(r));
+    return withPathAccess(() =>
+        commands.getBestMoves(engine.name, engine.path, tab, goMode, options),
+    ).then((r) => unwrap(r));
 }
 
 export function useDefaultEngines(os: Platform | undefined, opened: boolean) {
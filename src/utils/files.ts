@@ -8,6 +8,7 @@ import { commands } from "@/bindings";
 import type { FileMetadata } from "@/features/files/utils/file";
 import { unwrap } from "@/utils/unwrap";
 import { parsePGN } from "./chess";
+import { withPathAccess } from "./pathAccess";
 import { serializeStorageValue } from "./tabStateStorage";
 import { createTab, type Tab } from "./tabs";
 import { getGameName } from "./treeReducer";
@@ -34,7 +35,7 @@ export async function openFile(
     setTabs: React.Dispatch<React.SetStateAction<Tab[]>>,
     setActiveTab: React.Dispatch<React.SetStateAction<string | null>>,
 ) {
-    const count = unwrap(await commands.countPgnGames(file));
+    const count = unwrap(await withPathAccess(() => commands.countPgnGames(file)));
     const games = unwrap(await commands.readGames(file, 0, count - 1));
     const allGamesContent = games.join("");
 
@@ -182,6 +182,7 @@ export async function convertToNormalized(data: PositionGames): Promise<Normaliz
         site_id: 0,
         moves: pgn,
         ply_count: countMainPly(root),
+        decode_warnings: 0,
         // ply_count: root,
       };
       return normalized;
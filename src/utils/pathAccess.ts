@@ -0,0 +1,97 @@
+// The backend only touches paths below the app's own directories and the paths the user
+// authorized. Files picked in a dialog are authorized as they are picked, paths the app kept
+// from before are authorized once on start, and anything else needs the user's confirmation.
+import {
+    ask,
+    type OpenDialogOptions,
+    type OpenDialogReturn,
+    open as openDialog,
+    type SaveDialogOptions,
+    save as saveDialog,
+} from "@tauri-apps/plugin-dialog";
+import { getDefaultStore } from "jotai";
+import { commands } from "@/bindings";
+import i18n from "@/i18n";
+import { enginesAtom } from "@/state/engineAtoms";
+import { referenceDbAtom, selectedPuzzleDbAtom } from "@/state/gameAtoms";
+import { storedDocumentDirAtom } from "@/state/settingsAtoms";
+import { logger } from "@/utils/logger";
+
+type Result<T, E> = { status: "ok"; data: T } | { status: "error"; error: E };
+
+const MIGRATED_KEY = "authorized-paths-migrated";
+
+const NOT_AUTHORIZED = /^Access to (.+) was not authorized$/;
+
+/** The path a command was refused, if `error` is a refusal. */
+export function unauthorizedPath(error: string): string | null {
+    return NOT_AUTHORIZED.exec(error)?.[1] ?? null;
+}
+
+/** Let the backend use `paths` from now on. */
+export async function authorizePaths(paths: (string | null | undefined)[]) {
+    for (const path of paths) {
+        if (!path) continue;
+        const res = await commands.authorizePath(path);
+        if (res.status === "error") {
+            logger.warn("Failed to authorize path", { path, error: res.error });
+        }
+    }
+}
+
+/** The native open dialog, authorizing what was picked. */
+export async function open<T extends OpenDialogOptions>(options?: T): Promise<OpenDialogReturn<T>> {
+    const selected = await openDialog(options);
+    await authorizePaths(Array.isArray(selected) ? selected : [selected]);
+    return selected;
+}
+
+/** The native save dialog, authorizing the file to write. */
+export async function save(options?: SaveDialogOptions): Promise<string | null> {
+    const selected = await saveDialog(options);
+    await authorizePaths([selected]);
+    return selected;
+}
+
+/** Ask the user whether the backend may use `path`, authorizing it if so. */
+export async function confirmPathAccess(path: string): Promise<boolean> {
+    const confirmed = await ask(i18n.t("notifications.allowPathAccessMessage", { path }), {
+        title: i18n.t("notifications.allowPathAccess"),
+        kind: "warning",
+    });
+    if (!confirmed) return false;
+    const res = await commands.authorizePath(path);
+    return res.status === "ok";
+}
+
+/**
+ * Run `command`, and if it was refused a path, run it again once the user allowed it.
+ */
+export async function withPathAccess<T>(
+    command: () => Promise<Result<T, string>>,
+): Promise<Result<T, string>> {
+    const res = await command();
+    if (res.status === "ok") return res;
+    const path = unauthorizedPath(res.error);
+    if (path && (await confirmPathAccess(path))) {
+        return command();
+    }
+    return res;
+}
+
+/**
+ * Authorize the engines and databases the settings already point to, once, so they keep
+ * working now that the backend checks paths.
+ */
+export async function migrateAuthorizedPaths() {
+    if (localStorage.getItem(MIGRATED_KEY)) return;
+    const store = getDefaultStore();
+    const engines = await store.get(enginesAtom);
+    await authorizePaths([
+        ...engines.map((engine) => (engine.type === "local" ? engine.path : null)),
+        store.get(referenceDbAtom),
+        store.get(selectedPuzzleDbAtom),
+        store.get(storedDocumentDirAtom),
+    ]);
+    localStorage.setItem(MIGRATED_KEY, "true");
+}
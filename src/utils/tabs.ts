@@ -1,4 +1,3 @@
-import { save } from "@tauri-apps/plugin-dialog";
 import { INITIAL_FEN } from "chessops/fen";
 import { z } from "zod";
 import type { StoreApi } from "zustand";
@@ -7,6 +6,7 @@ import { serializeStorageValue } from "./tabStateStorage";
 import { fileMetadataSchema } from "@/features/files/utils/file";
 import type { TreeStoreState } from "@/state/store/tree";
 import { createFile, getFileNameWithoutExtension, isTempImportFile } from "@/utils/files";
+import { save } from "@/utils/pathAccess";
 import { unwrap } from "@/utils/unwrap";
 import { getMoveText, getPGN, parsePGN } from "./chess";
 import { formatDateToPGN } from "./format";
@@ -208,6 +208,7 @@ export async function saveToFile({
             glyphs: true,
             variations: true,
         })}\n\n`,
+        tab?.value ?? null,
     );
     store.getState().save();
 }
@@ -224,7 +225,7 @@ export async function saveTab(tab: Tab, store: StoreApi<TreeStoreState>) {
             variations: true,
         })}\n\n`;
 
-        await commands.writeGame(tab.source.path, tab?.gameNumber || 0, pgn);
+        await commands.writeGame(tab.source.path, tab?.gameNumber || 0, pgn, tab.value);
     } else if (tab.source?.type === "db") {
         const headers = store.getState().headers;
         const moves = `${getPGN(store.getState().root, {
@@ -235,10 +236,15 @@ export async function saveTab(tab: Tab, store: StoreApi<TreeStoreState>) {
             variations: true,
         })}\n\n`;
 
-        await commands.updateGame(tab.source.db, tab.source.id, {
-            ...headers,
-            moves,
-        });
+        await commands.updateGame(
+            tab.source.db,
+            tab.source.id,
+            {
+                ...headers,
+                moves,
+            },
+            tab.value,
+        );
     }
 }
 